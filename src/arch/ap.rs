@@ -0,0 +1,18 @@
+//! Application processor setup
+//!
+//! The CPU-local state the BSP sets up piecemeal during boot, done in one
+//! go for each application processor as it comes up: its own GDT, TSS and
+//! per-CPU block, the shared IDT, and the syscall MSRs.
+
+use crate::prelude::*;
+
+/// Give application processor `cpu` its descriptor tables and syscall
+/// entry. Runs on that CPU, with interrupts off, before it touches
+/// anything per-CPU.
+pub fn init_ap(cpu: usize) {
+    crate::arch::gdt::init_cpu_gdt(cpu);
+    crate::arch::idt::init_idt();
+    if let Err(e) = crate::arch::syscall::init_cpu_syscalls() {
+        println!("[SMP] cpu{}: no syscalls: {}", cpu, e);
+    }
+}
@@ -0,0 +1,181 @@
+//! Crash dumps
+//!
+//! After the panic report is printed, `write_crash_dump` serializes it as
+//! `key value` lines (message, registers, backtrace, the tail of the log
+//! ring and the executor's tasks) into a static buffer, sends it to COM1
+//! between `BEGIN`/`END` marker lines for a host to capture, and, when a
+//! region was set with `crashdump=DEVICE:LBA` on the command line, writes
+//! it to that block device so it can be read back after a reboot. A raw
+//! region rather than a file: the filesystem layer allocates and takes
+//! locks that a panicking CPU may already hold.
+//!
+//! Nothing here waits on a lock. Sections whose data sits behind a lock
+//! that is taken are recorded as unavailable, and the region is written
+//! with `write_blocks_nowait`, which gives up if the device is locked.
+
+use crate::prelude::*;
+use crate::arch::panic::{walk_stack, Registers};
+use alloc::string::String;
+use alloc::vec;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+/// Bytes reserved for a dump, header included; a multiple of any block
+/// size in use.
+pub const CRASH_DUMP_SIZE: usize = 16 * 1024;
+/// Bytes of the log ring included.
+const LOG_TAIL: usize = 4 * 1024;
+const MAGIC: &[u8; 8] = b"NXCRASH1";
+/// Magic plus the text length as a little-endian u32, padded.
+const HEADER_LEN: usize = 16;
+
+pub const BEGIN_MARKER: &str = "---- BEGIN NEUTRIX CRASH DUMP ----\n";
+pub const END_MARKER: &str = "---- END NEUTRIX CRASH DUMP ----\n";
+
+/// Block device name and first LBA of the dump region.
+static REGION: Mutex<Option<(String, u64)>> = Mutex::new(None);
+
+/// Set the dump region from `crashdump=DEVICE:LBA` on the kernel command
+/// line. The device only has to exist by the time of a panic.
+pub fn configure_crashdump_from_cmdline(cmdline: &str) {
+    let Some(spec) = cmdline.split_whitespace().find_map(|w| w.strip_prefix("crashdump=")) else { return };
+    let parsed = spec.split_once(':').and_then(|(dev, lba)| Some((dev, lba.parse::<u64>().ok()?)));
+    match parsed {
+        Some((dev, lba)) => set_crashdump_region(Some((dev, lba))),
+        None => println!("[CRASH] bad crashdump={} (want DEVICE:LBA)", spec),
+    }
+}
+
+pub fn set_crashdump_region(region: Option<(&str, u64)>) {
+    *REGION.lock() = region.map(|(dev, lba)| (String::from(dev), lba));
+}
+
+pub fn crashdump_region() -> Option<(String, u64)> {
+    REGION.lock().clone()
+}
+
+struct DumpBuffer {
+    buf: [u8; CRASH_DUMP_SIZE],
+    len: usize,
+}
+
+static mut DUMP: DumpBuffer = DumpBuffer { buf: [0; CRASH_DUMP_SIZE], len: HEADER_LEN };
+/// The log tail is copied here first; the panic may be on a small stack.
+static mut LOG_COPY: [u8; LOG_TAIL] = [0; LOG_TAIL];
+
+impl fmt::Write for DumpBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(CRASH_DUMP_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn serialize(out: &mut DumpBuffer, info: &PanicInfo, regs: &Registers) {
+    let _ = writeln!(out, "version 1");
+    let _ = writeln!(out, "cpu {}", crate::arch::task::current_cpu());
+    let _ = writeln!(out, "tsc {}", crate::arch::tsc_timer::rdtsc());
+    let _ = writeln!(out, "message {}", info);
+    let named = [
+        ("rax", regs.rax), ("rbx", regs.rbx), ("rcx", regs.rcx), ("rdx", regs.rdx),
+        ("rsi", regs.rsi), ("rdi", regs.rdi), ("rbp", regs.rbp), ("rsp", regs.rsp),
+        ("r8", regs.r8), ("r9", regs.r9), ("r10", regs.r10), ("r11", regs.r11),
+        ("r12", regs.r12), ("r13", regs.r13), ("r14", regs.r14), ("r15", regs.r15),
+        ("rip", regs.rip), ("rflags", regs.rflags),
+        ("cr0", regs.cr0), ("cr2", regs.cr2), ("cr3", regs.cr3), ("cr4", regs.cr4),
+    ];
+    for (name, value) in named {
+        let _ = writeln!(out, "reg {} {:#018x}", name, value);
+    }
+    walk_stack(regs.rbp, |addr| {
+        let _ = match crate::symbols::resolve(addr) {
+            Some((name, offset)) => writeln!(out, "frame {:#018x} {}+{:#x}", addr, name, offset),
+            None => writeln!(out, "frame {:#018x}", addr),
+        };
+    });
+
+    let tail = unsafe { &mut *(&raw mut LOG_COPY) };
+    match crate::log::try_read_log_ring(tail) {
+        Some(n) => {
+            for line in tail[..n].split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                let _ = out.write_str("log ");
+                for chunk in line.utf8_chunks() {
+                    let _ = out.write_str(chunk.valid());
+                }
+                let _ = out.write_str("\n");
+            }
+        }
+        None => {
+            let _ = writeln!(out, "log (ring locked)");
+        }
+    }
+
+    // straight from the registry: the panic may be the heap running out
+    let listed = crate::arch::task::try_for_each_task(|t| {
+        let _ = writeln!(out, "task {} {} {:?} polls={} cycles={}", t.id.as_u64(), t.name, t.state, t.polls, t.cycles);
+    });
+    if !listed {
+        let _ = writeln!(out, "task (registry locked)");
+    }
+}
+
+/// Write `dump` (header included) to the configured region.
+fn store(dump: &DumpBuffer) -> Result<(), &'static str> {
+    let region = REGION.try_lock().ok_or("region locked")?;
+    let Some((dev, lba)) = region.as_ref() else { return Ok(()) };
+    let queue = crate::storage::try_block_device(dev).ok_or("no such device")?;
+    let device = queue.device();
+    let bs = device.block_size();
+    let len = dump.len.div_ceil(bs) * bs;
+    if len > CRASH_DUMP_SIZE {
+        return Err("block size too large");
+    }
+    // the buffer is zeroed past the text, so rounding up writes no garbage
+    device.write_blocks_nowait(*lba, &dump.buf[..len]).map_err(|e| match e {
+        crate::storage::BlockError::Busy => "device locked",
+        _ => "write failed",
+    })
+}
+
+/// Serialize a crash report and send it to COM1 and the dump region. Called
+/// once, from `panic_report`, with the other CPUs stopped.
+pub fn write_crash_dump(info: &PanicInfo, regs: &Registers) {
+    // only the single reporting CPU gets here
+    let dump = unsafe { &mut *(&raw mut DUMP) };
+    serialize(dump, info, regs);
+    let text_len = (dump.len - HEADER_LEN) as u32;
+    dump.buf[..8].copy_from_slice(MAGIC);
+    dump.buf[8..12].copy_from_slice(&text_len.to_le_bytes());
+
+    let serial = crate::devices::serial::serial_write_panic;
+    serial(BEGIN_MARKER.as_bytes());
+    serial(&dump.buf[HEADER_LEN..dump.len]);
+    serial(END_MARKER.as_bytes());
+    if let Err(e) = store(dump) {
+        println!("[CRASH] dump not stored: {}", e);
+    }
+}
+
+/// The dump stored in the configured region, if there is one.
+pub fn read_stored_dump() -> Result<Option<String>, String> {
+    let (dev, lba) = crashdump_region().ok_or("no crashdump region configured")?;
+    let queue = crate::storage::block_device(&dev).ok_or_else(|| alloc::format!("no block device {}", dev))?;
+    let mut buf = vec![0u8; CRASH_DUMP_SIZE.div_ceil(queue.block_size()) * queue.block_size()];
+    queue.read_blocking(lba, &mut buf).map_err(|e| alloc::format!("{}: {}", dev, e))?;
+    if &buf[..8] != MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize;
+    let text = &buf[HEADER_LEN..(HEADER_LEN + len).min(buf.len())];
+    Ok(Some(String::from_utf8_lossy(text).into_owned()))
+}
+
+/// Erase the stored dump's header so it isn't reported again.
+pub fn clear_stored_dump() -> Result<(), String> {
+    let (dev, lba) = crashdump_region().ok_or("no crashdump region configured")?;
+    let queue = crate::storage::block_device(&dev).ok_or_else(|| alloc::format!("no block device {}", dev))?;
+    let zero = vec![0u8; queue.block_size()];
+    queue.write_blocking(lba, &zero).map_err(|e| alloc::format!("{}: {}", dev, e))
+}
@@ -1,75 +1,120 @@
-use crate::*;
+use crate::prelude::*;
+use crate::arch::idt::hlt;
 use x86_64::structures::idt::*;
+use x86_64::PrivilegeLevel;
+
+/// Faults raised by user code are sent to the process as `sig` instead of
+/// stopping the kernel.
+fn kill_if_user(stack_frame: &InterruptStackFrame, what: &str, addr: u64, sig: crate::process::Signal) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        crate::process::user_fault(
+            what,
+            addr,
+            sig,
+            stack_frame.instruction_pointer.as_u64(),
+            stack_frame.stack_pointer.as_u64(),
+            stack_frame.cpu_flags.bits(),
+        );
+    }
+}
 
 pub extern "x86-interrupt" fn division_by_zero(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    kill_if_user(&stack_frame, "division by zero", stack_frame.instruction_pointer.as_u64(), crate::process::SIGFPE);
     println!("EXCEPTION: DIVISION BY ZERO\n{:#?}", stack_frame);
 }
 
+/// Another CPU panicked and is stopping this one; otherwise a stray NMI.
+pub extern "x86-interrupt" fn nmi(
+    stack_frame: InterruptStackFrame)
+{
+    if crate::arch::panic::panicking() {
+        x86_64::instructions::interrupts::disable();
+        hlt();
+    }
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    println!("NMI at {}", crate::symbols::Sym(stack_frame.instruction_pointer.as_u64()));
+}
+
 pub extern "x86-interrupt" fn debug_error(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn overflow(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn bre(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn invalid_opcode(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    kill_if_user(&stack_frame, "invalid opcode", stack_frame.instruction_pointer.as_u64(), crate::process::SIGILL);
     println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn dno(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     println!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn breakpoint(
     stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn double_fault(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    // most often a kernel stack overflow; name the stack if it was
+    crate::arch::stackguard::check_stack_canaries(crate::arch::task::current_cpu());
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn invalid_tss(
     stack_frame: InterruptStackFrame, _error_code: u64)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     panic!("EXCEPTION: INVALID TSS\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn snp(
     stack_frame: InterruptStackFrame, _error_code: u64)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn ssf(
     stack_frame: InterruptStackFrame, _error_code: u64)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn gpf(
     stack_frame: InterruptStackFrame, _error_code: u64)
 {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    kill_if_user(&stack_frame, "general protection fault", stack_frame.instruction_pointer.as_u64(), crate::process::SIGSEGV);
     panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
 }
 
@@ -77,11 +122,16 @@ pub extern "x86-interrupt" fn pf(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt();
+    let addr = Cr2::read_raw();
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+        && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && crate::process::handle_user_page_fault(addr, error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE))
+    {
+        return;
+    }
+    kill_if_user(&stack_frame, "page fault", addr, crate::process::SIGSEGV);
+    panic!("EXCEPTION: PAGE FAULT\nAccessed Address: {:#x}\nError Code: {:?}\n{:#?}", addr, error_code, stack_frame);
 }
\ No newline at end of file
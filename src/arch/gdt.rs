@@ -1,42 +1,37 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
-use lazy_static::lazy_static;
 use core::convert::TryInto;
 use x86_64::structures::gdt::*;
 use x86_64::instructions::segmentation::*;
 use x86_64::instructions::tables::*;
 
-use crate::*;
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIs get a stack of their own: one can arrive in the syscall stub while
+/// RSP still holds the user stack.
+pub const NMI_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE.try_into().unwrap();
-            stack_end
-        };
-        tss
-    };
-}
-
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kcode = gdt.append(Descriptor::kernel_code_segment());
-		let kdata = gdt.append(Descriptor::kernel_data_segment());
-		let ucode = gdt.append(Descriptor::user_code_segment());
-		let udata = gdt.append(Descriptor::user_data_segment());
-        let stss = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {kcode, kdata, ucode, udata, stss})
-    };
-}
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+const NMI_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(align(16))]
+struct DoubleFaultStack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+#[repr(align(16))]
+struct NmiStack([u8; NMI_STACK_SIZE]);
 
+// Each CPU gets its own GDT and TSS: the TSS descriptor is marked busy when
+// loaded, and RSP0 has to point at the CPU's own stack.
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+static mut GDT: [GlobalDescriptorTable; MAX_CPUS] = [const { GlobalDescriptorTable::new() }; MAX_CPUS];
+static mut DOUBLE_FAULT_STACKS: [DoubleFaultStack; MAX_CPUS] =
+    [const { DoubleFaultStack([0; DOUBLE_FAULT_STACK_SIZE]) }; MAX_CPUS];
+static mut NMI_STACKS: [NmiStack; MAX_CPUS] = [const { NmiStack([0; NMI_STACK_SIZE]) }; MAX_CPUS];
+static SELECTORS: spin::Once<Selectors> = spin::Once::new();
+
+#[derive(Clone, Copy)]
 struct Selectors {
     kcode: SegmentSelector,
     kdata: SegmentSelector,
@@ -45,16 +40,75 @@ struct Selectors {
 	stss: SegmentSelector
 }
 
+/// Load the boot CPU's GDT and TSS.
 pub fn init_gdt() {
-    GDT.0.load();
+    init_cpu_gdt(0);
+}
+
+/// Build and load `cpu`'s GDT and TSS, then set up its per-CPU block. Every
+/// CPU runs this once during bring-up, before it enables interrupts.
+pub fn init_cpu_gdt(cpu: usize) {
+    assert!(cpu < MAX_CPUS, "cpu index out of range");
+    let (gdt, tss) = unsafe { (&mut *(&raw mut GDT[cpu]), &mut *(&raw mut TSS[cpu])) };
+    let df_stack = double_fault_stack_base(cpu);
+    unsafe { crate::arch::stackguard::place_canary(df_stack) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::new(df_stack) + DOUBLE_FAULT_STACK_SIZE as u64;
+    let nmi_stack = nmi_stack_base(cpu);
+    unsafe { crate::arch::stackguard::place_canary(nmi_stack) };
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = VirtAddr::new(nmi_stack) + NMI_STACK_SIZE as u64;
+    // RSP0 (the stack for interrupts arriving in user mode) is filled in by
+    // `percpu::init_percpu`.
+
+    let kcode = gdt.append(Descriptor::kernel_code_segment());
+	let kdata = gdt.append(Descriptor::kernel_data_segment());
+	// SYSRET loads SS from STAR+8 and CS from STAR+16, so user data
+	// has to sit directly below user code.
+	let udata = gdt.append(Descriptor::user_data_segment());
+	let ucode = gdt.append(Descriptor::user_code_segment());
+    let stss = gdt.append(Descriptor::tss_segment(unsafe { &*(&raw const TSS[cpu]) }));
+    let selectors = *SELECTORS.call_once(|| Selectors { kcode, kdata, ucode, udata, stss });
+
+    unsafe { &*(&raw const GDT[cpu]) }.load();
 	unsafe
 	{
-		CS::set_reg(GDT.1.kcode);
-		DS::set_reg(GDT.1.kdata);
-		ES::set_reg(GDT.1.kdata);
-		FS::set_reg(GDT.1.kdata);
-		GS::set_reg(GDT.1.kdata);
-		SS::set_reg(GDT.1.kdata);
-		load_tss(GDT.1.stss);
+		CS::set_reg(selectors.kcode);
+		DS::set_reg(selectors.kdata);
+		ES::set_reg(selectors.kdata);
+		FS::set_reg(selectors.kdata);
+		GS::set_reg(selectors.kdata);
+		SS::set_reg(selectors.kdata);
+		load_tss(selectors.stss);
+		crate::arch::percpu::init_percpu(cpu, &raw mut TSS[cpu]);
 	}
-}
\ No newline at end of file
+}
+
+/// Lowest address of `cpu`'s double fault stack.
+pub fn double_fault_stack_base(cpu: usize) -> u64 {
+    (unsafe { &raw const DOUBLE_FAULT_STACKS[cpu] }) as u64
+}
+
+/// Lowest address of `cpu`'s NMI stack.
+pub fn nmi_stack_base(cpu: usize) -> u64 {
+    (unsafe { &raw const NMI_STACKS[cpu] }) as u64
+}
+
+fn selectors() -> Selectors {
+    *SELECTORS.r#try().expect("GDT not initialized")
+}
+
+pub fn kernel_code_selector() -> SegmentSelector {
+	selectors().kcode
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+	selectors().kdata
+}
+
+pub fn user_code_selector() -> SegmentSelector {
+	selectors().ucode
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+	selectors().udata
+}
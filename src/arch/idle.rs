@@ -0,0 +1,67 @@
+//! CPU idle loop
+//!
+//! The executor calls `idle()` when a CPU has nothing to run. With
+//! interrupts disabled it re-checks for work, then sleeps with `hlt` or,
+//! when the CPU supports it, `monitor`/`mwait` on the run queue's wake word
+//! so a remote CPU can wake it just by writing that word instead of sending
+//! an IPI. Timer and device interrupts wake it either way.
+
+use crate::prelude::*;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IdleMethod {
+    Hlt = 0,
+    Mwait = 1,
+}
+
+static METHOD: AtomicU8 = AtomicU8::new(IdleMethod::Hlt as u8);
+
+/// Pick the idle method from CPUID. Call once during boot.
+pub fn init() {
+    let feats = crate::arch::detect_cpu_features();
+    let method = if feats.monitor { IdleMethod::Mwait } else { IdleMethod::Hlt };
+    set_method(method);
+    println!("[IDLE] using {:?}", method);
+}
+
+pub fn method() -> IdleMethod {
+    match METHOD.load(Ordering::Relaxed) {
+        1 => IdleMethod::Mwait,
+        _ => IdleMethod::Hlt,
+    }
+}
+
+/// Override the idle method (e.g. to force `hlt` when MWAIT misbehaves).
+pub fn set_method(method: IdleMethod) {
+    METHOD.store(method as u8, Ordering::Relaxed);
+}
+
+/// Sleep until an interrupt arrives or, under MWAIT, `wake_word` is written.
+/// `has_work` is evaluated with interrupts disabled; if it returns true the
+/// CPU doesn't sleep at all. Returns with interrupts enabled; the result
+/// says whether the CPU actually slept.
+pub fn idle(wake_word: &AtomicU64, has_work: impl Fn() -> bool) -> bool {
+    interrupts::disable();
+    if has_work() {
+        interrupts::enable();
+        return false;
+    }
+    match method() {
+        IdleMethod::Hlt => interrupts::enable_and_hlt(),
+        IdleMethod::Mwait => unsafe {
+            asm!("monitor", in("rax") wake_word as *const AtomicU64, in("ecx") 0u32, in("edx") 0u32, options(nostack));
+            // a write between the check above and MONITOR would be missed
+            if has_work() {
+                interrupts::enable();
+                return false;
+            }
+            // sti's interrupt shadow covers mwait, as with sti; hlt
+            asm!("sti", "mwait", in("eax") 0u32, in("ecx") 0u32, options(nostack));
+        },
+    }
+    true
+}
@@ -2,9 +2,14 @@ use lazy_static::lazy_static;
 use x86_64::structures::idt::*;
 use core::arch::asm;
 
-use crate::*;
+use crate::prelude::*;
+use crate::arch::exceptions::*;
+use crate::arch::gdt;
+use crate::arch::task::MAX_CPUS;
+use crate::trace::Subsys;
 use alloc::boxed::Box;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
 
 use x86_64::structures::idt::InterruptStackFrame;
 
@@ -22,6 +27,10 @@ fn ensure_idt_initialized() -> *mut InterruptDescriptorTable {
 		// Exceptions / traps
 		idt.divide_error.set_handler_fn(division_by_zero);
 		idt.debug.set_handler_fn(debug_error);
+		unsafe {
+			idt.non_maskable_interrupt.set_handler_fn(nmi)
+				.set_stack_index(gdt::NMI_IST_INDEX);
+		}
 		idt.overflow.set_handler_fn(overflow);
 		idt.bound_range_exceeded.set_handler_fn(bre);
 		idt.invalid_opcode.set_handler_fn(invalid_opcode);
@@ -37,9 +46,10 @@ fn ensure_idt_initialized() -> *mut InterruptDescriptorTable {
 		idt.general_protection_fault.set_handler_fn(gpf);
 		idt.page_fault.set_handler_fn(pf);
 
-		// Default IRQ handlers
+		// Every IRQ vector starts on its trampoline with no function, which
+		// reports it as unhandled
 		for vec in 32u8..=255u8 {
-			unsafe { idt[vec].set_handler_fn(default_irq_handler); }
+			unsafe { idt[vec].set_handler_fn(vector_stub_for(vec)); }
 		}
 
 		let leaked = Box::leak(Box::new(idt)) as *mut InterruptDescriptorTable;
@@ -56,22 +66,19 @@ fn ensure_idt_initialized() -> *mut InterruptDescriptorTable {
 	ptr
 }
 
-/// The signature drivers must use when registering an IRQ handler.
+/// The signature drivers must use when registering an IRQ handler. Handlers
+/// can interrupt user code, so they start with `percpu::kernel_gs` and end
+/// with `irq_exit`.
 pub type IrqHandler = extern "x86-interrupt" fn(InterruptStackFrame);
 
-/// Default IRQ handler used until a driver registers a real one. It simply
-/// prints a message and issues an EOI so the interrupt line is cleared.
-pub extern "x86-interrupt" fn default_irq_handler(_stack_frame: InterruptStackFrame) {
-	println!("[INT] received unhandled IRQ (placeholder)");
-	unsafe {
-		if crate::hal::apic::is_initialized() {
-			crate::hal::apic::send_eoi();
-		} else {
-			// If using PICs this should be the IRQ number; we don't know it here
-			// so call notify_end_of_interrupt with 0 as a safe no-op in many
-			// implementations — however we prefer APIC mode as per main.rs.
-			crate::arch::interrupts::PICS.lock().notify_end_of_interrupt(0);
-		}
+/// Last step of an IRQ handler, after the EOI. If the interrupt arrived in
+/// user mode, gives the process layer a chance to act on input and signals
+/// before returning there (it may not return at all).
+pub fn irq_exit(stack_frame: &InterruptStackFrame) {
+	let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+	let _ = IRQ_DEPTH[cpu].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| d.checked_sub(1));
+	if stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3 {
+		crate::process::interrupted_user();
 	}
 }
 
@@ -84,10 +91,132 @@ pub fn register_irq_handler(vector: u8, handler: IrqHandler) {
 	unsafe { (&mut *ptr)[vector].set_handler_fn(handler); }
 }
 
-/// Unregister the handler for `vector` and restore the default placeholder.
+/// Install a raw entry stub for `vector`, for a handler that needs more of
+/// the interrupted state than an `IrqHandler` gets.
+///
+/// # Safety
+/// `addr` must be an interrupt entry point that returns with `iretq`.
+pub unsafe fn register_irq_entry(vector: u8, addr: u64) {
+	let ptr = ensure_idt_initialized();
+	unsafe { (&mut *ptr)[vector].set_handler_addr(x86_64::VirtAddr::new(addr)); }
+}
+
+/// Unregister the handler for `vector`. Interrupts on it are reported as
+/// unhandled again.
 pub fn unregister_irq_handler(vector: u8) {
+	IRQ_FNS[vector as usize].store(0, Ordering::Release);
 	let ptr = ensure_idt_initialized();
-	unsafe { (&mut *ptr)[vector].set_handler_fn(default_irq_handler); }
+	unsafe { (&mut *ptr)[vector].set_handler_fn(vector_stub_for(vector)); }
+}
+
+/// Vector-aware IRQ callback: a plain function that is told which vector fired.
+pub type IrqFn = fn(u8);
+
+// IrqFn pointers stored as usize per vector; 0 means none.
+static IRQ_FNS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+static IRQ_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// Interrupt handlers running on each CPU, nested ones included.
+static IRQ_DEPTH: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Mark this CPU as running an interrupt handler until `irq_exit`.
+/// `note_irq` does this; handlers that don't call it call this instead.
+pub fn irq_enter() {
+	IRQ_DEPTH[crate::arch::task::current_cpu() % MAX_CPUS].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Whether this CPU is in an interrupt handler.
+pub fn in_irq() -> bool {
+	IRQ_DEPTH[crate::arch::task::current_cpu() % MAX_CPUS].load(Ordering::Relaxed) != 0
+}
+
+/// Count an interrupt on `vector`. The trampolines do this automatically;
+/// handlers registered with `register_irq_handler` call it themselves.
+pub fn note_irq(vector: u8) {
+	irq_enter();
+	IRQ_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+	crate::random::add_interrupt_timing(vector);
+	trace!(Subsys::Irq, "vector {:#04x}", vector);
+}
+
+/// Interrupts seen on `vector` since boot.
+pub fn irq_count(vector: u8) -> u64 {
+	IRQ_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// (vector, count) for every vector that has fired at least once.
+pub fn irq_counts() -> Vec<(u8, u64)> {
+	(0..=255u8).map(|v| (v, irq_count(v))).filter(|&(_, c)| c != 0).collect()
+}
+
+/// Per-vector trampoline: call the registered `IrqFn` with `V`, then EOI.
+extern "x86-interrupt" fn vector_stub<const V: u8>(stack_frame: InterruptStackFrame) {
+	let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+	note_irq(V);
+	let apic = crate::hal::apic::is_initialized();
+	if apic {
+		crate::hal::ioapic::level_irq_begin(V);
+	} else if !crate::hal::intctl::pic_irq_begin(V) {
+		// spurious IRQ 7 or 15: nothing to run, and no EOI
+		irq_exit(&stack_frame);
+		return;
+	}
+	let f = IRQ_FNS[V as usize].load(Ordering::Acquire);
+	if f != 0 {
+		let f: IrqFn = unsafe { core::mem::transmute::<usize, IrqFn>(f) };
+		let _tag = crate::memory::heaptrack::heap_tag("irq");
+		crate::fault::irq_delay();
+		f(V);
+	} else {
+		crate::hal::irq::unhandled_irq(V);
+	}
+	unsafe {
+		if apic {
+			crate::hal::apic::send_eoi();
+			// level-triggered lines need more than the local EOI
+			crate::hal::ioapic::level_irq_end(V, f != 0);
+		} else {
+			crate::hal::intctl::pic_eoi(V);
+		}
+	}
+	irq_exit(&stack_frame);
+}
+
+macro_rules! stub_row {
+	($hi:literal) => {
+		[
+			vector_stub::<{ $hi * 16 + 0x0 }> as IrqHandler, vector_stub::<{ $hi * 16 + 0x1 }>, vector_stub::<{ $hi * 16 + 0x2 }>, vector_stub::<{ $hi * 16 + 0x3 }>,
+			vector_stub::<{ $hi * 16 + 0x4 }>, vector_stub::<{ $hi * 16 + 0x5 }>, vector_stub::<{ $hi * 16 + 0x6 }>, vector_stub::<{ $hi * 16 + 0x7 }>,
+			vector_stub::<{ $hi * 16 + 0x8 }>, vector_stub::<{ $hi * 16 + 0x9 }>, vector_stub::<{ $hi * 16 + 0xA }>, vector_stub::<{ $hi * 16 + 0xB }>,
+			vector_stub::<{ $hi * 16 + 0xC }>, vector_stub::<{ $hi * 16 + 0xD }>, vector_stub::<{ $hi * 16 + 0xE }>, vector_stub::<{ $hi * 16 + 0xF }>,
+		]
+	};
+}
+
+// Indexed [vector >> 4][vector & 0xF]. Rows 0 and 1 are exception vectors
+// and never installed.
+static VECTOR_STUBS: [[IrqHandler; 16]; 16] = [
+	stub_row!(0x0), stub_row!(0x1), stub_row!(0x2), stub_row!(0x3),
+	stub_row!(0x4), stub_row!(0x5), stub_row!(0x6), stub_row!(0x7),
+	stub_row!(0x8), stub_row!(0x9), stub_row!(0xA), stub_row!(0xB),
+	stub_row!(0xC), stub_row!(0xD), stub_row!(0xE), stub_row!(0xF),
+];
+
+fn vector_stub_for(vector: u8) -> IrqHandler {
+	VECTOR_STUBS[(vector >> 4) as usize][(vector & 0xF) as usize]
+}
+
+/// Register a plain-function handler for `vector`. Unlike `register_irq_handler`
+/// the callback receives the vector number and the EOI is sent for it, so one
+/// function can serve many vectors.
+pub fn register_irq_fn(vector: u8, f: IrqFn) {
+	assert!(vector >= 32, "cannot register an IrqFn on an exception vector");
+	IRQ_FNS[vector as usize].store(f as usize, Ordering::Release);
+	register_irq_handler(vector, vector_stub_for(vector));
+}
+
+/// Remove a handler registered with `register_irq_fn`.
+pub fn unregister_irq_fn(vector: u8) {
+	unregister_irq_handler(vector);
 }
 
 pub fn init_idt() {
@@ -1,13 +1,13 @@
 use pic8259::*;
 use x86_64::structures::idt::*;
 use spin;
-use crate::*;
+use crate::prelude::*;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: crate::sync::Spinlock<ChainedPics> =
+    crate::sync::Spinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 	
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -6,11 +6,25 @@ pub mod idt;
 pub use idt::*;
 pub mod exceptions;
 pub use exceptions::*;
+pub mod panic;
+pub mod panic_screen;
+pub mod crashdump;
+pub mod stackguard;
 pub mod interrupts;
 pub use interrupts::*;
 pub mod processor;
 pub use processor::*;
 pub mod tsc_timer;
 pub use tsc_timer::*;
+pub mod syscall;
+pub use syscall::*;
+pub mod usermode;
+pub mod pmu;
+pub mod percpu;
+pub use percpu::*;
+pub mod ap;
+pub mod idle;
 pub mod task;
-pub use task::*;
\ No newline at end of file
+pub use task::*;
+pub mod workqueue;
+pub mod watchdog;
@@ -0,0 +1,187 @@
+//! Panic reporting
+//!
+//! On panic the kernel stops the other CPUs with an NMI, draws the
+//! `panic_screen` if a framebuffer is up, prints the message, the registers
+//! as they were on entry to the panic handler (plus CR0, CR2, CR3 and CR4),
+//! and a backtrace found by following saved frame pointers (the target is
+//! built with `frame-pointer: always`), saves the report through
+//! `crashdump`, then halts. Return addresses are named through the
+//! symbol resolver once one is installed. A panic raised while reporting
+//! another one just halts.
+//!
+//! The report doesn't wait for locks: a stopped CPU may hold one for good.
+//! The log sinks check `panicking()` and write around theirs (see
+//! `log::sinks`).
+
+use crate::prelude::*;
+use crate::arch::idt::hlt;
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Frames printed at most.
+pub const MAX_BACKTRACE: usize = 32;
+/// Largest gap accepted between consecutive frames before the walk stops.
+const MAX_FRAME_SIZE: u64 = 256 * 1024;
+
+/// Maps an address to the symbol containing it and the offset into it.
+pub type SymbolResolver = fn(u64) -> Option<(&'static str, u64)>;
+
+static SYMBOL_RESOLVER: Mutex<Option<SymbolResolver>> = Mutex::new(None);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_symbol_resolver(resolver: SymbolResolver) {
+    interrupts::without_interrupts(|| *SYMBOL_RESOLVER.lock() = Some(resolver));
+}
+
+/// Whether a panic is being reported. Other CPUs halt on NMI when it is.
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    /// The caller's registers. Scratch registers hold whatever the call
+    /// sequence left there; rbp, rsp and rip are the caller's own.
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut r = Registers::default();
+        let p = &raw mut r;
+        unsafe {
+            asm!(
+                "mov [{p} + 0x00], rax",
+                "mov [{p} + 0x08], rbx",
+                "mov [{p} + 0x10], rcx",
+                "mov [{p} + 0x18], rdx",
+                "mov [{p} + 0x20], rsi",
+                "mov [{p} + 0x28], rdi",
+                "mov [{p} + 0x30], rbp",
+                "mov [{p} + 0x38], rsp",
+                "mov [{p} + 0x40], r8",
+                "mov [{p} + 0x48], r9",
+                "mov [{p} + 0x50], r10",
+                "mov [{p} + 0x58], r11",
+                "mov [{p} + 0x60], r12",
+                "mov [{p} + 0x68], r13",
+                "mov [{p} + 0x70], r14",
+                "mov [{p} + 0x78], r15",
+                "lea {t}, [rip]",
+                "mov [{p} + 0x80], {t}",
+                "pushfq",
+                "pop qword ptr [{p} + 0x88]",
+                "mov {t}, cr0",
+                "mov [{p} + 0x90], {t}",
+                "mov {t}, cr2",
+                "mov [{p} + 0x98], {t}",
+                "mov {t}, cr3",
+                "mov [{p} + 0xa0], {t}",
+                "mov {t}, cr4",
+                "mov [{p} + 0xa8], {t}",
+                p = in(reg) p,
+                t = out(reg) _,
+            );
+        }
+        r
+    }
+
+    pub fn print(&self) {
+        println!("RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx);
+        println!("RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", self.rsi, self.rdi, self.rbp, self.rsp);
+        println!("R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", self.r8, self.r9, self.r10, self.r11);
+        println!("R12={:016x} R13={:016x} R14={:016x} R15={:016x}", self.r12, self.r13, self.r14, self.r15);
+        println!("RIP={:016x} RFLAGS={:016x}", self.rip, self.rflags);
+        println!("CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}", self.cr0, self.cr2, self.cr3, self.cr4);
+    }
+}
+
+/// Call `f` with each return address found by following frame pointers up
+/// from `rbp`. Stops at a null, misaligned or non-canonical frame pointer,
+/// or one that doesn't move up the stack by a plausible amount.
+pub fn walk_stack(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_BACKTRACE {
+        if rbp == 0 || rbp % 8 != 0 || x86_64::VirtAddr::try_new(rbp).is_err() {
+            return;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            return;
+        }
+        f(ret);
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+pub fn print_address(addr: u64) {
+    let resolver = if panicking() {
+        // go without names rather than wait
+        SYMBOL_RESOLVER.try_lock().and_then(|resolver| *resolver)
+    } else {
+        interrupts::without_interrupts(|| *SYMBOL_RESOLVER.lock())
+    };
+    match resolver.and_then(|resolve| resolve(addr)) {
+        Some((name, offset)) => println!("  {:#018x} {}+{:#x}", addr, name, offset),
+        None => println!("  {:#018x}", addr),
+    }
+}
+
+pub fn print_backtrace(rbp: u64) {
+    println!("Backtrace:");
+    walk_stack(rbp, print_address);
+}
+
+/// Stop every other CPU. They take the NMI and halt because `panicking()`.
+fn halt_other_cpus() {
+    if crate::hal::apic::is_initialized() {
+        crate::hal::apic::send_nmi_all_but_self();
+    }
+}
+
+/// Report a panic and halt. Called by the `#[panic_handler]`.
+#[inline(always)]
+pub fn panic_report(info: &PanicInfo) -> ! {
+    let regs = Registers::capture();
+    interrupts::disable();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // panicked while reporting; the first report is as far as we get
+        hlt();
+    }
+    halt_other_cpus();
+    crate::arch::panic_screen::show(&info.message(), crate::arch::task::current_cpu(), &regs);
+    println!();
+    println!("KERNEL PANIC on cpu{}: {}", crate::arch::task::current_cpu(), info);
+    regs.print();
+    print_backtrace(regs.rbp);
+    crate::arch::crashdump::write_crash_dump(info, &regs);
+    hlt();
+}
@@ -0,0 +1,267 @@
+//! Full-screen panic report
+//!
+//! With a framebuffer up, the console's last lines scroll the panic message
+//! out of sight, or never show it when the panic came from inside the
+//! console. So `panic_report` first paints the whole screen in
+//! `BACKGROUND` and writes the exception or panic message, the registers,
+//! a backtrace and the tail of the log ring on it. Drawing goes straight to
+//! framebuffer memory, through the snapshot `vbe_vga::raw_framebuffer`
+//! keeps in atomics and the built-in 8x8 font: nothing here allocates or
+//! takes a lock, and the log ring is only read if it is free. Once the
+//! screen is up the framebuffer log sink goes quiet so the rest of the
+//! report, still sent to serial, doesn't scroll over it.
+
+use crate::prelude::*;
+use crate::arch::panic::{walk_stack, Registers};
+use crate::driver_framework::drivers::vbe_vga::{font_glyph, RawFramebuffer};
+use crate::symbols::Sym;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const BACKGROUND: u32 = 0x0070_1010;
+const TEXT: u32 = 0x00ff_ffff;
+const HEADING: u32 = 0x00ff_d75f;
+const DIM: u32 = 0x00d0_b0b0;
+
+const CELL_W: usize = 8;
+const CELL_H: usize = 10;
+/// Rows the panic message may take before it is cut off.
+const MESSAGE_ROWS: usize = 12;
+/// Frames drawn at most, when there is room for them.
+const BACKTRACE_ROWS: usize = 16;
+const LOG_TAIL: usize = 4096;
+
+static SHOWN: AtomicBool = AtomicBool::new(false);
+static mut LOG_COPY: [u8; LOG_TAIL] = [0; LOG_TAIL];
+
+/// Whether the panic screen has been drawn. Framebuffer text output stops
+/// once it has.
+pub fn shown() -> bool {
+    SHOWN.load(Ordering::Relaxed)
+}
+
+/// Draw the panic screen if a framebuffer is active. Called once, by
+/// `panic_report`, with the other CPUs stopped.
+pub fn show(message: &dyn fmt::Display, cpu: usize, regs: &Registers) {
+    let Some(fb) = crate::driver_framework::drivers::vbe_vga::raw_framebuffer() else { return };
+    if !supported(&fb) {
+        return;
+    }
+    SHOWN.store(true, Ordering::Relaxed);
+    let log = unsafe { &mut *(&raw mut LOG_COPY) };
+    let n = crate::log::try_read_log_ring(log).unwrap_or(0);
+    render(fb, message, cpu, regs, &log[..n]);
+}
+
+fn supported(fb: &RawFramebuffer) -> bool {
+    matches!(fb.info.bpp, 24 | 32)
+        && fb.info.width as usize >= 40 * CELL_W
+        && fb.info.height as usize >= 25 * CELL_H
+        && fb.info.pitch >= fb.info.width as usize * (fb.info.bpp as usize / 8)
+}
+
+/// Paint the report onto `fb`. `log` is the end of the log ring; as many of
+/// its last lines as fit are shown.
+pub(crate) fn render(fb: RawFramebuffer, message: &dyn fmt::Display, cpu: usize, regs: &Registers, log: &[u8]) {
+    if !supported(&fb) {
+        return;
+    }
+    let mut screen = Screen::new(fb);
+    screen.fill(0, screen.rows, BACKGROUND);
+
+    // title bar: the exception when there is one
+    let mut first = FirstLine::new();
+    let _ = write!(first, "{}", message);
+    screen.fill(0, 1, TEXT);
+    screen.color = BACKGROUND;
+    screen.limit = 1;
+    match first.as_str().strip_prefix("EXCEPTION: ") {
+        Some(name) => { let _ = write!(screen, " {} on cpu{}", name, cpu); }
+        None => { let _ = write!(screen, " KERNEL PANIC on cpu{}", cpu); }
+    }
+
+    screen.section(2, MESSAGE_ROWS, TEXT);
+    let _ = write!(screen, "{}", message);
+
+    screen.section(screen.row + 2, 7, HEADING);
+    let _ = writeln!(screen, "Registers");
+    screen.color = TEXT;
+    let r = regs;
+    let _ = writeln!(screen, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", r.rax, r.rbx, r.rcx, r.rdx);
+    let _ = writeln!(screen, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", r.rsi, r.rdi, r.rbp, r.rsp);
+    let _ = writeln!(screen, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", r.r8, r.r9, r.r10, r.r11);
+    let _ = writeln!(screen, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", r.r12, r.r13, r.r14, r.r15);
+    let _ = writeln!(screen, "RIP={:016x} RFLAGS={:016x}", r.rip, r.rflags);
+    let _ = write!(screen, "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}", r.cr0, r.cr2, r.cr3, r.cr4);
+
+    // leave the log at least a few rows
+    let top = screen.row + 2;
+    let frames = BACKTRACE_ROWS.min(screen.rows.saturating_sub(top + 6));
+    screen.section(top, frames + 1, HEADING);
+    let _ = write!(screen, "Backtrace");
+    screen.color = TEXT;
+    walk_stack(regs.rbp, |addr| {
+        let _ = write!(screen, "\n  {:#018x} {}", addr, Sym(addr));
+    });
+
+    let top = screen.row + 2;
+    if top + 2 > screen.rows {
+        return;
+    }
+    screen.section(top, screen.rows - top, HEADING);
+    let _ = write!(screen, "Log");
+    screen.color = DIM;
+    for line in last_lines(log, screen.rows - top - 1).split(|&b| b == b'\n') {
+        screen.newline();
+        screen.write_bytes(line);
+    }
+}
+
+/// The end of `log` holding its last `n` complete lines, less the final newline.
+fn last_lines(log: &[u8], n: usize) -> &[u8] {
+    let log = log.strip_suffix(b"\n").unwrap_or(log);
+    let mut start = log.len();
+    for _ in 0..n {
+        match log[..start].iter().rposition(|&b| b == b'\n') {
+            Some(i) => start = i,
+            None => return log,
+        }
+    }
+    &log[start + 1..]
+}
+
+/// The first line of a message, as much of it as fits.
+struct FirstLine {
+    buf: [u8; 64],
+    len: usize,
+    done: bool,
+}
+
+impl FirstLine {
+    fn new() -> FirstLine {
+        FirstLine { buf: [0; 64], len: 0, done: false }
+    }
+
+    fn as_str(&self) -> &str {
+        // cut at a character boundary if the line was longer than buf
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Write for FirstLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.done || b == b'\n' || self.len == self.buf.len() {
+                self.done = true;
+                break;
+            }
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// A grid of character cells over the framebuffer. Text wraps at the right
+/// edge and anything past the current section's last row is dropped.
+struct Screen {
+    fb: RawFramebuffer,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    /// First row the current section may not write to.
+    limit: usize,
+    color: u32,
+}
+
+impl Screen {
+    fn new(fb: RawFramebuffer) -> Screen {
+        let cols = fb.info.width as usize / CELL_W;
+        let rows = fb.info.height as usize / CELL_H;
+        Screen { fb, cols, rows, col: 0, row: 0, limit: rows, color: TEXT }
+    }
+
+    /// Start writing at the beginning of `row`, for at most `rows` rows.
+    fn section(&mut self, row: usize, rows: usize, color: u32) {
+        self.row = row;
+        self.col = 0;
+        self.limit = (row + rows).min(self.rows);
+        self.color = color;
+    }
+
+    fn newline(&mut self) {
+        // stop at the limit, so the next section starts just below this one
+        self.row = (self.row + 1).min(self.limit);
+        self.col = 0;
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            match b {
+                b'\n' => self.newline(),
+                b'\r' => self.col = 0,
+                b'\t' => self.col = (self.col + 4) & !3,
+                _ => {
+                    if self.col >= self.cols {
+                        self.newline();
+                    }
+                    if self.row >= self.limit {
+                        return;
+                    }
+                    self.glyph(b);
+                    self.col += 1;
+                }
+            }
+        }
+    }
+
+    fn glyph(&self, ch: u8) {
+        let glyph = font_glyph(ch).or(font_glyph(b'?')).unwrap_or(&[0; 8]);
+        let x0 = self.col * CELL_W;
+        let y0 = self.row * CELL_H + (CELL_H - 8) / 2;
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..8 {
+                if bits & (0x80 >> dx) != 0 {
+                    self.pixel(x0 + dx, y0 + dy, self.color);
+                }
+            }
+        }
+    }
+
+    /// Paint `rows` whole text rows from `row` down.
+    fn fill(&self, row: usize, rows: usize, color: u32) {
+        let info = self.fb.info;
+        let y1 = ((row + rows) * CELL_H).min(info.height as usize);
+        for y in row * CELL_H..y1 {
+            for x in 0..info.width as usize {
+                self.pixel(x, y, color);
+            }
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize, color: u32) {
+        let info = self.fb.info;
+        let p = (self.fb.base as usize + y * info.pitch) as *mut u8;
+        unsafe {
+            if info.bpp == 32 {
+                (p.add(x * 4) as *mut u32).write_volatile(color);
+            } else {
+                let p = p.add(x * 3);
+                p.write_volatile(color as u8);
+                p.add(1).write_volatile((color >> 8) as u8);
+                p.add(2).write_volatile((color >> 16) as u8);
+            }
+        }
+    }
+}
+
+impl Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
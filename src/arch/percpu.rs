@@ -0,0 +1,151 @@
+//! Per-CPU data
+//!
+//! Each CPU has a `PerCpu` block that kernel code reaches through the GS
+//! base. User code has a GS base of its own (zero; nothing lets a process
+//! set one yet), so every way into ring 0 from ring 3 starts with `swapgs`
+//! and every way back ends with one: the syscall stub does it itself, the
+//! interrupt and exception handlers take a `KernelGs` guard, and
+//! `enter_user` swaps just before its `iretq`. While kernel code runs, the
+//! block is in GS_BASE and the user value in KERNEL_GS_BASE; the guard
+//! goes by which of the two holds a block, not by the interrupted CS,
+//! since the syscall stub runs in ring 0 for a few instructions on either
+//! side of its `swapgs`.
+//!
+//! The block also records the stack for entries from ring 3. The same
+//! address goes in the CPU's TSS as RSP0 (the CPU switches there itself on
+//! an interrupt) and in `kernel_rsp`, which the syscall stub loads by hand.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Offsets of the fields the syscall stub uses.
+pub const PERCPU_KERNEL_RSP: usize = 8;
+pub const PERCPU_USER_RSP: usize = 16;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of the block itself, so `gs:[0]` yields a pointer to it.
+    this: u64,
+    /// Stack for entries from ring 3.
+    kernel_rsp: u64,
+    /// User stack pointer, parked here by the syscall stub.
+    user_rsp: u64,
+    cpu: usize,
+    tss: *mut TaskStateSegment,
+}
+
+static mut PERCPU: [PerCpu; MAX_CPUS] = [const {
+    PerCpu { this: 0, kernel_rsp: 0, user_rsp: 0, cpu: 0, tss: core::ptr::null_mut() }
+}; MAX_CPUS];
+
+/// Bytes of stack for each CPU's entries from ring 3.
+pub const ENTRY_STACK_SIZE: usize = 64 * 1024;
+
+#[repr(align(16))]
+struct EntryStack([u8; ENTRY_STACK_SIZE]);
+
+static mut ENTRY_STACKS: [EntryStack; MAX_CPUS] = [const { EntryStack([0; ENTRY_STACK_SIZE]) }; MAX_CPUS];
+
+/// Lowest address of `cpu`'s stack for entries from ring 3.
+pub fn entry_stack_base(cpu: usize) -> u64 {
+    (unsafe { &raw const ENTRY_STACKS[cpu] }) as u64
+}
+
+/// Top of `cpu`'s stack for entries from ring 3.
+pub fn entry_stack_top(cpu: usize) -> u64 {
+    entry_stack_base(cpu) + ENTRY_STACK_SIZE as u64
+}
+
+/// Set up `cpu`'s block and point this CPU's GS base at it. Called once
+/// per CPU after its GDT and TSS are loaded (loading GS clears the base).
+///
+/// # Safety
+/// `tss` must be the TSS this CPU has loaded, and must stay valid.
+pub(crate) unsafe fn init_percpu(cpu: usize, tss: *mut TaskStateSegment) {
+    assert!(cpu < MAX_CPUS, "cpu index out of range");
+    unsafe {
+        let block = &raw mut PERCPU[cpu];
+        (*block).this = block as u64;
+        (*block).cpu = cpu;
+        (*block).tss = tss;
+        GsBase::write(VirtAddr::new(block as u64));
+        KernelGsBase::write(VirtAddr::new(0));
+        crate::arch::stackguard::place_canary(entry_stack_base(cpu));
+    }
+    set_kernel_stack(entry_stack_top(cpu));
+}
+
+/// Whether `base` is one of the per-CPU blocks, rather than a user GS base
+/// or the zero one from before `init_percpu`.
+fn is_percpu_block(base: u64) -> bool {
+    let first = (&raw const PERCPU) as u64;
+    let size = core::mem::size_of::<PerCpu>() as u64;
+    base >= first && base < first + size * MAX_CPUS as u64 && (base - first) % size == 0
+}
+
+/// This CPU's block, or `None` before `init_percpu` (or in a handler that
+/// forgot its `KernelGs` guard, where GS still holds the user value).
+fn this_cpu() -> Option<*mut PerCpu> {
+    let base = GsBase::read().as_u64();
+    if base == 0 {
+        return None;
+    }
+    let this: u64;
+    unsafe { core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags)) };
+    Some(this as *mut PerCpu)
+}
+
+/// Index of the running CPU, if its block is set up.
+pub fn percpu_cpu() -> Option<usize> {
+    this_cpu().map(|p| unsafe { (*p).cpu })
+}
+
+/// Use `top` as the stack for interrupts and system calls that arrive from
+/// ring 3 on this CPU. The scheduler calls this before entering a process.
+pub fn set_kernel_stack(top: u64) {
+    let Some(p) = this_cpu() else { return };
+    unsafe {
+        (*p).kernel_rsp = top;
+        if !(*p).tss.is_null() {
+            (*(*p).tss).privilege_stack_table[0] = VirtAddr::new(top);
+        }
+    }
+}
+
+/// The stack entries from ring 3 currently use on this CPU.
+pub fn kernel_stack() -> Option<u64> {
+    this_cpu().map(|p| unsafe { (*p).kernel_rsp })
+}
+
+/// Keeps the kernel GS base loaded for the duration of an interrupt or
+/// exception handler. If the user GS base was loaded when the handler was
+/// entered, creating the guard swaps GS in and dropping it swaps back
+/// before the `iretq`; otherwise it does nothing. Paths that never return to
+/// the interrupted code (a process killed by a fault) simply don't drop it.
+#[must_use]
+pub struct KernelGs {
+    swapped: bool,
+}
+
+/// Take the guard first thing in any handler that can interrupt ring 3.
+pub fn kernel_gs(_frame: &InterruptStackFrame) -> KernelGs {
+    // Not from CS: an NMI can land in the syscall stub before its swapgs,
+    // or after the one on the way out, in ring 0 with the user value loaded.
+    let swapped = !is_percpu_block(GsBase::read().as_u64()) && is_percpu_block(KernelGsBase::read().as_u64());
+    if swapped {
+        unsafe { core::arch::asm!("swapgs", options(nostack, preserves_flags)) };
+    }
+    KernelGs { swapped }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { core::arch::asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Performance monitoring counters
+//!
+//! Intel architectural PMU (CPUID leaf 0xA). Instructions retired and core
+//! cycles use the fixed counters when the CPU has them; other events take a
+//! general-purpose counter (IA32_PERFEVTSELx/IA32_PMCx). Counters count in
+//! ring 0 and ring 3 and are programmed on the CPU that starts them.
+//!
+//! Sampling mode preloads a counter with `-period` and has it raise a PMI on
+//! overflow through the LAPIC performance-counter LVT; the handler records
+//! the interrupted RIP and rearms. `print_profile` summarizes the samples.
+//!
+//! ```ignore
+//! let ((), misses) = pmu::measure(PmuEvent::LlcMisses, || vbe_print_only(text))?;
+//! ```
+
+use crate::prelude::*;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_FIXED_CTR0: u32 = 0x309;
+const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Vector the PMI is delivered on.
+pub const PMI_VECTOR: u8 = 0xF0;
+/// RIP samples kept; older ones are overwritten.
+pub const MAX_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmuEvent {
+    Instructions,
+    Cycles,
+    LlcReferences,
+    LlcMisses,
+    BranchInstructions,
+    BranchMisses,
+}
+
+impl PmuEvent {
+    /// (event select, unit mask) of the architectural event.
+    fn encoding(self) -> (u8, u8) {
+        match self {
+            PmuEvent::Instructions => (0xC0, 0x00),
+            PmuEvent::Cycles => (0x3C, 0x00),
+            PmuEvent::LlcReferences => (0x2E, 0x4F),
+            PmuEvent::LlcMisses => (0x2E, 0x41),
+            PmuEvent::BranchInstructions => (0xC4, 0x00),
+            PmuEvent::BranchMisses => (0xC5, 0x00),
+        }
+    }
+
+    /// Bit in CPUID.0AH:EBX that is set when the event is *not* available.
+    fn unavailable_bit(self) -> u32 {
+        match self {
+            PmuEvent::Cycles => 0,
+            PmuEvent::Instructions => 1,
+            PmuEvent::LlcReferences => 3,
+            PmuEvent::LlcMisses => 4,
+            PmuEvent::BranchInstructions => 5,
+            PmuEvent::BranchMisses => 6,
+        }
+    }
+
+    /// The fixed counter that counts this event, if any.
+    fn fixed_index(self) -> Option<u32> {
+        match self {
+            PmuEvent::Instructions => Some(0),
+            PmuEvent::Cycles => Some(1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PmuInfo {
+    pub version: u8,
+    pub gp_counters: u32,
+    pub gp_width: u32,
+    pub fixed_counters: u32,
+    pub fixed_width: u32,
+    /// CPUID.0AH:EBX: a set bit means the event is unavailable.
+    unavailable: u32,
+}
+
+impl PmuInfo {
+    pub fn supports(&self, event: PmuEvent) -> bool {
+        self.unavailable & (1 << event.unavailable_bit()) == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmuError {
+    /// No architectural PMU (or a hypervisor that hides it).
+    Unsupported,
+    EventUnsupported,
+    /// Every general-purpose counter is taken.
+    Busy,
+    /// Sampling needs the local APIC.
+    NoApic,
+}
+
+impl core::fmt::Display for PmuError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(match self {
+            PmuError::Unsupported => "no performance monitoring unit",
+            PmuError::EventUnsupported => "event not supported",
+            PmuError::Busy => "no free counter",
+            PmuError::NoApic => "local APIC not initialized",
+        })
+    }
+}
+
+static INFO: Once<Option<PmuInfo>> = Once::new();
+/// General-purpose counters in use, one bit each.
+static GP_USED: AtomicU32 = AtomicU32::new(0);
+/// Fixed counters in use, one bit each.
+static FIXED_USED: AtomicU32 = AtomicU32::new(0);
+
+/// Probe the PMU. Cached after the first call.
+pub fn pmu_info() -> Option<PmuInfo> {
+    *INFO.call_once(|| {
+        let max_leaf = unsafe { core::arch::x86_64::__cpuid(0) }.eax;
+        if max_leaf < 0xA {
+            return None;
+        }
+        let r = unsafe { core::arch::x86_64::__cpuid(0xA) };
+        let version = (r.eax & 0xFF) as u8;
+        if version == 0 {
+            return None;
+        }
+        let fixed_counters = if version >= 2 { r.edx & 0x1F } else { 0 };
+        Some(PmuInfo {
+            version,
+            gp_counters: (r.eax >> 8) & 0xFF,
+            gp_width: (r.eax >> 16) & 0xFF,
+            fixed_counters,
+            fixed_width: if version >= 2 { (r.edx >> 5) & 0xFF } else { 0 },
+            unavailable: r.ebx,
+        })
+    })
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    unsafe { Msr::new(msr).read() }
+}
+
+fn wrmsr(msr: u32, value: u64) {
+    unsafe { Msr::new(msr).write(value) }
+}
+
+/// Claim the lowest clear bit below `limit` in `used`.
+fn claim(used: &AtomicU32, limit: u32) -> Option<u32> {
+    let mut cur = used.load(Ordering::Relaxed);
+    loop {
+        let idx = (!cur).trailing_zeros();
+        if idx >= limit {
+            return None;
+        }
+        match used.compare_exchange(cur, cur | (1 << idx), Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return Some(idx),
+            Err(now) => cur = now,
+        }
+    }
+}
+
+fn release(used: &AtomicU32, idx: u32) {
+    used.fetch_and(!(1 << idx), Ordering::AcqRel);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Fixed(u32),
+    General(u32),
+}
+
+impl Slot {
+    fn counter_msr(self) -> u32 {
+        match self {
+            Slot::Fixed(i) => IA32_FIXED_CTR0 + i,
+            Slot::General(i) => IA32_PMC0 + i,
+        }
+    }
+
+    /// Bit in IA32_PERF_GLOBAL_CTRL/STATUS.
+    fn global_bit(self) -> u64 {
+        match self {
+            Slot::Fixed(i) => 1 << (32 + i),
+            Slot::General(i) => 1 << i,
+        }
+    }
+
+    fn width(self, info: &PmuInfo) -> u32 {
+        match self {
+            Slot::Fixed(_) => info.fixed_width,
+            Slot::General(_) => info.gp_width,
+        }
+    }
+
+    fn program(self, event: PmuEvent, interrupt: bool) {
+        match self {
+            Slot::Fixed(i) => {
+                // per counter: bit 0 ring 0, bit 1 ring 3, bit 3 PMI
+                let bits = 0b011 | if interrupt { 0b1000 } else { 0 };
+                let ctrl = rdmsr(IA32_FIXED_CTR_CTRL) & !(0xF << (i * 4));
+                wrmsr(IA32_FIXED_CTR_CTRL, ctrl | (bits << (i * 4)));
+            }
+            Slot::General(i) => {
+                let (sel, umask) = event.encoding();
+                let int = if interrupt { EVTSEL_INT } else { 0 };
+                wrmsr(IA32_PERFEVTSEL0 + i, sel as u64 | (umask as u64) << 8 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN | int);
+            }
+        }
+    }
+
+    fn clear(self) {
+        match self {
+            Slot::Fixed(i) => wrmsr(IA32_FIXED_CTR_CTRL, rdmsr(IA32_FIXED_CTR_CTRL) & !(0xF << (i * 4))),
+            Slot::General(i) => wrmsr(IA32_PERFEVTSEL0 + i, 0),
+        }
+    }
+
+    fn set_enabled(self, on: bool, info: &PmuInfo) {
+        if info.version >= 2 {
+            let ctrl = rdmsr(IA32_PERF_GLOBAL_CTRL);
+            wrmsr(IA32_PERF_GLOBAL_CTRL, if on { ctrl | self.global_bit() } else { ctrl & !self.global_bit() });
+        }
+    }
+
+    fn release(self) {
+        match self {
+            Slot::Fixed(i) => release(&FIXED_USED, i),
+            Slot::General(i) => release(&GP_USED, i),
+        }
+    }
+}
+
+fn acquire(event: PmuEvent) -> Result<(PmuInfo, Slot), PmuError> {
+    let info = pmu_info().ok_or(PmuError::Unsupported)?;
+    if !info.supports(event) {
+        return Err(PmuError::EventUnsupported);
+    }
+    if let Some(i) = event.fixed_index().filter(|&i| i < info.fixed_counters) {
+        if FIXED_USED.fetch_or(1 << i, Ordering::AcqRel) & (1 << i) == 0 {
+            return Ok((info, Slot::Fixed(i)));
+        }
+    }
+    let i = claim(&GP_USED, info.gp_counters).ok_or(PmuError::Busy)?;
+    Ok((info, Slot::General(i)))
+}
+
+/// A running counter. Dropping it stops the counter.
+pub struct Counter {
+    info: PmuInfo,
+    slot: Slot,
+    event: PmuEvent,
+}
+
+impl Counter {
+    /// Start counting `event` from zero on this CPU.
+    pub fn start(event: PmuEvent) -> Result<Counter, PmuError> {
+        let (info, slot) = acquire(event)?;
+        slot.set_enabled(false, &info);
+        wrmsr(slot.counter_msr(), 0);
+        slot.program(event, false);
+        slot.set_enabled(true, &info);
+        Ok(Counter { info, slot, event })
+    }
+
+    pub fn event(&self) -> PmuEvent {
+        self.event
+    }
+
+    /// Events counted so far.
+    pub fn read(&self) -> u64 {
+        rdmsr(self.slot.counter_msr()) & mask(self.slot.width(&self.info))
+    }
+
+    /// Stop and return the final count.
+    pub fn stop(self) -> u64 {
+        self.read()
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.slot.set_enabled(false, &self.info);
+        self.slot.clear();
+        self.slot.release();
+    }
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 { u64::MAX } else { (1u64 << width) - 1 }
+}
+
+/// Count `event` while `f` runs.
+pub fn measure<R>(event: PmuEvent, f: impl FnOnce() -> R) -> Result<(R, u64), PmuError> {
+    let counter = Counter::start(event)?;
+    let r = f();
+    Ok((r, counter.stop()))
+}
+
+// Sampling state; one session at a time. The counter MSR, reload value and
+// overflow bit are copied into atomics so the PMI handler takes no locks.
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+static SAMPLE_SLOT: Mutex<Option<(PmuInfo, Slot)>> = Mutex::new(None);
+static SAMPLE_HEAD: AtomicUsize = AtomicUsize::new(0);
+static SAMPLES: [AtomicU64; MAX_SAMPLES] = [const { AtomicU64::new(0) }; MAX_SAMPLES];
+static SAMPLE_MSR: AtomicU32 = AtomicU32::new(0);
+static SAMPLE_RELOAD: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_GLOBAL_BIT: AtomicU64 = AtomicU64::new(0);
+
+/// Take a RIP sample every `period` occurrences of `event` on this CPU.
+pub fn start_sampling(event: PmuEvent, period: u64) -> Result<(), PmuError> {
+    if !crate::hal::apic::is_initialized() {
+        return Err(PmuError::NoApic);
+    }
+    if SAMPLING.swap(true, Ordering::AcqRel) {
+        return Err(PmuError::Busy);
+    }
+    let (info, slot) = match acquire(event) {
+        Ok(x) => x,
+        Err(e) => {
+            SAMPLING.store(false, Ordering::Release);
+            return Err(e);
+        }
+    };
+    let reload = period.max(1).wrapping_neg() & mask(slot.width(&info));
+    SAMPLE_HEAD.store(0, Ordering::Relaxed);
+    SAMPLE_MSR.store(slot.counter_msr(), Ordering::Relaxed);
+    SAMPLE_RELOAD.store(reload, Ordering::Relaxed);
+    SAMPLE_GLOBAL_BIT.store(slot.global_bit(), Ordering::Relaxed);
+    interrupts::without_interrupts(|| *SAMPLE_SLOT.lock() = Some((info, slot)));
+
+    crate::arch::idt::register_irq_handler(PMI_VECTOR, pmi_handler);
+    crate::hal::apic::set_lvt_perf(PMI_VECTOR, false);
+    slot.set_enabled(false, &info);
+    wrmsr(slot.counter_msr(), reload);
+    slot.program(event, true);
+    slot.set_enabled(true, &info);
+    Ok(())
+}
+
+/// Stop sampling. Returns the number of samples taken.
+pub fn stop_sampling() -> usize {
+    let Some((info, slot)) = interrupts::without_interrupts(|| SAMPLE_SLOT.lock().take()) else {
+        return 0;
+    };
+    slot.set_enabled(false, &info);
+    slot.clear();
+    slot.release();
+    crate::hal::apic::set_lvt_perf(PMI_VECTOR, true);
+    crate::arch::idt::unregister_irq_handler(PMI_VECTOR);
+    SAMPLING.store(false, Ordering::Release);
+    SAMPLE_HEAD.load(Ordering::Relaxed)
+}
+
+extern "x86-interrupt" fn pmi_handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    crate::arch::idt::note_irq(PMI_VECTOR);
+    let n = SAMPLE_HEAD.fetch_add(1, Ordering::Relaxed);
+    SAMPLES[n % MAX_SAMPLES].store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    // rearm, acknowledge the overflow, and unmask the LVT (a PMI masks it)
+    wrmsr(SAMPLE_MSR.load(Ordering::Relaxed), SAMPLE_RELOAD.load(Ordering::Relaxed));
+    if pmu_info().is_some_and(|i| i.version >= 2) {
+        let status = rdmsr(IA32_PERF_GLOBAL_STATUS);
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, status & SAMPLE_GLOBAL_BIT.load(Ordering::Relaxed));
+    }
+    crate::hal::apic::set_lvt_perf(PMI_VECTOR, false);
+    crate::hal::apic::send_eoi();
+    crate::arch::idt::irq_exit(&stack_frame);
+}
+
+/// Print the `top` most frequently sampled addresses.
+pub fn print_profile(top: usize) {
+    let taken = SAMPLE_HEAD.load(Ordering::Relaxed);
+    let n = taken.min(MAX_SAMPLES);
+    let mut rips: alloc::vec::Vec<u64> = (0..n).map(|i| SAMPLES[i].load(Ordering::Relaxed)).collect();
+    rips.sort_unstable();
+    let mut counts: alloc::vec::Vec<(u64, usize)> = alloc::vec::Vec::new();
+    for rip in rips {
+        match counts.last_mut() {
+            Some((last, c)) if *last == rip => *c += 1,
+            _ => counts.push((rip, 1)),
+        }
+    }
+    counts.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    println!("[PMU] {} samples ({} kept)", taken, n);
+    for &(rip, count) in counts.iter().take(top) {
+        print!("{:>6} {:>3}%", count, count * 100 / n.max(1));
+        crate::arch::panic::print_address(rip);
+    }
+}
+
+/// Log what the PMU offers. Called once at boot.
+pub fn init_pmu() {
+    match pmu_info() {
+        Some(i) => println!(
+            "[PMU] version {}: {} general counters ({} bits), {} fixed ({} bits)",
+            i.version, i.gp_counters, i.gp_width, i.fixed_counters, i.fixed_width
+        ),
+        None => println!("[PMU] no architectural performance monitoring"),
+    }
+}
@@ -3,7 +3,9 @@ use x86_64::registers::control::Cr0Flags;
 use x86_64::registers::control::Cr4Flags;
 use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
 use core::arch::x86_64::{__cpuid, __cpuid_count};
-use crate::*;
+use crate::prelude::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::arch::idt::hlt;
 
 pub fn enable_sse() {
     unsafe {
@@ -123,6 +125,8 @@ pub struct CpuFeatures {
     pub ptsc: bool,
     pub perfctr_llc: bool,
     pub mwaitx: bool,
+    // Extended leaf 0x80000007 EDX
+    pub invariant_tsc: bool,
     // EAX=7, EBX
     pub fsgsbase: bool,
     pub tsc_adjust: bool,
@@ -249,6 +253,10 @@ pub fn detect_cpu_features() -> CpuFeatures {
             features.perfctr_llc = (ecx & (1 << 28)) != 0;
             features.mwaitx = (ecx & (1 << 29)) != 0;
         }
+        if max_extended >= 0x80000007 {
+            // the TSC runs at a constant rate in every P-, C- and T-state
+            features.invariant_tsc = (__cpuid(0x80000007).edx & (1 << 8)) != 0;
+        }
         // Basic features
         let result = __cpuid(1);
         let ecx = result.ecx;
@@ -383,6 +391,177 @@ pub fn detect_cpu_features() -> CpuFeatures {
     features
 }
 
+/// What a cache holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// One cache level as CPUID describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// Total size in bytes.
+    pub size: usize,
+    pub line_size: usize,
+    /// 0 if fully associative or not reported.
+    pub ways: usize,
+    pub sets: usize,
+}
+
+/// Line size assumed when CPUID doesn't give one.
+const DEFAULT_CACHE_LINE: usize = 64;
+static CACHE_LINE: AtomicUsize = AtomicUsize::new(0);
+
+/// The caches of this CPU, from the deterministic cache parameters leaf
+/// (4 on Intel, 0x8000001D on AMD with TOPOEXT) or, failing that, AMD's
+/// legacy L1/L2/L3 leaves 0x80000005 and 0x80000006.
+pub fn detect_caches() -> Vec<CacheInfo> {
+    let max_basic = unsafe { __cpuid(0) }.eax;
+    let max_extended = unsafe { __cpuid(0x80000000) }.eax;
+    let topoext = max_extended >= 0x80000001 && unsafe { __cpuid(0x80000001) }.ecx & (1 << 22) != 0;
+    let mut caches = Vec::new();
+    if topoext && max_extended >= 0x8000001D {
+        caches = deterministic_caches(0x8000001D);
+    }
+    if caches.is_empty() && max_basic >= 4 {
+        caches = deterministic_caches(4);
+    }
+    if caches.is_empty() {
+        caches = legacy_amd_caches(max_extended);
+    }
+    caches
+}
+
+fn deterministic_caches(leaf: u32) -> Vec<CacheInfo> {
+    let mut caches = Vec::new();
+    // subleaves end at the first with cache type 0
+    for subleaf in 0..16 {
+        let r = unsafe { __cpuid_count(leaf, subleaf) };
+        let kind = match r.eax & 0x1F {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => break,
+        };
+        let line_size = (r.ebx & 0xFFF) as usize + 1;
+        let partitions = (r.ebx >> 12 & 0x3FF) as usize + 1;
+        let ways = (r.ebx >> 22) as usize + 1;
+        let sets = r.ecx as usize + 1;
+        let fully_associative = r.eax & (1 << 9) != 0;
+        caches.push(CacheInfo {
+            level: (r.eax >> 5 & 0x7) as u8,
+            kind,
+            size: ways * partitions * line_size * sets,
+            line_size,
+            ways: if fully_associative { 0 } else { ways },
+            sets,
+        });
+    }
+    caches
+}
+
+/// Ways for the 4-bit associativity code of leaf 0x80000006; 0 for fully
+/// associative and the reserved codes.
+fn amd_ways(code: u32) -> usize {
+    match code {
+        1 => 1,
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        5 => 6,
+        6 => 8,
+        8 => 16,
+        0xA => 32,
+        0xB => 48,
+        0xC => 64,
+        0xD => 96,
+        0xE => 128,
+        _ => 0,
+    }
+}
+
+fn legacy_amd_caches(max_extended: u32) -> Vec<CacheInfo> {
+    let cache = |level, kind, size: usize, line_size: usize, ways: usize| CacheInfo {
+        level,
+        kind,
+        size,
+        line_size,
+        ways,
+        sets: if ways == 0 || line_size == 0 { 1 } else { size / (line_size * ways) },
+    };
+    let mut caches = Vec::new();
+    if max_extended >= 0x80000005 {
+        let r = unsafe { __cpuid(0x80000005) };
+        // L1: size in KiB in bits 31:24, ways in 23:16 (0xFF = fully), line size in 7:0
+        for (reg, kind) in [(r.ecx, CacheKind::Data), (r.edx, CacheKind::Instruction)] {
+            let ways = (reg >> 16 & 0xFF) as usize;
+            if reg >> 24 != 0 {
+                caches.push(cache(1, kind, (reg >> 24) as usize * 1024, (reg & 0xFF) as usize, if ways == 0xFF { 0 } else { ways }));
+            }
+        }
+    }
+    if max_extended >= 0x80000006 {
+        let r = unsafe { __cpuid(0x80000006) };
+        // L2 size in KiB in ECX 31:16, L3 in 512 KiB units in EDX 31:18;
+        // associativity code in 15:12 (0 = no cache), line size in 7:0
+        if r.ecx >> 12 & 0xF != 0 {
+            caches.push(cache(2, CacheKind::Unified, (r.ecx >> 16) as usize * 1024, (r.ecx & 0xFF) as usize, amd_ways(r.ecx >> 12 & 0xF)));
+        }
+        if r.edx >> 12 & 0xF != 0 {
+            caches.push(cache(3, CacheKind::Unified, (r.edx >> 18) as usize * 512 * 1024, (r.edx & 0xFF) as usize, amd_ways(r.edx >> 12 & 0xF)));
+        }
+    }
+    caches
+}
+
+/// Bytes CLFLUSH flushes, from CPUID.1 EBX[15:8]; 64 when the CPU doesn't
+/// report it.
+pub fn clflush_line_size() -> usize {
+    let r = unsafe { __cpuid(1) };
+    if r.edx & (1 << 19) == 0 {
+        return DEFAULT_CACHE_LINE;
+    }
+    match (r.ebx >> 8 & 0xFF) as usize * 8 {
+        0 => DEFAULT_CACHE_LINE,
+        size => size,
+    }
+}
+
+/// The CLFLUSH line size, read once. What descriptors shared with devices
+/// are aligned to, and the step of cache flush loops.
+pub fn cache_line_size() -> usize {
+    match CACHE_LINE.load(Ordering::Relaxed) {
+        0 => {
+            let size = clflush_line_size();
+            CACHE_LINE.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+/// Write back and invalidate every cache line holding part of `len` bytes
+/// at `addr`, and wait for it to finish.
+pub fn flush_cache_range(addr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let line = cache_line_size();
+    let end = addr as usize + len;
+    let mut p = addr as usize & !(line - 1);
+    unsafe {
+        while p < end {
+            core::arch::x86_64::_mm_clflush(p as *const u8);
+            p += line;
+        }
+        core::arch::x86_64::_mm_mfence();
+    }
+}
+
 pub fn disable_pit_timer() {
     use crate::arch::ports::*;
     unsafe {
@@ -561,4 +740,19 @@ pub fn enable_cpu_features(features: &CpuFeatures) {
     if features.popcnt {
         println!("[CPU] Enabled POPCNT");
     }
-}
\ No newline at end of file
+}
+/// Flush the filesystems and reset the machine.
+pub fn reboot() -> ! {
+    println!("rebooting");
+    let _ = crate::fs::vfs::sync_all();
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        // pulse the CPU reset line through the keyboard controller
+        crate::arch::ports::outb(0x64, 0xFE);
+        // failing that, triple fault: no IDT, then an exception
+        let empty = x86_64::structures::DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::new(0) };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    hlt();
+}
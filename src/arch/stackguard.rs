@@ -0,0 +1,76 @@
+//! Stack overflow detection
+//!
+//! Two checks, both ending in a panic that says where the damage was:
+//!
+//! - Stack protector: built with `-Z stack-protector=strong`, functions
+//!   holding arrays or borrowed locals copy `__stack_chk_guard` below their
+//!   return address and compare it before returning; a mismatch calls
+//!   `__stack_chk_fail`. The guard starts as a fixed terminator value and
+//!   `randomize_stack_guard` replaces it once the generator is seeded.
+//! - Canary words: the lowest word of every IST and entry stack holds
+//!   `STACK_CANARY`. A frame that runs off the bottom overwrites it, and the
+//!   scheduler checks each time it switches into or out of a process; the
+//!   double fault handler checks too, since an overflow is the usual cause.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+
+/// Written at the base of each kernel stack.
+pub const STACK_CANARY: u64 = 0x57AC_C0DE_CA4A_12E5;
+
+/// Read by every protected function. The NUL, newline and 0xff bytes stop
+/// string copies that run over it until it is randomized.
+#[unsafe(no_mangle)]
+#[allow(non_upper_case_globals)]
+pub static mut __stack_chk_guard: u64 = 0x0000_0aff_0a0d_ff00;
+
+/// Called by a protected function whose copy of the guard was overwritten.
+#[unsafe(no_mangle)]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    // the caller is the function whose frame was smashed
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    let ret = unsafe { *((rbp + 8) as *const u64) };
+    panic!("stack smashing detected in {}", crate::symbols::Sym(ret));
+}
+
+/// Replace the boot-time guard with a random one. Frames live at the time
+/// still hold the old value and fail their check if they return, so this
+/// is called straight from `kernel_main`, which never does, before other
+/// CPUs or tasks run. Not protected itself: it has no arrays or borrowed
+/// locals.
+#[inline(never)]
+pub fn randomize_stack_guard() {
+    let guard = crate::random::u64() & !0xFF;
+    unsafe { (&raw mut __stack_chk_guard).write_volatile(guard) };
+}
+
+/// Mark the stack whose lowest address is `base`.
+///
+/// # Safety
+/// `base` must be the bottom of a stack nothing has grown down to yet.
+pub(crate) unsafe fn place_canary(base: u64) {
+    unsafe { (base as *mut u64).write_volatile(STACK_CANARY) };
+}
+
+fn canary_intact(base: u64) -> bool {
+    unsafe { (base as *const u64).read_volatile() == STACK_CANARY }
+}
+
+/// Panic if a stack of `cpu` has run over its base.
+pub fn check_stack_canaries(cpu: usize) {
+    if cpu >= MAX_CPUS {
+        return;
+    }
+    let stacks = [
+        ("entry", crate::arch::percpu::entry_stack_base(cpu)),
+        ("double fault", crate::arch::gdt::double_fault_stack_base(cpu)),
+        ("NMI", crate::arch::gdt::nmi_stack_base(cpu)),
+    ];
+    for (name, base) in stacks {
+        if !canary_intact(base) {
+            let found = unsafe { (base as *const u64).read_volatile() };
+            panic!("stack overflow: cpu{} {} stack ran past {:#x} (canary {:#x})", cpu, name, base, found);
+        }
+    }
+}
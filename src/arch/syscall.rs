@@ -0,0 +1,668 @@
+//! System call interface
+//!
+//! User code enters the kernel with `syscall`. The ABI follows the usual
+//! x86-64 convention: the call number goes in `rax`, up to six arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`, and the result comes back in
+//! `rax`, with failures as a negative errno. `rcx` and `r11` are clobbered
+//! by the instruction itself; every other register is preserved.
+//!
+//! Numbers are stable; new calls are only ever appended to the table.
+//!
+//! | nr | call  | arguments                  | returns          |
+//! |----|-------|----------------------------|------------------|
+//! | 0  | exit  | status                     | does not return  |
+//! | 1  | read  | fd, buf, len               | bytes read       |
+//! | 2  | write | fd, buf, len               | bytes written    |
+//! | 3  | open  | path, path_len, flags      | fd               |
+//! | 4  | close | fd                         | 0                |
+//! | 5  | sleep | milliseconds               | 0                |
+//! | 6  | spawn | path, path_len, argv, envp | process id       |
+//! | 7  | brk   | new break (0 to query)     | break afterwards |
+//! | 8  | mmap  | hint, len, prot, flags     | address          |
+//! | 9  | munmap| addr, len                  | 0                |
+//! | 10 | fork  |                            | child pid / 0    |
+//! | 11 | exec  | path, path_len, argv, envp | 0 in new image   |
+//! | 12 | wait  | pid (-1 for any), status*  | child pid        |
+//! | 13 | kill  | pid, signal                | 0                |
+//! | 14 | sigaction | signal, handler, restorer | old handler   |
+//! | 15 | sigreturn |                        | does not return  |
+//!
+//! `argv` and `envp` are NULL-terminated arrays of pointers to
+//! NUL-terminated strings, as for `execve`; a null `argv` means just the
+//! path, a null `envp` an empty environment.
+//!
+//! Pointer arguments are checked against the active page tables before the
+//! kernel touches them; demand-paged memory the caller hasn't used yet is
+//! filled in first. Every call is counted, and with auditing on each one
+//! is logged with its arguments and result.
+
+use crate::prelude::*;
+use crate::arch::idt::hlt;
+use crate::fs::fd::{kernel_fds, FdTable};
+use crate::fs::vfs::{OpenOptions, VfsError};
+use crate::arch::usermode::{FpuState, UserContext};
+use crate::memory::paging::{user_range_accessible, USER_SPACE_END};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_READ: u64 = 1;
+pub const SYS_WRITE: u64 = 2;
+pub const SYS_OPEN: u64 = 3;
+pub const SYS_CLOSE: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_SPAWN: u64 = 6;
+pub const SYS_BRK: u64 = 7;
+pub const SYS_MMAP: u64 = 8;
+pub const SYS_MUNMAP: u64 = 9;
+pub const SYS_FORK: u64 = 10;
+pub const SYS_EXEC: u64 = 11;
+pub const SYS_WAIT: u64 = 12;
+pub const SYS_KILL: u64 = 13;
+pub const SYS_SIGACTION: u64 = 14;
+pub const SYS_SIGRETURN: u64 = 15;
+
+/// `open` flags.
+pub const O_READ: u64 = 1 << 0;
+pub const O_WRITE: u64 = 1 << 1;
+pub const O_CREATE: u64 = 1 << 2;
+pub const O_TRUNC: u64 = 1 << 3;
+pub const O_APPEND: u64 = 1 << 4;
+const O_ALL: u64 = O_READ | O_WRITE | O_CREATE | O_TRUNC | O_APPEND;
+
+/// `mmap` protection and flags. Only private anonymous mappings exist.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Error numbers, returned negated. Values match Linux.
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EINTR: i64 = 4;
+pub const EIO: i64 = 5;
+pub const E2BIG: i64 = 7;
+pub const ENOEXEC: i64 = 8;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOSPC: i64 = 28;
+pub const EROFS: i64 = 30;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const ENOTSOCK: i64 = 88;
+
+/// Longest path accepted by `open` and `spawn`.
+pub const PATH_MAX: usize = 4096;
+/// Largest single read or write; longer requests are cut short.
+pub const IO_MAX: usize = 1 << 20;
+
+pub type SysResult = Result<u64, i64>;
+
+/// Registers saved by the entry stub, lowest address first. Handlers see
+/// the arguments here, may rewrite the user state (`exec` does), and the
+/// stub returns `rax` to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    /// The caller's FPU state, put back on return.
+    pub fpu: FpuState,
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// User RFLAGS, restored by `sysret`.
+    pub rflags: u64,
+    /// User return address, restored by `sysret`.
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// The caller's registers as they will be after the call returns
+    /// `rax`. `rcx` and `r11` hold what `syscall` left in them.
+    pub fn user_context(&self, rax: u64) -> UserContext {
+        UserContext {
+            rax,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+            fpu: self.fpu,
+        }
+    }
+
+    /// Restart user code from `ctx` when the call returns. `rcx` and `r11`
+    /// are lost to `sysret`, and `rax` is overwritten by the result.
+    pub fn set_user_context(&mut self, ctx: &UserContext) {
+        self.rdi = ctx.rdi;
+        self.rsi = ctx.rsi;
+        self.rdx = ctx.rdx;
+        self.r10 = ctx.r10;
+        self.r8 = ctx.r8;
+        self.r9 = ctx.r9;
+        self.rbx = ctx.rbx;
+        self.rbp = ctx.rbp;
+        self.r12 = ctx.r12;
+        self.r13 = ctx.r13;
+        self.r14 = ctx.r14;
+        self.r15 = ctx.r15;
+        self.rflags = ctx.rflags;
+        self.rip = ctx.rip;
+        self.rsp = ctx.rsp;
+        self.fpu = ctx.fpu;
+    }
+}
+
+struct SyscallEntry {
+    name: &'static str,
+    /// Arguments shown in the audit log.
+    argc: usize,
+    handler: fn(&mut SyscallFrame, &[u64; 6]) -> SysResult,
+}
+
+static SYSCALLS: [SyscallEntry; 16] = [
+    SyscallEntry { name: "exit", argc: 1, handler: sys_exit },
+    SyscallEntry { name: "read", argc: 3, handler: sys_read },
+    SyscallEntry { name: "write", argc: 3, handler: sys_write },
+    SyscallEntry { name: "open", argc: 3, handler: sys_open },
+    SyscallEntry { name: "close", argc: 1, handler: sys_close },
+    SyscallEntry { name: "sleep", argc: 1, handler: sys_sleep },
+    SyscallEntry { name: "spawn", argc: 4, handler: sys_spawn },
+    SyscallEntry { name: "brk", argc: 1, handler: sys_brk },
+    SyscallEntry { name: "mmap", argc: 4, handler: sys_mmap },
+    SyscallEntry { name: "munmap", argc: 2, handler: sys_munmap },
+    SyscallEntry { name: "fork", argc: 0, handler: sys_fork },
+    SyscallEntry { name: "exec", argc: 4, handler: sys_exec },
+    SyscallEntry { name: "wait", argc: 2, handler: sys_wait },
+    SyscallEntry { name: "kill", argc: 2, handler: sys_kill },
+    SyscallEntry { name: "sigaction", argc: 3, handler: sys_sigaction },
+    SyscallEntry { name: "sigreturn", argc: 0, handler: sys_sigreturn },
+];
+
+static COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+static UNKNOWN: AtomicU64 = AtomicU64::new(0);
+static AUDIT: AtomicBool = AtomicBool::new(false);
+
+/// Called by `exit`. Installed by the process layer; until then the
+/// calling context is parked.
+static EXIT_HOOK: Mutex<Option<fn(i32) -> !>> = Mutex::new(None);
+/// Called by `spawn` with the program path, arguments and environment.
+/// Installed by the process layer.
+static SPAWN_HOOK: Mutex<Option<SpawnHook>> = Mutex::new(None);
+
+pub type SpawnHook = fn(&str, &[&str], &[&str]) -> SysResult;
+
+pub fn set_exit_hook(hook: fn(i32) -> !) {
+    interrupts::without_interrupts(|| *EXIT_HOOK.lock() = Some(hook));
+}
+
+pub fn set_spawn_hook(hook: SpawnHook) {
+    interrupts::without_interrupts(|| *SPAWN_HOOK.lock() = Some(hook));
+}
+
+/// Log every call with its arguments and result.
+pub fn set_syscall_audit(on: bool) {
+    AUDIT.store(on, Ordering::Relaxed);
+}
+
+pub fn syscall_audit() -> bool {
+    AUDIT.load(Ordering::Relaxed)
+}
+
+/// (name, calls) for every entry in the table, plus unknown numbers.
+pub fn syscall_counts() -> alloc::vec::Vec<(&'static str, u64)> {
+    let mut out: alloc::vec::Vec<_> =
+        SYSCALLS.iter().zip(COUNTS.iter()).map(|(e, c)| (e.name, c.load(Ordering::Relaxed))).collect();
+    out.push(("unknown", UNKNOWN.load(Ordering::Relaxed)));
+    out
+}
+
+pub fn errno_for(e: VfsError) -> i64 {
+    match e {
+        VfsError::NotFound | VfsError::NotMounted => ENOENT,
+        VfsError::NotADirectory => ENOTDIR,
+        VfsError::IsADirectory => EISDIR,
+        VfsError::Exists | VfsError::AlreadyMounted => EEXIST,
+        VfsError::NotEmpty => ENOTEMPTY,
+        VfsError::InvalidPath => EINVAL,
+        VfsError::ReadOnly => EROFS,
+        VfsError::NoSpace => ENOSPC,
+        VfsError::BadMode | VfsError::BadFd => EBADF,
+        VfsError::TooManyFiles => EMFILE,
+        VfsError::NotASocket => ENOTSOCK,
+        VfsError::WouldBlock => EAGAIN,
+        VfsError::Interrupted => EINTR,
+        VfsError::Unsupported => ENOSYS,
+        VfsError::Io => EIO,
+    }
+}
+
+// `syscall` leaves the user stack in place and masks IF via SFMASK, so the
+// stub can swap in the kernel GS base and switch to this CPU's entry stack
+// (see `percpu`) before anything else runs. Handlers may enable
+// interrupts, so they are masked again before GS is swapped back.
+global_asm!(
+    ".global neutrix_syscall_entry",
+    "neutrix_syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_rsp}]",
+    "push qword ptr gs:[{user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push rbp",
+    "push rbx",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "sub rsp, 512",
+    "fxsave64 [rsp]",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "cli",
+    "fxrstor64 [rsp]",
+    "add rsp, 512",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rbx",
+    "pop rbp",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    user_rsp = const crate::arch::percpu::PERCPU_USER_RSP,
+    kernel_rsp = const crate::arch::percpu::PERCPU_KERNEL_RSP,
+    dispatch = sym syscall_dispatch,
+);
+
+unsafe extern "C" {
+    fn neutrix_syscall_entry();
+}
+
+/// Enable `syscall`/`sysret` and point them at the entry stub. Needs the
+/// GDT loaded.
+pub fn init_syscalls() {
+    match init_cpu_syscalls() {
+        Ok(()) => println!("[SYSCALL] entry at {:#x}, {} calls", neutrix_syscall_entry as usize, SYSCALLS.len()),
+        Err(e) => println!("[SYSCALL] bad GDT layout: {}", e),
+    }
+}
+
+/// Program the calling CPU's syscall MSRs, as `init_syscalls` does for the
+/// BSP. Application processors run this during bring-up.
+pub fn init_cpu_syscalls() -> Result<(), String> {
+    Star::write(
+        crate::arch::gdt::user_code_selector(),
+        crate::arch::gdt::user_data_selector(),
+        crate::arch::gdt::kernel_code_selector(),
+        crate::arch::gdt::kernel_data_selector(),
+    )
+    .map_err(|e| e.to_string())?;
+    LStar::write(VirtAddr::new(neutrix_syscall_entry as usize as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|f| f.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    Ok(())
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    crate::process::syscall_begin();
+    let nr = frame.rax;
+    let args = frame.args();
+    let result = match SYSCALLS.get(nr as usize) {
+        Some(entry) => {
+            COUNTS[nr as usize].fetch_add(1, Ordering::Relaxed);
+            let r = (entry.handler)(frame, &args);
+            if syscall_audit() {
+                audit(entry, &args, r);
+            }
+            r
+        }
+        None => {
+            UNKNOWN.fetch_add(1, Ordering::Relaxed);
+            if syscall_audit() {
+                println!("[SYSCALL] unknown #{} = -ENOSYS", nr);
+            }
+            Err(ENOSYS)
+        }
+    };
+    trace!(crate::trace::Subsys::Syscall, "#{} = {:?}", nr, result);
+    frame.rax = match result {
+        Ok(v) => v,
+        Err(e) => (-e) as u64,
+    };
+    crate::process::deliver_on_syscall_return(frame);
+    crate::process::syscall_end();
+}
+
+fn audit(entry: &SyscallEntry, args: &[u64; 6], result: SysResult) {
+    use core::fmt::Write;
+    let mut line = alloc::string::String::new();
+    for (i, a) in args[..entry.argc].iter().enumerate() {
+        let _ = write!(line, "{}{:#x}", if i == 0 { "" } else { ", " }, a);
+    }
+    match result {
+        Ok(v) => println!("[SYSCALL] {}({}) = {}", entry.name, line, v),
+        Err(e) => println!("[SYSCALL] {}({}) = -{}", entry.name, line, e),
+    }
+}
+
+/// Check that `[ptr, ptr + len)` is user memory the caller may access.
+fn check_user(ptr: u64, len: usize, write: bool) -> Result<(), i64> {
+    if ptr == 0 || ptr >= USER_SPACE_END {
+        return Err(EFAULT);
+    }
+    if let Some(proc) = crate::process::current_process() {
+        proc.with_space(|space| space.populate(ptr, len as u64, write));
+    }
+    let offset = VirtAddr::new(crate::driver_framework::drivers::vbe_vga::get_boot_phys_offset());
+    if user_range_accessible(offset, ptr, len as u64, write) { Ok(()) } else { Err(EFAULT) }
+}
+
+fn user_slice<'a>(ptr: u64, len: usize) -> Result<&'a [u8], i64> {
+    check_user(ptr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+fn user_slice_mut<'a>(ptr: u64, len: usize) -> Result<&'a mut [u8], i64> {
+    check_user(ptr, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+}
+
+/// Copy a path out of user memory.
+fn user_path(ptr: u64, len: u64) -> Result<alloc::string::String, i64> {
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    if len as usize > PATH_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    let bytes = user_slice(ptr, len as usize)?;
+    core::str::from_utf8(bytes).map(alloc::string::String::from).map_err(|_| EINVAL)
+}
+
+/// Copy a NUL-terminated string of at most `max` bytes (NUL included) out
+/// of user memory, a page at a time so no byte past the NUL is touched.
+fn user_cstr(ptr: u64, max: usize) -> Result<alloc::string::String, i64> {
+    let mut out = alloc::vec::Vec::new();
+    let mut at = ptr;
+    loop {
+        let room = max.saturating_sub(out.len());
+        if room == 0 {
+            return Err(E2BIG);
+        }
+        let in_page = (4096 - at % 4096) as usize;
+        let chunk = user_slice(at, in_page.min(room))?;
+        if let Some(n) = chunk.iter().position(|&b| b == 0) {
+            out.extend_from_slice(&chunk[..n]);
+            break;
+        }
+        out.extend_from_slice(chunk);
+        at += chunk.len() as u64;
+    }
+    alloc::string::String::from_utf8(out).map_err(|_| EINVAL)
+}
+
+/// Copy a NULL-terminated array of string pointers. A null array is empty.
+/// The strings and pointers are charged to `budget`.
+fn user_string_array(ptr: u64, budget: &mut usize) -> Result<alloc::vec::Vec<alloc::string::String>, i64> {
+    let mut out = alloc::vec::Vec::new();
+    if ptr == 0 {
+        return Ok(out);
+    }
+    let mut at = ptr;
+    loop {
+        *budget = budget.checked_sub(8).ok_or(E2BIG)?;
+        let mut word = [0u8; 8];
+        word.copy_from_slice(user_slice(at, 8)?);
+        let s = match u64::from_le_bytes(word) {
+            0 => return Ok(out),
+            p => user_cstr(p, *budget)?,
+        };
+        *budget -= s.len() + 1;
+        out.push(s);
+        at += 8;
+    }
+}
+
+/// The `argv` and `envp` of `spawn` and `exec`, limited together to
+/// `ARG_MAX`. A null `argv` stands for `[path]`.
+fn user_args(path: &str, argv: u64, envp: u64) -> Result<(alloc::vec::Vec<alloc::string::String>, alloc::vec::Vec<alloc::string::String>), i64> {
+    let mut budget = crate::process::ARG_MAX;
+    let argv = match argv {
+        0 => alloc::vec![alloc::string::String::from(path)],
+        p => user_string_array(p, &mut budget)?,
+    };
+    let envp = user_string_array(envp, &mut budget)?;
+    Ok((argv, envp))
+}
+
+/// Run `f` on the caller's descriptor table: the running process's, or
+/// the kernel's when no process is running.
+fn with_fds<R>(f: impl FnOnce(&mut FdTable) -> R) -> R {
+    match crate::process::current_process() {
+        Some(proc) => f(&mut proc.fds().lock()),
+        None => f(&mut kernel_fds().lock()),
+    }
+}
+
+fn sys_exit(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let status = args[0] as i32;
+    let hook = interrupts::without_interrupts(|| *EXIT_HOOK.lock());
+    match hook {
+        Some(exit) => exit(status),
+        None => {
+            println!("[SYSCALL] exit({}) with no process layer; halting", status);
+            hlt();
+        }
+    }
+}
+
+fn sys_read(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let len = core::cmp::min(args[2] as usize, IO_MAX);
+    let buf = user_slice_mut(args[1], len)?;
+    let n = with_fds(|fds| fds.read(args[0] as usize, buf)).map_err(errno_for)?;
+    Ok(n as u64)
+}
+
+fn sys_write(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let len = core::cmp::min(args[2] as usize, IO_MAX);
+    let buf = user_slice(args[1], len)?;
+    let n = with_fds(|fds| fds.write(args[0] as usize, buf)).map_err(errno_for)?;
+    Ok(n as u64)
+}
+
+fn sys_open(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let path = user_path(args[0], args[1])?;
+    let flags = args[2];
+    if flags & !O_ALL != 0 || flags & (O_READ | O_WRITE) == 0 {
+        return Err(EINVAL);
+    }
+    let options = OpenOptions {
+        read: flags & O_READ != 0,
+        write: flags & O_WRITE != 0,
+        create: flags & O_CREATE != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+    };
+    let fd = with_fds(|fds| fds.open(&path, options)).map_err(errno_for)?;
+    Ok(fd as u64)
+}
+
+fn sys_close(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    with_fds(|fds| fds.close(args[0] as usize)).map_err(errno_for)?;
+    Ok(0)
+}
+
+fn sys_sleep(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let deadline = crate::time::Deadline::after_ms(args[0]);
+    // Nothing else can run on this stack yet, so wait here with interrupts
+    // on and let device and timer work proceed.
+    interrupts::enable();
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+    interrupts::disable();
+    Ok(0)
+}
+
+fn sys_spawn(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let path = user_path(args[0], args[1])?;
+    let (argv, envp) = user_args(&path, args[2], args[3])?;
+    let argv: alloc::vec::Vec<&str> = argv.iter().map(|s| s.as_str()).collect();
+    let envp: alloc::vec::Vec<&str> = envp.iter().map(|s| s.as_str()).collect();
+    let hook = interrupts::without_interrupts(|| *SPAWN_HOOK.lock());
+    match hook {
+        Some(spawn) => spawn(&path, &argv, &envp),
+        None => Err(ENOSYS),
+    }
+}
+
+/// The running process, for calls that only make sense inside one.
+fn caller() -> Result<alloc::sync::Arc<crate::process::Process>, i64> {
+    crate::process::current_process().ok_or(ESRCH)
+}
+
+fn sys_brk(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let proc = caller()?;
+    let brk = proc.with_space(|space| if args[0] == 0 { space.brk() } else { space.set_brk(args[0]) });
+    brk.ok_or(ESRCH)
+}
+
+fn sys_mmap(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let (len, prot, flags) = (args[1], args[2], args[3]);
+    if flags != MAP_PRIVATE | MAP_ANONYMOUS || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || len == 0 {
+        return Err(EINVAL);
+    }
+    let proc = caller()?;
+    let ceiling = crate::process::MMAP_CEILING;
+    let addr = proc.with_space(|space| space.mmap_anonymous(len, prot & PROT_WRITE != 0, ceiling)).ok_or(ESRCH)?;
+    addr.map_err(|e| e.errno())
+}
+
+fn sys_munmap(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let proc = caller()?;
+    let result = proc.with_space(|space| space.munmap(args[0], args[1])).ok_or(ESRCH)?;
+    result.map(|_| 0).map_err(|_| EINVAL)
+}
+
+fn sys_fork(frame: &mut SyscallFrame, _args: &[u64; 6]) -> SysResult {
+    crate::process::fork_current(frame).map_err(|e| e.errno())
+}
+
+fn sys_exec(frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let path = user_path(args[0], args[1])?;
+    let (argv, envp) = user_args(&path, args[2], args[3])?;
+    let argv: alloc::vec::Vec<&str> = argv.iter().map(|s| s.as_str()).collect();
+    let envp: alloc::vec::Vec<&str> = envp.iter().map(|s| s.as_str()).collect();
+    crate::process::exec_current(&path, &argv, &envp, frame).map(|_| 0).map_err(|e| e.errno())
+}
+
+fn sys_wait(frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let me = caller()?.pid();
+    let child = if args[0] as i64 == -1 { None } else { Some(args[0]) };
+    let status_ptr = args[1];
+    if status_ptr != 0 {
+        check_user(status_ptr, 4, true)?;
+    }
+    match crate::process::reap_child(me, child).map_err(|e| e.errno())? {
+        Some((pid, status)) => {
+            if status_ptr != 0 {
+                user_slice_mut(status_ptr, 4)?.copy_from_slice(&status.to_le_bytes());
+            }
+            Ok(pid)
+        }
+        None => crate::process::wait_block(frame, child, status_ptr),
+    }
+}
+
+fn sys_kill(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    let sig = u32::try_from(args[1]).map_err(|_| EINVAL)?;
+    crate::process::send_signal(args[0], sig).map(|_| 0).map_err(|e| e.errno())
+}
+
+fn sys_sigaction(_frame: &mut SyscallFrame, args: &[u64; 6]) -> SysResult {
+    use crate::process::{SigAction, SIG_DFL, SIG_IGN};
+    let sig = u32::try_from(args[0]).map_err(|_| EINVAL)?;
+    let (handler, restorer) = (args[1], args[2]);
+    if handler != SIG_DFL && handler != SIG_IGN
+        && (handler >= USER_SPACE_END || restorer == 0 || restorer >= USER_SPACE_END)
+    {
+        return Err(EINVAL);
+    }
+    let old = caller()?.signals().set_action(sig, SigAction { handler, restorer }).map_err(|e| e.errno())?;
+    Ok(old.handler)
+}
+
+/// Return from a signal handler: the context saved when it was called sits
+/// at the stack pointer its `ret` left behind.
+fn sys_sigreturn(frame: &mut SyscallFrame, _args: &[u64; 6]) -> SysResult {
+    let saved = user_slice(frame.rsp, core::mem::size_of::<UserContext>())?;
+    let mut ctx = unsafe { core::ptr::read_unaligned(saved.as_ptr().cast::<UserContext>()) };
+    ctx.sanitize();
+    if ctx.rip >= USER_SPACE_END {
+        crate::process::kill_current(crate::process::SIGSEGV);
+    }
+    frame.set_user_context(&ctx);
+    Ok(ctx.rax)
+}
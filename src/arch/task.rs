@@ -1,126 +1,705 @@
+use crate::prelude::*;
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
 use core::task::{Context, Poll};
 use alloc::collections::VecDeque;
 use core::task::{Waker, RawWaker};
 use core::task::RawWakerVTable;
-use core::sync::atomic::{AtomicU64, Ordering};
-use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use alloc::task::Wake;
 use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::sync::{WaitQueue, Waiter};
+
+/// Upper bound on CPUs that can run an executor.
+pub const MAX_CPUS: usize = 16;
+/// Per-CPU run queue size; overflow spills into the global injector.
+pub const RUN_QUEUE_CAPACITY: usize = 256;
+/// Affinity value meaning "any CPU".
+pub const ANY_CPU: usize = usize::MAX;
+
+/// Tasks that are runnable but not on any CPU's run queue: new tasks from
+/// `spawn()` and run queue overflow. Every executor drains it. Its capacity
+/// is kept at the number of live tasks (see `Task::submit`), so waking a
+/// task never allocates.
+static INJECTOR: Mutex<VecDeque<Arc<TaskInner>>> = Mutex::new(VecDeque::new());
+
+/// Every task that exists and has not finished, for `list()`/`cancel()`.
+static REGISTRY: Mutex<BTreeMap<TaskId, Arc<TaskInner>>> = Mutex::new(BTreeMap::new());
+
+/// Spawn a task onto the kernel executor from anywhere (driver `start()`,
+/// deferred work, other tasks). The task is picked up on the executor's next
+/// scheduling pass; it is safe to call before the executor is running.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_named(core::any::type_name::<F>(), future)
+}
+
+/// Like `spawn`, with a name shown by `list()`.
+pub fn spawn_named<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(None));
+    let out = slot.clone();
+    let task = Task::named(name, async move {
+        let value = future.await;
+        *out.lock() = Some(value);
+    });
+    let handle = JoinHandle { header: task.inner.clone(), slot, waiter: Waiter::new() };
+    task.submit();
+    handle
+}
+
+/// Like `spawn`, but the task only ever runs on `cpu`.
+pub fn spawn_on<F>(cpu: usize, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_named_on(cpu, core::any::type_name::<F>(), future)
+}
+
+/// Like `spawn_on`, with a name shown by `list()`.
+pub fn spawn_named_on<F>(cpu: usize, name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(None));
+    let out = slot.clone();
+    let task = Task::named(name, async move {
+        let value = future.await;
+        *out.lock() = Some(value);
+    }).with_affinity(cpu);
+    let handle = JoinHandle { header: task.inner.clone(), slot, waiter: Waiter::new() };
+    task.submit();
+    handle
+}
+
+fn injector_is_empty() -> bool {
+    interrupts::without_interrupts(|| INJECTOR.lock().is_empty())
+}
+
+fn pop_injector() -> Option<Arc<TaskInner>> {
+    interrupts::without_interrupts(|| INJECTOR.lock().pop_front())
+}
+
+fn push_injector(task: Arc<TaskInner>) {
+    interrupts::without_interrupts(|| INJECTOR.lock().push_back(task));
+    // any CPU can take it; wake the first one that is asleep
+    if let Some(cpu) = RUN_QUEUES.iter().position(|rq| rq.online.load(Ordering::Acquire) && rq.idle.load(Ordering::Acquire)) {
+        kick(cpu);
+    }
+}
+
+/// One CPU's run queue.
+struct RunQueue {
+    queue: ArrayQueue<Arc<TaskInner>>,
+    online: AtomicBool,
+    /// Set while the CPU's executor is halted waiting for work.
+    idle: AtomicBool,
+    /// Bumped whenever work is queued for this CPU; MWAIT watches it.
+    wake_seq: AtomicU64,
+    idle_entries: AtomicU64,
+    apic_id: AtomicUsize,
+    busy_cycles: AtomicU64,
+    idle_cycles: AtomicU64,
+    polls: AtomicU64,
+}
+
+lazy_static! {
+    static ref RUN_QUEUES: [RunQueue; MAX_CPUS] = core::array::from_fn(|cpu| RunQueue {
+        queue: ArrayQueue::new(RUN_QUEUE_CAPACITY),
+        online: AtomicBool::new(cpu == 0),
+        idle: AtomicBool::new(false),
+        wake_seq: AtomicU64::new(0),
+        idle_entries: AtomicU64::new(0),
+        apic_id: AtomicUsize::new(0),
+        busy_cycles: AtomicU64::new(0),
+        idle_cycles: AtomicU64::new(0),
+        polls: AtomicU64::new(0),
+    });
+}
+
+/// Hook used to kick a halted CPU when its run queue gains work. Installed
+/// by SMP bring-up; unset while only the BSP runs.
+static WAKE_IPI: Mutex<Option<fn(usize)>> = Mutex::new(None);
+
+/// Install the function that sends a wakeup IPI to a CPU index.
+pub fn set_wake_ipi(hook: fn(usize)) {
+    interrupts::without_interrupts(|| *WAKE_IPI.lock() = Some(hook));
+}
+
+/// Mark `cpu` as able to run an executor. CPU 0 (the BSP) is online from boot.
+pub fn register_cpu(cpu: usize, apic_id: u8) {
+    assert!(cpu < MAX_CPUS, "cpu index out of range");
+    RUN_QUEUES[cpu].apic_id.store(apic_id as usize, Ordering::Relaxed);
+    RUN_QUEUES[cpu].online.store(true, Ordering::Release);
+}
+
+/// Local APIC ID `cpu` was registered with, if it is online.
+pub fn cpu_apic_id(cpu: usize) -> Option<u8> {
+    let rq = RUN_QUEUES.get(cpu)?;
+    rq.online.load(Ordering::Acquire).then(|| rq.apic_id.load(Ordering::Relaxed) as u8)
+}
+
+/// Whether `cpu`'s executor is idle, waiting for work.
+pub fn cpu_is_idle(cpu: usize) -> bool {
+    RUN_QUEUES.get(cpu).is_some_and(|rq| rq.idle.load(Ordering::Acquire))
+}
+
+pub fn online_cpus() -> usize {
+    RUN_QUEUES.iter().filter(|rq| rq.online.load(Ordering::Acquire)).count()
+}
+
+/// Index of the CPU we're running on, as registered with `register_cpu`.
+pub fn current_cpu() -> usize {
+    if let Some(cpu) = crate::arch::percpu::percpu_cpu() {
+        return cpu;
+    }
+    if online_cpus() <= 1 {
+        return 0;
+    }
+    let apic_id = match crate::hal::apic::local_apic_id() {
+        Some(id) => id as usize,
+        None => return 0,
+    };
+    RUN_QUEUES.iter()
+        .position(|rq| rq.online.load(Ordering::Acquire) && rq.apic_id.load(Ordering::Relaxed) == apic_id)
+        .unwrap_or(0)
+}
+
+/// Total task wakeups since boot.
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+/// (tsc, wakeups) at the previous `stats()` call, for the wakeup rate.
+static LAST_SAMPLE: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuStats {
+    pub cpu: usize,
+    pub run_queue_depth: usize,
+    pub polls: u64,
+    /// TSC cycles spent running tasks.
+    pub busy_cycles: u64,
+    /// TSC cycles spent halted waiting for work.
+    pub idle_cycles: u64,
+    /// Times the idle task put the CPU to sleep.
+    pub idle_entries: u64,
+    /// Timer interrupts taken.
+    pub timer_ticks: u64,
+}
+
+impl CpuStats {
+    /// Busy time as a percentage of busy + idle.
+    pub fn busy_percent(&self) -> u64 {
+        let total = self.busy_cycles + self.idle_cycles;
+        if total == 0 { 0 } else { self.busy_cycles * 100 / total }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutorStats {
+    pub tasks: usize,
+    pub injector_depth: usize,
+    pub wakeups: u64,
+    /// Wakeup rate since the previous `stats()` call.
+    pub wakeups_per_sec: u64,
+    pub cpus: Vec<CpuStats>,
+}
+
+/// Snapshot executor counters for every online CPU.
+pub fn stats() -> ExecutorStats {
+    let wakeups = WAKEUPS.load(Ordering::Relaxed);
+    let now = crate::arch::tsc_timer::rdtsc();
+    let prev = interrupts::without_interrupts(|| LAST_SAMPLE.lock().replace((now, wakeups)));
+    let wakeups_per_sec = match prev {
+        Some((then, seen)) if now > then => {
+            ((wakeups - seen) as u128 * crate::arch::tsc_timer::tsc_hz() as u128 / (now - then) as u128) as u64
+        }
+        _ => 0,
+    };
+    let cpus = RUN_QUEUES.iter().enumerate()
+        .filter(|(_, rq)| rq.online.load(Ordering::Acquire))
+        .map(|(cpu, rq)| CpuStats {
+            cpu,
+            run_queue_depth: rq.queue.len(),
+            polls: rq.polls.load(Ordering::Relaxed),
+            busy_cycles: rq.busy_cycles.load(Ordering::Relaxed),
+            idle_cycles: rq.idle_cycles.load(Ordering::Relaxed),
+            idle_entries: rq.idle_entries.load(Ordering::Relaxed),
+            timer_ticks: crate::arch::tsc_timer::cpu_ticks(cpu),
+        })
+        .collect();
+    ExecutorStats {
+        tasks: interrupts::without_interrupts(|| REGISTRY.lock().len()),
+        injector_depth: interrupts::without_interrupts(|| INJECTOR.lock().len()),
+        wakeups,
+        wakeups_per_sec,
+        cpus,
+    }
+}
+
+/// Print `stats()` and the per-task table to the console.
+pub fn print_stats() {
+    let st = stats();
+    println!("tasks: {}  injector: {}  wakeups: {} ({}/s)", st.tasks, st.injector_depth, st.wakeups, st.wakeups_per_sec);
+    for c in st.cpus.iter() {
+        println!("cpu{}: rq={} polls={} ticks={} busy={}us idle={}us ({}% busy, {} sleeps)", c.cpu, c.run_queue_depth, c.polls, c.timer_ticks,
+            crate::arch::tsc_timer::cycles_to_us(c.busy_cycles), crate::arch::tsc_timer::cycles_to_us(c.idle_cycles), c.busy_percent(), c.idle_entries);
+    }
+    for t in list() {
+        println!("  {} {:?} polls={} busy={}us {}", t.id, t.state, t.polls, crate::arch::tsc_timer::cycles_to_us(t.cycles), t.name);
+    }
+}
+
+/// Put a runnable task on the right run queue and kick that CPU if it is
+/// halted.
+fn schedule(task: Arc<TaskInner>) {
+    let affinity = task.affinity.load(Ordering::Relaxed);
+    let target = if affinity != ANY_CPU { affinity } else { task.last_cpu.load(Ordering::Relaxed) };
+    let target = if target < MAX_CPUS && RUN_QUEUES[target].online.load(Ordering::Acquire) { target } else { 0 };
+    match RUN_QUEUES[target].queue.push(task) {
+        Ok(()) => kick(target),
+        Err(task) => push_injector(task),
+    }
+}
+
+/// Wake `cpu` if its executor is idle: the wake word write ends an MWAIT,
+/// a `hlt` needs an IPI.
+fn kick(cpu: usize) {
+    let rq = &RUN_QUEUES[cpu];
+    rq.wake_seq.fetch_add(1, Ordering::SeqCst);
+    if cpu == current_cpu() || !rq.idle.load(Ordering::SeqCst) {
+        return;
+    }
+    if crate::arch::idle::method() == crate::arch::idle::IdleMethod::Hlt {
+        let hook = interrupts::without_interrupts(|| *WAKE_IPI.lock());
+        if let Some(hook) = hook {
+            hook(cpu);
+        }
+    }
+}
+
+/// Snapshot of every live task.
+pub fn list() -> Vec<TaskInfo> {
+    let registry = interrupts::without_interrupts(|| REGISTRY.lock().clone());
+    task_infos(&registry)
+}
+
+/// `list` without the snapshot, for interrupt context and the panic path:
+/// calls `f` for each live task and allocates nothing. False if the
+/// registry is locked.
+pub fn try_for_each_task(f: impl FnMut(TaskInfo)) -> bool {
+    let Some(registry) = REGISTRY.try_lock() else { return false };
+    registry.values().map(|h| task_info(h)).for_each(f);
+    true
+}
+
+fn task_infos(registry: &BTreeMap<TaskId, Arc<TaskInner>>) -> Vec<TaskInfo> {
+    registry.values().map(|h| task_info(h)).collect()
+}
+
+fn task_info(h: &TaskInner) -> TaskInfo {
+    TaskInfo {
+        id: h.id,
+        name: h.name,
+        state: h.state(),
+        polls: h.polls.load(Ordering::Relaxed),
+        cycles: h.cycles.load(Ordering::Relaxed),
+    }
+}
+
+/// Request cancellation of task `id`. Returns false if no such task exists.
+pub fn cancel(id: TaskId) -> bool {
+    let task = interrupts::without_interrupts(|| REGISTRY.lock().get(&id).cloned());
+    match task {
+        Some(h) => { h.cancel(); true }
+        None => false,
+    }
+}
+
+/// Give other ready tasks a chance to run before continuing.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Queued to be polled.
+    Ready = 0,
+    /// Currently being polled.
+    Running = 1,
+    /// Returned `Pending` and is waiting for a wakeup.
+    Waiting = 2,
+    Done = 3,
+    Cancelled = 4,
+}
+
+impl TaskState {
+    fn from_u8(v: u8) -> TaskState {
+        match v {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            2 => TaskState::Waiting,
+            3 => TaskState::Done,
+            _ => TaskState::Cancelled,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub polls: u64,
+    /// TSC cycles spent inside the task's `poll`.
+    pub cycles: u64,
+}
+
+/// A task's future plus the state shared with its waker, its `JoinHandle`,
+/// the registry and whichever run queue it currently sits on.
+struct TaskInner {
+    id: TaskId,
+    name: &'static str,
+    state: AtomicU8,
+    polls: AtomicU64,
+    cycles: AtomicU64,
+    cancel_requested: AtomicBool,
+    /// Set while the task sits on a run queue, so a burst of wakeups queues
+    /// it only once.
+    scheduled: AtomicBool,
+    affinity: AtomicUsize,
+    last_cpu: AtomicUsize,
+    /// Taken by whichever CPU polls the task; None once it has finished.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    joiners: WaitQueue,
+}
+
+impl TaskInner {
+    fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state(), TaskState::Done | TaskState::Cancelled)
+    }
+
+    fn cancel(self: &Arc<Self>) {
+        self.cancel_requested.store(true, Ordering::Release);
+        self.notify();
+    }
+
+    /// Mark the task runnable and queue it unless it's already queued.
+    fn notify(self: &Arc<Self>) {
+        if self.is_finished() {
+            return;
+        }
+        self.set_state(TaskState::Ready);
+        WAKEUPS.fetch_add(1, Ordering::Relaxed);
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            schedule(self.clone());
+        }
+    }
+
+    /// Drop the future, record the final state and release joiners.
+    fn finish(&self, state: TaskState) {
+        self.future.lock().take();
+        self.set_state(state);
+        interrupts::without_interrupts(|| REGISTRY.lock().remove(&self.id));
+        self.joiners.wake_all();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Cancelled,
+}
+
+/// Handle to a task spawned with `spawn()`. Awaiting it yields the task's
+/// output; dropping it detaches the task.
+pub struct JoinHandle<T> {
+    header: Arc<TaskInner>,
+    slot: Arc<Mutex<Option<T>>>,
+    waiter: Waiter,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.header.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.header.is_finished()
+    }
+
+    /// Ask the task to stop. It is dropped before its next poll; awaiting
+    /// the handle afterwards yields `JoinError::Cancelled` unless the task
+    /// had already completed.
+    pub fn cancel(&self) {
+        self.header.cancel();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        let this = self.get_mut();
+        let header = &this.header;
+        let slot = &this.slot;
+        this.waiter.poll_until(&header.joiners, cx, || match header.state() {
+            TaskState::Done => slot.lock().take().map(Ok).or(Some(Err(JoinError::Cancelled))),
+            TaskState::Cancelled => Some(Err(JoinError::Cancelled)),
+            _ => None,
+        })
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.header.joiners);
+    }
+}
 
 pub struct Task {
-	id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    inner: Arc<TaskInner>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::named(core::any::type_name_of_val(&future), future)
+    }
+
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
-			id: TaskId::new(),
-            future: Box::pin(future),
+            inner: Arc::new(TaskInner {
+                id: TaskId::new(),
+                name,
+                state: AtomicU8::new(TaskState::Ready as u8),
+                polls: AtomicU64::new(0),
+                cycles: AtomicU64::new(0),
+                cancel_requested: AtomicBool::new(false),
+                scheduled: AtomicBool::new(true),
+                affinity: AtomicUsize::new(ANY_CPU),
+                last_cpu: AtomicUsize::new(0),
+                future: Mutex::new(Some(Box::pin(future))),
+                joiners: WaitQueue::new(),
+            }),
         }
     }
-	fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+
+    /// Pin the task to `cpu`.
+    pub fn with_affinity(self, cpu: usize) -> Task {
+        self.inner.affinity.store(cpu, Ordering::Relaxed);
+        self.inner.last_cpu.store(cpu, Ordering::Relaxed);
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.inner.id
+    }
+
+    /// Register the task and hand it to the injector.
+    fn submit(self) {
+        let tasks = interrupts::without_interrupts(|| {
+            let mut registry = REGISTRY.lock();
+            registry.insert(self.inner.id, self.inner.clone());
+            registry.len()
+        });
+        // A task is queued at most once (`scheduled`) and only while it is
+        // registered, so with room for every task the injector never grows
+        // when a wakeup from an IRQ handler spills into it.
+        interrupts::without_interrupts(|| {
+            let mut injector = INJECTOR.lock();
+            let room = tasks.saturating_sub(injector.len());
+            injector.reserve(room);
+        });
+        push_injector(self.inner);
     }
 }
 
+/// Runs tasks for one CPU: its own run queue first, then the injector, then
+/// whatever it can steal from other CPUs.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    cpu: usize,
 }
 
 impl Executor {
 	pub fn new() -> Self {
-        Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
-        }
+        Executor::for_cpu(0)
+    }
+
+    /// Executor for an application processor brought up by SMP code.
+    pub fn for_cpu(cpu: usize) -> Self {
+        assert!(cpu < MAX_CPUS, "cpu index out of range");
+        Executor { cpu }
     }
 	
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        if interrupts::without_interrupts(|| REGISTRY.lock().contains_key(&task.id())) {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        task.submit();
+    }
+
+    fn run_queue(&self) -> &'static RunQueue {
+        &RUN_QUEUES[self.cpu]
+    }
+
+    /// Pick the next task: local queue, then injector, then steal.
+    fn next_task(&self) -> Option<Arc<TaskInner>> {
+        if let Some(task) = self.run_queue().queue.pop() {
+            return Some(task);
+        }
+        while let Some(task) = pop_injector() {
+            if self.may_run(&task) {
+                return Some(task);
+            }
+            schedule(task);
+        }
+        self.steal()
+    }
+
+    fn may_run(&self, task: &TaskInner) -> bool {
+        let affinity = task.affinity.load(Ordering::Relaxed);
+        affinity == ANY_CPU || affinity == self.cpu
+    }
+
+    /// Take one unpinned task from another online CPU's run queue.
+    fn steal(&self) -> Option<Arc<TaskInner>> {
+        for offset in 1..MAX_CPUS {
+            let victim = &RUN_QUEUES[(self.cpu + offset) % MAX_CPUS];
+            if !victim.online.load(Ordering::Acquire) {
+                continue;
+            }
+            if let Some(task) = victim.queue.pop() {
+                if self.may_run(&task) {
+                    return Some(task);
+                }
+                // pinned to the victim: put it back at the tail
+                if let Err(task) = victim.queue.push(task) {
+                    push_injector(task);
+                }
+            }
+        }
+        None
     }
 	
 	fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        while let Some(task) = self.next_task() {
+            self.run_task(task);
+        }
+    }
+
+    fn run_task(&self, task: Arc<TaskInner>) {
+        // clear before polling so a wakeup during the poll requeues the task
+        task.scheduled.store(false, Ordering::Release);
+        task.last_cpu.store(self.cpu, Ordering::Relaxed);
+
+        if task.cancel_requested.load(Ordering::Acquire) {
+            task.finish(TaskState::Cancelled);
+            return;
+        }
+
+        let mut slot = task.future.lock();
+        let future = match slot.as_mut() {
+            Some(future) => future,
+            None => return, // already finished
+        };
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+        task.set_state(TaskState::Running);
+        task.polls.fetch_add(1, Ordering::Relaxed);
+        self.run_queue().polls.fetch_add(1, Ordering::Relaxed);
+        let start = crate::arch::tsc_timer::rdtsc();
+        let _tag = crate::memory::heaptrack::heap_tag(task.name);
+        let result = future.as_mut().poll(&mut context);
+        task.cycles.fetch_add(crate::arch::tsc_timer::rdtsc().wrapping_sub(start), Ordering::Relaxed);
+        match result {
+            Poll::Ready(()) => {
+                drop(slot);
+                task.finish(TaskState::Done);
+            }
+            Poll::Pending => {
+                // a wakeup during the poll already moved it back to Ready
+                let _ = task.state.compare_exchange(
+                    TaskState::Running as u8, TaskState::Waiting as u8,
+                    Ordering::AcqRel, Ordering::Acquire);
             }
         }
     }
 	
 	pub fn run(&mut self) -> ! {
+        let rq = self.run_queue();
         loop {
+            let t0 = crate::arch::tsc_timer::rdtsc();
             self.run_ready_tasks();
-            self.sleep_if_idle();   // new
+            let t1 = crate::arch::tsc_timer::rdtsc();
+            self.idle();
+            let t2 = crate::arch::tsc_timer::rdtsc();
+            rq.busy_cycles.fetch_add(t1.wrapping_sub(t0), Ordering::Relaxed);
+            rq.idle_cycles.fetch_add(t2.wrapping_sub(t1), Ordering::Relaxed);
         }
     }
 	
-	fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
+    /// The CPU's idle task: give the CPU to kernel threads, or sleep until
+    /// something is queued for us.
+	fn idle(&self) {
+        let rq = self.run_queue();
+        crate::sched::reap();
+        if crate::sched::yield_now() {
+            return;
         }
+        let kthreads = current_cpu() == crate::sched::KTHREAD_CPU;
+        rq.idle.store(true, Ordering::SeqCst);
+        if crate::arch::idle::idle(&rq.wake_seq, || !rq.queue.is_empty() || !injector_is_empty() || (kthreads && crate::sched::kthreads_busy())) {
+            rq.idle_entries.fetch_add(1, Ordering::Relaxed);
+        }
+        rq.idle.store(false, Ordering::SeqCst);
+        crate::arch::tsc_timer::idle_exit();
     }
 }
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-}
-
-impl TaskWaker {
-	fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
-    }
-	
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
-    }
-}
-
-impl Wake for TaskWaker {
+// The task itself is the waker, so building one per poll is just a refcount bump.
+impl Wake for TaskInner {
     fn wake(self: Arc<Self>) {
-        self.wake_task();
+        self.notify();
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+        self.notify();
     }
 }
 
@@ -132,11 +711,21 @@ pub trait Stream {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
-}
\ No newline at end of file
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl core::fmt::Display for TaskId {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
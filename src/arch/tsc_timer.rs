@@ -1,18 +1,107 @@
-use crate::*;
-use core::arch::asm;
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::prelude::*;
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::ports::{inb, outb};
+use core::arch::{asm, global_asm};
+use crate::arch::usermode::UserContext;
+use crate::arch::task::MAX_CPUS;
+use crate::time::clock::uptime_ns;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
 use x86_64::structures::idt::InterruptStackFrame;
-use x86_64::VirtAddr;
-use crate::devices::acpi;
-use x86_64::structures::paging::Translate;
-use x86_64::structures::paging::Mapper;
-use x86_64::PhysAddr;
-use x86_64::structures::paging::{OffsetPageTable, Page, PhysFrame, Size4KiB, FrameAllocator, PageTableFlags as Flags};
 
 const IA32_TSC_DEADLINE: u32 = 0x6E0;
 
-static PERIOD_CYCLES: AtomicU64 = AtomicU64::new(10_000_000); // default: 10M cycles (~10ms @1GHz)
-const HPET_MAIN_COUNTER_OFFSET: u64 = 0xF0;
+/// PIT input clock.
+pub const PIT_HZ: u64 = 1_193_182;
+/// Length of one PIT calibration run; the count must fit 16 bits.
+const PIT_CALIBRATE_MS: u64 = 50;
+const PIT_CALIBRATE_RUNS: usize = 3;
+/// Port 0x61: channel 2 gate, speaker enable, and channel 2 output.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 0x01;
+const PORT_B_SPEAKER: u8 = 0x02;
+const PORT_B_OUT2: u8 = 0x20;
+
+/// How the TSC rate was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TscCalibration {
+    /// Not measured; `tsc_hz` is a 1 GHz guess.
+    Guess,
+    Hpet,
+    Pit,
+}
+
+static CALIBRATION: AtomicU8 = AtomicU8::new(TscCalibration::Guess as u8);
+
+/// Which timer drives the tick on every CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerKind {
+    TscDeadline = 1,
+    LapicOneShot = 2,
+    /// PIT channel 0 ticking every period, in PIC mode. There is no
+    /// tickless idle with it.
+    Pit = 3,
+}
+
+/// 0 until `init` picks a `TimerKind`.
+static KIND: AtomicU8 = AtomicU8::new(0);
+/// Tick period while a CPU has work.
+static PERIOD_NS: AtomicU64 = AtomicU64::new(10_000_000);
+/// LAPIC timer counts per millisecond, for one-shot mode.
+static COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+/// Uptime (ns) at which each CPU's timer is armed to fire.
+static ARMED_NS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(u64::MAX) }; MAX_CPUS];
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+/// Longest an idle CPU goes without a timer interrupt.
+const MAX_IDLE_NS: u64 = 1_000_000_000;
+/// Soonest a timer interrupt is armed, so a deadline that has just passed
+/// doesn't turn into an interrupt storm.
+const MIN_ARM_NS: u64 = 20_000;
+/// Timer interrupts taken, per CPU.
+static TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static TSC_HZ: AtomicU64 = AtomicU64::new(1_000_000_000); // assumed 1GHz until calibrated
+
+/// TSC frequency in Hz (measured against the HPET or PIT by
+/// `time::init_clock`, a 1GHz guess before that).
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+pub fn tsc_calibration() -> TscCalibration {
+    match CALIBRATION.load(Ordering::Relaxed) {
+        1 => TscCalibration::Hpet,
+        2 => TscCalibration::Pit,
+        _ => TscCalibration::Guess,
+    }
+}
+
+/// The timer chosen by `init`, if any.
+pub fn timer_kind() -> Option<TimerKind> {
+    match KIND.load(Ordering::Acquire) {
+        1 => Some(TimerKind::TscDeadline),
+        2 => Some(TimerKind::LapicOneShot),
+        3 => Some(TimerKind::Pit),
+        _ => None,
+    }
+}
+
+/// Timer interrupts taken on `cpu` since its timer started.
+pub fn cpu_ticks(cpu: usize) -> u64 {
+    TICKS.get(cpu).map_or(0, |t| t.load(Ordering::Relaxed))
+}
+
+/// Whether the TSC ticks at a constant rate through frequency and sleep
+/// state changes, so it can time things and drive the timer.
+pub fn tsc_invariant() -> bool {
+    crate::arch::detect_cpu_features().invariant_tsc
+}
+
+/// Convert a TSC cycle delta to microseconds.
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    ((cycles as u128 * 1_000_000u128) / tsc_hz().max(1) as u128) as u64
+}
 
 pub fn rdtsc() -> u64 {
     unsafe {
@@ -29,115 +118,365 @@ unsafe fn write_msr(msr: u32, val: u64) {
     asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high);
 }
 
-/// Timer IRQ handler used when TSC-deadline is enabled.
-/// It re-arms the deadline and issues EOI.
-pub extern "x86-interrupt" fn tsc_timer_handler(_stack_frame: InterruptStackFrame) {
-    // compute next deadline and program MSR
-    let period = PERIOD_CYCLES.load(Ordering::SeqCst);
-    let now = rdtsc();
-    let next = now.wrapping_add(period);
-    unsafe { write_msr(IA32_TSC_DEADLINE, next); }
+/// Work done on every timer interrupt, whichever timer raised it: the
+/// watchdog, time keeping and CPU accounting.
+pub(crate) fn on_timer_tick(stack_frame: &InterruptStackFrame) {
+    crate::arch::idt::note_irq(InterruptIndex::Timer.as_u8());
+    TICKS[crate::arch::task::current_cpu() % MAX_CPUS].fetch_add(1, Ordering::Relaxed);
+    crate::arch::watchdog::on_timer_tick(stack_frame);
+    crate::time::timer_tick();
+    crate::process::account_tick(stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3);
+}
 
+fn timer_eoi() {
     unsafe {
         if crate::hal::apic::is_initialized() {
             crate::hal::apic::send_eoi();
         } else {
-            crate::arch::interrupts::PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            crate::hal::intctl::pic_eoi(InterruptIndex::Timer.as_u8());
         }
     }
 }
 
-/// Initialize TSC-deadline timer.
-/// If HPET is available (via ACPI) the function will calibrate the TSC frequency
-/// against the HPET main counter and set the period to desired_ms milliseconds.
-/// `phys_offset` is the kernel's physical memory offset used to map device MMIO.
-pub fn init(mapper: &mut OffsetPageTable<'static>, frame_allocator: &mut impl FrameAllocator<Size4KiB>, phys_offset: VirtAddr, desired_ms: u64) -> bool {
-    // Ensure CPU supports MSR and TSC-deadline before attempting to program MSR
-    let feats = crate::arch::detect_cpu_features();
-    if !feats.msr || !feats.tsc_deadline || !feats.tsc {
-        // CPU doesn't support required features; do not enable TSC-deadline
-        return false;
+/// Program this CPU's timer to fire at `ns` of uptime, or `MIN_ARM_NS`
+/// from now if that is sooner.
+fn arm_at(ns: u64) {
+    let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+    let now = uptime_ns();
+    let delta = ns.saturating_sub(now).max(MIN_ARM_NS);
+    match timer_kind() {
+        Some(TimerKind::TscDeadline) => {
+            let cycles = (delta as u128 * tsc_hz() as u128 / 1_000_000_000) as u64;
+            unsafe { write_msr(IA32_TSC_DEADLINE, rdtsc().wrapping_add(cycles.max(1))) };
+        }
+        Some(TimerKind::LapicOneShot) => {
+            let counts = (delta as u128 * COUNTS_PER_MS.load(Ordering::Relaxed) as u128 / 1_000_000).clamp(1, u32::MAX as u128);
+            crate::hal::apic::set_timer_initial_count(counts as u32);
+        }
+        Some(TimerKind::Pit) | None => return,
     }
+    ARMED_NS[cpu].store(now + delta, Ordering::Relaxed);
+}
 
-    // Try HPET-based calibration if available
-    if let Some(hpet_base) = acpi::get_hpet_address() {
-        // Avoid mapping very high MMIO regions here — if HPET is located
-        // above 4GiB and the kernel hasn't mapped that region into its
-        // direct-physical mapping, reads will cause a page fault. For
-        // safety, only attempt calibration for HPET bases in low physical
-        // memory. This is conservative; we can relax it later with a
-        // proper mapper-based mapping.
-        if hpet_base < 0x1_0000_0000u64 {
-            let main_phys = hpet_base + HPET_MAIN_COUNTER_OFFSET;
-
-            // Map HPET main counter pages into the kernel address space (page-aligned)
-            let page_start = main_phys & !0xFFFu64;
-            let offset_in_page = (main_phys & 0xFFF) as usize;
-            let needed = ((offset_in_page + core::mem::size_of::<u64>()) + 0xFFF) / 0x1000;
-
-            for i in 0..needed {
-                let phys_page = page_start + (i as u64) * 0x1000u64;
-                let virt_page_addr = phys_offset.as_u64().wrapping_add(phys_page);
-                let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(virt_page_addr));
-
-                // If already mapped, skip
-                if mapper.translate_addr(VirtAddr::new(virt_page_addr)).is_some() {
-                    continue;
-                }
-
-                let frame = PhysFrame::containing_address(PhysAddr::new(phys_page));
-                let flags = Flags::PRESENT | Flags::WRITABLE;
-                let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
-                match map_to_result {
-                    Ok(flush) => { flush.flush(); }
-                    Err(_) => { /* mapping failed; skip calibration */ return false; }
-                }
-            }
+/// Program this CPU's next timer interrupt: one period from now while it
+/// has work, or, while it idles and `tickless` is on, the next time
+/// something waits for (bounded by `MAX_IDLE_NS`).
+fn arm_next() {
+    let cpu = crate::arch::task::current_cpu();
+    let now = uptime_ns();
+    let kthreads = cpu == crate::sched::KTHREAD_CPU;
+    let idle = crate::arch::task::cpu_is_idle(cpu) && !(kthreads && crate::sched::kthreads_busy());
+    let limit = if tickless() && idle { MAX_IDLE_NS } else { PERIOD_NS.load(Ordering::Relaxed) };
+    let next = if kthreads { crate::time::next_event_ns().min(crate::sched::next_kthread_wake()) } else { crate::time::next_event_ns() };
+    arm_at(next.min(now.saturating_add(limit)));
+}
 
-            let main_virt = (main_phys + phys_offset.as_u64()) as *const u64;
-
-            // read period in femtoseconds
-            let period_fs = acpi::get_hpet_period_fs();
-            if period_fs != 0 {
-                // sample HPET and TSC over a short interval
-                unsafe {
-                    let h1 = core::ptr::read_volatile(main_virt);
-                    let t1 = rdtsc();
-                    // wait until HPET advances by at least 1000 ticks (should be fast)
-                    while core::ptr::read_volatile(main_virt).wrapping_sub(h1) < 1000 {
-                        core::arch::asm!("nop");
-                    }
-                    let h2 = core::ptr::read_volatile(main_virt);
-                    let t2 = rdtsc();
-                    let hdelta = h2.wrapping_sub(h1) as u128;
-                    let tdelta = t2.wrapping_sub(t1) as u128;
-
-                    if hdelta != 0 {
-                        // HPET period is in femtoseconds -> 1e15 femtoseconds = 1 second
-                        // We'll compute tsc_hz = (tdelta * 1e15) / (hdelta * period_fs)
-                        let num = tdelta.saturating_mul(1_000_000_000_000_000u128);
-                        let den = hdelta.saturating_mul(period_fs as u128);
-                        if den != 0 {
-                            let tsc_hz = num / den;
-                            // desired cycles for desired_ms milliseconds
-                            let cycles = (tsc_hz * (desired_ms as u128)) / 1000u128;
-                            if cycles > 0 {
-                                PERIOD_CYCLES.store(cycles as u64, Ordering::SeqCst);
-                            }
-                        }
-                    }
-                }
-            }
+/// Make sure this CPU's timer fires by `ns` of uptime. Called when a task
+/// starts waiting for a deadline or a timer callback is added.
+pub fn rearm_before(ns: u64) {
+    if timer_kind().is_none() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+        if ARMED_NS[cpu].load(Ordering::Relaxed) > ns {
+            arm_at(ns);
         }
+    });
+}
+
+/// Called when this CPU's executor wakes from idle: bring back the regular
+/// tick if a tickless sleep left the timer armed far ahead.
+pub fn idle_exit() {
+    if timer_kind().is_none() {
+        return;
     }
+    interrupts::without_interrupts(|| {
+        let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+        let tick = uptime_ns().saturating_add(PERIOD_NS.load(Ordering::Relaxed));
+        if ARMED_NS[cpu].load(Ordering::Relaxed) > tick {
+            arm_at(tick);
+        }
+    });
+}
+
+pub fn tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Turn tickless idle on or off; off, every CPU takes a tick each period.
+pub fn set_tickless(on: bool) {
+    TICKLESS.store(on, Ordering::Relaxed);
+}
 
-    // Register handler for timer vector and arm initial deadline
+/// Timer IRQ handler for both timer kinds: runs the tick, arms this CPU's
+/// next interrupt and issues EOI.
+pub extern "x86-interrupt" fn tsc_timer_handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    on_timer_tick(&stack_frame);
+    arm_next();
+    timer_eoi();
+    crate::arch::idt::irq_exit(&stack_frame);
+    crate::sched::preempt(&stack_frame);
+}
+
+/// Stack the user-mode path of the timer entry sets aside: a `UserContext`,
+/// and 8 bytes to align its FPU image.
+const USER_TICK_FRAME: usize = core::mem::size_of::<UserContext>() + 8;
+
+// The timer's IDT entry. Interrupted kernel code goes straight on to
+// `tsc_timer_handler`. For user code every register is saved as a
+// `UserContext` first, so the process can be taken off the CPU and resumed
+// later (see `timer_from_user`); when it isn't, they are restored as they
+// were. The CPU aligns the stack before pushing the interrupt frame, so
+// with the frame's 40 bytes and the 8 spare ones the FPU image at +144
+// lands on 16 bytes.
+global_asm!(
+    ".global neutrix_timer_entry",
+    "neutrix_timer_entry:",
+    "test qword ptr [rsp + 8], 3",
+    "jz {kernel}",
+    "sub rsp, {frame}",
+    "mov [rsp], rax",
+    "mov [rsp + 8], rbx",
+    "mov [rsp + 16], rcx",
+    "mov [rsp + 24], rdx",
+    "mov [rsp + 32], rsi",
+    "mov [rsp + 40], rdi",
+    "mov [rsp + 48], rbp",
+    "mov [rsp + 56], r8",
+    "mov [rsp + 64], r9",
+    "mov [rsp + 72], r10",
+    "mov [rsp + 80], r11",
+    "mov [rsp + 88], r12",
+    "mov [rsp + 96], r13",
+    "mov [rsp + 104], r14",
+    "mov [rsp + 112], r15",
+    // rip, rsp and rflags from the interrupt frame
+    "mov rax, [rsp + {frame}]",
+    "mov [rsp + 120], rax",
+    "mov rax, [rsp + {frame} + 24]",
+    "mov [rsp + 128], rax",
+    "mov rax, [rsp + {frame} + 16]",
+    "mov [rsp + 136], rax",
+    "fxsave64 [rsp + 144]",
+    "mov rdi, rsp",
+    "lea rsi, [rsp + {frame}]",
+    "call {user}",
+    "fxrstor64 [rsp + 144]",
+    "mov rax, [rsp]",
+    "mov rbx, [rsp + 8]",
+    "mov rcx, [rsp + 16]",
+    "mov rdx, [rsp + 24]",
+    "mov rsi, [rsp + 32]",
+    "mov rdi, [rsp + 40]",
+    "mov rbp, [rsp + 48]",
+    "mov r8, [rsp + 56]",
+    "mov r9, [rsp + 64]",
+    "mov r10, [rsp + 72]",
+    "mov r11, [rsp + 80]",
+    "mov r12, [rsp + 88]",
+    "mov r13, [rsp + 96]",
+    "mov r14, [rsp + 104]",
+    "mov r15, [rsp + 112]",
+    "add rsp, {frame}",
+    "iretq",
+    kernel = sym tsc_timer_handler,
+    user = sym timer_from_user,
+    frame = const USER_TICK_FRAME,
+);
+
+unsafe extern "C" {
+    fn neutrix_timer_entry();
+}
+
+/// The timer interrupt from user mode, with the process's registers in
+/// `context`. Returns to resume it, unless the process is taken off the
+/// CPU (`process::preempt_user`).
+extern "C" fn timer_from_user(context: *const UserContext, stack_frame: *const InterruptStackFrame) {
+    // SAFETY: both were just laid out by neutrix_timer_entry
+    let (context, stack_frame) = unsafe { (&*context, &*stack_frame) };
+    let _gs = crate::arch::percpu::kernel_gs(stack_frame);
+    on_timer_tick(stack_frame);
+    arm_next();
+    timer_eoi();
+    crate::arch::idt::irq_exit(stack_frame);
+    crate::process::preempt_user(context);
+}
+
+fn register_timer_entry() {
     let vec = crate::arch::interrupts::InterruptIndex::Timer.as_u8();
-    crate::arch::idt::register_irq_handler(vec, tsc_timer_handler);
+    // SAFETY: the stub ends in iretq on both paths
+    unsafe { crate::arch::idt::register_irq_entry(vec, neutrix_timer_entry as usize as u64) };
+}
+
+/// Measure the TSC frequency against the HPET main counter mapped by
+/// `time::init_clock`. Returns whether it worked.
+pub fn calibrate_with_hpet() -> bool {
+    let Some((counter, period_fs)) = crate::time::hpet_counter() else { return false };
+    unsafe {
+        let h1 = core::ptr::read_volatile(counter);
+        let t1 = rdtsc();
+        // wait until HPET advances by at least 1000 ticks (should be fast)
+        while core::ptr::read_volatile(counter).wrapping_sub(h1) < 1000 {
+            core::hint::spin_loop();
+        }
+        let h2 = core::ptr::read_volatile(counter);
+        let t2 = rdtsc();
+        let hdelta = h2.wrapping_sub(h1) as u128;
+        let tdelta = t2.wrapping_sub(t1) as u128;
+
+        // HPET period is in femtoseconds -> 1e15 femtoseconds = 1 second
+        // tsc_hz = (tdelta * 1e15) / (hdelta * period_fs)
+        let num = tdelta.saturating_mul(1_000_000_000_000_000u128);
+        let den = hdelta.saturating_mul(period_fs as u128);
+        if den == 0 || num / den == 0 {
+            return false;
+        }
+        TSC_HZ.store((num / den) as u64, Ordering::Relaxed);
+        CALIBRATION.store(TscCalibration::Hpet as u8, Ordering::Relaxed);
+        true
+    }
+}
 
-    let period = PERIOD_CYCLES.load(Ordering::SeqCst);
-    let now = rdtsc();
-    let next = now.wrapping_add(period);
-    unsafe { write_msr(IA32_TSC_DEADLINE, next); }
+/// TSC cycles over one `PIT_CALIBRATE_MS` countdown of PIT channel 2, or
+/// `None` if the PIT never finished counting.
+fn pit_run() -> Option<u64> {
+    pit_countdown((PIT_HZ * PIT_CALIBRATE_MS / 1000) as u16)
+}
+
+/// Count PIT channel 2 down from `count` (at `PIT_HZ`) with interrupts off
+/// and return the TSC cycles it took, or `None` if the PIT never finished
+/// counting. Needs no clock, so it works before calibration.
+pub(crate) fn pit_countdown(count: u16) -> Option<u64> {
+    interrupts::without_interrupts(|| unsafe {
+        let saved = inb(PORT_B);
+        // gate low and speaker off while programming channel 2 in mode 0
+        outb(PORT_B, saved & !(PORT_B_GATE2 | PORT_B_SPEAKER));
+        outb(0x43, 0xB0); // channel 2, lobyte/hibyte, mode 0
+        outb(0x42, count as u8);
+        outb(0x42, (count >> 8) as u8);
+        // raising the gate starts the countdown; OUT2 goes high at zero
+        outb(PORT_B, (saved & !PORT_B_SPEAKER) | PORT_B_GATE2);
+        let start = rdtsc();
+        // there may be no calibrated clock yet, so bound the wait in port
+        // reads (about 1us each): well over the 55k of the longest count
+        let mut reads = 0u32;
+        while inb(PORT_B) & PORT_B_OUT2 == 0 {
+            reads += 1;
+            if reads > 1_000_000 {
+                outb(PORT_B, saved);
+                return None;
+            }
+        }
+        let cycles = rdtsc().wrapping_sub(start);
+        outb(PORT_B, saved);
+        Some(cycles)
+    })
+}
+
+/// Measure the TSC frequency against PIT channel 2, for machines without a
+/// usable HPET. The shortest of a few runs is used: interrupts are off, so
+/// anything that makes a run longer is an SMI or a hypervisor stealing time.
+pub fn calibrate_with_pit() -> bool {
+    let Some(cycles) = (0..PIT_CALIBRATE_RUNS).filter_map(|_| pit_run()).min() else { return false };
+    let hz = cycles * 1000 / PIT_CALIBRATE_MS;
+    if hz == 0 {
+        return false;
+    }
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    CALIBRATION.store(TscCalibration::Pit as u8, Ordering::Relaxed);
     true
 }
+
+/// Why TSC-deadline mode can't be used on this machine, if it can't. It
+/// is refused unless the TSC is invariant: a TSC that slows down in deep
+/// C-states would fire deadlines late or never.
+fn tsc_deadline_unusable() -> Option<&'static str> {
+    let feats = crate::arch::detect_cpu_features();
+    if !feats.msr || !feats.tsc_deadline || !feats.tsc {
+        return Some("CPU has no TSC-deadline timer");
+    }
+    if !feats.invariant_tsc {
+        return Some("TSC is not invariant");
+    }
+    if tsc_calibration() == TscCalibration::Guess {
+        return Some("TSC rate was never measured");
+    }
+    None
+}
+
+/// LAPIC timer counts per millisecond, measured against the TSC, which is
+/// good enough over 10ms even when it is not invariant.
+fn lapic_counts_per_ms() -> Option<u32> {
+    use crate::hal::apic;
+    const MEASURE_MS: u64 = 10;
+    let vec = crate::arch::interrupts::InterruptIndex::Timer.as_u8();
+    apic::set_lvt_timer(vec, apic::TimerMode::OneShot, true);
+    apic::set_timer_initial_count(u32::MAX);
+    let end = rdtsc() + tsc_hz() * MEASURE_MS / 1000;
+    while rdtsc() < end {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - apic::timer_current_count();
+    apic::set_timer_initial_count(0);
+    let per_ms = elapsed / MEASURE_MS as u32;
+    (per_ms > 0).then_some(per_ms)
+}
+
+/// Choose the timer every CPU will use, with a period of `desired_ms`
+/// milliseconds, and start it on the calling CPU (the BSP). TSC-deadline
+/// mode is preferred; the LAPIC timer in one-shot mode, re-armed on each
+/// tick, is the fallback. Other CPUs call `start_cpu_timer` once their
+/// LAPIC is enabled. In PIC mode the PIT ticks instead.
+pub fn init(desired_ms: u64) -> Result<TimerKind, &'static str> {
+    if crate::hal::intctl::irq_mode() == crate::hal::intctl::IrqMode::Pic {
+        return Ok(start_pit(desired_ms));
+    }
+    if !crate::hal::apic::is_initialized() {
+        return Err("no local APIC");
+    }
+    PERIOD_NS.store(desired_ms.max(1) * 1_000_000, Ordering::Relaxed);
+    let kind = match tsc_deadline_unusable() {
+        None => TimerKind::TscDeadline,
+        Some(why) => {
+            println!("[TIMER] not using TSC-deadline mode: {}", why);
+            // every LAPIC timer runs off the same bus clock, so one
+            // measurement on the BSP serves all CPUs
+            let per_ms = lapic_counts_per_ms().ok_or("LAPIC timer did not count")?;
+            COUNTS_PER_MS.store(per_ms, Ordering::Relaxed);
+            TimerKind::LapicOneShot
+        }
+    };
+    KIND.store(kind as u8, Ordering::Release);
+    register_timer_entry();
+    start_cpu_timer();
+    Ok(kind)
+}
+
+/// Tick every `desired_ms` (at most 54) from PIT channel 0 on IRQ 0.
+fn start_pit(desired_ms: u64) -> TimerKind {
+    let count = (PIT_HZ * desired_ms.max(1) / 1000).min(u16::MAX as u64) as u16;
+    PERIOD_NS.store(count as u64 * 1_000_000_000 / PIT_HZ, Ordering::Relaxed);
+    KIND.store(TimerKind::Pit as u8, Ordering::Release);
+    register_timer_entry();
+    unsafe {
+        outb(0x43, 0x34); // channel 0, lobyte/hibyte, mode 2 (rate generator)
+        outb(0x40, count as u8);
+        outb(0x40, (count >> 8) as u8);
+    }
+    crate::hal::intctl::pic_unmask(0);
+    TimerKind::Pit
+}
+
+/// Start the chosen timer on the calling CPU. Does nothing before `init`.
+pub fn start_cpu_timer() {
+    use crate::hal::apic;
+    let vec = crate::arch::interrupts::InterruptIndex::Timer.as_u8();
+    match timer_kind() {
+        Some(TimerKind::TscDeadline) => apic::set_lvt_timer(vec, apic::TimerMode::TscDeadline, false),
+        Some(TimerKind::LapicOneShot) => apic::set_lvt_timer(vec, apic::TimerMode::OneShot, false),
+        Some(TimerKind::Pit) | None => return,
+    }
+    arm_next();
+}
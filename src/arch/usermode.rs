@@ -0,0 +1,165 @@
+//! Entering and leaving ring 3
+//!
+//! `enter_user` saves the kernel's callee-saved registers and stack pointer,
+//! loads a `UserContext` and `iretq`s to user code. When the program exits,
+//! faults or blocks, kernel code calls `leave_user` from whatever stack it is
+//! on, which unwinds to the saved stack so `enter_user` returns with the
+//! given value, as if it were an ordinary call.
+//!
+//! The context includes the x87/SSE state, since kernel code uses SSE
+//! registers too. Interrupt handlers preserve whatever they touch (that is
+//! the `x86-interrupt` ABI), and the syscall stub saves the whole FPU state
+//! into its frame, so a process sees its registers unchanged however it
+//! left ring 3.
+
+use crate::prelude::*;
+use core::arch::global_asm;
+
+/// An `fxsave` image: x87, MMX and SSE registers plus MXCSR.
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+pub struct FpuState(pub [u8; 512]);
+
+impl Default for FpuState {
+    /// The state after `fninit`: default control word, all exceptions
+    /// masked in MXCSR.
+    fn default() -> FpuState {
+        let mut image = [0u8; 512];
+        image[0..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        image[24..28].copy_from_slice(&0x1F80u32.to_le_bytes());
+        FpuState(image)
+    }
+}
+
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("FpuState")
+    }
+}
+
+/// User-visible register state. Field offsets are used by the entry stub.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub fpu: FpuState,
+}
+
+/// RFLAGS bits user code may set: CF, PF, AF, ZF, SF, TF, DF, OF, AC.
+const USER_RFLAGS: u64 = 0x0004_0DD5;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+impl UserContext {
+    /// Registers zeroed, starting at `entry` on `stack`.
+    pub fn new(entry: u64, stack: u64) -> UserContext {
+        UserContext { rip: entry, rsp: stack, rflags: RFLAGS_IF | RFLAGS_RESERVED, ..UserContext::default() }
+    }
+
+    /// Clear anything user code must not control: privileged RFLAGS bits
+    /// and MXCSR bits `fxrstor` would fault on. For contexts that came
+    /// from user memory.
+    pub fn sanitize(&mut self) {
+        self.rflags = (self.rflags & USER_RFLAGS) | RFLAGS_IF | RFLAGS_RESERVED;
+        self.fpu.0[26..28].fill(0);
+    }
+}
+
+// enter: rdi = context, rsi = where to save rsp, rdx = user CS, rcx = user SS
+// leave: rdi = saved rsp, rsi = value to return from enter
+// Interrupts stay off from the swapgs to the iretq: a handler must not see
+// the user GS base while the interrupted code is ring 0.
+global_asm!(
+    ".global neutrix_enter_user",
+    "neutrix_enter_user:",
+    "cli",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rsi], rsp",
+    "push rcx",
+    "push qword ptr [rdi + 128]",
+    "push qword ptr [rdi + 136]",
+    "push rdx",
+    "push qword ptr [rdi + 120]",
+    "fxrstor64 [rdi + 144]",
+    "mov rax, [rdi]",
+    "mov rbx, [rdi + 8]",
+    "mov rcx, [rdi + 16]",
+    "mov rdx, [rdi + 24]",
+    "mov rsi, [rdi + 32]",
+    "mov rbp, [rdi + 48]",
+    "mov r8, [rdi + 56]",
+    "mov r9, [rdi + 64]",
+    "mov r10, [rdi + 72]",
+    "mov r11, [rdi + 80]",
+    "mov r12, [rdi + 88]",
+    "mov r13, [rdi + 96]",
+    "mov r14, [rdi + 104]",
+    "mov r15, [rdi + 112]",
+    "mov rdi, [rdi + 40]",
+    "swapgs",
+    "iretq",
+    ".global neutrix_leave_user",
+    "neutrix_leave_user:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+unsafe extern "C" {
+    fn neutrix_enter_user(ctx: *const UserContext, saved_rsp: *mut u64, cs: u64, ss: u64) -> u64;
+    fn neutrix_leave_user(saved_rsp: u64, value: u64) -> !;
+}
+
+/// Run user code from `ctx` with interrupts enabled until something calls
+/// `leave_user` with the stack pointer stored in `saved_rsp`. Returns the
+/// value passed to `leave_user`.
+///
+/// # Safety
+/// The user address space must be active, this CPU's per-CPU block set up,
+/// and `saved_rsp` must stay valid until `leave_user` is called.
+pub unsafe fn enter_user(ctx: &UserContext, saved_rsp: *mut u64) -> u64 {
+    let mut ctx = *ctx;
+    ctx.rflags = (ctx.rflags & USER_RFLAGS) | RFLAGS_IF | RFLAGS_RESERVED;
+    let cs = crate::arch::gdt::user_code_selector().0 as u64;
+    let ss = crate::arch::gdt::user_data_selector().0 as u64;
+    unsafe { neutrix_enter_user(&ctx, saved_rsp, cs, ss) }
+}
+
+/// Return from the `enter_user` call that saved `saved_rsp`, discarding the
+/// current stack.
+///
+/// # Safety
+/// `saved_rsp` must come from an `enter_user` call that hasn't returned yet,
+/// and the kernel GS base must be active (it is on every path that has
+/// already entered the kernel).
+pub unsafe fn leave_user(saved_rsp: u64, value: u64) -> ! {
+    unsafe { neutrix_leave_user(saved_rsp, value) }
+}
@@ -0,0 +1,104 @@
+//! Soft lockup detector
+//!
+//! Each CPU runs a low-priority watchdog task that records when it last got
+//! scheduled. The timer interrupt checks that timestamp: if a task hogs the
+//! CPU (the executor is cooperative, so a task that never yields stalls
+//! everything) for longer than the threshold, the interrupt dumps the
+//! interrupted context, the task list and IRQ counts.
+//!
+//! The report is printed from interrupt context, so it allocates nothing
+//! and skips the task list if the stuck code holds the registry lock; if it
+//! holds the console lock the dump cannot be shown.
+
+use crate::prelude::*;
+use crate::arch::task::{self, MAX_CPUS};
+use crate::arch::tsc_timer::{rdtsc, tsc_hz};
+use crate::sync::IrqEvent;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Default lockup threshold in seconds.
+pub const DEFAULT_THRESHOLD_SECS: u64 = 10;
+
+static THRESHOLD_SECS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_SECS);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Per CPU: TSC when the watchdog task last ran, TSC of the last kick, and
+// whether the current lockup was already reported.
+static TOUCHED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static KICKED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static REPORTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+static KICK: [IrqEvent; MAX_CPUS] = [const { IrqEvent::new() }; MAX_CPUS];
+
+/// Spawn the watchdog task on every online CPU and start checking.
+pub fn init() {
+    let now = rdtsc();
+    for cpu in 0..task::online_cpus() {
+        TOUCHED[cpu].store(now, Ordering::Relaxed);
+        KICKED[cpu].store(now, Ordering::Relaxed);
+        task::spawn_on(cpu, watchdog_task(cpu));
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn set_threshold_secs(secs: u64) {
+    THRESHOLD_SECS.store(secs.max(1), Ordering::Relaxed);
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+/// Record that `cpu` is scheduling normally.
+pub fn touch(cpu: usize) {
+    TOUCHED[cpu].store(rdtsc(), Ordering::Relaxed);
+    REPORTED[cpu].store(false, Ordering::Relaxed);
+}
+
+async fn watchdog_task(cpu: usize) {
+    loop {
+        touch(cpu);
+        KICK[cpu].wait().await;
+    }
+}
+
+/// Called from the timer interrupt on each CPU.
+pub fn on_timer_tick(frame: &InterruptStackFrame) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let cpu = task::current_cpu();
+    let now = rdtsc();
+    let hz = tsc_hz();
+
+    // wake the watchdog task about once a second
+    if now.wrapping_sub(KICKED[cpu].load(Ordering::Relaxed)) >= hz {
+        KICKED[cpu].store(now, Ordering::Relaxed);
+        KICK[cpu].signal();
+    }
+
+    let stalled = now.wrapping_sub(TOUCHED[cpu].load(Ordering::Relaxed));
+    let threshold = THRESHOLD_SECS.load(Ordering::Relaxed).saturating_mul(hz);
+    if stalled >= threshold && !REPORTED[cpu].swap(true, Ordering::Relaxed) {
+        report(cpu, stalled / hz.max(1), frame);
+    }
+}
+
+fn report(cpu: usize, secs: u64, frame: &InterruptStackFrame) {
+    println!("[WATCHDOG] soft lockup on cpu{}: not scheduled for {}s", cpu, secs);
+    println!("[WATCHDOG] rip={} rsp={:#x} cs={:#x} rflags={:#x}",
+        crate::symbols::Sym(frame.instruction_pointer.as_u64()), frame.stack_pointer.as_u64(),
+        frame.code_segment.0, frame.cpu_flags.bits());
+    let listed = task::try_for_each_task(|t| {
+        println!("[WATCHDOG]   task {} {:?} polls={} {}", t.id, t.state, t.polls, t.name);
+    });
+    if !listed {
+        println!("[WATCHDOG]   (task list locked)");
+    }
+    for vector in 0..=255u8 {
+        let count = crate::arch::idt::irq_count(vector);
+        if count != 0 {
+            println!("[WATCHDOG]   irq 0x{:02x}: {}", vector, count);
+        }
+    }
+}
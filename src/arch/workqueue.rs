@@ -0,0 +1,70 @@
+//! Deferred work for driver bottom halves
+//!
+//! `queue_work` hands a closure to a pool of worker tasks running on the
+//! kernel executor. Each worker runs one item and then yields, so a burst of
+//! work can't starve other tasks (keyboard echo, mouse drawing) for longer
+//! than a single item takes.
+//!
+//! Queueing allocates, so call it from task or driver context rather than
+//! directly from an interrupt handler; IRQ handlers should push into their
+//! own lock-free queue and wake a task as the PS/2 drivers do.
+
+use crate::prelude::*;
+use crate::arch::task;
+use crate::sync::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static PENDING: Mutex<VecDeque<Work>> = Mutex::new(VecDeque::new());
+static WORK_WAIT: WaitQueue = WaitQueue::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+/// Default number of worker tasks started by `init`.
+pub const DEFAULT_WORKERS: usize = 2;
+
+/// Queue `work` to run on a worker task. Work queued before `init` runs
+/// once the workers start.
+pub fn queue_work(work: impl FnOnce() + Send + 'static) {
+    let work: Work = Box::new(work);
+    interrupts::without_interrupts(|| PENDING.lock().push_back(work));
+    WORK_WAIT.wake_one();
+}
+
+/// Spawn `workers` worker tasks. Calling it again is a no-op.
+pub fn init(workers: usize) {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    for _ in 0..workers.max(1) {
+        task::spawn_named("kworker", worker());
+    }
+}
+
+/// Number of work items waiting for a worker.
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| PENDING.lock().len())
+}
+
+/// Number of work items run since boot.
+pub fn completed() -> u64 {
+    COMPLETED.load(Ordering::Relaxed)
+}
+
+fn pop_work() -> Option<Work> {
+    interrupts::without_interrupts(|| PENDING.lock().pop_front())
+}
+
+async fn worker() {
+    loop {
+        let work = WORK_WAIT.wait_until(pop_work).await;
+        work();
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+        task::yield_now().await;
+    }
+}
@@ -0,0 +1,9 @@
+//! Bootloader hand-off
+//!
+//! Each entry path turns what its loader passes into `BootParams`, which is
+//! all the rest of the kernel sees of the loader.
+
+pub mod params;
+pub use params::*;
+pub mod multiboot2;
+pub use multiboot2::*;
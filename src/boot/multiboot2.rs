@@ -0,0 +1,321 @@
+//! Multiboot2 entry
+//!
+//! The kernel image carries a Multiboot2 header, so GRUB (or any other
+//! Multiboot2 loader) can start it as well as the `bootloader` crate. The
+//! header asks for the 32-bit entry `neutrix_multiboot2_start`, which turns
+//! on SSE, identity-maps the low 4 GiB with 2 MiB pages, enters long mode
+//! and calls the kernel's `multiboot2_entry_point!` function. That turns the
+//! Multiboot2 information structure into `BootParams`, with a physical
+//! memory offset of 0, and calls the kernel entry.
+//!
+//! Only memory below 4 GiB is reported usable, since nothing above it is
+//! mapped. The kernel image, the information structure and any modules are
+//! kept out of the usable regions. The framebuffer, modules and the RSDP
+//! copy go into `BootParams` as they are.
+//!
+//! GRUB only looks for the header in the first 32 KiB of the file, so the
+//! link for a GRUB image must put `.multiboot2_header` first.
+
+use crate::prelude::*;
+use crate::boot::params::{install_boot_params, BootFramebuffer, BootParams, MemoryKind, MemoryMap, MAX_BOOT_MODULES};
+
+/// What a Multiboot2 loader leaves in EAX.
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+const TAG_END: u32 = 0;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_ACPI_NVS: u32 = 4;
+const MMAP_BAD: u32 = 5;
+/// Framebuffer type for direct RGB colour.
+const FRAMEBUFFER_RGB: u8 = 1;
+const SHF_ALLOC: u64 = 2;
+
+/// What the entry code identity-maps.
+const IDENTITY_MAPPED: u64 = 4 << 30;
+/// Real-mode structures live below here; none of it is handed out.
+const LOW_MEMORY: u64 = 0x10_0000;
+const PAGE_SIZE: u64 = 4096;
+
+// The header, the 32-bit entry and its page tables. Left out of the test
+// kernel, which has no `multiboot2_entry_point!`.
+#[cfg(not(test))]
+core::arch::global_asm!(
+    ".section .multiboot2_header, \"aR\"",
+    ".balign 8",
+    "mb2_header:",
+    ".long 0xE85250D6",
+    ".long 0",
+    ".long mb2_header_end - mb2_header",
+    ".long 0x100000000 - (0xE85250D6 + (mb2_header_end - mb2_header))",
+    // entry address
+    ".balign 8",
+    ".short 3, 0",
+    ".long 12",
+    ".long neutrix_multiboot2_start",
+    // a linear framebuffer if there is one, any size, 32 bpp preferred
+    ".balign 8",
+    ".short 5, 1",
+    ".long 20",
+    ".long 0, 0, 32",
+    ".balign 8",
+    ".short 0, 0",
+    ".long 8",
+    "mb2_header_end:",
+    "",
+    ".section .rodata.multiboot2, \"a\"",
+    ".balign 8",
+    "mb2_gdt:",
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    "mb2_gdt_ptr:",
+    ".short 23",
+    ".long mb2_gdt",
+    "",
+    ".section .bss.multiboot2, \"aw\", @nobits",
+    ".balign 4096",
+    "mb2_pml4: .skip 4096",
+    "mb2_pdpt: .skip 4096",
+    "mb2_pd: .skip 4 * 4096",
+    "mb2_stack: .skip 64 * 1024",
+    "mb2_stack_top:",
+    "",
+    // entered in 32-bit protected mode, paging off: eax = magic, ebx = info
+    ".section .text.multiboot2, \"ax\"",
+    ".code32",
+    ".global neutrix_multiboot2_start",
+    "neutrix_multiboot2_start:",
+    "cli",
+    "mov edi, eax",
+    "mov esi, ebx",
+    "mov esp, offset mb2_stack_top",
+    "mov eax, 0x80000000",
+    "cpuid",
+    "cmp eax, 0x80000001",
+    "jb mb2_halt",
+    "mov eax, 0x80000001",
+    "cpuid",
+    "test edx, 1 << 29",
+    "jz mb2_halt",
+    // PML4[0] -> PDPT, PDPT[0..4] -> the four PDs, each PD entry a 2 MiB page
+    "mov eax, offset mb2_pdpt",
+    "or eax, 3",
+    "mov [mb2_pml4], eax",
+    "xor ecx, ecx",
+    "mb2_fill_pdpt:",
+    "mov eax, ecx",
+    "shl eax, 12",
+    "add eax, offset mb2_pd",
+    "or eax, 3",
+    "mov [mb2_pdpt + ecx * 8], eax",
+    "inc ecx",
+    "cmp ecx, 4",
+    "jne mb2_fill_pdpt",
+    "xor ecx, ecx",
+    "mb2_fill_pd:",
+    "mov eax, ecx",
+    "shl eax, 21",
+    "or eax, 0x83",
+    "mov [mb2_pd + ecx * 8], eax",
+    "inc ecx",
+    "cmp ecx, 2048",
+    "jne mb2_fill_pd",
+    "mov eax, offset mb2_pml4",
+    "mov cr3, eax",
+    // PAE, OSFXSR and OSXMMEXCPT: Rust code may use SSE from the start
+    "mov eax, cr4",
+    "or eax, (1 << 5) | (1 << 9) | (1 << 10)",
+    "mov cr4, eax",
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, 1 << 8",
+    "wrmsr",
+    // paging and protection on, FPU emulation off, monitor coprocessor on
+    "mov eax, cr0",
+    "and eax, ~(1 << 2)",
+    "or eax, (1 << 31) | (1 << 1) | 1",
+    "mov cr0, eax",
+    "lgdt [mb2_gdt_ptr]",
+    "push 0x08",
+    "mov eax, offset mb2_long_mode",
+    "push eax",
+    "retf",
+    "mb2_halt:",
+    "hlt",
+    "jmp mb2_halt",
+    ".code64",
+    "mb2_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "lea rsp, [rip + mb2_stack_top]",
+    // the upper halves are undefined after the switch
+    "mov edi, edi",
+    "mov esi, esi",
+    "call neutrix_multiboot2_main",
+    "mb2_hang:",
+    "hlt",
+    "jmp mb2_hang",
+    ".text",
+);
+
+/// Define the function the Multiboot2 entry code calls, passing control
+/// to `$path`, a `fn(&'static BootParams) -> !`.
+#[macro_export]
+macro_rules! multiboot2_entry_point {
+    ($path:path) => {
+        #[unsafe(no_mangle)]
+        extern "C" fn neutrix_multiboot2_main(magic: u32, info: u32) -> ! {
+            let kernel: fn(&'static $crate::boot::BootParams) -> ! = $path;
+            unsafe { $crate::boot::multiboot2::multiboot2_start(magic, info, kernel) }
+        }
+    };
+}
+
+fn read<T: Copy>(addr: u64) -> T {
+    unsafe { core::ptr::read_unaligned(addr as *const T) }
+}
+
+/// The tags of the information structure at `info`, as (type, address of
+/// the tag, size).
+fn tags(info: u64) -> impl Iterator<Item = (u32, u64, u64)> {
+    let end = info + read::<u32>(info) as u64;
+    let mut at = info + 8;
+    core::iter::from_fn(move || {
+        if at + 8 > end {
+            return None;
+        }
+        let (kind, size) = (read::<u32>(at), read::<u32>(at + 4) as u64);
+        if kind == TAG_END || size < 8 {
+            return None;
+        }
+        let tag = (kind, at, size);
+        at = (at + size + 7) & !7;
+        Some(tag)
+    })
+}
+
+/// Physical range of the loaded kernel, from its section headers.
+fn image_range(tag: u64) -> Option<(u64, u64)> {
+    let (count, entsize) = (read::<u32>(tag + 8) as u64, read::<u32>(tag + 12) as u64);
+    let headers = tag + 20;
+    let mut range: Option<(u64, u64)> = None;
+    for i in 0..count {
+        let sh = headers + i * entsize;
+        let (flags, addr, size) = (read::<u64>(sh + 8), read::<u64>(sh + 16), read::<u64>(sh + 32));
+        if flags & SHF_ALLOC == 0 || size == 0 {
+            continue;
+        }
+        let (lo, hi) = range.unwrap_or((addr, addr + size));
+        range = Some((lo.min(addr), hi.max(addr + size)));
+    }
+    range
+}
+
+/// Add the whole pages of `start..end` not covered by `busy` (sorted) as
+/// usable.
+fn add_usable(map: &mut MemoryMap, start: u64, end: u64, busy: &[(u64, u64)]) {
+    let end = end.min(IDENTITY_MAPPED) & !(PAGE_SIZE - 1);
+    let mut start = (start.max(LOW_MEMORY) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    for &(b0, b1) in busy {
+        if b1 <= start || b0 >= end {
+            continue;
+        }
+        map.push(start, b0 & !(PAGE_SIZE - 1), MemoryKind::Usable);
+        start = start.max((b1 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+    }
+    map.push(start, end, MemoryKind::Usable);
+}
+
+/// Turn the information structure at `info` into `BootParams`.
+fn translate(info: u64) -> BootParams {
+    let mut params = BootParams::new("multiboot2", 0);
+    let mut image = None;
+    let mut mmap = None;
+    for (kind, tag, size) in tags(info) {
+        match kind {
+            TAG_MODULE => {
+                params.add_module(read::<u32>(tag + 8) as u64, read::<u32>(tag + 12) as u64);
+            }
+            TAG_MMAP => mmap = Some((tag, size)),
+            TAG_FRAMEBUFFER if read::<u8>(tag + 29) == FRAMEBUFFER_RGB => {
+                params.framebuffer = Some(BootFramebuffer {
+                    addr: read(tag + 8),
+                    pitch: read(tag + 16),
+                    width: read(tag + 20),
+                    height: read(tag + 24),
+                    bpp: read(tag + 28),
+                });
+            }
+            TAG_ELF_SECTIONS => image = image_range(tag),
+            // prefer the ACPI 2.0 copy when both are there
+            TAG_ACPI_OLD if params.rsdp.is_none() => params.rsdp = Some(tag + 8),
+            TAG_ACPI_NEW => params.rsdp = Some(tag + 8),
+            _ => {}
+        }
+    }
+    // GRUB always passes the section headers of an ELF kernel; otherwise
+    // reserve a MiB past this code, as main does
+    let image = image.unwrap_or((LOW_MEMORY, (multiboot2_start as usize as u64 & !(PAGE_SIZE - 1)) + (1 << 20)));
+    let info_range = (info, info + read::<u32>(info) as u64);
+    let mut modules = [(0u64, 0u64); MAX_BOOT_MODULES];
+    let modules = &mut modules[..params.modules().len()];
+    modules.copy_from_slice(params.modules());
+    let mut busy = [(0u64, 0u64); 2 + MAX_BOOT_MODULES];
+    busy[0] = image;
+    busy[1] = info_range;
+    busy[2..2 + modules.len()].copy_from_slice(modules);
+    let busy = &mut busy[..2 + modules.len()];
+    busy.sort_unstable();
+
+    let map = &mut params.memory_map;
+    if let Some((tag, size)) = mmap {
+        let entry_size = read::<u32>(tag + 8) as u64;
+        let mut entry = tag + 16;
+        while entry_size >= 24 && entry + entry_size <= tag + size {
+            let (base, len, kind) = (read::<u64>(entry), read::<u64>(entry + 8), read::<u32>(entry + 16));
+            let end = base.saturating_add(len);
+            match kind {
+                MMAP_AVAILABLE => add_usable(map, base, end, busy),
+                MMAP_ACPI_RECLAIMABLE => _ = map.push(base, end, MemoryKind::AcpiReclaimable),
+                MMAP_ACPI_NVS => _ = map.push(base, end, MemoryKind::AcpiNvs),
+                MMAP_BAD => _ = map.push(base, end, MemoryKind::Bad),
+                _ => _ = map.push(base, end, MemoryKind::Reserved),
+            }
+            entry += entry_size;
+        }
+    }
+    map.push(image.0, image.1, MemoryKind::Kernel);
+    map.push(info_range.0, info_range.1, MemoryKind::Loader);
+    for &(start, end) in modules.iter() {
+        map.push(start, end, MemoryKind::Module);
+    }
+    params
+}
+
+/// Translate the loader's information, install it and call `kernel`.
+/// Called by the function `multiboot2_entry_point!` defines.
+///
+/// # Safety
+/// Only from the Multiboot2 entry code, once, with the loader's EAX and
+/// EBX, while the low 4 GiB are identity-mapped.
+pub unsafe fn multiboot2_start(magic: u32, info: u32, kernel: fn(&'static BootParams) -> !) -> ! {
+    if magic != MULTIBOOT2_BOOTLOADER_MAGIC {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    kernel(install_boot_params(translate(info as u64)))
+}
@@ -0,0 +1,215 @@
+//! Boot parameters
+//!
+//! `BootParams` is what the kernel needs from whichever loader started it:
+//! the physical memory map, where physical memory is mapped, and the
+//! framebuffer, RSDP and modules the loader found. Each entry path fills
+//! one in and hands it to `install_boot_params`; after that the frame
+//! allocator, ACPI discovery, the framebuffer driver and the initrd read
+//! it through `boot_params()` and never see a loader's own types.
+
+use crate::prelude::*;
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
+use spin::Once;
+
+/// Regions a `MemoryMap` holds; later ones are dropped.
+pub const MAX_MEMORY_REGIONS: usize = 128;
+/// Modules a `BootParams` remembers.
+pub const MAX_BOOT_MODULES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free for the frame allocator.
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Bad,
+    /// The kernel image, its stack and the page tables the loader built.
+    Kernel,
+    /// The loader's own data, including what it handed over.
+    Loader,
+    /// A module (initrd) the loader put in memory.
+    Module,
+}
+
+/// Physical memory from `start` up to, not including, `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    pub fn len(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The physical memory map, in the order the loader gave it.
+#[derive(Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> MemoryMap {
+        MemoryMap { regions: [MemoryRegion { start: 0, end: 0, kind: MemoryKind::Reserved }; MAX_MEMORY_REGIONS], len: 0 }
+    }
+
+    /// Add `start..end`; empty ranges are ignored. Returns false if the
+    /// map is full.
+    pub fn push(&mut self, start: u64, end: u64, kind: MemoryKind) -> bool {
+        if start >= end {
+            return true;
+        }
+        if self.len == MAX_MEMORY_REGIONS {
+            return false;
+        }
+        self.regions[self.len] = MemoryRegion { start, end, kind };
+        self.len += 1;
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.len].iter()
+    }
+
+    pub fn usable(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.iter().filter(|r| r.kind == MemoryKind::Usable)
+    }
+
+    pub fn usable_bytes(&self) -> u64 {
+        self.usable().map(|r| r.len()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> MemoryMap {
+        MemoryMap::new()
+    }
+}
+
+/// A linear RGB framebuffer the loader set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootFramebuffer {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+}
+
+#[derive(Clone)]
+pub struct BootParams {
+    /// Which entry path filled this in.
+    pub loader: &'static str,
+    pub memory_map: MemoryMap,
+    /// Virtual address at which all physical memory is mapped.
+    pub phys_offset: u64,
+    pub framebuffer: Option<BootFramebuffer>,
+    /// Physical address of an RSDP the loader found.
+    pub rsdp: Option<u64>,
+    modules: [(u64, u64); MAX_BOOT_MODULES],
+    module_count: usize,
+}
+
+impl BootParams {
+    pub const fn new(loader: &'static str, phys_offset: u64) -> BootParams {
+        BootParams {
+            loader,
+            memory_map: MemoryMap::new(),
+            phys_offset,
+            framebuffer: None,
+            rsdp: None,
+            modules: [(0, 0); MAX_BOOT_MODULES],
+            module_count: 0,
+        }
+    }
+
+    /// Remember a module at `start..end`. Returns false once full.
+    pub fn add_module(&mut self, start: u64, end: u64) -> bool {
+        if self.module_count == MAX_BOOT_MODULES {
+            return false;
+        }
+        self.modules[self.module_count] = (start, end);
+        self.module_count += 1;
+        true
+    }
+
+    /// Physical ranges of the modules, in loader order.
+    pub fn modules(&self) -> &[(u64, u64)] {
+        &self.modules[..self.module_count]
+    }
+
+    /// From the `bootloader` crate's `BootInfo`. It passes no framebuffer,
+    /// RSDP or modules.
+    pub fn from_bootloader(info: &BootInfo) -> BootParams {
+        let mut params = BootParams::new("bootloader", info.physical_memory_offset);
+        for region in info.memory_map.iter() {
+            let kind = match region.region_type {
+                MemoryRegionType::Usable => MemoryKind::Usable,
+                MemoryRegionType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+                MemoryRegionType::AcpiNvs => MemoryKind::AcpiNvs,
+                MemoryRegionType::BadMemory => MemoryKind::Bad,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack | MemoryRegionType::PageTable => MemoryKind::Kernel,
+                MemoryRegionType::InUse | MemoryRegionType::Bootloader | MemoryRegionType::BootInfo => MemoryKind::Loader,
+                MemoryRegionType::Package => MemoryKind::Module,
+                _ => MemoryKind::Reserved,
+            };
+            params.memory_map.push(region.range.start_addr(), region.range.end_addr(), kind);
+        }
+        params
+    }
+}
+
+static PARAMS: Once<BootParams> = Once::new();
+
+/// Keep `params` for the rest of the kernel's life. The first call wins;
+/// only entry paths call this.
+pub fn install_boot_params(params: BootParams) -> &'static BootParams {
+    PARAMS.call_once(|| params)
+}
+
+/// What the loader passed, once an entry path has installed it.
+pub fn boot_params() -> Option<&'static BootParams> {
+    PARAMS.r#try()
+}
+
+/// The contents of module `index`, through the physical memory mapping.
+pub fn boot_module(index: usize) -> Option<&'static [u8]> {
+    let params = boot_params()?;
+    let &(start, end) = params.modules().get(index)?;
+    let virt = params.phys_offset + start;
+    Some(unsafe { core::slice::from_raw_parts(virt as *const u8, end.saturating_sub(start) as usize) })
+}
+
+/// Log what the loader handed over.
+pub fn report_boot_params() {
+    let Some(params) = boot_params() else { return };
+    println!(
+        "[BOOT] {}: {} memory regions, {} MiB usable, {} modules, RSDP {}",
+        params.loader,
+        params.memory_map.len(),
+        params.memory_map.usable_bytes() >> 20,
+        params.modules().len(),
+        if params.rsdp.is_some() { "from the loader" } else { "not given" }
+    );
+    if let Some(fb) = params.framebuffer {
+        println!("[BOOT] framebuffer {}x{}x{} at {:#x}, pitch {}", fb.width, fb.height, fb.bpp, fb.addr, fb.pitch);
+    }
+}
@@ -1,12 +1,12 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::arch::ports::outb;
+use crate::sync::IrqSpinlock;
 use volatile::Volatile;
 
-pub use crate::*;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
         row_position: BUFFER_HEIGHT - 1,
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
@@ -37,6 +37,36 @@ pub enum Color {
     White = 15,
 }
 
+const COLOR_NAMES: [(&str, Color); 16] = [
+    ("black", Color::Black),
+    ("blue", Color::Blue),
+    ("green", Color::Green),
+    ("cyan", Color::Cyan),
+    ("red", Color::Red),
+    ("magenta", Color::Magenta),
+    ("brown", Color::Brown),
+    ("lightgray", Color::LightGray),
+    ("darkgray", Color::DarkGray),
+    ("lightblue", Color::LightBlue),
+    ("lightgreen", Color::LightGreen),
+    ("lightcyan", Color::LightCyan),
+    ("lightred", Color::LightRed),
+    ("pink", Color::Pink),
+    ("yellow", Color::Yellow),
+    ("white", Color::White),
+];
+
+impl Color {
+    /// Parse a lowercase color name such as `lightgray`.
+    pub fn from_name(name: &str) -> Option<Color> {
+        COLOR_NAMES.iter().find(|(n, _)| *n == name).map(|&(_, c)| c)
+    }
+
+    pub fn name(self) -> &'static str {
+        COLOR_NAMES[self as usize].0
+    }
+}
+
 /// A combination of a foreground and a background color.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -176,11 +206,11 @@ impl Writer {
         self.color_code = ColorCode::new(foreground, background);
     }
 
+    /// Move the cursor; a position past the last row or column is clamped
+    /// to it.
     pub fn set_position(&mut self, row: usize, col: usize) {
-        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
-            self.row_position = row;
-            self.column_position = col;
-        }
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
 		self.update_cursor();
     }
 
@@ -190,6 +220,16 @@ impl Writer {
         }
         self.row_position = 0;
         self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Move the text up `lines` rows, and the cursor with it.
+    pub fn scroll(&mut self, lines: usize) {
+        for _ in 0..lines.min(BUFFER_HEIGHT) {
+            self.shift_up();
+        }
+        self.row_position = self.row_position.saturating_sub(lines);
+        self.update_cursor();
     }
 }
 
@@ -199,17 +239,3 @@ impl fmt::Write for Writer {
         Ok(())
     }
 }
-
-
-
-
-/// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    // Prefer VBE driver when available
-    if crate::driver_framework::drivers::vbe_vga::vbe_try_print(args) { return; }
-
-    // Fallback to boot VGA text buffer
-    WRITER.lock().write_fmt(args).unwrap();
-}
\ No newline at end of file
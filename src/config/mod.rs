@@ -0,0 +1,229 @@
+//! Kernel configuration store
+//!
+//! Settings are `key = value` lines; `#` starts a comment. At boot
+//! `load_config` reads `/etc/neutrix.conf` from the initrd, then
+//! `/neutrix.conf` from the first FAT32 volume on a block device, whose
+//! values win, and applies every key it knows. `config_set` changes a
+//! setting at runtime, applying it at once where that makes sense;
+//! `save_config` writes the whole store back to the FAT volume, since the
+//! initrd can't be written.
+//!
+//! Known keys:
+//!
+//! - `console.fg`, `console.bg`: a VGA color name such as `lightgray`
+//! - `console.cursor`: `block`, `underline` or `off`
+//! - `video.mode`: `WIDTHxHEIGHTxBPP`, used when the framebuffer driver
+//!   starts, so a change takes effect at the next boot
+//! - `keyboard.layout`: one of `tty::KEYBOARD_LAYOUTS`
+//! - `log.level`: the level of every log sink
+//! - `mouse.sensitivity`, `mouse.accel`, `mouse.accel_threshold`,
+//!   `mouse.max_delta`, `mouse.invert_y`: see `input::motion`
+//! - `screen.blank`: seconds without input before the screen blanks, 0
+//!   for never
+//! - `screen.brightness`, `screen.gamma`: percent of full brightness
+//!   (10-100) and gamma (0.3-3.0) colors are drawn with
+//! - `DRIVER.PARAM`: a parameter for a driver attached after the config is
+//!   read, such as `vbe.mode`, `serial.baud` or `ps2mouse.rate`; see
+//!   `DriverParams::for_driver`
+//!
+//! Other keys are kept and saved but mean nothing to the kernel.
+
+use crate::prelude::*;
+use crate::bootvga::vga_buffer::Color;
+use crate::fs::fat::{FatError, FatFs};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const CONFIG_PATH: &str = "/etc/neutrix.conf";
+/// Where the config lives on a FAT boot partition.
+pub const FAT_CONFIG_PATH: &str = "/neutrix.conf";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The value doesn't suit the key.
+    BadValue(String),
+    /// No FAT32 volume to save to.
+    NoBootPartition,
+    Fat(FatError),
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::BadValue(why) => f.write_str(why),
+            ConfigError::NoBootPartition => f.write_str("no FAT32 boot partition"),
+            ConfigError::Fat(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<FatError> for ConfigError {
+    fn from(e: FatError) -> Self {
+        ConfigError::Fat(e)
+    }
+}
+
+static CONFIG: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Parse config text into (key, value) pairs, in order. Lines without an
+/// `=` are reported and skipped.
+pub fn parse_config(text: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => out.push((key.trim().to_string(), value.trim().to_string())),
+            _ => println!("[CONFIG] line {}: expected key = value", n + 1),
+        }
+    }
+    out
+}
+
+/// The store as config text, keys sorted.
+pub fn format_config() -> String {
+    let config = CONFIG.lock();
+    let mut text = String::from("# neutrix configuration\n");
+    for (key, value) in config.iter() {
+        text.push_str(&format!("{} = {}\n", key, value));
+    }
+    text
+}
+
+/// `WIDTHxHEIGHT[xBPP]`, 32 bpp if left out.
+pub fn parse_video_mode(value: &str) -> Option<(u16, u16, u16)> {
+    let mut parts = value.split('x').map(|p| p.parse::<u16>().ok());
+    let mode = (parts.next()??, parts.next()??, parts.next().unwrap_or(Some(32))?);
+    (parts.next().is_none() && mode.0 > 0 && mode.1 > 0 && matches!(mode.2, 8 | 15 | 16 | 24 | 32)).then_some(mode)
+}
+
+fn console_color(key: &str, fallback: Color) -> Color {
+    config_get(key).and_then(|name| Color::from_name(&name)).unwrap_or(fallback)
+}
+
+/// Make `key = value` take effect. Unknown keys are accepted as they are.
+fn apply(key: &str, value: &str) -> Result<(), ConfigError> {
+    let bad = |what: &str| ConfigError::BadValue(format!("{}: {}", key, what));
+    match key {
+        "console.fg" | "console.bg" => {
+            let color = Color::from_name(value).ok_or_else(|| bad("not a color name"))?;
+            let (fg, bg) = if key == "console.fg" {
+                (color, console_color("console.bg", Color::Black))
+            } else {
+                (console_color("console.fg", Color::Yellow), color)
+            };
+            setcolor!(fg, bg);
+        }
+        "console.cursor" => {
+            let style = crate::driver_framework::drivers::console::CursorStyle::parse(value)
+                .ok_or_else(|| bad("want block, underline or off"))?;
+            crate::driver_framework::drivers::console::set_console_cursor(style);
+        }
+        "video.mode" => {
+            let (xres, yres, bpp) = parse_video_mode(value).ok_or_else(|| bad("want WIDTHxHEIGHTxBPP"))?;
+            crate::driver_framework::drivers::vbe_vga::set_default_video_mode(xres, yres, bpp);
+        }
+        "keyboard.layout" => {
+            if !crate::devices::tty::set_keyboard_layout(value) {
+                return Err(bad("unknown layout"));
+            }
+        }
+        "screen.blank" => {
+            let secs = value.parse::<u64>().map_err(|_| bad("want a number of seconds"))?;
+            crate::input::set_blank_timeout(secs);
+        }
+        "screen.brightness" | "screen.gamma" => {
+            use crate::driver_framework::drivers::vbe_vga;
+            let (mut brightness, mut gamma) = vbe_vga::display_levels();
+            if key == "screen.brightness" {
+                brightness = value.trim_end_matches('%').parse().map_err(|_| bad("want a percentage"))?;
+            } else {
+                gamma = vbe_vga::parse_gamma(value).ok_or_else(|| bad("want a number such as 1.8"))?;
+            }
+            vbe_vga::set_display_levels(brightness, gamma).map_err(bad)?;
+        }
+        "log.level" => {
+            let level = crate::log::Level::parse(value).ok_or_else(|| bad("want error, warn, info, debug or trace"))?;
+            for (name, _) in crate::log::sinks().into_iter().flatten() {
+                crate::log::set_sink_level(name, level);
+            }
+        }
+        _ => {
+            if let Some(name) = key.strip_prefix("mouse.")
+                && crate::input::MOUSE_SETTINGS.contains(&name)
+            {
+                crate::input::set_mouse_setting(name, value).map_err(bad)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn config_get(key: &str) -> Option<String> {
+    CONFIG.lock().get(key).cloned()
+}
+
+/// Apply `value` and remember it. A value a known key can't use is
+/// rejected and nothing changes.
+pub fn config_set(key: &str, value: &str) -> Result<(), ConfigError> {
+    // stored first, so applying one console color can read the other
+    let old = CONFIG.lock().insert(key.to_string(), value.to_string());
+    let result = apply(key, value);
+    if result.is_err() {
+        let mut config = CONFIG.lock();
+        match old {
+            Some(old) => config.insert(key.to_string(), old),
+            None => config.remove(key),
+        };
+    }
+    result
+}
+
+/// Forget `key`. What it set stays in effect until the next boot.
+pub fn config_unset(key: &str) -> bool {
+    CONFIG.lock().remove(key).is_some()
+}
+
+/// Every setting, sorted by key.
+pub fn config_entries() -> Vec<(String, String)> {
+    CONFIG.lock().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+fn boot_partition() -> Option<FatFs> {
+    crate::storage::block_devices().into_iter().find_map(|queue| FatFs::mount_first_partition(queue).ok())
+}
+
+fn load_text(text: &str, source: &str) {
+    let entries = parse_config(text);
+    for (key, value) in &entries {
+        if let Err(e) = config_set(key, value) {
+            println!("[CONFIG] {}: {}", source, e);
+        }
+    }
+    println!("[CONFIG] {} settings from {}", entries.len(), source);
+}
+
+/// Read and apply the config from the initrd and the boot partition.
+pub fn load_config() {
+    if let Ok(data) = crate::fs::vfs::read_file(CONFIG_PATH) {
+        load_text(&String::from_utf8_lossy(&data), CONFIG_PATH);
+    }
+    if let Some(fs) = boot_partition() {
+        if let Ok(data) = fs.read_to_vec(FAT_CONFIG_PATH) {
+            load_text(&String::from_utf8_lossy(&data), &format!("{}:{}", fs.queue().name(), FAT_CONFIG_PATH));
+        }
+    }
+}
+
+/// Write the store to the boot partition. Returns the device written.
+pub fn save_config() -> Result<String, ConfigError> {
+    let fs = boot_partition().ok_or(ConfigError::NoBootPartition)?;
+    fs.write_all(FAT_CONFIG_PATH, format_config().as_bytes())?;
+    fs.sync()?;
+    Ok(fs.queue().name().to_string())
+}
@@ -1,15 +1,17 @@
-use crate::*;
+use crate::prelude::*;
+use crate::arch::interrupts::InterruptIndex;
 use x86_64::structures::idt::*;
 
 pub extern "x86-interrupt" fn void(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
-    unsafe {
-        // If Local APIC is present use APIC EOI, otherwise notify PICs
-        if crate::hal::apic::is_initialized() {
-            crate::hal::apic::send_eoi();
-        } else {
-            PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-        }
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    crate::arch::idt::irq_enter();
+    // If Local APIC is present use APIC EOI, otherwise notify PICs
+    if crate::hal::apic::is_initialized() {
+        unsafe { crate::hal::apic::send_eoi() };
+    } else {
+        crate::hal::intctl::pic_eoi(InterruptIndex::Timer.as_u8());
     }
+    crate::arch::idt::irq_exit(&stack_frame);
 }
\ No newline at end of file
@@ -1,8 +1,8 @@
-use crate::*;
+use crate::prelude::*;
 use core::str;
 use core::ptr;
 use x86_64::VirtAddr;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
 use alloc::vec::Vec;
 use spin::Mutex;
 use crate::driver_framework::manager::GLOBAL_MANAGER;
@@ -77,6 +77,14 @@ impl AcpiTableHeader {
 
 /// Find the RSDP in memory
 pub fn find_rsdp(phys_offset: u64) -> Option<&'static Rsdp> {
+    // the loader may pass one, which UEFI machines need: their RSDP isn't
+    // in the BIOS areas
+    if let Some(addr) = crate::boot::boot_params().and_then(|p| p.rsdp) {
+        let rsdp = unsafe { &*((addr + phys_offset) as *const Rsdp) };
+        if rsdp.is_valid() && rsdp.checksum_valid() {
+            return Some(rsdp);
+        }
+    }
     // Search in EBDA (Extended BIOS Data Area) first
     let ebda_start = 0x9FC00;
     let ebda_end = 0xA0000;
@@ -301,6 +309,61 @@ pub struct McfgAllocation {
     pub reserved: [u8; 4],
 }
 
+/// DMAR (DMA remapping) table, describing the VT-d IOMMUs
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Dmar {
+    pub header: AcpiTableHeader,
+    /// Most significant DMA address bit, minus one.
+    pub host_address_width: u8,
+    pub flags: u8,
+    pub reserved: [u8; 10],
+    // Followed by variable number of remapping structures
+}
+
+/// A device scope entry of a DMAR structure: the PCI function (type 1) or
+/// hierarchy below a bridge (type 2) reached from `start_bus` by following
+/// `path`, one (device, function) per bridge crossed. Types 3 and 4 are
+/// I/O APICs and HPETs, named by `enumeration_id`.
+#[derive(Debug, Clone)]
+pub struct DmarScope {
+    pub kind: u8,
+    pub enumeration_id: u8,
+    pub start_bus: u8,
+    pub path: Vec<(u8, u8)>,
+}
+
+/// A DMA remapping hardware unit (DRHD).
+#[derive(Debug, Clone)]
+pub struct DmarUnit {
+    pub segment: u16,
+    pub register_base: u64,
+    /// Covers every device of the segment no other unit lists.
+    pub include_pci_all: bool,
+    pub scopes: Vec<DmarScope>,
+}
+
+/// A reserved memory region (RMRR): memory the firmware has devices DMA
+/// to behind the OS's back, which must stay mapped 1:1 for them.
+#[derive(Debug, Clone)]
+pub struct DmarReservedRegion {
+    pub segment: u16,
+    pub base: u64,
+    /// Last byte of the region.
+    pub limit: u64,
+    pub scopes: Vec<DmarScope>,
+}
+
+/// What the DMAR describes.
+#[derive(Debug, Clone, Default)]
+pub struct DmarInfo {
+    /// DMA address bits the platform decodes.
+    pub host_address_width: u8,
+    pub flags: u8,
+    pub units: Vec<DmarUnit>,
+    pub reserved: Vec<DmarReservedRegion>,
+}
+
 impl Rsdt {
     /// Get the number of table entries
     pub fn entry_count(&self) -> usize {
@@ -385,6 +448,26 @@ pub fn parse_rsdt_xsdt(rsdp: &Rsdp, phys_offset: u64) {
     }
 }
 
+/// A table listed in the RSDT/XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTable {
+    pub signature: [u8; 4],
+    pub phys_addr: u64,
+    pub length: u32,
+}
+
+static TABLES: Mutex<Vec<AcpiTable>> = Mutex::new(Vec::new());
+
+/// Tables with valid checksums found by `parse_rsdt_xsdt`, in RSDT order.
+pub fn tables() -> Vec<AcpiTable> {
+    TABLES.lock().clone()
+}
+
+/// The first table with `signature` (`b"APIC"` for the MADT).
+pub fn find_table(signature: &[u8; 4]) -> Option<AcpiTable> {
+    TABLES.lock().iter().find(|t| &t.signature == signature).copied()
+}
+
 /// Print information about an ACPI table
 fn print_table_info(table_phys_addr: u64, phys_offset: u64) {
     let table_virt_addr = (table_phys_addr + phys_offset) as *const AcpiTableHeader;
@@ -418,9 +501,11 @@ fn print_table_info(table_phys_addr: u64, phys_offset: u64) {
             },
             capabilities: Vec::new(),
             description: table_desc,
+            location: None,
         };
         let id = GLOBAL_MANAGER.register_device(info);
         println!("ACPI: registered table device id={} sig={:?} @ {:#x}", id, signature, table_phys_addr);
+        TABLES.lock().push(AcpiTable { signature, phys_addr: table_phys_addr, length: length as u32 });
 
         // Parse specific table types
         parse_specific_table(&signature, table_phys_addr, phys_offset);
@@ -436,20 +521,53 @@ fn parse_specific_table(signature: &[u8; 4], table_phys_addr: u64, phys_offset:
         b"APIC" => parse_madt(table_virt_addr),
         b"HPET" => parse_hpet(table_virt_addr),
         b"MCFG" => parse_mcfg(table_virt_addr),
+        b"DMAR" => parse_dmar(table_virt_addr),
         _ => {} // Unknown table type, skip parsing
     }
 }
 
+/// CMOS index of the RTC century register from the FADT, 0 if none.
+static RTC_CENTURY: AtomicU8 = AtomicU8::new(0);
+
+/// CMOS register holding the RTC century, if the firmware has one.
+pub fn rtc_century_register() -> Option<u8> {
+    match RTC_CENTURY.load(Ordering::Relaxed) {
+        0 => None,
+        reg => Some(reg),
+    }
+}
+
+/// I/O port of the ACPI PM timer from the FADT, 0 if none; bit 31 set
+/// when the counter is 32 bits wide rather than 24.
+static PM_TIMER: AtomicU32 = AtomicU32::new(0);
+const PM_TIMER_32BIT: u32 = 1 << 31;
+/// FADT flag: the PM timer counter is 32 bits.
+const FADT_TMR_VAL_EXT: u32 = 1 << 8;
+
+/// The ACPI PM timer's I/O port and whether its counter is 32 bits wide
+/// (24 otherwise). It counts at 3.579545 MHz.
+pub fn pm_timer() -> Option<(u16, bool)> {
+    match PM_TIMER.load(Ordering::Relaxed) {
+        0 => None,
+        v => Some((v as u16, v & PM_TIMER_32BIT != 0)),
+    }
+}
+
 /// Parse FACP (Fixed ACPI Description Table)
 fn parse_facp(table_ptr: *const u8) {
     let facp = unsafe { &*(table_ptr as *const Facp) };
+    RTC_CENTURY.store(facp.century, Ordering::Relaxed);
+    let (port, len, flags) = (facp.pm_tmr_blk, facp.pm_tmr_len, facp.flags);
+    if port != 0 && port <= 0xFFFF && len == 4 {
+        PM_TIMER.store(port | if flags & FADT_TMR_VAL_EXT != 0 { PM_TIMER_32BIT } else { 0 }, Ordering::Relaxed);
+    }
 
     // Enable ACPI using the FACP information
     enable_acpi(facp);
 }
 
 /// Parse MADT (Multiple APIC Description Table)
-fn parse_madt(table_ptr: *const u8) {
+pub(crate) fn parse_madt(table_ptr: *const u8) {
     // Parse MADT and store useful information such as the Local APIC base address
     if table_ptr.is_null() {
         return;
@@ -462,6 +580,7 @@ fn parse_madt(table_ptr: *const u8) {
 
     // Store the local APIC address for other subsystems to use
     MADT_LOCAL_APIC_ADDR.store(madt.local_apic_addr as u64, Ordering::SeqCst);
+    MADT_FLAGS.store(MADT_PARSED | madt.flags, Ordering::SeqCst);
 
     // Parse MADT entries that follow the header: variable-length structures
     let table_len = madt.header.length as usize;
@@ -478,6 +597,20 @@ fn parse_madt(table_ptr: *const u8) {
         }
 
         match entry_header.entry_type {
+            0 => {
+                // Processor Local APIC
+                if entry_len >= core::mem::size_of::<MadtLocalApicEntry>() {
+                    let lapic = unsafe { &*(entry_ptr as *const MadtLocalApicEntry) };
+                    let flags = lapic.flags;
+                    if flags & (MADT_LAPIC_ENABLED | MADT_LAPIC_ONLINE_CAPABLE) != 0 {
+                        LOCAL_APICS.lock().push(LocalApicInfo {
+                            processor_id: lapic.processor_id,
+                            apic_id: lapic.apic_id,
+                            enabled: flags & MADT_LAPIC_ENABLED != 0,
+                        });
+                    }
+                }
+            }
             1 => {
                 // IO APIC
                 if entry_len >= core::mem::size_of::<MadtIoApicEntry>() {
@@ -507,6 +640,7 @@ fn parse_madt(table_ptr: *const u8) {
                         },
                         capabilities: Vec::new(),
                         description: alloc::format!("ACPI IOAPIC id={} gsi_base={}", apic_id, gsi_base),
+                        location: None,
                     };
                     let id = GLOBAL_MANAGER.register_device(info);
                     println!("ACPI: registered IOAPIC device id={} apic_id={} gsi_base={} @ {:#x}", id, apic_id, gsi_base, apic_addr);
@@ -536,6 +670,18 @@ fn parse_madt(table_ptr: *const u8) {
 // Atomic holder for the MADT local APIC address (0 = unknown/not set)
 static MADT_LOCAL_APIC_ADDR: AtomicU64 = AtomicU64::new(0);
 
+/// MADT flags, with `MADT_PARSED` set once there has been a MADT.
+static MADT_FLAGS: AtomicU32 = AtomicU32::new(0);
+const MADT_PARSED: u32 = 1 << 31;
+/// MADT flag: the machine also has dual 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+/// Whether the MADT says there are 8259 PICs. `None` without a MADT.
+pub fn madt_pcat_compat() -> Option<bool> {
+    let flags = MADT_FLAGS.load(Ordering::SeqCst);
+    (flags & MADT_PARSED != 0).then_some(flags & MADT_PCAT_COMPAT != 0)
+}
+
 /// Return the Local APIC physical address discovered from the MADT, if any
 pub fn get_local_apic_address() -> Option<u32> {
     let v = MADT_LOCAL_APIC_ADDR.load(Ordering::SeqCst);
@@ -546,7 +692,22 @@ pub fn get_local_apic_address() -> Option<u32> {
     }
 }
 
-// --- IOAPIC / ISO storage and types ---
+/// Local APIC entry flag: the processor is usable.
+const MADT_LAPIC_ENABLED: u32 = 1 << 0;
+/// Local APIC entry flag: the processor is disabled but can be brought
+/// online later.
+const MADT_LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// --- Local APIC / IOAPIC / ISO storage and types ---
+/// A processor from the MADT. Ones neither enabled nor online capable are
+/// left out.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicInfo {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct IoApicInfo {
     pub id: u8,
@@ -562,6 +723,7 @@ pub struct IsoInfo {
     pub flags: u16,
 }
 
+static LOCAL_APICS: Mutex<Vec<LocalApicInfo>> = Mutex::new(Vec::new());
 static IOAPICS: Mutex<Vec<IoApicInfo>> = Mutex::new(Vec::new());
 static ISOS: Mutex<Vec<IsoInfo>> = Mutex::new(Vec::new());
 
@@ -579,6 +741,11 @@ pub fn get_hpet_period_fs() -> u64 {
     HPET_PERIOD_FS.load(Ordering::SeqCst)
 }
 
+/// Return a cloned list of the processors' local APICs, in MADT order
+pub fn get_local_apics() -> Vec<LocalApicInfo> {
+    LOCAL_APICS.lock().clone()
+}
+
 /// Return a cloned list of discovered IOAPICs (id, addr, gsi_base)
 pub fn get_ioapics() -> Vec<IoApicInfo> {
     IOAPICS.lock().clone()
@@ -631,6 +798,7 @@ fn parse_hpet(_table_ptr: *const u8) {
             },
             capabilities: Vec::new(),
             description: alloc::format!("ACPI HPET @ {:#x}", addr),
+            location: None,
         };
         let id = GLOBAL_MANAGER.register_device(info);
         println!("ACPI: registered HPET device id={} @ {:#x}", id, addr);
@@ -671,6 +839,7 @@ fn parse_mcfg(_table_ptr: *const u8) {
             },
             capabilities: Vec::new(),
             description: alloc::format!("ACPI MCFG ECAM seg={} buses={}..{} @ {:#x}", seg, start_bus, end_bus, base),
+            location: None,
         };
         let id = GLOBAL_MANAGER.register_device(info);
         // push to global MCFG list for later ECAM-based PCI scanning
@@ -681,6 +850,9 @@ fn parse_mcfg(_table_ptr: *const u8) {
     }
 }
 
+/// How long the firmware gets to act on the SMI disable command.
+const ACPI_SMI_SETTLE_US: u64 = 100;
+
 /// Enable ACPI by disabling legacy power management and enabling ACPI mode
 /// This function should be called after parsing the FACP table
 pub fn enable_acpi(facp: &Facp) {
@@ -698,9 +870,7 @@ pub fn enable_acpi(facp: &Facp) {
     }
 
     // Small delay to let the disable command take effect
-    for _ in 0..10000 {
-        unsafe { core::arch::asm!("nop") };
-    }
+    crate::time::udelay(ACPI_SMI_SETTLE_US);
 
     // Enable ACPI (write enable value to SMI command port)
     if smi_cmd != 0 && acpi_enable != 0 {
@@ -711,17 +881,13 @@ pub fn enable_acpi(facp: &Facp) {
     // SCI_EN is typically bit 0 in the PM1 control register
     if pm1a_cnt_blk != 0 {
         // Wait up to 3 seconds for ACPI to enable
-        for _ in 0..3000000 {
+        let enabled = crate::time::spin_until_ms(3000, || {
             let pm1a_cnt = unsafe { inb(pm1a_cnt_blk as u16) };
-            if (pm1a_cnt & 0x01) != 0 { // SCI_EN bit set
-                return; // Success
-            }
-            // Small delay
-            for _ in 0..10 {
-                unsafe { core::arch::asm!("nop") };
-            }
+            ((pm1a_cnt & 0x01) != 0).then_some(()) // SCI_EN bit set
+        });
+        if enabled.is_err() {
+            kwarn_once!("[ACPI] SCI_EN still clear after 3 s; ACPI may not be enabled");
         }
-        // Timeout - ACPI may not be enabled
     }
 }
 
@@ -731,4 +897,98 @@ static MCFG_ALLOCS: Mutex<Vec<McfgAllocation>> = Mutex::new(Vec::new());
 /// Return a cloned list of MCFG allocations discovered by ACPI.
 pub fn get_mcfg_allocs() -> Vec<McfgAllocation> {
     MCFG_ALLOCS.lock().clone()
-}
\ No newline at end of file
+}
+static DMAR: Mutex<Option<DmarInfo>> = Mutex::new(None);
+
+/// The remapping units and reserved regions of the DMAR, if there is one.
+pub fn get_dmar() -> Option<DmarInfo> {
+    DMAR.lock().clone()
+}
+
+/// Device scope entries in `bytes`, which holds nothing else.
+fn parse_dmar_scopes(mut bytes: &[u8]) -> Vec<DmarScope> {
+    let mut scopes = Vec::new();
+    while bytes.len() >= 6 {
+        let len = bytes[1] as usize;
+        if len < 6 || len > bytes.len() {
+            break; // malformed
+        }
+        scopes.push(DmarScope {
+            kind: bytes[0],
+            enumeration_id: bytes[4],
+            start_bus: bytes[5],
+            path: bytes[6..len].chunks_exact(2).map(|p| (p[0], p[1])).collect(),
+        });
+        bytes = &bytes[len..];
+    }
+    scopes
+}
+
+/// Parse DMAR (DMA remapping reporting table)
+pub(crate) fn parse_dmar(table_ptr: *const u8) {
+    if table_ptr.is_null() {
+        return;
+    }
+    let dmar = unsafe { &*(table_ptr as *const Dmar) };
+    if !dmar.header.checksum_valid() {
+        return;
+    }
+    let table = unsafe { core::slice::from_raw_parts(table_ptr, dmar.header.length as usize) };
+    let u16_at = |b: &[u8], at: usize| u16::from_le_bytes([b[at], b[at + 1]]);
+    let u64_at = |b: &[u8], at: usize| u64::from_le_bytes(b[at..at + 8].try_into().unwrap());
+    let mut info = DmarInfo { host_address_width: dmar.host_address_width.wrapping_add(1), flags: dmar.flags, ..DmarInfo::default() };
+
+    let mut offset = core::mem::size_of::<Dmar>();
+    while offset + 4 <= table.len() {
+        let kind = u16_at(table, offset);
+        let len = u16_at(table, offset + 2) as usize;
+        if len < 4 || offset + len > table.len() {
+            break; // malformed
+        }
+        let entry = &table[offset..offset + len];
+        match kind {
+            0 if len >= 16 => {
+                let unit = DmarUnit {
+                    segment: u16_at(entry, 6),
+                    register_base: u64_at(entry, 8),
+                    include_pci_all: entry[4] & 1 != 0,
+                    scopes: parse_dmar_scopes(&entry[16..]),
+                };
+                let dev = DeviceInfo {
+                    vendor_id: 0xffff,
+                    device_id: unit.segment,
+                    class: 0x08, // Base System Peripheral
+                    subclass: 0x06, // IOMMU
+                    prog_if: 0x00,
+                    resources: {
+                        let mut v = Vec::new();
+                        v.push(Resource { kind: ResourceKind::MemoryMapped, addr: unit.register_base, len: 0x1000 });
+                        v
+                    },
+                    capabilities: Vec::new(),
+                    description: alloc::format!("ACPI DMAR unit seg={} @ {:#x}", unit.segment, unit.register_base),
+                    location: None,
+                };
+                let id = GLOBAL_MANAGER.register_device(dev);
+                println!(
+                    "ACPI: registered DMAR unit id={} seg={} scopes={}{} @ {:#x}",
+                    id,
+                    unit.segment,
+                    unit.scopes.len(),
+                    if unit.include_pci_all { " (all)" } else { "" },
+                    unit.register_base
+                );
+                info.units.push(unit);
+            }
+            1 if len >= 24 => info.reserved.push(DmarReservedRegion {
+                segment: u16_at(entry, 6),
+                base: u64_at(entry, 8),
+                limit: u64_at(entry, 16),
+                scopes: parse_dmar_scopes(&entry[24..]),
+            }),
+            _ => {} // ATSR, RHSA and the rest don't matter without ATS or NUMA
+        }
+        offset += len;
+    }
+    *DMAR.lock() = Some(info);
+}
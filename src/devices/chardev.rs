@@ -0,0 +1,101 @@
+//! Character devices
+//!
+//! A `CharDevice` is a byte stream without offsets: the console, a serial
+//! line, the random generator. Devices register under a name so descriptor
+//! tables (and, later, device files) can find them; processes get the
+//! console as their stdio.
+
+use crate::prelude::*;
+use crate::fs::vfs::VfsError;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub trait CharDevice: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Read what is available, waiting until there is at least one byte.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn write(&self, data: &[u8]) -> Result<usize, VfsError>;
+}
+
+static CHAR_DEVICES: Mutex<BTreeMap<&'static str, Arc<dyn CharDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Make `dev` available under its name, replacing any earlier device of
+/// that name.
+pub fn register_char_device(dev: Arc<dyn CharDevice>) {
+    CHAR_DEVICES.lock().insert(dev.name(), dev);
+}
+
+pub fn char_device(name: &str) -> Option<Arc<dyn CharDevice>> {
+    CHAR_DEVICES.lock().get(name).cloned()
+}
+
+pub fn char_devices() -> Vec<&'static str> {
+    CHAR_DEVICES.lock().keys().copied().collect()
+}
+
+/// The framebuffer (or VGA) console for output, the keyboard for input.
+pub struct ConsoleDevice;
+
+impl CharDevice for ConsoleDevice {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        crate::devices::tty::tty_read_blocking(buf).ok_or(VfsError::Interrupted)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, VfsError> {
+        print!("{}", alloc::string::String::from_utf8_lossy(data));
+        Ok(data.len())
+    }
+}
+
+/// COM1, output only.
+pub struct SerialDevice;
+
+impl CharDevice for SerialDevice {
+    fn name(&self) -> &'static str {
+        "ttyS0"
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, VfsError> {
+        crate::devices::serial::serial_write(data);
+        Ok(data.len())
+    }
+}
+
+/// The kernel random generator. Reads never wait; writes are mixed into
+/// its pool.
+pub struct RandomDevice;
+
+impl CharDevice for RandomDevice {
+    fn name(&self) -> &'static str {
+        "urandom"
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        crate::random::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, VfsError> {
+        crate::random::add_entropy(data);
+        Ok(data.len())
+    }
+}
+
+/// Register the built-in devices.
+pub fn init_char_devices() {
+    register_char_device(Arc::new(ConsoleDevice));
+    register_char_device(Arc::new(SerialDevice));
+    register_char_device(Arc::new(RandomDevice));
+}
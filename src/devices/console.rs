@@ -0,0 +1,143 @@
+//! Screen console backends
+//!
+//! The screen console is the boot VGA text buffer until a framebuffer
+//! driver starts, and the framebuffer console from then on. Both are a
+//! `ConsoleBackend`; the framebuffer driver switches with
+//! `set_console_backend`, and `setcolor!`, `setpos!` and `cls!` act on
+//! whichever is current. The colors are kept here rather than by each
+//! backend, so the one switched to carries on in the colors last set.
+//!
+//! Positions are (row, column) from the top left. One past the last row or
+//! column is clamped to it, on either backend.
+
+use crate::prelude::*;
+use crate::bootvga::vga_buffer::{Color, WRITER};
+use crate::driver_framework::drivers::{console, vbe_vga};
+use crate::sync::IrqSpinlock;
+use core::fmt::Write;
+
+pub trait ConsoleBackend: Sync {
+    fn name(&self) -> &'static str;
+    fn write_str(&self, s: &str);
+    fn set_color(&self, fg: Color, bg: Color);
+    fn set_cursor(&self, row: usize, col: usize);
+    /// Blank the screen and put the cursor at the top left.
+    fn clear(&self);
+    /// Move the text up `lines` rows, blanking the rows that come in at the
+    /// bottom; the cursor moves with the text.
+    fn scroll(&self, lines: usize);
+}
+
+/// The boot VGA text buffer.
+pub struct VgaTextConsole;
+
+impl ConsoleBackend for VgaTextConsole {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write_str(&self, s: &str) {
+        let _ = WRITER.lock().write_str(s);
+    }
+
+    fn set_color(&self, fg: Color, bg: Color) {
+        WRITER.lock().set_color(fg, bg);
+    }
+
+    fn set_cursor(&self, row: usize, col: usize) {
+        WRITER.lock().set_position(row, col);
+    }
+
+    fn clear(&self) {
+        WRITER.lock().clear_screen();
+    }
+
+    fn scroll(&self, lines: usize) {
+        WRITER.lock().scroll(lines);
+    }
+}
+
+/// Write to the VGA text buffer even if its lock is held. For the panic
+/// report: the other CPUs are stopped, so whoever holds it is never going
+/// to let go.
+pub fn vga_write_panic(s: &str) {
+    let writer = WRITER.try_lock().or_else(|| {
+        // SAFETY: the holder won't run again
+        unsafe { WRITER.force_unlock() };
+        WRITER.try_lock()
+    });
+    if let Some(mut writer) = writer {
+        let _ = writer.write_str(s);
+    }
+}
+
+/// The console on the first framebuffer.
+pub struct FramebufferConsole;
+
+impl ConsoleBackend for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+
+    fn write_str(&self, s: &str) {
+        console::console_print_first(s);
+    }
+
+    fn set_color(&self, fg: Color, bg: Color) {
+        console::console_set_colors_first(vbe_vga::vbe_color_from_vga_color(fg), vbe_vga::vbe_color_from_vga_color(bg));
+    }
+
+    fn set_cursor(&self, row: usize, col: usize) {
+        console::console_set_cursor_first(col, row);
+    }
+
+    fn clear(&self) {
+        console::console_clear_first();
+    }
+
+    fn scroll(&self, lines: usize) {
+        console::console_scroll_first(lines);
+    }
+}
+
+pub static VGA_TEXT_CONSOLE: VgaTextConsole = VgaTextConsole;
+pub static FRAMEBUFFER_CONSOLE: FramebufferConsole = FramebufferConsole;
+
+struct ConsoleState {
+    backend: &'static dyn ConsoleBackend,
+    colors: (Color, Color),
+}
+
+// Backends are called after this is unlocked; they take their own locks.
+static STATE: IrqSpinlock<ConsoleState> =
+    IrqSpinlock::new(ConsoleState { backend: &VGA_TEXT_CONSOLE, colors: (Color::Yellow, Color::Black) });
+
+/// The current backend.
+pub fn console() -> &'static dyn ConsoleBackend {
+    STATE.lock().backend
+}
+
+/// Make `backend` the console, in the current colors.
+pub fn set_console_backend(backend: &'static dyn ConsoleBackend) {
+    let (fg, bg) = {
+        let mut state = STATE.lock();
+        state.backend = backend;
+        state.colors
+    };
+    backend.set_color(fg, bg);
+}
+
+/// The current (foreground, background) colors.
+pub fn console_colors() -> (Color, Color) {
+    STATE.lock().colors
+}
+
+/// Set the colors of the console and of any backend switched to later.
+pub fn set_console_colors(fg: Color, bg: Color) {
+    let backend = {
+        let mut state = STATE.lock();
+        state.colors = (fg, bg);
+        state.backend
+    };
+    backend.set_color(fg, bg);
+}
@@ -0,0 +1,151 @@
+//! QEMU firmware configuration (fw_cfg) interface
+//!
+//! fw_cfg exposes named blobs to the guest through a selector port and a
+//! data port. `-fw_cfg name=opt/neutrix/initrd,file=initrd.tar` and
+//! `-initrd` both end up here; `read_file` fetches a blob by name. The
+//! kernel command line comes from `-append`, or from
+//! `-fw_cfg name=opt/neutrix/cmdline,string=...` when booting from a disk
+//! image (where QEMU ignores `-append`).
+
+use crate::prelude::*;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const FW_CFG_PORT_SEL: u16 = 0x510;
+const FW_CFG_PORT_DATA: u16 = 0x511;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+pub const FW_CFG_INITRD_SIZE: u16 = 0x000b;
+pub const FW_CFG_INITRD_DATA: u16 = 0x0012;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x0014;
+const FW_CFG_CMDLINE_DATA: u16 = 0x0015;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// The port pair is shared state: a selector write followed by data reads.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn select(key: u16) {
+    unsafe { Port::<u16>::new(FW_CFG_PORT_SEL).write(key) };
+}
+
+fn read_bytes(buf: &mut [u8]) {
+    let mut data: Port<u8> = Port::new(FW_CFG_PORT_DATA);
+    for b in buf.iter_mut() {
+        *b = unsafe { data.read() };
+    }
+}
+
+/// Read `len` bytes of item `key` from its start.
+pub fn read_item(key: u16, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    read_item_into(key, &mut buf);
+    buf
+}
+
+/// Fill `buf` from the start of item `key`.
+pub fn read_item_into(key: u16, buf: &mut [u8]) {
+    let _g = LOCK.lock();
+    select(key);
+    read_bytes(buf);
+}
+
+/// Read `len` bytes of item `key` into memory mapped for good: an image
+/// such as an initrd is much larger than the heap.
+fn read_item_mapped(key: u16, len: usize) -> Option<&'static [u8]> {
+    match crate::memory::map_permanent(len) {
+        Ok(buf) => {
+            read_item_into(key, buf);
+            Some(buf)
+        }
+        Err(e) => {
+            println!("[FW_CFG] no memory for a {} byte item: {}", len, e);
+            None
+        }
+    }
+}
+
+/// True when running under QEMU with fw_cfg available.
+pub fn present() -> bool {
+    read_item(FW_CFG_SIGNATURE, 4) == b"QEMU"
+}
+
+#[derive(Debug, Clone)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub select: u16,
+    pub size: u32,
+}
+
+/// Every named file in the fw_cfg directory.
+pub fn files() -> Vec<FwCfgFile> {
+    if !present() {
+        return Vec::new();
+    }
+    let _g = LOCK.lock();
+    select(FW_CFG_FILE_DIR);
+    let mut count = [0u8; 4];
+    read_bytes(&mut count);
+    let count = u32::from_be_bytes(count);
+    let mut out = Vec::new();
+    let mut entry = [0u8; 64];
+    for _ in 0..count.min(4096) {
+        read_bytes(&mut entry);
+        let name_bytes = &entry[8..];
+        let len = name_bytes.iter().position(|&c| c == 0).unwrap_or(name_bytes.len());
+        out.push(FwCfgFile {
+            name: String::from_utf8_lossy(&name_bytes[..len]).into_owned(),
+            select: u16::from_be_bytes([entry[4], entry[5]]),
+            size: u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+        });
+    }
+    out
+}
+
+/// Read a named file ("opt/neutrix/cmdline") onto the heap.
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    let f = files().into_iter().find(|f| f.name == name)?;
+    Some(read_item(f.select, f.size as usize))
+}
+
+/// Read a named image ("opt/neutrix/initrd") into memory of its own, kept
+/// for good.
+pub fn read_file_mapped(name: &str) -> Option<&'static [u8]> {
+    let f = files().into_iter().find(|f| f.name == name)?;
+    read_item_mapped(f.select, f.size as usize)
+}
+
+/// The image passed with `-initrd`, if any, in memory of its own.
+pub fn read_initrd() -> Option<&'static [u8]> {
+    if !present() {
+        return None;
+    }
+    let size = read_item(FW_CFG_INITRD_SIZE, 4);
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+    if size == 0 {
+        return None;
+    }
+    read_item_mapped(FW_CFG_INITRD_DATA, size)
+}
+
+/// Name of the fw_cfg file that overrides `-append`.
+pub const CMDLINE_FW_CFG_NAME: &str = "opt/neutrix/cmdline";
+
+/// The kernel command line, if one was given.
+pub fn read_cmdline() -> Option<String> {
+    if !present() {
+        return None;
+    }
+    let raw = match read_file(CMDLINE_FW_CFG_NAME) {
+        Some(data) => data,
+        None => {
+            let size = read_item(FW_CFG_CMDLINE_SIZE, 4);
+            let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+            read_item(FW_CFG_CMDLINE_DATA, size)
+        }
+    };
+    let line: String = String::from_utf8_lossy(crate::rlib::cstr::cstr_bytes(&raw)).trim().into();
+    (!line.is_empty()).then_some(line)
+}
@@ -4,6 +4,15 @@ pub mod acpi;
 pub use acpi::*;
 pub mod pci;
 pub use pci::*;
+pub mod fw_cfg;
+pub mod rtc;
+pub mod serial;
+pub use serial::*;
+pub mod chardev;
+pub use chardev::*;
+pub mod tty;
+pub use tty::*;
+pub mod console;
 
 // PS/2 keyboard driver is implemented as a KMDF-style driver under
 // `driver_framework::drivers::ps2kbd` and registered manually by `main.rs`.
\ No newline at end of file
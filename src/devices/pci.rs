@@ -1,8 +1,8 @@
 use crate::driver_framework::manager::GLOBAL_MANAGER;
-use crate::driver_framework::device::{DeviceInfo, Resource, ResourceKind};
+use crate::driver_framework::device::{DeviceInfo, PciAddress, Resource, ResourceKind};
 use crate::arch::ports::{outdw, indw};
 use alloc::string::String;
-use crate::*;
+use crate::prelude::*;
 use alloc::format;
 use crate::devices::acpi;
 
@@ -27,6 +27,58 @@ fn pci_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     unsafe { indw(0xCFC) }
 }
 
+/// Read the dword at `offset` in the configuration space of bus/slot/func.
+/// Absent functions read as all ones.
+pub fn config_read(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    x86_64::instructions::interrupts::without_interrupts(|| pci_read(bus, slot, func, offset))
+}
+
+/// The ECAM window of `addr`'s configuration space, if ACPI described one
+/// covering its segment and bus.
+fn ecam_config_base(addr: PciAddress) -> Option<u64> {
+    let alloc = acpi::get_mcfg_allocs().into_iter().find(|a| {
+        let (segment, start, end) = (a.pci_segment_group, a.start_bus, a.end_bus);
+        segment == addr.segment && (start..=end).contains(&addr.bus)
+    })?;
+    let phys = alloc.base_address
+        + ((addr.bus as u64) << 20)
+        + ((addr.device as u64) << 15)
+        + ((addr.function as u64) << 12);
+    Some(crate::driver_framework::drivers::get_boot_phys_offset().wrapping_add(phys))
+}
+
+/// Read the dword at `offset` in `addr`'s configuration space: through
+/// ECAM when there is a window for it, which reaches all 4 KiB, else the
+/// legacy ports, which reach segment 0 and the first 256 bytes. Whatever
+/// can't be reached reads as all ones, like an absent function.
+pub fn config_read_at(addr: PciAddress, offset: u16) -> u32 {
+    if offset >= 0x1000 {
+        return 0xFFFF_FFFF;
+    }
+    if let Some(base) = ecam_config_base(addr) {
+        return unsafe { ((base + (offset & 0xFFC) as u64) as *const u32).read_volatile() };
+    }
+    if addr.segment != 0 || offset >= 0x100 {
+        return 0xFFFF_FFFF;
+    }
+    config_read(addr.bus, addr.device, addr.function, offset as u8)
+}
+
+/// Write the dword at `offset` in `addr`'s configuration space; writes
+/// `config_read_at` couldn't read back are dropped.
+pub fn config_write_at(addr: PciAddress, offset: u16, value: u32) {
+    if offset >= 0x1000 {
+        return;
+    }
+    if let Some(base) = ecam_config_base(addr) {
+        unsafe { ((base + (offset & 0xFFC) as u64) as *mut u32).write_volatile(value) };
+        return;
+    }
+    if addr.segment == 0 && offset < 0x100 {
+        x86_64::instructions::interrupts::without_interrupts(|| pci_write(addr.bus, addr.device, addr.function, offset as u8, value));
+    }
+}
+
 /// Very small PCI scan that registers devices with the global manager.
 pub fn scan_and_register() {
     scan_and_register_with_phys_offset(0)
@@ -34,10 +86,15 @@ pub fn scan_and_register() {
 
 /// Scan with a physical memory offset so we can map BARs for MSI-X table reads.
 pub fn scan_and_register_with_phys_offset(physical_memory_offset: u64) {
+    // the legacy configuration mechanism, used alongside ECAM
+    if let Err(e) = crate::hal::ioport::claim_kernel_ports("pci", 0xCF8, 8) {
+        println!("[PCI] configuration ports 0xCF8-0xCFF: {}", e);
+    }
     // If ACPI provided MCFG ECAM ranges, use them for memory-mapped config access.
     let mcfgs = acpi::get_mcfg_allocs();
     if !mcfgs.is_empty() {
         for alloc in mcfgs.iter() {
+            let segment = alloc.pci_segment_group;
             let base = alloc.base_address;
             let start_bus = alloc.start_bus;
             let end_bus = alloc.end_bus;
@@ -292,6 +349,7 @@ pub fn scan_and_register_with_phys_offset(physical_memory_offset: u64) {
                     resources,
                     capabilities,
                     description: String::from(format!("PCI {:02x}:{:02x}.{:x}", bus, slot, func)),
+                    location: Some(PciAddress::new(segment, bus, slot, func)),
                 };
 
                 // Try to merge with an existing device (e.g., discovered via ACPI).
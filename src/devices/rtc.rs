@@ -0,0 +1,104 @@
+//! CMOS real-time clock
+//!
+//! The RTC keeps calendar time in CMOS registers reached through ports
+//! 0x70/0x71, in BCD or binary and in 12- or 24-hour form depending on
+//! status register B. Its year is two digits; the century comes from the
+//! CMOS register the FADT names, or is assumed to be 20xx. Reads retry
+//! until two in a row agree so an update in the middle can't tear them.
+
+use crate::prelude::*;
+use crate::time::DateTime;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: an update is in progress.
+const STATUS_A_UIP: u8 = 0x80;
+/// Status B: hours are 24-hour.
+const STATUS_B_24H: u8 = 0x02;
+/// Status B: values are binary, not BCD.
+const STATUS_B_BINARY: u8 = 0x04;
+/// Hours register: PM in 12-hour mode.
+const HOURS_PM: u8 = 0x80;
+
+/// Reads that may disagree before giving up.
+const MAX_READS: usize = 8;
+
+/// The index/data port pair is shared state.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn cmos_read(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    regs: [u8; 6],
+    century: u8,
+}
+
+fn read_raw(century_reg: Option<u8>) -> Raw {
+    // wait out an update in progress; it takes under 2 ms
+    let _ = crate::time::spin_until_ms(10, || (cmos_read(REG_STATUS_A) & STATUS_A_UIP == 0).then_some(()));
+    Raw {
+        regs: [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(cmos_read),
+        century: century_reg.map_or(0, cmos_read),
+    }
+}
+
+fn bcd(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0F)
+}
+
+/// The RTC's current time, or `None` if it won't give a stable or sane
+/// reading.
+pub fn read_rtc() -> Option<DateTime> {
+    let century_reg = crate::devices::acpi::rtc_century_register();
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let _g = LOCK.lock();
+        let mut last = read_raw(century_reg);
+        for _ in 0..MAX_READS {
+            let next = read_raw(century_reg);
+            if next == last {
+                return Some((next, cmos_read(REG_STATUS_B)));
+            }
+            last = next;
+        }
+        None
+    })?;
+
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let conv = |v: u8| if binary { v } else { bcd(v) };
+    let [sec, min, hour, day, month, year] = raw.regs;
+    let pm = hour & HOURS_PM != 0;
+    let mut hour = conv(hour & !HOURS_PM);
+    if status_b & STATUS_B_24H == 0 {
+        // 12 AM is 0, 12 PM stays 12
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let year = conv(year) as u16;
+    let century = match conv(raw.century) {
+        c @ 19..=99 if century_reg.is_some() => c as u16,
+        _ => 20,
+    };
+    let dt = DateTime { year: century * 100 + year, month: conv(month), day: conv(day), hour, minute: conv(min), second: conv(sec), nanos: 0 };
+    dt.is_valid().then_some(dt)
+}
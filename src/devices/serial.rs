@@ -0,0 +1,89 @@
+//! Polled 16550 UART on COM1
+//!
+//! Output only. Used for dumps that need to reach the host byte-exact
+//! (`-serial file:...` or `-serial stdio` under QEMU).
+
+use crate::prelude::*;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub const COM1: u16 = 0x3F8;
+
+const LSR_THR_EMPTY: u8 = 0x20;
+/// The UART clock divided by 16: the rate at divisor 1.
+pub const MAX_BAUD: u32 = 115200;
+
+static READY: AtomicBool = AtomicBool::new(false);
+static LOCK: Mutex<()> = Mutex::new(());
+static BAUD: AtomicU32 = AtomicU32::new(MAX_BAUD);
+
+/// Program COM1 for 8N1 at the current baud rate, with FIFOs and
+/// interrupts off.
+pub fn serial_init() {
+    let divisor = (MAX_BAUD / BAUD.load(Ordering::Relaxed)) as u16;
+    unsafe {
+        Port::<u8>::new(COM1 + 1).write(0x00); // no interrupts
+        Port::<u8>::new(COM1 + 3).write(0x80); // DLAB on
+        Port::<u8>::new(COM1).write(divisor as u8);
+        Port::<u8>::new(COM1 + 1).write((divisor >> 8) as u8);
+        Port::<u8>::new(COM1 + 3).write(0x03); // 8N1, DLAB off
+        Port::<u8>::new(COM1 + 2).write(0xC7); // FIFO on, cleared, 14-byte threshold
+        Port::<u8>::new(COM1 + 4).write(0x03); // DTR + RTS
+    }
+    READY.store(true, Ordering::Release);
+}
+
+/// Switch COM1 to `baud`, which must divide 115200. False for a rate the
+/// UART can't make.
+pub fn serial_set_baud(baud: u32) -> bool {
+    if baud == 0 || MAX_BAUD % baud != 0 {
+        return false;
+    }
+    BAUD.store(baud, Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        let _g = LOCK.lock();
+        serial_init();
+    });
+    true
+}
+
+pub fn serial_baud() -> u32 {
+    BAUD.load(Ordering::Relaxed)
+}
+
+fn put(b: u8) {
+    let mut lsr: Port<u8> = Port::new(COM1 + 5);
+    // a missing UART reads back 0xFF, so this doesn't hang on absent hardware
+    let _ = crate::time::spin_until_ms(10, || (unsafe { lsr.read() } & LSR_THR_EMPTY != 0).then_some(()));
+    unsafe { Port::<u8>::new(COM1).write(b) };
+}
+
+/// Write raw bytes to COM1, initializing it on first use.
+pub fn serial_write(bytes: &[u8]) {
+    if !READY.load(Ordering::Acquire) {
+        serial_init();
+    }
+    interrupts::without_interrupts(|| {
+        let _g = LOCK.lock();
+        for &b in bytes {
+            put(b);
+        }
+    });
+}
+
+/// Write without waiting for the lock, which a panicking CPU may hold.
+pub fn serial_write_panic(bytes: &[u8]) {
+    if !READY.load(Ordering::Acquire) {
+        serial_init();
+    }
+    let _g = LOCK.try_lock();
+    for &b in bytes {
+        put(b);
+    }
+}
+
+pub fn serial_write_str(s: &str) {
+    serial_write(s.as_bytes());
+}
@@ -0,0 +1,244 @@
+//! Console line discipline
+//!
+//! Keyboard scancodes are decoded here (into key events by
+//! `input::scancode`, then into characters by the layout) and edited into
+//! lines: characters are echoed, backspace removes the last one, and Enter
+//! hands the line (with its newline) to readers. Kernel tasks wait with
+//! `tty_read`; code that can't await, such as a system call made by a
+//! process, uses `tty_read_blocking`, which decodes pending scancodes
+//! itself since the keyboard task may not get to run meanwhile.
+//!
+//! Key combinations registered as hotkeys (`input::hotkey`) never reach the
+//! line editor.
+//!
+//! Ctrl+C discards the line being edited, cuts a blocking read short and
+//! calls the interrupt hook, which the process layer uses to send `SIGINT`.
+
+use crate::prelude::*;
+use crate::sync::{IrqSpinlock, WaitQueue};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::input::scancode::{keyboard_scancodes, ScancodeDecoder, Scancodes};
+use pc_keyboard::{layouts, DecodedKey, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyState};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Longest line kept while editing; further characters are dropped.
+pub const TTY_LINE_MAX: usize = 1024;
+
+struct Tty {
+    scancodes: ScancodeDecoder,
+    keyboard: EventDecoder<layouts::AnyLayout>,
+    /// The line being edited.
+    line: Vec<u8>,
+    /// Finished lines not yet read.
+    ready: VecDeque<u8>,
+}
+
+static TTY: IrqSpinlock<Tty> = IrqSpinlock::new(Tty {
+    scancodes: ScancodeDecoder::new(Scancodes::Set1),
+    keyboard: EventDecoder::new(layouts::AnyLayout::Us104Key(layouts::Us104Key), HandleControl::MapLettersToUnicode),
+    line: Vec::new(),
+    ready: VecDeque::new(),
+});
+static TTY_WAIT: WaitQueue = WaitQueue::new();
+/// Set by Ctrl+C; stops the blocking read in progress.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Called on Ctrl+C. Installed by the process layer.
+static INTERRUPT_HOOK: IrqSpinlock<Option<fn()>> = IrqSpinlock::new(None);
+
+/// Names `set_keyboard_layout` accepts.
+pub const KEYBOARD_LAYOUTS: [&str; 8] = ["us", "uk", "de", "azerty", "jis", "dvorak", "dvp", "colemak"];
+
+/// Switch the layout keystrokes are decoded with. Returns false for an
+/// unknown name (see `KEYBOARD_LAYOUTS`).
+pub fn set_keyboard_layout(name: &str) -> bool {
+    use layouts::AnyLayout;
+    let layout = match name {
+        "us" => AnyLayout::Us104Key(layouts::Us104Key),
+        "uk" => AnyLayout::Uk105Key(layouts::Uk105Key),
+        "de" => AnyLayout::De105Key(layouts::De105Key),
+        "azerty" => AnyLayout::Azerty(layouts::Azerty),
+        "jis" => AnyLayout::Jis109Key(layouts::Jis109Key),
+        "dvorak" => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+        "dvp" => AnyLayout::DVP104Key(layouts::DVP104Key),
+        "colemak" => AnyLayout::Colemak(layouts::Colemak),
+        _ => return false,
+    };
+    let keyboard = EventDecoder::new(layout, HandleControl::MapLettersToUnicode);
+    TTY.lock().keyboard = keyboard;
+    true
+}
+
+pub fn set_tty_interrupt_hook(hook: fn()) {
+    *INTERRUPT_HOOK.lock() = Some(hook);
+}
+
+/// Decode one scancode and feed any resulting character to the line editor.
+/// Hotkeys are taken out first and their handlers run instead. A key press
+/// counts as input for screen blanking.
+pub fn tty_feed_scancode(scancode: u8) {
+    let mut pressed = false;
+    let (key, hotkey) = {
+        let mut tty = TTY.lock();
+        let set = keyboard_scancodes();
+        if tty.scancodes.set() != set {
+            tty.scancodes = ScancodeDecoder::new(set);
+        }
+        match tty.scancodes.add_byte(scancode) {
+            Some(event) => {
+                pressed = event.state == KeyState::Down;
+                match crate::input::intercept(&event) {
+                    Some(hotkey) => (None, Some(hotkey)),
+                    None => {
+                        update_lock_leds(&event);
+                        (tty.keyboard.process_keyevent(event), None)
+                    }
+                }
+            }
+            None => (None, None),
+        }
+    };
+    if pressed {
+        crate::input::note_input();
+    }
+    if let Some(hotkey) = hotkey {
+        (hotkey.handler)();
+    }
+    if let Some(DecodedKey::Unicode(c)) = key {
+        tty_input(c);
+    }
+}
+
+/// Follow the lock keys on the keyboard LEDs. The decoder keeps its own
+/// lock state, toggled by the same presses.
+fn update_lock_leds(event: &KeyEvent) {
+    use crate::driver_framework::drivers::ps2::{keyboard_leds, set_keyboard_leds, LED_CAPS_LOCK, LED_NUM_LOCK, LED_SCROLL_LOCK};
+    if event.state != KeyState::Down {
+        return;
+    }
+    let led = match event.code {
+        KeyCode::CapsLock => LED_CAPS_LOCK,
+        KeyCode::NumpadLock => LED_NUM_LOCK,
+        KeyCode::ScrollLock => LED_SCROLL_LOCK,
+        _ => return,
+    };
+    set_keyboard_leds(keyboard_leds() ^ led);
+}
+
+/// Edit one typed character into the current line.
+pub fn tty_input(c: char) {
+    match c {
+        '\n' | '\r' => {
+            println!("");
+            {
+                let mut tty = TTY.lock();
+                let mut line = core::mem::take(&mut tty.line);
+                line.push(b'\n');
+                tty.ready.extend(line);
+            }
+            TTY_WAIT.wake_all();
+        }
+        '\x08' => {
+            let erased = {
+                let mut tty = TTY.lock();
+                // drop a whole UTF-8 sequence
+                let mut erased = false;
+                while let Some(b) = tty.line.pop() {
+                    if b & 0xC0 != 0x80 {
+                        erased = true;
+                        break;
+                    }
+                }
+                erased
+            };
+            if erased {
+                print!("\x08 \x08");
+            }
+        }
+        '\x03' => {
+            println!("^C");
+            TTY.lock().line.clear();
+            INTERRUPTED.store(true, Ordering::Release);
+            let hook = *INTERRUPT_HOOK.lock();
+            if let Some(hook) = hook {
+                hook();
+            }
+        }
+        // other control keys have no meaning here
+        c if c.is_control() && c != '\t' => {}
+        c => {
+            let mut utf8 = [0u8; 4];
+            let bytes = c.encode_utf8(&mut utf8).as_bytes();
+            let kept = {
+                let mut tty = TTY.lock();
+                let fits = tty.line.len() + bytes.len() <= TTY_LINE_MAX;
+                if fits {
+                    tty.line.extend_from_slice(bytes);
+                }
+                fits
+            };
+            if kept {
+                print!("{}", c);
+            }
+        }
+    }
+}
+
+/// Take up to `buf.len()` bytes of finished lines without waiting.
+pub fn tty_try_read(buf: &mut [u8]) -> usize {
+    let mut tty = TTY.lock();
+    let n = core::cmp::min(buf.len(), tty.ready.len());
+    for (dst, src) in buf.iter_mut().zip(tty.ready.drain(..n)) {
+        *dst = src;
+    }
+    n
+}
+
+/// Wait for a finished line and read from it.
+pub async fn tty_read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    crate::driver_framework::drivers::ps2kbd::enable_keyboard_port();
+    TTY_WAIT.wait_until(|| match tty_try_read(buf) {
+        0 => None,
+        n => Some(n),
+    })
+    .await
+}
+
+/// Decode the scancodes the keyboard driver has queued. For contexts where
+/// the keyboard task can't run.
+pub fn tty_pump() {
+    while let Some(sc) = crate::driver_framework::drivers::ps2kbd::try_next_scancode() {
+        tty_feed_scancode(sc);
+    }
+}
+
+/// Like `tty_read`, for callers that can't await: decodes scancodes
+/// straight from the keyboard queue and halts between keystrokes. `None`
+/// if Ctrl+C was typed before a line was ready.
+pub fn tty_read_blocking(buf: &mut [u8]) -> Option<usize> {
+    if buf.is_empty() {
+        return Some(0);
+    }
+    crate::driver_framework::drivers::ps2kbd::enable_keyboard_port();
+    INTERRUPTED.store(false, Ordering::Release);
+    let was_enabled = interrupts::are_enabled();
+    let n = loop {
+        tty_pump();
+        if INTERRUPTED.swap(false, Ordering::AcqRel) {
+            break None;
+        }
+        let n = tty_try_read(buf);
+        if n > 0 {
+            break Some(n);
+        }
+        interrupts::enable_and_hlt();
+    };
+    if !was_enabled {
+        interrupts::disable();
+    }
+    n
+}
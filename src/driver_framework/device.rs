@@ -34,6 +34,52 @@ pub enum Capability {
 	Other { id: u8, raw0: u32, raw1: u32 },
 }
 
+/// Where a PCI function sits: segment (ECAM group), bus, device, function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+	pub segment: u16,
+	pub bus: u8,
+	pub device: u8,
+	pub function: u8,
+}
+
+impl PciAddress {
+	pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+		PciAddress { segment, bus, device, function }
+	}
+
+	/// Parse `SSSS:BB:DD.F` or `BB:DD.F` (segment 0), in hex, as `Display`
+	/// and `lspci` print them.
+	pub fn parse(s: &str) -> Option<PciAddress> {
+		let (head, function) = s.rsplit_once('.')?;
+		let (head, device) = head.rsplit_once(':')?;
+		let (segment, bus) = match head.split_once(':') {
+			Some((segment, bus)) => (u16::from_str_radix(segment, 16).ok()?, bus),
+			None => (0, head),
+		};
+		let bus = u8::from_str_radix(bus, 16).ok()?;
+		let device = u8::from_str_radix(device, 16).ok().filter(|&d| d < 32)?;
+		let function = u8::from_str_radix(function, 16).ok().filter(|&f| f < 8)?;
+		Some(PciAddress { segment, bus, device, function })
+	}
+
+	/// Read the dword at `offset` of this function's configuration space.
+	/// Absent functions read as all ones.
+	pub fn config_read(&self, offset: u16) -> u32 {
+		crate::devices::pci::config_read_at(*self, offset)
+	}
+
+	pub fn config_write(&self, offset: u16, value: u32) {
+		crate::devices::pci::config_write_at(*self, offset, value)
+	}
+}
+
+impl fmt::Display for PciAddress {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:04x}:{:02x}:{:02x}.{:x}", self.segment, self.bus, self.device, self.function)
+	}
+}
+
 /// Portable device information. Drivers should use this to probe and attach.
 #[derive(Clone)]
 pub struct DeviceInfo {
@@ -45,12 +91,34 @@ pub struct DeviceInfo {
 	pub resources: Vec<Resource>,
 	pub capabilities: Vec<Capability>,
 	pub description: String,
+	/// The PCI function this is, for devices found on PCI.
+	pub location: Option<PciAddress>,
+}
+
+impl DeviceInfo {
+	/// A name that stays the same from boot to boot: the PCI address where
+	/// there is one, the description otherwise.
+	pub fn name(&self) -> String {
+		match self.location {
+			Some(addr) => format!("{}", addr),
+			None => self.description.clone(),
+		}
+	}
+
+	/// Whether this is a PCI-to-PCI bridge, with functions behind it.
+	pub fn is_pci_bridge(&self) -> bool {
+		self.location.is_some() && self.class == 0x06 && self.subclass == 0x04
+	}
 }
 
 impl fmt::Debug for DeviceInfo {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "DeviceInfo {{ {:04x}:{:04x} class={:02x}/{:02x} desc='{}' res={} caps={} }}",
-			self.vendor_id, self.device_id, self.class, self.subclass, self.description, self.resources.len(), self.capabilities.len())
+		write!(f, "DeviceInfo {{ {:04x}:{:04x} class={:02x}/{:02x} desc='{}' res={} caps={}",
+			self.vendor_id, self.device_id, self.class, self.subclass, self.description, self.resources.len(), self.capabilities.len())?;
+		if let Some(addr) = self.location {
+			write!(f, " at={}", addr)?;
+		}
+		write!(f, " }}")
 	}
 }
 
@@ -85,6 +153,12 @@ impl Device {
 		let info = self.info.lock();
 		info.resources.iter().filter(|r| matches!(r.kind, ResourceKind::Msix { .. })).cloned().collect()
 	}
+
+	/// Map memory BAR `index`, uncached, or return the mapping already made
+	/// of it. See `hal::bar`.
+	pub fn map_bar(&self, index: usize) -> Result<crate::hal::bar::BarMapping, &'static str> {
+		crate::hal::bar::map_bar(self, index)
+	}
 }
 
 pub type DeviceHandle = Box<Device>;
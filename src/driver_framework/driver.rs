@@ -1,5 +1,71 @@
 use crate::driver_framework::device::DeviceHandle;
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::prelude::*;
+
+/// Settings a driver is given when it is attached, so one build can run a
+/// device at another mode or rate. `for_driver` collects them for a driver
+/// from the config store and the kernel command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriverParams {
+	entries: Vec<(String, String)>,
+}
+
+impl DriverParams {
+	pub const fn new() -> Self {
+		DriverParams { entries: Vec::new() }
+	}
+
+	/// The parameters for driver `name`: config store keys `name.key`, then
+	/// command line words `name.key=value`, which win.
+	pub fn for_driver(name: &str) -> Self {
+		let mut params = DriverParams::new();
+		let prefix = format!("{}.", name);
+		for (key, value) in crate::config::config_entries() {
+			if let Some(key) = key.strip_prefix(&prefix) {
+				params.set(key, &value);
+			}
+		}
+		if let Some(cmdline) = crate::devices::fw_cfg::read_cmdline() {
+			params.merge_cmdline(name, &cmdline);
+		}
+		params
+	}
+
+	/// Take the `name.key=value` words of `cmdline`.
+	pub fn merge_cmdline(&mut self, name: &str, cmdline: &str) {
+		for word in cmdline.split_whitespace() {
+			if let Some(setting) = word.strip_prefix(name).and_then(|w| w.strip_prefix('.'))
+				&& let Some((key, value)) = setting.split_once('=')
+				&& !key.is_empty()
+			{
+				self.set(key, value);
+			}
+		}
+	}
+
+	/// Set `key`, replacing any value it had.
+	pub fn set(&mut self, key: &str, value: &str) {
+		match self.entries.iter_mut().find(|(k, _)| k == key) {
+			Some(entry) => entry.1 = value.to_string(),
+			None => self.entries.push((key.to_string(), value.to_string())),
+		}
+	}
+
+	pub fn get(&self, key: &str) -> Option<&str> {
+		self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+	}
+
+	/// (key, value) pairs in the order they were first set.
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
 
 /// Minimal KMDF-like driver trait. Implementors should be able to probe,
 /// start, stop and release devices.
@@ -16,6 +82,23 @@ pub trait Driver: Send + Sync {
 
 	/// Release any remaining resources and prepare for device removal.
 	fn release(&self, device: &DeviceHandle);
+
+	/// Change a runtime setting of a started device, e.g. a mouse's
+	/// sensitivity. Drivers without settings keep the default.
+	fn control(&self, _device: &DeviceHandle, _key: &str, _value: &str) -> Result<(), &'static str> {
+		Err("device has no settings")
+	}
+
+	/// Take attach-time parameter `key`, before `probe`. A runtime setting
+	/// is a parameter too, so by default this is `control`.
+	fn configure(&self, device: &DeviceHandle, key: &str, value: &str) -> Result<(), &'static str> {
+		self.control(device, key, value)
+	}
+
+	/// The settings `control` takes, with their current values.
+	fn control_values(&self, _device: &DeviceHandle) -> Vec<(&'static str, String)> {
+		Vec::new()
+	}
 }
 
 pub type DriverBox = Box<dyn Driver>;
@@ -1,10 +1,11 @@
-use crate::*;
+use crate::prelude::*;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::ptr;
-use core::fmt::Write;
-use spin::Mutex;
+use crate::sync::IrqSpinlock;
 use crate::driver_framework::driver::Driver;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
 
 // A per-framebuffer Console object moved out of the VBE driver. It holds
 // cursor position, colors and text metrics and calls into the VBE drawing
@@ -19,6 +20,10 @@ struct Console {
     bg: u32,
     char_w: usize,
     char_h: usize,
+    /// Whether the text cursor is inverted onto the screen now.
+    cursor_drawn: bool,
+    /// Pixel row being laid out by `draw_run`.
+    scratch: Vec<u32>,
 }
 
 impl Console {
@@ -26,10 +31,170 @@ impl Console {
         self.cur_x = 0;
         self.cur_y += 1;
     }
+
+    /// Invert the cursor's cell (or its bottom rows) between fg and bg, so
+    /// a character under it stays readable and a second flip restores it.
+    fn flip_cursor(&mut self, style: CursorStyle) {
+        let (y, h) = match style {
+            CursorStyle::Underline => (self.char_h.saturating_sub(2), 2.min(self.char_h)),
+            _ => (0, self.char_h),
+        };
+        let px = self.cur_x * self.char_w;
+        let py = self.cur_y * self.char_h + y;
+        // the cell's last column is spacing between glyphs
+        let w = self.char_w.saturating_sub(1).max(1);
+        // the colors as drawn, not as set
+        use crate::driver_framework::drivers::vbe_vga;
+        vbe_vga::invert_rect_at(self.fb_virt, px, py, w, h, (vbe_vga::adjust_color(self.fg) ^ vbe_vga::adjust_color(self.bg)) & 0x00FF_FFFF);
+        self.cursor_drawn = !self.cursor_drawn;
+    }
+
+    /// Draw `text`, which fits on the cursor's row, starting at column `col`.
+    fn draw_run(&mut self, col: usize, text: &[u8]) {
+        if text.is_empty() {
+            return;
+        }
+        let (px, py) = (col * self.char_w, self.cur_y * self.char_h);
+        if BATCHED_TEXT.load(Ordering::Relaxed) {
+            crate::driver_framework::drivers::vbe_vga::draw_text_run_at(self.fb_virt, px, py, text, self.char_w, self.fg, self.bg, &mut self.scratch);
+        } else {
+            for (i, &b) in text.iter().enumerate() {
+                crate::driver_framework::drivers::vbe_vga::draw_char_at(self.fb_virt, px + i * self.char_w, py, b, self.fg);
+            }
+        }
+    }
+
+    /// Take the cursor off the screen before drawing or scrolling.
+    fn hide_cursor(&mut self) {
+        if self.cursor_drawn {
+            self.flip_cursor(console_cursor());
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        let style = console_cursor();
+        if !self.cursor_drawn && style != CursorStyle::Off && CURSOR_LIT.load(Ordering::Relaxed) && !crate::arch::panic_screen::shown() {
+            self.flip_cursor(style);
+        }
+    }
+}
+
+/// Time the cursor stays lit, then dark.
+pub const CURSOR_BLINK_MS: u64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CursorStyle {
+    Off,
+    Block,
+    Underline,
+}
+
+impl CursorStyle {
+    pub fn parse(s: &str) -> Option<CursorStyle> {
+        match s {
+            "off" => Some(CursorStyle::Off),
+            "block" => Some(CursorStyle::Block),
+            "underline" => Some(CursorStyle::Underline),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CursorStyle::Off => "off",
+            CursorStyle::Block => "block",
+            CursorStyle::Underline => "underline",
+        }
+    }
+}
+
+static CURSOR_STYLE: AtomicU8 = AtomicU8::new(CursorStyle::Block as u8);
+static CURSOR_BLINK: AtomicBool = AtomicBool::new(true);
+/// Blink phase: whether the cursor should be showing now.
+static CURSOR_LIT: AtomicBool = AtomicBool::new(true);
+static BLINK_TIMER: Mutex<Option<crate::time::TimerId>> = Mutex::new(None);
+
+pub fn console_cursor() -> CursorStyle {
+    match CURSOR_STYLE.load(Ordering::Relaxed) {
+        x if x == CursorStyle::Block as u8 => CursorStyle::Block,
+        x if x == CursorStyle::Underline as u8 => CursorStyle::Underline,
+        _ => CursorStyle::Off,
+    }
+}
+
+/// Change how the text cursor is drawn; `Off` hides it.
+pub fn set_console_cursor(style: CursorStyle) {
+    let mut consoles = CONSOLES.lock();
+    for c in consoles.iter_mut() {
+        c.hide_cursor();
+    }
+    CURSOR_STYLE.store(style as u8, Ordering::Relaxed);
+    for c in consoles.iter_mut() {
+        c.show_cursor();
+    }
+}
+
+pub fn console_cursor_blinks() -> bool {
+    CURSOR_BLINK.load(Ordering::Relaxed)
+}
+
+/// Blink the cursor, or keep it lit.
+pub fn set_console_cursor_blink(blink: bool) {
+    CURSOR_BLINK.store(blink, Ordering::Relaxed);
+    if !blink {
+        light_cursor();
+    }
+}
+
+/// Show the cursor and restart its blink, so it stays visible while text
+/// is being written.
+fn light_cursor() {
+    CURSOR_LIT.store(true, Ordering::Relaxed);
+    for c in CONSOLES.lock().iter_mut() {
+        c.show_cursor();
+    }
+}
+
+/// Blink timer callback.
+fn blink_cursor() {
+    if !console_cursor_blinks() || crate::arch::panic_screen::shown() {
+        return;
+    }
+    let lit = !CURSOR_LIT.fetch_xor(true, Ordering::Relaxed);
+    for c in CONSOLES.lock().iter_mut() {
+        if lit { c.show_cursor() } else { c.hide_cursor() }
+    }
+}
+
+/// Draw text a row of glyphs at a time rather than a glyph at a time.
+/// Only `console_text_benchmark` turns it off, to compare the two.
+static BATCHED_TEXT: AtomicBool = AtomicBool::new(true);
+
+/// Time printing `lines` full lines to the first framebuffer console with
+/// each way of drawing text, in ns: (a row of glyphs at a time, a glyph at
+/// a time). Clears the console afterwards. `None` without a framebuffer.
+pub fn console_text_benchmark(lines: usize) -> Option<(u64, u64)> {
+    let fb = *crate::driver_framework::drivers::vbe_vga::get_framebuffer_addrs().first()?;
+    let idx = get_or_create_console(fb);
+    let cols = CONSOLES.lock()[idx].cols;
+    let mut line: alloc::string::String = (0..cols.saturating_sub(1)).map(|i| (b'!' + (i % 94) as u8) as char).collect();
+    line.push('\n');
+    let time = |batched: bool| {
+        BATCHED_TEXT.store(batched, Ordering::Relaxed);
+        let start = crate::time::uptime_ns();
+        for _ in 0..lines {
+            console_print_first(&line);
+        }
+        crate::time::uptime_ns() - start
+    };
+    let result = (time(true), time(false));
+    BATCHED_TEXT.store(true, Ordering::Relaxed);
+    console_clear_first();
+    Some(result)
 }
 
 /// Simple manager storing Console objects (one per framebuffer).
-static CONSOLES: Mutex<Vec<Console>> = Mutex::new(Vec::new());
+static CONSOLES: IrqSpinlock<Vec<Console>> = IrqSpinlock::new(Vec::new());
 
 /// Find or create a console for a given framebuffer virtual address.
 fn get_or_create_console(fb_virt: u64) -> usize {
@@ -50,7 +215,7 @@ fn get_or_create_console(fb_virt: u64) -> usize {
         if cols == 0 { cols = 80; }
         if rows == 0 { rows = 25; }
     }
-    let c = Console { fb_virt, cols, rows, cur_x: 0, cur_y: 0, fg: 0xFFFFFFFFu32, bg: 0x00000000u32, char_w, char_h };
+    let c = Console { fb_virt, cols, rows, cur_x: 0, cur_y: 0, fg: 0xFFFFFFFFu32, bg: 0x00000000u32, char_w, char_h, cursor_drawn: false, scratch: Vec::new() };
     consoles.push(c);
     consoles.len() - 1
 }
@@ -60,8 +225,6 @@ pub fn console_print_first(s: &str) -> bool {
     use crate::driver_framework::drivers::vbe_vga;
     let addrs = vbe_vga::get_framebuffer_addrs();
     if addrs.is_empty() {
-        // fallback to boot VGA
-        crate::bootvga::vga_buffer::WRITER.lock().write_str(s).ok();
         return false;
     }
     // Use the first FB
@@ -69,8 +232,33 @@ pub fn console_print_first(s: &str) -> bool {
     let idx = get_or_create_console(fb);
     let mut consoles = CONSOLES.lock();
     let mut console = consoles.remove(idx);
+    console.hide_cursor();
+    // Printable bytes are collected into runs on the cursor's row, drawn
+    // together when a control byte, the end of the row or the end of `s`
+    // comes
+    let bytes = s.as_bytes();
+    let mut run_start = 0;
+    let mut run_col = console.cur_x;
     // Write bytes with handling for newline/tab/backspace
-    for b in s.bytes() {
+    for (i, &b) in bytes.iter().enumerate() {
+        if !matches!(b, b'\n' | b'\r' | 8u8 | 9u8) {
+            if i == run_start {
+                run_col = console.cur_x;
+            }
+            console.cur_x += 1;
+            if console.cur_x >= console.cols {
+                console.draw_run(run_col, &bytes[run_start..=i]);
+                run_start = i + 1;
+                console.newline();
+                if console.cur_y >= console.rows {
+                    console_scroll_mut(&mut console, 1);
+                    console.cur_y = console.rows - 1;
+                }
+            }
+            continue;
+        }
+        console.draw_run(run_col, &bytes[run_start..i]);
+        run_start = i + 1;
         match b {
             b'\n' => {
                 console.newline();
@@ -90,23 +278,24 @@ pub fn console_print_first(s: &str) -> bool {
             9u8 => { // tab
                 let tab_width = 8usize;
                 let next = ((console.cur_x / tab_width) + 1) * tab_width;
-                if next >= console.cols { console.newline(); } else { console.cur_x = next; }
-            }
-            _ => {
-                let px = (console.cur_x * console.char_w) as usize;
-                let py = (console.cur_y * console.char_h) as usize;
-                crate::driver_framework::drivers::vbe_vga::draw_char_at(fb, px, py, b, console.fg);
-                console.cur_x += 1;
-                if console.cur_x >= console.cols {
+                if next >= console.cols {
                     console.newline();
+                    // the cursor must stay on screen
                     if console.cur_y >= console.rows {
                         console_scroll_mut(&mut console, 1);
                         console.cur_y = console.rows - 1;
                     }
-                }
+                } else { console.cur_x = next; }
             }
+            _ => {}
         }
     }
+    if run_start < bytes.len() {
+        console.draw_run(run_col, &bytes[run_start..]);
+    }
+    // typing keeps the cursor lit; the blink starts over from here
+    CURSOR_LIT.store(true, Ordering::Relaxed);
+    console.show_cursor();
     consoles.insert(idx, console);
     true
 }
@@ -122,6 +311,7 @@ fn console_scroll_mut(console: &mut Console, lines: usize) {
     let move_height = (console.rows - lines) * console.char_h;
     let src_offset = lines * console.char_h * pitch;
     let move_bytes = move_height * pitch;
+    let bg = crate::driver_framework::drivers::vbe_vga::adjust_color(console.bg);
     unsafe {
         let base = console.fb_virt as *mut u8;
         let src = base.add(src_offset);
@@ -134,13 +324,14 @@ fn console_scroll_mut(console: &mut Console, lines: usize) {
         let end = p.add(clear_bytes);
         while p < end {
             if (end as usize).wrapping_sub(p as usize) >= 4 {
-                core::ptr::write_volatile(p as *mut u32, console.bg);
+                core::ptr::write_volatile(p as *mut u32, bg);
                 p = p.add(4);
             } else {
                 core::ptr::write_volatile(p, 0u8);
                 p = p.add(1);
             }
         }
+        crate::arch::processor::flush_cache_range(base, clear_start + clear_bytes);
     }
 }
 
@@ -148,16 +339,46 @@ fn console_scroll_mut(console: &mut Console, lines: usize) {
 pub fn console_clear_first() {
     use crate::driver_framework::drivers::vbe_vga;
     let addrs = vbe_vga::get_framebuffer_addrs();
-    if addrs.is_empty() { crate::bootvga::vga_buffer::WRITER.lock().clear_screen(); return; }
+    if addrs.is_empty() { return; }
     let fb = addrs[0];
     let idx = get_or_create_console(fb);
     let mut consoles = CONSOLES.lock();
     let mut c = consoles.remove(idx);
     crate::driver_framework::drivers::vbe_vga::draw_rect_at(c.fb_virt, 0, 0, c.cols * c.char_w, c.rows * c.char_h, c.bg);
     c.cur_x = 0; c.cur_y = 0;
+    c.cursor_drawn = false;
+    c.show_cursor();
     consoles.insert(idx, c);
 }
 
+/// Move the first console's text up `lines` rows, and the cursor with it.
+pub fn console_scroll_first(lines: usize) {
+    use crate::driver_framework::drivers::vbe_vga;
+    let addrs = vbe_vga::get_framebuffer_addrs();
+    if addrs.is_empty() { return; }
+    let idx = get_or_create_console(addrs[0]);
+    let mut consoles = CONSOLES.lock();
+    if let Some(c) = consoles.get_mut(idx) {
+        c.hide_cursor();
+        console_scroll_mut(c, lines);
+        c.cur_y = c.cur_y.saturating_sub(lines);
+        c.show_cursor();
+    }
+}
+
+/// Run `f` with console output held off and the cursor off the screen,
+/// for changes to how everything on it is drawn.
+pub fn console_without_cursor(f: impl FnOnce()) {
+    let mut consoles = CONSOLES.lock();
+    for c in consoles.iter_mut() {
+        c.hide_cursor();
+    }
+    f();
+    for c in consoles.iter_mut() {
+        c.show_cursor();
+    }
+}
+
 pub fn console_set_colors_first(fg: u32, bg: u32) {
     use crate::driver_framework::drivers::vbe_vga;
     let addrs = vbe_vga::get_framebuffer_addrs();
@@ -165,7 +386,12 @@ pub fn console_set_colors_first(fg: u32, bg: u32) {
     let fb = addrs[0];
     let idx = get_or_create_console(fb);
     let mut consoles = CONSOLES.lock();
-    if let Some(c) = consoles.get_mut(idx) { c.fg = fg; c.bg = bg; }
+    if let Some(c) = consoles.get_mut(idx) {
+        // the cursor is inverted with the colors it was drawn in
+        c.hide_cursor();
+        c.fg = fg; c.bg = bg;
+        c.show_cursor();
+    }
 }
 
 pub fn console_set_cursor_first(col: usize, row: usize) {
@@ -176,8 +402,10 @@ pub fn console_set_cursor_first(col: usize, row: usize) {
     let idx = get_or_create_console(fb);
     let mut consoles = CONSOLES.lock();
     if let Some(c) = consoles.get_mut(idx) {
+        c.hide_cursor();
         c.cur_x = core::cmp::min(col, c.cols.saturating_sub(1));
         c.cur_y = core::cmp::min(row, c.rows.saturating_sub(1));
+        c.show_cursor();
     }
 }
 
@@ -188,9 +416,41 @@ impl ConsoleDriver { pub fn new() -> Self { ConsoleDriver {} } }
 
 impl Driver for ConsoleDriver {
     fn probe(&self, _device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> { Ok(()) }
-    fn start(&self, _device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> { Ok(()) }
-    fn stop(&self, _device: &crate::driver_framework::device::DeviceHandle) {}
-    fn release(&self, _device: &crate::driver_framework::device::DeviceHandle) {}
+    fn start(&self, _device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> {
+        // runs once the ktimer task is up
+        let mut timer = BLINK_TIMER.lock();
+        if timer.is_none() {
+            *timer = Some(crate::time::every(core::time::Duration::from_millis(CURSOR_BLINK_MS), blink_cursor));
+        }
+        Ok(())
+    }
+    fn stop(&self, _device: &crate::driver_framework::device::DeviceHandle) {
+        if let Some(id) = BLINK_TIMER.lock().take() {
+            crate::time::cancel(id);
+        }
+        light_cursor();
+    }
+    fn release(&self, device: &crate::driver_framework::device::DeviceHandle) { self.stop(device); }
+
+    fn control(&self, _device: &crate::driver_framework::device::DeviceHandle, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "cursor" => set_console_cursor(CursorStyle::parse(value).ok_or("want block, underline or off")?),
+            "blink" => set_console_cursor_blink(match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err("want on or off"),
+            }),
+            _ => return Err("unknown console setting"),
+        }
+        Ok(())
+    }
+
+    fn control_values(&self, _device: &crate::driver_framework::device::DeviceHandle) -> Vec<(&'static str, alloc::string::String)> {
+        alloc::vec![
+            ("cursor", alloc::string::String::from(console_cursor().name())),
+            ("blink", alloc::string::String::from(if console_cursor_blinks() { "on" } else { "off" })),
+        ]
+    }
 }
 
 pub fn boxed_driver() -> Box<dyn Driver> { Box::new(ConsoleDriver::new()) }
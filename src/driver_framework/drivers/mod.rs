@@ -1,8 +1,11 @@
+pub mod ps2;
 pub mod ps2kbd;
 pub mod ps2mouse;
 pub mod vbe_vga;
 pub mod console;
+pub mod serial;
 
+pub use ps2::*;
 pub use ps2kbd::*;
 pub use ps2mouse::*;
 pub use vbe_vga::*;
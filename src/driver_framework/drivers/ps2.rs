@@ -0,0 +1,508 @@
+//! PS/2 hot-plug
+//!
+//! Keyboards and mice on the PS/2 ports get unplugged and plugged back
+//! (KVM switches do it all the time), and a device that comes back is in
+//! its power-on state: a keyboard with its LEDs off, a mouse that doesn't
+//! report movement. Two signs give it away:
+//!
+//! - Plugging in: the device runs its self-test and sends the BAT
+//!   completion code, 0xAA (0xFC if it failed). Each driver's IRQ handler
+//!   picks that out of its input and calls `device_reset`.
+//! - Unplugging: nothing arrives at all. Once a port has been quiet for
+//!   `PROBE_INTERVAL_MS` the monitor probes it, with an echo (0xEE) for the
+//!   keyboard and a set-scaling-1:1 (0xE6, which changes nothing) for the
+//!   mouse. After `MISSED_PROBES` unanswered probes in a row the device is
+//!   taken as gone, and a gone device answering again as plugged back.
+//!
+//! Either way the monitor task runs the device's init again: defaults,
+//! then the keyboard's LEDs or the mouse's wheel and sample rate, then
+//! enabling input. Commands are sent from the task and their replies come back
+//! through the IRQ handlers, which give each byte to `port_byte` first so
+//! replies aren't decoded as input.
+//!
+//! Before a driver has its IRQ, it talks to the controller and its device
+//! by polling instead: `controller_config`, `set_controller_config` and
+//! `keyboard_command_polled`.
+//!
+//! Both drivers claim the controller's ports with `claim_controller_ports`
+//! when they start; they share them, and no other driver can have them.
+//!
+//! Polled waits on the controller go through `poll_ms`, which looks at it
+//! every `POLL_US` and counts the time in `udelay`s, so they are the right
+//! length before the TSC is calibrated too.
+
+use crate::prelude::*;
+use crate::sync::WaitQueue;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+
+/// How long a port stays quiet before it is probed, and how often it is probed after that.
+pub const PROBE_INTERVAL_MS: u64 = 1000;
+/// Unanswered probes in a row before a device counts as unplugged.
+pub const MISSED_PROBES: u32 = 2;
+/// How long a device gets to reply to a command.
+const REPLY_TIMEOUT_MS: u64 = 50;
+/// How long to wait for the controller to take a byte.
+const CONTROLLER_TIMEOUT_MS: u64 = 20;
+/// Gap between looks at the status register in polled waits.
+pub const POLL_US: u64 = 10;
+/// Times a command is sent when the device asks for it again.
+const COMMAND_TRIES: usize = 3;
+
+pub const BAT_OK: u8 = 0xAA;
+pub const BAT_FAILED: u8 = 0xFC;
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const ECHO: u8 = 0xEE;
+const SET_LEDS: u8 = 0xED;
+const SET_SCALING_1_1: u8 = 0xE6;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const ENABLE: u8 = 0xF4;
+const SET_DEFAULTS: u8 = 0xF6;
+const GET_ID: u8 = 0xF2;
+/// Keyboard command: select a scancode set, or with 0 report the current one.
+pub const SCANCODE_SET: u8 = 0xF0;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+/// Controller configuration bit: translate the keyboard's scancode set 2
+/// to set 1.
+pub const CONFIG_TRANSLATE: u8 = 1 << 6;
+/// Status bits: output buffer full, and the byte in it is from the mouse.
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_AUX_DATA: u8 = 0x20;
+/// Marks a reply slot as filled, so a reply of 0 still counts.
+const REPLY_SET: u16 = 0x100;
+
+/// Sample rates set in a row to turn on an IntelliMouse's wheel. It then
+/// reports device ID 3 and sends 4-byte packets.
+pub const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+pub const WHEEL_MOUSE_ID: u8 = 3;
+
+/// Keyboard LED bits for `set_keyboard_leds`.
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Controller data and status/command ports.
+pub const DATA_PORT: u16 = 0x60;
+pub const STATUS_PORT: u16 = 0x64;
+
+/// Claim the controller's ports for `device`, a keyboard or mouse on it.
+pub fn claim_controller_ports(device: &crate::driver_framework::device::Device) -> Result<(), &'static str> {
+    crate::hal::ioport::request_ports(device, "i8042", DATA_PORT, 1)?;
+    crate::hal::ioport::request_ports(device, "i8042", STATUS_PORT, 1)
+}
+
+/// Poll `f` every `POLL_US` until it returns `Some`, for at most
+/// `timeout_ms`.
+pub fn poll_ms<T>(timeout_ms: u64, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    for _ in 0..timeout_ms * 1000 / POLL_US {
+        if let Some(v) = f() {
+            return Some(v);
+        }
+        crate::time::udelay(POLL_US);
+    }
+    f()
+}
+
+/// Wait up to `timeout_ms` for the controller's input buffer to empty, so
+/// it can take a byte. False if it stayed full.
+pub fn wait_input_clear(timeout_ms: u64) -> bool {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    poll_ms(timeout_ms, || (unsafe { status.read() } & 0x02 == 0).then_some(())).is_some()
+}
+
+/// Sample rates a PS/2 mouse accepts, in reports per second.
+pub const MOUSE_SAMPLE_RATES: [u8; 7] = [10, 20, 40, 60, 80, 100, 200];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ps2Port {
+    Keyboard,
+    Mouse,
+}
+
+impl Ps2Port {
+    fn state(self) -> &'static PortState {
+        &PORTS[self as usize]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ps2Port::Keyboard => "kbd",
+            Ps2Port::Mouse => "mouse",
+        }
+    }
+}
+
+struct PortState {
+    present: AtomicBool,
+    /// A command is waiting for its reply.
+    awaiting: AtomicBool,
+    /// The reply, `REPLY_SET` plus the byte once it has come.
+    reply: AtomicU16,
+    /// A command's data byte is expected after its reply.
+    awaiting_data: AtomicBool,
+    data: AtomicU16,
+    /// The device reset itself; it needs initializing.
+    reset: AtomicBool,
+    /// LEDs or sample rate changed and haven't been sent yet.
+    settings_dirty: AtomicBool,
+    last_byte_ns: AtomicU64,
+    missed: AtomicU32,
+    resets: AtomicU32,
+    disconnects: AtomicU32,
+    reinits: AtomicU32,
+}
+
+impl PortState {
+    const fn new(settings_dirty: bool) -> PortState {
+        PortState {
+            present: AtomicBool::new(true),
+            awaiting: AtomicBool::new(false),
+            reply: AtomicU16::new(0),
+            awaiting_data: AtomicBool::new(false),
+            data: AtomicU16::new(0),
+            reset: AtomicBool::new(false),
+            settings_dirty: AtomicBool::new(settings_dirty),
+            last_byte_ns: AtomicU64::new(0),
+            missed: AtomicU32::new(0),
+            resets: AtomicU32::new(0),
+            disconnects: AtomicU32::new(0),
+            reinits: AtomicU32::new(0),
+        }
+    }
+}
+
+// the keyboard powers up with its LEDs off, while the decoder starts with num lock on
+static PORTS: [PortState; 2] = [PortState::new(true), PortState::new(false)];
+static KEYBOARD_LEDS: AtomicU8 = AtomicU8::new(LED_NUM_LOCK);
+static MOUSE_SAMPLE_RATE: AtomicU8 = AtomicU8::new(100);
+static REPLY_WAIT: WaitQueue = WaitQueue::new();
+static MONITOR_WAIT: WaitQueue = WaitQueue::new();
+static MONITOR_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ps2PortStatus {
+    pub port: Ps2Port,
+    pub present: bool,
+    /// Self-test completion codes seen: plug-ins and spontaneous resets.
+    pub resets: u32,
+    pub disconnects: u32,
+    /// Times the init sequence was run again, successfully.
+    pub reinits: u32,
+}
+
+pub fn ps2_port_status(port: Ps2Port) -> Ps2PortStatus {
+    let s = port.state();
+    Ps2PortStatus {
+        port,
+        present: s.present.load(Ordering::Relaxed),
+        resets: s.resets.load(Ordering::Relaxed),
+        disconnects: s.disconnects.load(Ordering::Relaxed),
+        reinits: s.reinits.load(Ordering::Relaxed),
+    }
+}
+
+/// Set the keyboard LEDs (`LED_*` bits). They are sent by the monitor
+/// task, and again whenever the keyboard is re-initialized.
+pub fn set_keyboard_leds(leds: u8) {
+    KEYBOARD_LEDS.store(leds & 0x07, Ordering::Relaxed);
+    Ps2Port::Keyboard.state().settings_dirty.store(true, Ordering::Release);
+    MONITOR_WAIT.wake_one();
+}
+
+pub fn keyboard_leds() -> u8 {
+    KEYBOARD_LEDS.load(Ordering::Relaxed)
+}
+
+/// Set the mouse sample rate, one of `MOUSE_SAMPLE_RATES`. False for any
+/// other rate.
+pub fn set_mouse_sample_rate(rate: u8) -> bool {
+    if !MOUSE_SAMPLE_RATES.contains(&rate) {
+        return false;
+    }
+    MOUSE_SAMPLE_RATE.store(rate, Ordering::Relaxed);
+    Ps2Port::Mouse.state().settings_dirty.store(true, Ordering::Release);
+    MONITOR_WAIT.wake_one();
+    true
+}
+
+pub fn mouse_sample_rate() -> u8 {
+    MOUSE_SAMPLE_RATE.load(Ordering::Relaxed)
+}
+
+/// Called by a driver's IRQ handler with each byte from its port. True if
+/// it was the reply to a command from the monitor, which the handler must
+/// not take as input.
+pub(crate) fn port_byte(port: Ps2Port, byte: u8) -> bool {
+    let s = port.state();
+    s.last_byte_ns.store(crate::time::uptime_ns(), Ordering::Relaxed);
+    let slot = if matches!(byte, ACK | RESEND | ECHO) && s.awaiting.swap(false, Ordering::AcqRel) {
+        &s.reply
+    } else if s.awaiting_data.swap(false, Ordering::AcqRel) {
+        &s.data
+    } else {
+        return false;
+    };
+    slot.store(REPLY_SET | byte as u16, Ordering::Release);
+    REPLY_WAIT.wake_all();
+    true
+}
+
+/// Called by a driver's IRQ handler when its device sent a self-test
+/// completion code: it was just plugged in, or reset itself, and needs
+/// initializing again.
+pub(crate) fn device_reset(port: Ps2Port) {
+    let s = port.state();
+    s.resets.fetch_add(1, Ordering::Relaxed);
+    s.reset.store(true, Ordering::Release);
+    MONITOR_WAIT.wake_one();
+}
+
+/// Spawn the monitor task. Called by both drivers' `start`; only the first
+/// call does anything.
+pub fn start_hotplug_monitor() {
+    if MONITOR_STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    crate::arch::task::spawn_named("ps2hotplug", monitor());
+}
+
+async fn monitor() {
+    loop {
+        let _ = crate::time::with_timeout_ms(PROBE_INTERVAL_MS, MONITOR_WAIT.wait_until(|| work_pending().then_some(()))).await;
+        for port in [Ps2Port::Keyboard, Ps2Port::Mouse] {
+            if attached(port) {
+                check_port(port).await;
+            }
+        }
+    }
+}
+
+fn work_pending() -> bool {
+    [Ps2Port::Keyboard, Ps2Port::Mouse].into_iter().any(|port| {
+        let s = port.state();
+        attached(port)
+            && (s.reset.load(Ordering::Acquire) || (s.present.load(Ordering::Acquire) && s.settings_dirty.load(Ordering::Acquire)))
+    })
+}
+
+/// Whether the port's driver is running and the port is open; a closed
+/// port answers nothing and would look unplugged.
+fn attached(port: Ps2Port) -> bool {
+    match port {
+        Ps2Port::Keyboard => crate::driver_framework::drivers::ps2kbd::keyboard_port_enabled(),
+        Ps2Port::Mouse => crate::driver_framework::drivers::ps2mouse::get_global_instance_typed().is_some(),
+    }
+}
+
+async fn check_port(port: Ps2Port) {
+    let s = port.state();
+    if s.reset.swap(false, Ordering::AcqRel) {
+        if !s.present.swap(true, Ordering::AcqRel) {
+            println!("[{}] device plugged back in", port.name());
+        }
+        s.missed.store(0, Ordering::Relaxed);
+        reinit(port).await;
+        return;
+    }
+    // not retried if it fails: a device that isn't answering gets them with its init
+    if s.present.load(Ordering::Acquire) && s.settings_dirty.swap(false, Ordering::AcqRel) {
+        let _ = send_settings(port).await;
+    }
+
+    // input arriving shows the device is there
+    let quiet_ns = crate::time::uptime_ns().saturating_sub(s.last_byte_ns.load(Ordering::Relaxed));
+    if s.present.load(Ordering::Acquire) && quiet_ns < PROBE_INTERVAL_MS * 1_000_000 {
+        return;
+    }
+    let (probe, answer) = match port {
+        Ps2Port::Keyboard => (ECHO, ECHO),
+        Ps2Port::Mouse => (SET_SCALING_1_1, ACK),
+    };
+    if command(port, probe).await == Some(answer) {
+        s.missed.store(0, Ordering::Relaxed);
+        if !s.present.swap(true, Ordering::AcqRel) {
+            println!("[{}] device answering again", port.name());
+            reinit(port).await;
+        }
+    } else if s.missed.fetch_add(1, Ordering::Relaxed) + 1 == MISSED_PROBES && s.present.swap(false, Ordering::AcqRel) {
+        s.disconnects.fetch_add(1, Ordering::Relaxed);
+        println!("[{}] device not answering; unplugged?", port.name());
+    }
+}
+
+/// Run the device's init sequence, as after power-on.
+async fn reinit(port: Ps2Port) {
+    // defaults also stop a mouse reporting, so no packet is half-read after
+    if command(port, SET_DEFAULTS).await == Some(ACK) {
+        if port == Ps2Port::Mouse {
+            crate::driver_framework::drivers::ps2mouse::reset_packet_state();
+            if crate::driver_framework::drivers::ps2mouse::wheel_enabled() {
+                enable_wheel().await;
+            }
+        }
+        if send_settings(port).await && command(port, ENABLE).await == Some(ACK) {
+            port.state().settings_dirty.store(false, Ordering::Release);
+            port.state().reinits.fetch_add(1, Ordering::Relaxed);
+            println!("[{}] re-initialized", port.name());
+            return;
+        }
+    }
+    kwarn_once!("[PS2] re-initializing the {} failed", port.name());
+}
+
+/// Knock for the wheel again; the packet size follows whether the mouse
+/// that is there now has one.
+async fn enable_wheel() {
+    for rate in WHEEL_KNOCK {
+        if command(Ps2Port::Mouse, SET_SAMPLE_RATE).await != Some(ACK) || command(Ps2Port::Mouse, rate).await != Some(ACK) {
+            break;
+        }
+    }
+    let id = query(Ps2Port::Mouse, GET_ID).await;
+    crate::driver_framework::drivers::ps2mouse::set_wheel(id == Some(WHEEL_MOUSE_ID));
+}
+
+/// Send the LEDs or the sample rate.
+async fn send_settings(port: Ps2Port) -> bool {
+    let (cmd, value) = match port {
+        Ps2Port::Keyboard => (SET_LEDS, keyboard_leds()),
+        Ps2Port::Mouse => (SET_SAMPLE_RATE, mouse_sample_rate()),
+    };
+    command(port, cmd).await == Some(ACK) && command(port, value).await == Some(ACK)
+}
+
+/// Send `byte` to the device on `port` and wait for the reply, sending it
+/// again while the device asks for that. The reply, or `None` if none came.
+async fn command(port: Ps2Port, byte: u8) -> Option<u8> {
+    let s = port.state();
+    for _ in 0..COMMAND_TRIES {
+        s.reply.store(0, Ordering::Relaxed);
+        s.awaiting.store(true, Ordering::Release);
+        if !write_device(port, byte) {
+            break;
+        }
+        let reply = wait_reply(&s.reply).await;
+        match reply {
+            Ok(RESEND) => continue,
+            Ok(r) => return Some(r),
+            Err(_) => break,
+        }
+    }
+    s.awaiting.store(false, Ordering::Release);
+    None
+}
+
+/// Send a command that is acknowledged and then answered with one byte,
+/// and return that byte.
+async fn query(port: Ps2Port, byte: u8) -> Option<u8> {
+    let s = port.state();
+    s.data.store(0, Ordering::Relaxed);
+    s.awaiting_data.store(true, Ordering::Release);
+    let data = match command(port, byte).await {
+        Some(ACK) => wait_reply(&s.data).await.ok(),
+        _ => None,
+    };
+    s.awaiting_data.store(false, Ordering::Release);
+    data
+}
+
+async fn wait_reply(slot: &AtomicU16) -> Result<u8, crate::time::TimeoutError> {
+    crate::time::with_timeout_ms(REPLY_TIMEOUT_MS, REPLY_WAIT.wait_until(|| {
+        let r = slot.load(Ordering::Acquire);
+        (r & REPLY_SET != 0).then_some(r as u8)
+    }))
+    .await
+}
+
+/// Hand `byte` to the controller for the device on `port`.
+fn write_device(port: Ps2Port, byte: u8) -> bool {
+    let ready = || wait_input_clear(CONTROLLER_TIMEOUT_MS);
+    if port == Ps2Port::Mouse {
+        // the next data byte goes to the second port
+        if !ready() {
+            return false;
+        }
+        unsafe { Port::<u8>::new(0x64).write(0xD4u8) };
+    }
+    if !ready() {
+        return false;
+    }
+    unsafe { Port::<u8>::new(0x60).write(byte) };
+    true
+}
+
+/// Hand a command byte to the controller itself.
+fn write_controller(cmd: u8) -> bool {
+    if !wait_input_clear(CONTROLLER_TIMEOUT_MS) {
+        return false;
+    }
+    unsafe { Port::<u8>::new(0x64).write(cmd) };
+    true
+}
+
+/// Wait for a byte from the controller or the keyboard, passing over any
+/// the mouse sends meanwhile.
+fn read_polled(timeout_ms: u64) -> Option<u8> {
+    let mut status: Port<u8> = Port::new(0x64);
+    let mut data: Port<u8> = Port::new(0x60);
+    poll_ms(timeout_ms, || {
+        let st = unsafe { status.read() };
+        if st & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let byte = unsafe { data.read() };
+        (st & STATUS_AUX_DATA == 0).then_some(byte)
+    })
+}
+
+/// Throw away whatever is waiting in the output buffer.
+fn drain_output() {
+    let mut status: Port<u8> = Port::new(0x64);
+    for _ in 0..16 {
+        if unsafe { status.read() } & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        unsafe { Port::<u8>::new(0x60).read() };
+    }
+}
+
+/// The controller's configuration byte, read by polling. For use before
+/// the keyboard's IRQ is requested, since the handler would take the reply.
+pub fn controller_config() -> Option<u8> {
+    drain_output();
+    if !write_controller(READ_CONFIG) {
+        return None;
+    }
+    read_polled(CONTROLLER_TIMEOUT_MS)
+}
+
+/// Write the controller's configuration byte.
+pub fn set_controller_config(config: u8) -> bool {
+    write_controller(WRITE_CONFIG) && write_device(Ps2Port::Keyboard, config)
+}
+
+/// Send `bytes` to the keyboard one at a time, each to be acknowledged,
+/// polling for the replies; then, with `answer`, read the one-byte answer
+/// too. `Some(0)` without `answer`. For use before the keyboard's IRQ is
+/// requested.
+pub(crate) fn keyboard_command_polled(bytes: &[u8], answer: bool) -> Option<u8> {
+    drain_output();
+    for &byte in bytes {
+        let mut reply = None;
+        for _ in 0..COMMAND_TRIES {
+            if !write_device(Ps2Port::Keyboard, byte) {
+                return None;
+            }
+            reply = read_polled(REPLY_TIMEOUT_MS);
+            if reply != Some(RESEND) {
+                break;
+            }
+        }
+        if reply != Some(ACK) {
+            return None;
+        }
+    }
+    if answer { read_polled(REPLY_TIMEOUT_MS) } else { Some(0) }
+}
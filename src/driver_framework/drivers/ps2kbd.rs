@@ -1,23 +1,34 @@
-use crate::*;
+use crate::prelude::*;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::pin::Pin;
 use core::task::Poll;
 use futures_util::stream::Stream;
-use futures_util::task::AtomicWaker;
-use core::sync::atomic::Ordering as AtomicOrdering;
+use core::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use futures_util::StreamExt;
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
 use pc_keyboard::*;
 use x86_64::structures::idt::InterruptStackFrame;
 use spin::Mutex;
+use crate::sync::channel::{channel, Receiver, Sender};
 
 use crate::driver_framework::driver::Driver;
+use crate::input::scancode::{set_keyboard_scancodes, Scancodes};
 use crate::driver_framework::device::{DeviceInfo, Resource, ResourceKind};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+/// How long to wait for the PS/2 controller to accept a command.
+const PS2_TIMEOUT_MS: u64 = 20;
+
+static SCANCODE_TX: OnceCell<Sender<u8>> = OnceCell::uninit();
+static SCANCODE_RX: OnceCell<Receiver<u8>> = OnceCell::uninit();
+
+fn scancode_channel() -> &'static Receiver<u8> {
+    SCANCODE_RX.get_or_init(|| {
+        let (tx, rx) = channel(100);
+        SCANCODE_TX.init_once(|| tx);
+        rx
+    })
+}
 
 pub struct Ps2KbdDriver {
     /// Tracks which IRQ vectors this driver registered so they can be
@@ -32,27 +43,98 @@ impl Ps2KbdDriver {
     }
 
     fn init_queue_if_needed(&self) {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100)).ok();
+        scancode_channel();
     }
 
-    extern "x86-interrupt" fn irq_handler(_stack_frame: InterruptStackFrame) {
+    /// Runs from the IDT trampoline, which sends the EOI for us.
+    fn irq_handler(_vector: u8) {
+        use crate::driver_framework::drivers::ps2::{self, Ps2Port};
         use x86_64::instructions::port::Port;
         let mut port = Port::new(0x60);
-        let scancode: u8 = unsafe { port.read() };
-        if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-            let _ = queue.push(scancode);
-            WAKER.wake();
+        let scancode: u8 = crate::fault::ps2_byte(unsafe { port.read() });
+        if ps2::port_byte(Ps2Port::Keyboard, scancode) {
+            return;
         }
-        unsafe {
-            if crate::hal::apic::is_initialized() {
-                crate::hal::apic::send_eoi();
-            } else {
-                crate::arch::interrupts::PICS.lock().notify_end_of_interrupt(crate::arch::interrupts::InterruptIndex::Keyboard.as_u8());
+        if is_self_test_code(scancode) {
+            ps2::device_reset(Ps2Port::Keyboard);
+            return;
+        }
+        if let Ok(tx) = SCANCODE_TX.try_get() {
+            if crate::fault::inject(crate::fault::FaultPoint::QueueFull) || tx.try_send(scancode).is_err() {
+                kwarn_once!("[kbd] scancode queue full; dropping keys");
             }
         }
     }
 }
 
+static LSHIFT_DOWN: AtomicBool = AtomicBool::new(false);
+static AFTER_PREFIX: AtomicBool = AtomicBool::new(false);
+
+/// Whether `byte` is the keyboard reporting its self-test, i.e. it was just
+/// plugged in. In scancode set 1, 0xAA is also left shift being released,
+/// so it only counts when left shift isn't down and no 0xE0 prefix came
+/// first (0xE0 0xAA is the fake shift some keys send). In set 2 no key
+/// sends 0xAA, but it still doesn't count after a 0xE0 or 0xF0 prefix.
+pub(crate) fn is_self_test_code(byte: u8) -> bool {
+    use crate::driver_framework::drivers::ps2::{BAT_FAILED, BAT_OK};
+    use crate::input::scancode::{keyboard_scancodes, Scancodes};
+    let set2 = keyboard_scancodes() == Scancodes::Set2;
+    let prefixed = AFTER_PREFIX.swap(byte == 0xE0 || (set2 && byte == 0xF0), AtomicOrdering::Relaxed);
+    match byte {
+        _ if prefixed => false,
+        0x2A if !set2 => {
+            LSHIFT_DOWN.store(true, AtomicOrdering::Relaxed);
+            false
+        }
+        BAT_OK if set2 => true,
+        BAT_OK => !LSHIFT_DOWN.swap(false, AtomicOrdering::Relaxed),
+        BAT_FAILED => true,
+        _ => false,
+    }
+}
+
+static TRANSLATED: AtomicBool = AtomicBool::new(true);
+
+/// Whether the controller translates the keyboard's bytes to set 1.
+pub fn controller_translates() -> bool {
+    TRANSLATED.load(AtomicOrdering::Relaxed)
+}
+
+/// Get the controller and keyboard to agree with the decoder on a
+/// scancode set, and tell the decoder which. The controller is asked to
+/// translate, as firmware normally leaves it. The keyboard is put in set 2,
+/// the one translation expects and every keyboard has, unless it answers
+/// untranslated that it is in set 1 already. Translated bytes decode as
+/// set 1, untranslated ones as what the keyboard sends.
+fn configure_scancodes() -> Scancodes {
+    use crate::driver_framework::drivers::ps2::{controller_config, keyboard_command_polled, set_controller_config, CONFIG_TRANSLATE, SCANCODE_SET};
+    let translated = match controller_config() {
+        Some(config) if config & CONFIG_TRANSLATE != 0 => true,
+        Some(config) => {
+            set_controller_config(config | CONFIG_TRANSLATE) && controller_config().is_some_and(|c| c & CONFIG_TRANSLATE != 0)
+        }
+        None => {
+            kwarn_once!("[kbd] PS/2 controller didn't report its configuration; assuming it translates");
+            true
+        }
+    };
+    TRANSLATED.store(translated, AtomicOrdering::Relaxed);
+    // the answer is translated too: sets 1 and 2 read as 0x43 and 0x41
+    let (set1, set2) = if translated { (0x43, 0x41) } else { (1, 2) };
+    match keyboard_command_polled(&[SCANCODE_SET, 0], true) {
+        // nothing answering; a keyboard plugged in later starts in set 2
+        None => {}
+        Some(set) if set == set2 => {}
+        Some(set) if set == set1 && !translated => return Scancodes::Set1,
+        Some(set) => {
+            if keyboard_command_polled(&[SCANCODE_SET, 2], false).is_none() {
+                kwarn_once!("[kbd] keyboard in scancode set {:#x} didn't switch to set 2", set);
+            }
+        }
+    }
+    if translated { Scancodes::Set1 } else { Scancodes::Set2 }
+}
+
 impl Driver for Ps2KbdDriver {
     fn probe(&self, device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> {
         let info = device.info();
@@ -65,49 +147,60 @@ impl Driver for Ps2KbdDriver {
     }
 
     fn start(&self, device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> {
+        crate::driver_framework::drivers::ps2::claim_controller_ports(device)?;
         // Initialize queues and register IRQ handler on the IDT for the vector
         self.init_queue_if_needed();
+        // polled, so before the IRQ handler is there to take the replies
+        let scancodes = configure_scancodes();
+        set_keyboard_scancodes(scancodes);
+        println!(
+            "[kbd] scancode set {}{}",
+            scancodes as u8,
+            if controller_translates() { " (set 2 translated by the controller)" } else { ", untranslated" }
+        );
         // The device resources may include an Interrupt entry with the vector
         let info = device.info();
         for r in info.resources.iter() {
             if let ResourceKind::Interrupt(vector) = r.kind {
-                // Register IRQ handler now so the kernel can receive scancodes when
+                // Request the IRQ now so the kernel can receive scancodes when
                 // the controller/port is enabled. Keep the vector in our registered
-                // list so we can unregister on stop/release.
-                crate::arch::idt::register_irq_handler(vector, Ps2KbdDriver::irq_handler);
+                // list so we can free it on stop/release.
+                crate::hal::request_irq(device, vector, Ps2KbdDriver::irq_handler)?;
                 let mut reg = self.registered_vectors.lock();
                 if !reg.contains(&vector) { reg.push(vector); }
             }
         }
         // Start with keyboard port disabled by default so callers must enable it
-        // explicitly (e.g., getline). Use PS/2 controller command 0xAD.
+        // explicitly (`enable_keyboard_port`). Use PS/2 controller command 0xAD.
         {
             use x86_64::instructions::port::Port;
             // Wait until input buffer clear then send 0xAD
-            let mut status_port: Port<u8> = Port::new(0x64);
-            // simple spin wait (small) to avoid blocking too long
-            for _ in 0..10000 { if (unsafe { status_port.read() } & 0x02) == 0 { break; } }
+            // bounded wait; send the command regardless if the controller stays busy
+            if !crate::driver_framework::drivers::ps2::wait_input_clear(PS2_TIMEOUT_MS) {
+                kwarn_once!("[kbd] controller input buffer stuck full; sending 0xAD anyway");
+            }
             let mut cmd_port: Port<u8> = Port::new(0x64);
             unsafe { cmd_port.write(0xADu8); }
-            crate::driver_framework::drivers::console::console_print_first("[kbd] PS/2 keyboard port disabled by default at start()\n");
+            print!("[kbd] PS/2 keyboard port disabled by default at start()\n");
         }
+        crate::driver_framework::drivers::ps2::start_hotplug_monitor();
         Ok(())
     }
 
-    fn stop(&self, _device: &crate::driver_framework::device::DeviceHandle) {
-        // Unregister any IRQ handlers registered by this driver. Keep the
-        // vector list so a later `release` call is idempotent.
+    fn stop(&self, device: &crate::driver_framework::device::DeviceHandle) {
+        // Free any IRQs requested by this driver. Keep the vector list so a
+        // later `release` call is idempotent.
         let reg = self.registered_vectors.lock();
         for &v in reg.iter() {
-            crate::arch::idt::unregister_irq_handler(v);
+            crate::hal::free_irq(device, v);
         }
     }
 
-    fn release(&self, _device: &crate::driver_framework::device::DeviceHandle) {
+    fn release(&self, device: &crate::driver_framework::device::DeviceHandle) {
         // Fully release resources and clear our registered vector list.
         let mut reg = self.registered_vectors.lock();
         for &v in reg.iter() {
-            crate::arch::idt::unregister_irq_handler(v);
+            crate::hal::free_irq(device, v);
         }
         reg.clear();
     }
@@ -121,126 +214,66 @@ pub fn boxed_driver() -> Box<dyn Driver> {
 pub struct ScancodeStream { _private: () }
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100)).ok();
+        scancode_channel();
         ScancodeStream { _private: () }
     }
 }
 impl Stream for ScancodeStream {
     type Item = u8;
     fn poll_next(self: Pin<&mut Self>, cx: &mut core::task::Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("scancode queue not initialized");
-        if let Some(s) = queue.pop() { return Poll::Ready(Some(s)); }
-        WAKER.register(&cx.waker());
-        match queue.pop() { Some(s) => { WAKER.take(); Poll::Ready(Some(s)) } None => Poll::Pending }
+        scancode_channel().poll_recv(cx)
     }
 }
 
-pub async fn getline() -> alloc::string::String {
-    use alloc::string::String;
-    use alloc::vec::Vec;
-
-    // Enable PS/2 keyboard port before starting the getline stream and
-    // disable it before returning. This masks the keyboard at the controller
-    // level (not the IRQ vector) so other input remains unaffected.
-    fn read_status_port() -> u8 {
-        use x86_64::instructions::port::Port;
-        let mut p: Port<u8> = Port::new(0x64);
-        unsafe { p.read() }
-    }
+static PORT_ENABLED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
 
-    fn wait_input_clear_local(max_loops: usize) -> bool {
-        for _ in 0..max_loops {
-            if (read_status_port() & 0x02) == 0 { return true; }
-        }
-        false
-    }
+/// Whether `enable_keyboard_port` has opened the port.
+pub fn keyboard_port_enabled() -> bool {
+    PORT_ENABLED.load(AtomicOrdering::Acquire)
+}
 
-    fn write_controller_cmd_local(cmd: u8) -> bool {
-        use x86_64::instructions::port::Port;
-        if !wait_input_clear_local(10000) { return false; }
-        let mut p: Port<u8> = Port::new(0x64);
-        unsafe { p.write(cmd); }
-        true
+/// Enable the keyboard port at the controller (0xAE), which `start` leaves
+/// disabled until something reads the keyboard. Only the first call talks
+/// to the controller.
+pub fn enable_keyboard_port() {
+    use x86_64::instructions::port::Port;
+    if PORT_ENABLED.swap(true, AtomicOrdering::AcqRel) {
+        return;
     }
-
-    fn enable_keyboard_port() {
-        // 0xAE = Enable first PS/2 port (keyboard)
-        if !write_controller_cmd_local(0xAE) {
-            crate::driver_framework::drivers::console::console_print_first("[kbd] Warning: failed to enable PS/2 keyboard port (0xAE)\n");
-        } else {
-            crate::driver_framework::drivers::console::console_print_first("[kbd] PS/2 keyboard port enabled\n");
-        }
-    }
-
-    fn disable_keyboard_port() {
-        // 0xAD = Disable first PS/2 port (keyboard)
-        if !write_controller_cmd_local(0xAD) {
-            crate::driver_framework::drivers::console::console_print_first("[kbd] Warning: failed to disable PS/2 keyboard port (0xAD)\n");
-        } else {
-            crate::driver_framework::drivers::console::console_print_first("[kbd] PS/2 keyboard port disabled\n");
-        }
+    if !crate::driver_framework::drivers::ps2::wait_input_clear(PS2_TIMEOUT_MS) {
+        PORT_ENABLED.store(false, AtomicOrdering::Release);
+        print!("[kbd] Warning: failed to enable PS/2 keyboard port (0xAE)\n");
+        return;
     }
+    unsafe { Port::<u8>::new(0x64).write(0xAEu8) };
+    print!("[kbd] PS/2 keyboard port enabled\n");
+}
 
-    // Enable keyboard at controller before creating the stream so the device
-    // will begin reporting scancodes. We'll disable it before returning.
-    enable_keyboard_port();
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
-
-    let mut buf: Vec<char> = Vec::new();
-
-    while let Some(sc) = scancodes.next().await {
-        if let Ok(Some(ev)) = keyboard.add_byte(sc) {
-            if let Some(key) = keyboard.process_keyevent(ev) {
-                match key {
-                    DecodedKey::Unicode(character) => {
-                        match character {
-                            '\n' | '\r' => {
-                                // echo newline and return
-                                println!("");
-                                let s: String = buf.iter().collect();
-                                // disable keyboard before returning
-                                disable_keyboard_port();
-                                return s;
-                            }
-                            '\x08' => {
-                                // backspace - remove last char if any
-                                if let Some(_) = buf.pop() {
-                                    // Move cursor back, overwrite with space, move back again
-                                    // Many VGA terminals don't interpret backspace, so emulate
-                                    print!("\x08 \x08");
-                                }
-                            }
-                            c => {
-                                buf.push(c);
-                                print!("{}", c);
-                            }
-                        }
-                    }
-                    DecodedKey::RawKey(_key) => {
-                        // ignore raw keys for line input
-                    }
-                }
-            }
+/// Read one line from the console, without its newline. Keystrokes are
+/// decoded, echoed and edited by the tty line discipline, which
+/// `print_keypresses` feeds, so the shell and processes share one input.
+pub async fn getline() -> alloc::string::String {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        crate::devices::tty::tty_read(&mut byte).await;
+        if byte[0] == b'\n' {
+            return alloc::string::String::from_utf8_lossy(&line).into_owned();
         }
+        line.push(byte[0]);
     }
+}
 
-    // If the stream ended, disable keyboard and return whatever we have
-    disable_keyboard_port();
-    buf.iter().collect()
+/// Take a queued scancode without waiting.
+pub fn try_next_scancode() -> Option<u8> {
+    scancode_channel().try_recv().ok()
 }
 
+/// Feed keystrokes to the console line discipline, which echoes them and
+/// collects lines for readers of stdin.
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(k) => print!("{:?}", k),
-                }
-            }
-        }
+        crate::devices::tty::tty_feed_scancode(scancode);
     }
 }
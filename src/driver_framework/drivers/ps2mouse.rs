@@ -1,11 +1,11 @@
-use crate::*;
+use crate::prelude::*;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use spin::Mutex;
+use crate::sync::IrqSpinlock;
+use crate::sync::channel::{channel, Receiver, Sender};
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
 use core::pin::Pin;
 use core::task::Poll;
 use futures_util::stream::Stream;
@@ -16,13 +16,16 @@ use crate::driver_framework::driver::Driver;
 use crate::driver_framework::device::{DeviceInfo, Resource, ResourceKind};
 // (No global debug counters)
 
+/// How long to wait for the controller or the mouse to respond.
+const PS2_TIMEOUT_MS: u64 = 20;
+
 /// Simple PS/2 mouse driver that registers an IRQ handler and tracks a small
 /// software cursor drawn into the VBE framebuffer.
 pub struct Ps2MouseDriver {
     registered_vectors: Mutex<Vec<u8>>,
-    // packet state: collect 3-byte PS/2 packets
-    pkt_state: AtomicU8, // 0..=2 current index
-    pkt_buf: Mutex<[u8;3]>,
+    // packet state: collect 3-byte PS/2 packets, 4 with a wheel
+    pkt_state: AtomicU8, // 0..=3 current index
+    pkt_buf: IrqSpinlock<[u8;4]>,
     // current cursor position (in pixels)
     cursor_x: Mutex<i32>,
     cursor_y: Mutex<i32>,
@@ -36,7 +39,7 @@ impl Ps2MouseDriver {
         Ps2MouseDriver {
             registered_vectors: Mutex::new(Vec::new()),
             pkt_state: AtomicU8::new(0),
-            pkt_buf: Mutex::new([0u8;3]),
+            pkt_buf: IrqSpinlock::new([0u8;4]),
             cursor_x: Mutex::new(40),
             cursor_y: Mutex::new(40),
             // no targets
@@ -44,10 +47,15 @@ impl Ps2MouseDriver {
         }
     }
 
-    extern "x86-interrupt" fn irq_handler(_stack_frame: InterruptStackFrame) {
+    /// Runs from the IDT trampoline, which sends the EOI for us.
+    fn irq_handler(_vector: u8) {
+        use crate::driver_framework::drivers::ps2::{self, Ps2Port};
         use x86_64::instructions::port::Port;
         let mut port = Port::new(0x60);
         let b: u8 = unsafe { port.read() };
+        if ps2::port_byte(Ps2Port::Mouse, b) {
+            return;
+        }
 
         // SAFETY: this is a static handler; use the typed global instance
         if let Some(drv) = crate::driver_framework::drivers::ps2mouse::get_global_instance_typed() {
@@ -66,24 +74,36 @@ impl Ps2MouseDriver {
                 }
                 1 => {
                     let mut buf = drv.pkt_buf.lock();
+                    if buf[0] == ps2::BAT_OK && b == 0x00 {
+                        // self-test passed and device ID 0: a mouse was just plugged in
+                        drv.pkt_state.store(0, Ordering::SeqCst);
+                        ps2::device_reset(Ps2Port::Mouse);
+                        return;
+                    }
                     buf[1] = b;
                     drv.pkt_state.store(2, Ordering::SeqCst);
                 }
-                2 => {
+                2 if WHEEL.load(Ordering::Relaxed) => {
+                    drv.pkt_buf.lock()[2] = b;
+                    drv.pkt_state.store(3, Ordering::SeqCst);
+                }
+                2 | 3 => {
                     let mut buf = drv.pkt_buf.lock();
-                    buf[2] = b;
+                    buf[state as usize] = b;
                     // Full packet ready: extract and push to queue
                     let buttons = buf[0];
                     let dx = buf[1] as i8;
                     let dy = buf[2] as i8;
+                    // the wheel is the low nibble of the 4th byte, signed
+                    let wheel = if state == 3 { ((buf[3] as i8) << 4) >> 4 } else { 0 };
                     // reset state
                     drv.pkt_state.store(0, Ordering::SeqCst);
 
                     // Push packet into cross-thread queue for non-IRQ processing
-                    if let Ok(q) = MOUSE_QUEUE.try_get() {
-                        let _ = q.push(MousePacket { buttons, dx, dy });
-                        // Also wake any waiters
-                        MOUSE_WAKER.wake();
+                    if let Ok(tx) = MOUSE_TX.try_get() {
+                        if crate::fault::inject(crate::fault::FaultPoint::QueueFull) || tx.try_send(MousePacket { buttons, dx, dy, wheel }).is_err() {
+                            kwarn_once!("[mouse] packet queue full; dropping movement");
+                        }
                     }
                 }
                 _ => {
@@ -92,15 +112,6 @@ impl Ps2MouseDriver {
             }
             // Avoid heavy work (drawing/alloc) in IRQ context
         }
-
-        unsafe {
-            if crate::hal::apic::is_initialized() {
-                crate::hal::apic::send_eoi();
-            } else {
-                let vec = GLOBAL_PS2MOUSE_VECTOR.load(Ordering::SeqCst);
-                crate::arch::interrupts::PICS.lock().notify_end_of_interrupt(vec);
-            }
-        }
     }
 
     fn redraw_cursor(&self) {
@@ -148,40 +159,15 @@ impl Ps2MouseDriver {
                 if let Some((px, py, ref vec)) = *saved {
                     // If previously saved area differs from new area, restore and clear saved
                     if px != bx || py != by {
-                        // restore pixel-wise
-                        let mut i = 0usize;
-                        for ry in 0..H {
-                            for rx in 0..W {
-                                vbe_vga::draw_pixel_at(fb, px + rx, py + ry, vec[i]);
-                                i += 1;
-                            }
-                        }
+                        // as read, so brightness isn't applied twice
+                        vbe_vga::write_rect(fb, px, py, W, H, vec);
                         *saved = None;
                     }
                 }
             }
 
-            // Capture current background under new cursor position into a vector
-            let mut bg: alloc::vec::Vec<u32> = alloc::vec::Vec::with_capacity(W * H);
-            bg.resize(W * H, 0u32);
-            // Read pixels by reading memory from framebuffer mapping directly
-            // Unfortunately vbe_vga doesn't expose a read API; emulate by reading via volatile pointer
-            if let Some(info) = vbe_vga::get_fb_info() {
-                let pitch = info.pitch;
-                unsafe {
-                    let base = fb as *mut u8;
-                    let mut idx = 0usize;
-                    for ry in 0..H {
-                        let row = base.add((by + ry) * pitch);
-                        for rx in 0..W {
-                            let p = row.add((bx + rx) * 4) as *mut u32;
-                            let val = core::ptr::read_volatile(p);
-                            bg[idx] = val;
-                            idx += 1;
-                        }
-                    }
-                }
-            }
+            // Capture current background under new cursor position
+            let bg = vbe_vga::read_rect(fb, bx, by, W, H).unwrap_or_else(|| alloc::vec![0u32; W * H]);
 
             // Save captured background
             *self.saved_bg.lock() = Some((bx, by, bg));
@@ -211,32 +197,24 @@ impl Ps2MouseDriver {
     fn read_data(&self) -> u8 {
         use x86_64::instructions::port::Port;
         let mut p: Port<u8> = Port::new(0x60);
-        unsafe { p.read() }
+        crate::fault::ps2_byte(unsafe { p.read() })
     }
 
     // Wait until input buffer clear (controller ready to accept command/data)
     // Returns true on success, false on timeout
-    fn wait_input_clear(&self, max_loops: usize) -> bool {
-        for _ in 0..max_loops {
-            if (self.read_status() & 0x02) == 0 { return true; }
-        }
-        false
+    fn wait_input_clear(&self, timeout_ms: u64) -> bool {
+        crate::driver_framework::drivers::ps2::poll_ms(timeout_ms, || ((self.read_status() & 0x02) == 0).then_some(())).is_some()
     }
 
     // Wait for output buffer to have data and return it (with timeout)
-    fn wait_for_data(&self, max_loops: usize) -> Option<u8> {
-        for _ in 0..max_loops {
-            if (self.read_status() & 0x01) != 0 {
-                return Some(self.read_data());
-            }
-        }
-        None
+    fn wait_for_data(&self, timeout_ms: u64) -> Option<u8> {
+        crate::driver_framework::drivers::ps2::poll_ms(timeout_ms, || ((self.read_status() & 0x01) != 0).then(|| self.read_data()))
     }
 
     // Send a byte to the controller (0x64) as a command. Wait for input buffer clear first.
     fn write_controller_cmd(&self, cmd: u8) -> bool {
         use x86_64::instructions::port::Port;
-        if !self.wait_input_clear(10000) { return false; }
+        if !self.wait_input_clear(PS2_TIMEOUT_MS) { return false; }
         let mut p: Port<u8> = Port::new(0x64);
         unsafe { p.write(cmd); }
         true
@@ -245,7 +223,7 @@ impl Ps2MouseDriver {
     // Write a byte to the controller data port (0x60). Wait for input clear first.
     fn write_controller_data(&self, data: u8) -> bool {
         use x86_64::instructions::port::Port;
-        if !self.wait_input_clear(10000) { return false; }
+        if !self.wait_input_clear(PS2_TIMEOUT_MS) { return false; }
         let mut p: Port<u8> = Port::new(0x60);
         unsafe { p.write(data); }
         true
@@ -256,7 +234,7 @@ impl Ps2MouseDriver {
         // Issue command 0x20 to read command byte
         if !self.write_controller_cmd(0x20) { return None; }
         // Wait for data in output buffer
-        self.wait_for_data(10000)
+        self.wait_for_data(PS2_TIMEOUT_MS)
     }
 
     // Send a mouse-targeted byte: tell controller 0xD4 then write data to 0x60.
@@ -265,7 +243,7 @@ impl Ps2MouseDriver {
         // Send 0xD4 command to controller to forward next byte to mouse
         if !self.write_controller_cmd(0xD4) { return false; }
         // Wait input clear then write data
-        if !self.wait_input_clear(10000) { return false; }
+        if !self.wait_input_clear(PS2_TIMEOUT_MS) { return false; }
         let mut p: Port<u8> = Port::new(0x60);
         unsafe { p.write(data); }
         true
@@ -281,7 +259,7 @@ impl Ps2MouseDriver {
             }
 
             // Wait for ACK/response
-            if let Some(resp) = self.wait_for_data(10000) {
+            if let Some(resp) = self.wait_for_data(PS2_TIMEOUT_MS) {
                 if resp == 0xFA { return true; } // ACK
                 if resp == 0xFE {
                     // Resend requested by device, retry
@@ -296,26 +274,56 @@ impl Ps2MouseDriver {
     }
 }
 
+impl Ps2MouseDriver {
+    /// Send the wheel knock and ask for the device ID; ID 3 means the
+    /// mouse now has its wheel on and sends 4-byte packets.
+    fn enable_wheel(&self) {
+        use crate::driver_framework::drivers::ps2::{WHEEL_KNOCK, WHEEL_MOUSE_ID};
+        let knocked = WHEEL_KNOCK.iter().all(|&rate| self.send_mouse_cmd_with_ack(0xF3, 4) && self.send_mouse_cmd_with_ack(rate, 4));
+        let id = if knocked && self.send_mouse_cmd_with_ack(0xF2, 4) { self.wait_for_data(PS2_TIMEOUT_MS) } else { None };
+        set_wheel(id == Some(WHEEL_MOUSE_ID));
+        if wheel_enabled() {
+            print!("[mouse] wheel mouse (ID {})\n", WHEEL_MOUSE_ID);
+        }
+    }
+}
+
+/// Set when the mouse sends 4-byte packets, the last one carrying the wheel.
+static WHEEL: AtomicBool = AtomicBool::new(false);
+
+pub fn wheel_enabled() -> bool {
+    WHEEL.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_wheel(on: bool) {
+    WHEEL.store(on, Ordering::Relaxed);
+}
+
 // (No debug logging in this driver build)
 
 // --- IRQ-safe queue and async stream for mouse packets ---
 #[derive(Clone, Copy, Debug)]
-pub struct MousePacket { buttons: u8, dx: i8, dy: i8 }
+pub struct MousePacket { buttons: u8, dx: i8, dy: i8, wheel: i8 }
 
-static MOUSE_QUEUE: OnceCell<ArrayQueue<MousePacket>> = OnceCell::uninit();
-static MOUSE_WAKER: AtomicWaker = AtomicWaker::new();
+static MOUSE_TX: OnceCell<Sender<MousePacket>> = OnceCell::uninit();
+static MOUSE_RX: OnceCell<Receiver<MousePacket>> = OnceCell::uninit();
+
+fn mouse_channel() -> &'static Receiver<MousePacket> {
+    MOUSE_RX.get_or_init(|| {
+        let (tx, rx) = channel(256);
+        MOUSE_TX.init_once(|| tx);
+        rx
+    })
+}
 
 pub struct MousePacketStream { _private: () }
 impl MousePacketStream {
-    pub fn new() -> Self { MOUSE_QUEUE.try_init_once(|| ArrayQueue::new(256)).ok(); MousePacketStream { _private: () } }
+    pub fn new() -> Self { mouse_channel(); MousePacketStream { _private: () } }
 }
 impl Stream for MousePacketStream {
     type Item = MousePacket;
     fn poll_next(self: Pin<&mut Self>, cx: &mut core::task::Context) -> Poll<Option<MousePacket>> {
-        let q = MOUSE_QUEUE.try_get().expect("mouse queue not initialized");
-        if let Some(pkt) = q.pop() { return Poll::Ready(Some(pkt)); }
-        MOUSE_WAKER.register(&cx.waker());
-        match q.pop() { Some(p) => { MOUSE_WAKER.take(); Poll::Ready(Some(p)) } None => Poll::Pending }
+        mouse_channel().poll_recv(cx)
     }
 }
 
@@ -323,11 +331,6 @@ impl Stream for MousePacketStream {
 pub async fn mouse_event_loop() {
     let mut stream = MousePacketStream::new();
     let mut count: usize = 0;
-    // Movement tuning parameters: adjust sensitivity, maximum per-packet delta
-    const MOUSE_SENS_NUM: i32 = 1; // numerator for sensitivity multiplier
-    const MOUSE_SENS_DEN: i32 = 1; // denominator for sensitivity multiplier
-    const MOUSE_MAX_DELTA: i32 = 16; // clamp per-packet delta to this range
-    const MOUSE_INVERT_Y: bool = false; // if true, invert vertical axis
 
     while let Some(pkt) = stream.next().await {
         // Diagnostic: print every packet (throttled by count to avoid spam)
@@ -336,25 +339,33 @@ pub async fn mouse_event_loop() {
 
         // Move cursor and perform lightweight redraw on every packet.
         if let Some(drv) = crate::driver_framework::drivers::ps2mouse::get_global_instance_typed() {
-            // Normalize and clamp packet deltas, apply sensitivity and optional inversion.
-            let mut dx = pkt.dx as i32;
-            let mut dy = pkt.dy as i32;
-            if dx > MOUSE_MAX_DELTA { dx = MOUSE_MAX_DELTA } else if dx < -MOUSE_MAX_DELTA { dx = -MOUSE_MAX_DELTA }
-            if dy > MOUSE_MAX_DELTA { dy = MOUSE_MAX_DELTA } else if dy < -MOUSE_MAX_DELTA { dy = -MOUSE_MAX_DELTA }
-            // Apply sensitivity scaling
-            dx = dx * MOUSE_SENS_NUM / MOUSE_SENS_DEN;
-            dy = dy * MOUSE_SENS_NUM / MOUSE_SENS_DEN;
-            // Convert device Y (positive = up) to screen Y (positive = down) by negating
-            let screen_dy = if MOUSE_INVERT_Y { dy } else { -dy };
-            // Apply movement immediately to displayed cursor
-            {
+            // Device Y is positive up; the input layer wants screen Y (positive
+            // down) and applies the clamp, acceleration, sensitivity and inversion
+            let (dx, screen_dy) = crate::input::scale_mouse_motion(pkt.dx as i32, -(pkt.dy as i32));
+            // Apply movement immediately to displayed cursor, kept on screen
+            let (x, y) = {
                 let mut x = drv.cursor_x.lock();
                 let mut y = drv.cursor_y.lock();
                 *x = (*x).saturating_add(dx);
                 *y = (*y).saturating_add(screen_dy);
-            }
+                if let Some(info) = crate::driver_framework::drivers::vbe_vga::get_fb_info() {
+                    *x = (*x).clamp(0, info.width as i32 - 1);
+                    *y = (*y).clamp(0, info.height as i32 - 1);
+                }
+                (*x, *y)
+            };
             // Redraw cursor at new position
-            drv.redraw_cursor();
+            if dx != 0 || screen_dy != 0 {
+                drv.redraw_cursor();
+            }
+            crate::input::report_mouse(&crate::input::MouseReport {
+                buttons: pkt.buttons & 0x07,
+                x,
+                y,
+                dx,
+                dy: screen_dy,
+                wheel: pkt.wheel,
+            });
             // Movement applied immediately; the outer stream await will park when the queue is empty.
         }
     }
@@ -369,10 +380,11 @@ impl Driver for Ps2MouseDriver {
     }
 
     fn start(&self, device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> {
+        crate::driver_framework::drivers::ps2::claim_controller_ports(device)?;
         let info = device.info();
         for r in info.resources.iter() {
             if let ResourceKind::Interrupt(vector) = r.kind {
-                crate::arch::idt::register_irq_handler(vector, Ps2MouseDriver::irq_handler);
+                crate::hal::request_irq(device, vector, Ps2MouseDriver::irq_handler)?;
                 let mut reg = self.registered_vectors.lock();
                 if !reg.contains(&vector) { reg.push(vector); }
                 // store vector for PIC EOI fallback
@@ -386,49 +398,84 @@ impl Driver for Ps2MouseDriver {
         // This approach polls the controller input/output buffer bits (0x64 status port).
 
         // Enable auxiliary device (second PS/2 port)
-        let _ = self.write_controller_cmd(0xA8);
+        if !self.write_controller_cmd(0xA8) {
+            kwarn_once!("[mouse] controller did not take 0xA8 (enable aux port)");
+        }
 
-        // Flush any pending output bytes before starting
-        while (self.read_status() & 0x01) != 0 {
+        // Flush any pending output bytes before starting; a controller that
+        // keeps reporting data gets PS2_TIMEOUT_MS, not forever
+        let _ = crate::driver_framework::drivers::ps2::poll_ms(PS2_TIMEOUT_MS, || {
+            if (self.read_status() & 0x01) == 0 { return Some(()); }
             let _ = self.read_data();
-        }
+            None
+        });
 
         // Ensure the controller command byte enables mouse IRQs (bit 1).
         if let Some(cfg) = self.read_controller_config() {
             let want = cfg | 0x02u8; // set bit1 = enable aux/mouse IRQ
             if want != cfg {
                 // Write back via command 0x60 then data port
-                if self.write_controller_cmd(0x60) {
-                    let _ = self.write_controller_data(want);
-                } else {
-                    // ignore write failure
+                if !(self.write_controller_cmd(0x60) && self.write_controller_data(want)) {
+                    kwarn_once!("[mouse] could not write controller config {:#04x}; no mouse IRQs", want);
                 }
             }
         } else {
-            // ignore read failure
+            kwarn_once!("[mouse] could not read controller config; mouse IRQs may stay off");
+        }
+
+        // Knock for an IntelliMouse wheel, then put the sample rate back
+        self.enable_wheel();
+        let rate = crate::driver_framework::drivers::ps2::mouse_sample_rate();
+        if !(self.send_mouse_cmd_with_ack(0xF3, 4) && self.send_mouse_cmd_with_ack(rate, 4)) {
+            kwarn_once!("[mouse] could not set sample rate {}", rate);
         }
 
         // Try sending Enable Data Reporting (0xF4) with ACK polling
-        let success = self.send_mouse_cmd_with_ack(0xF4u8, 4);
-        let _ = success;
+        if !self.send_mouse_cmd_with_ack(0xF4u8, 4) {
+            kwarn_once!("[mouse] no ACK for 0xF4 (enable data reporting)");
+        }
+
+        // Process packets outside interrupt context on the kernel executor
+        mouse_channel();
+        crate::arch::task::spawn(mouse_event_loop());
+        crate::driver_framework::drivers::ps2::start_hotplug_monitor();
 
-        // Diagnostic: print that start completed and which vector we registered (if any)
-        let vec = GLOBAL_PS2MOUSE_VECTOR.load(Ordering::SeqCst);
-    let _ = vec;
         Ok(())
     }
 
-    fn stop(&self, _device: &crate::driver_framework::device::DeviceHandle) {
+    fn stop(&self, device: &crate::driver_framework::device::DeviceHandle) {
         let reg = self.registered_vectors.lock();
-        for &v in reg.iter() { crate::arch::idt::unregister_irq_handler(v); }
+        for &v in reg.iter() { crate::hal::free_irq(device, v); }
     }
 
-    fn release(&self, _device: &crate::driver_framework::device::DeviceHandle) {
+    fn release(&self, device: &crate::driver_framework::device::DeviceHandle) {
         let mut reg = self.registered_vectors.lock();
-        for &v in reg.iter() { crate::arch::idt::unregister_irq_handler(v); }
+        for &v in reg.iter() { crate::hal::free_irq(device, v); }
         reg.clear();
         crate::driver_framework::drivers::ps2mouse::set_global_instance(core::ptr::null_mut());
     }
+
+    fn control(&self, _device: &crate::driver_framework::device::DeviceHandle, key: &str, value: &str) -> Result<(), &'static str> {
+        if key == "rate" {
+            let rate = value.parse::<u8>().map_err(|_| "want a number")?;
+            return if crate::driver_framework::drivers::ps2::set_mouse_sample_rate(rate) {
+                Ok(())
+            } else {
+                Err("not a PS/2 sample rate")
+            };
+        }
+        crate::input::set_mouse_setting(key, value)
+    }
+
+    fn control_values(&self, _device: &crate::driver_framework::device::DeviceHandle) -> Vec<(&'static str, alloc::string::String)> {
+        let mut values = alloc::vec![("rate", alloc::format!("{}", crate::driver_framework::drivers::ps2::mouse_sample_rate()))];
+        for name in crate::input::MOUSE_SETTINGS {
+            if let Some(value) = crate::input::mouse_setting(name) {
+                values.push((name, value));
+            }
+        }
+        values
+    }
 }
 
 pub fn boxed_driver() -> Box<dyn Driver> { Box::new(Ps2MouseDriver::new()) }
@@ -445,6 +492,13 @@ pub fn get_global_instance_typed() -> Option<&'static Ps2MouseDriver> {
     }
 }
 
+/// Drop any half-read packet, so the next byte is taken as a header.
+pub fn reset_packet_state() {
+    if let Some(drv) = get_global_instance_typed() {
+        drv.pkt_state.store(0, Ordering::SeqCst);
+    }
+}
+
 /// Public helper to set cursor position from outside (e.g., main.rs)
 pub fn set_cursor_pos(x: i32, y: i32) {
     unsafe {
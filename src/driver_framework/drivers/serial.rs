@@ -0,0 +1,49 @@
+//! COM1 serial port driver
+//!
+//! The UART is programmed by `devices::serial` on first use, before any
+//! driver exists; the driver is there so its rate can be set per device,
+//! as the `baud` parameter (`serial.baud=38400` on the command line) or
+//! with `devctl`.
+
+use crate::prelude::*;
+use crate::devices::serial::{serial_baud, serial_set_baud};
+use crate::driver_framework::device::{DeviceHandle, ResourceKind};
+use crate::driver_framework::driver::Driver;
+
+pub struct SerialDriver {}
+
+impl Driver for SerialDriver {
+    fn probe(&self, device: &DeviceHandle) -> Result<(), &'static str> {
+        // class 0x07 subclass 0x00: serial controller
+        let info = device.info();
+        if info.class == 0x07 && info.subclass == 0x00 { Ok(()) } else { Err("not a serial controller") }
+    }
+
+    fn start(&self, device: &DeviceHandle) -> Result<(), &'static str> {
+        for r in device.info().resources.iter().filter(|r| r.kind == ResourceKind::IO) {
+            crate::hal::ioport::request_ports(device, "16550", r.addr as u16, r.len as u16)?;
+        }
+        Ok(())
+    }
+    fn stop(&self, _device: &DeviceHandle) {}
+    fn release(&self, _device: &DeviceHandle) {}
+
+    fn control(&self, _device: &DeviceHandle, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "baud" => {
+                let baud = value.parse::<u32>().map_err(|_| "want a number")?;
+                if !serial_set_baud(baud) {
+                    return Err("not a divisor of 115200");
+                }
+            }
+            _ => return Err("unknown serial setting"),
+        }
+        Ok(())
+    }
+
+    fn control_values(&self, _device: &DeviceHandle) -> Vec<(&'static str, String)> {
+        vec![("baud", format!("{}", serial_baud()))]
+    }
+}
+
+pub fn boxed_driver() -> Box<dyn Driver> { Box::new(SerialDriver {}) }
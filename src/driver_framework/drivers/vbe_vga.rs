@@ -1,20 +1,11 @@
-use crate::*;
+use crate::prelude::*;
 use alloc::boxed::Box;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 use crate::driver_framework::driver::Driver;
-use crate::driver_framework::device::ResourceKind;
-use x86_64::VirtAddr;
-use x86_64::structures::paging::{OffsetPageTable, Page, PhysFrame, Size4KiB};
-use x86_64::PhysAddr;
-use crate::memory::frame::BootInfoFrameAllocator;
-use x86_64::structures::paging::PageTableFlags as Flags;
-use x86_64::structures::paging::Mapper;
-// Mapper trait not needed directly here
-
-/// VBE/linear framebuffer driver that maps BARs using the kernel mapper.
-struct FbMapping { virt_base: u64, phys_map_start: u64, bar_phys: u64, pages: usize }
+use crate::hal::bar::BarMapping;
+
 
 #[derive(Clone, Copy, Debug)]
 pub struct FramebufferInfo {
@@ -26,6 +17,124 @@ pub struct FramebufferInfo {
 
 // (Console state moved into the console driver)
 
+/// The active framebuffer as plain values, for code that can't take locks.
+#[derive(Clone, Copy, Debug)]
+pub struct RawFramebuffer {
+    /// Virtual address of the first pixel.
+    pub base: u64,
+    pub info: FramebufferInfo,
+}
+
+impl RawFramebuffer {
+    /// The pixel at (`x`, `y`), or None outside the mode. Assumes ARGB32.
+    pub fn read_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x >= self.info.width as usize || y >= self.info.height as usize { return None; }
+        Some(unsafe { ptr::read_volatile((self.base as *const u8).add(y * self.info.pitch + x * 4) as *const u32) })
+    }
+
+    /// The `w` x `h` pixels at (`x`, `y`), row by row. Pixels outside the
+    /// mode read as 0. Assumes ARGB32.
+    pub fn read_rect(&self, x: usize, y: usize, w: usize, h: usize) -> alloc::vec::Vec<u32> {
+        let mut out = alloc::vec![0u32; w * h];
+        let cols = w.min((self.info.width as usize).saturating_sub(x));
+        let rows = h.min((self.info.height as usize).saturating_sub(y));
+        if cols == 0 { return out; }
+        for r in 0..rows {
+            unsafe {
+                let row = (self.base as *const u8).add((y + r) * self.info.pitch + x * 4) as *const u32;
+                ptr::copy_nonoverlapping(row, out[r * w..].as_mut_ptr(), cols);
+            }
+        }
+        out
+    }
+
+    /// Write back pixels as `read_rect` returned them, unadjusted; the
+    /// inverse of `read_rect`. Pixels outside the mode are skipped.
+    pub fn write_rect(&self, x: usize, y: usize, w: usize, h: usize, pixels: &[u32]) {
+        let cols = w.min((self.info.width as usize).saturating_sub(x));
+        let rows = h.min((self.info.height as usize).saturating_sub(y)).min(pixels.len() / w.max(1));
+        if cols == 0 { return; }
+        for r in 0..rows {
+            unsafe {
+                let row = (self.base as *mut u8).add((y + r) * self.info.pitch + x * 4) as *mut u32;
+                ptr::copy_nonoverlapping(pixels[r * w..].as_ptr(), row, cols);
+            }
+        }
+        flush_fb_rect(self.base, self.info.pitch, x, y, cols, rows);
+    }
+
+    /// Pass the red, green and blue of every pixel through `table`, leaving
+    /// alpha alone. Assumes ARGB32.
+    pub fn map_channels(&self, table: &[u8; 256]) {
+        let (w, h) = (self.info.width as usize, self.info.height as usize);
+        for y in 0..h {
+            let row = unsafe { (self.base as *mut u8).add(y * self.info.pitch) as *mut u32 };
+            for x in 0..w {
+                unsafe {
+                    let p = row.add(x);
+                    ptr::write_volatile(p, map_color(ptr::read_volatile(p), |c| table[c as usize]));
+                }
+            }
+        }
+        flush_fb_rect(self.base, self.info.pitch, 0, 0, w, h);
+    }
+}
+
+/// `color` with `f` applied to its red, green and blue bytes.
+fn map_color(color: u32, f: impl Fn(u8) -> u8) -> u32 {
+    let channel = |shift: u32| (f((color >> shift) as u8) as u32) << shift;
+    color & 0xFF00_0000 | channel(16) | channel(8) | channel(0)
+}
+
+// Published by start once the mode is known, withdrawn by stop before the
+// pages go. A zero base means no framebuffer; it is written last.
+static RAW_FB_BASE: AtomicU64 = AtomicU64::new(0);
+static RAW_FB_WIDTH: AtomicU32 = AtomicU32::new(0);
+static RAW_FB_HEIGHT: AtomicU32 = AtomicU32::new(0);
+static RAW_FB_BPP: AtomicU32 = AtomicU32::new(0);
+static RAW_FB_PITCH: AtomicUsize = AtomicUsize::new(0);
+
+/// The first framebuffer, read without locking or allocating, so a panic
+/// can draw on it whatever the driver and console were doing.
+pub fn raw_framebuffer() -> Option<RawFramebuffer> {
+    let base = RAW_FB_BASE.load(Ordering::Acquire);
+    if base == 0 { return None; }
+    Some(RawFramebuffer {
+        base,
+        info: FramebufferInfo {
+            width: RAW_FB_WIDTH.load(Ordering::Relaxed),
+            height: RAW_FB_HEIGHT.load(Ordering::Relaxed),
+            bpp: RAW_FB_BPP.load(Ordering::Relaxed),
+            pitch: RAW_FB_PITCH.load(Ordering::Relaxed),
+        },
+    })
+}
+
+fn publish_raw_framebuffer(base: u64, info: FramebufferInfo) {
+    RAW_FB_WIDTH.store(info.width, Ordering::Relaxed);
+    RAW_FB_HEIGHT.store(info.height, Ordering::Relaxed);
+    RAW_FB_BPP.store(info.bpp, Ordering::Relaxed);
+    RAW_FB_PITCH.store(info.pitch, Ordering::Relaxed);
+    RAW_FB_BASE.store(base, Ordering::Release);
+}
+
+/// The 8x8 glyph for an ASCII character 0x20..0x7F; rows top first, MSB leftmost.
+pub fn font_glyph(ch: u8) -> Option<&'static [u8; 8]> {
+    VGA8X8::get_glyph(ch)
+}
+
+/// The rows `draw_char_at` draws for `ch`, MSB leftmost: its font glyph,
+/// or the made-up pattern characters outside the font get.
+fn glyph_rows(ch: u8) -> [u8; 8] {
+    if let Some(glyph) = VGA8X8::get_glyph(ch) {
+        return *glyph;
+    }
+    core::array::from_fn(|r| {
+        let pattern = (ch.wrapping_add(r as u8) ^ (ch >> (r % 8))).rotate_left((r as u32) & 7);
+        pattern.reverse_bits()
+    })
+}
+
 // --- Embedded VGA 8x8 font ---
 struct VGA8X8;
 impl VGA8X8 {
@@ -139,34 +248,177 @@ static FONT8X8: [[u8;8]; 95] = [
     [0x76,0xdc,0x00,0x00,0x00,0x00,0x00,0x00], // ~
 ];
 
+// Bochs/QEMU DISPI interface: index, then data
+const DISPI_INDEX_PORT: u16 = 0x01CE;
+const DISPI_DATA_PORT: u16 = 0x01CF;
+// VGA sequencer: index, then data
+const SEQ_INDEX_PORT: u16 = 0x03C4;
+const SEQ_DATA_PORT: u16 = 0x03C5;
+const SEQ_CLOCKING_MODE: u8 = 0x01;
+const SEQ_SCREEN_OFF: u8 = 1 << 5;
+
+static SCREEN_BLANKED: AtomicBool = AtomicBool::new(false);
+
+/// Turn the display off or back on with the VGA sequencer's screen-off bit.
+/// The framebuffer keeps its contents and can still be drawn on; it just
+/// isn't scanned out, which also spares an emulator redrawing it. Works in
+/// text mode too.
+pub fn set_screen_blanked(blank: bool) {
+    unsafe {
+        crate::arch::ports::outb(SEQ_INDEX_PORT, SEQ_CLOCKING_MODE);
+        let mode = crate::arch::ports::inb(SEQ_DATA_PORT);
+        let mode = if blank { mode | SEQ_SCREEN_OFF } else { mode & !SEQ_SCREEN_OFF };
+        crate::arch::ports::outb(SEQ_DATA_PORT, mode);
+    }
+    SCREEN_BLANKED.store(blank, Ordering::Relaxed);
+}
+
+pub fn screen_blanked() -> bool {
+    SCREEN_BLANKED.load(Ordering::Relaxed)
+}
+
+// --- Brightness and gamma ---
+//
+// There is no hardware gamma ramp in a direct-color mode, so the drawing
+// primitives pass each color through a lookup table on its way to the
+// framebuffer. Changing the levels converts what is on screen already:
+// back through the old table, then through the new one. Where dimming
+// merged shades, converting back can't split them again.
+
+/// Brightness in percent of full.
+pub const BRIGHTNESS_MIN: u32 = 10;
+pub const BRIGHTNESS_MAX: u32 = 100;
+/// Gamma in hundredths; above 100 brightens the mid-tones.
+pub const GAMMA_MIN: u32 = 30;
+pub const GAMMA_MAX: u32 = 300;
+
+static BRIGHTNESS: AtomicU32 = AtomicU32::new(BRIGHTNESS_MAX);
+static GAMMA: AtomicU32 = AtomicU32::new(100);
+/// What each channel value is drawn as. Only used while `LEVELS_ACTIVE`.
+static LEVELS: [AtomicU8; 256] = [const { AtomicU8::new(0) }; 256];
+static LEVELS_ACTIVE: AtomicBool = AtomicBool::new(false);
+static LEVELS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Current brightness (percent) and gamma (hundredths).
+pub fn display_levels() -> (u32, u32) {
+    (BRIGHTNESS.load(Ordering::Relaxed), GAMMA.load(Ordering::Relaxed))
+}
+
+/// `color` as drawn at the current brightness and gamma.
+pub fn adjust_color(color: u32) -> u32 {
+    if !LEVELS_ACTIVE.load(Ordering::Acquire) {
+        return color;
+    }
+    map_color(color, |c| LEVELS[c as usize].load(Ordering::Relaxed))
+}
+
+/// Parse a gamma such as `1.8` or `0.45` into hundredths.
+pub fn parse_gamma(s: &str) -> Option<u32> {
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 2 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac: u32 = if frac.is_empty() { 0 } else { frac.parse::<u32>().ok()? * if frac.len() == 1 { 10 } else { 1 } };
+    whole.parse::<u32>().ok()?.checked_mul(100)?.checked_add(frac)
+}
+
+/// Draw at `brightness` percent of full with `gamma` (in hundredths) and
+/// convert the screen to match. 100 and 100 draw colors unchanged.
+pub fn set_display_levels(brightness: u32, gamma: u32) -> Result<(), &'static str> {
+    if !(BRIGHTNESS_MIN..=BRIGHTNESS_MAX).contains(&brightness) {
+        return Err("brightness out of range (10-100%)");
+    }
+    if !(GAMMA_MIN..=GAMMA_MAX).contains(&gamma) {
+        return Err("gamma out of range (0.30-3.00)");
+    }
+    let _g = LEVELS_LOCK.lock();
+    let old: [u8; 256] = core::array::from_fn(|c| if LEVELS_ACTIVE.load(Ordering::Relaxed) { LEVELS[c].load(Ordering::Relaxed) } else { c as u8 });
+    let new = level_table(brightness, gamma);
+    // on-screen value -> the value it was drawn from -> how that draws now
+    let mut source = [0u8; 256];
+    let mut seen = [false; 256];
+    for c in 0..256 {
+        if !seen[old[c] as usize] {
+            source[old[c] as usize] = c as u8;
+            seen[old[c] as usize] = true;
+        }
+    }
+    for v in 1..256 {
+        if !seen[v] {
+            source[v] = source[v - 1];
+        }
+    }
+    let convert: [u8; 256] = core::array::from_fn(|v| new[source[v] as usize]);
+    // console output drawn halfway would come out in a mix of the two
+    crate::driver_framework::drivers::console::console_without_cursor(|| {
+        for (slot, &v) in LEVELS.iter().zip(new.iter()) {
+            slot.store(v, Ordering::Relaxed);
+        }
+        LEVELS_ACTIVE.store(brightness != BRIGHTNESS_MAX || gamma != 100, Ordering::Release);
+        BRIGHTNESS.store(brightness, Ordering::Relaxed);
+        GAMMA.store(gamma, Ordering::Relaxed);
+        if let Some(fb) = raw_framebuffer()
+            && fb.info.bpp == 32
+            && convert.iter().enumerate().any(|(v, &c)| v != c as usize)
+        {
+            fb.map_channels(&convert);
+        }
+    });
+    Ok(())
+}
+
+/// Channel values at `brightness` percent and `gamma` hundredths:
+/// 255 * (c / 255) ^ (100 / gamma) * brightness / 100, rounded.
+fn level_table(brightness: u32, gamma: u32) -> [u8; 256] {
+    let exponent = 100.0 / gamma as f64;
+    core::array::from_fn(|c| {
+        if c == 0 {
+            return 0;
+        }
+        let v = 255.0 * pow_unit(c as f64 / 255.0, exponent) * brightness as f64 / 100.0;
+        (v + 0.5).min(255.0) as u8
+    })
+}
+
+/// `x` to the power `e` for `x` in (0, 1]; core has no `powf`. Good to
+/// well past the precision a channel needs.
+fn pow_unit(x: f64, e: f64) -> f64 {
+    const LN_2: f64 = core::f64::consts::LN_2;
+    // ln x = k ln 2 + ln m, m in [1, 2), ln m = 2 atanh((m - 1) / (m + 1))
+    let bits = x.to_bits();
+    let k = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    let m = f64::from_bits(bits & ((1 << 52) - 1) | 1023 << 52);
+    let s = (m - 1.0) / (m + 1.0);
+    let (mut term, mut atanh) = (s, 0.0);
+    for n in 0..20 {
+        atanh += term / (2 * n + 1) as f64;
+        term *= s * s;
+    }
+    let y = e * (k as f64 * LN_2 + 2.0 * atanh);
+    // e^y = 2^j e^r, |r| < ln 2
+    let j = (y / LN_2) as i64;
+    let r = y - j as f64 * LN_2;
+    let (mut term, mut exp) = (1.0, 1.0);
+    for n in 1..25 {
+        term *= r / n as f64;
+        exp += term;
+    }
+    exp * f64::from_bits(((j + 1023) as u64) << 52)
+}
+
 pub struct VbeVgaDriver {
     started: AtomicBool,
     // store all mappings created for this device so we can unmap on stop
-    mappings: Mutex<alloc::vec::Vec<FbMapping>>,
+    mappings: Mutex<alloc::vec::Vec<BarMapping>>,
     // optional framebuffer info deduced after modeset
     fb_info: Mutex<Option<FramebufferInfo>>,
+    // mode from the `mode` parameter, over the default
+    mode: Mutex<Option<(u16, u16, u16)>>,
 }
 
-// Globals populated by `main.rs` before drivers are attached
-static mut GLOBAL_MAPPER_PTR: *mut OffsetPageTable<'static> = core::ptr::null_mut();
-static mut GLOBAL_ALLOC_PTR: *mut BootInfoFrameAllocator = core::ptr::null_mut();
-
 // Runtime pointer to the active VBE driver instance (set in start, cleared in stop).
 static mut ACTIVE_VBE_PTR: *mut VbeVgaDriver = core::ptr::null_mut();
 
-/// Try to print formatted arguments to the active VBE console. Returns true if handled.
-pub fn vbe_try_print(args: core::fmt::Arguments) -> bool {
-    // Format into a String (alloc is available in kernel after heap init)
-    use alloc::string::String;
-    use core::fmt::Write;
-    let mut s = String::new();
-    if s.write_fmt(args).is_err() { return false; }
-
-    // Delegate to the console driver which owns console state and will call
-    // into the VBE drawing primitives. Returns true if printed to framebuffer.
-    crate::driver_framework::drivers::console::console_print_first(&s)
-}
-
 /// Convenience: clear the first framebuffer console if present.
 pub fn vbe_clear_first() {
     crate::driver_framework::drivers::console::console_clear_first();
@@ -180,6 +432,9 @@ pub fn vbe_print_first(s: &str) {
 /// Print to the VBE framebuffer only if a framebuffer is active; do nothing
 /// otherwise. This intentionally avoids falling back to the boot VGA text buffer.
 pub fn vbe_print_only(s: &str) {
+    // keep the panic screen readable; the console also takes locks and
+    // allocates, which a panic can't wait for
+    if crate::arch::panic::panicking() || crate::arch::panic_screen::shown() { return; }
     let addrs = get_framebuffer_addrs();
     if addrs.is_empty() { return; }
     let _ = crate::driver_framework::drivers::console::console_print_first(s);
@@ -218,21 +473,17 @@ pub fn vbe_color_from_vga_color(c: crate::bootvga::vga_buffer::Color) -> u32 {
     }
 }
 
-pub fn set_global_mapper_ptr(p: *mut OffsetPageTable<'static>) { unsafe { GLOBAL_MAPPER_PTR = p; } }
-pub fn set_global_frame_allocator_ptr(p: *mut BootInfoFrameAllocator) { unsafe { GLOBAL_ALLOC_PTR = p; } }
-
 impl VbeVgaDriver {
     pub fn new() -> Self {
         VbeVgaDriver {
             started: AtomicBool::new(false),
             mappings: Mutex::new(alloc::vec::Vec::new()),
             fb_info: Mutex::new(None),
+            mode: Mutex::new(None),
         }
     }
 
     unsafe fn set_vbe_mode_dispi(xres: u16, yres: u16, bpp: u16) -> bool {
-        const DISPI_INDEX_PORT: u16 = 0x01CE;
-        const DISPI_DATA_PORT: u16 = 0x01CF;
         const DISPI_INDEX_ID: u16 = 0x0;
         const DISPI_INDEX_XRES: u16 = 0x1;
         const DISPI_INDEX_YRES: u16 = 0x2;
@@ -268,122 +519,89 @@ impl Driver for VbeVgaDriver {
 
     fn start(&self, device: &crate::driver_framework::device::DeviceHandle) -> Result<(), &'static str> {
         if self.started.load(Ordering::SeqCst) { return Err("already started"); }
-        let info = device.info();
 
-        // Find an MMIO BAR (prefer large BARs)
         // Attempt to set a VBE mode (best-effort)
-        unsafe { let _ = Self::set_vbe_mode_dispi(1024, 768, 32); }
-
-    // We'll map every MemoryMapped BAR we find (prefer large ones) and write a test box
-        let phys_mem_offset_val: u64 = crate::driver_framework::drivers::get_boot_phys_offset();
-
-        // Prepare to store mappings
-        let mut created: alloc::vec::Vec<FbMapping> = alloc::vec::Vec::new();
+        let (xres, yres, bpp) = self.mode.lock().unwrap_or_else(default_video_mode);
+        crate::hal::ioport::request_ports(device, "dispi", DISPI_INDEX_PORT, 2)?;
+        let dispi = unsafe { Self::set_vbe_mode_dispi(xres, yres, bpp) };
 
-        // Map each MMIO BAR we discovered for this device
-        for r in info.resources.iter() {
-            if let ResourceKind::MemoryMapped = r.kind {
-                let bar_phys = r.addr;
-                let bar_len = if r.len == 0 { 0x1000u64 } else { r.len };
-                let phys_map_start = bar_phys & !0xFFFu64;
-                let phys_map_end = ((bar_phys + bar_len + 0xFFFu64) & !0xFFFu64);
-                let page_count = ((phys_map_end - phys_map_start) / 0x1000u64) as usize;
-                if page_count == 0 { continue; }
+        // Map every memory BAR; the first is the framebuffer
+        let created: alloc::vec::Vec<BarMapping> = (0..6).filter_map(|index| device.map_bar(index).ok()).collect();
 
-                let virt_base = phys_mem_offset_val.wrapping_add(phys_map_start);
+        if created.is_empty() { return Err("no MMIO BARs mapped"); }
 
-                unsafe {
-                    if GLOBAL_MAPPER_PTR.is_null() || GLOBAL_ALLOC_PTR.is_null() { return Err("mapper/alloc not set"); }
-                    let mapper: &mut OffsetPageTable = &mut *GLOBAL_MAPPER_PTR;
-                    let frame_alloc: &mut BootInfoFrameAllocator = &mut *GLOBAL_ALLOC_PTR;
-                    for i in 0..page_count {
-                        let phys = phys_map_start + (i as u64) * 0x1000u64;
-                        let frame = PhysFrame::containing_address(PhysAddr::new(phys));
-                        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt_base + (i as u64) * 0x1000u64));
-                        let flags = Flags::PRESENT | Flags::WRITABLE;
-                        match mapper.map_to(page, frame, flags, frame_alloc) {
-                            Ok(flush) => { flush.flush(); }
-                            Err(_) => { break; }
-                        }
-                    }
+        // Read back resolution/BPP from DISPI registers (best-effort) and store in fb_info;
+        // without DISPI (real hardware), use the mode the loader set, if any
+        if dispi {
+            unsafe {
+                // DISPI registers
+                const DISPI_INDEX_PORT: u16 = 0x01CE;
+                const DISPI_DATA_PORT: u16 = 0x01CF;
+                const DISPI_INDEX_XRES: u16 = 0x1;
+                const DISPI_INDEX_YRES: u16 = 0x2;
+                const DISPI_INDEX_BPP: u16 = 0x3;
+                crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_XRES);
+                let xres = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
+                crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_YRES);
+                let yres = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
+                crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_BPP);
+                let bpp = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
+                if xres != 0 && yres != 0 {
+                    let pitch = (xres as usize) * ((bpp as usize + 7) / 8);
+                    *self.fb_info.lock() = Some(FramebufferInfo { width: xres, height: yres, bpp, pitch });
                 }
-
-                created.push(FbMapping { virt_base, phys_map_start, bar_phys, pages: page_count });
             }
+        } else if let Some(fb) = crate::boot::boot_params().and_then(|p| p.framebuffer) {
+            *self.fb_info.lock() = Some(FramebufferInfo { width: fb.width, height: fb.height, bpp: fb.bpp as u32, pitch: fb.pitch as usize });
         }
 
-        if created.is_empty() { return Err("no MMIO BARs mapped"); }
-
-        // Read back resolution/BPP from DISPI registers (best-effort) and store in fb_info
-        unsafe {
-            // DISPI registers
-            const DISPI_INDEX_PORT: u16 = 0x01CE;
-            const DISPI_DATA_PORT: u16 = 0x01CF;
-            const DISPI_INDEX_XRES: u16 = 0x1;
-            const DISPI_INDEX_YRES: u16 = 0x2;
-            const DISPI_INDEX_BPP: u16 = 0x3;
-            crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_XRES);
-            let xres = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
-            crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_YRES);
-            let yres = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
-            crate::arch::ports::outw(DISPI_INDEX_PORT, DISPI_INDEX_BPP);
-            let bpp = crate::arch::ports::inw(DISPI_DATA_PORT) as u32;
-            if xres != 0 && yres != 0 {
-                let pitch = (xres as usize) * ((bpp as usize + 7) / 8);
-                *self.fb_info.lock() = Some(FramebufferInfo { width: xres, height: yres, bpp, pitch });
-            }
+        if let Some(fb) = *self.fb_info.lock() {
+            publish_raw_framebuffer(created[0].virt, fb);
         }
-
         // Save mappings on the struct for later unmap (move created)
         *self.mappings.lock() = created;
         // Mark driver as active for global helpers
         unsafe { ACTIVE_VBE_PTR = (self as *const VbeVgaDriver) as *mut VbeVgaDriver; }
 
         self.started.store(true, Ordering::SeqCst);
+        // the text buffer isn't visible in graphics mode; log to the framebuffer
+        // instead, starting with what the text buffer showed
+        crate::devices::console::set_console_backend(&crate::devices::console::FRAMEBUFFER_CONSOLE);
+        cls!();
+        crate::log::replay_early_log(&crate::log::FRAMEBUFFER_SINK);
+        let added = crate::log::add_sink(&crate::log::FRAMEBUFFER_SINK, crate::log::Level::Info);
+        kassert!(added.is_ok(), "[VBE] framebuffer log sink not added: {:?}", added);
+        crate::log::remove_sink("vga");
         Ok(())
     }
 
-    fn stop(&self, _device: &crate::driver_framework::device::DeviceHandle) {
+    fn stop(&self, device: &crate::driver_framework::device::DeviceHandle) {
         if !self.started.load(Ordering::SeqCst) { return; }
+        RAW_FB_BASE.store(0, Ordering::Release);
 
-        // Clear the test box for each mapping and unmap pages
+        // Clear the test box for each mapping and unmap them
         let mut mappings = self.mappings.lock();
-        if !mappings.is_empty() {
-            // Clear box in each mapping
-            let pitch = 1024 * 4;
-            for m in mappings.iter() {
-                unsafe {
-                    let fb = m.virt_base + (m.bar_phys - m.phys_map_start);
-                    let fb_ptr = fb as *mut u8;
-                    if !fb_ptr.is_null() {
-                        for y in 100usize..300usize {
-                            let row = fb_ptr.add(y * pitch);
-                            for x in 100usize..300usize {
-                                ptr::write_volatile(row.add(x * 4) as *mut u32, 0u32);
-                            }
-                        }
-                    }
-                }
-            }
-
-            // Unmap pages
+        let pitch = 1024 * 4;
+        for m in mappings.iter() {
             unsafe {
-                if !GLOBAL_MAPPER_PTR.is_null() {
-                    let mapper: &mut OffsetPageTable = &mut *GLOBAL_MAPPER_PTR;
-                    for m in mappings.iter() {
-                        for i in 0..m.pages {
-                            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(m.virt_base + (i as u64) * 0x1000u64));
-                            let _ = mapper.unmap(page);
-                        }
+                let fb_ptr = m.as_ptr::<u8>();
+                for y in 100usize..300usize {
+                    let row = fb_ptr.add(y * pitch);
+                    for x in 100usize..300usize {
+                        ptr::write_volatile(row.add(x * 4) as *mut u32, 0u32);
                     }
                 }
             }
-
-            // clear stored mappings
-            *mappings = alloc::vec::Vec::new();
         }
+        crate::hal::bar::free_device_bars(device.id());
+        mappings.clear();
+        drop(mappings);
 
         self.started.store(false, Ordering::SeqCst);
+        crate::devices::console::set_console_backend(&crate::devices::console::VGA_TEXT_CONSOLE);
+        crate::log::remove_sink("fb");
+        let added = crate::log::add_sink(&crate::log::BOOT_VGA_SINK, crate::log::Level::Info);
+        kassert!(added.is_ok(), "[VBE] VGA log sink not restored: {:?}", added);
         // clear active pointer if we were the active driver
         unsafe {
             if !ACTIVE_VBE_PTR.is_null() && ACTIVE_VBE_PTR == (self as *const VbeVgaDriver) as *mut VbeVgaDriver {
@@ -393,10 +611,23 @@ impl Driver for VbeVgaDriver {
     }
 
     fn release(&self, _device: &crate::driver_framework::device::DeviceHandle) { self.stop(_device); }
+
+    fn configure(&self, _device: &crate::driver_framework::device::DeviceHandle, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "mode" => *self.mode.lock() = Some(crate::config::parse_video_mode(value).ok_or("want WIDTHxHEIGHTxBPP")?),
+            _ => return Err("unknown framebuffer parameter"),
+        }
+        Ok(())
+    }
 }
 
 pub fn boxed_driver() -> Box<dyn Driver> { Box::new(VbeVgaDriver::new()) }
 
+// Mode the driver asks DISPI for when it starts (the config's video.mode)
+static DEFAULT_MODE: Mutex<(u16, u16, u16)> = Mutex::new((1024, 768, 32));
+pub fn set_default_video_mode(xres: u16, yres: u16, bpp: u16) { *DEFAULT_MODE.lock() = (xres, yres, bpp); }
+pub fn default_video_mode() -> (u16, u16, u16) { *DEFAULT_MODE.lock() }
+
 // Boot-phys offset helper (set by main.rs)
 static BOOT_PHYS_OFFSET_GLOBAL: Mutex<u64> = Mutex::new(0);
 pub fn set_boot_phys_offset(val: u64) { *BOOT_PHYS_OFFSET_GLOBAL.lock() = val; }
@@ -436,6 +667,15 @@ pub fn draw_rect_at(fb_virt: u64, x: usize, y: usize, w: usize, h: usize, color:
     }
 }
 
+/// XOR every pixel of a rectangle with `mask`; doing it twice restores it.
+pub fn invert_rect_at(fb_virt: u64, x: usize, y: usize, w: usize, h: usize, mask: u32) {
+    unsafe {
+        if ACTIVE_VBE_PTR.is_null() { return; }
+        let drv: &VbeVgaDriver = &*ACTIVE_VBE_PTR;
+        drv.invert_rect_at(fb_virt, x, y, w, h, mask);
+    }
+}
+
 pub fn draw_char_at(fb_virt: u64, x: usize, y: usize, ch: u8, color: u32) {
     unsafe {
         if ACTIVE_VBE_PTR.is_null() { return; }
@@ -444,6 +684,15 @@ pub fn draw_char_at(fb_virt: u64, x: usize, y: usize, ch: u8, color: u32) {
     }
 }
 
+/// Draw a run of text cells at once; see `VbeVgaDriver::draw_text_run_at`.
+pub fn draw_text_run_at(fb_virt: u64, x: usize, y: usize, text: &[u8], cell_w: usize, fg: u32, bg: u32, scratch: &mut alloc::vec::Vec<u32>) {
+    unsafe {
+        if ACTIVE_VBE_PTR.is_null() { return; }
+        let drv: &VbeVgaDriver = &*ACTIVE_VBE_PTR;
+        drv.draw_text_run_at(fb_virt, x, y, text, cell_w, fg, bg, scratch);
+    }
+}
+
 pub fn draw_text_absolute(fb_virt: u64, x: usize, y: usize, s: &str, color: u32) {
     unsafe {
         if ACTIVE_VBE_PTR.is_null() { return; }
@@ -452,14 +701,55 @@ pub fn draw_text_absolute(fb_virt: u64, x: usize, y: usize, s: &str, color: u32)
     }
 }
 
+/// Read one pixel back. None without an active driver, or outside the mode.
+pub fn read_pixel_at(fb_virt: u64, x: usize, y: usize) -> Option<u32> {
+    unsafe {
+        if ACTIVE_VBE_PTR.is_null() { return None; }
+        let drv: &VbeVgaDriver = &*ACTIVE_VBE_PTR;
+        drv.read_pixel_at(fb_virt, x, y)
+    }
+}
+
+/// Read a rectangle back, row by row; see `RawFramebuffer::read_rect`. None
+/// without an active driver.
+pub fn read_rect(fb_virt: u64, x: usize, y: usize, w: usize, h: usize) -> Option<alloc::vec::Vec<u32>> {
+    unsafe {
+        if ACTIVE_VBE_PTR.is_null() { return None; }
+        let drv: &VbeVgaDriver = &*ACTIVE_VBE_PTR;
+        drv.read_rect(fb_virt, x, y, w, h)
+    }
+}
+
+/// Put back pixels `read_rect` returned; see `RawFramebuffer::write_rect`.
+pub fn write_rect(fb_virt: u64, x: usize, y: usize, w: usize, h: usize, pixels: &[u32]) {
+    unsafe {
+        if ACTIVE_VBE_PTR.is_null() { return; }
+        let drv: &VbeVgaDriver = &*ACTIVE_VBE_PTR;
+        drv.write_rect(fb_virt, x, y, w, h, pixels)
+    }
+}
+
+/// Push a drawn rectangle out to the framebuffer. The BARs are mapped UC-,
+/// which a write-combining MTRR over the framebuffer turns into
+/// write-combining, and then writes sit in the CPU until something drains
+/// them. Each row is flushed over just the pixels drawn, a cache line at a
+/// time.
+pub fn flush_fb_rect(fb_virt: u64, pitch: usize, x: usize, y: usize, w: usize, h: usize) {
+    if w == 0 { return; }
+    for yy in y..(y + h) {
+        let row = fb_virt as usize + yy * pitch + x * 4;
+        crate::arch::processor::flush_cache_range(row as *const u8, w * 4);
+    }
+}
+
 // --- Drawing / text helpers ---
 impl VbeVgaDriver {
     /// Return a vector of framebuffer virtual addresses for each mapped BAR.
-    /// Each entry is the virtual base corresponding to the BAR's start (virt_base + (bar_phys - phys_map_start)).
+    /// Each entry is where the BAR's first byte is mapped.
     pub fn get_framebuffer_addrs(&self) -> alloc::vec::Vec<u64> {
         let mut out = alloc::vec::Vec::new();
         for m in self.mappings.lock().iter() {
-            out.push(m.virt_base + (m.bar_phys - m.phys_map_start));
+            out.push(m.virt);
         }
         out
     }
@@ -471,13 +761,14 @@ impl VbeVgaDriver {
             let base = fb_virt as *mut u8;
             let row = base.add(y * pitch);
             let p = row.add(x * 4) as *mut u32;
-            ptr::write_volatile(p, color);
+            ptr::write_volatile(p, adjust_color(color));
         }
     }
 
     /// Draw a filled rectangle to a framebuffer virtual address. Assumes ARGB32.
     pub fn draw_rect_at(&self, fb_virt: u64, x: usize, y: usize, w: usize, h: usize, color: u32) {
         let pitch = if let Some(info) = *self.fb_info.lock() { info.pitch } else { 1024usize * 4 };
+        let color = adjust_color(color);
         unsafe {
             let base = fb_virt as *mut u8;
             for yy in y..(y + h) {
@@ -487,6 +778,22 @@ impl VbeVgaDriver {
                 }
             }
         }
+        flush_fb_rect(fb_virt, pitch, x, y, w, h);
+    }
+
+    /// XOR a rectangle with `mask`. Assumes ARGB32.
+    pub fn invert_rect_at(&self, fb_virt: u64, x: usize, y: usize, w: usize, h: usize, mask: u32) {
+        let pitch = if let Some(info) = *self.fb_info.lock() { info.pitch } else { 1024usize * 4 };
+        unsafe {
+            let base = fb_virt as *mut u8;
+            for yy in y..(y + h) {
+                let row = base.add(yy * pitch);
+                for xx in x..(x + w) {
+                    let p = row.add(xx * 4) as *mut u32;
+                    ptr::write_volatile(p, ptr::read_volatile(p) ^ mask);
+                }
+            }
+        }
     }
 
     /// Draw a single 8x8 character using a simple procedural glyph generator.
@@ -495,6 +802,7 @@ impl VbeVgaDriver {
     pub fn draw_char_at(&self, fb_virt: u64, x: usize, y: usize, ch: u8, color: u32) {
         // Use embedded VGA 8x8 font when available; fallback to procedural glyph otherwise.
         let pitch = if let Some(info) = *self.fb_info.lock() { info.pitch } else { 1024usize * 4 };
+        let color = adjust_color(color);
         // Attempt to read font data
         if let Some(glyph) = VGA8X8::get_glyph(ch) {
             unsafe {
@@ -527,6 +835,53 @@ impl VbeVgaDriver {
                 }
         }
     }
+    /// Draw `text` as cells `cell_w` pixels apart, glyphs in `fg` on `bg`.
+    /// Each of the run's pixel rows is laid out in `scratch` and copied to
+    /// the framebuffer in one go, instead of a write per pixel; that is
+    /// what keeps bulk console output fast. Cells past the right edge are
+    /// cut off. Assumes ARGB32.
+    pub fn draw_text_run_at(&self, fb_virt: u64, x: usize, y: usize, text: &[u8], cell_w: usize, fg: u32, bg: u32, scratch: &mut alloc::vec::Vec<u32>) {
+        let Some(info) = *self.fb_info.lock() else { return };
+        let width = (text.len() * cell_w).min((info.width as usize).saturating_sub(x));
+        if width == 0 || y + 8 > info.height as usize { return; }
+        let (fg, bg) = (adjust_color(fg), adjust_color(bg));
+        scratch.clear();
+        scratch.resize(text.len() * cell_w, bg);
+        for r in 0..8usize {
+            for (i, &ch) in text.iter().enumerate() {
+                let bits = glyph_rows(ch)[r];
+                let cell = &mut scratch[i * cell_w..(i + 1) * cell_w];
+                for (c, px) in cell.iter_mut().enumerate() {
+                    *px = if c < 8 && bits & (0x80 >> c) != 0 { fg } else { bg };
+                }
+            }
+            unsafe {
+                let row = (fb_virt as *mut u8).add((y + r) * info.pitch + x * 4) as *mut u32;
+                ptr::copy_nonoverlapping(scratch.as_ptr(), row, width);
+            }
+        }
+        flush_fb_rect(fb_virt, info.pitch, x, y, width, 8);
+    }
+
+    /// Read one pixel back from a framebuffer virtual address.
+    pub fn read_pixel_at(&self, fb_virt: u64, x: usize, y: usize) -> Option<u32> {
+        let info = (*self.fb_info.lock())?;
+        RawFramebuffer { base: fb_virt, info }.read_pixel(x, y)
+    }
+
+    /// Read a rectangle back from a framebuffer virtual address.
+    pub fn read_rect(&self, fb_virt: u64, x: usize, y: usize, w: usize, h: usize) -> Option<alloc::vec::Vec<u32>> {
+        let info = (*self.fb_info.lock())?;
+        Some(RawFramebuffer { base: fb_virt, info }.read_rect(x, y, w, h))
+    }
+
+    /// Write back a rectangle `read_rect` returned, as it was.
+    pub fn write_rect(&self, fb_virt: u64, x: usize, y: usize, w: usize, h: usize, pixels: &[u32]) {
+        if let Some(info) = *self.fb_info.lock() {
+            RawFramebuffer { base: fb_virt, info }.write_rect(x, y, w, h, pixels);
+        }
+    }
+
     /// Keep the old absolute text drawing API if needed.
     pub fn draw_text_absolute(&self, fb_virt: u64, x: usize, y: usize, s: &str, color: u32) {
         let mut cx = x;
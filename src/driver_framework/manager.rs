@@ -4,9 +4,9 @@ use alloc::format;
 use alloc::string::String;
 use spin::Mutex;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use crate::driver_framework::device::{Device, DeviceHandle, DeviceInfo};
-use crate::driver_framework::driver::{DriverBox};
-pub use crate::*;
+use crate::driver_framework::device::{Device, DeviceHandle, DeviceInfo, PciAddress};
+use crate::driver_framework::driver::{DriverBox, DriverParams};
+use crate::prelude::*;
 use crate::alloc::string::ToString;
 
 static NEXT_DEVICE_ID: AtomicUsize = AtomicUsize::new(1);
@@ -57,6 +57,9 @@ impl DeviceManager {
 					existing.capabilities.push(c.clone());
 				}
 			}
+			if existing.location.is_none() {
+				existing.location = info.location;
+			}
 			// Append to description if missing parts
 			if !existing.description.contains(&info.description) {
 				existing.description = alloc::format!("{}; {}", existing.description, info.description);
@@ -68,11 +71,20 @@ impl DeviceManager {
 
 	/// Attach a driver to a device id. The manager calls probe, then start.
 	pub fn attach_driver(&self, device_id: usize, driver: DriverBox) -> Result<(), String> {
+		self.attach_driver_with_params(device_id, driver, &DriverParams::new())
+	}
+
+	/// Attach a driver, handing it `params` through `configure` before
+	/// probe. A parameter the driver rejects fails the attach.
+	pub fn attach_driver_with_params(&self, device_id: usize, driver: DriverBox, params: &DriverParams) -> Result<(), String> {
 		let mut devices = self.devices.lock();
 		if let Some(entry) = devices.iter_mut().find(|e| e.device.id == device_id) {
 			if entry.driver.is_some() {
 				return Err(format!("device {} already has a driver", device_id));
 			}
+			for (key, value) in params.iter() {
+				driver.configure(&entry.device, key, value).map_err(|e| format!("parameter {}={}: {}", key, value, e))?;
+			}
 			// Call probe
 			match driver.probe(&entry.device) {
 				Ok(()) => {
@@ -82,7 +94,13 @@ impl DeviceManager {
 							entry.driver = Some(driver);
 							Ok(())
 						}
-						Err(e) => Err(format!("start failed: {}", e)),
+						Err(e) => {
+							crate::hal::free_device_irqs(device_id);
+							crate::hal::free_device_dma(device_id);
+							crate::hal::free_device_bars(device_id);
+							crate::hal::free_device_ports(device_id);
+							Err(format!("start failed: {}", e))
+						}
 					}
 				}
 				Err(e) => Err(format!("probe failed: {}", e)),
@@ -99,6 +117,12 @@ impl DeviceManager {
 			if let Some(driver) = entry.driver.take() {
 				driver.stop(&entry.device);
 				driver.release(&entry.device);
+				// lines the driver didn't free would be left unmasked with nobody handling them,
+				// buffers it didn't unmap open to the device, its BARs mapped, and its ports unclaimable
+				crate::hal::free_device_irqs(device_id);
+				crate::hal::free_device_dma(device_id);
+				crate::hal::free_device_bars(device_id);
+				crate::hal::free_device_ports(device_id);
 				Ok(())
 			} else {
 				Err(format!("device {} has no driver", device_id))
@@ -108,6 +132,31 @@ impl DeviceManager {
 		}
 	}
 
+	/// Change setting `key` of a device through its driver.
+	pub fn control_device(&self, device_id: usize, key: &str, value: &str) -> Result<(), String> {
+		let devices = self.devices.lock();
+		let entry = devices.iter().find(|e| e.device.id == device_id)
+			.ok_or_else(|| format!("no device with id {}", device_id))?;
+		let driver = entry.driver.as_ref().ok_or_else(|| format!("device {} has no driver", device_id))?;
+		driver.control(&entry.device, key, value).map_err(|e| format!("{}: {}", key, e))
+	}
+
+	/// A device's settings and their values, as its driver reports them.
+	pub fn device_controls(&self, device_id: usize) -> Result<Vec<(&'static str, String)>, String> {
+		let devices = self.devices.lock();
+		let entry = devices.iter().find(|e| e.device.id == device_id)
+			.ok_or_else(|| format!("no device with id {}", device_id))?;
+		let driver = entry.driver.as_ref().ok_or_else(|| format!("device {} has no driver", device_id))?;
+		Ok(driver.control_values(&entry.device))
+	}
+
+	/// Ids of devices no driver has claimed, for a driver registered late
+	/// (from a module) to probe.
+	pub fn unclaimed_devices(&self) -> Vec<usize> {
+		let devices = self.devices.lock();
+		devices.iter().filter(|e| e.driver.is_none()).map(|e| e.device.id).collect()
+	}
+
 	/// Find devices by vendor/device id; returns a vector of ids.
 	pub fn find_by_vid_pid(&self, vendor: u16, device: u16) -> Vec<usize> {
 		let devices = self.devices.lock();
@@ -120,6 +169,46 @@ impl DeviceManager {
 			.collect()
 	}
 
+	/// The device at PCI address `addr`.
+	pub fn find_by_location(&self, addr: PciAddress) -> Option<usize> {
+		let devices = self.devices.lock();
+		devices.iter().find(|e| e.device.info.lock().location == Some(addr)).map(|e| e.device.id)
+	}
+
+	/// The PCI-to-PCI bridge a PCI device sits behind: the one whose
+	/// secondary bus is the device's bus. None for devices on a root bus.
+	pub fn pci_parent(&self, device_id: usize) -> Option<usize> {
+		let devices = self.devices.lock();
+		let addr = devices.iter().find(|e| e.device.id == device_id)?.device.info.lock().location?;
+		devices.iter().find(|e| {
+			let info = e.device.info.lock();
+			// bus number register: primary, secondary, subordinate
+			info.is_pci_bridge() && info.location.is_some_and(|bridge| {
+				bridge.segment == addr.segment && bridge != addr && (bridge.config_read(0x18) >> 8) as u8 == addr.bus
+			})
+		}).map(|e| e.device.id)
+	}
+
+	/// Where a device sits, root first: `pci0000:00/0000:00:1c.0/0000:01:00.0`
+	/// for a function behind a bridge, its `DeviceInfo::name` for devices
+	/// not on PCI.
+	pub fn device_path(&self, device_id: usize) -> Option<String> {
+		let info = self.devices.lock().iter().find(|e| e.device.id == device_id)?.device.info();
+		let Some(addr) = info.location else { return Some(info.name()) };
+		let mut path = format!("{}", addr);
+		let mut id = device_id;
+		let mut root_bus = addr.bus;
+		// a bus numbering loop must not hang us
+		for _ in 0..256 {
+			let Some(parent) = self.pci_parent(id) else { break };
+			let Some(parent_addr) = self.devices.lock().iter().find(|e| e.device.id == parent).and_then(|e| e.device.info.lock().location) else { break };
+			path = format!("{}/{}", parent_addr, path);
+			root_bus = parent_addr.bus;
+			id = parent;
+		}
+		Some(format!("pci{:04x}:{:02x}/{}", addr.segment, root_bus, path))
+	}
+
 	/// Provides a debug listing
 	pub fn list_devices(&self) {
 		let devices = self.devices.lock();
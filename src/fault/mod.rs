@@ -0,0 +1,138 @@
+//! Fault injection
+//!
+//! Each `FaultPoint` is a place where the kernel can be told to misbehave
+//! one time in N: the heap returns null, an interrupt handler starts late,
+//! a byte read from the PS/2 controller is corrupted, a queue reports
+//! full, or a received network frame gets a flipped bit. All rates are 0
+//! (off) at boot; `fault=alloc:1000,ps2:50` on the command line or the
+//! `fault` shell command sets them, and the test kernel sets them directly
+//! to drive driver error paths. An unset point costs one atomic load.
+//!
+//! An injected allocation failure reaches fallible callers (`try_reserve`)
+//! as an error but panics in infallible ones, like running out of heap
+//! does.
+
+use crate::prelude::*;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FaultPoint {
+    /// Heap allocation returns null.
+    Alloc,
+    /// An interrupt handler is delayed by up to `MAX_IRQ_DELAY_US`.
+    IrqDelay,
+    /// A byte read from the PS/2 data port is replaced.
+    Ps2,
+    /// A driver queue behaves as if full.
+    QueueFull,
+    /// A bit is flipped in a received network frame.
+    NetRx,
+}
+
+impl FaultPoint {
+    pub const ALL: [FaultPoint; 5] = [FaultPoint::Alloc, FaultPoint::IrqDelay, FaultPoint::Ps2, FaultPoint::QueueFull, FaultPoint::NetRx];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FaultPoint::Alloc => "alloc",
+            FaultPoint::IrqDelay => "irqdelay",
+            FaultPoint::Ps2 => "ps2",
+            FaultPoint::QueueFull => "queue",
+            FaultPoint::NetRx => "netrx",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FaultPoint> {
+        FaultPoint::ALL.into_iter().find(|p| p.name() == name)
+    }
+}
+
+/// Longest delay injected into an interrupt handler.
+pub const MAX_IRQ_DELAY_US: u64 = 2000;
+
+/// One in how many events fails, per point; 0 is off.
+static RATES: [AtomicU32; 5] = [const { AtomicU32::new(0) }; 5];
+static INJECTED: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+static SEED: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+
+/// Fail one `point` event in `one_in`; 0 turns it off.
+pub fn set_fault_rate(point: FaultPoint, one_in: u32) {
+    RATES[point as usize].store(one_in, Ordering::Relaxed);
+}
+
+pub fn fault_rate(point: FaultPoint) -> u32 {
+    RATES[point as usize].load(Ordering::Relaxed)
+}
+
+/// Faults injected at `point` since boot.
+pub fn faults_injected(point: FaultPoint) -> u64 {
+    INJECTED[point as usize].load(Ordering::Relaxed)
+}
+
+/// Apply `fault=POINT:N,...` from the kernel command line.
+pub fn configure_faults_from_cmdline(cmdline: &str) {
+    let Some(specs) = cmdline.split_whitespace().find_map(|w| w.strip_prefix("fault=")) else { return };
+    for item in specs.split(',') {
+        let parsed = item.split_once(':').and_then(|(p, n)| Some((FaultPoint::from_name(p)?, n.parse::<u32>().ok()?)));
+        match parsed {
+            Some((point, n)) => set_fault_rate(point, n),
+            None => println!("[FAULT] bad fault spec '{}' (want POINT:N)", item),
+        }
+    }
+}
+
+/// Next value of a xorshift64* generator. Not for anything but picking
+/// faults.
+fn next_random() -> u64 {
+    let mut x = SEED.load(Ordering::Relaxed);
+    loop {
+        let mut y = x ^ crate::arch::tsc_timer::rdtsc();
+        y ^= y >> 12;
+        y ^= y << 25;
+        y ^= y >> 27;
+        match SEED.compare_exchange_weak(x, y, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return y.wrapping_mul(0x2545_F491_4F6C_DD1D),
+            Err(cur) => x = cur,
+        }
+    }
+}
+
+/// Whether to inject a fault at `point` this time.
+#[inline]
+pub fn inject(point: FaultPoint) -> bool {
+    let rate = RATES[point as usize].load(Ordering::Relaxed);
+    if rate == 0 || next_random() % rate as u64 != 0 {
+        return false;
+    }
+    INJECTED[point as usize].fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// `byte`, or a random replacement when a `Ps2` fault fires.
+#[inline]
+pub fn ps2_byte(byte: u8) -> u8 {
+    if inject(FaultPoint::Ps2) { next_random() as u8 } else { byte }
+}
+
+/// Spin for a random time up to `MAX_IRQ_DELAY_US` when an `IrqDelay`
+/// fault fires.
+#[inline]
+pub fn irq_delay() {
+    if inject(FaultPoint::IrqDelay) {
+        use crate::arch::tsc_timer::{rdtsc, tsc_hz};
+        let us = next_random() % MAX_IRQ_DELAY_US;
+        let end = rdtsc() + us * tsc_hz() / 1_000_000;
+        while rdtsc() < end {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Flip one random bit of `frame` when a `NetRx` fault fires.
+pub fn corrupt_frame(frame: &mut [u8]) {
+    if !frame.is_empty() && inject(FaultPoint::NetRx) {
+        let bit = next_random() as usize % (frame.len() * 8);
+        frame[bit / 8] ^= 1 << (bit % 8);
+    }
+}
@@ -0,0 +1,1071 @@
+//! FAT32 filesystem
+//!
+//! Mounts a FAT32 volume through the block layer: FAT chain traversal,
+//! directory iteration with long file names, file reads, and file/directory
+//! creation, append and truncate. All I/O uses the queue's blocking helpers
+//! so the filesystem can be used during boot, before the executor runs.
+//!
+//! Writes are ordered so an interrupted update leaks clusters rather than
+//! corrupting files: a new cluster is zeroed before the FAT links it into a
+//! chain, the data goes in after that, and the directory entry's size is
+//! updated last, so an interrupted append leaves zeroed clusters past the
+//! file's end rather than stale ones inside it. Every FAT copy is kept in
+//! step, and FSInfo's free-cluster hints are written back by `sync`.
+
+use crate::prelude::*;
+use crate::storage::{BlockError, BlockQueue};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_EOC_MIN: u32 = 0x0FFF_FFF8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Block(BlockError),
+    /// The boot sector doesn't describe a FAT32 volume.
+    NotFat32,
+    /// No FAT32 partition in the partition table.
+    NoPartition,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    InvalidPath,
+    /// A cluster chain or directory entry points somewhere impossible.
+    Corrupt,
+    NoSpace,
+    Exists,
+    /// The name can't be stored (too long or contains invalid characters).
+    InvalidName,
+}
+
+impl From<BlockError> for FatError {
+    fn from(e: BlockError) -> FatError {
+        FatError::Block(e)
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::Block(e) => write!(f, "block device error: {}", e),
+            FatError::NotFat32 => f.write_str("not a FAT32 volume"),
+            FatError::NoPartition => f.write_str("no FAT32 partition found"),
+            FatError::NotFound => f.write_str("no such file or directory"),
+            FatError::NotADirectory => f.write_str("not a directory"),
+            FatError::IsADirectory => f.write_str("is a directory"),
+            FatError::InvalidPath => f.write_str("invalid path"),
+            FatError::Corrupt => f.write_str("filesystem is corrupt"),
+            FatError::NoSpace => f.write_str("no space left on volume"),
+            FatError::Exists => f.write_str("file exists"),
+            FatError::InvalidName => f.write_str("invalid file name"),
+        }
+    }
+}
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+/// A directory entry, with the long name when one is present.
+#[derive(Debug, Clone)]
+pub struct FatDirEntry {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Volume sector holding the short entry, and its byte offset within it.
+    pub entry_sector: u64,
+    pub entry_offset: usize,
+}
+
+impl FatDirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_name_string(&self.short_name).eq_ignore_ascii_case(name)
+    }
+}
+
+/// "NAME    EXT" -> "NAME.EXT"
+fn short_name_string(raw: &[u8; 11]) -> String {
+    let mut base: String = raw[0..8].iter().map(|&c| c as char).collect::<String>().trim_end().into();
+    if raw[0] == 0x05 {
+        base.replace_range(0..1, "\u{e5}");
+    }
+    let ext: String = raw[8..11].iter().map(|&c| c as char).collect::<String>().trim_end().into();
+    if ext.is_empty() {
+        base
+    } else {
+        base.push('.');
+        base.push_str(&ext);
+        base
+    }
+}
+
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Collects long-name fragments until the short entry they belong to.
+struct LfnBuilder {
+    chars: Vec<u16>,
+    checksum: u8,
+    valid: bool,
+}
+
+impl LfnBuilder {
+    fn new() -> Self {
+        LfnBuilder { chars: Vec::new(), checksum: 0, valid: false }
+    }
+
+    fn push(&mut self, e: &[u8]) {
+        let ord = e[0];
+        let seq = (ord & 0x1F) as usize;
+        if seq == 0 {
+            self.valid = false;
+            return;
+        }
+        if ord & 0x40 != 0 {
+            // last fragment comes first on disk and tells us the length
+            self.chars = vec![0xFFFF; seq * 13];
+            self.checksum = e[13];
+            self.valid = true;
+        } else if !self.valid || e[13] != self.checksum || seq * 13 > self.chars.len() {
+            self.valid = false;
+            return;
+        }
+        let base = (seq - 1) * 13;
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (i, &off) in offsets.iter().enumerate() {
+            self.chars[base + i] = le16(e, off);
+        }
+    }
+
+    fn take(&mut self, short: &[u8]) -> Option<String> {
+        if !self.valid || lfn_checksum(short) != self.checksum {
+            self.valid = false;
+            return None;
+        }
+        self.valid = false;
+        let end = self.chars.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(self.chars.len());
+        Some(char::decode_utf16(self.chars[..end].iter().copied()).map(|r| r.unwrap_or('?')).collect())
+    }
+}
+
+pub struct FatFs {
+    queue: Arc<BlockQueue>,
+    /// First block of the volume on the device.
+    part_lba: u64,
+    blocks_per_sector: u64,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    reserved_sectors: u64,
+    num_fats: u64,
+    fat_size: u64,
+    root_cluster: u32,
+    fsinfo_sector: u64,
+    first_data_sector: u64,
+    total_clusters: u32,
+    /// Most recently read FAT sector: (sector, data).
+    fat_cache: Mutex<Option<(u64, Vec<u8>)>>,
+    alloc: Mutex<AllocHints>,
+    /// Serializes operations that modify the volume.
+    write_lock: Mutex<()>,
+}
+
+/// FSInfo free-cluster count and allocation hint, kept in memory and
+/// written back by `sync`.
+struct AllocHints {
+    free_count: u32,
+    next_free: u32,
+    dirty: bool,
+}
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+impl FatFs {
+    /// Mount the FAT32 volume starting at `part_lba`.
+    pub fn mount(queue: Arc<BlockQueue>, part_lba: u64) -> Result<FatFs, FatError> {
+        let bs = queue.block_size();
+        let mut boot = vec![0u8; bs.max(512)];
+        queue.read_blocking(part_lba, &mut boot[..bs])?;
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(FatError::NotFat32);
+        }
+        let bytes_per_sector = le16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved_sectors = le16(&boot, 14) as u64;
+        let num_fats = boot[16] as u64;
+        let root_entries = le16(&boot, 17);
+        let fat_size16 = le16(&boot, 22);
+        let total16 = le16(&boot, 19) as u64;
+        let total32 = le32(&boot, 32) as u64;
+        let fat_size = le32(&boot, 36) as u64;
+        let root_cluster = le32(&boot, 44);
+        let fsinfo_sector = le16(&boot, 48) as u64;
+
+        // FAT32 has no fixed root directory and a 32-bit FAT size
+        if root_entries != 0 || fat_size16 != 0 || fat_size == 0 || num_fats == 0 {
+            return Err(FatError::NotFat32);
+        }
+        if bytes_per_sector < 512 || bytes_per_sector % bs != 0 || !sectors_per_cluster.is_power_of_two() {
+            return Err(FatError::NotFat32);
+        }
+        let total_sectors = if total16 != 0 { total16 } else { total32 };
+        let first_data_sector = reserved_sectors + num_fats * fat_size;
+        if total_sectors <= first_data_sector {
+            return Err(FatError::NotFat32);
+        }
+        let total_clusters = ((total_sectors - first_data_sector) / sectors_per_cluster as u64) as u32;
+
+        let fs = FatFs {
+            queue,
+            part_lba,
+            blocks_per_sector: (bytes_per_sector / bs) as u64,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_size,
+            root_cluster,
+            fsinfo_sector,
+            first_data_sector,
+            total_clusters,
+            fat_cache: Mutex::new(None),
+            alloc: Mutex::new(AllocHints { free_count: FSINFO_UNKNOWN, next_free: 2, dirty: false }),
+            write_lock: Mutex::new(()),
+        };
+        fs.load_fsinfo();
+        println!("[FAT] mounted {} at lba {}: {} clusters of {} bytes", fs.queue.name(), part_lba, total_clusters, fs.cluster_size());
+        Ok(fs)
+    }
+
+    /// Find a FAT32 volume on `queue`: an MBR partition of type 0x0B/0x0C,
+    /// or an unpartitioned volume starting at LBA 0.
+    pub fn mount_first_partition(queue: Arc<BlockQueue>) -> Result<FatFs, FatError> {
+        let bs = queue.block_size();
+        let mut mbr = vec![0u8; bs.max(512)];
+        queue.read_blocking(0, &mut mbr[..bs])?;
+        if mbr[510] == 0x55 && mbr[511] == 0xAA {
+            for i in 0..4 {
+                let e = 446 + i * 16;
+                let kind = mbr[e + 4];
+                if kind == 0x0B || kind == 0x0C {
+                    let start = le32(&mbr, e + 8) as u64;
+                    // partition LBAs are in 512-byte units
+                    return FatFs::mount(queue, start * 512 / bs as u64);
+                }
+            }
+        }
+        match FatFs::mount(queue, 0) {
+            Ok(fs) => Ok(fs),
+            Err(FatError::NotFat32) => Err(FatError::NoPartition),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn queue(&self) -> &Arc<BlockQueue> {
+        &self.queue
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    pub fn root_cluster(&self) -> u32 {
+        self.root_cluster
+    }
+
+    pub fn total_clusters(&self) -> u32 {
+        self.total_clusters
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FatError> {
+        self.queue.read_blocking(self.part_lba + sector * self.blocks_per_sector, buf)?;
+        Ok(())
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), FatError> {
+        self.queue.write_blocking(self.part_lba + sector * self.blocks_per_sector, buf)?;
+        Ok(())
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> Result<u64, FatError> {
+        if cluster < 2 || cluster >= self.total_clusters + 2 {
+            return Err(FatError::Corrupt);
+        }
+        Ok(self.first_data_sector + (cluster as u64 - 2) * self.sectors_per_cluster as u64)
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        let sector = self.cluster_sector(cluster)?;
+        self.read_sector(sector, &mut buf[..self.cluster_size()])
+    }
+
+    fn write_cluster(&self, cluster: u32, buf: &[u8]) -> Result<(), FatError> {
+        let sector = self.cluster_sector(cluster)?;
+        self.write_sector(sector, &buf[..self.cluster_size()])
+    }
+
+    /// Location of `cluster`'s entry in the first FAT: (sector, byte offset).
+    fn fat_location(&self, cluster: u32) -> (u64, usize) {
+        let byte = cluster as u64 * 4;
+        let bps = self.bytes_per_sector as u64;
+        (self.reserved_sectors + byte / bps, (byte % bps) as usize)
+    }
+
+    /// Next cluster after `cluster`, or None at end of chain.
+    pub fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let next = self.raw_fat_entry(cluster)?;
+        match next {
+            n if n >= FAT_EOC_MIN => Ok(None),
+            FAT_BAD | 0 | 1 => Err(FatError::Corrupt),
+            n => Ok(Some(n)),
+        }
+    }
+
+    /// Every cluster of the chain starting at `start`.
+    pub fn cluster_chain(&self, start: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        if start == 0 {
+            return Ok(chain);
+        }
+        let mut cur = Some(start);
+        while let Some(c) = cur {
+            // a chain longer than the volume has a loop in it
+            if chain.len() as u32 > self.total_clusters {
+                return Err(FatError::Corrupt);
+            }
+            chain.push(c);
+            cur = self.next_cluster(c)?;
+        }
+        Ok(chain)
+    }
+
+    /// List the directory whose first cluster is `cluster`, skipping "."
+    /// and "..", deleted entries and volume labels.
+    pub fn read_dir(&self, cluster: u32) -> Result<Vec<FatDirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut lfn = LfnBuilder::new();
+        let mut buf = vec![0u8; self.cluster_size()];
+        for c in self.cluster_chain(cluster)? {
+            self.read_cluster(c, &mut buf)?;
+            let base_sector = self.cluster_sector(c)?;
+            for (i, e) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                match e[0] {
+                    0x00 => return Ok(entries), // end of directory
+                    0xE5 => { lfn.valid = false; continue; }
+                    _ => {}
+                }
+                let attr = e[11];
+                if attr & ATTR_LFN == ATTR_LFN {
+                    lfn.push(e);
+                    continue;
+                }
+                let long = lfn.take(e);
+                if attr & ATTR_VOLUME_ID != 0 || e[0] == b'.' {
+                    continue;
+                }
+                let mut short_name = [0u8; 11];
+                short_name.copy_from_slice(&e[0..11]);
+                let name = long.unwrap_or_else(|| display_short_name(&short_name, e[12]));
+                let byte = i * DIR_ENTRY_SIZE;
+                entries.push(FatDirEntry {
+                    name,
+                    short_name,
+                    attr,
+                    first_cluster: ((le16(e, 20) as u32) << 16) | le16(e, 26) as u32,
+                    size: le32(e, 28),
+                    entry_sector: base_sector + (byte / self.bytes_per_sector) as u64,
+                    entry_offset: byte % self.bytes_per_sector,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Resolve an absolute or root-relative path ("/boot/font.psf").
+    pub fn lookup(&self, path: &str) -> Result<FatDirEntry, FatError> {
+        let mut dir = self.root_entry();
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if !dir.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            if part == "." {
+                continue;
+            }
+            dir = self.read_dir(self.dir_cluster(&dir))?
+                .into_iter()
+                .find(|e| e.matches(part))
+                .ok_or(FatError::NotFound)?;
+        }
+        Ok(dir)
+    }
+
+    /// Synthetic entry for the root directory.
+    pub fn root_entry(&self) -> FatDirEntry {
+        FatDirEntry {
+            name: String::from("/"),
+            short_name: *b"           ",
+            attr: ATTR_DIRECTORY,
+            first_cluster: self.root_cluster,
+            size: 0,
+            entry_sector: 0,
+            entry_offset: 0,
+        }
+    }
+
+    /// ".." entries use cluster 0 for the root directory.
+    fn dir_cluster(&self, entry: &FatDirEntry) -> u32 {
+        if entry.first_cluster == 0 { self.root_cluster } else { entry.first_cluster }
+    }
+
+    /// List a directory by path.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<FatDirEntry>, FatError> {
+        let dir = self.lookup(path)?;
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        self.read_dir(self.dir_cluster(&dir))
+    }
+
+    /// Read from `entry` at byte `offset` into `buf`; returns bytes read.
+    pub fn read_file(&self, entry: &FatDirEntry, offset: u64, buf: &mut [u8]) -> Result<usize, FatError> {
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let size = entry.size as u64;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = core::cmp::min(buf.len() as u64, size - offset) as usize;
+        let csize = self.cluster_size() as u64;
+        let mut cluster = entry.first_cluster;
+        for _ in 0..offset / csize {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+        }
+
+        let mut scratch = vec![0u8; csize as usize];
+        let mut done = 0usize;
+        let mut pos = (offset % csize) as usize;
+        loop {
+            self.read_cluster(cluster, &mut scratch)?;
+            let n = core::cmp::min(want - done, csize as usize - pos);
+            buf[done..done + n].copy_from_slice(&scratch[pos..pos + n]);
+            done += n;
+            pos = 0;
+            if done == want {
+                return Ok(done);
+            }
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+        }
+    }
+
+    /// Read a whole file by path.
+    pub fn read_to_vec(&self, path: &str) -> Result<Vec<u8>, FatError> {
+        let entry = self.lookup(path)?;
+        let mut data = vec![0u8; entry.size as usize];
+        let n = self.read_file(&entry, 0, &mut data)?;
+        data.truncate(n);
+        Ok(data)
+    }
+}
+
+/// Short name for display, honouring the NT lowercase flags in byte 12.
+fn display_short_name(raw: &[u8; 11], nt_flags: u8) -> String {
+    let mut fixed = *raw;
+    if nt_flags & 0x08 != 0 {
+        fixed[0..8].make_ascii_lowercase();
+    }
+    if nt_flags & 0x10 != 0 {
+        fixed[8..11].make_ascii_lowercase();
+    }
+    short_name_string(&fixed)
+}
+
+// --- write support ---
+
+fn put16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Characters allowed in a short name besides letters and digits.
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// The exact 8.3 form of `name`, if it has one (uppercase only).
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let ok = |c: u8| c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&c);
+    if !base.bytes().all(ok) || !ext.bytes().all(ok) {
+        return None;
+    }
+    let mut raw = [b' '; 11];
+    raw[..base.len()].copy_from_slice(base.as_bytes());
+    raw[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(raw)
+}
+
+/// Basis for a generated short name ("Long File.txt" -> "LONGFI~N  TXT")
+/// with numeric tail `n`.
+fn generated_short_name(name: &str, n: u32) -> [u8; 11] {
+    let clean = |s: &str| -> Vec<u8> {
+        s.bytes()
+            .filter(|&c| c != b' ' && c != b'.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii_alphanumeric() || SHORT_NAME_SPECIALS.contains(&c) { c } else { b'_' }
+            })
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (clean(&name[..i]), clean(&name[i + 1..])),
+        _ => (clean(name), Vec::new()),
+    };
+    let tail = alloc::format!("~{}", n);
+    let keep = core::cmp::min(base.len(), 8 - tail.len());
+    let mut raw = [b' '; 11];
+    raw[..keep].copy_from_slice(&base[..keep]);
+    raw[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    let elen = core::cmp::min(ext.len(), 3);
+    raw[8..8 + elen].copy_from_slice(&ext[..elen]);
+    raw
+}
+
+/// Build the LFN entries for `name`, in on-disk order (highest ordinal first).
+fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    let count = (chars.len() + 12) / 13;
+    let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+    let mut out = Vec::with_capacity(count);
+    for seq in (1..=count).rev() {
+        let mut e = [0u8; DIR_ENTRY_SIZE];
+        e[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+        e[11] = ATTR_LFN;
+        e[13] = checksum;
+        for (i, &off) in offsets.iter().enumerate() {
+            let idx = (seq - 1) * 13 + i;
+            let c = match idx.cmp(&chars.len()) {
+                core::cmp::Ordering::Less => chars[idx],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xFFFF,
+            };
+            put16(&mut e, off, c);
+        }
+        out.push(e);
+    }
+    out
+}
+
+fn split_parent(path: &str) -> Result<(&str, &str), FatError> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FatError::InvalidPath);
+    }
+    Ok((parent, name))
+}
+
+impl FatFs {
+    fn load_fsinfo(&self) {
+        if self.fsinfo_sector == 0 || self.fsinfo_sector == 0xFFFF {
+            return;
+        }
+        let mut buf = vec![0u8; self.bytes_per_sector];
+        if self.read_sector(self.fsinfo_sector, &mut buf).is_err() {
+            return;
+        }
+        if le32(&buf, 0) != FSINFO_LEAD_SIG || le32(&buf, 484) != FSINFO_STRUCT_SIG {
+            return;
+        }
+        let mut hints = self.alloc.lock();
+        let free = le32(&buf, 488);
+        if free <= self.total_clusters {
+            hints.free_count = free;
+        }
+        let next = le32(&buf, 492);
+        if next >= 2 && next < self.total_clusters + 2 {
+            hints.next_free = next;
+        }
+    }
+
+    /// Write FSInfo back if allocation changed it, then flush the device.
+    pub fn sync(&self) -> Result<(), FatError> {
+        let _w = self.write_lock.lock();
+        self.sync_locked()
+    }
+
+    fn sync_locked(&self) -> Result<(), FatError> {
+        let (free, next, dirty) = {
+            let h = self.alloc.lock();
+            (h.free_count, h.next_free, h.dirty)
+        };
+        if dirty && self.fsinfo_sector != 0 && self.fsinfo_sector != 0xFFFF {
+            let mut buf = vec![0u8; self.bytes_per_sector];
+            self.read_sector(self.fsinfo_sector, &mut buf)?;
+            if le32(&buf, 0) == FSINFO_LEAD_SIG && le32(&buf, 484) == FSINFO_STRUCT_SIG {
+                put32(&mut buf, 488, free);
+                put32(&mut buf, 492, next);
+                self.write_sector(self.fsinfo_sector, &buf)?;
+            }
+            self.alloc.lock().dirty = false;
+        }
+        self.queue.flush_blocking()?;
+        Ok(())
+    }
+
+    /// Free clusters according to FSInfo, if known.
+    pub fn free_clusters(&self) -> Option<u32> {
+        match self.alloc.lock().free_count {
+            FSINFO_UNKNOWN => None,
+            n => Some(n),
+        }
+    }
+
+    fn raw_fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let (sector, off) = self.fat_location(cluster);
+        let mut cache = self.fat_cache.lock();
+        if !matches!(&*cache, Some((s, _)) if *s == sector) {
+            let mut buf = vec![0u8; self.bytes_per_sector];
+            self.read_sector(sector, &mut buf)?;
+            *cache = Some((sector, buf));
+        }
+        Ok(le32(&cache.as_ref().unwrap().1, off) & FAT_ENTRY_MASK)
+    }
+
+    /// Set `cluster`'s FAT entry in every FAT copy, keeping the reserved
+    /// top four bits.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let (sector, off) = self.fat_location(cluster);
+        let mut buf = vec![0u8; self.bytes_per_sector];
+        self.read_sector(sector, &mut buf)?;
+        let old = le32(&buf, off);
+        put32(&mut buf, off, (old & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK));
+        for copy in 0..self.num_fats {
+            self.write_sector(sector + copy * self.fat_size, &buf)?;
+        }
+        let mut cache = self.fat_cache.lock();
+        if matches!(&*cache, Some((s, _)) if *s == sector) {
+            *cache = Some((sector, buf));
+        }
+        Ok(())
+    }
+
+    /// Allocate a zeroed cluster, marked end-of-chain, and link it after
+    /// `prev`. The new cluster is terminated before `prev` points at it.
+    fn allocate_cluster(&self, prev: Option<u32>) -> Result<u32, FatError> {
+        let start = self.alloc.lock().next_free;
+        let end = self.total_clusters + 2;
+        let mut found = None;
+        for i in 0..self.total_clusters {
+            let c = 2 + (start - 2 + i) % (end - 2);
+            if self.raw_fat_entry(c)? == 0 {
+                found = Some(c);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FatError::NoSpace)?;
+
+        self.write_cluster(cluster, &vec![0u8; self.cluster_size()])?;
+        self.set_fat_entry(cluster, FAT_ENTRY_MASK)?;
+        if let Some(p) = prev {
+            self.set_fat_entry(p, cluster)?;
+        }
+
+        let mut h = self.alloc.lock();
+        if h.free_count != FSINFO_UNKNOWN {
+            h.free_count = h.free_count.saturating_sub(1);
+        }
+        h.next_free = if cluster + 1 < end { cluster + 1 } else { 2 };
+        h.dirty = true;
+        Ok(cluster)
+    }
+
+    /// Release every cluster of the chain starting at `start`.
+    fn free_chain(&self, start: u32) -> Result<(), FatError> {
+        let chain = self.cluster_chain(start)?;
+        for &c in chain.iter() {
+            self.set_fat_entry(c, 0)?;
+        }
+        let mut h = self.alloc.lock();
+        if h.free_count != FSINFO_UNKNOWN {
+            h.free_count = h.free_count.saturating_add(chain.len() as u32);
+        }
+        h.dirty = true;
+        Ok(())
+    }
+
+    /// Write `entry`'s first cluster and size back to its directory slot.
+    fn update_dir_entry(&self, entry: &FatDirEntry) -> Result<(), FatError> {
+        if entry.entry_sector == 0 {
+            return Ok(()); // root directory has no entry
+        }
+        let mut buf = vec![0u8; self.bytes_per_sector];
+        self.read_sector(entry.entry_sector, &mut buf)?;
+        let e = &mut buf[entry.entry_offset..entry.entry_offset + DIR_ENTRY_SIZE];
+        e[11] = entry.attr;
+        put16(e, 20, (entry.first_cluster >> 16) as u16);
+        put16(e, 26, entry.first_cluster as u16);
+        put32(e, 28, entry.size);
+        self.write_sector(entry.entry_sector, &buf)
+    }
+
+    /// Find `count` consecutive free slots in the directory at `cluster`,
+    /// extending it if needed. Returns (sector, offset) of each slot.
+    fn find_free_slots(&self, dir_cluster: u32, count: usize) -> Result<Vec<(u64, usize)>, FatError> {
+        let mut run: Vec<(u64, usize)> = Vec::new();
+        let mut buf = vec![0u8; self.cluster_size()];
+        let chain = self.cluster_chain(dir_cluster)?;
+        for &c in chain.iter() {
+            self.read_cluster(c, &mut buf)?;
+            let base = self.cluster_sector(c)?;
+            for (i, e) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                if e[0] == 0x00 || e[0] == 0xE5 {
+                    let byte = i * DIR_ENTRY_SIZE;
+                    run.push((base + (byte / self.bytes_per_sector) as u64, byte % self.bytes_per_sector));
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+        }
+        // grow the directory; fresh clusters are zeroed, i.e. all free
+        let per_cluster = self.cluster_size() / DIR_ENTRY_SIZE;
+        let mut last = *chain.last().ok_or(FatError::Corrupt)?;
+        while run.len() < count {
+            let c = self.allocate_cluster(Some(last))?;
+            let base = self.cluster_sector(c)?;
+            for i in 0..per_cluster {
+                let byte = i * DIR_ENTRY_SIZE;
+                run.push((base + (byte / self.bytes_per_sector) as u64, byte % self.bytes_per_sector));
+                if run.len() == count {
+                    break;
+                }
+            }
+            last = c;
+        }
+        Ok(run)
+    }
+
+    fn write_entry_at(&self, slot: (u64, usize), raw: &[u8; DIR_ENTRY_SIZE]) -> Result<(), FatError> {
+        let mut buf = vec![0u8; self.bytes_per_sector];
+        self.read_sector(slot.0, &mut buf)?;
+        buf[slot.1..slot.1 + DIR_ENTRY_SIZE].copy_from_slice(raw);
+        self.write_sector(slot.0, &buf)
+    }
+
+    /// Create an entry called `name` in the directory at `dir_cluster`.
+    fn create_entry(&self, dir_cluster: u32, name: &str, attr: u8, first_cluster: u32) -> Result<FatDirEntry, FatError> {
+        if name.len() > 255 || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+            return Err(FatError::InvalidName);
+        }
+        let existing = self.read_dir(dir_cluster)?;
+        if existing.iter().any(|e| e.matches(name)) {
+            return Err(FatError::Exists);
+        }
+
+        let (short, needs_lfn) = match exact_short_name(name) {
+            Some(raw) => (raw, false),
+            None => {
+                let mut n = 1;
+                loop {
+                    let candidate = generated_short_name(name, n);
+                    if !existing.iter().any(|e| e.short_name == candidate) {
+                        break (candidate, true);
+                    }
+                    n += 1;
+                    if n > 999_999 {
+                        return Err(FatError::NoSpace);
+                    }
+                }
+            }
+        };
+        let lfns = if needs_lfn { lfn_entries(name, lfn_checksum(&short)) } else { Vec::new() };
+        let slots = self.find_free_slots(dir_cluster, lfns.len() + 1)?;
+
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0..11].copy_from_slice(&short);
+        raw[11] = attr;
+        put16(&mut raw, 20, (first_cluster >> 16) as u16);
+        put16(&mut raw, 26, first_cluster as u16);
+
+        for (slot, lfn) in slots.iter().zip(lfns.iter()) {
+            self.write_entry_at(*slot, lfn)?;
+        }
+        let short_slot = *slots.last().unwrap();
+        self.write_entry_at(short_slot, &raw)?;
+
+        Ok(FatDirEntry {
+            name: String::from(name),
+            short_name: short,
+            attr,
+            first_cluster,
+            size: 0,
+            entry_sector: short_slot.0,
+            entry_offset: short_slot.1,
+        })
+    }
+
+    fn parent_cluster(&self, parent: &str) -> Result<u32, FatError> {
+        let dir = self.lookup(parent)?;
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        Ok(self.dir_cluster(&dir))
+    }
+
+    /// Create an empty file.
+    pub fn create_file(&self, path: &str) -> Result<FatDirEntry, FatError> {
+        let _w = self.write_lock.lock();
+        let (parent, name) = split_parent(path)?;
+        let dir = self.parent_cluster(parent)?;
+        self.create_entry(dir, name, ATTR_ARCHIVE, 0)
+    }
+
+    /// Create an empty directory with its "." and ".." entries.
+    pub fn create_dir(&self, path: &str) -> Result<FatDirEntry, FatError> {
+        let _w = self.write_lock.lock();
+        let (parent, name) = split_parent(path)?;
+        let parent_cluster = self.parent_cluster(parent)?;
+
+        let cluster = self.allocate_cluster(None)?;
+        let mut buf = vec![0u8; self.cluster_size()];
+        let dot_parent = if parent_cluster == self.root_cluster { 0 } else { parent_cluster };
+        for (i, (n, c)) in [(*b".          ", cluster), (*b"..         ", dot_parent)].iter().enumerate() {
+            let e = &mut buf[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+            e[0..11].copy_from_slice(n);
+            e[11] = ATTR_DIRECTORY;
+            put16(e, 20, (c >> 16) as u16);
+            put16(e, 26, *c as u16);
+        }
+        self.write_cluster(cluster, &buf)?;
+
+        match self.create_entry(parent_cluster, name, ATTR_DIRECTORY, cluster) {
+            Ok(entry) => Ok(entry),
+            Err(e) => {
+                let _ = self.free_chain(cluster);
+                Err(e)
+            }
+        }
+    }
+
+    /// Write `data` at byte `offset` of `entry`, growing the file (and
+    /// allocating clusters) as needed. `entry` is updated in place.
+    pub fn write_file(&self, entry: &mut FatDirEntry, offset: u64, data: &[u8]) -> Result<usize, FatError> {
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(data.len() as u64).filter(|&e| e <= u32::MAX as u64).ok_or(FatError::NoSpace)?;
+        let _w = self.write_lock.lock();
+        let csize = self.cluster_size() as u64;
+
+        let mut chain = self.cluster_chain(entry.first_cluster)?;
+        let needed = ((end + csize - 1) / csize) as usize;
+        let old_first = entry.first_cluster;
+        while chain.len() < needed {
+            let c = self.allocate_cluster(chain.last().copied())?;
+            chain.push(c);
+        }
+        if old_first == 0 {
+            entry.first_cluster = chain[0];
+        }
+
+        let mut scratch = vec![0u8; csize as usize];
+        let mut done = 0usize;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let idx = (pos / csize) as usize;
+            let within = (pos % csize) as usize;
+            let n = core::cmp::min(data.len() - done, csize as usize - within);
+            if n < csize as usize {
+                self.read_cluster(chain[idx], &mut scratch)?;
+            }
+            scratch[within..within + n].copy_from_slice(&data[done..done + n]);
+            self.write_cluster(chain[idx], &scratch)?;
+            done += n;
+        }
+
+        // size last: the data and chain are in place before the entry claims them
+        if end > entry.size as u64 {
+            entry.size = end as u32;
+        }
+        self.update_dir_entry(entry)?;
+        Ok(done)
+    }
+
+    /// Append `data` to the file at `path`, creating it if missing.
+    pub fn append(&self, path: &str, data: &[u8]) -> Result<usize, FatError> {
+        let mut entry = match self.lookup(path) {
+            Ok(e) => e,
+            Err(FatError::NotFound) => self.create_file(path)?,
+            Err(e) => return Err(e),
+        };
+        let offset = entry.size as u64;
+        self.write_file(&mut entry, offset, data)
+    }
+
+    /// Shrink (or zero-extend) the file at `path` to `size` bytes.
+    pub fn truncate(&self, path: &str, size: u32) -> Result<(), FatError> {
+        let mut entry = self.lookup(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        if size > entry.size {
+            let zeros = vec![0u8; (size - entry.size) as usize];
+            let offset = entry.size as u64;
+            self.write_file(&mut entry, offset, &zeros)?;
+            return Ok(());
+        }
+        let _w = self.write_lock.lock();
+        // in u64: a size near 4 GiB would overflow rounding up
+        let keep = (size as u64).div_ceil(self.cluster_size() as u64) as usize;
+        let chain = self.cluster_chain(entry.first_cluster)?;
+
+        // the entry stops referencing the tail before the tail is freed
+        entry.size = size;
+        if keep == 0 {
+            entry.first_cluster = 0;
+        }
+        self.update_dir_entry(&entry)?;
+        if keep == 0 {
+            if let Some(&first) = chain.first() {
+                self.free_chain(first)?;
+            }
+        } else if keep < chain.len() {
+            self.set_fat_entry(chain[keep - 1], FAT_ENTRY_MASK)?;
+            self.free_chain(chain[keep])?;
+        }
+        Ok(())
+    }
+
+    /// Write a whole file, replacing any previous contents.
+    pub fn write_all(&self, path: &str, data: &[u8]) -> Result<(), FatError> {
+        match self.lookup(path) {
+            Ok(_) => self.truncate(path, 0)?,
+            Err(FatError::NotFound) => { self.create_file(path)?; }
+            Err(e) => return Err(e),
+        }
+        let mut entry = self.lookup(path)?;
+        self.write_file(&mut entry, 0, data)?;
+        Ok(())
+    }
+}
+
+impl From<FatError> for crate::fs::vfs::VfsError {
+    fn from(e: FatError) -> Self {
+        use crate::fs::vfs::VfsError;
+        match e {
+            FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+            FatError::InvalidPath | FatError::InvalidName => VfsError::InvalidPath,
+            FatError::NoSpace => VfsError::NoSpace,
+            FatError::Exists => VfsError::Exists,
+            FatError::Block(BlockError::ReadOnly) => VfsError::ReadOnly,
+            _ => VfsError::Io,
+        }
+    }
+}
+
+impl crate::fs::vfs::FileSystem for FatFs {
+    fn fs_type(&self) -> &str {
+        "fat32"
+    }
+
+    fn stat(&self, path: &str) -> Result<crate::fs::vfs::Metadata, crate::fs::vfs::VfsError> {
+        use crate::fs::vfs::{FileType, Metadata};
+        let e = self.lookup(path)?;
+        Ok(Metadata {
+            kind: if e.is_dir() { FileType::Directory } else { FileType::File },
+            size: e.size as u64,
+            read_only: e.attr & ATTR_READ_ONLY != 0 || self.queue.device().is_read_only(),
+        })
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, crate::fs::vfs::VfsError> {
+        let e = self.lookup(path)?;
+        Ok(self.read_file(&e, offset, buf)?)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<crate::fs::vfs::DirEntry>, crate::fs::vfs::VfsError> {
+        use crate::fs::vfs::{DirEntry, FileType};
+        Ok(self
+            .list_dir(path)?
+            .into_iter()
+            .map(|e| DirEntry {
+                kind: if e.is_dir() { FileType::Directory } else { FileType::File },
+                size: e.size as u64,
+                name: e.name,
+            })
+            .collect())
+    }
+
+    fn write(&self, path: &str, offset: u64, data: &[u8]) -> Result<usize, crate::fs::vfs::VfsError> {
+        let mut e = self.lookup(path)?;
+        Ok(self.write_file(&mut e, offset, data)?)
+    }
+
+    fn create(&self, path: &str) -> Result<(), crate::fs::vfs::VfsError> {
+        self.create_file(path)?;
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), crate::fs::vfs::VfsError> {
+        self.create_dir(path)?;
+        Ok(())
+    }
+
+    fn truncate(&self, path: &str, size: u64) -> Result<(), crate::fs::vfs::VfsError> {
+        let size = u32::try_from(size).map_err(|_| FatError::NoSpace)?;
+        Ok(FatFs::truncate(self, path, size)?)
+    }
+
+    fn remove(&self, _path: &str) -> Result<(), crate::fs::vfs::VfsError> {
+        Err(crate::fs::vfs::VfsError::Unsupported)
+    }
+
+    fn sync(&self) -> Result<(), crate::fs::vfs::VfsError> {
+        Ok(FatFs::sync(self)?)
+    }
+}
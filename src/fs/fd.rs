@@ -0,0 +1,194 @@
+//! File descriptor tables
+//!
+//! An `FdTable` maps small integers to open VFS files, sockets and
+//! character devices.
+//! Descriptors made by `dup`/`dup2` share one open file, and with it the
+//! offset, so redirection behaves as it does on Unix. The kernel has its own
+//! table; processes will get one each.
+
+use crate::prelude::*;
+use crate::fs::vfs::{self, File, FileType, Metadata, OpenOptions, SeekFrom, VfsError};
+use crate::devices::chardev::CharDevice;
+use crate::net::socket::Socket;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub type Fd = usize;
+
+/// Descriptors per table.
+pub const MAX_FDS: usize = 256;
+
+/// An open file shared by every descriptor that refers to it.
+pub type OpenFile = Arc<Mutex<File>>;
+
+/// What a descriptor refers to.
+#[derive(Clone)]
+pub enum FdObject {
+    File(OpenFile),
+    Socket(Arc<Socket>),
+    Char(Arc<dyn CharDevice>),
+}
+
+#[derive(Clone)]
+pub struct FdTable {
+    slots: Vec<Option<FdObject>>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { slots: Vec::new() }
+    }
+
+    /// Store `desc` in the lowest free descriptor.
+    pub fn insert(&mut self, desc: FdObject) -> Result<Fd, VfsError> {
+        let fd = (0..MAX_FDS)
+            .find(|&fd| self.slots.get(fd).is_none_or(|s| s.is_none()))
+            .ok_or(VfsError::TooManyFiles)?;
+        self.place(fd, desc);
+        Ok(fd)
+    }
+
+    fn place(&mut self, fd: Fd, desc: FdObject) {
+        if self.slots.len() <= fd {
+            self.slots.resize(fd + 1, None);
+        }
+        self.slots[fd] = Some(desc);
+    }
+
+    pub fn open(&mut self, path: &str, options: OpenOptions) -> Result<Fd, VfsError> {
+        let file = vfs::open(path, options)?;
+        self.insert(FdObject::File(Arc::new(Mutex::new(file))))
+    }
+
+    pub fn insert_socket(&mut self, socket: Socket) -> Result<Fd, VfsError> {
+        self.insert(FdObject::Socket(Arc::new(socket)))
+    }
+
+    pub fn insert_char(&mut self, dev: Arc<dyn CharDevice>) -> Result<Fd, VfsError> {
+        self.insert(FdObject::Char(dev))
+    }
+
+    /// Point descriptors 0, 1 and 2 at `dev`.
+    pub fn set_stdio(&mut self, dev: Arc<dyn CharDevice>) {
+        for fd in 0..3 {
+            self.place(fd, FdObject::Char(dev.clone()));
+        }
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<FdObject, VfsError> {
+        self.slots.get(fd).and_then(|s| s.clone()).ok_or(VfsError::BadFd)
+    }
+
+    /// The open file behind `fd`; sockets and devices are `Unsupported`.
+    pub fn file(&self, fd: Fd) -> Result<OpenFile, VfsError> {
+        match self.get(fd)? {
+            FdObject::File(f) => Ok(f),
+            _ => Err(VfsError::Unsupported),
+        }
+    }
+
+    pub fn socket(&self, fd: Fd) -> Result<Arc<Socket>, VfsError> {
+        match self.get(fd)? {
+            FdObject::Socket(s) => Ok(s),
+            _ => Err(VfsError::NotASocket),
+        }
+    }
+
+    /// Read from a file at its offset, take one queued datagram from a
+    /// socket without waiting (`WouldBlock` if there is none), or wait for
+    /// input from a device.
+    pub fn read(&self, fd: Fd, buf: &mut [u8]) -> Result<usize, VfsError> {
+        match self.get(fd)? {
+            FdObject::File(f) => f.lock().read(buf),
+            FdObject::Socket(s) => {
+                let (data, _, _) = s.try_recv_from()?;
+                let n = core::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            FdObject::Char(dev) => dev.read(buf),
+        }
+    }
+
+    /// Write to a file, or send to a connected socket's peer. Socket sends
+    /// are queued on a task and reported as complete.
+    pub fn write(&self, fd: Fd, data: &[u8]) -> Result<usize, VfsError> {
+        match self.get(fd)? {
+            FdObject::File(f) => f.lock().write(data),
+            FdObject::Socket(s) => {
+                if s.peer().is_none() {
+                    return Err(crate::net::NetError::NotConnected.into());
+                }
+                let payload = data.to_vec();
+                crate::arch::task::spawn_named("sockwr", async move {
+                    let _ = s.send(&payload).await;
+                });
+                Ok(data.len())
+            }
+            FdObject::Char(dev) => dev.write(data),
+        }
+    }
+
+    pub fn seek(&self, fd: Fd, pos: SeekFrom) -> Result<u64, VfsError> {
+        self.file(fd)?.lock().seek(pos)
+    }
+
+    pub fn stat(&self, fd: Fd) -> Result<Metadata, VfsError> {
+        match self.get(fd)? {
+            FdObject::File(f) => f.lock().stat(),
+            FdObject::Socket(_) => Ok(Metadata { kind: FileType::Socket, size: 0, read_only: false }),
+            FdObject::Char(_) => Ok(Metadata { kind: FileType::CharDevice, size: 0, read_only: false }),
+        }
+    }
+
+    pub fn close(&mut self, fd: Fd) -> Result<(), VfsError> {
+        let slot = self.slots.get_mut(fd).ok_or(VfsError::BadFd)?;
+        slot.take().ok_or(VfsError::BadFd)?;
+        while matches!(self.slots.last(), Some(None)) {
+            self.slots.pop();
+        }
+        Ok(())
+    }
+
+    /// New descriptor sharing `fd`'s open file.
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd, VfsError> {
+        let desc = self.get(fd)?;
+        self.insert(desc)
+    }
+
+    /// Make `new` refer to `old`'s open file, closing `new` first.
+    pub fn dup2(&mut self, old: Fd, new: Fd) -> Result<Fd, VfsError> {
+        let desc = self.get(old)?;
+        if new >= MAX_FDS {
+            return Err(VfsError::BadFd);
+        }
+        if old != new {
+            self.place(new, desc);
+        }
+        Ok(new)
+    }
+
+    /// Open descriptors in ascending order.
+    pub fn fds(&self) -> Vec<Fd> {
+        self.slots.iter().enumerate().filter(|(_, s)| s.is_some()).map(|(fd, _)| fd).collect()
+    }
+
+    /// Close every descriptor.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static KERNEL_FDS: Mutex<FdTable> = Mutex::new(FdTable::new());
+
+/// The descriptor table used by kernel code and the shell.
+pub fn kernel_fds() -> &'static Mutex<FdTable> {
+    &KERNEL_FDS
+}
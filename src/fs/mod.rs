@@ -0,0 +1,11 @@
+//! Filesystems.
+
+pub mod fat;
+pub use fat::*;
+pub mod vfs;
+pub mod tarfs;
+pub use tarfs::*;
+pub mod ramfs;
+pub use ramfs::*;
+pub mod fd;
+pub use fd::*;
@@ -0,0 +1,171 @@
+//! Heap-backed writable filesystem
+//!
+//! Every file lives in a `Vec<u8>` in a flat path-indexed map, the same
+//! layout tarfs uses. Contents vanish on reboot; main mounts one at `/tmp`.
+
+use crate::prelude::*;
+use crate::fs::vfs::{self, DirEntry, FileSystem, FileType, Metadata, VfsError};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+struct RamNode {
+    kind: FileType,
+    data: Vec<u8>,
+}
+
+pub struct RamFs {
+    /// Normalized absolute path -> node. "/" is always present.
+    nodes: Mutex<BTreeMap<String, RamNode>>,
+    /// Upper bound on total file bytes; 0 means unlimited.
+    limit: usize,
+}
+
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        Self::with_limit(0)
+    }
+
+    /// A ramfs refusing writes that would take it past `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), RamNode { kind: FileType::Directory, data: Vec::new() });
+        RamFs { nodes: Mutex::new(nodes), limit }
+    }
+
+    /// Total bytes held in files.
+    pub fn used_bytes(&self) -> usize {
+        self.nodes.lock().values().map(|n| n.data.len()).sum()
+    }
+
+    fn check_grow(&self, nodes: &BTreeMap<String, RamNode>, extra: usize) -> Result<(), VfsError> {
+        if self.limit != 0 && nodes.values().map(|n| n.data.len()).sum::<usize>() + extra > self.limit {
+            return Err(VfsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, path: &str, kind: FileType) -> Result<(), VfsError> {
+        let path = vfs::normalize(path)?;
+        let mut nodes = self.nodes.lock();
+        if nodes.contains_key(&path) {
+            return Err(VfsError::Exists);
+        }
+        match nodes.get(parent_of(&path)) {
+            Some(p) if p.kind == FileType::Directory => {}
+            Some(_) => return Err(VfsError::NotADirectory),
+            None => return Err(VfsError::NotFound),
+        }
+        nodes.insert(path, RamNode { kind, data: Vec::new() });
+        Ok(())
+    }
+}
+
+impl FileSystem for RamFs {
+    fn fs_type(&self) -> &str {
+        "ramfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, VfsError> {
+        let path = vfs::normalize(path)?;
+        let nodes = self.nodes.lock();
+        let n = nodes.get(&path).ok_or(VfsError::NotFound)?;
+        Ok(Metadata { kind: n.kind, size: n.data.len() as u64, read_only: false })
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let path = vfs::normalize(path)?;
+        let nodes = self.nodes.lock();
+        let n = nodes.get(&path).ok_or(VfsError::NotFound)?;
+        if n.kind == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if offset >= n.data.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = core::cmp::min(buf.len(), n.data.len() - start);
+        buf[..len].copy_from_slice(&n.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let dir = vfs::normalize(path)?;
+        let nodes = self.nodes.lock();
+        match nodes.get(&dir) {
+            Some(n) if n.kind == FileType::Directory => {}
+            Some(_) => return Err(VfsError::NotADirectory),
+            None => return Err(VfsError::NotFound),
+        }
+        let prefix = if dir == "/" { dir.clone() } else { alloc::format!("{}/", dir) };
+        Ok(nodes
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter(|(p, _)| p.len() > prefix.len() && !p[prefix.len()..].contains('/'))
+            .map(|(p, n)| DirEntry { name: p[prefix.len()..].to_string(), kind: n.kind, size: n.data.len() as u64 })
+            .collect())
+    }
+
+    fn write(&self, path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let path = vfs::normalize(path)?;
+        let end = usize::try_from(offset).ok().and_then(|o| o.checked_add(data.len())).ok_or(VfsError::NoSpace)?;
+        let mut nodes = self.nodes.lock();
+        let cur = match nodes.get(&path) {
+            Some(n) if n.kind == FileType::Directory => return Err(VfsError::IsADirectory),
+            Some(n) => n.data.len(),
+            None => return Err(VfsError::NotFound),
+        };
+        self.check_grow(&nodes, end.saturating_sub(cur))?;
+        let n = nodes.get_mut(&path).unwrap();
+        if end > n.data.len() {
+            n.data.resize(end, 0);
+        }
+        n.data[offset as usize..end].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn create(&self, path: &str) -> Result<(), VfsError> {
+        self.insert(path, FileType::File)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), VfsError> {
+        self.insert(path, FileType::Directory)
+    }
+
+    fn truncate(&self, path: &str, size: u64) -> Result<(), VfsError> {
+        let path = vfs::normalize(path)?;
+        let size = usize::try_from(size).map_err(|_| VfsError::NoSpace)?;
+        let mut nodes = self.nodes.lock();
+        let cur = match nodes.get(&path) {
+            Some(n) if n.kind == FileType::Directory => return Err(VfsError::IsADirectory),
+            Some(n) => n.data.len(),
+            None => return Err(VfsError::NotFound),
+        };
+        self.check_grow(&nodes, size.saturating_sub(cur))?;
+        let n = nodes.get_mut(&path).unwrap();
+        n.data.resize(size, 0);
+        n.data.shrink_to_fit();
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        let path = vfs::normalize(path)?;
+        if path == "/" {
+            return Err(VfsError::InvalidPath);
+        }
+        let mut nodes = self.nodes.lock();
+        let prefix = alloc::format!("{}/", path);
+        if nodes.range(prefix.clone()..).next().is_some_and(|(p, _)| p.starts_with(&prefix)) {
+            return Err(VfsError::NotEmpty);
+        }
+        nodes.remove(&path).map(|_| ()).ok_or(VfsError::NotFound)
+    }
+}
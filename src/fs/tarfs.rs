@@ -0,0 +1,217 @@
+//! Read-only filesystem over a ustar archive (the initrd)
+//!
+//! The archive is read where it lies, never copied to the heap, which is
+//! much smaller than an initrd: a boot module in place, an fw_cfg image in
+//! memory mapped for it. Parsing builds an index of paths to (offset,
+//! size) and synthesizes any parent directories the archive leaves out. `mount_initrd` finds an image in QEMU fw_cfg or among the
+//! loader's boot modules and mounts it at `/`.
+
+use crate::prelude::*;
+use crate::fs::vfs::{self, DirEntry, FileSystem, FileType, Metadata, VfsError};
+use crate::rlib::cstr::cstr;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const BLOCK: usize = 512;
+
+/// fw_cfg file name checked before the `-initrd` blob.
+pub const INITRD_FW_CFG_NAME: &str = "opt/neutrix/initrd";
+
+#[derive(Debug, Clone, Copy)]
+struct TarNode {
+    kind: FileType,
+    /// Byte offset of the file data in the archive.
+    offset: usize,
+    size: usize,
+}
+
+pub struct TarFs {
+    data: &'static [u8],
+    /// Normalized absolute path -> node. "/" is always present.
+    nodes: BTreeMap<String, TarNode>,
+}
+
+fn octal(field: &[u8]) -> Option<usize> {
+    let mut v = 0usize;
+    for &c in field {
+        match c {
+            b'0'..=b'7' => v = v.checked_mul(8)?.checked_add((c - b'0') as usize)?,
+            b' ' | 0 => {
+                if v != 0 {
+                    break;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(v)
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let stored = match octal(&header[148..156]) {
+        Some(v) => v,
+        None => return false,
+    };
+    let sum: usize = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as usize } else { b as usize })
+        .sum();
+    sum == stored
+}
+
+impl TarFs {
+    /// Index a ustar archive. Entries other than regular files and
+    /// directories (links, devices) are skipped.
+    pub fn parse(data: &'static [u8]) -> Result<TarFs, VfsError> {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), TarNode { kind: FileType::Directory, offset: 0, size: 0 });
+        let mut pos = 0;
+        while pos + BLOCK <= data.len() {
+            let h = &data[pos..pos + BLOCK];
+            if h.iter().all(|&b| b == 0) {
+                break; // end-of-archive marker
+            }
+            if !checksum_ok(h) {
+                println!("[TARFS] bad header checksum at offset {}", pos);
+                return Err(VfsError::Io);
+            }
+            let size = octal(&h[124..136]).ok_or(VfsError::Io)?;
+            let mut name = String::new();
+            if &h[257..262] == b"ustar" && h[345] != 0 {
+                name.push_str(cstr(&h[345..500]));
+                name.push('/');
+            }
+            name.push_str(cstr(&h[0..100]));
+            let data_off = pos + BLOCK;
+            if data_off + size > data.len() {
+                return Err(VfsError::Io);
+            }
+
+            let kind = match h[156] {
+                b'0' | 0 | b'7' => Some(FileType::File),
+                b'5' => Some(FileType::Directory),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                let path = vfs::normalize(&alloc::format!("/{}", name))?;
+                if path != "/" {
+                    Self::add_parents(&mut nodes, &path);
+                    nodes.insert(path, TarNode { kind, offset: data_off, size });
+                }
+            }
+            pos = data_off + (size + BLOCK - 1) / BLOCK * BLOCK;
+        }
+        Ok(TarFs { data, nodes })
+    }
+
+    fn add_parents(nodes: &mut BTreeMap<String, TarNode>, path: &str) {
+        let mut end = path.rfind('/').unwrap_or(0);
+        while end > 0 {
+            let parent = &path[..end];
+            nodes.entry(parent.to_string()).or_insert(TarNode { kind: FileType::Directory, offset: 0, size: 0 });
+            end = parent.rfind('/').unwrap_or(0);
+        }
+    }
+
+    fn node(&self, path: &str) -> Result<TarNode, VfsError> {
+        let path = vfs::normalize(path)?;
+        self.nodes.get(&path).copied().ok_or(VfsError::NotFound)
+    }
+
+    /// Number of files and directories in the archive.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Size of the backing archive in bytes.
+    pub fn image_size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl FileSystem for TarFs {
+    fn fs_type(&self) -> &str {
+        "tarfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, VfsError> {
+        let n = self.node(path)?;
+        Ok(Metadata { kind: n.kind, size: n.size as u64, read_only: true })
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let n = self.node(path)?;
+        if n.kind == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if offset >= n.size as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = core::cmp::min(buf.len(), n.size - start);
+        buf[..len].copy_from_slice(&self.data[n.offset + start..n.offset + start + len]);
+        Ok(len)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let dir = vfs::normalize(path)?;
+        if self.node(&dir)?.kind != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let prefix = if dir == "/" { dir.clone() } else { alloc::format!("{}/", dir) };
+        Ok(self
+            .nodes
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter(|(p, _)| p.len() > prefix.len() && !p[prefix.len()..].contains('/'))
+            .map(|(p, n)| DirEntry { name: p[prefix.len()..].to_string(), kind: n.kind, size: n.size as u64 })
+            .collect())
+    }
+}
+
+/// Where the mounted initrd came from, for diagnostics.
+static INITRD_SOURCE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Parse `image` and mount it at `/`. Boot code that receives an initrd
+/// from the bootloader hands it over here.
+pub fn load_initrd(image: &'static [u8], source: &'static str) -> Result<(), VfsError> {
+    let size = image.len();
+    let fs = TarFs::parse(image)?;
+    println!("[INITRD] {} bytes from {}, {} entries", size, source, fs.len());
+    vfs::mount("/", Arc::new(fs))?;
+    *INITRD_SOURCE.lock() = Some(source);
+    Ok(())
+}
+
+/// Look for an initrd in fw_cfg (a named file, then `-initrd`), then in
+/// the first boot module, and mount it. Returns false when none is found or it fails to parse.
+pub fn mount_initrd() -> bool {
+    if INITRD_SOURCE.lock().is_some() {
+        return true;
+    }
+    let found = crate::devices::fw_cfg::read_file_mapped(INITRD_FW_CFG_NAME)
+        .map(|img| (img, "fw_cfg file"))
+        .or_else(|| crate::devices::fw_cfg::read_initrd().map(|img| (img, "fw_cfg initrd")))
+        .or_else(|| crate::boot::boot_module(0).map(|img| (img, "boot module")));
+    match found {
+        Some((image, source)) => match load_initrd(image, source) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("[INITRD] failed to load: {}", e);
+                false
+            }
+        },
+        None => {
+            println!("[INITRD] no initrd found");
+            false
+        }
+    }
+}
+
+pub fn initrd_source() -> Option<&'static str> {
+    *INITRD_SOURCE.lock()
+}
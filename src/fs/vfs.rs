@@ -0,0 +1,494 @@
+//! Virtual filesystem layer
+//!
+//! Filesystems implement `FileSystem` and are attached to the global mount
+//! table with `mount("/boot", fs)`. Paths are normalized ("." and ".." are
+//! resolved lexically) and routed to the filesystem with the longest
+//! matching mount point; the filesystem sees a path relative to its root.
+//!
+//! `open` returns a `File` handle carrying its own offset; `open_dir` and
+//! `read_dir` list directories, including mount points that sit below the
+//! listed directory. The trait is path-based, so filesystems don't have to
+//! keep per-handle state.
+
+use crate::prelude::*;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    /// A directory can't be removed while it has entries.
+    NotEmpty,
+    InvalidPath,
+    ReadOnly,
+    NoSpace,
+    /// Nothing is mounted at (or above) the path.
+    NotMounted,
+    /// A filesystem is already mounted at the path.
+    AlreadyMounted,
+    /// The file wasn't opened with the access the operation needs.
+    BadMode,
+    /// The descriptor isn't open.
+    BadFd,
+    /// The descriptor table is full.
+    TooManyFiles,
+    /// A socket operation on a descriptor that isn't a socket.
+    NotASocket,
+    /// Nothing to read yet and the caller asked not to wait.
+    WouldBlock,
+    /// A blocking read was cut short by a signal.
+    Interrupted,
+    Unsupported,
+    Io,
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            VfsError::NotFound => "no such file or directory",
+            VfsError::NotADirectory => "not a directory",
+            VfsError::IsADirectory => "is a directory",
+            VfsError::Exists => "file exists",
+            VfsError::NotEmpty => "directory not empty",
+            VfsError::InvalidPath => "invalid path",
+            VfsError::ReadOnly => "read-only filesystem",
+            VfsError::NoSpace => "no space left on device",
+            VfsError::NotMounted => "no filesystem mounted",
+            VfsError::AlreadyMounted => "mount point busy",
+            VfsError::BadMode => "bad file mode",
+            VfsError::BadFd => "bad file descriptor",
+            VfsError::TooManyFiles => "too many open files",
+            VfsError::NotASocket => "not a socket",
+            VfsError::WouldBlock => "operation would block",
+            VfsError::Interrupted => "interrupted",
+            VfsError::Unsupported => "operation not supported",
+            VfsError::Io => "I/O error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    /// Only reported for socket descriptors.
+    Socket,
+    /// Only reported for character device descriptors.
+    CharDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    pub size: u64,
+    pub read_only: bool,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+    pub size: u64,
+}
+
+/// A mountable filesystem. Paths are relative to the filesystem root and
+/// always start with '/'. Write operations default to `ReadOnly`.
+pub trait FileSystem: Send + Sync {
+    /// Short type name ("fat32", "ramfs", ...).
+    fn fs_type(&self) -> &str;
+
+    fn stat(&self, path: &str) -> Result<Metadata, VfsError>;
+
+    /// Read from the file at `path` at byte `offset`; returns bytes read.
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError>;
+
+    /// Write to the file at `path` at byte `offset`, growing it as needed.
+    fn write(&self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Create an empty file.
+    fn create(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn mkdir(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn truncate(&self, _path: &str, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Delete a file or an empty directory.
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Write back any cached state.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    /// Normalized absolute path ("/", "/boot").
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Normalize `path` into an absolute path without ".", ".." or repeated
+/// slashes. ".." at the root stays at the root.
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop(); }
+            p => parts.push(p),
+        }
+    }
+    let mut out = String::new();
+    for p in parts {
+        out.push('/');
+        out.push_str(p);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    Ok(out)
+}
+
+/// `prefix` is `path` or one of its ancestor directories.
+fn is_under(path: &str, prefix: &str) -> bool {
+    prefix == "/" || path == prefix || (path.starts_with(prefix) && path.as_bytes()[prefix.len()] == b'/')
+}
+
+/// Find the filesystem serving `path` and the path relative to its root.
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String), VfsError> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+    let m = mounts
+        .iter()
+        .filter(|m| is_under(&path, &m.path))
+        .max_by_key(|m| m.path.len())
+        .ok_or(VfsError::NotMounted)?;
+    let rel = if m.path == "/" { path.clone() } else { path[m.path.len()..].to_string() };
+    let rel = if rel.is_empty() { String::from("/") } else { rel };
+    Ok((m.fs.clone(), rel))
+}
+
+/// Attach `fs` at `path`. Mounting over a directory hides its contents.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    {
+        let mounts = MOUNTS.lock();
+        if mounts.iter().any(|m| m.path == path) {
+            return Err(VfsError::AlreadyMounted);
+        }
+    }
+    // anything but the first (root) mount needs a directory to sit on
+    if path != "/" && !MOUNTS.lock().is_empty() {
+        match stat(&path) {
+            Ok(m) if !m.is_dir() => return Err(VfsError::NotADirectory),
+            Ok(_) | Err(VfsError::NotFound) | Err(VfsError::NotMounted) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    println!("[VFS] mounted {} at {}", fs.fs_type(), path);
+    MOUNTS.lock().push(Mount { path, fs });
+    Ok(())
+}
+
+/// Detach the filesystem at `path`, syncing it first.
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let fs = {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|m| m.path != path && is_under(&m.path, &path)) {
+            return Err(VfsError::AlreadyMounted);
+        }
+        let idx = mounts.iter().position(|m| m.path == path).ok_or(VfsError::NotMounted)?;
+        mounts.remove(idx).fs
+    };
+    fs.sync()
+}
+
+/// (mount point, filesystem type) for every mount.
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS.lock().iter().map(|m| (m.path.clone(), m.fs.fs_type().to_string())).collect()
+}
+
+/// Sync every mounted filesystem; returns the first error.
+pub fn sync_all() -> Result<(), VfsError> {
+    let all: Vec<Arc<dyn FileSystem>> = MOUNTS.lock().iter().map(|m| m.fs.clone()).collect();
+    let mut result = Ok(());
+    for fs in all {
+        if let Err(e) = fs.sync() {
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+pub fn stat(path: &str) -> Result<Metadata, VfsError> {
+    let (fs, rel) = resolve(path)?;
+    fs.stat(&rel)
+}
+
+/// How to open a file. `OpenOptions::read()` is the common case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Create the file if it doesn't exist.
+    pub create: bool,
+    /// Truncate to zero length on open.
+    pub truncate: bool,
+    /// Every write goes to the end of the file.
+    pub append: bool,
+}
+
+impl OpenOptions {
+    pub const fn read() -> Self {
+        OpenOptions { read: true, write: false, create: false, truncate: false, append: false }
+    }
+
+    /// Read/write, creating the file if needed.
+    pub const fn write() -> Self {
+        OpenOptions { read: true, write: true, create: true, truncate: false, append: false }
+    }
+
+    pub const fn append() -> Self {
+        OpenOptions { read: true, write: true, create: true, truncate: false, append: true }
+    }
+
+    pub const fn truncate(mut self) -> Self {
+        self.truncate = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file: a filesystem, a path within it and a cursor.
+pub struct File {
+    fs: Arc<dyn FileSystem>,
+    path: String,
+    offset: u64,
+    options: OpenOptions,
+}
+
+/// Open the file at `path`.
+pub fn open(path: &str, options: OpenOptions) -> Result<File, VfsError> {
+    let (fs, rel) = resolve(path)?;
+    if (options.create || options.truncate || options.append) && !options.write {
+        return Err(VfsError::BadMode);
+    }
+    let meta = match fs.stat(&rel) {
+        Ok(m) => m,
+        Err(VfsError::NotFound) if options.create => {
+            fs.create(&rel)?;
+            fs.stat(&rel)?
+        }
+        Err(e) => return Err(e),
+    };
+    if meta.is_dir() {
+        return Err(VfsError::IsADirectory);
+    }
+    if options.write && meta.read_only {
+        return Err(VfsError::ReadOnly);
+    }
+    if options.truncate && meta.size != 0 {
+        fs.truncate(&rel, 0)?;
+    }
+    Ok(File { fs, path: rel, offset: 0, options })
+}
+
+impl File {
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.options.read {
+            return Err(VfsError::BadMode);
+        }
+        let n = self.fs.read(&self.path, self.offset, buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, VfsError> {
+        if !self.options.write {
+            return Err(VfsError::BadMode);
+        }
+        if self.options.append {
+            self.offset = self.size()?;
+        }
+        let n = self.fs.write(&self.path, self.offset, data)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    /// Read from the cursor to the end of the file.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let size = self.size()?;
+        let mut data = alloc::vec![0u8; size.saturating_sub(self.offset) as usize];
+        let mut done = 0;
+        while done < data.len() {
+            let n = self.read(&mut data[done..])?;
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        data.truncate(done);
+        Ok(data)
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsError> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.size()?.checked_add_signed(d),
+            SeekFrom::Current(d) => self.offset.checked_add_signed(d),
+        };
+        self.offset = target.ok_or(VfsError::InvalidPath)?;
+        Ok(self.offset)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn stat(&self) -> Result<Metadata, VfsError> {
+        self.fs.stat(&self.path)
+    }
+
+    pub fn size(&self) -> Result<u64, VfsError> {
+        Ok(self.stat()?.size)
+    }
+
+    pub fn set_len(&mut self, size: u64) -> Result<(), VfsError> {
+        if !self.options.write {
+            return Err(VfsError::BadMode);
+        }
+        self.fs.truncate(&self.path, size)
+    }
+
+    pub fn sync(&self) -> Result<(), VfsError> {
+        self.fs.sync()
+    }
+}
+
+/// A directory listing taken when the directory was opened.
+pub struct Dir {
+    entries: Vec<DirEntry>,
+    pos: usize,
+}
+
+/// Open the directory at `path` for iteration.
+pub fn open_dir(path: &str) -> Result<Dir, VfsError> {
+    Ok(Dir { entries: read_dir(path)?, pos: 0 })
+}
+
+impl Dir {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+    }
+}
+
+impl Iterator for Dir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        let e = self.entries.get(self.pos).cloned();
+        self.pos += 1;
+        e
+    }
+}
+
+/// List the directory at `path`. Mount points directly below it are
+/// included even if the underlying directory doesn't exist.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let path = normalize(path)?;
+    let (fs, rel) = resolve(&path)?;
+    let mut entries = fs.read_dir(&rel)?;
+    for (mp, _) in mounts() {
+        if mp == path || !is_under(&mp, &path) {
+            continue;
+        }
+        let rest = if path == "/" { &mp[1..] } else { &mp[path.len() + 1..] };
+        if rest.contains('/') {
+            continue;
+        }
+        if !entries.iter().any(|e| e.name == rest) {
+            entries.push(DirEntry { name: rest.to_string(), kind: FileType::Directory, size: 0 });
+        }
+    }
+    Ok(entries)
+}
+
+/// Read a whole file.
+pub fn read_file(path: &str) -> Result<Vec<u8>, VfsError> {
+    open(path, OpenOptions::read())?.read_to_end()
+}
+
+/// Replace the contents of `path`, creating it if needed.
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), VfsError> {
+    let mut f = open(path, OpenOptions::write().truncate())?;
+    let mut done = 0;
+    while done < data.len() {
+        match f.write(&data[done..])? {
+            0 => return Err(VfsError::NoSpace),
+            n => done += n,
+        }
+    }
+    Ok(())
+}
+
+pub fn mkdir(path: &str) -> Result<(), VfsError> {
+    let (fs, rel) = resolve(path)?;
+    if rel == "/" {
+        return Err(VfsError::Exists);
+    }
+    fs.mkdir(&rel)
+}
+
+/// Delete a file or an empty directory. Mount points can't be removed.
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let norm = normalize(path)?;
+    if MOUNTS.lock().iter().any(|m| m.path == norm) {
+        return Err(VfsError::AlreadyMounted);
+    }
+    let (fs, rel) = resolve(&norm)?;
+    fs.remove(&rel)
+}
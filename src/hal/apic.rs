@@ -4,19 +4,79 @@
 //! reads the Local APIC base address from the MADT (via ACPI) when available
 //! and provides basic operations: init, enable, send EOI, and read ID.
 
-use crate::*;
+use crate::prelude::*;
 use x86_64::VirtAddr;
 use core::ptr::{read_volatile, write_volatile};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
 
 // Offsets for some Local APIC registers (relative to the LAPIC base)
 const LAPIC_ID: usize = 0x20;
 const LAPIC_EOI: usize = 0xB0;
 const LAPIC_SVR: usize = 0xF0;
 const LAPIC_SVR_APIC_ENABLE: u32 = 0x100;
+/// SVR bit 12: EOIs for level-triggered vectors are not broadcast to the IOAPICs.
+const LAPIC_SVR_SUPPRESS_EOI_BROADCAST: u32 = 1 << 12;
+const LAPIC_VERSION: usize = 0x30;
+/// Version register bit 24: the SVR suppress-EOI-broadcast bit is implemented.
+const LAPIC_VERSION_EOI_SUPPRESSIBLE: u32 = 1 << 24;
+const LAPIC_ESR: usize = 0x280;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_THERMAL: usize = 0x330;
+const LAPIC_LVT_PERF: usize = 0x340;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LVT_MASKED: u32 = 1 << 16;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+/// Divide configuration value for dividing the bus clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// Vector the local APIC delivers spurious interrupts on. Its low four
+/// bits must be set on the oldest APICs, which hardwire them.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector of the LVT error entry.
+pub const ERROR_VECTOR: u8 = 0xFE;
+/// Vector of the LVT thermal sensor entry.
+pub const THERMAL_VECTOR: u8 = 0xFA;
+
+/// ESR bits, lowest first.
+const ESR_ERRORS: [&str; 8] = [
+    "send checksum",
+    "receive checksum",
+    "send accept",
+    "receive accept",
+    "redirectable IPI",
+    "send illegal vector",
+    "received illegal vector",
+    "illegal register address",
+];
+
+const IA32_THERM_INTERRUPT: u32 = 0x19B;
+const IA32_THERM_STATUS: u32 = 0x19C;
+/// IA32_THERM_INTERRUPT: interrupt on crossing the high and low temperature
+/// thresholds, and on PROCHOT# asserted by another agent.
+const THERM_INTERRUPT_ENABLES: u64 = 0b111;
+/// IA32_THERM_STATUS sticky log bits: thermal status, PROCHOT#, critical
+/// temperature, threshold 1 and 2, power limit. Written 0 to clear.
+const THERM_STATUS_LOGS: u64 = (1 << 1) | (1 << 3) | (1 << 5) | (1 << 7) | (1 << 9) | (1 << 11);
+
+static APIC_ERRORS: AtomicU64 = AtomicU64::new(0);
+static THERMAL_EVENTS: AtomicU64 = AtomicU64::new(0);
 
 // Store LAPIC base as an atomic usize (0 == not initialized)
 static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+/// Set once the BSP's LAPIC stopped broadcasting EOIs, so the APs' match.
+static EOI_BROADCAST_SUPPRESSED: AtomicBool = AtomicBool::new(false);
 
 /// Initialize Local APIC using ACPI-provided MADT address (phys_offset is required to map)
 pub fn init_from_acpi(phys_offset: VirtAddr) -> bool {
@@ -38,16 +98,54 @@ fn init_lapic_phys(phys_addr: u64, phys_offset: VirtAddr) -> bool {
         // store pointer as usize atomically
         LAPIC_BASE.store(virt as usize, Ordering::SeqCst);
 
-        // Enable APIC by setting Spurious Interrupt Vector Register's APIC enable bit
-        let svr_addr = virt.add(LAPIC_SVR) as *mut u32;
-        let mut svr = read_volatile(svr_addr);
-        svr |= LAPIC_SVR_APIC_ENABLE;
-        write_volatile(svr_addr, svr);
+        // Enable APIC by setting Spurious Interrupt Vector Register's APIC
+        // enable bit, with spurious interrupts on a vector we handle
+        crate::arch::idt::register_irq_handler(SPURIOUS_VECTOR, spurious_interrupt);
     }
+    enable_local_apic();
     println!("[HAL][APIC] Local APIC initialized at phys 0x{:x}", phys_addr);
     true
 }
 
+/// Software-enable the calling CPU's local APIC, spurious interrupts going
+/// to `SPURIOUS_VECTOR`.
+fn enable_local_apic() {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    unsafe {
+        let svr_addr = (base_usize as *mut u8).add(LAPIC_SVR) as *mut u32;
+        let mut svr = read_volatile(svr_addr) & !0xFF;
+        svr |= LAPIC_SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32;
+        if EOI_BROADCAST_SUPPRESSED.load(Ordering::SeqCst) {
+            svr |= LAPIC_SVR_SUPPRESS_EOI_BROADCAST;
+        }
+        write_volatile(svr_addr, svr);
+    }
+}
+
+/// Set up an application processor's local APIC the way `init_from_acpi`
+/// and `init_lvt` set up the BSP's: enabled, with the same LVT entries.
+/// The LAPIC sits at the same address on every CPU.
+pub fn init_ap_lapic() {
+    enable_local_apic();
+    program_lvt();
+}
+
+/// Spurious interrupts aren't in service, so they get no EOI: one would
+/// retire whatever interrupt really is. They are only counted.
+extern "x86-interrupt" fn spurious_interrupt(stack_frame: InterruptStackFrame) {
+    let _gs = crate::arch::percpu::kernel_gs(&stack_frame);
+    crate::arch::idt::note_irq(SPURIOUS_VECTOR);
+    crate::arch::idt::irq_exit(&stack_frame);
+}
+
+/// Spurious interrupts seen since boot, on every CPU.
+pub fn spurious_count() -> u64 {
+    crate::arch::idt::irq_count(SPURIOUS_VECTOR)
+}
+
 /// Send End Of Interrupt to the local APIC
 pub fn send_eoi() {
     // Load the base pointer atomically
@@ -62,6 +160,233 @@ pub fn send_eoi() {
     }
 }
 
+/// Stop the local APIC broadcasting EOIs for level-triggered vectors to the
+/// IOAPICs, which the caller then has to EOI itself. False if the local
+/// APIC can't, or isn't initialized.
+pub fn suppress_eoi_broadcast() -> bool {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return false;
+    }
+    unsafe {
+        let base = base_usize as *mut u8;
+        if read_volatile(base.add(LAPIC_VERSION) as *const u32) & LAPIC_VERSION_EOI_SUPPRESSIBLE == 0 {
+            return false;
+        }
+        let svr = base.add(LAPIC_SVR) as *mut u32;
+        write_volatile(svr, read_volatile(svr) | LAPIC_SVR_SUPPRESS_EOI_BROADCAST);
+    }
+    EOI_BROADCAST_SUPPRESSED.store(true, Ordering::SeqCst);
+    true
+}
+
+/// Replace the firmware's LVT error, thermal and performance-counter
+/// entries, which may point at vectors nothing handles, with our own:
+/// APIC errors go to `ERROR_VECTOR` and are logged with the ESR bits,
+/// thermal events to `THERMAL_VECTOR` and are logged with the thermal
+/// status, and the PMI entry is pointed at `pmu::PMI_VECTOR`, masked until
+/// sampling starts. Entries the LAPIC lacks (its max LVT index says which)
+/// are left alone.
+pub fn init_lvt() {
+    match program_lvt() {
+        Some(true) => println!("[HAL][APIC] LVT error -> {:#x}, thermal -> {:#x}", ERROR_VECTOR, THERMAL_VECTOR),
+        Some(false) => println!("[HAL][APIC] LVT error -> {:#x}", ERROR_VECTOR),
+        None => {}
+    }
+}
+
+/// Program the calling CPU's LVT entries for `init_lvt`. Whether the
+/// thermal entry was set up, `None` before the LAPIC is initialized.
+fn program_lvt() -> Option<bool> {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return None;
+    }
+    let base = base_usize as *mut u8;
+    let max_lvt = unsafe { read_volatile(base.add(LAPIC_VERSION) as *const u32) } >> 16 & 0xFF;
+    crate::arch::idt::register_irq_fn(ERROR_VECTOR, apic_error);
+    unsafe {
+        // clear errors latched before now: the ESR is rearmed by a write
+        write_volatile(base.add(LAPIC_ESR) as *mut u32, 0);
+        write_volatile(base.add(LAPIC_LVT_ERROR) as *mut u32, ERROR_VECTOR as u32);
+        write_volatile(base.add(LAPIC_ESR) as *mut u32, 0);
+    }
+    if max_lvt >= 4 {
+        set_lvt_perf(crate::arch::pmu::PMI_VECTOR, true);
+    }
+    // IA32_THERM_* exist when CPUID.1:EDX.ACPI is set
+    let thermal_msrs = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 22) != 0;
+    if max_lvt >= 5 && thermal_msrs {
+        crate::arch::idt::register_irq_fn(THERMAL_VECTOR, thermal_event);
+        unsafe {
+            let mut status = Msr::new(IA32_THERM_STATUS);
+            status.write(status.read() & !THERM_STATUS_LOGS);
+            let mut enables = Msr::new(IA32_THERM_INTERRUPT);
+            enables.write(enables.read() | THERM_INTERRUPT_ENABLES);
+            write_volatile(base.add(LAPIC_LVT_THERMAL) as *mut u32, THERMAL_VECTOR as u32);
+        }
+    }
+    Some(max_lvt >= 5 && thermal_msrs)
+}
+
+/// LVT error handler: latch and log the ESR.
+fn apic_error(_vector: u8) {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    let esr = unsafe {
+        let esr = (base_usize as *mut u8).add(LAPIC_ESR) as *mut u32;
+        write_volatile(esr, 0);
+        read_volatile(esr)
+    };
+    APIC_ERRORS.fetch_add(1, Ordering::Relaxed);
+    print!("[HAL][APIC] error on APIC {}: ESR {:#x}", local_apic_id().unwrap_or(0), esr);
+    for (bit, name) in ESR_ERRORS.iter().enumerate() {
+        if esr & (1 << bit) != 0 {
+            print!(" ({})", name);
+        }
+    }
+    println!();
+}
+
+/// LVT thermal handler: log the thermal status and clear its log bits.
+fn thermal_event(_vector: u8) {
+    let status = unsafe {
+        let mut msr = Msr::new(IA32_THERM_STATUS);
+        let status = msr.read();
+        msr.write(status & !THERM_STATUS_LOGS);
+        status
+    };
+    THERMAL_EVENTS.fetch_add(1, Ordering::Relaxed);
+    // bits 16-22: degrees below the TCC activation temperature
+    let margin = status >> 16 & 0x7F;
+    println!(
+        "[HAL][APIC] thermal event: status {:#x}, {} C below TjMax{}{}",
+        status,
+        margin,
+        if status & 1 != 0 { ", throttling" } else { "" },
+        if status & (1 << 4) != 0 { ", CRITICAL" } else { "" }
+    );
+}
+
+/// APIC errors and thermal events seen since boot.
+pub fn lvt_event_counts() -> (u64, u64) {
+    (APIC_ERRORS.load(Ordering::Relaxed), THERMAL_EVENTS.load(Ordering::Relaxed))
+}
+
+/// Route performance-counter overflow (PMI) to `vector`, or mask it.
+pub fn set_lvt_perf(vector: u8, masked: bool) {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    let value = vector as u32 | if masked { LVT_MASKED } else { 0 };
+    unsafe { write_volatile((base_usize as *mut u8).add(LAPIC_LVT_PERF) as *mut u32, value) };
+}
+
+/// LAPIC timer modes (LVT timer bits 17-18).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1 << 17,
+    TscDeadline = 2 << 17,
+}
+
+/// Route the LAPIC timer to `vector` in `mode`, or mask it. The counter
+/// modes count the bus clock divided by 16.
+pub fn set_lvt_timer(vector: u8, mode: TimerMode, masked: bool) {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    let base = base_usize as *mut u8;
+    let value = vector as u32 | mode as u32 | if masked { LVT_MASKED } else { 0 };
+    unsafe {
+        write_volatile(base.add(LAPIC_TIMER_DIVIDE) as *mut u32, TIMER_DIVIDE_16);
+        write_volatile(base.add(LAPIC_LVT_TIMER) as *mut u32, value);
+    }
+}
+
+/// Load the LAPIC timer's count, starting it in one-shot and periodic
+/// modes; 0 stops it.
+pub fn set_timer_initial_count(count: u32) {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    unsafe { write_volatile((base_usize as *mut u8).add(LAPIC_TIMER_INITIAL) as *mut u32, count) };
+}
+
+/// What is left of the LAPIC timer's count.
+pub fn timer_current_count() -> u32 {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return 0;
+    }
+    unsafe { read_volatile((base_usize as *const u8).add(LAPIC_TIMER_CURRENT) as *const u32) }
+}
+
+/// Send an NMI to every other CPU. Used to stop them on panic.
+pub fn send_nmi_all_but_self() {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return;
+    }
+    unsafe {
+        let base = base_usize as *mut u8;
+        write_volatile(base.add(LAPIC_ICR_HIGH) as *mut u32, 0);
+        write_volatile(base.add(LAPIC_ICR_LOW) as *mut u32, ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_DELIVERY_NMI);
+        // bounded: a panicking kernel can't wait on a wedged APIC
+        for _ in 0..100_000 {
+            if read_volatile(base.add(LAPIC_ICR_LOW) as *const u32) & ICR_DELIVERY_PENDING == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Write the ICR, addressing `apic_id`, and wait for the local APIC to
+/// send the IPI. False if it didn't in time, or isn't initialized.
+fn send_icr(apic_id: u8, low: u32) -> bool {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 {
+        return false;
+    }
+    // an interrupt sending an IPI between the two writes would change the
+    // destination under us
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let base = base_usize as *mut u8;
+        write_volatile(base.add(LAPIC_ICR_HIGH) as *mut u32, (apic_id as u32) << 24);
+        write_volatile(base.add(LAPIC_ICR_LOW) as *mut u32, low);
+        for _ in 0..100_000 {
+            if read_volatile(base.add(LAPIC_ICR_LOW) as *const u32) & ICR_DELIVERY_PENDING == 0 {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    })
+}
+
+/// Send an INIT IPI to `apic_id`, resetting that CPU to wait for a SIPI.
+pub fn send_init(apic_id: u8) -> bool {
+    send_icr(apic_id, ICR_ASSERT | ICR_DELIVERY_INIT)
+}
+
+/// Send a startup IPI to `apic_id`: the CPU starts in real mode at
+/// `page << 12`.
+pub fn send_startup(apic_id: u8, page: u8) -> bool {
+    send_icr(apic_id, ICR_ASSERT | ICR_DELIVERY_STARTUP | page as u32)
+}
+
+/// Send interrupt `vector` to the CPU with local APIC `apic_id`.
+pub fn send_ipi(apic_id: u8, vector: u8) -> bool {
+    send_icr(apic_id, ICR_ASSERT | vector as u32)
+}
+
 /// Read Local APIC ID
 pub fn local_apic_id() -> Option<u8> {
     let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
@@ -80,3 +405,13 @@ pub fn local_apic_id() -> Option<u8> {
 pub fn is_initialized() -> bool {
     LAPIC_BASE.load(Ordering::SeqCst) != 0
 }
+
+/// Read the Local APIC register at `offset` (a multiple of 0x10), or `None`
+/// before the LAPIC is initialized.
+pub fn read_register(offset: usize) -> Option<u32> {
+    let base_usize = LAPIC_BASE.load(Ordering::SeqCst);
+    if base_usize == 0 || offset >= 0x400 || offset % 0x10 != 0 {
+        return None;
+    }
+    unsafe { Some(read_volatile((base_usize as *const u8).add(offset) as *const u32)) }
+}
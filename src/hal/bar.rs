@@ -0,0 +1,158 @@
+//! Memory BAR mappings
+//!
+//! `Device::map_bar` maps a memory BAR into the kernel's physical memory
+//! window, at the physical memory offset plus its address, with caching off
+//! (UC-, so a write-combining MTRR over a framebuffer still applies). The
+//! mapping covers the BAR's whole pages and is made once: asking again for
+//! the same BAR returns the same mapping.
+//!
+//! For a PCI function, `index` is the BAR register number (0-5), a 64-bit
+//! BAR being numbered by its low half; for other devices it counts their
+//! memory resources. The device manager unmaps whatever a device still has
+//! mapped when its driver stops, as it does with its DMA mappings.
+
+use crate::prelude::*;
+use crate::driver_framework::device::{Device, ResourceKind};
+use crate::sync::IrqSpinlock;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// PCI BAR registers in a type 0 header.
+const PCI_BARS: usize = 6;
+const PCI_BAR0: u16 = 0x10;
+
+/// A mapped BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarMapping {
+    /// Physical address of the BAR.
+    pub phys: u64,
+    /// Where the BAR's first byte is mapped.
+    pub virt: u64,
+    pub len: u64,
+}
+
+impl BarMapping {
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt as *mut T
+    }
+}
+
+struct BarEntry {
+    device: usize,
+    index: usize,
+    mapping: BarMapping,
+    /// Pages this mapped, as opposed to ones that were mapped already.
+    pages: Vec<Page<Size4KiB>>,
+}
+
+static BARS: IrqSpinlock<Vec<BarEntry>> = IrqSpinlock::new(Vec::new());
+
+/// Physical address and length of memory BAR `index` of `device`.
+fn bar_range(device: &Device, index: usize) -> Result<(u64, u64), &'static str> {
+    let info = device.info();
+    let mut memory = info.resources.iter().filter(|r| r.kind == ResourceKind::MemoryMapped);
+    let resource = match info.location {
+        Some(addr) => {
+            if index >= PCI_BARS {
+                return Err("no such BAR");
+            }
+            let low = addr.config_read(PCI_BAR0 + index as u16 * 4);
+            if low & 1 != 0 {
+                return Err("BAR is an I/O BAR");
+            }
+            let high = if low >> 1 & 3 == 2 && index + 1 < PCI_BARS { addr.config_read(PCI_BAR0 + (index as u16 + 1) * 4) } else { 0 };
+            let base = (high as u64) << 32 | (low & !0xF) as u64;
+            memory.find(|r| r.addr == base)
+        }
+        None => memory.nth(index),
+    };
+    let resource = resource.ok_or("no such BAR")?;
+    if resource.addr == 0 {
+        return Err("BAR not assigned");
+    }
+    Ok((resource.addr, if resource.len == 0 { 0x1000 } else { resource.len }))
+}
+
+/// Map memory BAR `index` of `device`, or return the mapping it already has.
+pub fn map_bar(device: &Device, index: usize) -> Result<BarMapping, &'static str> {
+    let mut bars = BARS.lock();
+    if let Some(e) = bars.iter().find(|e| e.device == device.id() && e.index == index) {
+        return Ok(e.mapping);
+    }
+    let (phys, len) = bar_range(device, index)?;
+    let offset = crate::boot::boot_params().ok_or("no physical memory mapping")?.phys_offset;
+    let start = phys & !0xFFF;
+    let end = (phys + len).next_multiple_of(0x1000);
+    let mut pages = Vec::new();
+    let mut space = crate::memory::kernel_space().ok_or("kernel space not set up")?;
+    for frame_addr in (start..end).step_by(0x1000) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(offset + frame_addr));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(frame_addr));
+        // the physical memory mapping may cover the BAR already
+        if space.translate(page.start_address()) == Some(frame.start_address()) {
+            continue;
+        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        if unsafe { space.map(page, frame, flags) }.is_err() {
+            for &p in &pages {
+                let _ = space.unmap(p);
+            }
+            return Err("mapping BAR pages failed");
+        }
+        pages.push(page);
+    }
+    drop(space);
+    let mapping = BarMapping { phys, virt: offset + phys, len };
+    bars.push(BarEntry { device: device.id(), index, mapping, pages });
+    Ok(mapping)
+}
+
+/// Undo the mapping `map_bar` made of BAR `index` of `device`. False if
+/// there was none.
+pub fn unmap_bar(device: &Device, index: usize) -> bool {
+    release(|e| e.device == device.id() && e.index == index) != 0
+}
+
+/// Unmap every BAR `device_id` still has mapped. Returns how many there
+/// were.
+pub fn free_device_bars(device_id: usize) -> usize {
+    release(|e| e.device == device_id)
+}
+
+impl BarEntry {
+    fn covers(&self, page: Page<Size4KiB>) -> bool {
+        let va = page.start_address().as_u64();
+        self.mapping.virt & !0xFFF <= va && va < self.mapping.virt + self.mapping.len
+    }
+}
+
+/// Drop the matching entries and unmap their pages, except ones another
+/// mapped BAR shares, which pass to it.
+fn release(matches: impl Fn(&BarEntry) -> bool) -> usize {
+    let mut bars = BARS.lock();
+    let (gone, mut kept): (Vec<BarEntry>, Vec<BarEntry>) = core::mem::take(&mut *bars).into_iter().partition(|e| matches(e));
+    let mut unmap = Vec::new();
+    for page in gone.iter().flat_map(|e| e.pages.iter().copied()) {
+        match kept.iter_mut().find(|k| k.covers(page)) {
+            Some(k) => k.pages.push(page),
+            None => unmap.push(page),
+        }
+    }
+    *bars = kept;
+    if !unmap.is_empty()
+        && let Some(mut space) = crate::memory::kernel_space()
+    {
+        for page in unmap {
+            if let Err(e) = space.unmap(page) {
+                println!("[HAL][BAR] unmapping {:?}: {:?}", page, e);
+            }
+        }
+    }
+    gone.len()
+}
+
+/// (device, BAR index, mapping) of every mapped BAR.
+pub fn bar_mappings() -> Vec<(usize, usize, BarMapping)> {
+    BARS.lock().iter().map(|e| (e.device, e.index, e.mapping)).collect()
+}
@@ -0,0 +1,155 @@
+//! DMA mapping
+//!
+//! Drivers give devices bus addresses from `dma_map` rather than physical
+//! addresses of their own. For a PCI function behind a translating IOMMU
+//! the mapping is what lets it reach the buffer at all, and `dma_unmap`
+//! takes that away again; elsewhere the mapping is only bookkeeping. The
+//! bus address is the physical address either way.
+//!
+//! Two mappings may share a page. The page stays mapped, readable or
+//! writable as any mapping of it allows, until the last one goes. The
+//! device manager unmaps whatever a device still has mapped when its
+//! driver stops, as it does with its IRQs.
+//!
+//! Descriptors and buffers a device shares with the CPU go at
+//! `dma_alignment`, a cache line, so that no two share a line and flushing
+//! one with `dma_flush`, for a device that doesn't snoop the caches, leaves
+//! the others alone.
+
+use crate::prelude::*;
+use crate::driver_framework::device::{Device, PciAddress};
+use crate::hal::iommu::{DMA_READ, DMA_WRITE};
+use crate::sync::IrqSpinlock;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+/// Which way data moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    Bidirectional,
+}
+
+impl DmaDirection {
+    fn perms(self) -> u64 {
+        match self {
+            DmaDirection::ToDevice => DMA_READ,
+            DmaDirection::FromDevice => DMA_WRITE,
+            DmaDirection::Bidirectional => DMA_READ | DMA_WRITE,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DmaMapping {
+    device: usize,
+    /// The function, if the IOMMU translates its DMA.
+    translated: Option<PciAddress>,
+    addr: u64,
+    len: u64,
+    perms: u64,
+}
+
+impl DmaMapping {
+    fn pages(&self) -> core::ops::Range<u64> {
+        self.addr / 4096..(self.addr + self.len).div_ceil(4096)
+    }
+}
+
+// Drivers map and unmap from interrupt handlers too.
+static MAPPINGS: IrqSpinlock<Vec<DmaMapping>> = IrqSpinlock::new(Vec::new());
+
+/// Bring the IOMMU tables for `pages` of `location` in line with the
+/// mappings in `mappings`, a run of pages with the same permissions at a
+/// time.
+fn sync_pages(mappings: &[DmaMapping], location: PciAddress, pages: core::ops::Range<u64>) -> Result<(), &'static str> {
+    let perms = |page: u64| {
+        mappings
+            .iter()
+            .filter(|m| m.translated == Some(location) && m.pages().contains(&page))
+            .fold(0, |acc, m| acc | m.perms)
+    };
+    let mut start = pages.start;
+    while start < pages.end {
+        let run_perms = perms(start);
+        let mut end = start + 1;
+        while end < pages.end && perms(end) == run_perms {
+            end += 1;
+        }
+        crate::hal::iommu::iommu_map(location, start * 4096, start * 4096, (end - start) * 4096, run_perms)?;
+        start = end;
+    }
+    Ok(())
+}
+
+/// Let `device` DMA to or from `len` bytes at `phys`, and return the bus
+/// address to give it for them.
+pub fn dma_map(device: &Device, phys: PhysAddr, len: usize, direction: DmaDirection) -> Result<u64, &'static str> {
+    if len == 0 {
+        return Err("empty DMA buffer");
+    }
+    let translated = device.info().location.filter(|&addr| crate::hal::iommu::iommu_translates(addr));
+    let mapping = DmaMapping { device: device.id(), translated, addr: phys.as_u64(), len: len as u64, perms: direction.perms() };
+    let mut mappings = MAPPINGS.lock();
+    mappings.push(mapping);
+    if let Some(location) = translated
+        && let Err(e) = sync_pages(&mappings, location, mapping.pages())
+    {
+        mappings.pop();
+        let _ = sync_pages(&mappings, location, mapping.pages());
+        return Err(e);
+    }
+    Ok(mapping.addr)
+}
+
+/// Alignment for descriptors and buffers shared with devices: the CPU's
+/// cache line size.
+pub fn dma_alignment() -> usize {
+    crate::arch::processor::cache_line_size()
+}
+
+/// `len` rounded up to whole cache lines.
+pub fn dma_align(len: usize) -> usize {
+    len.next_multiple_of(dma_alignment())
+}
+
+/// Write the CPU's cached copy of `len` bytes at `virt` back to memory,
+/// for a device that reads it without snooping the caches.
+pub fn dma_flush(virt: *const u8, len: usize) {
+    crate::arch::processor::flush_cache_range(virt, len);
+}
+
+/// Undo the `dma_map` that returned `addr` for `len` bytes. False if there
+/// was no such mapping.
+pub fn dma_unmap(device: &Device, addr: u64, len: usize) -> bool {
+    release(|m| m.device == device.id() && m.addr == addr && m.len == len as u64) != 0
+}
+
+/// Undo every mapping `device_id` still has. Returns how many there were.
+pub fn free_device_dma(device_id: usize) -> usize {
+    release(|m| m.device == device_id)
+}
+
+/// Drop the matching mappings and unmap the pages nothing else maps.
+fn release(matches: impl Fn(&DmaMapping) -> bool) -> usize {
+    let mut mappings = MAPPINGS.lock();
+    let gone: Vec<DmaMapping> = mappings.iter().copied().filter(|m| matches(m)).collect();
+    mappings.retain(|m| !matches(m));
+    for m in &gone {
+        if let Some(location) = m.translated
+            && let Err(e) = sync_pages(&mappings, location, m.pages())
+        {
+            println!("[HAL][DMA] unmapping {:#x} for {}: {}", m.addr, location, e);
+        }
+    }
+    gone.len()
+}
+
+/// (device, bus address, length, translated) of every mapping, in the
+/// order they were made.
+pub fn dma_mappings() -> Vec<(usize, u64, u64, bool)> {
+    MAPPINGS.lock().iter().map(|m| (m.device, m.addr, m.len, m.translated.is_some())).collect()
+}
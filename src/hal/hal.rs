@@ -3,13 +3,15 @@
 //! Provides high-level functions for CPU and ACPI initialization.
 
 use x86_64::VirtAddr;
-use crate::*;
+use crate::prelude::*;
 
 /// CPU feature information returned by initialization
 #[derive(Debug, Clone)]
 pub struct CpuInfo {
     pub vendor: [u8; 12],
     pub features: crate::arch::processor::CpuFeatures,
+    pub caches: Vec<crate::arch::processor::CacheInfo>,
+    pub clflush_line_size: usize,
 }
 
 /// ACPI initialization result
@@ -29,7 +31,7 @@ pub fn init_cpu() -> CpuInfo {
     println!("[HAL] Initializing CPU features...");
 
     // Enable SSE (required for most modern operations)
-    crate::enable_sse();
+    crate::arch::processor::enable_sse();
 
     // Detect CPU features
     let features = crate::arch::detect_cpu_features();
@@ -38,11 +40,20 @@ pub fn init_cpu() -> CpuInfo {
     // Enable detected features
     crate::arch::enable_cpu_features(&features);
 
+    let caches = crate::arch::processor::detect_caches();
+    for c in &caches {
+        println!("[HAL] L{} {:?} cache: {} KiB, {}-byte lines, {} ways", c.level, c.kind, c.size / 1024, c.line_size, c.ways);
+    }
+    let clflush_line_size = crate::arch::processor::cache_line_size();
+    println!("[HAL] CLFLUSH line size: {} bytes", clflush_line_size);
+
     println!("[HAL] CPU features initialized successfully");
 
     CpuInfo {
         vendor: features.vendor,
         features,
+        caches,
+        clflush_line_size,
     }
 }
 
@@ -71,26 +82,17 @@ pub fn init_hardware(phys_offset: VirtAddr) -> (CpuInfo, AcpiStatus) {
     println!("[HAL] =========================================");
 
     // Initialize CPU features first
-    let cpu_info = init_cpu();
+    let cpu_info = crate::time::boot_phase("cpu", init_cpu);
 
     // Initialize ACPI
-    let acpi_status = init_acpi(phys_offset);
+    let acpi_status = crate::time::boot_phase("acpi", || init_acpi(phys_offset));
 
-    // Initialize Local APIC if possible
-    if crate::hal::apic::init_from_acpi(phys_offset) {
-        println!("[HAL] Local APIC initialized");
-    } else {
-        println!("[HAL] Local APIC not initialized or not present");
-    }
+    // Local APIC and IOAPICs, or the 8259 PICs when they can't be used
+    crate::hal::intctl::init_interrupt_controller(phys_offset);
 
-    // Initialize IOAPICs discovered via ACPI MADT (if any)
-    crate::hal::ioapic::init_from_acpi(phys_offset);
-    // Apply Interrupt Source Overrides (ISOs) to IOAPIC configuration (logged/stubbed)
-    crate::hal::ioapic::apply_isos_from_acpi(phys_offset);
-    // As a fallback, ensure legacy ISA IRQs 0..15 are programmed into IOAPIC
-    // to the conventional vectors (masked). This makes keyboard (IRQ1)
-    // deliver to vector 0x21 when APIC-only interrupts are used.
-    crate::hal::ioapic::apply_legacy_isa_fallback(phys_offset);
+    // Turn on DMA remapping; devices behind an IOMMU reach only what is
+    // mapped for them through `dma_map`
+    crate::time::boot_phase("iommu", || crate::hal::iommu::init_iommu(phys_offset));
 
     println!("[HAL] =========================================");
     println!("[HAL] Hardware initialization complete");
@@ -0,0 +1,168 @@
+//! Interrupt controller policy
+//!
+//! Decides at boot whether device interrupts come through the IOAPICs or
+//! the legacy 8259 PICs. APIC mode needs the local APIC to come up and the
+//! MADT to list an IOAPIC; without either, or with `noapic` on the command
+//! line, the kernel runs in PIC mode and leaves the local APIC off. The
+//! MADT's PCAT_COMPAT flag says whether there are PICs at all (no MADT is
+//! taken to mean there are): in APIC mode they are masked, and in PIC mode
+//! without them there are no device interrupts.
+//!
+//! In PIC mode the PICs are remapped to the ISA vectors 0x20-0x2F with
+//! every line masked, and `request_irq` unmasks lines as it does IOAPIC
+//! inputs in APIC mode. EOIs go to the PICs, except for spurious IRQ 7 and
+//! IRQ 15, which are not in service and take none (IRQ 15 does take one on
+//! the master, which saw the cascade line raised).
+
+use crate::prelude::*;
+use crate::arch::interrupts::{PICS, PIC_1_OFFSET};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::VirtAddr;
+
+/// How device interrupts are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IrqMode {
+    /// Not decided yet, or no interrupt controller to use.
+    None = 0,
+    Apic = 1,
+    Pic = 2,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(IrqMode::None as u8);
+static SPURIOUS_PIC: AtomicU64 = AtomicU64::new(0);
+
+/// IRQ on the master that the slave PIC cascades through.
+const CASCADE_IRQ: u8 = 2;
+/// OCW3: read the in-service register on the next read of the command port.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The mode `init_interrupt_controller` chose.
+pub fn irq_mode() -> IrqMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => IrqMode::Apic,
+        2 => IrqMode::Pic,
+        _ => IrqMode::None,
+    }
+}
+
+/// Whether `cmdline` asks for PIC mode.
+pub fn noapic_requested(cmdline: &str) -> bool {
+    cmdline.split_whitespace().any(|w| w == "noapic")
+}
+
+/// Whether the machine has 8259 PICs, as far as the MADT says.
+pub fn pics_present() -> bool {
+    crate::devices::acpi::madt_pcat_compat().unwrap_or(true)
+}
+
+/// Pick the interrupt mode and bring up its controllers: the local APIC
+/// and IOAPICs, masking the PICs, or the PICs alone.
+pub fn init_interrupt_controller(phys_offset: VirtAddr) -> IrqMode {
+    // the command line is read this early only for this; everything else
+    // configured from it is set up later
+    let noapic = crate::devices::fw_cfg::read_cmdline().is_some_and(|c| noapic_requested(&c));
+    let apic = if noapic {
+        println!("[HAL] noapic: using the 8259 PICs");
+        false
+    } else if crate::devices::acpi::get_ioapics().is_empty() {
+        println!("[HAL] no IOAPIC in the MADT; using the 8259 PICs");
+        false
+    } else if crate::time::boot_phase("lapic", || crate::hal::apic::init_from_acpi(phys_offset)) {
+        println!("[HAL] Local APIC initialized");
+        crate::hal::apic::init_lvt();
+        true
+    } else {
+        println!("[HAL] Local APIC not initialized or not present; using the 8259 PICs");
+        false
+    };
+
+    let mode = if apic {
+        // Every input starts masked; drivers route and unmask theirs with
+        // `request_irq`
+        crate::time::boot_phase("ioapic", || crate::hal::ioapic::init_from_acpi(phys_offset));
+        if pics_present() {
+            unsafe { PICS.lock().disable() };
+        }
+        IrqMode::Apic
+    } else if pics_present() {
+        unsafe {
+            let mut pics = PICS.lock();
+            pics.initialize();
+            pics.write_masks(0xFF, 0xFF);
+        }
+        IrqMode::Pic
+    } else {
+        println!("[HAL] MADT says there are no PICs either; no device interrupts");
+        IrqMode::None
+    };
+    MODE.store(mode as u8, Ordering::Relaxed);
+    println!("[HAL] interrupt mode: {:?}", mode);
+    mode
+}
+
+/// Unmask ISA IRQ `irq` on the PICs, and the cascade for the slave's.
+pub fn pic_unmask(irq: u8) {
+    let mut pics = PICS.lock();
+    let [mut master, mut slave] = unsafe { pics.read_masks() };
+    if irq < 8 {
+        master &= !(1 << irq);
+    } else {
+        slave &= !(1 << (irq - 8));
+        master &= !(1 << CASCADE_IRQ);
+    }
+    unsafe { pics.write_masks(master, slave) };
+}
+
+/// Mask ISA IRQ `irq` on the PICs, and the cascade once no slave IRQ is
+/// left unmasked.
+pub fn pic_mask(irq: u8) {
+    let mut pics = PICS.lock();
+    let [mut master, mut slave] = unsafe { pics.read_masks() };
+    if irq < 8 {
+        master |= 1 << irq;
+    } else {
+        slave |= 1 << (irq - 8);
+        if slave == 0xFF {
+            master |= 1 << CASCADE_IRQ;
+        }
+    }
+    unsafe { pics.write_masks(master, slave) };
+}
+
+/// Whether ISA IRQ `irq` is in service on its PIC.
+fn pic_in_service(irq: u8) -> bool {
+    use crate::arch::ports::{inb, outb};
+    let port = if irq < 8 { 0x20 } else { 0xA0 };
+    let _pics = PICS.lock();
+    unsafe {
+        outb(port, OCW3_READ_ISR);
+        inb(port) & 1 << (irq % 8) != 0
+    }
+}
+
+/// Called first thing for a PIC interrupt on `vector`. False if it was
+/// spurious, in which case it has been dealt with and there is nothing to
+/// handle or acknowledge.
+pub fn pic_irq_begin(vector: u8) -> bool {
+    let Some(irq @ (7 | 15)) = crate::hal::irq::isa_irq_for_vector(vector) else { return true };
+    if pic_in_service(irq) {
+        return true;
+    }
+    SPURIOUS_PIC.fetch_add(1, Ordering::Relaxed);
+    if irq == 15 {
+        pic_eoi(PIC_1_OFFSET + CASCADE_IRQ);
+    }
+    false
+}
+
+/// Acknowledge the PIC interrupt on `vector`. Does nothing for vectors the
+/// PICs don't deliver.
+pub fn pic_eoi(vector: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+}
+
+/// Spurious IRQ 7 and 15 interrupts seen in PIC mode.
+pub fn spurious_pic_count() -> u64 {
+    SPURIOUS_PIC.load(Ordering::Relaxed)
+}
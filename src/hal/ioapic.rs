@@ -1,15 +1,19 @@
 #![no_std]
 
-use crate::*;
+use crate::prelude::*;
 use x86_64::VirtAddr;
 use core::ptr::{read_volatile, write_volatile};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 /// Minimal IOAPIC driver: provides MMIO access to IOAPIC registers via
 /// index/data registers and helpers to read ID/version and set redirection table entries.
 
 const IOAPIC_REG_SELECT: usize = 0x00;
 const IOAPIC_REG_WINDOW: usize = 0x10;
+/// EOI register, on IOAPICs of version 0x20 and later: writing a vector
+/// clears the Remote IRR of the level-triggered entries using it.
+const IOAPIC_REG_EOI: usize = 0x40;
 
 /// A discovered IOAPIC instance
 #[derive(Debug, Clone, Copy)]
@@ -19,6 +23,7 @@ pub struct IoApic {
     pub gsi_base: u32,
     /// Number of redirection entries this IOAPIC implements
     pub redir_entries: u32,
+    pub version: u8,
 }
 
 impl IoApic {
@@ -73,8 +78,17 @@ impl IoApic {
 }
 
 // Store discovered IOAPICs for later use; allow HAL to initialize them from ACPI data.
-use spin::Mutex;
-static IOAPIC_TABLE: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+// Taken in interrupt handlers, for level-triggered lines.
+static IOAPIC_TABLE: crate::sync::IrqSpinlock<Vec<IoApic>> = crate::sync::IrqSpinlock::new(Vec::new());
+/// The phys_offset IOAPICs were found with, for the interrupt-time helpers.
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// GSI + 1 of the level-triggered entry delivering each vector, 0 for none.
+static LEVEL_VECTORS: [AtomicU32; 256] = [const { AtomicU32::new(0) }; 256];
+/// GSIs 0..256 masked with `mask_gsi`; level handling leaves these masked.
+static QUIESCED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// Level-triggered interrupts end with a directed EOI to the IOAPIC rather
+/// than masking the line while they are handled.
+static DIRECTED_EOI: AtomicBool = AtomicBool::new(false);
 
 /// Initialize IOAPIC subsystem using MADT entries discovered by ACPI.
 pub fn init_from_acpi(phys_offset: VirtAddr) {
@@ -84,15 +98,18 @@ pub fn init_from_acpi(phys_offset: VirtAddr) {
         return;
     }
 
+    PHYS_OFFSET.store(phys_offset.as_u64(), Ordering::Relaxed);
     for info in ioapics.iter() {
         // Read redirection count if possible
         let mut redir_entries = 24u32; // fallback
+        let mut version = 0u8;
         let virt = (info.addr as u64 + phys_offset.as_u64()) as *mut u8;
         if !virt.is_null() {
             unsafe {
                 let reg = IoApic::read_reg(virt, 1);
                 let max_redir = (reg >> 16) & 0xFF;
                 redir_entries = max_redir + 1;
+                version = reg as u8;
             }
         }
 
@@ -102,9 +119,17 @@ pub fn init_from_acpi(phys_offset: VirtAddr) {
             phys_addr: info.addr,
             gsi_base: info.gsi_base,
             redir_entries,
+            version,
         });
     }
 
+    // Directed EOI needs an EOI register on every IOAPIC, and a local APIC
+    // that can stop broadcasting EOIs to them
+    if IOAPIC_TABLE.lock().iter().all(|io| io.version >= 0x20) && crate::hal::apic::suppress_eoi_broadcast() {
+        DIRECTED_EOI.store(true, Ordering::Relaxed);
+        println!("[HAL][IOAPIC] Level-triggered interrupts end with a directed EOI");
+    }
+
     // Log discovered IOAPICs
     for io in IOAPIC_TABLE.lock().iter() {
         println!("[HAL][IOAPIC] Found IOAPIC id={} phys=0x{:x} gsi_base={} redirs={}", io.id, io.phys_addr, io.gsi_base, io.redir_entries);
@@ -112,6 +137,17 @@ pub fn init_from_acpi(phys_offset: VirtAddr) {
             println!("[HAL][IOAPIC] IOAPIC id={} version={}", io.id, ver);
         }
     }
+
+    // Firmware's routing is dropped: every input stays masked until a
+    // driver asks for its line with `request_irq`
+    for io in list_ioapics() {
+        for gsi in io.gsi_base..io.gsi_base + io.redir_entries {
+            write_redirection_entry_for_gsi(gsi, REDIR_MASKED, 0, phys_offset);
+        }
+    }
+    for iso in crate::devices::acpi::get_isos() {
+        println!("[HAL][IOAPIC] ISO: bus={} source={} gsi={} flags=0x{:x}", iso.bus, iso.source, iso.gsi, iso.flags);
+    }
 }
 
 /// Return a cloned list of discovered IOAPICs
@@ -147,154 +183,176 @@ pub fn write_redirection_entry_for_gsi(gsi: u32, low: u32, high: u32, phys_offse
                 IoApic::write_reg(virt, reg_high, high);
                 IoApic::write_reg(virt, reg_low, low);
             }
+            note_entry(gsi, low);
             return true;
         }
     }
     false
 }
 
-/// Apply Interrupt Source Overrides discovered from ACPI MADT: map legacy ISA IRQs to GSIs
-pub fn apply_isos_from_acpi(phys_offset: VirtAddr) {
-    let isos = crate::devices::acpi::get_isos();
-    if isos.is_empty() {
-        println!("[HAL][IOAPIC] No ISOs found in MADT");
-        return;
+/// Redirection entry bit 13: the input is active low.
+pub const REDIR_ACTIVE_LOW: u32 = 1 << 13;
+/// Redirection entry bit 15: the input is level triggered.
+pub const REDIR_LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Redirection entry bit 16: the input is masked.
+pub const REDIR_MASKED: u32 = 1 << 16;
+
+/// The polarity and trigger bits of a redirection entry for the MPS INTI
+/// flags of a MADT interrupt source override: polarity in bits 0-1 and
+/// trigger mode in bits 2-3, where 0 means "conforms to the bus". ISOs
+/// describe ISA sources, so that is active high and edge triggered.
+pub fn iso_redirection_bits(flags: u16) -> u32 {
+    let mut bits = 0;
+    // 1 = active high, 3 = active low, 2 is reserved
+    if flags & 0x3 == 0x3 {
+        bits |= REDIR_ACTIVE_LOW;
+    }
+    // 1 = edge, 3 = level, 2 is reserved
+    if (flags >> 2) & 0x3 == 0x3 {
+        bits |= REDIR_LEVEL_TRIGGERED;
     }
+    bits
+}
 
-    for iso in isos.iter() {
-        println!("[HAL][IOAPIC] ISO: bus={} source={} gsi={} flags=0x{:x}", iso.bus, iso.source, iso.gsi, iso.flags);
-        if let Some((ioidx, local)) = find_ioapic_for_gsi(iso.gsi) {
-            println!("[HAL][IOAPIC] GSI {} belongs to IOAPIC index {} at local entry {}", iso.gsi, ioidx, local);
-
-            // Program a sane default redirection entry:
-            // - use vector = 0x20 + source (keeps legacy mapping)
-            // - delivery mode = fixed (0)
-            // - destination mode = physical (0)
-            // - polarity = 0 (active high)
-            // - trigger mode = 0 (edge)
-            // - masked = 1 initially (do not enable interrupts until kernel configures)
-            let vector = 0x20u32.wrapping_add(iso.source as u32) & 0xFF;
-            let low: u32 = (vector & 0xFF) | (1 << 16); // mask bit set
-            let high: u32 = 0; // destination field left zero (physical CPU 0); can be updated later
-
-            if write_redirection_entry_for_gsi(iso.gsi, low, high, phys_offset) {
-                println!("[HAL][IOAPIC] Programmed redir for GSI {} -> vector 0x{:x} (masked)", iso.gsi, vector);
-            } else {
-                println!("[HAL][IOAPIC] Failed to program redir for GSI {}", iso.gsi);
-            }
-        } else {
-            println!("[HAL][IOAPIC] No IOAPIC found that handles GSI {}", iso.gsi);
+/// Read-modify-write the redirection entry (low, high) for `gsi`. Returns
+/// false if no IOAPIC handles it.
+fn modify_redirection_entry(gsi: u32, phys_offset: VirtAddr, f: impl FnOnce(u32, u32) -> (u32, u32)) -> bool {
+    let Some((ioidx, local)) = find_ioapic_for_gsi(gsi) else { return false };
+    let table = IOAPIC_TABLE.lock();
+    let Some(io) = table.get(ioidx) else { return false };
+    let virt = (io.phys_addr as u64 + phys_offset.as_u64()) as *mut u8;
+    if virt.is_null() { return false; }
+    let low = unsafe {
+        let reg_low = 0x10 + (local as usize * 2) as u8;
+        let reg_high = reg_low + 1;
+        let (low, high) = f(IoApic::read_reg(virt, reg_low), IoApic::read_reg(virt, reg_high));
+        // mask first, so the entry never fires half written
+        IoApic::write_reg(virt, reg_low, low | REDIR_MASKED);
+        IoApic::write_reg(virt, reg_high, high);
+        IoApic::write_reg(virt, reg_low, low);
+        low
+    };
+    drop(table);
+    note_entry(gsi, low);
+    true
+}
+
+/// Remember which vector a level-triggered GSI delivers, for
+/// `level_irq_begin`/`level_irq_end`.
+fn note_entry(gsi: u32, low: u32) {
+    let vector = (low & 0xFF) as usize;
+    for (v, slot) in LEVEL_VECTORS.iter().enumerate() {
+        if v != vector || low & REDIR_LEVEL_TRIGGERED == 0 {
+            // the GSI moved to another vector, or became edge triggered
+            let _ = slot.compare_exchange(gsi + 1, 0, Ordering::Relaxed, Ordering::Relaxed);
         }
     }
+    if low & REDIR_LEVEL_TRIGGERED != 0 && vector >= 32 {
+        LEVEL_VECTORS[vector].store(gsi + 1, Ordering::Relaxed);
+    }
 }
 
-// NOTE: On some systems MADT may not contain explicit ISOs for all legacy
-// ISA IRQs. As a pragmatic fallback we also program redirection entries for
-// legacy IRQs 0..15 (GSI 0..15) to the conventional vectors 0x20+IRQ and
-// keep them masked; the per-CPU unmask routine will set the destination
-// and unmask when appropriate. This helps ensure devices like the PS/2
-// keyboard (IRQ1 -> vector 0x21) actually deliver interrupts when using
-// an IOAPIC-only setup.
-pub fn apply_legacy_isa_fallback(phys_offset: VirtAddr) {
-    for irq in 0u32..16u32 {
-        let gsi = irq; // legacy ISA interrupts map directly to GSI 0..15 on most platforms
-        // vector: 0x20 + irq
-        let vector = 0x20u32.wrapping_add(irq) & 0xFF;
-        let low: u32 = (vector & 0xFF) | (1 << 16); // masked by default
-        let high: u32 = 0; // leave destination zero until per-CPU enable
-
-        if write_redirection_entry_for_gsi(gsi, low, high, phys_offset) {
-            println!("[HAL][IOAPIC] Fallback-programmed GSI {} -> vector 0x{:x} (masked)", gsi, vector);
-        }
+/// Set the physical destination APIC ID (bits 56-63) in the high dword,
+/// keeping the reserved bits below it.
+fn with_destination(high: u32, local_apic_id: u8) -> u32 {
+    (high & 0x00FF_FFFF) | ((local_apic_id as u32) << 24)
+}
+
+/// Set the trigger mode and polarity of `gsi` to `bits` (`REDIR_LEVEL_TRIGGERED`
+/// and/or `REDIR_ACTIVE_LOW`), leaving the rest of its entry alone. PCI
+/// INTx lines, for one, are level triggered and active low.
+pub fn set_gsi_trigger_polarity(gsi: u32, bits: u32, phys_offset: VirtAddr) -> bool {
+    let mode = REDIR_LEVEL_TRIGGERED | REDIR_ACTIVE_LOW;
+    modify_redirection_entry(gsi, phys_offset, |low, high| ((low & !mode) | (bits & mode), high))
+}
+
+/// Where ISA `irq` arrives: the GSI and the polarity and trigger bits of
+/// the MADT override that moves it, or GSI `irq`, edge triggered and
+/// active high, if none does.
+pub fn isa_irq_route(isos: &[crate::devices::acpi::IsoInfo], irq: u8) -> (u32, u32) {
+    match isos.iter().find(|iso| iso.bus == 0 && iso.source == irq) {
+        Some(iso) => (iso.gsi, iso_redirection_bits(iso.flags)),
+        None => (irq as u32, 0),
     }
 }
 
-/// Enable ISOs for the current CPU by setting the destination field to this CPU's APIC ID
-/// and clearing the mask bit. This should be called on each CPU (AP) during bring-up when
-/// the IDT is prepared to handle the vectors.
-pub fn enable_isos_for_local(phys_offset: VirtAddr, local_apic_id: u8) {
-    let isos = crate::devices::acpi::get_isos();
-    if isos.is_empty() { return; }
-
-    for iso in isos.iter() {
-        if let Some((ioidx, local)) = find_ioapic_for_gsi(iso.gsi) {
-            // read current low/high from the correct IOAPIC
-            let table = IOAPIC_TABLE.lock();
-            if let Some(io) = table.get(ioidx) {
-                let virt = (io.phys_addr as u64 + phys_offset.as_u64()) as *mut u8;
-                if virt.is_null() { continue; }
-                unsafe {
-                    let reg_low = 0x10 + (local as usize * 2) as u8;
-                    let reg_high = reg_low + 1;
-                    let mut high = IoApic::read_reg(virt, reg_high);
-                    let mut low = IoApic::read_reg(virt, reg_low);
-                    // set destination (high dword bits [63:56] for physical mode)
-                    high = ((local_apic_id as u32) << 24) as u32; // corresponds to high dword bits [56:63] in x86 doc
-                    // clear mask bit (bit 16)
-                    low &= !(1 << 16);
-                    IoApic::write_reg(virt, reg_high, high);
-                    IoApic::write_reg(virt, reg_low, low);
-                    println!("[HAL][IOAPIC] Enabled ISO GSI {} -> APIC {} (unmasked)", iso.gsi, local_apic_id);
-                }
-            }
-        }
+/// The phys_offset the IOAPICs were found with.
+pub fn ioapic_phys_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Unmask a specific GSI (clear mask bit) and set its vector/destination to this CPU,
+/// keeping its trigger mode and polarity. Returns true on success.
+pub fn unmask_gsi(gsi: u32, vector: u8, local_apic_id: u8, phys_offset: VirtAddr) -> bool {
+    set_quiesced(gsi, false);
+    modify_redirection_entry(gsi, phys_offset, |low, high| {
+        let low = (low & !0xFF & !REDIR_MASKED) | vector as u32;
+        (low, with_destination(high, local_apic_id))
+    })
+}
+
+/// Mask a GSI, e.g. while its driver is stopped, until `unmask_gsi`. The
+/// rest of the entry is kept. Returns false if no IOAPIC handles it.
+pub fn mask_gsi(gsi: u32, phys_offset: VirtAddr) -> bool {
+    set_quiesced(gsi, true);
+    modify_redirection_entry(gsi, phys_offset, |low, high| (low | REDIR_MASKED, high))
+}
+
+fn set_quiesced(gsi: u32, quiesced: bool) {
+    let Some(word) = QUIESCED.get(gsi as usize / 64) else { return };
+    let bit = 1u64 << (gsi % 64);
+    if quiesced {
+        word.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        word.fetch_and(!bit, Ordering::Relaxed);
     }
+}
 
-    // Also enable legacy ISA IRQs (GSI 0..15) as a pragmatic per-CPU fallback.
-    // This ensures devices wired to legacy IRQs (keyboard IRQ1) are targeted
-    // to this CPU and unmasked when the CPU calls this function.
-    for irq in 0u32..16u32 {
-        if let Some((ioidx, local)) = find_ioapic_for_gsi(irq) {
-            let table = IOAPIC_TABLE.lock();
-            if let Some(io) = table.get(ioidx) {
-                let virt = (io.phys_addr as u64 + phys_offset.as_u64()) as *mut u8;
-                if virt.is_null() { continue; }
-                unsafe {
-                    let reg_low = 0x10 + (local as usize * 2) as u8;
-                    let reg_high = reg_low + 1;
-                    let mut high = IoApic::read_reg(virt, reg_high);
-                    let mut low = IoApic::read_reg(virt, reg_low);
-                    // program destination for physical mode
-                    high = ((local_apic_id as u32) << 24) as u32;
-                    // clear mask bit
-                    low &= !(1 << 16);
-                    IoApic::write_reg(virt, reg_high, high);
-                    IoApic::write_reg(virt, reg_low, low);
-                    let vector = 0x20u32.wrapping_add(irq) & 0xFF;
-                    println!("[HAL][IOAPIC] Per-CPU enabled legacy IRQ {} (GSI {}) -> APIC {} vector 0x{:x}", irq, irq, local_apic_id, vector);
-                }
-            }
-        }
+fn quiesced(gsi: u32) -> bool {
+    QUIESCED.get(gsi as usize / 64).is_some_and(|w| w.load(Ordering::Relaxed) & (1 << (gsi % 64)) != 0)
+}
+
+/// The level-triggered GSI delivering `vector`, if there is one.
+fn level_gsi(vector: u8) -> Option<u32> {
+    LEVEL_VECTORS[vector as usize].load(Ordering::Relaxed).checked_sub(1)
+}
+
+/// Called by the interrupt entry before the handler runs. Without directed
+/// EOI a level-triggered line is masked while it is handled, so the local
+/// APIC's EOI can't make it fire again before the device was quiesced.
+pub fn level_irq_begin(vector: u8) {
+    if DIRECTED_EOI.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(gsi) = level_gsi(vector) {
+        let offset = VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed));
+        modify_redirection_entry(gsi, offset, |low, high| (low | REDIR_MASKED, high));
     }
 }
 
-/// Unmask a specific GSI (clear mask bit) and set its vector/destination to this CPU.
-/// Returns true on success.
-pub fn unmask_gsi(gsi: u32, vector: u8, local_apic_id: u8, phys_offset: VirtAddr) -> bool {
-    if let Some((ioidx, local)) = find_ioapic_for_gsi(gsi) {
+/// Called by the interrupt entry after the local APIC EOI. Finishes a
+/// level-triggered interrupt: a directed EOI to its IOAPIC, or unmasking
+/// the line again. `handled` false leaves the line masked, so a level
+/// interrupt nobody acknowledges can't fire forever; so does a `mask_gsi`
+/// made meanwhile.
+pub fn level_irq_end(vector: u8, handled: bool) {
+    let Some(gsi) = level_gsi(vector) else { return };
+    let offset = VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed));
+    if !handled {
+        set_quiesced(gsi, true);
+        modify_redirection_entry(gsi, offset, |low, high| (low | REDIR_MASKED, high));
+        kwarn_once!("[HAL][IOAPIC] unhandled level-triggered GSI {} (vector {:#x}) masked", gsi, vector);
+    }
+    if DIRECTED_EOI.load(Ordering::Relaxed) {
+        let Some((ioidx, _)) = find_ioapic_for_gsi(gsi) else { return };
         let table = IOAPIC_TABLE.lock();
         if let Some(io) = table.get(ioidx) {
-            let virt = (io.phys_addr as u64 + phys_offset.as_u64()) as *mut u8;
-            if virt.is_null() { return false; }
-            unsafe {
-                let reg_low = 0x10 + (local as usize * 2) as u8;
-                let reg_high = reg_low + 1;
-                // Read current entries
-                let mut high = IoApic::read_reg(virt, reg_high);
-                let mut low = IoApic::read_reg(virt, reg_low);
-                // Set vector (bits 0..7)
-                low = (low & !0xFF) | (vector as u32 & 0xFF);
-                // Set destination (physical mode) in high dword ([63:56] -> high >> 24)
-                high = ((local_apic_id as u32) << 24) as u32;
-                // Clear mask bit (bit 16)
-                low &= !(1 << 16);
-                IoApic::write_reg(virt, reg_high, high);
-                IoApic::write_reg(virt, reg_low, low);
-            }
-            return true;
+            let base = (io.phys_addr as u64 + offset.as_u64()) as *mut u8;
+            unsafe { write_volatile(base.add(IOAPIC_REG_EOI) as *mut u32, vector as u32) };
         }
+    } else if handled && !quiesced(gsi) {
+        modify_redirection_entry(gsi, offset, |low, high| (low & !REDIR_MASKED, high));
     }
-    false
 }
 
 /// Read back the 64-bit redirection entry (low, high) for the given GSI.
@@ -316,3 +374,23 @@ pub fn read_redirection_entry(gsi: u32, phys_offset: VirtAddr) -> Option<(u32,u3
     }
     None
 }
+
+/// The GSI whose redirection entry delivers `vector`, with the entry
+/// (low, high). An unmasked entry is preferred over a masked one. Doesn't
+/// allocate, so it can be used from an interrupt handler.
+pub fn gsi_for_vector(vector: u8) -> Option<(u32, u32, u32)> {
+    let offset = ioapic_phys_offset();
+    let gsi_end = IOAPIC_TABLE.lock().iter().map(|io| io.gsi_base + io.redir_entries).max()?;
+    let mut masked = None;
+    for gsi in 0..gsi_end {
+        let Some((low, high)) = read_redirection_entry(gsi, offset) else { continue };
+        if low & 0xFF != vector as u32 {
+            continue;
+        }
+        if low & REDIR_MASKED == 0 {
+            return Some((gsi, low, high));
+        }
+        masked.get_or_insert((gsi, low, high));
+    }
+    masked
+}
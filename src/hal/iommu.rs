@@ -0,0 +1,391 @@
+//! Intel VT-d DMA remapping
+//!
+//! Every remapping unit the DMAR lists gets a root table at boot and has
+//! translation turned on, so a PCI function behind one reaches no memory
+//! at all until something maps memory for it. `hal::dma` does that for
+//! drivers. Each function gets a domain of its own, with second-level page
+//! tables nobody else shares, the first time memory is mapped for it.
+//! Functions named by a reserved region (RMRR) get theirs at boot with the
+//! region mapped 1:1, since the firmware left them using it.
+//!
+//! Tables are only written from the CPU side, and each change is followed
+//! by a global invalidation of the unit's context cache and IOTLB. That is
+//! slow, but nothing maps often yet.
+
+use crate::prelude::*;
+use crate::devices::acpi::{DmarScope, DmarUnit};
+use crate::driver_framework::device::PciAddress;
+use crate::memory::frame::GlobalFrameAllocator;
+use crate::sync::IrqSpinlock;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::FrameAllocator;
+
+const REG_CAP: usize = 0x08;
+const REG_ECAP: usize = 0x10;
+const REG_GCMD: usize = 0x18;
+const REG_GSTS: usize = 0x1C;
+const REG_RTADDR: usize = 0x20;
+const REG_CCMD: usize = 0x28;
+const REG_FSTS: usize = 0x34;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_WBF: u32 = 1 << 27;
+/// GSTS bits that are state rather than one-shot commands, kept when a
+/// status is written back as a command.
+const GSTS_PERSISTENT: u32 = 0x96FF_FFFF;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DRAIN: u64 = 1 << 49 | 1 << 48;
+
+const FSTS_PPF: u32 = 1 << 1;
+
+/// The device may read the page.
+pub const DMA_READ: u64 = 1 << 0;
+/// The device may write the page.
+pub const DMA_WRITE: u64 = 1 << 1;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// How long a unit gets to act on a command or invalidation.
+const COMMAND_TIMEOUT_MS: u64 = 1000;
+
+struct Domain {
+    device: PciAddress,
+    id: u16,
+    /// Top-level second-level page table.
+    root: u64,
+}
+
+struct Unit {
+    segment: u16,
+    register_base: u64,
+    regs: u64,
+    cap: u64,
+    ecap: u64,
+    include_pci_all: bool,
+    /// What the unit's device scopes resolve to: a function, and for a
+    /// bridge the buses behind it.
+    scopes: Vec<(PciAddress, Option<(u8, u8)>)>,
+    /// Page-table levels, 3 (39-bit) or 4 (48-bit).
+    levels: u8,
+    root_table: u64,
+    domains: Vec<Domain>,
+    max_domains: u32,
+}
+
+// Taken from drivers' `dma_map`, which may run in interrupt context.
+static UNITS: IrqSpinlock<Vec<Unit>> = IrqSpinlock::new(Vec::new());
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn phys_ptr(phys: u64) -> *mut u64 {
+    (phys + PHYS_OFFSET.load(Ordering::Relaxed)) as *mut u64
+}
+
+/// A zeroed frame for a table.
+fn alloc_table() -> Result<u64, &'static str> {
+    let frame = GlobalFrameAllocator.allocate_frame().ok_or("out of frames for IOMMU tables")?;
+    let phys = frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(phys_ptr(phys) as *mut u8, 0, 4096) };
+    Ok(phys)
+}
+
+/// The function a scope path leads to, following bridges' secondary bus
+/// numbers, and for a bridge scope the buses behind it.
+fn resolve_scope(segment: u16, scope: &DmarScope) -> Option<(PciAddress, Option<(u8, u8)>)> {
+    let (&(device, function), bridges) = scope.path.split_last()?;
+    let mut bus = scope.start_bus;
+    for &(d, f) in bridges {
+        bus = (PciAddress::new(segment, bus, d, f).config_read(0x18) >> 8) as u8;
+    }
+    let addr = PciAddress::new(segment, bus, device, function);
+    match scope.kind {
+        1 => Some((addr, None)),
+        2 => {
+            let buses = addr.config_read(0x18);
+            Some((addr, Some(((buses >> 8) as u8, (buses >> 16) as u8))))
+        }
+        _ => None,
+    }
+}
+
+impl Unit {
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.regs + reg as u64) as *const u32) }
+    }
+
+    fn write32(&self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.regs + reg as u64) as *mut u32, value) }
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.regs + reg as u64) as *const u64) }
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        unsafe { core::ptr::write_volatile((self.regs + reg as u64) as *mut u64, value) }
+    }
+
+    fn covers(&self, addr: PciAddress) -> bool {
+        addr.segment == self.segment
+            && self.scopes.iter().any(|&(f, buses)| f == addr || buses.is_some_and(|(lo, hi)| (lo..=hi).contains(&addr.bus)))
+    }
+
+    fn enabled(&self) -> bool {
+        self.read32(REG_GSTS) & GCMD_TE != 0
+    }
+
+    /// Issue the one-shot or state command `bit` and wait for the status
+    /// to show it done.
+    fn command(&self, bit: u32, done: impl Fn(u32) -> bool) -> Result<(), &'static str> {
+        self.write32(REG_GCMD, (self.read32(REG_GSTS) & GSTS_PERSISTENT) | bit);
+        crate::time::spin_until_ms(COMMAND_TIMEOUT_MS, || done(self.read32(REG_GSTS)).then_some(())).map_err(|_| "IOMMU command timed out")
+    }
+
+    /// Make table changes visible: drain the write buffer if the unit has
+    /// one, then invalidate the context cache and IOTLB globally.
+    fn flush(&self) -> Result<(), &'static str> {
+        if self.cap & 1 << 4 != 0 {
+            self.command(GCMD_WBF, |sts| sts & GCMD_WBF == 0)?;
+        }
+        self.write64(REG_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        crate::time::spin_until_ms(COMMAND_TIMEOUT_MS, || (self.read64(REG_CCMD) & CCMD_ICC == 0).then_some(()))
+            .map_err(|_| "context invalidation timed out")?;
+        let iotlb = ((self.ecap >> 8 & 0x3FF) * 16 + 8) as usize;
+        self.write64(iotlb, IOTLB_IVT | IOTLB_GLOBAL | IOTLB_DRAIN);
+        crate::time::spin_until_ms(COMMAND_TIMEOUT_MS, || (self.read64(iotlb) & IOTLB_IVT == 0).then_some(()))
+            .map_err(|_| "IOTLB invalidation timed out")
+    }
+
+    /// Write `value` to the table entry at `entry`, pushing it out of the
+    /// CPU caches if the unit doesn't snoop them.
+    fn set_entry(&self, entry: *mut u64, value: u64) {
+        unsafe {
+            core::ptr::write_volatile(entry, value);
+        }
+        if self.ecap & 1 == 0 {
+            crate::arch::processor::flush_cache_range(entry as *const u8, 8);
+        }
+    }
+
+    /// `addr`'s domain, giving it a fresh one with nothing mapped if it has
+    /// none yet.
+    fn domain_for(&mut self, addr: PciAddress) -> Result<usize, &'static str> {
+        if let Some(i) = self.domains.iter().position(|d| d.device == addr) {
+            return Ok(i);
+        }
+        // domain 0 is reserved when the unit is in caching mode; skip it always
+        let id = self.domains.len() as u32 + 1;
+        if id >= self.max_domains {
+            return Err("IOMMU out of domain ids");
+        }
+        let root_entry = phys_ptr(self.root_table + addr.bus as u64 * 16);
+        if unsafe { *root_entry } & 1 == 0 {
+            let context_table = alloc_table()?;
+            self.set_entry(root_entry, context_table | 1);
+        }
+        let context_table = unsafe { *root_entry } & ADDR_MASK;
+        let root = alloc_table()?;
+        let context = phys_ptr(context_table + (addr.device as u64 * 8 + addr.function as u64) * 16);
+        let address_width = if self.levels == 4 { 2 } else { 1 };
+        // the high half first: the entry goes live when the present bit does
+        self.set_entry(unsafe { context.add(1) }, address_width | (id as u64) << 8);
+        self.set_entry(context, root | 1);
+        self.domains.push(Domain { device: addr, id: id as u16, root });
+        Ok(self.domains.len() - 1)
+    }
+
+    /// Point `iova` at `phys` in the tables under `root`, or unmap it when
+    /// `perms` is 0.
+    fn set_page(&self, root: u64, iova: u64, phys: u64, perms: u64) -> Result<(), &'static str> {
+        let mut table = root;
+        for level in (1..self.levels as u64).rev() {
+            let entry = unsafe { phys_ptr(table).add((iova >> (12 + 9 * level) & 0x1FF) as usize) };
+            if unsafe { *entry } & (DMA_READ | DMA_WRITE) == 0 {
+                if perms == 0 {
+                    return Ok(());
+                }
+                self.set_entry(entry, alloc_table()? | DMA_READ | DMA_WRITE);
+            }
+            table = unsafe { *entry } & ADDR_MASK;
+        }
+        let leaf = unsafe { phys_ptr(table).add((iova >> 12 & 0x1FF) as usize) };
+        self.set_entry(leaf, if perms == 0 { 0 } else { (phys & ADDR_MASK) | perms });
+        Ok(())
+    }
+}
+
+/// Set up a unit's root table, with translation still off.
+fn init_unit(dmar: &DmarUnit, phys_offset: u64) -> Result<Unit, &'static str> {
+    let regs = dmar.register_base + phys_offset;
+    let cap = unsafe { core::ptr::read_volatile((regs + REG_CAP as u64) as *const u64) };
+    if cap == u64::MAX {
+        return Err("no registers");
+    }
+    let sagaw = cap >> 8 & 0x1F;
+    let levels = if sagaw & 1 << 2 != 0 {
+        4
+    } else if sagaw & 1 << 1 != 0 {
+        3
+    } else {
+        return Err("no 3- or 4-level page tables");
+    };
+    let mut unit = Unit {
+        segment: dmar.segment,
+        register_base: dmar.register_base,
+        regs,
+        cap,
+        ecap: unsafe { core::ptr::read_volatile((regs + REG_ECAP as u64) as *const u64) },
+        include_pci_all: dmar.include_pci_all,
+        scopes: dmar.scopes.iter().filter_map(|s| resolve_scope(dmar.segment, s)).collect(),
+        levels,
+        root_table: 0,
+        domains: Vec::new(),
+        max_domains: 1 << (4 + 2 * (cap & 7)),
+    };
+    unit.root_table = alloc_table()?;
+    unit.write64(REG_RTADDR, unit.root_table);
+    unit.command(GCMD_SRTP, |sts| sts & GCMD_SRTP != 0)?;
+    unit.flush()?;
+    Ok(unit)
+}
+
+/// Index of the unit translating DMA from `addr`: one that lists it, or
+/// failing that the catch-all unit of its segment.
+fn unit_index(units: &[Unit], addr: PciAddress) -> Option<usize> {
+    units
+        .iter()
+        .position(|u| !u.include_pci_all && u.covers(addr))
+        .or_else(|| units.iter().position(|u| u.include_pci_all && u.segment == addr.segment))
+}
+
+/// Bring up every remapping unit in the DMAR, map the reserved regions
+/// for their devices and turn translation on. Returns how many units are
+/// translating.
+pub fn init_iommu(phys_offset: x86_64::VirtAddr) -> usize {
+    let Some(dmar) = crate::devices::acpi::get_dmar() else {
+        println!("[HAL][IOMMU] no DMAR table; device DMA is not remapped");
+        return 0;
+    };
+    PHYS_OFFSET.store(phys_offset.as_u64(), Ordering::Relaxed);
+    let mut units = UNITS.lock();
+    for dmar_unit in &dmar.units {
+        match init_unit(dmar_unit, phys_offset.as_u64()) {
+            Ok(unit) => units.push(unit),
+            Err(e) => println!("[HAL][IOMMU] unit @ {:#x} left off: {}", dmar_unit.register_base, e),
+        }
+    }
+    drop(units);
+
+    for region in &dmar.reserved {
+        for addr in region.scopes.iter().filter_map(|s| resolve_scope(region.segment, s)).map(|(addr, _)| addr) {
+            let len = region.limit.saturating_sub(region.base) + 1;
+            if let Err(e) = iommu_map(addr, region.base, region.base, len, DMA_READ | DMA_WRITE) {
+                println!("[HAL][IOMMU] reserved region {:#x}-{:#x} for {}: {}", region.base, region.limit, addr, e);
+            }
+        }
+    }
+
+    let units = UNITS.lock();
+    let mut enabled = 0;
+    for unit in units.iter() {
+        match unit.command(GCMD_TE, |sts| sts & GCMD_TE != 0) {
+            Ok(()) => {
+                enabled += 1;
+                println!(
+                    "[HAL][IOMMU] unit @ {:#x} seg {} translating ({}-level, {} reserved domains){}",
+                    unit.register_base,
+                    unit.segment,
+                    unit.levels,
+                    unit.domains.len(),
+                    if unit.include_pci_all { ", catch-all" } else { "" }
+                );
+            }
+            Err(e) => println!("[HAL][IOMMU] unit @ {:#x}: {}", unit.register_base, e),
+        }
+    }
+    enabled
+}
+
+/// Whether DMA from `addr` goes through an IOMMU that is translating.
+pub fn iommu_translates(addr: PciAddress) -> bool {
+    let units = UNITS.lock();
+    unit_index(&units, addr).is_some_and(|i| units[i].enabled())
+}
+
+/// Let `addr` reach `len` bytes at `phys` through bus addresses from
+/// `iova` on, with `perms` (`DMA_READ` and/or `DMA_WRITE`). Both addresses
+/// are rounded down to a page; 0 for `perms` unmaps the range instead.
+pub fn iommu_map(addr: PciAddress, iova: u64, phys: u64, len: u64, perms: u64) -> Result<(), &'static str> {
+    let mut units = UNITS.lock();
+    let unit = unit_index(&units, addr).ok_or("no IOMMU unit for the device")?;
+    let unit = &mut units[unit];
+    let domain = unit.domain_for(addr)?;
+    let root = unit.domains[domain].root;
+    let pages = (len + (iova & 0xFFF)).div_ceil(4096);
+    for page in 0..pages {
+        unit.set_page(root, (iova & !0xFFF) + page * 4096, (phys & !0xFFF) + page * 4096, perms)?;
+    }
+    unit.flush()
+}
+
+/// Take `len` bytes from `iova` on away from `addr`.
+pub fn iommu_unmap(addr: PciAddress, iova: u64, len: u64) -> Result<(), &'static str> {
+    iommu_map(addr, iova, 0, len, 0)
+}
+
+/// A remapping unit, as the `iommu` command shows it.
+pub struct IommuStatus {
+    pub segment: u16,
+    pub register_base: u64,
+    pub levels: u8,
+    pub translating: bool,
+    pub fault_status: u32,
+    /// (function, domain id)
+    pub domains: Vec<(PciAddress, u16)>,
+    /// (source function, reason, address, write) of each recorded fault.
+    pub faults: Vec<(PciAddress, u8, u64, bool)>,
+}
+
+/// Every unit's state and recorded faults. Reading the faults clears them,
+/// so the hardware can record new ones.
+pub fn iommu_status() -> Vec<IommuStatus> {
+    let units = UNITS.lock();
+    units
+        .iter()
+        .map(|unit| {
+            let fault_status = unit.read32(REG_FSTS);
+            let mut faults = Vec::new();
+            if fault_status & FSTS_PPF != 0 {
+                let base = ((unit.cap >> 24 & 0x3FF) * 16) as usize;
+                for record in 0..=(unit.cap >> 40 & 0xFF) as usize {
+                    let high = unit.read64(base + record * 16 + 8);
+                    if high & 1 << 63 == 0 {
+                        continue;
+                    }
+                    let source = high as u16;
+                    faults.push((
+                        PciAddress::new(unit.segment, (source >> 8) as u8, (source >> 3 & 0x1F) as u8, (source & 7) as u8),
+                        (high >> 32) as u8,
+                        unit.read64(base + record * 16) & !0xFFF,
+                        high & 1 << 62 == 0,
+                    ));
+                    // the fault bit is write-one-to-clear
+                    unit.write64(base + record * 16 + 8, 1 << 63);
+                }
+            }
+            IommuStatus {
+                segment: unit.segment,
+                register_base: unit.register_base,
+                levels: unit.levels,
+                translating: unit.enabled(),
+                fault_status,
+                domains: unit.domains.iter().map(|d| (d.device, d.id)).collect(),
+                faults,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,101 @@
+//! I/O port ownership
+//!
+//! A driver claims the ports of the controller it drives with
+//! `request_ports` before it touches them, naming its device and the
+//! controller. Claims for the same controller share ports, as the PS/2
+//! keyboard and mouse share the i8042's; a claim that overlaps another
+//! controller's ports is refused, so two drivers can't interleave accesses
+//! to one piece of hardware. Claims are a convention: the port
+//! instructions themselves are not checked.
+//!
+//! The device manager frees whatever a device still claims when its driver
+//! stops, as it does with its IRQs. Ports the kernel drives itself, such as
+//! the PCI configuration mechanism, are claimed with `claim_kernel_ports`
+//! and stay claimed.
+
+use crate::prelude::*;
+use crate::driver_framework::device::Device;
+use crate::sync::Spinlock;
+use alloc::vec::Vec;
+
+/// Device id kernel claims are held under; the manager never hands it out.
+pub const KERNEL_PORT_OWNER: usize = 0;
+
+#[derive(Clone, Copy)]
+struct PortClaim {
+    device: usize,
+    controller: &'static str,
+    base: u16,
+    len: u16,
+}
+
+impl PortClaim {
+    fn end(&self) -> u32 {
+        self.base as u32 + self.len as u32
+    }
+
+    fn overlaps(&self, base: u16, len: u16) -> bool {
+        (base as u32) < self.end() && (self.base as u32) < base as u32 + len as u32
+    }
+}
+
+static CLAIMS: Spinlock<Vec<PortClaim>> = Spinlock::new(Vec::new());
+
+fn claim(device: usize, controller: &'static str, base: u16, len: u16) -> Result<(), &'static str> {
+    if len == 0 || base as u32 + len as u32 > 0x1_0000 {
+        return Err("bad port range");
+    }
+    let mut claims = CLAIMS.lock();
+    if let Some(other) = claims.iter().find(|c| c.overlaps(base, len) && (c.controller != controller || c.device == device)) {
+        if other.controller != controller {
+            println!(
+                "[HAL][PORT] {:#x}-{:#x} for {} (device {}) overlaps {} (device {})",
+                base,
+                base as u32 + len as u32 - 1,
+                controller,
+                device,
+                other.controller,
+                other.device
+            );
+            return Err("I/O ports claimed by another controller");
+        }
+        return Err("I/O ports already claimed by this device");
+    }
+    claims.push(PortClaim { device, controller, base, len });
+    Ok(())
+}
+
+/// Claim `len` ports from `base` for `device`, which drives `controller`
+/// through them.
+pub fn request_ports(device: &Device, controller: &'static str, base: u16, len: u16) -> Result<(), &'static str> {
+    claim(device.id(), controller, base, len)
+}
+
+/// Claim ports the kernel drives itself, for good.
+pub fn claim_kernel_ports(controller: &'static str, base: u16, len: u16) -> Result<(), &'static str> {
+    claim(KERNEL_PORT_OWNER, controller, base, len)
+}
+
+/// Drop `device`'s claim starting at `base`. False if it had none.
+pub fn release_ports(device: &Device, base: u16) -> bool {
+    release(|c| c.device == device.id() && c.base == base) != 0
+}
+
+/// Drop every claim `device_id` still holds. Returns how many there were.
+pub fn free_device_ports(device_id: usize) -> usize {
+    release(|c| c.device == device_id)
+}
+
+fn release(matches: impl Fn(&PortClaim) -> bool) -> usize {
+    let mut claims = CLAIMS.lock();
+    let before = claims.len();
+    claims.retain(|c| !matches(c));
+    before - claims.len()
+}
+
+/// (device, controller, first port, count) of every claim, by port.
+pub fn port_claims() -> Vec<(usize, &'static str, u16, u16)> {
+    let mut claims: Vec<_> = CLAIMS.lock().iter().map(|c| (c.device, c.controller, c.base, c.len)).collect();
+    claims.sort_by_key(|c| (c.2, c.0));
+    claims
+}
@@ -0,0 +1,264 @@
+//! Interrupt line ownership
+//!
+//! IOAPIC inputs start masked and stay masked until a driver asks for one
+//! with `request_irq`, naming the device it asks for. The first request for
+//! a line routes its GSI to the vector, with the trigger mode and polarity
+//! of the MADT's source override, and unmasks it. Later requests share the
+//! line, and every handler on it is called, in the order they asked.
+//! `free_irq` drops a request, and the line is masked again when its last
+//! one goes. The device manager frees whatever a device still holds when
+//! its driver stops.
+//!
+//! In PIC mode (see `intctl`) the line is the PIC input of the ISA IRQ,
+//! and asking for it only unmasks it there; trigger mode and polarity are
+//! the PICs' own.
+//!
+//! Vectors 0x20-0x2F are the ISA IRQs 0-15, as in `ResourceKind::Interrupt`.
+//! Other vectors have no IOAPIC line; requesting one only installs the
+//! handler.
+//!
+//! An interrupt on a vector nobody handles is reported once in detail: the
+//! vector, and the IOAPIC entry (or PIC input) that delivers it. After that
+//! it is only counted, with a line at most every `UNHANDLED_REPORT_MS`
+//! saying how many came. A vector taking `IRQ_STORM_THRESHOLD` unhandled
+//! interrupts within one of those intervals is storming, and its line is
+//! masked until a driver requests it.
+
+use crate::prelude::*;
+use crate::arch::idt::IrqFn;
+use crate::driver_framework::device::Device;
+use crate::hal::intctl::IrqMode;
+use crate::sync::IrqSpinlock;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// First vector of the ISA IRQs.
+pub const ISA_IRQ_VECTOR_BASE: u8 = 0x20;
+
+#[derive(Clone, Copy)]
+struct IrqAction {
+    device: usize,
+    vector: u8,
+    /// IOAPIC input for ISA IRQs, or in PIC mode the ISA IRQ itself.
+    gsi: Option<u32>,
+    handler: IrqFn,
+}
+
+// Read by `dispatch` in interrupt context.
+static ACTIONS: IrqSpinlock<Vec<IrqAction>> = IrqSpinlock::new(Vec::new());
+
+/// The ISA IRQ `vector` stands for, if it stands for one.
+pub fn isa_irq_for_vector(vector: u8) -> Option<u8> {
+    vector.checked_sub(ISA_IRQ_VECTOR_BASE).filter(|&irq| irq < 16)
+}
+
+/// Call `handler` on interrupts at `vector` for `device`, unmasking the
+/// line behind it if it is the first request for the line.
+pub fn request_irq(device: &Device, vector: u8, handler: IrqFn) -> Result<(), &'static str> {
+    if vector < 32 {
+        return Err("exception vector");
+    }
+    let mode = crate::hal::intctl::irq_mode();
+    let gsi_route = isa_irq_for_vector(vector).and_then(|irq| match mode {
+        IrqMode::Apic => Some(crate::hal::ioapic::isa_irq_route(&crate::devices::acpi::get_isos(), irq)),
+        IrqMode::Pic => Some((irq as u32, 0)),
+        IrqMode::None => None,
+    });
+    let mut actions = ACTIONS.lock();
+    if actions.iter().any(|a| a.device == device.id() && a.vector == vector) {
+        return Err("IRQ already requested by this device");
+    }
+    if let Some((gsi, _)) = gsi_route
+        && actions.iter().any(|a| a.gsi == Some(gsi) && a.vector != vector)
+    {
+        return Err("line routed to another vector");
+    }
+    let first_on_vector = !actions.iter().any(|a| a.vector == vector);
+    let first_on_line = gsi_route.is_some_and(|(gsi, _)| !actions.iter().any(|a| a.gsi == Some(gsi)));
+    actions.push(IrqAction { device: device.id(), vector, gsi: gsi_route.map(|(gsi, _)| gsi), handler });
+    drop(actions);
+    // a line masked for storming is unmasked below like any other
+    UNHANDLED[vector as usize].masked.store(false, Ordering::Relaxed);
+
+    if first_on_vector {
+        crate::arch::idt::register_irq_fn(vector, dispatch);
+    }
+    if let Some((irq, _)) = gsi_route
+        && first_on_line
+        && mode == IrqMode::Pic
+    {
+        crate::hal::intctl::pic_unmask(irq as u8);
+        println!("[HAL][IRQ] PIC IRQ {} -> vector {:#x} for device {}", irq, vector, device.id());
+    } else if let Some((gsi, bits)) = gsi_route
+        && first_on_line
+    {
+        let offset = crate::hal::ioapic::ioapic_phys_offset();
+        let apic_id = crate::hal::apic::local_apic_id().unwrap_or(0);
+        if !(crate::hal::ioapic::set_gsi_trigger_polarity(gsi, bits, offset) && crate::hal::ioapic::unmask_gsi(gsi, vector, apic_id, offset)) {
+            free_irq(device, vector);
+            return Err("no IOAPIC input for the line");
+        }
+        println!(
+            "[HAL][IRQ] GSI {} -> vector {:#x} apic {} ({}, active {}) for device {}",
+            gsi,
+            vector,
+            apic_id,
+            if bits & crate::hal::ioapic::REDIR_LEVEL_TRIGGERED != 0 { "level" } else { "edge" },
+            if bits & crate::hal::ioapic::REDIR_ACTIVE_LOW != 0 { "low" } else { "high" },
+            device.id()
+        );
+    }
+    Ok(())
+}
+
+/// Drop `device`'s request for `vector`. False if it had none.
+pub fn free_irq(device: &Device, vector: u8) -> bool {
+    release(|a| a.device == device.id() && a.vector == vector) != 0
+}
+
+/// Drop every request `device` still holds. Returns how many there were.
+pub fn free_device_irqs(device_id: usize) -> usize {
+    release(|a| a.device == device_id)
+}
+
+/// Remove the matching requests, masking lines and restoring vectors
+/// nobody uses any more.
+fn release(matches: impl Fn(&IrqAction) -> bool) -> usize {
+    let mut actions = ACTIONS.lock();
+    let gone: Vec<IrqAction> = actions.iter().copied().filter(|a| matches(a)).collect();
+    actions.retain(|a| !matches(a));
+    let idle_lines: Vec<u32> =
+        gone.iter().filter_map(|a| a.gsi).filter(|&gsi| !actions.iter().any(|b| b.gsi == Some(gsi))).collect();
+    let idle_vectors: Vec<u8> =
+        gone.iter().map(|a| a.vector).filter(|&v| !actions.iter().any(|b| b.vector == v)).collect();
+    drop(actions);
+    for gsi in idle_lines {
+        match crate::hal::intctl::irq_mode() {
+            IrqMode::Pic => crate::hal::intctl::pic_mask(gsi as u8),
+            _ => {
+                crate::hal::ioapic::mask_gsi(gsi, crate::hal::ioapic::ioapic_phys_offset());
+            }
+        }
+    }
+    for vector in idle_vectors {
+        crate::arch::idt::unregister_irq_fn(vector);
+    }
+    gone.len()
+}
+
+/// (device, vector, GSI) of every request, in the order they were made.
+pub fn irq_requests() -> Vec<(usize, u8, Option<u32>)> {
+    ACTIONS.lock().iter().map(|a| (a.device, a.vector, a.gsi)).collect()
+}
+
+/// Run the handlers requested for `vector`.
+fn dispatch(vector: u8) {
+    let actions = ACTIONS.lock();
+    for action in actions.iter().filter(|a| a.vector == vector) {
+        (action.handler)(vector);
+    }
+}
+
+/// Shortest time between two reports of unhandled interrupts on a vector.
+pub const UNHANDLED_REPORT_MS: u64 = 1000;
+/// Unhandled interrupts within one report interval that get a line masked.
+pub const IRQ_STORM_THRESHOLD: u64 = 1000;
+
+struct Unhandled {
+    count: AtomicU64,
+    /// When the current report interval began.
+    interval_start_ms: AtomicU64,
+    /// `count` when it began.
+    interval_base: AtomicU64,
+    masked: AtomicBool,
+}
+
+impl Unhandled {
+    const fn new() -> Self {
+        Unhandled {
+            count: AtomicU64::new(0),
+            interval_start_ms: AtomicU64::new(0),
+            interval_base: AtomicU64::new(0),
+            masked: AtomicBool::new(false),
+        }
+    }
+}
+
+static UNHANDLED: [Unhandled; 256] = [const { Unhandled::new() }; 256];
+
+/// Where interrupts on `vector` come from: the IOAPIC entry or PIC input
+/// that delivers it, if there is one.
+fn describe_source(vector: u8) {
+    match crate::hal::intctl::irq_mode() {
+        IrqMode::Apic => match crate::hal::ioapic::gsi_for_vector(vector) {
+            Some((gsi, low, high)) => println!(
+                "[HAL][IRQ]   from GSI {}: entry {:#010x}_{:08x}, {}, {}{}",
+                gsi,
+                high,
+                low,
+                if low & crate::hal::ioapic::REDIR_LEVEL_TRIGGERED != 0 { "level" } else { "edge" },
+                if low & crate::hal::ioapic::REDIR_ACTIVE_LOW != 0 { "active low" } else { "active high" },
+                if low & crate::hal::ioapic::REDIR_MASKED != 0 { ", masked" } else { "" }
+            ),
+            None => println!("[HAL][IRQ]   no IOAPIC entry delivers it (MSI, IPI or a stray vector)"),
+        },
+        IrqMode::Pic => match isa_irq_for_vector(vector) {
+            Some(irq) => println!("[HAL][IRQ]   from PIC IRQ {}", irq),
+            None => println!("[HAL][IRQ]   not a PIC vector"),
+        },
+        IrqMode::None => {}
+    }
+}
+
+/// Mask the line delivering `vector`. False if there is none to mask.
+fn mask_vector_line(vector: u8) -> bool {
+    match crate::hal::intctl::irq_mode() {
+        IrqMode::Apic => crate::hal::ioapic::gsi_for_vector(vector)
+            .is_some_and(|(gsi, _, _)| crate::hal::ioapic::mask_gsi(gsi, crate::hal::ioapic::ioapic_phys_offset())),
+        IrqMode::Pic => isa_irq_for_vector(vector).map(crate::hal::intctl::pic_mask).is_some(),
+        IrqMode::None => false,
+    }
+}
+
+/// Called by the interrupt entry for an interrupt on `vector` that has no
+/// handler, before its EOI.
+pub fn unhandled_irq(vector: u8) {
+    let state = &UNHANDLED[vector as usize];
+    let count = state.count.fetch_add(1, Ordering::Relaxed) + 1;
+    let now = crate::time::uptime_ns() / 1_000_000;
+    if count == 1 {
+        state.interval_start_ms.store(now, Ordering::Relaxed);
+        println!("[HAL][IRQ] unhandled interrupt on vector {:#04x}", vector);
+        describe_source(vector);
+        return;
+    }
+    let start = state.interval_start_ms.load(Ordering::Relaxed);
+    let base = state.interval_base.load(Ordering::Relaxed);
+    if now.saturating_sub(start) >= UNHANDLED_REPORT_MS {
+        state.interval_start_ms.store(now, Ordering::Relaxed);
+        state.interval_base.store(count, Ordering::Relaxed);
+        println!(
+            "[HAL][IRQ] vector {:#04x}: {} unhandled interrupts in {} ms, {} in all",
+            vector,
+            count - base,
+            now - start,
+            count
+        );
+    } else if count - base >= IRQ_STORM_THRESHOLD && !state.masked.swap(true, Ordering::Relaxed) {
+        if mask_vector_line(vector) {
+            println!("[HAL][IRQ] vector {:#04x}: interrupt storm, {} unhandled in {} ms; line masked", vector, count - base, now - start);
+        } else {
+            println!("[HAL][IRQ] vector {:#04x}: interrupt storm, {} unhandled in {} ms; no line to mask", vector, count - base, now - start);
+        }
+    }
+}
+
+/// (vector, unhandled interrupts, masked for storming) of every vector
+/// that has had an unhandled interrupt.
+pub fn unhandled_irqs() -> Vec<(u8, u64, bool)> {
+    (0..=255u8)
+        .map(|v| (v, &UNHANDLED[v as usize]))
+        .filter(|(_, u)| u.count.load(Ordering::Relaxed) != 0)
+        .map(|(v, u)| (v, u.count.load(Ordering::Relaxed), u.masked.load(Ordering::Relaxed)))
+        .collect()
+}
@@ -7,5 +7,18 @@ pub mod hal;
 pub use hal::*;
 pub mod apic;
 pub use apic::*;
+pub mod smp;
 pub mod ioapic;
-pub use ioapic::*;
\ No newline at end of file
+pub use ioapic::*;
+pub mod intctl;
+pub use intctl::*;
+pub mod irq;
+pub use irq::*;
+pub mod iommu;
+pub use iommu::*;
+pub mod dma;
+pub use dma::*;
+pub mod bar;
+pub use bar::*;
+pub mod ioport;
+pub use ioport::*;
@@ -0,0 +1,347 @@
+//! SMP bring-up
+//!
+//! Only the BSP runs until `start_aps`. It starts every other processor the
+//! MADT lists as enabled with the INIT-SIPI-SIPI sequence through the local
+//! APIC, one at a time. A startup IPI starts the processor in real mode at
+//! a page below 1 MiB, so a copy of the trampoline below goes into a free
+//! page of conventional memory, identity-mapped while the APs start.
+//!
+//! The trampoline switches to protected mode, then to long mode on the
+//! BSP's page tables (which is why they have to sit below 4 GiB), takes the
+//! BSP's CR0 and CR4 and calls `ap_entry` on a kernel stack of its own
+//! (`memory::stack`). That gives the CPU its GDT, TSS, IDT and syscall MSRs
+//! (`arch::ap::init_ap`), sets up its local APIC and timer, and runs the
+//! CPU's executor, which takes tasks off the shared queues like the BSP's.
+//!
+//! CPUs are numbered in the order they came up, the BSP being 0. An AP that
+//! doesn't report in time is sent INIT again, which parks it, and gets no
+//! number.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+use crate::hal::apic;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Vector of the IPI that wakes a CPU halted in its idle loop.
+pub const WAKE_VECTOR: u8 = 0xF1;
+/// How long an AP has to report in after its second startup IPI.
+const AP_START_TIMEOUT_MS: u64 = 100;
+/// EFER.LMA, set by the CPU itself once long mode is active.
+const EFER_LMA: u64 = 1 << 10;
+
+/// What the trampoline needs from the BSP, filled in before each AP starts.
+#[repr(C)]
+struct ApParams {
+    cr3: u64,
+    efer: u64,
+    cr0: u64,
+    cr4: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+// Runs at whatever page it is copied to, entered at its first byte with
+// CS = page >> 4. ebx holds the page's address throughout; the GDT pointer
+// and both far jumps are patched with it by `start_aps`.
+global_asm!(
+    ".pushsection .text.neutrix_ap_trampoline, \"ax\"",
+    ".balign 16",
+    ".global neutrix_ap_trampoline",
+    ".global neutrix_ap_trampoline_end",
+    ".global neutrix_ap_gdtr",
+    ".global neutrix_ap_jump32",
+    ".global neutrix_ap_protected",
+    ".global neutrix_ap_jump64",
+    ".global neutrix_ap_long",
+    ".global neutrix_ap_params",
+    ".code16",
+    "neutrix_ap_trampoline:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lgdt [AP_GDTR]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // jmp far dword 0x08:(page + neutrix_ap_protected)
+    "neutrix_ap_jump32:",
+    ".byte 0x66, 0xEA",
+    ".long 0",
+    ".short 0x08",
+    ".code32",
+    "neutrix_ap_protected:",
+    "mov ax, 0x18",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [ebx + AP_PARAMS + {cr3}]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, [ebx + AP_PARAMS + {efer}]",
+    "mov edx, [ebx + AP_PARAMS + {efer} + 4]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 1 << 31",
+    "mov cr0, eax",
+    // jmp far 0x10:(page + neutrix_ap_long)
+    "neutrix_ap_jump64:",
+    ".byte 0xEA",
+    ".long 0",
+    ".short 0x10",
+    ".code64",
+    "neutrix_ap_long:",
+    // the upper halves are undefined after the switch
+    "mov ebx, ebx",
+    "mov rax, [rbx + AP_PARAMS + {cr4}]",
+    "mov cr4, rax",
+    "mov rax, [rbx + AP_PARAMS + {cr0}]",
+    "mov cr0, rax",
+    "mov rsp, [rbx + AP_PARAMS + {stack}]",
+    "mov rdi, [rbx + AP_PARAMS + {cpu}]",
+    "xor ebp, ebp",
+    "call [rbx + AP_PARAMS + {entry}]",
+    "ud2",
+    ".balign 8",
+    // null, 32-bit code, 64-bit code, data
+    "neutrix_ap_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    "neutrix_ap_gdtr:",
+    ".short 4 * 8 - 1",
+    ".long 0",
+    ".balign 8",
+    "neutrix_ap_params:",
+    ".fill {params_len}, 1, 0",
+    "neutrix_ap_trampoline_end:",
+    ".set AP_GDTR, neutrix_ap_gdtr - neutrix_ap_trampoline",
+    ".set AP_PARAMS, neutrix_ap_params - neutrix_ap_trampoline",
+    ".popsection",
+    cr3 = const offset_of!(ApParams, cr3),
+    efer = const offset_of!(ApParams, efer),
+    cr0 = const offset_of!(ApParams, cr0),
+    cr4 = const offset_of!(ApParams, cr4),
+    stack = const offset_of!(ApParams, stack),
+    entry = const offset_of!(ApParams, entry),
+    cpu = const offset_of!(ApParams, cpu),
+    params_len = const size_of::<ApParams>(),
+);
+
+unsafe extern "C" {
+    static neutrix_ap_trampoline: u8;
+    static neutrix_ap_trampoline_end: u8;
+    static neutrix_ap_gdtr: u8;
+    static neutrix_ap_jump32: u8;
+    static neutrix_ap_protected: u8;
+    static neutrix_ap_jump64: u8;
+    static neutrix_ap_long: u8;
+    static neutrix_ap_params: u8;
+}
+
+/// Set by an AP once it is registered and done with the trampoline.
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+/// The trampoline as assembled, before patching.
+fn trampoline_code() -> &'static [u8] {
+    unsafe {
+        let start = &raw const neutrix_ap_trampoline;
+        let end = &raw const neutrix_ap_trampoline_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Offset of trampoline label `label` from its first byte.
+fn offset_of_label(label: *const u8) -> usize {
+    label as usize - (&raw const neutrix_ap_trampoline) as usize
+}
+
+/// Copy the trampoline into the page at physical `page`, seen at `virt`,
+/// with its GDT pointer and jumps aimed at it. Returns where its
+/// parameters went.
+///
+/// # Safety
+/// `virt` must map a page nothing else uses.
+unsafe fn install_trampoline(virt: u64, page: u32) -> *mut ApParams {
+    let code = trampoline_code();
+    assert!(code.len() <= 4096, "AP trampoline larger than a page");
+    let patch = |label: *const u8, at: usize, value: u32| unsafe {
+        ((virt as usize + offset_of_label(label) + at) as *mut u32).write_unaligned(value);
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), virt as *mut u8, code.len());
+        let gdt = offset_of_label(&raw const neutrix_ap_gdtr) - 4 * 8;
+        patch(&raw const neutrix_ap_gdtr, 2, page + gdt as u32);
+        patch(&raw const neutrix_ap_jump32, 2, page + offset_of_label(&raw const neutrix_ap_protected) as u32);
+        patch(&raw const neutrix_ap_jump64, 1, page + offset_of_label(&raw const neutrix_ap_long) as u32);
+        (virt as usize + offset_of_label(&raw const neutrix_ap_params)) as *mut ApParams
+    }
+}
+
+/// Number of CPUs running an executor.
+pub fn cpu_count() -> usize {
+    crate::arch::task::online_cpus()
+}
+
+/// Index of the CPU we're running on; the BSP is 0.
+pub fn current_cpu() -> usize {
+    crate::arch::task::current_cpu()
+}
+
+/// Start every application processor the MADT lists as enabled. Needs the
+/// local APIC and the timer set up on the BSP. Returns how many CPUs are
+/// online afterwards.
+pub fn start_aps() -> usize {
+    let bsp_apic = match apic::local_apic_id() {
+        Some(id) => id,
+        None => return cpu_count(),
+    };
+    // CPU 0 is online from boot, under APIC ID 0 until now
+    crate::arch::task::register_cpu(0, bsp_apic);
+    let targets: Vec<u8> = crate::devices::acpi::get_local_apics()
+        .iter()
+        .filter(|c| c.enabled && c.apic_id != bsp_apic && c.apic_id != 0xFF)
+        .map(|c| c.apic_id)
+        .collect();
+    if targets.is_empty() {
+        return cpu_count();
+    }
+    if let Err(e) = start_all(&targets) {
+        println!("[SMP] application processors not started: {}", e);
+    }
+    println!("[SMP] {} of {} CPUs online", cpu_count(), targets.len() + 1);
+    cpu_count()
+}
+
+fn start_all(targets: &[u8]) -> Result<(), &'static str> {
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        return Err("5-level paging");
+    }
+    let (pml4, _) = Cr3::read_raw();
+    if pml4.start_address().as_u64() >= 1 << 32 {
+        return Err("page tables above 4 GiB");
+    }
+    let frame = crate::memory::with_frame_allocator(|f| f.low_memory_frame())
+        .flatten()
+        .ok_or("no free page below 640 KiB")?;
+    let phys = frame.start_address().as_u64();
+    let offset = crate::boot::boot_params().ok_or("no physical memory mapping")?.phys_offset;
+    let params = unsafe { install_trampoline(offset + phys, phys as u32) };
+    unsafe {
+        params.write(ApParams {
+            cr3: pml4.start_address().as_u64(),
+            efer: Efer::read_raw() & !EFER_LMA,
+            cr0: Cr0::read_raw(),
+            cr4: Cr4::read_raw(),
+            stack: 0,
+            entry: ap_entry as usize as u64,
+            cpu: 0,
+        });
+    }
+
+    // the trampoline runs at its physical address with paging on
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(phys));
+    let mut space = crate::memory::kernel_space().ok_or("kernel space not set up")?;
+    let mapped = match space.translate(page.start_address()) {
+        Some(addr) if addr.as_u64() == phys => false,
+        Some(_) => return Err("trampoline address mapped to other memory"),
+        None => {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { space.map(page, frame, flags) }.map_err(|_| "mapping the trampoline failed")?;
+            true
+        }
+    };
+    drop(space);
+
+    crate::arch::idt::register_irq_fn(WAKE_VECTOR, |_| {});
+    for &apic_id in targets {
+        let cpu = cpu_count();
+        if cpu >= MAX_CPUS {
+            println!("[SMP] only {} CPUs supported", MAX_CPUS);
+            break;
+        }
+        if let Err(e) = start_ap(apic_id, cpu, (phys >> 12) as u8, params) {
+            println!("[SMP] APIC {}: {}", apic_id, e);
+        }
+    }
+    if cpu_count() > 1 {
+        crate::arch::task::set_wake_ipi(wake_cpu);
+    }
+
+    if mapped && let Some(mut space) = crate::memory::kernel_space() {
+        let _ = space.unmap(page);
+    }
+    Ok(())
+}
+
+/// Start the CPU with local APIC `apic_id` as CPU `cpu`, through the
+/// trampoline at `page << 12`.
+fn start_ap(apic_id: u8, cpu: usize, page: u8, params: *mut ApParams) -> Result<(), &'static str> {
+    // kept: the CPU runs on it for good, or may yet if it started late
+    let stack = crate::memory::alloc_kernel_stack()?;
+    let top = stack.top();
+    core::mem::forget(stack);
+    unsafe {
+        (&raw mut (*params).stack).write_volatile(top);
+        (&raw mut (*params).cpu).write_volatile(cpu as u64);
+    }
+    AP_READY.store(false, Ordering::SeqCst);
+    if !apic::send_init(apic_id) {
+        return Err("INIT not delivered");
+    }
+    crate::time::delay::mdelay(10);
+    for timeout_ms in [1, AP_START_TIMEOUT_MS] {
+        if !apic::send_startup(apic_id, page) {
+            return Err("startup IPI not delivered");
+        }
+        if wait_ready(timeout_ms) {
+            return Ok(());
+        }
+    }
+    // park it rather than have it turn up later on the next CPU's stack
+    apic::send_init(apic_id);
+    Err("did not start")
+}
+
+fn wait_ready(ms: u64) -> bool {
+    for _ in 0..ms * 10 {
+        if AP_READY.load(Ordering::SeqCst) {
+            return true;
+        }
+        crate::time::delay::udelay(100);
+    }
+    AP_READY.load(Ordering::SeqCst)
+}
+
+/// Where the trampoline leaves each AP, on its own stack.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    crate::arch::ap::init_ap(cpu);
+    apic::init_ap_lapic();
+    crate::arch::task::register_cpu(cpu, apic::local_apic_id().unwrap_or(0));
+    AP_READY.store(true, Ordering::SeqCst);
+    crate::arch::tsc_timer::start_cpu_timer();
+    x86_64::instructions::interrupts::enable();
+    crate::arch::task::Executor::for_cpu(cpu).run()
+}
+
+/// `set_wake_ipi` hook: interrupt `cpu` out of `hlt`.
+fn wake_cpu(cpu: usize) {
+    if let Some(apic_id) = crate::arch::task::cpu_apic_id(cpu) {
+        apic::send_ipi(apic_id, WAKE_VECTOR);
+    }
+}
@@ -0,0 +1,243 @@
+//! Hotkeys
+//!
+//! A hotkey is a key pressed while a set of modifiers is held, e.g.
+//! Ctrl+Alt+Del. Components register one with a handler; the tty hands
+//! every key event to `intercept` before decoding it, and a press matching
+//! a hotkey runs the handler instead of reaching `getline` or a process.
+//! Modifiers match exactly, so Ctrl+Alt+L doesn't also fire on
+//! Ctrl+Alt+Shift+L, and left and right modifier keys count the same.
+//!
+//! Handlers run in the task that feeds the tty, after the tty lock is
+//! released, so they may print, allocate and block.
+
+use crate::prelude::*;
+use crate::sync::Spinlock;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+pub const MOD_CTRL: u8 = 1 << 0;
+pub const MOD_ALT: u8 = 1 << 1;
+pub const MOD_SHIFT: u8 = 1 << 2;
+pub const MOD_META: u8 = 1 << 3;
+
+const MODIFIER_NAMES: [(&str, u8); 4] = [("ctrl", MOD_CTRL), ("alt", MOD_ALT), ("shift", MOD_SHIFT), ("meta", MOD_META)];
+
+/// Names `KeyCombo::parse` accepts for keys other than letters and digits.
+const KEY_NAMES: [(&str, KeyCode); 30] = [
+    ("esc", KeyCode::Escape),
+    ("f1", KeyCode::F1),
+    ("f2", KeyCode::F2),
+    ("f3", KeyCode::F3),
+    ("f4", KeyCode::F4),
+    ("f5", KeyCode::F5),
+    ("f6", KeyCode::F6),
+    ("f7", KeyCode::F7),
+    ("f8", KeyCode::F8),
+    ("f9", KeyCode::F9),
+    ("f10", KeyCode::F10),
+    ("f11", KeyCode::F11),
+    ("f12", KeyCode::F12),
+    ("del", KeyCode::Delete),
+    ("ins", KeyCode::Insert),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pgup", KeyCode::PageUp),
+    ("pgdn", KeyCode::PageDown),
+    ("up", KeyCode::ArrowUp),
+    ("down", KeyCode::ArrowDown),
+    ("left", KeyCode::ArrowLeft),
+    ("right", KeyCode::ArrowRight),
+    ("tab", KeyCode::Tab),
+    ("enter", KeyCode::Return),
+    ("space", KeyCode::Spacebar),
+    ("backspace", KeyCode::Backspace),
+    ("prtsc", KeyCode::PrintScreen),
+    ("sysrq", KeyCode::SysRq),
+    ("pause", KeyCode::PauseBreak),
+];
+
+const LETTERS: [KeyCode; 26] = [
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::I,
+    KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N, KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R,
+    KeyCode::S, KeyCode::T, KeyCode::U, KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+];
+
+const DIGITS: [KeyCode; 10] = [
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+];
+
+/// Modifiers held and the key pressed with them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyCombo {
+    pub modifiers: u8,
+    pub key: KeyCode,
+}
+
+impl KeyCombo {
+    pub const fn new(modifiers: u8, key: KeyCode) -> KeyCombo {
+        KeyCombo { modifiers, key }
+    }
+
+    /// Parse `ctrl+alt+del` style names: modifiers, then one key, joined by
+    /// `+`, in any case.
+    pub fn parse(s: &str) -> Option<KeyCombo> {
+        let mut parts = s.split('+').map(str::trim);
+        let key = key_by_name(parts.next_back()?)?;
+        let mut modifiers = 0;
+        for part in parts {
+            let (_, bit) = MODIFIER_NAMES.iter().find(|(name, _)| part.eq_ignore_ascii_case(name))?;
+            modifiers |= bit;
+        }
+        Some(KeyCombo { modifiers, key })
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, bit) in MODIFIER_NAMES {
+            if self.modifiers & bit != 0 {
+                write!(f, "{}+", name)?;
+            }
+        }
+        if let Some(i) = LETTERS.iter().position(|&k| k == self.key) {
+            write!(f, "{}", (b'a' + i as u8) as char)
+        } else if let Some(i) = DIGITS.iter().position(|&k| k == self.key) {
+            write!(f, "{}", i)
+        } else if let Some((name, _)) = KEY_NAMES.iter().find(|(_, k)| *k == self.key) {
+            f.write_str(name)
+        } else {
+            write!(f, "{:?}", self.key)
+        }
+    }
+}
+
+fn key_by_name(name: &str) -> Option<KeyCode> {
+    let lower = name.to_ascii_lowercase();
+    match lower.as_bytes() {
+        [c @ b'a'..=b'z'] => return Some(LETTERS[(c - b'a') as usize]),
+        [c @ b'0'..=b'9'] => return Some(DIGITS[(c - b'0') as usize]),
+        _ => {}
+    }
+    KEY_NAMES.iter().find(|(n, _)| *n == lower || (lower == "delete" && *n == "del")).map(|&(_, k)| k)
+}
+
+#[derive(Clone, Copy)]
+pub struct Hotkey {
+    pub combo: KeyCombo,
+    /// What it does, for listings.
+    pub name: &'static str,
+    pub handler: fn(),
+}
+
+static HOTKEYS: Spinlock<Vec<Hotkey>> = Spinlock::new(Vec::new());
+/// Modifier keys down now: `MOD_*` bits for the left-hand keys, the same
+/// bits shifted up four for the right-hand ones, so letting go of one of a
+/// pair leaves the other held.
+static MODIFIERS: AtomicU8 = AtomicU8::new(0);
+
+/// Run `handler` whenever `combo` is pressed. Fails if something already
+/// has the combination.
+pub fn register_hotkey(combo: KeyCombo, name: &'static str, handler: fn()) -> Result<(), &'static str> {
+    let mut hotkeys = HOTKEYS.lock();
+    if hotkeys.iter().any(|h| h.combo == combo) {
+        return Err("hotkey already registered");
+    }
+    hotkeys.push(Hotkey { combo, name, handler });
+    Ok(())
+}
+
+/// Remove the hotkey for `combo`. False if there wasn't one.
+pub fn unregister_hotkey(combo: KeyCombo) -> bool {
+    let mut hotkeys = HOTKEYS.lock();
+    let before = hotkeys.len();
+    hotkeys.retain(|h| h.combo != combo);
+    hotkeys.len() != before
+}
+
+pub fn hotkeys() -> Vec<Hotkey> {
+    HOTKEYS.lock().clone()
+}
+
+/// `MOD_*` bits for the modifier keys held now.
+pub fn held_modifiers() -> u8 {
+    let held = MODIFIERS.load(Ordering::Relaxed);
+    (held | (held >> 4)) & 0x0F
+}
+
+/// The modifier bit `code` sets, shifted into the high nibble for the
+/// right-hand key of a pair.
+fn modifier_bit(code: KeyCode) -> Option<u8> {
+    Some(match code {
+        KeyCode::LControl => MOD_CTRL,
+        KeyCode::RControl => MOD_CTRL << 4,
+        KeyCode::LAlt => MOD_ALT,
+        KeyCode::RAltGr => MOD_ALT << 4,
+        KeyCode::LShift => MOD_SHIFT,
+        KeyCode::RShift => MOD_SHIFT << 4,
+        KeyCode::LWin => MOD_META,
+        KeyCode::RWin => MOD_META << 4,
+        _ => return None,
+    })
+}
+
+/// Look at a key event before it is decoded. Returns the hotkey it
+/// presses, whose handler the caller runs instead of delivering the
+/// event; `None` means deliver it as usual. Modifier keys always go on to
+/// the decoder, which keeps its own shift and control state.
+pub fn intercept(event: &KeyEvent) -> Option<Hotkey> {
+    if let Some(bit) = modifier_bit(event.code) {
+        match event.state {
+            KeyState::Down => MODIFIERS.fetch_or(bit, Ordering::Relaxed),
+            KeyState::Up => MODIFIERS.fetch_and(!bit, Ordering::Relaxed),
+            _ => 0,
+        };
+        return None;
+    }
+    if event.state != KeyState::Down {
+        return None;
+    }
+    let combo = KeyCombo::new(held_modifiers(), event.code);
+    HOTKEYS.lock().iter().find(|h| h.combo == combo).copied()
+}
+
+fn dump_log_to_serial() {
+    let mut buf = alloc::vec![0u8; crate::log::LOG_RING_SIZE];
+    let n = crate::log::read_log_ring(&mut buf);
+    crate::devices::serial::serial_write_str("\n----- log ring -----\n");
+    for chunk in buf[..n].utf8_chunks() {
+        crate::devices::serial::serial_write_str(chunk.valid());
+    }
+    crate::devices::serial::serial_write_str("\n----- end of log ring -----\n");
+    println!("[INPUT] log ring written to serial ({} bytes)", n);
+}
+
+/// Move the screen's brightness by `percent` and gamma by `hundredths`,
+/// stopping at the ends of their ranges.
+fn step_display_levels(percent: i32, hundredths: i32) {
+    use crate::driver_framework::drivers::vbe_vga::{self, BRIGHTNESS_MAX, BRIGHTNESS_MIN, GAMMA_MAX, GAMMA_MIN};
+    let (brightness, gamma) = vbe_vga::display_levels();
+    let brightness = brightness.saturating_add_signed(percent).clamp(BRIGHTNESS_MIN, BRIGHTNESS_MAX);
+    let gamma = gamma.saturating_add_signed(hundredths).clamp(GAMMA_MIN, GAMMA_MAX);
+    if vbe_vga::set_display_levels(brightness, gamma).is_ok() {
+        println!("[INPUT] brightness {}% gamma {}.{:02}", brightness, gamma / 100, gamma % 100);
+    }
+}
+
+pub(crate) fn register_default_hotkeys() {
+    let defaults: [(KeyCombo, &'static str, fn()); 6] = [
+        (KeyCombo::new(MOD_CTRL | MOD_ALT, KeyCode::Delete), "reboot", || crate::arch::processor::reboot()),
+        (KeyCombo::new(MOD_CTRL | MOD_ALT, KeyCode::L), "dump the log ring to serial", dump_log_to_serial),
+        (KeyCombo::new(MOD_CTRL | MOD_ALT, KeyCode::PageUp), "brighten the screen", || step_display_levels(10, 0)),
+        (KeyCombo::new(MOD_CTRL | MOD_ALT, KeyCode::PageDown), "dim the screen", || step_display_levels(-10, 0)),
+        (KeyCombo::new(MOD_CTRL | MOD_ALT | MOD_SHIFT, KeyCode::PageUp), "raise the screen gamma", || step_display_levels(0, 10)),
+        (KeyCombo::new(MOD_CTRL | MOD_ALT | MOD_SHIFT, KeyCode::PageDown), "lower the screen gamma", || step_display_levels(0, -10)),
+    ];
+    for (combo, name, handler) in defaults {
+        if let Err(e) = register_hotkey(combo, name, handler) {
+            kwarn_once!("[INPUT] hotkey {}: {}", combo, e);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Input idle detection and screen blanking
+//!
+//! The keyboard and mouse paths call `note_input` for everything they
+//! receive. Once nothing has come in for the blank timeout the screen is
+//! turned off with `vbe_vga::set_screen_blanked`, and the next key or mouse
+//! report turns it back on; that input is delivered as usual.
+//!
+//! The check runs on a one-shot timer callback armed for when the timeout
+//! would run out. Input only records the time, so when the callback finds
+//! there was some since, it arms itself again for the rest of the timeout.
+//! A timeout of 0 turns blanking off.
+
+use crate::prelude::*;
+use crate::driver_framework::drivers::vbe_vga::{screen_blanked, set_screen_blanked};
+use crate::sync::Spinlock;
+use crate::time::timer::TimerId;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+pub const DEFAULT_BLANK_SECS: u64 = 600;
+
+static LAST_INPUT_NS: AtomicU64 = AtomicU64::new(0);
+static BLANK_SECS: AtomicU64 = AtomicU64::new(DEFAULT_BLANK_SECS);
+/// Set by `start_blank_timer`, once timer callbacks run.
+static STARTED: AtomicBool = AtomicBool::new(false);
+static TIMER: Spinlock<Option<TimerId>> = Spinlock::new(None);
+
+/// Record keyboard or mouse input, waking the screen if it was blanked.
+pub fn note_input() {
+    LAST_INPUT_NS.store(crate::time::uptime_ns(), Ordering::Relaxed);
+    if screen_blanked() {
+        set_screen_blanked(false);
+        arm(blank_timeout_ns());
+    }
+}
+
+/// Nanoseconds since the last key or mouse report.
+pub fn input_idle_ns() -> u64 {
+    crate::time::uptime_ns().saturating_sub(LAST_INPUT_NS.load(Ordering::Relaxed))
+}
+
+fn blank_timeout_ns() -> u64 {
+    BLANK_SECS.load(Ordering::Relaxed).saturating_mul(1_000_000_000)
+}
+
+pub fn blank_timeout_secs() -> u64 {
+    BLANK_SECS.load(Ordering::Relaxed)
+}
+
+/// Blank the screen after `secs` seconds without input; 0 never does.
+pub fn set_blank_timeout(secs: u64) {
+    BLANK_SECS.store(secs, Ordering::Relaxed);
+    if secs == 0 {
+        if let Some(id) = TIMER.lock().take() {
+            crate::time::timer::cancel(id);
+        }
+        return;
+    }
+    arm(blank_timeout_ns().saturating_sub(input_idle_ns()));
+}
+
+/// Start counting idle time. Called once timer callbacks can run.
+pub fn start_blank_timer() {
+    LAST_INPUT_NS.store(crate::time::uptime_ns(), Ordering::Relaxed);
+    STARTED.store(true, Ordering::Relaxed);
+    if blank_timeout_secs() != 0 {
+        arm(blank_timeout_ns());
+    }
+}
+
+/// Blank the screen now; input wakes it as after a timeout.
+pub fn blank_screen() {
+    if let Some(id) = TIMER.lock().take() {
+        crate::time::timer::cancel(id);
+    }
+    set_screen_blanked(true);
+}
+
+fn arm(delay_ns: u64) {
+    if !STARTED.load(Ordering::Relaxed) || blank_timeout_secs() == 0 {
+        return;
+    }
+    let id = crate::time::timer::after(Duration::from_nanos(delay_ns), check_idle);
+    if let Some(old) = TIMER.lock().replace(id) {
+        crate::time::timer::cancel(old);
+    }
+}
+
+fn check_idle() {
+    TIMER.lock().take();
+    let timeout = blank_timeout_ns();
+    if timeout == 0 || screen_blanked() {
+        return;
+    }
+    let idle = input_idle_ns();
+    if idle >= timeout {
+        set_screen_blanked(true);
+    } else {
+        arm(timeout - idle);
+    }
+}
@@ -0,0 +1,33 @@
+//! Input subsystem
+//!
+//! Sits between the input drivers and whatever consumes their input (the
+//! tty line discipline, the mouse cursor):
+//!
+//! - `scancode`: keyboard bytes, in scancode set 1 or 2, assembled into
+//!   key events for the tty to decode.
+//! - `hotkey`: key combinations kernel components register, taken out of
+//!   the key stream before the tty decodes it into characters.
+//! - `mouse`: clicks, double clicks, drags and wheel turns worked out from
+//!   the mouse drivers' reports, for listeners to act on.
+//! - `motion`: the acceleration, sensitivity and axis settings every mouse
+//!   driver runs its movement through.
+//! - `idle`: how long since the last key or mouse input, and blanking the
+//!   screen when that gets too long.
+
+use crate::prelude::*;
+
+pub mod scancode;
+pub use scancode::*;
+pub mod hotkey;
+pub use hotkey::*;
+pub mod mouse;
+pub use mouse::*;
+pub mod motion;
+pub use motion::*;
+pub mod idle;
+pub use idle::*;
+
+/// Register the kernel's own hotkeys.
+pub fn init() {
+    hotkey::register_default_hotkeys();
+}
@@ -0,0 +1,159 @@
+//! Mouse motion settings
+//!
+//! How far the cursor moves for a given movement of the mouse, the same for
+//! every mouse driver: each hands its raw counts to `scale_mouse_motion`.
+//! A report is first cut to `max_delta` counts per axis, then multiplied
+//! by the acceleration curve's factor for its speed and by `sensitivity`.
+//! The fractions of a pixel left over are carried into the next report, so
+//! a low sensitivity slows the cursor down rather than stopping it.
+//!
+//! The settings can be changed at runtime by name, through the config
+//! store's `mouse.*` keys and through the mouse driver's device controls.
+
+use crate::prelude::*;
+use crate::sync::Spinlock;
+use alloc::format;
+use alloc::string::String;
+
+/// Fastest the acceleration curve makes the cursor go, in percent.
+pub const MAX_ACCEL_PERCENT: u32 = 400;
+
+/// Names `set_mouse_setting` and `mouse_setting` take.
+pub const MOUSE_SETTINGS: [&str; 5] = ["sensitivity", "accel", "accel_threshold", "max_delta", "invert_y"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccelCurve {
+    /// Movement is only scaled by the sensitivity.
+    Off,
+    /// The factor grows with the speed above the threshold...
+    Linear,
+    /// ...or with its square, gentle at first and steep later.
+    Quadratic,
+}
+
+impl AccelCurve {
+    pub fn parse(s: &str) -> Option<AccelCurve> {
+        match s {
+            "off" => Some(AccelCurve::Off),
+            "linear" => Some(AccelCurve::Linear),
+            "quadratic" => Some(AccelCurve::Quadratic),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AccelCurve::Off => "off",
+            AccelCurve::Linear => "linear",
+            AccelCurve::Quadratic => "quadratic",
+        }
+    }
+
+    /// Percent to scale a report by at `speed` counts per report.
+    pub fn factor(self, speed: u32, threshold: u32) -> u32 {
+        let t = threshold.max(1);
+        if speed <= t {
+            return 100;
+        }
+        let over = speed - t;
+        let factor = match self {
+            AccelCurve::Off => 100,
+            AccelCurve::Linear => 100 + 100 * over / t,
+            AccelCurve::Quadratic => 100 + 100 * over * over / (t * t),
+        };
+        factor.min(MAX_ACCEL_PERCENT)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MouseSettings {
+    /// Pixels per hundred counts of movement.
+    pub sensitivity: u32,
+    pub accel: AccelCurve,
+    /// Speed, in counts per report, above which acceleration starts.
+    pub accel_threshold: u32,
+    /// Largest movement per report and axis taken from the device.
+    pub max_delta: u32,
+    /// Moving the mouse away from you moves the cursor down.
+    pub invert_y: bool,
+}
+
+impl MouseSettings {
+    pub const DEFAULT: MouseSettings =
+        MouseSettings { sensitivity: 100, accel: AccelCurve::Off, accel_threshold: 4, max_delta: 16, invert_y: false };
+}
+
+impl Default for MouseSettings {
+    fn default() -> Self {
+        MouseSettings::DEFAULT
+    }
+}
+
+struct Motion {
+    settings: MouseSettings,
+    /// Hundredths of a pixel not moved yet, per axis.
+    remainder: (i32, i32),
+}
+
+static MOTION: Spinlock<Motion> = Spinlock::new(Motion { settings: MouseSettings::DEFAULT, remainder: (0, 0) });
+
+pub fn mouse_settings() -> MouseSettings {
+    MOTION.lock().settings
+}
+
+pub fn set_mouse_settings(settings: MouseSettings) {
+    let mut motion = MOTION.lock();
+    motion.settings = settings;
+    motion.remainder = (0, 0);
+}
+
+/// Change one setting, named as in `MOUSE_SETTINGS`.
+pub fn set_mouse_setting(name: &str, value: &str) -> Result<(), &'static str> {
+    let number = || value.parse::<u32>().map_err(|_| "want a number");
+    let mut settings = mouse_settings();
+    match name {
+        "sensitivity" => settings.sensitivity = number()?.clamp(1, 1000),
+        "accel" => settings.accel = AccelCurve::parse(value).ok_or("want off, linear or quadratic")?,
+        "accel_threshold" => settings.accel_threshold = number()?.max(1),
+        "max_delta" => settings.max_delta = number()?.clamp(1, 255),
+        "invert_y" => {
+            settings.invert_y = match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err("want on or off"),
+            }
+        }
+        _ => return Err("unknown mouse setting"),
+    }
+    set_mouse_settings(settings);
+    Ok(())
+}
+
+/// A setting's value, as `set_mouse_setting` takes it.
+pub fn mouse_setting(name: &str) -> Option<String> {
+    let settings = mouse_settings();
+    Some(match name {
+        "sensitivity" => format!("{}", settings.sensitivity),
+        "accel" => String::from(settings.accel.name()),
+        "accel_threshold" => format!("{}", settings.accel_threshold),
+        "max_delta" => format!("{}", settings.max_delta),
+        "invert_y" => String::from(if settings.invert_y { "on" } else { "off" }),
+        _ => return None,
+    })
+}
+
+/// Turn one report's movement, in counts with y growing downwards, into
+/// cursor movement in pixels.
+pub fn scale_mouse_motion(dx: i32, dy: i32) -> (i32, i32) {
+    let mut motion = MOTION.lock();
+    let s = motion.settings;
+    let max = s.max_delta as i32;
+    let (dx, dy) = (dx.clamp(-max, max), dy.clamp(-max, max));
+    let dy = if s.invert_y { -dy } else { dy };
+    let speed = dx.unsigned_abs().max(dy.unsigned_abs());
+    let scale = (s.sensitivity * s.accel.factor(speed, s.accel_threshold) / 100) as i32;
+    let x = dx * scale + motion.remainder.0;
+    let y = dy * scale + motion.remainder.1;
+    motion.remainder = (x % 100, y % 100);
+    (x / 100, y / 100)
+}
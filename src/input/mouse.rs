@@ -0,0 +1,203 @@
+//! Mouse events
+//!
+//! Mouse drivers report raw state: which buttons are down and how far the
+//! mouse moved. `GestureTracker` turns the reports into what consumers
+//! act on: presses and releases, clicks (a press and release without
+//! moving further than `DRAG_THRESHOLD`), double clicks (a second click
+//! within `DOUBLE_CLICK_MS`), drags, and wheel turns. Every event carries
+//! the cursor position it happened at.
+//!
+//! Drivers call `report_mouse` from task context; the events go to the
+//! functions registered with `add_mouse_listener`, in order.
+
+use crate::prelude::*;
+use crate::sync::Spinlock;
+use alloc::vec::Vec;
+
+/// Pixels a held button may move before its press turns into a drag.
+pub const DRAG_THRESHOLD: i32 = 4;
+/// Longest a button may be held for the press to count as a click.
+pub const CLICK_MAX_MS: u64 = 500;
+/// Longest gap between two clicks that make a double click.
+pub const DOUBLE_CLICK_MS: u64 = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    pub const ALL: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+    /// The button's bit in `MouseReport::buttons`, laid out as in a PS/2 packet.
+    pub const fn bit(self) -> u8 {
+        match self {
+            MouseButton::Left => 1 << 0,
+            MouseButton::Right => 1 << 1,
+            MouseButton::Middle => 1 << 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseEventKind {
+    /// Moved with no drag in progress.
+    Move,
+    Press(MouseButton),
+    Release(MouseButton),
+    Click(MouseButton),
+    DoubleClick(MouseButton),
+    DragStart(MouseButton),
+    DragMove(MouseButton),
+    DragEnd(MouseButton),
+    /// Wheel turned; positive is towards the user.
+    Wheel(i8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub kind: MouseEventKind,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// One report from a mouse driver, after its movement was applied to the cursor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MouseReport {
+    /// `MouseButton::bit`s of the buttons down.
+    pub buttons: u8,
+    /// Cursor position.
+    pub x: i32,
+    pub y: i32,
+    /// Movement in screen pixels, y growing downwards.
+    pub dx: i32,
+    pub dy: i32,
+    pub wheel: i8,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    since_ns: u64,
+    /// Distance moved since the press, per axis.
+    travel: (i32, i32),
+}
+
+/// Turns a driver's reports into `MouseEvent`s.
+pub struct GestureTracker {
+    held: [Option<Held>; 3],
+    dragging: Option<MouseButton>,
+    last_click: Option<(MouseButton, u64)>,
+}
+
+impl GestureTracker {
+    pub const fn new() -> GestureTracker {
+        GestureTracker { held: [None; 3], dragging: None, last_click: None }
+    }
+
+    /// Work out the events in `report`, taken at `now_ns`, and pass each
+    /// to `emit`: movement first, then button changes, then the wheel.
+    pub fn feed(&mut self, report: &MouseReport, now_ns: u64, mut emit: impl FnMut(MouseEvent)) {
+        let mut event = |kind| emit(MouseEvent { kind, x: report.x, y: report.y });
+
+        if report.dx != 0 || report.dy != 0 {
+            for held in self.held.iter_mut().flatten() {
+                held.travel.0 += report.dx;
+                held.travel.1 += report.dy;
+            }
+            if self.dragging.is_none() {
+                // the first held button to move far enough starts a drag
+                self.dragging = MouseButton::ALL.into_iter().find(|&b| {
+                    self.held[b as usize].is_some_and(|h| h.travel.0.abs().max(h.travel.1.abs()) > DRAG_THRESHOLD)
+                });
+                if let Some(button) = self.dragging {
+                    event(MouseEventKind::DragStart(button));
+                }
+            }
+            match self.dragging {
+                Some(button) => event(MouseEventKind::DragMove(button)),
+                None => event(MouseEventKind::Move),
+            }
+        }
+
+        for button in MouseButton::ALL {
+            let down = report.buttons & button.bit() != 0;
+            match (self.held[button as usize], down) {
+                (None, true) => {
+                    self.held[button as usize] = Some(Held { since_ns: now_ns, travel: (0, 0) });
+                    event(MouseEventKind::Press(button));
+                }
+                (Some(held), false) => {
+                    self.held[button as usize] = None;
+                    event(MouseEventKind::Release(button));
+                    if self.dragging == Some(button) {
+                        self.dragging = None;
+                        event(MouseEventKind::DragEnd(button));
+                    } else if now_ns.saturating_sub(held.since_ns) <= CLICK_MAX_MS * 1_000_000 {
+                        event(MouseEventKind::Click(button));
+                        match self.last_click {
+                            Some((last, at)) if last == button && now_ns.saturating_sub(at) <= DOUBLE_CLICK_MS * 1_000_000 => {
+                                // a third click starts a new pair
+                                self.last_click = None;
+                                event(MouseEventKind::DoubleClick(button));
+                            }
+                            _ => self.last_click = Some((button, now_ns)),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if report.wheel != 0 {
+            event(MouseEventKind::Wheel(report.wheel));
+        }
+    }
+}
+
+impl Default for GestureTracker {
+    fn default() -> Self {
+        GestureTracker::new()
+    }
+}
+
+pub type MouseListener = fn(&MouseEvent);
+
+static LISTENERS: Spinlock<Vec<MouseListener>> = Spinlock::new(Vec::new());
+static TRACKER: Spinlock<GestureTracker> = Spinlock::new(GestureTracker::new());
+
+/// Call `listener` with every mouse event from now on.
+pub fn add_mouse_listener(listener: MouseListener) {
+    LISTENERS.lock().push(listener);
+}
+
+/// Stop calling `listener`. False if it wasn't registered.
+pub fn remove_mouse_listener(listener: MouseListener) -> bool {
+    let mut listeners = LISTENERS.lock();
+    match listeners.iter().position(|&l| core::ptr::fn_addr_eq(l, listener)) {
+        Some(i) => {
+            listeners.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Hand a mouse report to the input subsystem. Task context only:
+/// listeners run before it returns.
+pub fn report_mouse(report: &MouseReport) {
+    crate::input::note_input();
+    let mut events = Vec::new();
+    TRACKER.lock().feed(report, crate::time::uptime_ns(), |e| events.push(e));
+    if events.is_empty() {
+        return;
+    }
+    // listeners may add or remove listeners
+    let listeners = LISTENERS.lock().clone();
+    for event in &events {
+        for listener in &listeners {
+            listener(event);
+        }
+    }
+}
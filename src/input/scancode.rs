@@ -0,0 +1,141 @@
+//! Scancode decoding
+//!
+//! Turns the bytes a PS/2 keyboard sends into key events. The bytes are in
+//! scancode set 1 when the controller translates the keyboard's set 2, as
+//! it normally does, and in set 2 when it doesn't; the keyboard driver
+//! works out which and sets it with `set_keyboard_scancodes`.
+//!
+//! Bytes are collected until they make up a whole key sequence, and only
+//! then decoded, so:
+//!
+//! - A repeated 0xE0 prefix counts once.
+//! - The fake shifts keyboards put around some extended keys, to undo a
+//!   Shift or Num Lock held on the host (0xE0 0x2A/0xAA/0x36/0xB6 in set 1,
+//!   0xE0 0x12/0x59 made and broken in set 2), are dropped.
+//! - Pause, sent as 0xE1 sequences, comes out as one `PauseBreak` press and
+//!   release, not a hidden Ctrl and Num Lock that would toggle Num Lock.
+//! - A sequence that doesn't decode is dropped whole, and leaves no prefix
+//!   behind to garble the next key.
+//!
+//! The codes themselves are mapped by `pc_keyboard`'s set decoders.
+
+use crate::prelude::*;
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{KeyCode, KeyEvent, KeyState, ScancodeSet, ScancodeSet1, ScancodeSet2};
+
+/// Which scancode set keyboard bytes are in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Scancodes {
+    Set1 = 1,
+    Set2 = 2,
+}
+
+const PREFIX: u8 = 0xE0;
+const PAUSE_PREFIX: u8 = 0xE1;
+/// Set 2 break prefix.
+const RELEASE: u8 = 0xF0;
+/// Longest sequence kept; anything longer is garbage.
+const MAX_SEQUENCE: usize = 8;
+
+const FAKE_SHIFTS_SET1: [u8; 4] = [0x2A, 0xAA, 0x36, 0xB6];
+const FAKE_SHIFTS_SET2: [u8; 2] = [0x12, 0x59];
+const PAUSE_SET1: (&[u8], &[u8]) = (&[0xE1, 0x1D, 0x45], &[0xE1, 0x9D, 0xC5]);
+const PAUSE_SET2: (&[u8], &[u8]) = (&[0xE1, 0x14, 0x77], &[0xE1, 0xF0, 0x14, 0xF0, 0x77]);
+
+static KEYBOARD_SCANCODES: AtomicU8 = AtomicU8::new(Scancodes::Set1 as u8);
+
+/// The set the keyboard's bytes are in.
+pub fn keyboard_scancodes() -> Scancodes {
+    match KEYBOARD_SCANCODES.load(Ordering::Relaxed) {
+        2 => Scancodes::Set2,
+        _ => Scancodes::Set1,
+    }
+}
+
+/// Record which set the keyboard's bytes are in. Called by the keyboard
+/// driver once it has set up the controller and keyboard.
+pub fn set_keyboard_scancodes(set: Scancodes) {
+    KEYBOARD_SCANCODES.store(set as u8, Ordering::Relaxed);
+}
+
+/// Scancode bytes in, key events out.
+pub struct ScancodeDecoder {
+    set: Scancodes,
+    bytes: [u8; MAX_SEQUENCE],
+    len: usize,
+}
+
+impl ScancodeDecoder {
+    pub const fn new(set: Scancodes) -> Self {
+        ScancodeDecoder { set, bytes: [0; MAX_SEQUENCE], len: 0 }
+    }
+
+    pub fn set(&self) -> Scancodes {
+        self.set
+    }
+
+    /// Take the next byte. The key event, once it completes a sequence
+    /// that is a key.
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        if byte == PREFIX && self.len > 0 && self.bytes[self.len - 1] == PREFIX {
+            return None;
+        }
+        if self.len == MAX_SEQUENCE {
+            self.len = 0;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if !self.complete() {
+            return None;
+        }
+        let len = core::mem::take(&mut self.len);
+        self.decode(&self.bytes[..len])
+    }
+
+    /// Whether the bytes so far end a sequence: they don't end in a prefix,
+    /// and a Pause sequence has both its codes.
+    fn complete(&self) -> bool {
+        let seq = &self.bytes[..self.len];
+        let last = seq[seq.len() - 1];
+        if last == PREFIX || last == PAUSE_PREFIX || (self.set == Scancodes::Set2 && last == RELEASE) {
+            return false;
+        }
+        seq[0] != PAUSE_PREFIX || seq.iter().filter(|&&b| !matches!(b, PREFIX | PAUSE_PREFIX | RELEASE)).count() >= 2
+    }
+
+    fn decode(&self, seq: &[u8]) -> Option<KeyEvent> {
+        let (pause, fake_shift) = match self.set {
+            Scancodes::Set1 => (PAUSE_SET1, seq.len() == 2 && FAKE_SHIFTS_SET1.contains(&seq[1])),
+            Scancodes::Set2 => (
+                PAUSE_SET2,
+                FAKE_SHIFTS_SET2.contains(&seq[seq.len() - 1]) && (seq.len() == 2 || (seq.len() == 3 && seq[1] == RELEASE)),
+            ),
+        };
+        if seq == pause.0 {
+            return Some(KeyEvent::new(KeyCode::PauseBreak, KeyState::Down));
+        }
+        if seq == pause.1 {
+            return Some(KeyEvent::new(KeyCode::PauseBreak, KeyState::Up));
+        }
+        if seq[0] == PAUSE_PREFIX || (seq[0] == PREFIX && fake_shift) {
+            return None;
+        }
+        match self.set {
+            Scancodes::Set1 => decode_with(ScancodeSet1::new(), seq),
+            Scancodes::Set2 => decode_with(ScancodeSet2::new(), seq),
+        }
+    }
+}
+
+/// Run a whole sequence through a fresh `pc_keyboard` decoder.
+fn decode_with(mut decoder: impl ScancodeSet, seq: &[u8]) -> Option<KeyEvent> {
+    for &byte in seq {
+        match decoder.advance_state(byte) {
+            Ok(Some(event)) => return Some(event),
+            Ok(None) => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
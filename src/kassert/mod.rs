@@ -0,0 +1,135 @@
+//! Kernel assertions
+//!
+//! `kassert!(cond, "...")` checks something that should always hold but
+//! that the kernel can survive being wrong about; `kwarn_once!("...")`
+//! reports a failure worth knowing about, once per call site. What a failed
+//! `kassert!` does is fixed per build by `POLICY`:
+//!
+//! - `Panic` (test builds and `--features kassert-panic`): stop right there.
+//! - `Log` (the default): log at error level the first time a site fails
+//!   and again each time its count reaches a power of two, then continue.
+//! - `Count` (`--features kassert-count`): only count.
+//!
+//! Every site that has fired is kept in a lock-free list with its count, so
+//! the `asserts` shell command can show what went wrong even when nothing
+//! was logged.
+
+use crate::prelude::*;
+use crate::log::Level;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssertPolicy {
+    Panic,
+    Log,
+    Count,
+}
+
+pub const POLICY: AssertPolicy = if cfg!(any(test, feature = "kassert-panic")) {
+    AssertPolicy::Panic
+} else if cfg!(feature = "kassert-count") {
+    AssertPolicy::Count
+} else {
+    AssertPolicy::Log
+};
+
+/// One `kassert!` or `kwarn_once!` call site.
+pub struct Site {
+    pub file: &'static str,
+    pub line: u32,
+    pub kind: SiteKind,
+    hits: AtomicU32,
+    listed: AtomicBool,
+    next: AtomicPtr<Site>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteKind {
+    Assert,
+    Warn,
+}
+
+static SITES: AtomicPtr<Site> = AtomicPtr::new(ptr::null_mut());
+
+impl Site {
+    pub const fn new(file: &'static str, line: u32, kind: SiteKind) -> Site {
+        Site { file, line, kind, hits: AtomicU32::new(0), listed: AtomicBool::new(false), next: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    pub fn hits(&self) -> u32 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Count a hit and return the new count, listing the site on its first.
+    fn hit(&'static self) -> u32 {
+        if !self.listed.swap(true, Ordering::AcqRel) {
+            let me = self as *const Site as *mut Site;
+            let mut head = SITES.load(Ordering::Acquire);
+            loop {
+                self.next.store(head, Ordering::Relaxed);
+                match SITES.compare_exchange_weak(head, me, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => break,
+                    Err(h) => head = h,
+                }
+            }
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed).saturating_add(1)
+    }
+}
+
+/// Call `f` with every site that has fired, most recently listed first.
+pub fn for_each_site(mut f: impl FnMut(&'static Site)) {
+    let mut p = SITES.load(Ordering::Acquire);
+    while let Some(site) = unsafe { p.as_ref() } {
+        f(site);
+        p = site.next.load(Ordering::Acquire);
+    }
+}
+
+/// A failed `kassert!`. Use the macro.
+#[cold]
+pub fn assert_failed(site: &'static Site, args: fmt::Arguments) {
+    let n = site.hit();
+    match POLICY {
+        AssertPolicy::Panic => panic!("kassert failed at {}:{}: {}", site.file, site.line, args),
+        AssertPolicy::Log if n.is_power_of_two() => {
+            klog!(Level::Error, "[ASSERT] {}:{}: {} (failed {} times)", site.file, site.line, args, n)
+        }
+        _ => {}
+    }
+}
+
+/// A `kwarn_once!` reached. Use the macro.
+#[cold]
+pub fn warn_once(site: &'static Site, args: fmt::Arguments) {
+    if site.hit() == 1 {
+        klog!(Level::Warn, "{} ({}:{}, reported once)", args, site.file, site.line);
+    }
+}
+
+/// Check `cond`, handling a failure according to `POLICY`. The message
+/// defaults to the condition's text.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            static SITE: $crate::kassert::Site = $crate::kassert::Site::new(file!(), line!(), $crate::kassert::SiteKind::Assert);
+            $crate::kassert::assert_failed(&SITE, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Log a warning the first time this line is reached; later hits are only
+/// counted.
+#[macro_export]
+macro_rules! kwarn_once {
+    ($($arg:tt)+) => {{
+        static SITE: $crate::kassert::Site = $crate::kassert::Site::new(file!(), line!(), $crate::kassert::SiteKind::Warn);
+        $crate::kassert::warn_once(&SITE, format_args!($($arg)+));
+    }};
+}
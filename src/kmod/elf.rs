@@ -0,0 +1,354 @@
+//! Relocatable ELF objects
+//!
+//! A module is an x86-64 `ET_REL` object: what `cc -c` or `rustc --emit=obj`
+//! produces. `link_module` copies its allocated sections into one zeroed
+//! block, resolves undefined symbols through a callback, and applies the
+//! relocations. The kernel lives more than 2 GiB away from the heap that
+//! holds modules, so calls to imported functions that can't reach go
+//! through a stub that jumps via a table of absolute addresses placed
+//! after the sections; the same table is the GOT for `-fPIC` code. The
+//! heap is above 4 GiB, so objects must be position-independent: absolute
+//! 32-bit references, and direct ones to kernel data, are refused.
+
+use crate::prelude::*;
+use crate::rlib::cstr::cstr_at;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 0x3E;
+const EHDR_LEN: usize = 64;
+const SHDR_LEN: usize = 64;
+const SYM_LEN: usize = 24;
+const RELA_LEN: usize = 24;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 2;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const SHN_COMMON: u16 = 0xFFF2;
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_GOTPCREL: u32 = 9;
+pub const R_X86_64_32: u32 = 10;
+pub const R_X86_64_32S: u32 = 11;
+pub const R_X86_64_PC64: u32 = 24;
+pub const R_X86_64_GOTPCRELX: u32 = 41;
+pub const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+/// Names the slot table in `-fPIC` objects.
+const GOT_SYMBOL: &str = "_GLOBAL_OFFSET_TABLE_";
+/// A GOT slot (8 bytes) and a `jmp [rip+slot]` stub (6, padded to 8) per
+/// imported or GOT-referenced symbol.
+const SLOT_LEN: usize = 16;
+const STUB_JMP: [u8; 2] = [0xFF, 0x25];
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+    align: usize,
+    /// Offset in the loaded image, for allocated sections.
+    load_at: Option<usize>,
+}
+
+struct Symbol {
+    name: String,
+    bind: u8,
+    kind: u8,
+    shndx: u16,
+    value: u64,
+}
+
+/// A linked module image. The memory is freed on drop.
+pub struct ModuleImage {
+    base: usize,
+    layout: Layout,
+    /// Global symbols the object defines, with their addresses.
+    pub exports: Vec<(String, usize)>,
+    /// Undefined symbols and the addresses they were bound to.
+    pub imports: Vec<(String, usize)>,
+}
+
+impl ModuleImage {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.base + self.layout.size()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.exports.iter().find(|(n, _)| n == name).map(|&(_, a)| a)
+    }
+}
+
+impl Drop for ModuleImage {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base as *mut u8, self.layout) };
+    }
+}
+
+fn sections(data: &[u8]) -> Result<Vec<Section>, &'static str> {
+    if data.len() < EHDR_LEN || &data[..4] != ELF_MAGIC {
+        return Err("not an ELF file");
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return Err("not 64-bit little-endian");
+    }
+    if u16_at(data, 16) != ET_REL {
+        return Err("not a relocatable object");
+    }
+    if u16_at(data, 18) != EM_X86_64 {
+        return Err("not x86-64");
+    }
+    let shoff = u64_at(data, 40) as usize;
+    let shentsize = u16_at(data, 58) as usize;
+    let shnum = u16_at(data, 60) as usize;
+    if shentsize < SHDR_LEN {
+        return Err("bad section header size");
+    }
+    let table_end = shnum.checked_mul(shentsize).and_then(|n| n.checked_add(shoff));
+    if table_end.is_none_or(|end| end > data.len()) {
+        return Err("section headers out of range");
+    }
+    let mut out = Vec::with_capacity(shnum);
+    for i in 0..shnum {
+        let sh = &data[shoff + i * shentsize..];
+        let section = Section {
+            kind: u32_at(sh, 4),
+            flags: u64_at(sh, 8),
+            offset: u64_at(sh, 24) as usize,
+            size: u64_at(sh, 32) as usize,
+            link: u32_at(sh, 40) as usize,
+            info: u32_at(sh, 44) as usize,
+            align: (u64_at(sh, 48) as usize).max(1),
+            load_at: None,
+        };
+        if section.kind != SHT_NOBITS && section.offset.checked_add(section.size).is_none_or(|end| end > data.len()) {
+            return Err("section out of range");
+        }
+        if !section.align.is_power_of_two() {
+            return Err("bad section alignment");
+        }
+        out.push(section);
+    }
+    Ok(out)
+}
+
+fn symbols(data: &[u8], sections: &[Section]) -> Result<(usize, Vec<Symbol>), &'static str> {
+    let (index, symtab) = sections.iter().enumerate().find(|(_, s)| s.kind == SHT_SYMTAB).ok_or("no symbol table")?;
+    let strtab = sections.get(symtab.link).ok_or("bad string table")?;
+    let strings = &data[strtab.offset..strtab.offset + strtab.size];
+    let table = &data[symtab.offset..symtab.offset + symtab.size];
+    let mut out = Vec::with_capacity(table.len() / SYM_LEN);
+    for sym in table.chunks_exact(SYM_LEN) {
+        out.push(Symbol {
+            name: cstr_at(strings, u32_at(sym, 0) as usize).ok_or("bad symbol name")?.to_string(),
+            bind: sym[4] >> 4,
+            kind: sym[4] & 0xF,
+            shndx: u16_at(sym, 6),
+            value: u64_at(sym, 8),
+        });
+    }
+    Ok((index, out))
+}
+
+/// Apply one relocation at `at` in `image` (which is loaded at `base`):
+/// `s` is the symbol's address, `got` its import slot if it has one.
+pub fn apply_relocation(image: &mut [u8], base: usize, at: usize, kind: u32, s: u64, got: Option<u64>, addend: i64) -> Result<(), &'static str> {
+    let p = (base + at) as u64;
+    let sa = s.wrapping_add_signed(addend);
+    let pc32 = |v: u64, why| i32::try_from(v as i64).map(|v| (v as u32 as u64, 4)).map_err(|_| why);
+    let (value, len) = match kind {
+        R_X86_64_NONE => return Ok(()),
+        R_X86_64_64 => (sa, 8),
+        R_X86_64_PC64 => (sa.wrapping_sub(p), 8),
+        R_X86_64_PC32 | R_X86_64_PLT32 => pc32(sa.wrapping_sub(p), "PC-relative reference out of range")?,
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX => {
+            let slot = got.ok_or("GOT reference to a local symbol")?;
+            pc32(slot.wrapping_add_signed(addend).wrapping_sub(p), "GOT reference out of range")?
+        }
+        R_X86_64_32 => (u32::try_from(sa).map_err(|_| "absolute 32-bit reference; build with -fPIC")? as u64, 4),
+        R_X86_64_32S => pc32(sa, "absolute 32-bit reference; build with -fPIC")?,
+        _ => return Err("unsupported relocation"),
+    };
+    image.get_mut(at..at + len).ok_or("relocation out of range")?.copy_from_slice(&value.to_le_bytes()[..len]);
+    Ok(())
+}
+
+fn is_got(kind: u32) -> bool {
+    matches!(kind, R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX)
+}
+
+/// Relocation entries as (section offset, symbol index, type, addend).
+fn relocations<'a>(data: &'a [u8], rel: &Section) -> impl Iterator<Item = (usize, usize, u32, i64)> + 'a {
+    data[rel.offset..rel.offset + rel.size].chunks_exact(RELA_LEN).map(|r| {
+        let info = u64_at(r, 8);
+        (u64_at(r, 0) as usize, (info >> 32) as usize, info as u32, u64_at(r, 16) as i64)
+    })
+}
+
+/// Load and link the object in `data`. `resolve` finds an undefined
+/// symbol's address.
+pub fn link_module(data: &[u8], mut resolve: impl FnMut(&str) -> Option<usize>) -> Result<ModuleImage, String> {
+    let mut sections = sections(data).map_err(String::from)?;
+    let (symtab_index, syms) = symbols(data, &sections).map_err(String::from)?;
+    let rela: Vec<usize> = (0..sections.len())
+        .filter(|&i| sections[i].kind == SHT_RELA && sections[i].link == symtab_index)
+        .collect();
+
+    // lay out the allocated sections, then the slot table
+    let mut size = 0usize;
+    let mut align = 16usize;
+    for s in sections.iter_mut().filter(|s| s.flags & SHF_ALLOC != 0 && s.size != 0) {
+        size = size.next_multiple_of(s.align);
+        s.load_at = Some(size);
+        size += s.size;
+        align = align.max(s.align);
+    }
+
+    // bind imports; they and anything reached through the GOT get a slot
+    let mut imports: Vec<(String, usize)> = Vec::new();
+    let mut import_addr = vec![None; syms.len()];
+    let mut slot_of = vec![None; syms.len()];
+    let mut slots = 0;
+    for (i, sym) in syms.iter().enumerate() {
+        if sym.shndx == SHN_COMMON {
+            return Err(format!("{}: common symbols are not supported; build with -fno-common", sym.name));
+        }
+        if sym.shndx != SHN_UNDEF || sym.name.is_empty() || sym.name == GOT_SYMBOL {
+            continue;
+        }
+        let addr = match resolve(&sym.name) {
+            Some(addr) => addr,
+            None if sym.bind == STB_WEAK => 0,
+            None => return Err(format!("unknown symbol {}", sym.name)),
+        };
+        import_addr[i] = Some(addr as u64);
+        imports.push((sym.name.clone(), addr));
+        slot_of[i] = Some(slots);
+        slots += 1;
+    }
+    for &r in &rela {
+        for (_, sym, kind, _) in relocations(data, &sections[r]) {
+            if is_got(kind) && sym < syms.len() && slot_of[sym].is_none() {
+                slot_of[sym] = Some(slots);
+                slots += 1;
+            }
+        }
+    }
+    let slots_at = size.next_multiple_of(SLOT_LEN);
+    size = (slots_at + slots * SLOT_LEN).max(1);
+
+    let layout = Layout::from_size_align(size, align).map_err(|_| String::from("bad module layout"))?;
+    let base = unsafe { alloc_zeroed(layout) } as usize;
+    if base == 0 {
+        return Err(String::from("out of memory"));
+    }
+    // from here on, dropping `module` frees the block
+    let mut module = ModuleImage { base, layout, exports: Vec::new(), imports };
+    let image = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size) };
+
+    for s in sections.iter().filter(|s| s.kind != SHT_NOBITS) {
+        if let Some(at) = s.load_at {
+            image[at..at + s.size].copy_from_slice(&data[s.offset..s.offset + s.size]);
+        }
+    }
+
+    let address_of = |i: usize| -> Result<u64, String> {
+        let sym = &syms[i];
+        match sym.shndx {
+            SHN_UNDEF if sym.name == GOT_SYMBOL => Ok((base + slots_at) as u64),
+            SHN_UNDEF => Ok(import_addr[i].unwrap_or(0)),
+            SHN_ABS => Ok(sym.value),
+            n => match sections.get(n as usize).and_then(|s| s.load_at) {
+                Some(at) => Ok((base + at) as u64 + sym.value),
+                None => Err(format!("{}: symbol in a section that isn't loaded", sym.name)),
+            },
+        }
+    };
+    let slot_addr = |n: usize| (base + slots_at + n * SLOT_LEN) as u64;
+
+    for (i, slot) in slot_of.iter().enumerate() {
+        let Some(n) = *slot else { continue };
+        let at = slots_at + n * SLOT_LEN;
+        image[at..at + 8].copy_from_slice(&address_of(i)?.to_le_bytes());
+        // jmp [rip - 14]: back to the slot, from the end of the 6-byte stub
+        image[at + 8..at + 10].copy_from_slice(&STUB_JMP);
+        image[at + 10..at + 14].copy_from_slice(&(-14i32).to_le_bytes());
+    }
+
+    for &r in &rela {
+        let Some(target) = sections.get(sections[r].info) else { continue };
+        let Some(target_at) = target.load_at else { continue };
+        for (offset, sym_index, kind, addend) in relocations(data, &sections[r]) {
+            let sym = syms.get(sym_index).ok_or_else(|| String::from("bad relocation symbol"))?;
+            if offset >= target.size {
+                return Err(String::from("relocation out of range"));
+            }
+            let got = slot_of[sym_index].map(slot_addr);
+            let mut s = address_of(sym_index)?;
+            // calls that can't reach an import go through its stub
+            if let (R_X86_64_PLT32 | R_X86_64_PC32, Some(slot)) = (kind, got) {
+                let p = (base + target_at + offset) as u64;
+                if i32::try_from(s.wrapping_add_signed(addend).wrapping_sub(p) as i64).is_err() {
+                    if kind == R_X86_64_PC32 && sym.kind != STT_FUNC {
+                        return Err(format!("{}: data reference out of range; build with -fPIC", sym.name));
+                    }
+                    s = slot + 8;
+                }
+            }
+            apply_relocation(image, base, target_at + offset, kind, s, got, addend).map_err(|e| {
+                let what = if sym.name.is_empty() { "relocation" } else { &sym.name };
+                format!("{}: {}", what, e)
+            })?;
+        }
+    }
+
+    for (i, sym) in syms.iter().enumerate() {
+        if sym.bind != STB_LOCAL && sym.shndx != SHN_UNDEF && sym.kind != STT_SECTION && sym.kind != STT_FILE {
+            if let Ok(addr) = address_of(i) {
+                module.exports.push((sym.name.clone(), addr as usize));
+            }
+        }
+    }
+    Ok(module)
+}
@@ -0,0 +1,102 @@
+//! Symbols the kernel exports to modules
+//!
+//! Modules link only against the names in `kernel_symbols`: a small C ABI
+//! for logging, memory, port I/O and driver registration, plus the memory
+//! functions compilers emit calls to. Anything else a module needs has to
+//! be added here first, which keeps the surface modules depend on explicit.
+
+use crate::prelude::*;
+use super::KmodDriverOps;
+use alloc::alloc::{alloc, dealloc, Layout};
+use x86_64::instructions::port::Port;
+
+/// Print `len` bytes at `msg` to the kernel log.
+unsafe extern "C" fn kmod_log(msg: *const u8, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(msg, len) };
+    print!("{}", alloc::string::String::from_utf8_lossy(bytes));
+}
+
+/// Allocate from the kernel heap; null on failure.
+extern "C" fn kmod_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size.max(1), align.max(1)) {
+        Ok(layout) => unsafe { alloc(layout) },
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+/// Free a `kmod_alloc` block; `size` and `align` must be what was asked for.
+unsafe extern "C" fn kmod_free(ptr: *mut u8, size: usize, align: usize) {
+    if let (false, Ok(layout)) = (ptr.is_null(), Layout::from_size_align(size.max(1), align.max(1))) {
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+/// Register a driver from `module_init`; returns the number of devices it
+/// was attached to, or -1.
+unsafe extern "C" fn kmod_register_driver(ops: *const KmodDriverOps) -> i32 {
+    if ops.is_null() {
+        return -1;
+    }
+    match super::register_module_driver(unsafe { *ops }) {
+        Ok(attached) => attached as i32,
+        Err(why) => {
+            println!("[KMOD] kmod_register_driver: {}", why);
+            -1
+        }
+    }
+}
+
+extern "C" fn kmod_uptime_ns() -> u64 {
+    crate::time::uptime_ns()
+}
+
+extern "C" fn kmod_inb(port: u16) -> u8 {
+    unsafe { Port::new(port).read() }
+}
+
+extern "C" fn kmod_inw(port: u16) -> u16 {
+    unsafe { Port::new(port).read() }
+}
+
+extern "C" fn kmod_inl(port: u16) -> u32 {
+    unsafe { Port::new(port).read() }
+}
+
+extern "C" fn kmod_outb(port: u16, value: u8) {
+    unsafe { Port::new(port).write(value) }
+}
+
+extern "C" fn kmod_outw(port: u16, value: u16) {
+    unsafe { Port::new(port).write(value) }
+}
+
+extern "C" fn kmod_outl(port: u16, value: u32) {
+    unsafe { Port::new(port).write(value) }
+}
+
+/// The exported names and their addresses.
+pub fn kernel_symbols() -> [(&'static str, usize); 16] {
+    use crate::rlib::mem::{memcmp, memcpy, memmove, memset};
+    [
+        ("kmod_log", kmod_log as usize),
+        ("kmod_alloc", kmod_alloc as usize),
+        ("kmod_free", kmod_free as usize),
+        ("kmod_register_driver", kmod_register_driver as usize),
+        ("kmod_uptime_ns", kmod_uptime_ns as usize),
+        ("kmod_inb", kmod_inb as usize),
+        ("kmod_inw", kmod_inw as usize),
+        ("kmod_inl", kmod_inl as usize),
+        ("kmod_outb", kmod_outb as usize),
+        ("kmod_outw", kmod_outw as usize),
+        ("kmod_outl", kmod_outl as usize),
+        ("memcpy", memcpy as usize),
+        ("memset", memset as usize),
+        ("memcmp", memcmp as usize),
+        ("memmove", memmove as usize),
+        ("kmod_abi_version", &super::KMOD_ABI_VERSION as *const u32 as usize),
+    ]
+}
+
+pub fn kernel_symbol(name: &str) -> Option<usize> {
+    kernel_symbols().iter().find(|&&(n, _)| n == name).map(|&(_, addr)| addr)
+}
@@ -0,0 +1,313 @@
+//! Loadable kernel modules
+//!
+//! `insmod PATH` reads a relocatable object from the VFS (the initrd, or
+//! anything mounted), links it against the kernel's exported symbols and
+//! the globals of modules already loaded, and calls its
+//! `int module_init(void)`. From there the module registers its drivers
+//! with `kmod_register_driver`; each is offered every device that has no
+//! driver yet, and attached to those it accepts. A nonzero return from
+//! `module_init` undoes all of that. `rmmod NAME` detaches the module's
+//! devices, calls `void module_exit(void)` if it has one, and frees it,
+//! unless another module still uses its symbols.
+//!
+//! A module is named after its file, less the extension. Modules are
+//! built freestanding and position-independent (`-ffreestanding -fPIC
+//! -fno-common`, or `no_std` Rust with `-C relocation-model=pic
+//! --emit=obj`); see `elf` for how they reach the kernel.
+
+pub mod elf;
+pub mod exports;
+
+use crate::prelude::*;
+use crate::driver_framework::device::DeviceHandle;
+use crate::driver_framework::driver::Driver;
+use crate::driver_framework::manager::GLOBAL_MANAGER;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use elf::{link_module, ModuleImage};
+use spin::Mutex;
+
+/// Bumped when the exported ABI changes incompatibly; modules can check
+/// the `kmod_abi_version` symbol.
+pub static KMOD_ABI_VERSION: u32 = 1;
+
+/// `vendor_id`/`device_id`/`class` that match any device.
+pub const KMOD_ANY_ID: u16 = 0xFFFF;
+
+/// What a module passes to `kmod_register_driver`. Callbacks get the
+/// device id; `probe` and `start` return 0 to accept.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct KmodDriverOps {
+    /// NUL-terminated.
+    pub name: *const u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// PCI class in the high byte and subclass in the low byte.
+    pub class: u16,
+    pub probe: Option<unsafe extern "C" fn(device: usize, info: *const KmodDeviceInfo) -> i32>,
+    pub start: Option<unsafe extern "C" fn(device: usize) -> i32>,
+    pub stop: Option<unsafe extern "C" fn(device: usize)>,
+    pub release: Option<unsafe extern "C" fn(device: usize)>,
+}
+
+#[repr(C)]
+pub struct KmodDeviceInfo {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    Vfs(crate::fs::vfs::VfsError),
+    Link(String),
+    AlreadyLoaded,
+    NotLoaded,
+    NoInit,
+    /// `module_init` returned this.
+    InitFailed(i32),
+    /// Other modules import its symbols.
+    InUse(String),
+}
+
+impl core::fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ModuleError::Vfs(e) => write!(f, "{}", e),
+            ModuleError::Link(why) => write!(f, "can't link: {}", why),
+            ModuleError::AlreadyLoaded => f.write_str("already loaded"),
+            ModuleError::NotLoaded => f.write_str("not loaded"),
+            ModuleError::NoInit => f.write_str("no module_init"),
+            ModuleError::InitFailed(code) => write!(f, "module_init returned {}", code),
+            ModuleError::InUse(by) => write!(f, "in use by {}", by),
+        }
+    }
+}
+
+impl From<crate::fs::vfs::VfsError> for ModuleError {
+    fn from(e: crate::fs::vfs::VfsError) -> Self {
+        ModuleError::Vfs(e)
+    }
+}
+
+/// A driver a module registered, and the devices it took.
+struct ModuleDriverEntry {
+    name: String,
+    devices: Vec<usize>,
+}
+
+struct Module {
+    name: String,
+    image: ModuleImage,
+    exit: Option<usize>,
+    drivers: Vec<ModuleDriverEntry>,
+    /// Modules whose symbols this one imports.
+    uses: Vec<String>,
+}
+
+/// A loaded module, as `lsmod` shows it.
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub base: usize,
+    pub size: usize,
+    pub drivers: Vec<String>,
+    pub devices: usize,
+    pub used_by: Vec<String>,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+/// Held while a module is loaded or unloaded.
+static LOADING: Mutex<()> = Mutex::new(());
+/// Drivers registered by the `module_init` that is running, if one is.
+static REGISTERED: Mutex<Option<Vec<ModuleDriverEntry>>> = Mutex::new(None);
+
+/// Adapts a module's `KmodDriverOps` to the driver framework.
+struct ModuleDriver {
+    ops: KmodDriverOps,
+}
+
+// the ops point into the module image, which outlives the driver: rmmod
+// detaches every device before freeing it
+unsafe impl Send for ModuleDriver {}
+unsafe impl Sync for ModuleDriver {}
+
+impl ModuleDriver {
+    fn matches(&self, vendor: u16, device: u16, class: u8, subclass: u8) -> bool {
+        let o = &self.ops;
+        (o.vendor_id == KMOD_ANY_ID || o.vendor_id == vendor)
+            && (o.device_id == KMOD_ANY_ID || o.device_id == device)
+            && (o.class == KMOD_ANY_ID || o.class == (class as u16) << 8 | subclass as u16)
+    }
+}
+
+impl Driver for ModuleDriver {
+    fn probe(&self, device: &DeviceHandle) -> Result<(), &'static str> {
+        let info = device.info();
+        if !self.matches(info.vendor_id, info.device_id, info.class, info.subclass) {
+            return Err("no match");
+        }
+        let c_info = KmodDeviceInfo {
+            vendor_id: info.vendor_id,
+            device_id: info.device_id,
+            class: info.class,
+            subclass: info.subclass,
+            prog_if: info.prog_if,
+        };
+        match self.ops.probe {
+            Some(probe) if unsafe { probe(device.id, &c_info) } != 0 => Err("declined by module"),
+            _ => Ok(()),
+        }
+    }
+
+    fn start(&self, device: &DeviceHandle) -> Result<(), &'static str> {
+        match self.ops.start {
+            Some(start) if unsafe { start(device.id) } != 0 => Err("module failed to start device"),
+            _ => Ok(()),
+        }
+    }
+
+    fn stop(&self, device: &DeviceHandle) {
+        if let Some(stop) = self.ops.stop {
+            unsafe { stop(device.id) };
+        }
+    }
+
+    fn release(&self, device: &DeviceHandle) {
+        if let Some(release) = self.ops.release {
+            unsafe { release(device.id) };
+        }
+    }
+}
+
+/// Called through `kmod_register_driver`; only allowed while a
+/// `module_init` runs. Returns the number of devices attached.
+fn register_module_driver(ops: KmodDriverOps) -> Result<usize, &'static str> {
+    if REGISTERED.lock().is_none() {
+        return Err("drivers are registered from module_init");
+    }
+    let name = if ops.name.is_null() {
+        String::from("?")
+    } else {
+        // the module promised a NUL-terminated string
+        let len = unsafe { crate::rlib::cstr::strlen(ops.name) };
+        String::from_utf8_lossy(unsafe { core::slice::from_raw_parts(ops.name, len) }).into_owned()
+    };
+    let mut devices = Vec::new();
+    for id in GLOBAL_MANAGER.unclaimed_devices() {
+        if GLOBAL_MANAGER.attach_driver(id, Box::new(ModuleDriver { ops })).is_ok() {
+            devices.push(id);
+        }
+    }
+    println!("[KMOD] driver {} attached to {} device(s)", name, devices.len());
+    let attached = devices.len();
+    if let Some(registered) = REGISTERED.lock().as_mut() {
+        registered.push(ModuleDriverEntry { name, devices });
+    }
+    Ok(attached)
+}
+
+fn detach_all(drivers: &[ModuleDriverEntry]) {
+    for id in drivers.iter().flat_map(|d| d.devices.iter()) {
+        let _ = GLOBAL_MANAGER.detach_driver(*id);
+    }
+}
+
+fn module_name(path: &str) -> &str {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    file.split('.').next().filter(|s| !s.is_empty()).unwrap_or(file)
+}
+
+/// Load, link and initialize the module at `path`. Returns its name.
+pub fn insmod(path: &str) -> Result<String, ModuleError> {
+    let name = module_name(path).to_string();
+    let data = crate::fs::vfs::read_file(path)?;
+    let _loading = LOADING.lock();
+    if MODULES.lock().iter().any(|m| m.name == name) {
+        return Err(ModuleError::AlreadyLoaded);
+    }
+
+    let mut uses = Vec::new();
+    let image = {
+        let modules = MODULES.lock();
+        link_module(&data, |sym| {
+            if let Some(addr) = exports::kernel_symbol(sym) {
+                return Some(addr);
+            }
+            let m = modules.iter().find(|m| m.image.symbol(sym).is_some())?;
+            if !uses.contains(&m.name) {
+                uses.push(m.name.clone());
+            }
+            m.image.symbol(sym)
+        })
+        .map_err(ModuleError::Link)?
+    };
+    let init_addr = image.symbol("module_init").ok_or(ModuleError::NoInit)?;
+    let exit = image.symbol("module_exit");
+
+    *REGISTERED.lock() = Some(Vec::new());
+    // the image was linked for this address and the symbol is in it
+    let module_init: extern "C" fn() -> i32 = unsafe { core::mem::transmute(init_addr) };
+    let code = module_init();
+    let drivers = REGISTERED.lock().take().unwrap_or_default();
+    if code != 0 {
+        detach_all(&drivers);
+        return Err(ModuleError::InitFailed(code));
+    }
+
+    println!(
+        "[KMOD] loaded {} at {:#x} ({} bytes, {} imports)",
+        name,
+        image.base(),
+        image.size(),
+        image.imports.len()
+    );
+    MODULES.lock().push(Module { name: name.clone(), image, exit, drivers, uses });
+    Ok(name)
+}
+
+/// Detach, finalize and free the module `name`.
+pub fn rmmod(name: &str) -> Result<(), ModuleError> {
+    let _loading = LOADING.lock();
+    let module = {
+        let mut modules = MODULES.lock();
+        let index = modules.iter().position(|m| m.name == name).ok_or(ModuleError::NotLoaded)?;
+        if let Some(user) = modules.iter().find(|m| m.uses.iter().any(|u| u == name)) {
+            return Err(ModuleError::InUse(user.name.clone()));
+        }
+        modules.remove(index)
+    };
+    detach_all(&module.drivers);
+    if let Some(exit) = module.exit {
+        let module_exit: extern "C" fn() = unsafe { core::mem::transmute(exit) };
+        module_exit();
+    }
+    println!("[KMOD] unloaded {}", module.name);
+    drop(module);
+    Ok(())
+}
+
+/// Loaded modules, in load order.
+pub fn modules() -> Vec<ModuleInfo> {
+    let modules = MODULES.lock();
+    modules
+        .iter()
+        .map(|m| ModuleInfo {
+            name: m.name.clone(),
+            base: m.image.base(),
+            size: m.image.size(),
+            drivers: m.drivers.iter().map(|d| d.name.clone()).collect(),
+            devices: m.drivers.iter().map(|d| d.devices.len()).sum(),
+            used_by: modules.iter().filter(|u| u.uses.contains(&m.name)).map(|u| u.name.clone()).collect(),
+        })
+        .collect()
+}
+
+/// The module whose image holds `addr`, and the offset into it.
+pub fn module_at(addr: usize) -> Option<(String, usize)> {
+    MODULES.lock().iter().find(|m| m.image.contains(addr)).map(|m| (m.name.clone(), addr - m.image.base()))
+}
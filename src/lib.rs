@@ -2,22 +2,57 @@
 #![no_main]  // if you boot directly without an OS
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![allow(warnings)]
 
 // Provide the `alloc` crate to modules that need heap types (Vec, Box, String).
 extern crate alloc;
 
 pub mod arch;
-pub use arch::*;
+pub mod boot;
 pub mod bootvga;
-pub use bootvga::*;
+pub mod log;
+pub mod kassert;
+pub mod fault;
+pub mod random;
 pub mod rlib;
-pub use rlib::*;
 pub mod devices;
-pub use devices::*;
 pub mod memory;
-pub use memory::*;
 pub mod hal;
-pub use hal::*;
 pub mod driver_framework;
-pub use driver_framework::*;
+/// The driver framework: devices, drivers and the device manager.
+pub use driver_framework as framework;
+pub mod kmod;
+pub mod config;
+pub mod input;
+pub mod symbols;
+pub mod sync;
+pub mod time;
+pub mod trace;
+pub mod storage;
+pub mod fs;
+pub mod net;
+pub mod process;
+pub mod sched;
+pub mod shell;
+pub mod prelude;
+pub mod testing;
+
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
+/// Entry point of the kernel `cargo test` builds from this library.
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    testing::init_test_kernel(boot_info);
+    test_main();
+    testing::exit_qemu(testing::QemuExitCode::Success);
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
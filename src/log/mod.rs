@@ -0,0 +1,310 @@
+//! Kernel log
+//!
+//! `print!`/`println!` and `klog!` produce log records that fan out to every
+//! registered sink whose level admits them. Sinks are statics registered by
+//! reference, so the registry works before the heap is up; drivers add their
+//! sinks as they come up (the framebuffer console when VBE starts) and the
+//! kernel command line can add or retune them (`log.serial=debug`,
+//! `log.vga=off`). Records are written in pieces as they are formatted, so
+//! nothing is allocated on the way.
+//!
+//! Each line starts with a timestamp, `[    3.141592] ` (seconds since
+//! boot on the monotonic clock) by default, or the raw TSC in hex with
+//! `log.time=tsc`, which costs no division; `log.time=off` drops them. The
+//! screen consoles, which the shell also talks through, don't take stamps;
+//! serial, debugcon and the ring do.
+
+pub mod sinks;
+pub use sinks::*;
+
+use crate::prelude::*;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Sinks that can be registered at once.
+pub const MAX_SINKS: usize = 8;
+
+/// Record severity, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+/// Somewhere log output goes.
+pub trait LogSink: Sync {
+    /// Short name used to look the sink up (`log.<name>=` on the command line).
+    fn name(&self) -> &'static str;
+    /// Write a piece of a record. Records end with their own newline.
+    fn write_str(&self, s: &str);
+    /// Whether lines written to this sink start with a timestamp.
+    fn timestamps(&self) -> bool {
+        true
+    }
+}
+
+/// What starts each log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Timestamps {
+    Off,
+    /// `[sec.micros]` since boot.
+    Mono,
+    /// The raw TSC, in hex.
+    Tsc,
+}
+
+impl Timestamps {
+    pub fn parse(s: &str) -> Option<Timestamps> {
+        match s {
+            "off" => Some(Timestamps::Off),
+            "mono" => Some(Timestamps::Mono),
+            "tsc" => Some(Timestamps::Tsc),
+            _ => None,
+        }
+    }
+}
+
+static TIMESTAMPS: AtomicU8 = AtomicU8::new(Timestamps::Mono as u8);
+/// The last character logged ended a line.
+static LINE_START: AtomicBool = AtomicBool::new(true);
+
+pub fn timestamps() -> Timestamps {
+    match TIMESTAMPS.load(Ordering::Relaxed) {
+        0 => Timestamps::Off,
+        2 => Timestamps::Tsc,
+        _ => Timestamps::Mono,
+    }
+}
+
+pub fn set_timestamps(mode: Timestamps) {
+    TIMESTAMPS.store(mode as u8, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy)]
+struct Registered {
+    sink: &'static dyn LogSink,
+    /// Least severe level the sink takes.
+    level: Level,
+}
+
+static SINKS: Mutex<[Option<Registered>; MAX_SINKS]> = Mutex::new(default_sinks());
+
+const fn default_sinks() -> [Option<Registered>; MAX_SINKS] {
+    let mut sinks = [None; MAX_SINKS];
+    let mut i = 0;
+    while i < DEFAULT_SINKS.len() {
+        let (sink, level) = DEFAULT_SINKS[i];
+        sinks[i] = Some(Registered { sink, level });
+        i += 1;
+    }
+    sinks
+}
+
+fn with_sinks<R>(f: impl FnOnce(&mut [Option<Registered>; MAX_SINKS]) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut SINKS.lock()))
+}
+
+/// Register `sink` for records at `level` and above, or retune it if it is
+/// already registered. Fails when every slot is taken.
+pub fn add_sink(sink: &'static dyn LogSink, level: Level) -> Result<(), &'static str> {
+    with_sinks(|sinks| {
+        if let Some(slot) = sinks.iter_mut().flatten().find(|r| r.sink.name() == sink.name()) {
+            *slot = Registered { sink, level };
+            return Ok(());
+        }
+        let free = sinks.iter_mut().find(|s| s.is_none()).ok_or("log sink table full")?;
+        *free = Some(Registered { sink, level });
+        Ok(())
+    })
+}
+
+/// Unregister the sink called `name`. Returns whether it was registered.
+pub fn remove_sink(name: &str) -> bool {
+    with_sinks(|sinks| match sinks.iter_mut().find(|s| s.map_or(false, |r| r.sink.name() == name)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Change the level of a registered sink. Returns whether it was registered.
+pub fn set_sink_level(name: &str, level: Level) -> bool {
+    with_sinks(|sinks| match sinks.iter_mut().flatten().find(|r| r.sink.name() == name) {
+        Some(r) => {
+            r.level = level;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Registered sinks and their levels.
+pub fn sinks() -> [Option<(&'static str, Level)>; MAX_SINKS] {
+    with_sinks(|sinks| sinks.map(|s| s.map(|r| (r.sink.name(), r.level))))
+}
+
+/// Apply `log.<sink>=<level|off>` words from the kernel command line.
+/// Sinks that aren't registered by default (serial, debugcon) are added.
+pub fn configure_from_cmdline(cmdline: &str) {
+    for word in cmdline.split_whitespace() {
+        let Some((name, value)) = word.strip_prefix("log.").and_then(|w| w.split_once('=')) else { continue };
+        if name == "time" {
+            match Timestamps::parse(value) {
+                Some(mode) => set_timestamps(mode),
+                None => println!("[LOG] bad log.time '{}' (want off, mono or tsc)", value),
+            }
+            continue;
+        }
+        if value == "off" {
+            remove_sink(name);
+            continue;
+        }
+        let Some(level) = Level::parse(value) else {
+            println!("[LOG] bad level '{}' for sink {}", value, name);
+            continue;
+        };
+        let result = match builtin_sink(name) {
+            Some(sink) => add_sink(sink, level),
+            None if set_sink_level(name, level) => Ok(()),
+            None => Err("no such sink"),
+        };
+        if let Err(e) = result {
+            println!("[LOG] {}: {}", name, e);
+        }
+    }
+}
+
+/// Formats a record straight into the sinks that take it.
+struct FanOut {
+    sinks: [Option<Registered>; MAX_SINKS],
+    level: Level,
+}
+
+/// A line's timestamp, formatted on the stack.
+struct Stamp {
+    buf: [u8; 24],
+    len: usize,
+}
+
+impl fmt::Write for Stamp {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl Stamp {
+    fn now(mode: Timestamps) -> Option<Stamp> {
+        use core::fmt::Write;
+        let mut stamp = Stamp { buf: [0; 24], len: 0 };
+        let _ = match mode {
+            Timestamps::Off => return None,
+            Timestamps::Mono => {
+                let us = crate::time::uptime_ns() / 1000;
+                write!(stamp, "[{:5}.{:06}] ", us / 1_000_000, us % 1_000_000)
+            }
+            Timestamps::Tsc => write!(stamp, "[{:016x}] ", crate::arch::tsc_timer::rdtsc()),
+        };
+        Some(stamp)
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl FanOut {
+    fn emit(&self, s: &str, stamp_only: bool) {
+        for r in self.sinks.iter().flatten().filter(|r| self.level <= r.level) {
+            if !stamp_only || r.sink.timestamps() {
+                r.sink.write_str(s);
+            }
+        }
+    }
+}
+
+impl fmt::Write for FanOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while !rest.is_empty() {
+            if LINE_START.swap(false, Ordering::Relaxed) {
+                if let Some(stamp) = Stamp::now(timestamps()) {
+                    self.emit(stamp.as_str(), true);
+                }
+            }
+            let line_end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+            let (piece, more) = rest.split_at(line_end);
+            self.emit(piece, false);
+            if piece.ends_with('\n') {
+                LINE_START.store(true, Ordering::Relaxed);
+            }
+            rest = more;
+        }
+        Ok(())
+    }
+}
+
+/// The registry for the panic report, taken even if a stopped CPU (or the
+/// panicking one) holds its lock.
+fn panic_sinks() -> [Option<Registered>; MAX_SINKS] {
+    if let Some(sinks) = SINKS.try_lock() {
+        return *sinks;
+    }
+    // SAFETY: the holder won't run again; the entries are plain copies
+    unsafe { SINKS.force_unlock() };
+    SINKS.try_lock().map_or_else(default_sinks, |sinks| *sinks)
+}
+
+/// Emit one record at `level`.
+pub fn log(level: Level, args: fmt::Arguments) {
+    use core::fmt::Write;
+    // a snapshot, so sinks don't run under the registry lock
+    let sinks = if crate::arch::panic::panicking() { panic_sinks() } else { with_sinks(|sinks| *sinks) };
+    let _ = FanOut { sinks, level }.write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    log(Level::Info, args);
+}
+
+/// Log a line at the given level: `klog!(Level::Warn, "[NET] {}", e)`.
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => ($crate::log::log($level, format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,276 @@
+//! Built-in log sinks
+//!
+//! - `vga`: the boot VGA text buffer, registered from the start
+//! - `ring`: an in-memory ring of recent output, registered from the start;
+//!   once the wall clock is set, each line starts with its `[HH:MM:SS]`
+//! - `early`: what the screen showed before a framebuffer console existed,
+//!   registered from the start and replayed onto the framebuffer console
+//!   by `replay_early_log` when it comes up, since switching to graphics
+//!   mode loses the VGA text buffer
+//! - `fb`: the first framebuffer console, registered when VBE comes up
+//! - `serial`: COM1, on request
+//! - `debugcon`: QEMU's port 0xE9 console (`-debugcon stdio`), on request
+//!
+//! While a panic is reported, no sink waits for a lock: the other CPUs
+//! are stopped and may hold one for good, and so may the panicking CPU.
+//! `vga` takes its writer over, `serial` writes around its lock, `ring`
+//! and `early` drop what they can't lock, and `fb` leaves the screen to
+//! the panic screen.
+
+use crate::prelude::*;
+use super::{Level, LogSink};
+use crate::devices::console::ConsoleBackend;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Bytes of recent output kept by the `ring` sink.
+pub const LOG_RING_SIZE: usize = 16 * 1024;
+/// Bytes of screen output kept by the `early` sink; older output is dropped.
+pub const EARLY_LOG_SIZE: usize = 8 * 1024;
+
+pub struct BootVgaSink;
+
+impl LogSink for BootVgaSink {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write_str(&self, s: &str) {
+        if crate::arch::panic::panicking() {
+            crate::devices::console::vga_write_panic(s);
+            return;
+        }
+        crate::devices::console::VGA_TEXT_CONSOLE.write_str(s);
+    }
+
+    fn timestamps(&self) -> bool {
+        false
+    }
+}
+
+pub struct FramebufferSink;
+
+impl LogSink for FramebufferSink {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+
+    fn write_str(&self, s: &str) {
+        crate::driver_framework::drivers::vbe_vga::vbe_print_only(s);
+    }
+
+    fn timestamps(&self) -> bool {
+        false
+    }
+}
+
+pub struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&self, s: &str) {
+        if crate::arch::panic::panicking() {
+            crate::devices::serial::serial_write_panic(s.as_bytes());
+            return;
+        }
+        crate::devices::serial::serial_write_str(s);
+    }
+}
+
+pub struct DebugconSink;
+
+impl LogSink for DebugconSink {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn write_str(&self, s: &str) {
+        for b in s.bytes() {
+            unsafe { crate::arch::ports::outb(0xE9, b) };
+        }
+    }
+}
+
+struct Ring {
+    buf: [u8; LOG_RING_SIZE],
+    /// Next byte to write.
+    head: usize,
+    len: usize,
+    /// The last byte written ended a line.
+    line_start: bool,
+}
+
+impl Ring {
+    fn push(&mut self, b: u8) {
+        let head = self.head;
+        self.buf[head] = b;
+        self.head = (head + 1) % LOG_RING_SIZE;
+        self.len = (self.len + 1).min(LOG_RING_SIZE);
+    }
+
+    /// `[HH:MM:SS] ` from the wall clock, if it has been set.
+    fn push_stamp(&mut self) {
+        if !crate::time::wall_clock_synced() {
+            return;
+        }
+        let t = crate::time::now();
+        self.push(b'[');
+        for (i, v) in [t.hour, t.minute, t.second].into_iter().enumerate() {
+            if i > 0 {
+                self.push(b':');
+            }
+            self.push(b'0' + v / 10);
+            self.push(b'0' + v % 10);
+        }
+        self.push(b']');
+        self.push(b' ');
+    }
+}
+
+pub struct RingSink {
+    ring: Mutex<Ring>,
+}
+
+impl LogSink for RingSink {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn write_str(&self, s: &str) {
+        interrupts::without_interrupts(|| {
+            let Some(mut ring) = lock_unless_panicking(&self.ring) else { return };
+            for &b in s.as_bytes() {
+                if ring.line_start {
+                    ring.push_stamp();
+                }
+                ring.push(b);
+                ring.line_start = b == b'\n';
+            }
+        });
+    }
+}
+
+struct Early {
+    buf: [u8; EARLY_LOG_SIZE],
+    head: usize,
+    len: usize,
+}
+
+pub struct EarlySink {
+    early: Mutex<Early>,
+}
+
+impl LogSink for EarlySink {
+    fn name(&self) -> &'static str {
+        "early"
+    }
+
+    fn write_str(&self, s: &str) {
+        interrupts::without_interrupts(|| {
+            let Some(mut early) = lock_unless_panicking(&self.early) else { return };
+            for &b in s.as_bytes() {
+                let head = early.head;
+                early.buf[head] = b;
+                early.head = (head + 1) % EARLY_LOG_SIZE;
+                early.len = (early.len + 1).min(EARLY_LOG_SIZE);
+            }
+        });
+    }
+
+    // like the screen it stands in for
+    fn timestamps(&self) -> bool {
+        false
+    }
+}
+
+/// The lock, or while a panic is reported, the lock if it is free: what a
+/// stopped CPU was writing is lost rather than the report.
+fn lock_unless_panicking<T>(lock: &Mutex<T>) -> Option<spin::MutexGuard<'_, T>> {
+    if crate::arch::panic::panicking() {
+        lock.try_lock()
+    } else {
+        Some(lock.lock())
+    }
+}
+
+pub static BOOT_VGA_SINK: BootVgaSink = BootVgaSink;
+pub static FRAMEBUFFER_SINK: FramebufferSink = FramebufferSink;
+pub static SERIAL_SINK: SerialSink = SerialSink;
+pub static DEBUGCON_SINK: DebugconSink = DebugconSink;
+pub static EARLY_SINK: EarlySink = EarlySink { early: Mutex::new(Early { buf: [0; EARLY_LOG_SIZE], head: 0, len: 0 }) };
+pub static RING_SINK: RingSink = RingSink { ring: Mutex::new(Ring { buf: [0; LOG_RING_SIZE], head: 0, len: 0, line_start: true }) };
+
+/// Sinks registered before anything else runs.
+pub(super) const DEFAULT_SINKS: [(&'static dyn LogSink, Level); 3] =
+    [(&BOOT_VGA_SINK, Level::Info), (&EARLY_SINK, Level::Info), (&RING_SINK, Level::Trace)];
+
+/// The built-in sink called `name`.
+pub fn builtin_sink(name: &str) -> Option<&'static dyn LogSink> {
+    match name {
+        "vga" => Some(&BOOT_VGA_SINK),
+        "fb" => Some(&FRAMEBUFFER_SINK),
+        "serial" => Some(&SERIAL_SINK),
+        "debugcon" => Some(&DEBUGCON_SINK),
+        "ring" => Some(&RING_SINK),
+        _ => None,
+    }
+}
+
+/// Write the output the `early` sink kept to `sink`, then unregister and
+/// empty it: from here on `sink` shows the output itself. If the buffer
+/// overflowed, replay starts at the first whole line it still holds.
+pub fn replay_early_log(sink: &dyn LogSink) {
+    super::remove_sink("early");
+    interrupts::without_interrupts(|| {
+        let mut early = EARLY_SINK.early.lock();
+        let start = (early.head + EARLY_LOG_SIZE - early.len) % EARLY_LOG_SIZE;
+        let (older, newer) = if start + early.len <= EARLY_LOG_SIZE {
+            (&early.buf[start..start + early.len], &early.buf[..0])
+        } else {
+            (&early.buf[start..], &early.buf[..early.head])
+        };
+        let mut skip_partial = early.len == EARLY_LOG_SIZE;
+        if skip_partial {
+            sink.write_str("[... earlier boot output dropped]\n");
+        }
+        for mut part in [older, newer] {
+            if skip_partial {
+                match part.iter().position(|&b| b == b'\n') {
+                    Some(i) => {
+                        part = &part[i + 1..];
+                        skip_partial = false;
+                    }
+                    None => continue,
+                }
+            }
+            for chunk in part.utf8_chunks() {
+                sink.write_str(chunk.valid());
+            }
+        }
+        early.head = 0;
+        early.len = 0;
+    });
+}
+
+/// Copy the most recent output held by the ring into `out`, oldest first.
+/// Returns the number of bytes copied.
+pub fn read_log_ring(out: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| copy_ring_tail(&RING_SINK.ring.lock(), out))
+}
+
+/// `read_log_ring` for the panic path: `None` if the ring is locked.
+pub fn try_read_log_ring(out: &mut [u8]) -> Option<usize> {
+    RING_SINK.ring.try_lock().map(|ring| copy_ring_tail(&ring, out))
+}
+
+fn copy_ring_tail(ring: &Ring, out: &mut [u8]) -> usize {
+    let n = ring.len.min(out.len());
+    let start = (ring.head + LOG_RING_SIZE - n) % LOG_RING_SIZE;
+    for (i, b) in out[..n].iter_mut().enumerate() {
+        *b = ring.buf[(start + i) % LOG_RING_SIZE];
+    }
+    n
+}
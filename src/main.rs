@@ -9,31 +9,45 @@ use core::arch::asm;
 use core::panic::PanicInfo;
 use bootloader::*;
 use x86_64::{structures::paging::Translate, VirtAddr, structures::paging::Page};
-use x86_64::instructions::port::Port;
 
 entry_point!(kernel_main);
+multiboot2_entry_point!(kernel_start);
 
 extern crate neutrix;
 extern crate alloc;
 
 use neutrix::*;
+use neutrix::arch::gdt::init_gdt;
+use neutrix::arch::idt::init_idt;
+use neutrix::arch::processor::enable_sse;
+use neutrix::arch::syscall::init_syscalls;
+use neutrix::arch::task::{Executor, Task};
+use neutrix::bootvga::Color;
+use neutrix::driver_framework::driver::DriverParams;
+use neutrix::memory::{allocator, BootInfoFrameAllocator};
 use crate::driver_framework::drivers::ps2kbd;
 
+/// Entry from the `bootloader` crate.
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+	kernel_start(boot::install_boot_params(boot::BootParams::from_bootloader(boot_info)))
+}
+
+fn kernel_start(params: &'static boot::BootParams) -> ! {
+	time::boot_start();
 	enable_sse();
-	let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+	let phys_mem_offset = VirtAddr::new(params.phys_offset);
 	
 	// Initialize paging and frame allocator first so we can set up the heap
-	let mut mapper = unsafe { memory::init(phys_mem_offset) };
+	let mut mapper = time::boot_phase("paging", || unsafe { memory::init(phys_mem_offset) });
 	// Try to obtain a linker-provided kernel end symbol (optional).
 	// If it's not present (e.g., building with LLVM on Windows), fall back to
-	// a conservative heuristic: reserve the page containing `kernel_main` and
+	// a conservative heuristic: reserve the page containing `kernel_start` and
 	// the next 1 MiB to avoid allocating kernel code/data pages.
-	// Compute a conservative kernel_reserved_end using the address of `kernel_main`.
+	// Compute a conservative kernel_reserved_end using the address of `kernel_start`.
 	// This avoids requiring a linker-provided symbol on platforms where it's not available.
 	let kernel_reserved_end = unsafe {
-		// Fallback: take address of `kernel_main` and reserve a 1 MiB window
-		let fn_virt = kernel_main as usize as u64;
+		// Fallback: take address of `kernel_start` and reserve a 1 MiB window
+		let fn_virt = kernel_start as usize as u64;
 		let phys_offset_val = phys_mem_offset.as_u64();
 		if fn_virt <= phys_offset_val {
 			None
@@ -44,30 +58,33 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 		}
 	};
 
-	let mut frame_allocator = unsafe {
-		BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset, kernel_reserved_end)
-	};
+	let mut frame_allocator = time::boot_phase("frame allocator", || unsafe {
+		BootInfoFrameAllocator::init(&params.memory_map, phys_mem_offset, kernel_reserved_end)
+	});
 
-	// Provide mapper / frame allocator pointers to drivers that map BARs
-	// Safety: pass raw pointers to the global set functions used by drivers
-	crate::driver_framework::drivers::vbe_vga::set_global_mapper_ptr(&mut mapper as *mut _);
-	crate::driver_framework::drivers::vbe_vga::set_global_frame_allocator_ptr(&mut frame_allocator as *mut _);
+	memory::set_global_frame_allocator(&mut frame_allocator as *mut _);
 
 	// Initialize the global heap before calling HAL so modules that use
 	// `alloc` (Vec/Box) during ACPI/MADT parsing have a working allocator.
-	allocator::init_heap(&mut mapper, &mut frame_allocator)
+	time::boot_phase("heap", || allocator::init_heap(&mut mapper, &mut frame_allocator))
 		.expect("heap initialization failed");
+	// from here on the page tables are only reached through kernel_space()
+	memory::init_kernel_space(mapper);
+	boot::report_boot_params();
 
-	init_gdt();
+	time::boot_phase("symbols", symbols::init);
+	time::boot_phase("gdt", init_gdt);
 	setcolor!(Color::Yellow, Color::Black);
-	init_idt();
+	time::boot_phase("idt", init_idt);
+	time::boot_phase("syscalls", init_syscalls);
 
 	// Initialize hardware through HAL (ACPI parsing may allocate)
-	let (cpu_info, acpi_status) = hal::init_hardware(phys_mem_offset);
+	let (cpu_info, acpi_status) = time::boot_phase("hal", || hal::init_hardware(phys_mem_offset));
+	time::boot_phase("pmu", arch::pmu::init_pmu);
 
 	// Scan PCI devices and register them with the device manager (no drivers attached yet)
 	// Pass the physical memory offset so PCI code can probe MMIO (MSI-X tables)
-	devices::pci::scan_and_register_with_phys_offset(phys_mem_offset.as_u64());
+	time::boot_phase("pci scan", || devices::pci::scan_and_register_with_phys_offset(phys_mem_offset.as_u64()));
 
 	// Provide the global boot physical offset to drivers that need to map BARs
 	crate::driver_framework::drivers::set_boot_phys_offset(phys_mem_offset.as_u64());
@@ -86,14 +103,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
  		},
  		capabilities: alloc::vec::Vec::new(),
  		description: alloc::format!("PS/2 Keyboard"),
+ 		location: None,
  	};
 
 	let dev_id = crate::driver_framework::manager::GLOBAL_MANAGER.register_device(kbd_info);
 	// Attach our KMDF-style ps2 keyboard driver
 	let drv = driver_framework::drivers::ps2kbd::boxed_driver();
-	if let Err(e) = crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver(dev_id, drv) {
+	if let Err(e) = time::boot_phase("attach ps2kbd", || crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver_with_params(dev_id, drv, &DriverParams::for_driver("ps2kbd"))) {
  		println!("Failed to attach PS/2 keyboard driver: {}", e);
  	}
+	input::init();
 
 	// Register a logical console device and attach the console driver. This
 	// lets other subsystems treat the console as a managed device and allows
@@ -110,43 +129,32 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 		},
 		capabilities: alloc::vec::Vec::new(),
 		description: alloc::format!("Logical Console Device"),
+		location: None,
 	};
 
 	let console_dev_id = crate::driver_framework::manager::GLOBAL_MANAGER.register_device(console_info);
 	let console_drv = driver_framework::drivers::console::boxed_driver();
-	if let Err(e) = crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver(console_dev_id, console_drv) {
+	if let Err(e) = time::boot_phase("attach console", || crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver_with_params(console_dev_id, console_drv, &DriverParams::for_driver("console"))) {
 		println!("Failed to attach console driver: {}", e);
 	}
 
-	// Continue with architecture-specific initialization
-	// Do not initialize legacy PICs when running with APIC-only interrupts.
-	// Instead, mask (disable) both PICs so they don't deliver IRQs.
-	unsafe {
-		// Mask all IRQs on the legacy PICs by writing 0xFF to their data ports
-		// Master PIC data port = 0x21, Slave PIC data port = 0xA1
-		let mut master_data: Port<u8> = Port::new(0x21);
-		let mut slave_data: Port<u8> = Port::new(0xA1);
-		// Safety: writing to I/O ports is inherently unsafe; this masks all PIC IRQs
-		master_data.write(0xFFu8);
-		slave_data.write(0xFFu8);
-	}
-	// Program IOAPIC ISOs to target this CPU and unmask them now that IDT is ready.
-	if hal::apic::is_initialized() {
-		if let Some(apic_id) = hal::apic::local_apic_id() {
-			hal::ioapic::enable_isos_for_local(phys_mem_offset, apic_id);
-		} else {
-			println!("[HAL] APIC initialized but failed to read local APIC id");
-		}
-	}
+	// Calibrate the TSC against the HPET and pick the monotonic clock source
+	time::boot_phase("clock", || time::init_clock(phys_mem_offset));
+	time::boot_phase("wall clock", time::sync_wall_clock);
+	time::boot_phase("random", random::init);
+	// here rather than in a boot phase: frames live across the change fail
+	// their check when they return, and kernel_main never returns
+	arch::stackguard::randomize_stack_guard();
 
-	// If CPU supports TSC and APIC is present, switch to TSC-deadline timer
-	if hal::apic::is_initialized() && crate::arch::detect_cpu_features().tsc {
+	// If CPU supports TSC and APIC is present, switch to the TSC-deadline
+	// timer, or to the one-shot LAPIC timer if the TSC can't be trusted with it;
+	// in PIC mode the PIT ticks
+	if hal::irq_mode() == hal::IrqMode::Pic || (hal::apic::is_initialized() && crate::arch::detect_cpu_features().tsc) {
 		// Disable PIT (already handled in enable_cpu_features if tsc was present),
-		// initialize TSC-deadline timer and calibrate it against HPET if available
-		if crate::arch::tsc_timer::init(&mut mapper, &mut frame_allocator, phys_mem_offset, 10) {
-			println!("[TIMER] TSC-deadline timer initialized (calibrated if HPET present)");
-		} else {
-			println!("[TIMER] TSC-deadline timer not enabled (missing features or calibration failed)");
+		// and start this CPU's timer at the calibrated rate
+		match time::boot_phase("timer", || crate::arch::tsc_timer::init(10)) {
+			Ok(kind) => println!("[TIMER] {:?} timer initialized", kind),
+			Err(e) => println!("[TIMER] no timer interrupt: {}", e),
 		}
 	}
 	x86_64::instructions::interrupts::enable();
@@ -154,6 +162,30 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 	// Print registered devices for debugging (human-readable class/subclass)
 	crate::driver_framework::manager::GLOBAL_MANAGER.list_devices();
 
+	// Early files (test binaries, fonts, configuration) come from the initrd;
+	// the configuration is read before the framebuffer picks its mode
+	time::boot_phase("initrd", fs::mount_initrd);
+	time::boot_phase("config", config::load_config);
+
+	// COM1, which the log has been writing to since boot; the driver only
+	// lets its rate be set
+	let serial_info = driver_framework::device::DeviceInfo {
+		vendor_id: 0xffff,
+		device_id: 0xffff,
+		class: 0x07, // serial controller
+		subclass: 0x00,
+		prog_if: 0x02, // 16550
+		resources: alloc::vec![driver_framework::device::Resource { kind: driver_framework::device::ResourceKind::IO, addr: devices::serial::COM1 as u64, len: 8 }],
+		capabilities: alloc::vec::Vec::new(),
+		description: alloc::format!("COM1 Serial Port"),
+		location: None,
+	};
+	let serial_dev_id = crate::driver_framework::manager::GLOBAL_MANAGER.register_device(serial_info);
+	let serial_drv = driver_framework::drivers::serial::boxed_driver();
+	if let Err(e) = time::boot_phase("attach serial", || crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver_with_params(serial_dev_id, serial_drv, &DriverParams::for_driver("serial"))) {
+		println!("Failed to attach serial driver: {}", e);
+	}
+
 	// Attach VBE/linear framebuffer driver to any discovered PCI display controller
 	// (class 0x03). Do not hold GLOBAL_MANAGER.devices lock while calling attach_driver
 	// (it will re-lock internally).
@@ -172,11 +204,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 		// Try to attach our VBE driver for any display controller found
 		let drv = driver_framework::drivers::vbe_vga::boxed_driver();
 		// Ignore attach errors (probe/start may fail on some hardware)
-		let _ = crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver(dev_id, drv);
+		let _ = time::boot_phase("attach vbe", || crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver_with_params(dev_id, drv, &DriverParams::for_driver("vbe")));
 	}
 
-	// If VBE driver activated, clear screen and print a short message
-	cls!();
+	// The framebuffer console, if VBE came up, already shows the output so far
 	println!("neutrix: vbe framebuffer ready\n");
 
 	// Manually register a PS/2 mouse device (legacy IRQ-based)
@@ -193,11 +224,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 		},
 		capabilities: alloc::vec::Vec::new(),
 		description: alloc::format!("PS/2 Mouse"),
+		location: None,
 	};
 
 	let mouse_dev_id = crate::driver_framework::manager::GLOBAL_MANAGER.register_device(mouse_info);
 	let mouse_drv = driver_framework::drivers::ps2mouse::boxed_driver();
-	if let Err(e) = crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver(mouse_dev_id, mouse_drv) {
+	if let Err(e) = time::boot_phase("attach ps2mouse", || crate::driver_framework::manager::GLOBAL_MANAGER.attach_driver_with_params(mouse_dev_id, mouse_drv, &DriverParams::for_driver("ps2mouse"))) {
 		println!("Failed to attach PS/2 mouse driver: {}", e);
 	} else {
 		// If we have framebuffer info, set cursor to center
@@ -206,90 +238,57 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 			let cy = (info.height as i32) / 2;
 			crate::driver_framework::drivers::ps2mouse::set_cursor_pos(cx, cy);
 		}
+	}
 
-		// Ensure IOAPIC redirection entry for the PS/2 device is unmasked.
-		// Prefer to map using ACPI ISOs if present so we unmask the correct GSI.
-		if hal::apic::is_initialized() {
-			if let Some(apic_id) = hal::apic::local_apic_id() {
-				// Find the interrupt vector resource on the device (we registered one earlier)
-				let devinfo_opt = {
-					let devices = crate::driver_framework::manager::GLOBAL_MANAGER.devices.lock();
-					devices.iter().find(|e| e.device.id == mouse_dev_id).map(|e| e.device.info())
-				};
-				// If device info isn't available, fall back to legacy IRQ 12
-				if let Some(devinfo) = devinfo_opt {
-					let mut handled = false;
-					for r in devinfo.resources.iter() {
-						if let driver_framework::device::ResourceKind::Interrupt(vec) = r.kind {
-							let vector = vec;
-							// Legacy IRQ candidate = vector - 0x20
-							let legacy_irq = (vector as u32).wrapping_sub(0x20u32) & 0xFF;
-							// Try to find an ISO that maps this legacy IRQ to a GSI
-							let mut gsi_candidate = legacy_irq; // fallback
-							let isos = crate::devices::acpi::get_isos();
-							for iso in isos.iter() {
-								if iso.source as u32 == legacy_irq {
-									gsi_candidate = iso.gsi;
-									break;
-								}
-							}
-
-							if hal::ioapic::unmask_gsi(gsi_candidate, vector, apic_id, phys_mem_offset) {
-								println!("[MAIN] Unmasked IOAPIC GSI {} -> vector 0x{:x} apic {}", gsi_candidate, vector, apic_id);
-								if let Some((low, high)) = hal::ioapic::read_redirection_entry(gsi_candidate, phys_mem_offset) {
-									println!("[MAIN] IOAPIC GSI {} redir low=0x{:08x} high=0x{:08x}", gsi_candidate, low, high);
-								}
-							} else {
-								println!("[MAIN] Failed to unmask IOAPIC GSI {} (vector 0x{:x})", gsi_candidate, vector);
-							}
-							handled = true;
-						}
-					}
-					if !handled {
-						// no interrupt resource found; try legacy IRQ 12 as last resort
-						let legacy_irq = 12u32;
-						let vector = 0x20u8.wrapping_add(12u8);
-						if hal::ioapic::unmask_gsi(legacy_irq, vector, apic_id, phys_mem_offset) {
-							println!("[MAIN] Unmasked IOAPIC fallback GSI {} -> vector 0x{:x} apic {}", legacy_irq, vector, apic_id);
-							if let Some((low, high)) = hal::ioapic::read_redirection_entry(legacy_irq, phys_mem_offset) {
-								println!("[MAIN] IOAPIC GSI {} redir low=0x{:08x} high=0x{:08x}", legacy_irq, low, high);
-							}
-						} else {
-							println!("[MAIN] Failed to unmask IOAPIC fallback GSI {}", legacy_irq);
-						}
-					}
-				} else {
-					// Could not retrieve device info, fallback
-					let legacy_irq = 12u32;
-					let vector = 0x20u8.wrapping_add(12u8);
-					if hal::ioapic::unmask_gsi(legacy_irq, vector, apic_id, phys_mem_offset) {
-						println!("[MAIN] Unmasked IOAPIC fallback GSI {} -> vector 0x{:x} apic {}", legacy_irq, vector, apic_id);
-						if let Some((low, high)) = hal::ioapic::read_redirection_entry(legacy_irq, phys_mem_offset) {
-							println!("[MAIN] IOAPIC GSI {} redir low=0x{:08x} high=0x{:08x}", legacy_irq, low, high);
-						}
-					} else {
-						println!("[MAIN] Failed to unmask IOAPIC fallback GSI {}", legacy_irq);
-					}
-				}
-			} else {
-				println!("[MAIN] APIC initialized but failed to read local APIC id for IOAPIC unmask");
-			}
+	if let Err(e) = fs::vfs::mount("/tmp", alloc::sync::Arc::new(fs::RamFs::new())) {
+		println!("[VFS] failed to mount /tmp: {}", e);
+	}
+	devices::init_char_devices();
+	process::init_processes();
+	if process::smoketest::run_user_test_requested() {
+		process::smoketest::run_user_test();
+	}
+	let mut init_started = false;
+	if let Some(cmdline) = devices::fw_cfg::read_cmdline() {
+		println!("[MAIN] command line: {}", cmdline);
+		log::configure_from_cmdline(&cmdline);
+		trace::configure_trace_from_cmdline(&cmdline);
+		arch::crashdump::configure_crashdump_from_cmdline(&cmdline);
+		memory::heaptrack::configure_heaptrack_from_cmdline(&cmdline);
+		fault::configure_faults_from_cmdline(&cmdline);
+		net::configure_sntp_from_cmdline(&cmdline);
+		if testing::selftest::selftest_requested(&cmdline) {
+			testing::selftest::run_selftest(phys_mem_offset.as_u64());
 		}
-
-		// Spawn a background task to process mouse packets outside interrupt context
-		
+		match process::spawn_init(&cmdline) {
+			Some(Ok(_)) => init_started = true,
+			Some(Err(e)) => println!("[PROC] could not start init: {}", e),
+			None => {}
+		}
+	}
+	// the console belongs to init when there is one
+	if !init_started {
+		shell::start_shell();
 	}
 
+	arch::workqueue::init(arch::workqueue::DEFAULT_WORKERS);
+	time::init_timers();
+	net::start_sntp();
+	input::start_blank_timer();
+	arch::idle::init();
+	time::boot_phase("smp", hal::smp::start_aps);
+	arch::watchdog::init();
+	time::boot_done();
+
 	let mut executor = Executor::new();
 	executor.spawn(Task::new(driver_framework::drivers::ps2kbd::print_keypresses()));
-	executor.spawn(Task::new(driver_framework::drivers::ps2mouse::mouse_event_loop()));
+	// never returns: when no task is ready the CPU sleeps in arch::idle
+	// until an interrupt or a waker queues one
 	executor.run();
-	hlt();
 }
 
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt();
+    arch::panic::panic_report(info)
 }
\ No newline at end of file
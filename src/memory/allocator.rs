@@ -1,4 +1,4 @@
-use crate::*;
+use crate::prelude::*;
 use core::ptr::*;
 use x86_64::{
     structures::paging::{
@@ -7,12 +7,41 @@ use x86_64::{
     VirtAddr,
 };
 
+use core::alloc::{GlobalAlloc, Layout};
 use linked_list_allocator::LockedHeap;
 
 pub struct Dummy;
 
+/// The kernel heap, reporting to `heaptrack`. IRQ handlers allocate and
+/// free (waking tasks, the watchdog), so the heap lock is only held with
+/// interrupts off; otherwise an interrupt taken while it is held spins on
+/// it for good.
+pub struct TrackedHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for TrackedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if crate::fault::inject(crate::fault::FaultPoint::Alloc) {
+            return core::ptr::null_mut();
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let ptr = unsafe { self.0.alloc(layout) };
+            if !ptr.is_null() {
+                crate::memory::heaptrack::note_alloc(ptr, layout.size());
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            crate::memory::heaptrack::note_free(ptr);
+            unsafe { self.0.dealloc(ptr, layout) }
+        })
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: TrackedHeap = TrackedHeap(LockedHeap::empty());
 
 pub const HEAP_START: u64 = 0x_4444_4444_0000;
 pub const HEAP_SIZE: u64 = 100 * 1024; // 100 KiB
@@ -40,8 +69,22 @@ pub fn init_heap(
     }
 	
 	unsafe {
-        ALLOCATOR.lock().init(HEAP_START.try_into().unwrap(), HEAP_SIZE.try_into().unwrap());
+        ALLOCATOR.0.lock().init(HEAP_START.try_into().unwrap(), HEAP_SIZE.try_into().unwrap());
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Whether the heap lock is held. A panic report skips anything that
+/// allocates when it is, since the holder may never release it.
+pub fn heap_locked() -> bool {
+    ALLOCATOR.0.is_locked()
+}
+
+/// (used, free) bytes of the kernel heap.
+pub fn heap_usage() -> (usize, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        (heap.used(), heap.free())
+    })
+}
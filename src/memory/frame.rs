@@ -1,14 +1,18 @@
 #![no_std]
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
+use crate::boot::{MemoryKind, MemoryMap};
 use core::{ptr, slice};
 use x86_64::{
     VirtAddr,
     PhysAddr,
     structures::paging::{PhysFrame, Size4KiB, FrameAllocator},
 };
-use crate::*;
+use crate::prelude::*;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use spin::Mutex;
+
+/// Top of real-mode conventional memory; the EBDA and video memory follow.
+const CONVENTIONAL_MEMORY_END: u64 = 0xA0000;
 
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -41,8 +45,8 @@ impl BootInfoFrameAllocator {
         // determine highest physical address among usable regions
         let mut max_addr: u64 = 0;
         for region in memory_map.iter() {
-            if region.region_type == MemoryRegionType::Usable {
-                let end = region.range.end_addr();
+            if region.kind == MemoryKind::Usable {
+                let end = region.end;
                 if end as u64 > max_addr {
                     max_addr = end as u64;
                 }
@@ -68,11 +72,11 @@ impl BootInfoFrameAllocator {
         // find a usable region to hold the bitmap
         let mut bitmap_phys_start: u64 = 0;
         for region in memory_map.iter() {
-            if region.region_type != MemoryRegionType::Usable {
+            if region.kind != MemoryKind::Usable {
                 continue;
             }
-            let mut start = region.range.start_addr() as u64;
-            let end = region.range.end_addr() as u64;
+            let mut start = region.start as u64;
+            let end = region.end as u64;
             // skip regions that are before kernel_reserved_end
             if let Some(kend) = kernel_reserved_end {
                 if end <= kend {
@@ -94,9 +98,9 @@ impl BootInfoFrameAllocator {
         // If we didn't find a place for the bitmap, try to place at the first usable region (may overlap kernel)
         if bitmap_phys_start == 0 {
             for region in memory_map.iter() {
-                if region.region_type != MemoryRegionType::Usable { continue; }
-                let aligned = (region.range.start_addr() + 0xFFF) & !0xFFFu64;
-                let end = region.range.end_addr() as u64;
+                if region.kind != MemoryKind::Usable { continue; }
+                let aligned = (region.start + 0xFFF) & !0xFFFu64;
+                let end = region.end as u64;
                 if aligned + bitmap_bytes as u64 <= end {
                     bitmap_phys_start = aligned as u64;
                     break;
@@ -149,9 +153,9 @@ impl BootInfoFrameAllocator {
 
         // mark non-usable frames as used by leaving them set to 1; now clear usable ranges
         for region in memory_map.iter() {
-            if region.region_type == MemoryRegionType::Usable {
-                let start = region.range.start_addr() as u64;
-                let end = region.range.end_addr() as u64;
+            if region.kind == MemoryKind::Usable {
+                let start = region.start as u64;
+                let end = region.end as u64;
                 let start_idx = (start / 0x1000) as usize;
                 let end_idx = ((end + 0xFFF) / 0x1000) as usize;
                 clear_range(start_idx, end_idx);
@@ -197,6 +201,33 @@ impl BootInfoFrameAllocator {
         ptr::write_volatile(p, new);
     }
 
+    /// Frames free to allocate.
+    pub fn free_frames(&self) -> usize {
+        (0..self.num_frames).filter(|&i| !self.test_bit(i)).count()
+    }
+
+    /// Frames covered by the bitmap, from address 0 to the top of usable memory.
+    pub fn total_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// The highest usable frame below the 640 KiB of conventional memory,
+    /// for code a CPU runs in real mode; page 0, the real-mode interrupt
+    /// table, is never picked. It is marked used, though `init` reserves
+    /// everything below the kernel and this memory with it, so the same
+    /// frame comes back each call.
+    pub fn low_memory_frame(&mut self) -> Option<PhysFrame> {
+        let addr = self.memory_map.iter()
+            .filter(|r| r.kind == MemoryKind::Usable)
+            .filter_map(|r| {
+                let end = r.end.min(CONVENTIONAL_MEMORY_END) & !0xFFF;
+                (end >= 0x2000 && end >= r.start.next_multiple_of(0x1000) + 0x1000).then_some(end - 0x1000)
+            })
+            .max()?;
+        self.set_bit_runtime((addr / 0x1000) as usize, true);
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
     fn test_bit(&self, idx: usize) -> bool {
         if self.bitmap_bytes == 0 || idx >= self.num_frames { return true; }
         let virt_u64 = self.phys_offset.as_u64().wrapping_add(self.bitmap_phys_start);
@@ -254,3 +285,35 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+
+/// The boot frame allocator, registered by `kernel_main` once it exists, so
+/// code outside the boot path (process address spaces) can get frames.
+static GLOBAL_FRAME_ALLOCATOR: AtomicPtr<BootInfoFrameAllocator> = AtomicPtr::new(ptr::null_mut());
+static GLOBAL_FRAME_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn set_global_frame_allocator(p: *mut BootInfoFrameAllocator) {
+    GLOBAL_FRAME_ALLOCATOR.store(p, Ordering::Release);
+}
+
+/// Run `f` with exclusive use of the frame allocator, or return `None` if
+/// none has been registered.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    let p = GLOBAL_FRAME_ALLOCATOR.load(Ordering::Acquire);
+    if p.is_null() {
+        return None;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _g = GLOBAL_FRAME_LOCK.lock();
+        Some(f(unsafe { &mut *p }))
+    })
+}
+
+/// `FrameAllocator` over the registered frame allocator, for page-table
+/// code that wants one. Each frame takes the allocator's lock on its own.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|frames| frames.allocate_frame()).flatten()
+    }
+}
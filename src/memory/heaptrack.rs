@@ -0,0 +1,268 @@
+//! Heap allocation tracking
+//!
+//! Off by default; `heaptrack` on the command line or `heapstats on` turns
+//! it on. While on, every allocation is charged to the current CPU's tag:
+//! executor tasks are tagged with their name while polled, interrupt
+//! handlers with `irq`, and any code can narrow that down with
+//! `let _tag = heap_tag("e1000 rx");`. Per tag it keeps allocation and free
+//! counts and live bytes, so a driver that allocates per interrupt or per
+//! packet without freeing shows up as a tag whose live bytes only grow.
+//! `snapshot` and `diff` compare two points in time.
+//!
+//! All bookkeeping is in fixed tables (this runs inside the allocator):
+//! live blocks go in an open-addressed table keyed by address. Blocks
+//! allocated while it is full are counted as untracked.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Distinct tags kept; later ones are charged to `untagged`.
+pub const MAX_TAGS: usize = 64;
+/// Live blocks tracked at once.
+const MAX_LIVE: usize = 8192;
+const LIVE_BITS: u32 = MAX_LIVE.trailing_zeros();
+
+#[derive(Debug, Clone, Copy)]
+pub struct TagStats {
+    pub name: &'static str,
+    pub allocs: u64,
+    pub frees: u64,
+    pub live_bytes: u64,
+    pub live_count: u64,
+    pub peak_bytes: u64,
+}
+
+impl TagStats {
+    const EMPTY: TagStats = TagStats { name: "", allocs: 0, frees: 0, live_bytes: 0, live_count: 0, peak_bytes: 0 };
+}
+
+#[derive(Clone, Copy)]
+struct Live {
+    /// 0 for an empty slot.
+    addr: usize,
+    size: u32,
+    tag: u16,
+}
+
+struct Tracker {
+    tags: [TagStats; MAX_TAGS],
+    ntags: usize,
+    live: [Live; MAX_LIVE],
+    nlive: usize,
+    untracked: u64,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    tags: {
+        let mut tags = [TagStats::EMPTY; MAX_TAGS];
+        tags[0].name = "untagged";
+        tags
+    },
+    ntags: 1,
+    live: [Live { addr: 0, size: 0, tag: 0 }; MAX_LIVE],
+    nlive: 0,
+    untracked: 0,
+});
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Blocks in the live table; frees are looked up while any remain, even
+/// with tracking off.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_TAG: [AtomicU16; MAX_CPUS] = [const { AtomicU16::new(0) }; MAX_CPUS];
+static SNAPSHOT: Mutex<Option<[TagStats; MAX_TAGS]>> = Mutex::new(None);
+
+pub fn heap_tracking() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_heap_tracking(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// Turn tracking on if `heaptrack` is on the kernel command line.
+pub fn configure_heaptrack_from_cmdline(cmdline: &str) {
+    if cmdline.split_whitespace().any(|w| w == "heaptrack") {
+        set_heap_tracking(true);
+    }
+}
+
+fn slot_of(addr: usize) -> usize {
+    ((addr as u64 >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - LIVE_BITS)) as usize
+}
+
+impl Tracker {
+    fn intern(&mut self, name: &'static str) -> u16 {
+        if let Some(i) = self.tags[..self.ntags].iter().position(|t| t.name == name) {
+            return i as u16;
+        }
+        if self.ntags == MAX_TAGS {
+            return 0;
+        }
+        self.tags[self.ntags].name = name;
+        self.ntags += 1;
+        (self.ntags - 1) as u16
+    }
+
+    fn find(&self, addr: usize) -> Option<usize> {
+        let mut i = slot_of(addr);
+        while self.live[i].addr != 0 {
+            if self.live[i].addr == addr {
+                return Some(i);
+            }
+            i = (i + 1) % MAX_LIVE;
+        }
+        None
+    }
+
+    fn insert(&mut self, addr: usize, size: usize, tag: u16) {
+        // keep the table at most 3/4 full so probes stay short
+        if self.nlive >= MAX_LIVE / 4 * 3 {
+            self.untracked += 1;
+            return;
+        }
+        let mut i = slot_of(addr);
+        while self.live[i].addr != 0 {
+            i = (i + 1) % MAX_LIVE;
+        }
+        self.live[i] = Live { addr, size: size as u32, tag };
+        self.nlive += 1;
+        let t = &mut self.tags[tag as usize];
+        t.allocs += 1;
+        t.live_count += 1;
+        t.live_bytes += size as u64;
+        t.peak_bytes = t.peak_bytes.max(t.live_bytes);
+    }
+
+    /// Remove slot `i`, shifting later entries of its probe run back so
+    /// lookups never need tombstones.
+    fn remove(&mut self, mut i: usize) {
+        let entry = self.live[i];
+        let t = &mut self.tags[entry.tag as usize];
+        t.frees += 1;
+        t.live_count -= 1;
+        t.live_bytes -= entry.size as u64;
+        self.nlive -= 1;
+        let mut j = i;
+        loop {
+            j = (j + 1) % MAX_LIVE;
+            if self.live[j].addr == 0 {
+                break;
+            }
+            let home = slot_of(self.live[j].addr);
+            // move j back into the hole unless its home lies cyclically in (i, j]
+            let stays = if i <= j { i < home && home <= j } else { i < home || home <= j };
+            if !stays {
+                self.live[i] = self.live[j];
+                i = j;
+            }
+        }
+        self.live[i].addr = 0;
+    }
+}
+
+/// Record an allocation. Called by the global allocator.
+pub(crate) fn note_alloc(ptr: *mut u8, size: usize) {
+    if !heap_tracking() {
+        return;
+    }
+    let tag = CURRENT_TAG[crate::arch::task::current_cpu() % MAX_CPUS].load(Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        let mut t = TRACKER.lock();
+        t.insert(ptr as usize, size, tag);
+        LIVE.store(t.nlive, Ordering::Relaxed);
+    });
+}
+
+/// Record a free. Called by the global allocator.
+pub(crate) fn note_free(ptr: *mut u8) {
+    if LIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut t = TRACKER.lock();
+        if let Some(i) = t.find(ptr as usize) {
+            t.remove(i);
+        }
+        LIVE.store(t.nlive, Ordering::Relaxed);
+    });
+}
+
+/// Restores the previous tag when dropped.
+pub struct HeapTagGuard {
+    cpu: usize,
+    previous: u16,
+}
+
+impl Drop for HeapTagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG[self.cpu].store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Charge this CPU's allocations to `name` until the guard is dropped.
+pub fn heap_tag(name: &'static str) -> HeapTagGuard {
+    let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+    let previous = CURRENT_TAG[cpu].load(Ordering::Relaxed);
+    if heap_tracking() {
+        let id = interrupts::without_interrupts(|| TRACKER.lock().intern(name));
+        CURRENT_TAG[cpu].store(id, Ordering::Relaxed);
+    }
+    HeapTagGuard { cpu, previous }
+}
+
+fn copy_tags() -> ([TagStats; MAX_TAGS], usize, u64) {
+    interrupts::without_interrupts(|| {
+        let t = TRACKER.lock();
+        (t.tags, t.ntags, t.untracked)
+    })
+}
+
+/// Every tag seen so far, and the number of allocations that found the
+/// live table full.
+pub fn heap_tag_stats() -> (Vec<TagStats>, u64) {
+    // copy out first: collecting allocates, which takes the tracker lock
+    let (tags, n, untracked) = copy_tags();
+    (tags[..n].to_vec(), untracked)
+}
+
+/// Remember the current per-tag counts for `print_heap_diff`.
+pub fn heap_snapshot() {
+    let (tags, _, _) = copy_tags();
+    *SNAPSHOT.lock() = Some(tags);
+}
+
+/// Tags sorted by live bytes, largest first.
+pub fn print_heap_stats(top: usize) {
+    let (mut tags, untracked) = heap_tag_stats();
+    tags.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes));
+    println!("tracking {}; {} allocations untracked", if heap_tracking() { "on" } else { "off" }, untracked);
+    println!("{:<20} {:>10} {:>7} {:>9} {:>9} {:>10}", "TAG", "LIVE", "BLOCKS", "ALLOCS", "FREES", "PEAK");
+    for t in tags.iter().take(top) {
+        println!("{:<20} {:>10} {:>7} {:>9} {:>9} {:>10}", t.name, t.live_bytes, t.live_count, t.allocs, t.frees, t.peak_bytes);
+    }
+}
+
+/// Change per tag since `heap_snapshot`, largest growth first.
+pub fn print_heap_diff() -> Result<(), &'static str> {
+    let before = SNAPSHOT.lock().ok_or("no snapshot taken")?;
+    let (now, _) = heap_tag_stats();
+    let mut rows: Vec<(&'static str, i64, i64, u64, u64)> = now
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let b = &before[i];
+            (t.name, t.live_bytes as i64 - b.live_bytes as i64, t.live_count as i64 - b.live_count as i64, t.allocs - b.allocs, t.frees - b.frees)
+        })
+        .filter(|r| r.1 != 0 || r.3 != 0)
+        .collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1));
+    println!("{:<20} {:>10} {:>7} {:>9} {:>9}", "TAG", "LIVE+-", "BLOCKS+-", "ALLOCS", "FREES");
+    for (name, bytes, count, allocs, frees) in rows {
+        println!("{:<20} {:>+10} {:>+7} {:>9} {:>9}", name, bytes, count, allocs, frees);
+    }
+    Ok(())
+}
@@ -2,7 +2,7 @@
 // These are small, zero-dependency wrappers around the global allocator
 // exposing a few convenient constructors that mirror typical kernel APIs.
 
-use crate::*;
+use crate::prelude::*;
 use core::mem::MaybeUninit;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
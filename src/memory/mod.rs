@@ -2,7 +2,12 @@ pub mod paging;
 pub use paging::*;
 pub mod frame;
 pub use frame::*;
+pub mod space;
+pub use space::*;
 pub mod allocator;
 pub use allocator::*;
+pub mod heaptrack;
+pub use heaptrack::*;
 pub mod kmalloc;
-pub use kmalloc::*;
\ No newline at end of file
+pub use kmalloc::*;pub mod stack;
+pub use stack::*;
@@ -50,4 +50,51 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         None
     }
-}
\ No newline at end of file
+}
+/// Lowest non-canonical address; user mappings live below it.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Whether every byte of `[start, start + len)` is mapped user-accessible
+/// (and writable, if `write`) in the active page tables. Permission bits are
+/// checked at every level, since the CPU requires them all.
+pub fn user_range_accessible(physical_memory_offset: VirtAddr, start: u64, len: u64, write: bool) -> bool {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let end = match start.checked_add(len) {
+        Some(e) if e <= USER_SPACE_END => e,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    let mut need = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+    if write {
+        need |= Flags::WRITABLE;
+    }
+    let table = unsafe { &*active_level_4_table(physical_memory_offset) };
+    let mut addr = start & !0xFFF;
+    while addr < end {
+        let va = VirtAddr::new(addr);
+        let indices = [va.p4_index(), va.p3_index(), va.p2_index(), va.p1_index()];
+        let mut current = table;
+        let mut step = 0x1000u64;
+        for (level, index) in indices.iter().enumerate() {
+            let entry = &current[*index];
+            if !entry.flags().contains(need) {
+                return false;
+            }
+            if level == 3 || (level > 0 && entry.flags().contains(Flags::HUGE_PAGE)) {
+                step = match level {
+                    1 => 1 << 30,
+                    2 => 1 << 21,
+                    _ => 0x1000,
+                };
+                break;
+            }
+            let virt = physical_memory_offset + entry.addr().as_u64();
+            current = unsafe { &*virt.as_ptr::<PageTable>() };
+        }
+        addr = (addr & !(step - 1)) + step;
+    }
+    true
+}
@@ -0,0 +1,104 @@
+//! Kernel address space
+//!
+//! The kernel's page tables belong to one lock once `kernel_main` has set
+//! up the heap. Whatever maps or unmaps kernel memory after that (drivers
+//! mapping BARs, the clock mapping the HPET) goes through `kernel_space()`,
+//! whose handle holds the lock for as long as it lives. Page-table frames
+//! come from the global frame allocator, so the handle is all a caller
+//! needs.
+//!
+//! The lock masks interrupts, so mapping is allowed from any context. The
+//! frame allocator's lock is taken inside it: never call `kernel_space()`
+//! from within `with_frame_allocator`.
+//!
+//! `map_permanent` hands out memory for buffers too big for the heap that
+//! are kept for good, such as an initrd read from fw_cfg. It maps fresh
+//! frames into a region of its own, in the heap's top-level page table
+//! slot (see `memory::stack`), and never unmaps them.
+
+use crate::prelude::*;
+use crate::memory::frame::{with_frame_allocator, GlobalFrameAllocator};
+use crate::memory::HEAP_START;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::{IrqSpinlock, IrqSpinlockGuard};
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+static KERNEL_SPACE: IrqSpinlock<Option<OffsetPageTable<'static>>> = IrqSpinlock::new(None);
+
+/// Hand the kernel's page tables over to `kernel_space()`.
+pub fn init_kernel_space(mapper: OffsetPageTable<'static>) {
+    *KERNEL_SPACE.lock() = Some(mapper);
+}
+
+/// Exclusive use of the kernel page tables, with interrupts off until it
+/// is dropped.
+pub struct KernelSpace {
+    guard: IrqSpinlockGuard<'static, Option<OffsetPageTable<'static>>>,
+}
+
+/// Lock the kernel page tables. `None` before `init_kernel_space`.
+pub fn kernel_space() -> Option<KernelSpace> {
+    let guard = KERNEL_SPACE.lock();
+    guard.is_some().then_some(KernelSpace { guard })
+}
+
+impl KernelSpace {
+    fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        self.guard.as_mut().expect("kernel space handle without page tables")
+    }
+
+    /// Map `page` to `frame` and flush it from the TLB.
+    ///
+    /// # Safety
+    /// The caller must make sure the new mapping doesn't alias memory in
+    /// use as something else.
+    pub unsafe fn map(&mut self, page: Page<Size4KiB>, frame: PhysFrame<Size4KiB>, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let flush = unsafe { self.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator) }?;
+        flush.flush();
+        Ok(())
+    }
+
+    /// Unmap `page`, flush it from the TLB, and return the frame it mapped.
+    /// The frame is the caller's to free, if it should be.
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, UnmapError> {
+        let (frame, flush) = self.mapper().unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    }
+
+    /// Physical address `addr` maps to, if it is mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.guard.as_ref().expect("kernel space handle without page tables").translate_addr(addr)
+    }
+}
+
+/// Start of the `map_permanent` region, 4 GiB past the heap.
+pub const PERMANENT_START: u64 = HEAP_START + 0x1_0000_0000;
+/// Size of the `map_permanent` region.
+pub const PERMANENT_SIZE: u64 = 0x4000_0000;
+
+/// Next free address in the `map_permanent` region.
+static PERMANENT_NEXT: AtomicU64 = AtomicU64::new(PERMANENT_START);
+
+/// Map `len` bytes of zeroed memory that stays mapped for good, for
+/// buffers too big for the heap.
+pub fn map_permanent(len: usize) -> Result<&'static mut [u8], &'static str> {
+    let pages = (len as u64).div_ceil(4096);
+    let start = PERMANENT_NEXT.fetch_add(pages * 4096, Ordering::Relaxed);
+    if start + pages * 4096 > PERMANENT_START + PERMANENT_SIZE {
+        return Err("permanent mapping region full");
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for i in 0..pages {
+        let page = Page::containing_address(VirtAddr::new(start + i * 4096));
+        let frame = with_frame_allocator(|a| a.allocate_frame()).flatten().ok_or("out of memory")?;
+        let mut space = kernel_space().ok_or("kernel space not set up")?;
+        // SAFETY: the range was just reserved, and nothing else maps here
+        unsafe { space.map(page, frame, flags) }.map_err(|_| "could not map memory")?;
+    }
+    let buf = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, len) };
+    buf.fill(0);
+    Ok(buf)
+}
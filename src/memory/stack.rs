@@ -0,0 +1,102 @@
+//! Kernel stacks
+//!
+//! Kernel threads and application processors get their stacks from frames
+//! mapped into a region of their own, rather than from the heap, which is
+//! far smaller than a few of them. The region is cut into slots of one
+//! guard page and `KERNEL_STACK_SIZE` above it. The guard page is never
+//! mapped, so running off the bottom of a stack faults (and, with no stack
+//! to push the page fault on, double faults onto the IST stack) instead of
+//! writing over the stack below.
+//!
+//! The region sits in the heap's top-level page table slot, which every
+//! process address space copies when it is created; one of its own might
+//! not be there yet when the first process is.
+
+use crate::prelude::*;
+use crate::memory::{kernel_space, with_frame_allocator, HEAP_START};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+/// Start of the stack region, 2 GiB past the heap.
+pub const KSTACK_START: u64 = HEAP_START + 0x8000_0000;
+/// Stacks at once: one per kernel thread and per CPU, with room to spare.
+pub const KSTACK_SLOTS: usize = 64;
+const PAGE: u64 = 4096;
+const SLOT_SIZE: u64 = KERNEL_STACK_SIZE as u64 + PAGE;
+
+/// Bit n set while slot n is in use.
+static SLOTS: AtomicU64 = AtomicU64::new(0);
+
+/// A mapped kernel stack, unmapped and its frames freed on drop. Leak it
+/// with `core::mem::forget` for a stack that is used for good.
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Lowest address of the stack, just above the guard page.
+    pub fn bottom(&self) -> u64 {
+        KSTACK_START + self.slot as u64 * SLOT_SIZE + PAGE
+    }
+
+    /// One past the highest address: the initial stack pointer.
+    pub fn top(&self) -> u64 {
+        self.bottom() + KERNEL_STACK_SIZE as u64
+    }
+
+    pub fn as_slice(&self) -> &[u64] {
+        unsafe { core::slice::from_raw_parts(self.bottom() as *const u64, KERNEL_STACK_SIZE / 8) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u64] {
+        unsafe { core::slice::from_raw_parts_mut(self.bottom() as *mut u64, KERNEL_STACK_SIZE / 8) }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let bottom = self.bottom();
+        (0..KERNEL_STACK_SIZE as u64 / PAGE).map(move |i| Page::containing_address(VirtAddr::new(bottom + i * PAGE)))
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for page in self.pages() {
+            // a stack that failed to map fully is dropped too
+            let Some(frame) = kernel_space().and_then(|mut space| space.unmap(page).ok()) else { continue };
+            with_frame_allocator(|a| unsafe { a.free_frame(frame) });
+        }
+        SLOTS.fetch_and(!(1 << self.slot), Ordering::AcqRel);
+    }
+}
+
+/// Map a zeroed `KERNEL_STACK_SIZE` stack below an unmapped guard page.
+pub fn alloc_kernel_stack() -> Result<KernelStack, &'static str> {
+    let mut slot = 0;
+    SLOTS
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            slot = (!used).trailing_zeros() as usize;
+            (slot < KSTACK_SLOTS).then_some(used | 1 << slot)
+        })
+        .map_err(|_| "out of kernel stacks")?;
+    let mut stack = KernelStack { slot };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in stack.pages() {
+        let frame = with_frame_allocator(|a| a.allocate_frame()).flatten().ok_or("out of memory for a kernel stack")?;
+        let mut space = kernel_space().ok_or("kernel space not set up")?;
+        // SAFETY: the slot is ours, and nothing else maps into the region
+        if unsafe { space.map(page, frame, flags) }.is_err() {
+            drop(space);
+            with_frame_allocator(|a| unsafe { a.free_frame(frame) });
+            return Err("could not map a kernel stack");
+        }
+    }
+    stack.as_mut_slice().fill(0);
+    Ok(stack)
+}
+
+/// Kernel stacks currently allocated.
+pub fn kernel_stacks_in_use() -> usize {
+    SLOTS.load(Ordering::Relaxed).count_ones() as usize
+}
@@ -0,0 +1,170 @@
+use crate::prelude::*;
+use crate::net::addr::{Ipv4Addr, MacAddr};
+use crate::net::device::{NetError, NetInterface};
+use crate::net::ethernet::{send_ethernet, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::sync::WaitQueue;
+use crate::time::{with_timeout_ms, Deadline};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+const ARP_PACKET_LEN: usize = 28;
+const HTYPE_ETHERNET: u16 = 1;
+
+/// How long a learned mapping stays valid.
+pub const ARP_TTL_MS: u64 = 60_000;
+/// Wait per request before retrying.
+pub const ARP_TIMEOUT_MS: u64 = 1000;
+pub const ARP_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parse an Ethernet/IPv4 ARP packet; anything else is rejected.
+    pub fn parse(b: &[u8]) -> Result<ArpPacket, NetError> {
+        if b.len() < ARP_PACKET_LEN
+            || u16::from_be_bytes([b[0], b[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([b[2], b[3]]) != ETHERTYPE_IPV4
+            || b[4] != 6
+            || b[5] != 4
+        {
+            return Err(NetError::Malformed);
+        }
+        Ok(ArpPacket {
+            op: u16::from_be_bytes([b[6], b[7]]),
+            sender_mac: MacAddr::from_bytes(&b[8..14]),
+            sender_ip: Ipv4Addr::from_bytes(&b[14..18]),
+            target_mac: MacAddr::from_bytes(&b[18..24]),
+            target_ip: Ipv4Addr::from_bytes(&b[24..28]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(ARP_PACKET_LEN);
+        b.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        b.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        b.push(6);
+        b.push(4);
+        b.extend_from_slice(&self.op.to_be_bytes());
+        b.extend_from_slice(&self.sender_mac.0);
+        b.extend_from_slice(&self.sender_ip.0);
+        b.extend_from_slice(&self.target_mac.0);
+        b.extend_from_slice(&self.target_ip.0);
+        b
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ArpEntry {
+    mac: MacAddr,
+    expires: Deadline,
+}
+
+/// (interface index, address) -> mapping.
+static ARP_CACHE: Mutex<BTreeMap<(usize, Ipv4Addr), ArpEntry>> = Mutex::new(BTreeMap::new());
+/// Resolvers park here until a reply lands in the cache.
+static ARP_WAIT: WaitQueue = WaitQueue::new();
+
+/// Cached, unexpired mapping for `ip` on `iface`.
+pub fn arp_lookup(iface: &NetInterface, ip: Ipv4Addr) -> Option<MacAddr> {
+    interrupts::without_interrupts(|| {
+        let mut cache = ARP_CACHE.lock();
+        let key = (iface.index(), ip);
+        match cache.get(&key) {
+            Some(e) if !e.expires.expired() => Some(e.mac),
+            Some(_) => {
+                cache.remove(&key);
+                None
+            }
+            None => None,
+        }
+    })
+}
+
+pub fn arp_insert(iface: &NetInterface, ip: Ipv4Addr, mac: MacAddr) {
+    interrupts::without_interrupts(|| {
+        ARP_CACHE.lock().insert((iface.index(), ip), ArpEntry { mac, expires: Deadline::after_ms(ARP_TTL_MS) });
+    });
+    ARP_WAIT.wake_all();
+}
+
+/// Unexpired cache entries as (interface index, address, mac).
+pub fn arp_entries() -> Vec<(usize, Ipv4Addr, MacAddr)> {
+    interrupts::without_interrupts(|| {
+        let mut cache = ARP_CACHE.lock();
+        cache.retain(|_, e| !e.expires.expired());
+        cache.iter().map(|(&(i, ip), e)| (i, ip, e.mac)).collect()
+    })
+}
+
+pub fn arp_flush() {
+    interrupts::without_interrupts(|| ARP_CACHE.lock().clear());
+}
+
+/// Broadcast a who-has for `ip`.
+pub fn send_arp_request(iface: &NetInterface, ip: Ipv4Addr) -> Result<(), NetError> {
+    let pkt = ArpPacket {
+        op: ARP_REQUEST,
+        sender_mac: iface.mac(),
+        sender_ip: iface.ipv4().addr,
+        target_mac: MacAddr::ZERO,
+        target_ip: ip,
+    };
+    send_ethernet(iface, MacAddr::BROADCAST, ETHERTYPE_ARP, &pkt.to_bytes())
+}
+
+/// Resolve `ip` to a hardware address, asking the network if it isn't
+/// cached. Gives up after `ARP_RETRIES` unanswered requests.
+pub async fn arp_resolve(iface: &NetInterface, ip: Ipv4Addr) -> Result<MacAddr, NetError> {
+    if ip.is_broadcast() || ip == iface.ipv4().subnet_broadcast() {
+        return Ok(MacAddr::BROADCAST);
+    }
+    if let Some(mac) = arp_lookup(iface, ip) {
+        return Ok(mac);
+    }
+    for _ in 0..ARP_RETRIES {
+        send_arp_request(iface, ip)?;
+        if let Ok(mac) = with_timeout_ms(ARP_TIMEOUT_MS, ARP_WAIT.wait_until(|| arp_lookup(iface, ip))).await {
+            return Ok(mac);
+        }
+    }
+    Err(NetError::Timeout)
+}
+
+/// Handle a received ARP packet: learn the sender and answer requests for
+/// our address.
+pub fn arp_input(iface: &NetInterface, payload: &[u8]) {
+    let pkt = match ArpPacket::parse(payload) {
+        Ok(p) => p,
+        Err(_) => return iface.note_rx_error(),
+    };
+    let our_ip = iface.ipv4().addr;
+    let for_us = !our_ip.is_unspecified() && pkt.target_ip == our_ip;
+    // Update an existing mapping from any packet; only add new ones when
+    // the packet was aimed at us, so broadcasts don't fill the cache.
+    if !pkt.sender_ip.is_unspecified() && (for_us || arp_lookup(iface, pkt.sender_ip).is_some()) {
+        arp_insert(iface, pkt.sender_ip, pkt.sender_mac);
+    }
+    if pkt.op == ARP_REQUEST && for_us {
+        let reply = ArpPacket {
+            op: ARP_REPLY,
+            sender_mac: iface.mac(),
+            sender_ip: our_ip,
+            target_mac: pkt.sender_mac,
+            target_ip: pkt.sender_ip,
+        };
+        if let Err(e) = send_ethernet(iface, pkt.sender_mac, ETHERTYPE_ARP, &reply.to_bytes()) {
+            println!("[ARP] reply to {} failed: {}", pkt.sender_ip, e);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use crate::prelude::*;
+use crate::net::addr::{Ipv4Addr, MacAddr};
+use crate::sync::channel::{channel, Receiver, Sender, TrySendError};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoDevice,
+    /// No interface or gateway can reach the destination.
+    NoRoute,
+    /// The port is already bound.
+    AddrInUse,
+    /// Payload doesn't fit the MTU.
+    TooLarge,
+    /// A received packet failed to parse.
+    Malformed,
+    Timeout,
+    /// The socket has no peer or local address yet.
+    NotConnected,
+    /// Nothing to receive and the caller asked not to wait.
+    WouldBlock,
+    Unsupported,
+    /// The device refused the frame.
+    Io,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NetError::NoDevice => "no such network device",
+            NetError::NoRoute => "no route to host",
+            NetError::AddrInUse => "address already in use",
+            NetError::TooLarge => "message too long",
+            NetError::Malformed => "malformed packet",
+            NetError::Timeout => "timed out",
+            NetError::NotConnected => "socket is not connected",
+            NetError::WouldBlock => "operation would block",
+            NetError::Unsupported => "operation not supported",
+            NetError::Io => "device I/O error",
+        })
+    }
+}
+
+impl From<crate::time::TimeoutError> for NetError {
+    fn from(_: crate::time::TimeoutError) -> NetError {
+        NetError::Timeout
+    }
+}
+
+/// A network card. Drivers implement transmit; received frames are handed
+/// to the stack with `NetInterface::deliver`, which feeds the interface's
+/// receive stream.
+pub trait NetworkDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn mac(&self) -> MacAddr;
+
+    /// Largest payload (excluding the Ethernet header) the device carries.
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    /// Send one complete Ethernet frame.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+}
+
+/// Frames buffered between a driver's receive path and the stack.
+pub const RX_QUEUE_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Ipv4Config {
+    /// `ip` is on this interface's subnet.
+    pub fn is_local(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        !self.addr.is_unspecified() && ip.to_u32() & mask == self.addr.to_u32() & mask
+    }
+
+    pub fn subnet_broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetStats {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub rx_dropped: u64,
+    /// Frames that failed to parse or had a bad checksum.
+    pub rx_errors: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// A registered device plus the stack's per-interface state.
+pub struct NetInterface {
+    index: usize,
+    device: Arc<dyn NetworkDevice>,
+    rx: Sender<Vec<u8>>,
+    ipv4: Mutex<Ipv4Config>,
+    rx_frames: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    rx_errors: AtomicU64,
+    tx_frames: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+impl NetInterface {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.device.mac()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetworkDevice> {
+        &self.device
+    }
+
+    pub fn ipv4(&self) -> Ipv4Config {
+        *self.ipv4.lock()
+    }
+
+    pub fn set_ipv4(&self, config: Ipv4Config) {
+        *self.ipv4.lock() = config;
+        println!("[NET] {}: {} netmask {} gateway {}", self.name(), config.addr, config.netmask, config.gateway);
+    }
+
+    /// Queue a received frame for the stack. Never blocks, so drivers may
+    /// call it from their interrupt handler; returns false if it was dropped.
+    pub fn deliver(&self, mut frame: Vec<u8>) -> bool {
+        let len = frame.len() as u64;
+        crate::fault::corrupt_frame(&mut frame);
+        if crate::fault::inject(crate::fault::FaultPoint::QueueFull) {
+            self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        match self.rx.try_send(frame) {
+            Ok(()) => {
+                self.rx_frames.fetch_add(1, Ordering::Relaxed);
+                self.rx_bytes.fetch_add(len, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Transmit a complete frame.
+    pub fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        match self.device.transmit(frame) {
+            Ok(()) => {
+                self.tx_frames.fetch_add(1, Ordering::Relaxed);
+                self.tx_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                self.tx_errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Count a frame the stack received but had no use for.
+    pub fn note_rx_dropped(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a frame that was malformed or failed a checksum.
+    pub fn note_rx_error(&self) {
+        self.rx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> NetStats {
+        NetStats {
+            rx_frames: self.rx_frames.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            tx_frames: self.tx_frames.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static INTERFACES: Mutex<Vec<Arc<NetInterface>>> = Mutex::new(Vec::new());
+
+/// Register a network card with the stack and start its receive task.
+pub fn register_net_device(device: Arc<dyn NetworkDevice>) -> Arc<NetInterface> {
+    let (tx, rx) = channel(RX_QUEUE_LEN);
+    let mut ifaces = INTERFACES.lock();
+    let iface = Arc::new(NetInterface {
+        index: ifaces.len(),
+        device,
+        rx: tx,
+        ipv4: Mutex::new(Ipv4Config::default()),
+        rx_frames: AtomicU64::new(0),
+        rx_bytes: AtomicU64::new(0),
+        rx_dropped: AtomicU64::new(0),
+        rx_errors: AtomicU64::new(0),
+        tx_frames: AtomicU64::new(0),
+        tx_bytes: AtomicU64::new(0),
+        tx_errors: AtomicU64::new(0),
+    });
+    ifaces.push(iface.clone());
+    drop(ifaces);
+    println!("[NET] {} registered, mac {} mtu {}", iface.name(), iface.mac(), iface.mtu());
+    crate::arch::task::spawn_named("netrx", rx_loop(iface.clone(), rx));
+    iface
+}
+
+pub fn net_interface(index: usize) -> Option<Arc<NetInterface>> {
+    INTERFACES.lock().get(index).cloned()
+}
+
+pub fn net_interfaces() -> Vec<Arc<NetInterface>> {
+    INTERFACES.lock().clone()
+}
+
+async fn rx_loop(iface: Arc<NetInterface>, rx: Receiver<Vec<u8>>) {
+    while let Some(frame) = rx.recv().await {
+        crate::net::ethernet::ethernet_input(&iface, &frame);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::prelude::*;
+use crate::net::addr::MacAddr;
+use crate::net::device::{NetError, NetInterface};
+use crate::net::pcap::{capture_tap, CaptureDir};
+use alloc::vec::Vec;
+
+pub const ETH_HEADER_LEN: usize = 14;
+/// Frames shorter than this (without FCS) are padded on transmit.
+pub const ETH_MIN_FRAME: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// A parsed Ethernet II frame borrowing the receive buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Result<EthernetFrame<'a>, NetError> {
+        if frame.len() < ETH_HEADER_LEN {
+            return Err(NetError::Malformed);
+        }
+        Ok(EthernetFrame {
+            dst: MacAddr::from_bytes(&frame[0..6]),
+            src: MacAddr::from_bytes(&frame[6..12]),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[ETH_HEADER_LEN..],
+        })
+    }
+}
+
+/// Build a frame, padding it to the Ethernet minimum.
+pub fn build_frame(dst: MacAddr, src: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut f = Vec::with_capacity(core::cmp::max(ETH_HEADER_LEN + payload.len(), ETH_MIN_FRAME));
+    f.extend_from_slice(&dst.0);
+    f.extend_from_slice(&src.0);
+    f.extend_from_slice(&ethertype.to_be_bytes());
+    f.extend_from_slice(payload);
+    if f.len() < ETH_MIN_FRAME {
+        f.resize(ETH_MIN_FRAME, 0);
+    }
+    f
+}
+
+/// Frame `payload` and send it from `iface` to `dst`.
+pub fn send_ethernet(iface: &NetInterface, dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    if payload.len() > iface.mtu() {
+        return Err(NetError::TooLarge);
+    }
+    let frame = build_frame(dst, iface.mac(), ethertype, payload);
+    capture_tap(iface, CaptureDir::Tx, &frame);
+    iface.transmit(&frame)
+}
+
+/// Entry point for every received frame: filter on destination address and
+/// hand the payload to the protocol named by the ethertype.
+pub fn ethernet_input(iface: &NetInterface, frame: &[u8]) {
+    capture_tap(iface, CaptureDir::Rx, frame);
+    let eth = match EthernetFrame::parse(frame) {
+        Ok(e) => e,
+        Err(_) => return iface.note_rx_error(),
+    };
+    if eth.dst != iface.mac() && !eth.dst.is_broadcast() && !eth.dst.is_multicast() {
+        return iface.note_rx_dropped();
+    }
+    match eth.ethertype {
+        ETHERTYPE_ARP => crate::net::arp::arp_input(iface, eth.payload),
+        ETHERTYPE_IPV4 => crate::net::ipv4::ipv4_input(iface, eth.payload),
+        _ => iface.note_rx_dropped(),
+    }
+}
@@ -0,0 +1,118 @@
+use crate::prelude::*;
+use crate::arch::tsc_timer::{cycles_to_us, rdtsc};
+use crate::net::addr::Ipv4Addr;
+use crate::net::device::{NetError, NetInterface};
+use crate::net::ipv4::{internet_checksum, send_ipv4, send_ipv4_nowait, Ipv4Header, PROTO_ICMP};
+use crate::sync::WaitQueue;
+use crate::time::with_timeout_ms;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+
+const ICMP_HEADER_LEN: usize = 8;
+
+/// Build an ICMP message with its checksum filled in.
+fn build_icmp(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut m = Vec::with_capacity(ICMP_HEADER_LEN + data.len());
+    m.push(kind);
+    m.push(code);
+    m.extend_from_slice(&[0, 0]);
+    m.extend_from_slice(&rest);
+    m.extend_from_slice(data);
+    let sum = internet_checksum(&m);
+    m[2..4].copy_from_slice(&sum.to_be_bytes());
+    m
+}
+
+/// Outstanding pings: (identifier, sequence) -> TSC at send, replaced by
+/// the round trip in microseconds when the reply arrives.
+static PENDING: Mutex<BTreeMap<(u16, u16), PingState>> = Mutex::new(BTreeMap::new());
+static PING_WAIT: WaitQueue = WaitQueue::new();
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(0x4e58);
+
+#[derive(Debug, Clone, Copy)]
+enum PingState {
+    Sent(u64),
+    Answered(u64),
+}
+
+/// Handle a received ICMP message: answer echo requests and complete
+/// pending pings.
+pub fn icmp_input(iface: &NetInterface, ip: &Ipv4Header, msg: &[u8]) {
+    if msg.len() < ICMP_HEADER_LEN || internet_checksum(msg) != 0 {
+        return iface.note_rx_error();
+    }
+    let ident = u16::from_be_bytes([msg[4], msg[5]]);
+    let seq = u16::from_be_bytes([msg[6], msg[7]]);
+    match msg[0] {
+        ICMP_ECHO_REQUEST => {
+            // don't answer pings sent to broadcast addresses
+            if ip.dst != iface.ipv4().addr {
+                return;
+            }
+            let reply = build_icmp(ICMP_ECHO_REPLY, 0, [msg[4], msg[5], msg[6], msg[7]], &msg[ICMP_HEADER_LEN..]);
+            if let Err(e) = send_ipv4_nowait(ip.src, PROTO_ICMP, reply) {
+                println!("[ICMP] echo reply to {} failed: {}", ip.src, e);
+            }
+        }
+        ICMP_ECHO_REPLY => {
+            let now = rdtsc();
+            let answered = interrupts::without_interrupts(|| {
+                let mut pending = PENDING.lock();
+                match pending.get_mut(&(ident, seq)) {
+                    Some(state) => match *state {
+                        PingState::Sent(t0) => {
+                            *state = PingState::Answered(cycles_to_us(now.saturating_sub(t0)));
+                            true
+                        }
+                        PingState::Answered(_) => false,
+                    },
+                    None => false,
+                }
+            });
+            if answered {
+                PING_WAIT.wake_all();
+            }
+        }
+        ICMP_DEST_UNREACHABLE | ICMP_TIME_EXCEEDED => {
+            println!("[ICMP] type {} code {} from {}", msg[0], msg[1], ip.src);
+        }
+        _ => {}
+    }
+}
+
+/// Send one echo request to `dst` with `size` bytes of payload and wait up
+/// to `timeout_ms` for the reply. Returns the round trip in microseconds.
+pub async fn ping(dst: Ipv4Addr, seq: u16, size: usize, timeout_ms: u64) -> Result<u64, NetError> {
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    let msg = build_icmp(ICMP_ECHO_REQUEST, 0, {
+        let mut r = [0u8; 4];
+        r[0..2].copy_from_slice(&ident.to_be_bytes());
+        r[2..4].copy_from_slice(&seq.to_be_bytes());
+        r
+    }, &data);
+
+    let key = (ident, seq);
+    interrupts::without_interrupts(|| PENDING.lock().insert(key, PingState::Sent(rdtsc())));
+    let result = async {
+        send_ipv4(dst, PROTO_ICMP, &msg).await?;
+        let rtt = PING_WAIT.wait_until(|| {
+            interrupts::without_interrupts(|| match PENDING.lock().get(&key) {
+                Some(PingState::Answered(us)) => Some(*us),
+                _ => None,
+            })
+        });
+        Ok::<u64, NetError>(rtt.await)
+    };
+    let outcome = with_timeout_ms(timeout_ms, result).await;
+    interrupts::without_interrupts(|| PENDING.lock().remove(&key));
+    outcome?
+}
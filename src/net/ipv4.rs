@@ -0,0 +1,395 @@
+use crate::prelude::*;
+use crate::net::addr::{Ipv4Addr, MacAddr};
+use crate::net::arp::{arp_lookup, arp_resolve};
+use crate::net::device::{net_interfaces, NetError, NetInterface};
+use crate::net::ethernet::{send_ethernet, ETHERTYPE_IPV4};
+use crate::net::udp::Datagram;
+use crate::sync::channel::{channel, Receiver, Sender, TryRecvError};
+use crate::time::Deadline;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts;
+use spin::Mutex;
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const DEFAULT_TTL: u8 = 64;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+const FLAG_MF: u16 = 0x2000;
+const FRAG_OFFSET_MASK: u16 = 0x1FFF;
+
+/// Give up on a datagram whose fragments haven't all arrived by then.
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
+/// Datagrams being reassembled at once; the oldest is dropped beyond this.
+const MAX_REASSEMBLIES: usize = 16;
+/// Largest datagram accepted through reassembly.
+const MAX_DATAGRAM: usize = 65_535;
+/// Fragment bytes held across all datagrams being reassembled, which also
+/// caps how large one can be; the oldest are dropped to stay under it.
+/// Without it, a sender could fill the heap with partial datagrams.
+const MAX_REASSEMBLY_BYTES: usize = 32 * 1024;
+
+/// One's-complement sum of `data` folded into `sum`, for checksums that
+/// span several buffers (pseudo-headers).
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The RFC 1071 Internet checksum.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub header_len: usize,
+    pub total_len: usize,
+    pub id: u16,
+    /// Fragment offset in bytes.
+    pub frag_offset: usize,
+    pub more_fragments: bool,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+impl Ipv4Header {
+    /// Parse and validate (version, lengths, checksum) a header.
+    pub fn parse(b: &[u8]) -> Result<Ipv4Header, NetError> {
+        if b.len() < IPV4_HEADER_LEN || b[0] >> 4 != 4 {
+            return Err(NetError::Malformed);
+        }
+        let header_len = ((b[0] & 0x0F) as usize) * 4;
+        let total_len = u16::from_be_bytes([b[2], b[3]]) as usize;
+        if header_len < IPV4_HEADER_LEN || total_len < header_len || total_len > b.len() {
+            return Err(NetError::Malformed);
+        }
+        if internet_checksum(&b[..header_len]) != 0 {
+            return Err(NetError::Malformed);
+        }
+        let frag = u16::from_be_bytes([b[6], b[7]]);
+        Ok(Ipv4Header {
+            header_len,
+            total_len,
+            id: u16::from_be_bytes([b[4], b[5]]),
+            frag_offset: ((frag & FRAG_OFFSET_MASK) as usize) * 8,
+            more_fragments: frag & FLAG_MF != 0,
+            ttl: b[8],
+            protocol: b[9],
+            src: Ipv4Addr::from_bytes(&b[12..16]),
+            dst: Ipv4Addr::from_bytes(&b[16..20]),
+        })
+    }
+
+    /// Serialize an option-less header with a fresh checksum.
+    pub fn to_bytes(&self) -> [u8; IPV4_HEADER_LEN] {
+        let mut h = [0u8; IPV4_HEADER_LEN];
+        h[0] = 0x45;
+        h[2..4].copy_from_slice(&(self.total_len as u16).to_be_bytes());
+        h[4..6].copy_from_slice(&self.id.to_be_bytes());
+        let frag = (self.frag_offset / 8) as u16 | if self.more_fragments { FLAG_MF } else { 0 };
+        h[6..8].copy_from_slice(&frag.to_be_bytes());
+        h[8] = self.ttl;
+        h[9] = self.protocol;
+        h[12..16].copy_from_slice(&self.src.0);
+        h[16..20].copy_from_slice(&self.dst.0);
+        let sum = internet_checksum(&h);
+        h[10..12].copy_from_slice(&sum.to_be_bytes());
+        h
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.frag_offset != 0
+    }
+}
+
+/// A datagram being put back together from fragments.
+struct Reassembly {
+    /// (byte offset, data) in arrival order.
+    pieces: Vec<(usize, Vec<u8>)>,
+    /// Known once the last fragment (MF clear) arrives.
+    total: Option<usize>,
+    /// Bytes held in `pieces`.
+    bytes: usize,
+    expires: Deadline,
+}
+
+impl Reassembly {
+    /// The whole payload if every byte up to `total` is covered.
+    fn complete(&mut self) -> Option<Vec<u8>> {
+        let total = self.total?;
+        self.pieces.sort_by_key(|p| p.0);
+        let mut covered = 0;
+        for (off, data) in self.pieces.iter() {
+            if *off > covered {
+                return None;
+            }
+            covered = covered.max(off + data.len());
+        }
+        if covered < total {
+            return None;
+        }
+        let mut out = alloc::vec![0u8; total];
+        for (off, data) in self.pieces.iter().filter(|p| p.0 < total) {
+            let end = core::cmp::min(off + data.len(), total);
+            out[*off..end].copy_from_slice(&data[..end - off]);
+        }
+        Some(out)
+    }
+}
+
+type ReassemblyKey = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+static REASSEMBLY: Mutex<BTreeMap<ReassemblyKey, Reassembly>> = Mutex::new(BTreeMap::new());
+
+/// Add a fragment; returns the full payload once the datagram is complete.
+pub(crate) fn reassemble(h: &Ipv4Header, payload: &[u8]) -> Option<Vec<u8>> {
+    let key = (h.src, h.dst, h.protocol, h.id);
+    let mut table = REASSEMBLY.lock();
+    table.retain(|_, r| !r.expires.expired());
+    let end = h.frag_offset + payload.len();
+    if end > MAX_DATAGRAM || payload.len() > MAX_REASSEMBLY_BYTES {
+        table.remove(&key);
+        return None;
+    }
+    // make room among the other datagrams, oldest first
+    while table.values().map(|r| r.bytes).sum::<usize>() + payload.len() > MAX_REASSEMBLY_BYTES
+        || !table.contains_key(&key) && table.len() >= MAX_REASSEMBLIES
+    {
+        match table.iter().filter(|(k, _)| **k != key).min_by_key(|(_, r)| r.expires).map(|(k, _)| *k) {
+            Some(oldest) => table.remove(&oldest),
+            None => table.remove(&key),
+        };
+    }
+    let r = table.entry(key).or_insert_with(|| Reassembly {
+        pieces: Vec::new(),
+        total: None,
+        bytes: 0,
+        expires: Deadline::after_ms(REASSEMBLY_TIMEOUT_MS),
+    });
+    // a fragment past the end, or a second, different end, can only be
+    // a broken or hostile sender
+    let total = if h.more_fragments { r.total } else { Some(end) };
+    let received = r.pieces.iter().map(|(off, data)| off + data.len()).max().unwrap_or(0);
+    if let Some(total) = total
+        && (end > total || received > total || r.total.is_some_and(|t| t != total))
+    {
+        table.remove(&key);
+        return None;
+    }
+    r.total = total;
+    r.bytes += payload.len();
+    r.pieces.push((h.frag_offset, payload.to_vec()));
+    let done = r.complete();
+    if done.is_some() {
+        table.remove(&key);
+    }
+    done
+}
+
+/// Handle a received IPv4 packet.
+pub fn ipv4_input(iface: &NetInterface, packet: &[u8]) {
+    let h = match Ipv4Header::parse(packet) {
+        Ok(h) => h,
+        Err(_) => return iface.note_rx_error(),
+    };
+    let cfg = iface.ipv4();
+    // An unconfigured interface takes everything, so address configuration
+    // protocols can run before it has an address.
+    let for_us = cfg.addr.is_unspecified()
+        || h.dst == cfg.addr
+        || h.dst.is_broadcast()
+        || h.dst == cfg.subnet_broadcast()
+        || h.dst.is_multicast();
+    if !for_us {
+        return iface.note_rx_dropped();
+    }
+    let payload = &packet[h.header_len..h.total_len];
+    if h.is_fragment() {
+        if let Some(full) = reassemble(&h, payload) {
+            deliver(iface, &h, &full);
+        }
+    } else {
+        deliver(iface, &h, payload);
+    }
+}
+
+fn deliver(iface: &NetInterface, h: &Ipv4Header, payload: &[u8]) {
+    raw_input(h, payload);
+    match h.protocol {
+        PROTO_ICMP => crate::net::icmp::icmp_input(iface, h, payload),
+        PROTO_UDP => crate::net::udp::udp_input(iface, h, payload),
+        _ => iface.note_rx_dropped(),
+    }
+}
+
+/// Pick the interface and next hop for `dst`: a directly attached subnet
+/// first, then the first interface with a gateway.
+pub fn route(dst: Ipv4Addr) -> Result<(Arc<NetInterface>, Ipv4Addr), NetError> {
+    let ifaces = net_interfaces();
+    if dst.is_broadcast() {
+        return ifaces.into_iter().next().map(|i| (i, dst)).ok_or(NetError::NoRoute);
+    }
+    if let Some(i) = ifaces.iter().find(|i| i.ipv4().is_local(dst)) {
+        return Ok((i.clone(), dst));
+    }
+    ifaces
+        .into_iter()
+        .find(|i| !i.ipv4().gateway.is_unspecified())
+        .map(|i| {
+            let gw = i.ipv4().gateway;
+            (i, gw)
+        })
+        .ok_or(NetError::NoRoute)
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Wrap `payload` in IPv4 headers, fragmenting to the interface MTU, and
+/// send every piece to `next_mac`.
+fn transmit(iface: &NetInterface, next_mac: MacAddr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    if IPV4_HEADER_LEN + payload.len() > MAX_DATAGRAM {
+        return Err(NetError::TooLarge);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // fragment payloads must be multiples of 8 bytes, except the last
+    let max_frag = (iface.mtu() - IPV4_HEADER_LEN) & !7;
+    let mut offset = 0;
+    loop {
+        let len = core::cmp::min(max_frag, payload.len() - offset);
+        let more = offset + len < payload.len();
+        let h = Ipv4Header {
+            header_len: IPV4_HEADER_LEN,
+            total_len: IPV4_HEADER_LEN + len,
+            id,
+            frag_offset: offset,
+            more_fragments: more,
+            ttl: DEFAULT_TTL,
+            protocol,
+            src: iface.ipv4().addr,
+            dst,
+        };
+        let mut packet = Vec::with_capacity(IPV4_HEADER_LEN + len);
+        packet.extend_from_slice(&h.to_bytes());
+        packet.extend_from_slice(&payload[offset..offset + len]);
+        send_ethernet(iface, next_mac, ETHERTYPE_IPV4, &packet)?;
+        offset += len;
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+/// Send `payload` to `dst`, resolving the next hop with ARP.
+pub async fn send_ipv4(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let (iface, hop) = route(dst)?;
+    let mac = arp_resolve(&iface, hop).await?;
+    transmit(&iface, mac, dst, protocol, payload)
+}
+
+/// Send without waiting: uses the ARP cache, and when the next hop isn't
+/// cached hands the packet to a task that resolves it. For replies sent
+/// from the receive path.
+pub fn send_ipv4_nowait(dst: Ipv4Addr, protocol: u8, payload: Vec<u8>) -> Result<(), NetError> {
+    let (iface, hop) = route(dst)?;
+    let cached = if hop.is_broadcast() || hop == iface.ipv4().subnet_broadcast() {
+        Some(MacAddr::BROADCAST)
+    } else {
+        arp_lookup(&iface, hop)
+    };
+    match cached {
+        Some(mac) => transmit(&iface, mac, dst, protocol, &payload),
+        None => {
+            crate::arch::task::spawn_named("ipv4tx", async move {
+                if let Err(e) = send_ipv4(dst, protocol, &payload).await {
+                    kwarn_once!("[IPv4] deferred send to {} failed: {}", dst, e);
+                }
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Packets buffered per raw socket.
+const RAW_QUEUE_LEN: usize = 32;
+
+/// Raw sockets: (registration id, protocol, channel).
+static RAW_SOCKETS: Mutex<Vec<(u64, u8, Sender<Datagram>)>> = Mutex::new(Vec::new());
+static NEXT_RAW_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A copy of every received payload of one protocol. Unregisters on drop.
+pub struct RawRegistration {
+    id: u64,
+    rx: Receiver<Datagram>,
+}
+
+/// Start receiving copies of `protocol` payloads; the source port of each
+/// datagram is 0.
+pub fn raw_bind(protocol: u8) -> RawRegistration {
+    let (tx, rx) = channel(RAW_QUEUE_LEN);
+    let id = NEXT_RAW_ID.fetch_add(1, Ordering::Relaxed);
+    interrupts::without_interrupts(|| RAW_SOCKETS.lock().push((id, protocol, tx)));
+    RawRegistration { id, rx }
+}
+
+impl RawRegistration {
+    pub fn poll_recv(&self, cx: &mut Context) -> Poll<Result<Datagram, NetError>> {
+        self.rx.poll_recv(cx).map(|d| d.ok_or(NetError::NoDevice))
+    }
+
+    pub fn try_recv(&self) -> Option<Datagram> {
+        match self.rx.try_recv() {
+            Ok(d) => Some(d),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => None,
+        }
+    }
+}
+
+impl Drop for RawRegistration {
+    fn drop(&mut self) {
+        let id = self.id;
+        interrupts::without_interrupts(|| RAW_SOCKETS.lock().retain(|r| r.0 != id));
+    }
+}
+
+/// (protocol, queued packets) for every raw socket.
+pub fn raw_sockets() -> Vec<(u8, usize)> {
+    interrupts::without_interrupts(|| RAW_SOCKETS.lock().iter().map(|(_, p, tx)| (*p, tx.len())).collect())
+}
+
+/// Datagrams waiting for their remaining fragments.
+pub fn reassembly_pending() -> usize {
+    REASSEMBLY.lock().len()
+}
+
+fn raw_input(h: &Ipv4Header, payload: &[u8]) {
+    interrupts::without_interrupts(|| {
+        for (_, proto, tx) in RAW_SOCKETS.lock().iter() {
+            if *proto == h.protocol {
+                // a full raw queue just misses packets
+                let _ = tx.try_send((payload.to_vec(), h.src, 0));
+            }
+        }
+    });
+}
@@ -0,0 +1,29 @@
+//! Network stack
+//!
+//! Drivers implement `NetworkDevice` and register with the stack, which
+//! gives each card a `NetInterface`. Received frames flow through the
+//! interface's receive channel into `ethernet_input` and on to the protocol
+//! handlers.
+
+pub mod addr;
+pub use addr::*;
+pub mod device;
+pub use device::*;
+pub mod ethernet;
+pub use ethernet::*;
+pub mod arp;
+pub use arp::*;
+pub mod ipv4;
+pub use ipv4::*;
+pub mod icmp;
+pub use icmp::*;
+pub mod udp;
+pub use udp::*;
+pub mod sntp;
+pub use sntp::*;
+pub mod socket;
+pub use socket::*;
+pub mod pcap;
+pub use pcap::*;
+pub mod netstat;
+pub use netstat::*;
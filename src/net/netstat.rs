@@ -0,0 +1,79 @@
+//! Network counters in one snapshot, for the shell and for spotting driver
+//! ring bugs and socket leaks at runtime.
+
+use crate::prelude::*;
+use crate::net::arp::arp_entries;
+use crate::net::addr::MacAddr;
+use crate::net::device::{net_interfaces, Ipv4Config, NetStats};
+use crate::net::ipv4::{raw_sockets, reassembly_pending};
+use crate::net::pcap::{capture_counts, capture_enabled};
+use crate::net::udp::{udp_sockets, UdpSocketInfo};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone)]
+pub struct InterfaceStats {
+    pub index: usize,
+    pub name: String,
+    pub mac: MacAddr,
+    pub mtu: usize,
+    pub ipv4: Ipv4Config,
+    pub counters: NetStats,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetSnapshot {
+    pub interfaces: Vec<InterfaceStats>,
+    pub udp: Vec<UdpSocketInfo>,
+    /// (protocol, queued packets) per raw socket.
+    pub raw: Vec<(u8, usize)>,
+    pub arp_entries: usize,
+    pub reassembly_pending: usize,
+}
+
+/// Counters for every interface and socket.
+pub fn stats() -> NetSnapshot {
+    NetSnapshot {
+        interfaces: net_interfaces()
+            .iter()
+            .map(|i| InterfaceStats {
+                index: i.index(),
+                name: String::from(i.name()),
+                mac: i.mac(),
+                mtu: i.mtu(),
+                ipv4: i.ipv4(),
+                counters: i.stats(),
+            })
+            .collect(),
+        udp: udp_sockets(),
+        raw: raw_sockets(),
+        arp_entries: arp_entries().len(),
+        reassembly_pending: reassembly_pending(),
+    }
+}
+
+/// Print `stats()` to the console in netstat style.
+pub fn print_netstat() {
+    let st = stats();
+    for i in st.interfaces.iter() {
+        let c = &i.counters;
+        println!("{}: mac {} mtu {} inet {}/{} gw {}", i.name, i.mac, i.mtu, i.ipv4.addr, i.ipv4.netmask, i.ipv4.gateway);
+        println!("    rx {} pkts {} bytes, {} dropped, {} errors", c.rx_frames, c.rx_bytes, c.rx_dropped, c.rx_errors);
+        println!("    tx {} pkts {} bytes, {} errors", c.tx_frames, c.tx_bytes, c.tx_errors);
+    }
+    if st.interfaces.is_empty() {
+        println!("no network interfaces");
+    }
+    println!("Proto  Local      Queued  Rx      Dropped  Tx");
+    for u in st.udp.iter() {
+        println!("udp    *:{:<8} {:<7} {:<7} {:<8} {}", u.port, u.queued, u.rx, u.rx_dropped, u.tx);
+    }
+    for (proto, queued) in st.raw.iter() {
+        println!("raw    proto {:<4} {:<7}", proto, queued);
+    }
+    println!("arp cache {} entries, {} datagrams in reassembly", st.arp_entries, st.reassembly_pending);
+    if capture_enabled() {
+        let (held, dropped) = capture_counts();
+        println!("capture on: {} frames held, {} overwritten", held, dropped);
+    }
+}
@@ -0,0 +1,222 @@
+//! Packet capture
+//!
+//! When enabled, the Ethernet layer mirrors every received and sent frame
+//! into a bounded ring with a timestamp, truncated to the snap length. The
+//! ring can be written out in pcap format to a file or, hex-encoded between
+//! marker lines, to the serial port (`xxd -r -p` turns it back into a file
+//! Wireshark opens).
+//!
+//! The ring is static, `CAPTURE_SLOTS` frames of up to `MAX_SNAPLEN` bytes,
+//! so capturing takes no heap memory, and the export streams it out a frame
+//! at a time rather than copying it first.
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::{cycles_to_us, rdtsc};
+use crate::net::device::NetInterface;
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Most bytes kept of a frame.
+pub const MAX_SNAPLEN: usize = 256;
+pub const DEFAULT_SNAPLEN: usize = MAX_SNAPLEN;
+/// Frames the ring has room for.
+pub const CAPTURE_SLOTS: usize = 128;
+pub const DEFAULT_CAPTURE_FRAMES: usize = CAPTURE_SLOTS;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDir {
+    Rx,
+    Tx,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CapturedFrame {
+    /// Microseconds since the TSC started counting.
+    pub timestamp_us: u64,
+    pub iface: usize,
+    pub dir: CaptureDir,
+    /// Length on the wire; `data()` may be shorter.
+    pub orig_len: usize,
+    len: usize,
+    bytes: [u8; MAX_SNAPLEN],
+}
+
+impl CapturedFrame {
+    const EMPTY: CapturedFrame = CapturedFrame { timestamp_us: 0, iface: 0, dir: CaptureDir::Rx, orig_len: 0, len: 0, bytes: [0; MAX_SNAPLEN] };
+
+    /// The bytes kept, at most the snap length.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+struct Capture {
+    frames: [CapturedFrame; CAPTURE_SLOTS],
+    /// Frames captured since the start; frame n is in slot n % max_frames.
+    captured: u64,
+    snaplen: usize,
+    max_frames: usize,
+    /// Only capture on this interface, if set.
+    iface: Option<usize>,
+}
+
+impl Capture {
+    /// Numbers of the frames the ring still holds, oldest first.
+    fn held(&self) -> core::ops::Range<u64> {
+        self.captured.saturating_sub(self.max_frames as u64)..self.captured
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture {
+    frames: [CapturedFrame::EMPTY; CAPTURE_SLOTS],
+    captured: 0,
+    snaplen: DEFAULT_SNAPLEN,
+    max_frames: DEFAULT_CAPTURE_FRAMES,
+    iface: None,
+});
+
+/// Start capturing into an empty ring of `max_frames` frames, keeping at
+/// most `snaplen` bytes of each. `iface` limits capture to one interface.
+/// Both limits are capped at what the ring has room for.
+pub fn capture_start(snaplen: usize, max_frames: usize, iface: Option<usize>) {
+    interrupts::without_interrupts(|| {
+        let mut c = CAPTURE.lock();
+        c.captured = 0;
+        c.snaplen = snaplen.clamp(14, MAX_SNAPLEN);
+        c.max_frames = max_frames.clamp(1, CAPTURE_SLOTS);
+        c.iface = iface;
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop capturing; the ring keeps its contents until the next start.
+pub fn capture_stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn capture_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Frames currently held, and frames overwritten since capture started.
+pub fn capture_counts() -> (usize, u64) {
+    interrupts::without_interrupts(|| {
+        let c = CAPTURE.lock();
+        let held = c.held();
+        ((held.end - held.start) as usize, held.start)
+    })
+}
+
+/// Tap point called by the Ethernet layer for every frame.
+pub fn capture_tap(iface: &NetInterface, dir: CaptureDir, frame: &[u8]) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let timestamp_us = cycles_to_us(rdtsc());
+    interrupts::without_interrupts(|| {
+        let mut c = CAPTURE.lock();
+        if c.iface.is_some_and(|i| i != iface.index()) {
+            return;
+        }
+        let keep = core::cmp::min(frame.len(), c.snaplen);
+        let slot = (c.captured % c.max_frames as u64) as usize;
+        let f = &mut c.frames[slot];
+        f.timestamp_us = timestamp_us;
+        f.iface = iface.index();
+        f.dir = dir;
+        f.orig_len = frame.len();
+        f.len = keep;
+        f.bytes[..keep].copy_from_slice(&frame[..keep]);
+        c.captured += 1;
+    });
+}
+
+/// Frame `n`, counting from the start of the capture, if the ring still
+/// holds it.
+fn captured_frame(n: u64) -> Option<CapturedFrame> {
+    interrupts::without_interrupts(|| {
+        let c = CAPTURE.lock();
+        c.held().contains(&n).then(|| c.frames[(n % c.max_frames as u64) as usize])
+    })
+}
+
+/// Pass the capture ring, as a pcap file, to `out` a piece at a time.
+/// Frames overwritten while this runs are left out. Returns the bytes
+/// written.
+pub fn write_pcap<E>(mut out: impl FnMut(&[u8]) -> Result<(), E>) -> Result<usize, E> {
+    let (held, snaplen) = interrupts::without_interrupts(|| {
+        let c = CAPTURE.lock();
+        (c.held(), c.snaplen)
+    });
+    let mut header = [0u8; 24];
+    header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // thiszone and sigfigs stay 0
+    header[16..20].copy_from_slice(&(snaplen as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    out(&header)?;
+    let mut written = header.len();
+    for n in held {
+        let Some(f) = captured_frame(n) else { continue };
+        let mut record = [0u8; 16];
+        record[0..4].copy_from_slice(&((f.timestamp_us / 1_000_000) as u32).to_le_bytes());
+        record[4..8].copy_from_slice(&((f.timestamp_us % 1_000_000) as u32).to_le_bytes());
+        record[8..12].copy_from_slice(&(f.len as u32).to_le_bytes());
+        record[12..16].copy_from_slice(&(f.orig_len as u32).to_le_bytes());
+        out(&record)?;
+        out(f.data())?;
+        written += record.len() + f.len;
+    }
+    Ok(written)
+}
+
+/// Write the capture to `path` as a pcap file.
+pub fn save_pcap(path: &str) -> Result<usize, crate::fs::vfs::VfsError> {
+    use crate::fs::vfs::{self, OpenOptions, VfsError};
+    let mut file = vfs::open(path, OpenOptions::write().truncate())?;
+    write_pcap(|mut bytes| {
+        while !bytes.is_empty() {
+            match file.write(bytes)? {
+                0 => return Err(VfsError::NoSpace),
+                n => bytes = &bytes[n..],
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Hex-dump the capture to COM1 between BEGIN/END marker lines.
+pub fn dump_pcap_serial() -> usize {
+    use crate::devices::serial::{serial_write, serial_write_str};
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    // 32 bytes to a line
+    let mut line = [0u8; 65];
+    let mut used = 0;
+    serial_write_str("\n-----BEGIN PCAP-----\n");
+    let Ok(written) = write_pcap(|bytes| {
+        for &b in bytes {
+            line[used] = HEX[(b >> 4) as usize];
+            line[used + 1] = HEX[(b & 0xF) as usize];
+            used += 2;
+            if used == 64 {
+                line[64] = b'\n';
+                serial_write(&line);
+                used = 0;
+            }
+        }
+        Ok::<(), Infallible>(())
+    });
+    if used > 0 {
+        line[used] = b'\n';
+        serial_write(&line[..used + 1]);
+    }
+    serial_write_str("-----END PCAP-----\n");
+    written
+}
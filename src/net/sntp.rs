@@ -0,0 +1,239 @@
+//! SNTP client
+//!
+//! The `sntp` task asks one server (`ntp=ADDR` on the command line, or
+//! `ntp server ADDR` in the shell) for the time at boot and then every
+//! `SNTP_INTERVAL_S`, and steps the wall clock by the measured offset. The
+//! offset found at each sync after the first, divided by the time since the
+//! last one, is how fast the RTC-derived clock drifts. Without DNS the
+//! server is an address; the default is time.cloudflare.com, which QEMU's
+//! user-mode network reaches through the host. `ntp=off` disables it.
+
+use crate::prelude::*;
+use crate::net::addr::Ipv4Addr;
+use crate::net::device::NetError;
+use crate::net::udp::UdpSocket;
+use crate::sync::WaitQueue;
+use crate::time::{sleep_ms, unix_time_ns, uptime_ns, with_timeout_ms};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const SNTP_PORT: u16 = 123;
+/// time.cloudflare.com.
+pub const SNTP_DEFAULT_SERVER: Ipv4Addr = Ipv4Addr::new(162, 159, 200, 1);
+/// Seconds between syncs.
+pub const SNTP_INTERVAL_S: u64 = 1024;
+const SNTP_TIMEOUT_MS: u64 = 2000;
+/// Seconds between retries while the server can't be reached.
+const SNTP_RETRY_S: u64 = 30;
+
+const SNTP_PACKET_LEN: usize = 48;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const VERSION: u8 = 4;
+/// Leap indicator for a server whose clock isn't synchronised.
+const LI_ALARM: u8 = 3;
+/// Seconds from the NTP era (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Convert a 64-bit NTP timestamp (era 0) to Unix nanoseconds.
+pub fn ntp_to_unix_ns(ntp: u64) -> u64 {
+    let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let frac = ((ntp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+    secs * 1_000_000_000 + frac
+}
+
+pub fn unix_ns_to_ntp(ns: u64) -> u64 {
+    let secs = ns / 1_000_000_000 + NTP_UNIX_OFFSET;
+    let frac = ((ns % 1_000_000_000) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+/// One exchange with a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SntpSample {
+    /// How far the server's clock is ahead of ours.
+    pub offset_ns: i64,
+    /// Round trip, less the time the server held the request.
+    pub delay_ns: u64,
+    pub stratum: u8,
+}
+
+impl SntpSample {
+    /// Work out offset and delay from the four timestamps of an exchange:
+    /// `t1` sent, `t2` received by the server, `t3` sent by the server,
+    /// `t4` received, all in Unix nanoseconds.
+    pub fn from_timestamps(t1: u64, t2: u64, t3: u64, t4: u64, stratum: u8) -> SntpSample {
+        let (t1, t2, t3, t4) = (t1 as i128, t2 as i128, t3 as i128, t4 as i128);
+        SntpSample {
+            offset_ns: (((t2 - t1) + (t3 - t4)) / 2) as i64,
+            delay_ns: ((t4 - t1) - (t3 - t2)).max(0) as u64,
+            stratum,
+        }
+    }
+}
+
+fn ntp_field(packet: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(packet[at..at + 8].try_into().unwrap())
+}
+
+/// Ask `server` for the time once.
+pub async fn sntp_query(server: Ipv4Addr) -> Result<SntpSample, NetError> {
+    let socket = UdpSocket::bind(0)?;
+    let mut request = [0u8; SNTP_PACKET_LEN];
+    request[0] = VERSION << 3 | MODE_CLIENT;
+    let t1 = unix_time_ns();
+    // the server echoes this back as the originate timestamp
+    let origin = unix_ns_to_ntp(t1);
+    request[40..48].copy_from_slice(&origin.to_be_bytes());
+    socket.send_to(&request, server, SNTP_PORT).await?;
+    let reply = async {
+        loop {
+            let (packet, from, port) = socket.recv_from().await?;
+            let t4 = unix_time_ns();
+            if from == server && port == SNTP_PORT && packet.len() >= SNTP_PACKET_LEN && ntp_field(&packet, 24) == origin {
+                return Ok::<_, NetError>((packet, t4));
+            }
+        }
+    };
+    let (packet, t4) = with_timeout_ms(SNTP_TIMEOUT_MS, reply).await??;
+    let stratum = packet[1];
+    // stratum 0 is a "kiss-o'-death": the server wants us to go away
+    if packet[0] & 7 != MODE_SERVER || packet[0] >> 6 == LI_ALARM || stratum == 0 || ntp_field(&packet, 40) == 0 {
+        return Err(NetError::Malformed);
+    }
+    let t2 = ntp_to_unix_ns(ntp_field(&packet, 32));
+    let t3 = ntp_to_unix_ns(ntp_field(&packet, 40));
+    Ok(SntpSample::from_timestamps(t1, t2, t3, t4, stratum))
+}
+
+/// What the last syncs found.
+#[derive(Debug, Clone, Copy)]
+pub struct SntpStatus {
+    pub server: Ipv4Addr,
+    pub enabled: bool,
+    pub syncs: u64,
+    pub failures: u64,
+    /// Uptime at the last successful sync, 0 before the first.
+    pub last_sync_ns: u64,
+    pub last: Option<SntpSample>,
+    /// Drift of the local clock against the server, in parts per billion
+    /// (positive means ours is slow); known after the second sync.
+    pub drift_ppb: Option<i64>,
+}
+
+static STATUS: Mutex<SntpStatus> = Mutex::new(SntpStatus {
+    server: SNTP_DEFAULT_SERVER,
+    enabled: true,
+    syncs: 0,
+    failures: 0,
+    last_sync_ns: 0,
+    last: None,
+    drift_ppb: None,
+});
+static SYNC_WAIT: WaitQueue = WaitQueue::new();
+static SYNC_NOW: AtomicBool = AtomicBool::new(false);
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn with_status<R>(f: impl FnOnce(&mut SntpStatus) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut STATUS.lock()))
+}
+
+pub fn sntp_status() -> SntpStatus {
+    with_status(|s| *s)
+}
+
+pub fn set_sntp_server(server: Ipv4Addr) {
+    with_status(|s| s.server = server);
+}
+
+/// Apply `ntp=ADDR|off`.
+pub fn configure_sntp_from_cmdline(cmdline: &str) {
+    let Some(value) = cmdline.split_whitespace().find_map(|w| w.strip_prefix("ntp=")) else { return };
+    if value == "off" {
+        with_status(|s| s.enabled = false);
+        return;
+    }
+    match Ipv4Addr::parse(value) {
+        Some(server) => set_sntp_server(server),
+        None => println!("[SNTP] bad ntp={} (want an IPv4 address or off)", value),
+    }
+}
+
+/// Wake the `sntp` task to sync now rather than at its next interval.
+pub fn sntp_sync_now() {
+    SYNC_NOW.store(true, Ordering::Relaxed);
+    SYNC_WAIT.wake_one();
+}
+
+/// Query the server and step the wall clock. Returns whether it worked.
+async fn sync_once() -> bool {
+    let server = with_status(|s| s.server);
+    let sample = match sntp_query(server).await {
+        Ok(sample) => sample,
+        Err(e) => {
+            let first = with_status(|s| {
+                s.failures += 1;
+                s.failures == 1
+            });
+            // an unreachable server would otherwise log every retry
+            if first || e != NetError::NoRoute {
+                println!("[SNTP] {}: {}", server, e);
+            }
+            return false;
+        }
+    };
+    crate::time::adjust_wall_clock(sample.offset_ns);
+    let now = uptime_ns();
+    let drift = with_status(|s| {
+        if s.syncs > 0 {
+            let elapsed = now.saturating_sub(s.last_sync_ns).max(1) as i128;
+            s.drift_ppb = Some((sample.offset_ns as i128 * 1_000_000_000 / elapsed) as i64);
+        }
+        s.syncs += 1;
+        s.last_sync_ns = now;
+        s.last = Some(sample);
+        s.drift_ppb
+    });
+    match drift {
+        Some(ppb) => println!(
+            "[SNTP] {} stratum {}: offset {} us, delay {} us, drift {}{}.{:03} ppm",
+            server,
+            sample.stratum,
+            sample.offset_ns / 1000,
+            sample.delay_ns / 1000,
+            if ppb < 0 { "-" } else { "" },
+            ppb.unsigned_abs() / 1000,
+            ppb.unsigned_abs() % 1000
+        ),
+        None => println!(
+            "[SNTP] {} stratum {}: offset {} us, delay {} us; wall clock now {} UTC",
+            server,
+            sample.stratum,
+            sample.offset_ns / 1000,
+            sample.delay_ns / 1000,
+            crate::time::now()
+        ),
+    }
+    true
+}
+
+async fn sntp_task() {
+    loop {
+        let wait_s = if sync_once().await { SNTP_INTERVAL_S } else { SNTP_RETRY_S };
+        let woken = SYNC_WAIT.wait_until(|| SYNC_NOW.swap(false, Ordering::Relaxed).then_some(()));
+        let _ = with_timeout_ms(wait_s * 1000, woken).await;
+    }
+}
+
+/// Start the `sntp` task unless `ntp=off`. It first waits a moment for
+/// interfaces to come up.
+pub fn start_sntp() {
+    if !sntp_status().enabled || STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    crate::arch::task::spawn_named("sntp", async {
+        sleep_ms(500).await;
+        sntp_task().await
+    });
+}
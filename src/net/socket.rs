@@ -0,0 +1,177 @@
+//! One handle type for every kind of socket
+//!
+//! `Socket` wraps the protocol sockets behind BSD-like operations (bind,
+//! connect, send_to, recv_from) so descriptor tables and the syscall layer
+//! don't need to know which protocol is underneath. Stream sockets report
+//! `Unsupported` until there is a TCP implementation.
+
+use crate::prelude::*;
+use crate::net::addr::Ipv4Addr;
+use crate::net::device::NetError;
+use crate::net::ipv4::{raw_bind, send_ipv4, RawRegistration};
+use crate::net::udp::{udp_send, Datagram, UdpSocket};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    Stream,
+    Datagram,
+    /// IPv4 payloads of one protocol number, headers stripped.
+    Raw(u8),
+}
+
+enum Inner {
+    /// A datagram socket before its first bind or send.
+    UnboundUdp,
+    Udp(UdpSocket),
+    Raw { protocol: u8, reg: RawRegistration },
+}
+
+pub struct Socket {
+    kind: SocketType,
+    inner: Mutex<Inner>,
+    /// Default destination set by `connect`; received datagrams from
+    /// anyone else are discarded.
+    peer: Mutex<Option<(Ipv4Addr, u16)>>,
+}
+
+impl Socket {
+    pub fn new(kind: SocketType) -> Result<Socket, NetError> {
+        let inner = match kind {
+            SocketType::Stream => return Err(NetError::Unsupported),
+            SocketType::Datagram => Inner::UnboundUdp,
+            SocketType::Raw(protocol) => Inner::Raw { protocol, reg: raw_bind(protocol) },
+        };
+        Ok(Socket { kind, inner: Mutex::new(inner), peer: Mutex::new(None) })
+    }
+
+    pub fn kind(&self) -> SocketType {
+        self.kind
+    }
+
+    /// Bind to a local port (0 picks an ephemeral one). Raw sockets have
+    /// no ports.
+    pub fn bind(&self, port: u16) -> Result<(), NetError> {
+        let mut inner = self.inner.lock();
+        match *inner {
+            Inner::UnboundUdp => {
+                *inner = Inner::Udp(UdpSocket::bind(port)?);
+                Ok(())
+            }
+            Inner::Udp(_) => Err(NetError::AddrInUse),
+            Inner::Raw { .. } => Err(NetError::Unsupported),
+        }
+    }
+
+    /// The bound local port, if any.
+    pub fn local_port(&self) -> Option<u16> {
+        match &*self.inner.lock() {
+            Inner::Udp(s) => Some(s.local_port()),
+            _ => None,
+        }
+    }
+
+    /// Bind an unbound datagram socket to an ephemeral port.
+    fn ensure_bound(&self) -> Result<(), NetError> {
+        let mut inner = self.inner.lock();
+        if let Inner::UnboundUdp = *inner {
+            *inner = Inner::Udp(UdpSocket::bind(0)?);
+        }
+        Ok(())
+    }
+
+    /// Set the default destination for `send` and filter received data to it.
+    pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        self.ensure_bound()?;
+        *self.peer.lock() = Some((addr, port));
+        Ok(())
+    }
+
+    pub fn peer(&self) -> Option<(Ipv4Addr, u16)> {
+        *self.peer.lock()
+    }
+
+    pub async fn send_to(&self, data: &[u8], addr: Ipv4Addr, port: u16) -> Result<usize, NetError> {
+        self.ensure_bound()?;
+        // copy out what the send needs; the lock isn't held across the await
+        let target = match &*self.inner.lock() {
+            Inner::Raw { protocol, .. } => Err(*protocol),
+            Inner::Udp(s) => Ok(s.local_port()),
+            Inner::UnboundUdp => return Err(NetError::NotConnected),
+        };
+        match target {
+            Ok(local) => udp_send(local, data, addr, port).await?,
+            Err(protocol) => send_ipv4(addr, protocol, data).await?,
+        }
+        Ok(data.len())
+    }
+
+    /// Send to the connected peer.
+    pub async fn send(&self, data: &[u8]) -> Result<usize, NetError> {
+        let (addr, port) = self.peer().ok_or(NetError::NotConnected)?;
+        self.send_to(data, addr, port).await
+    }
+
+    /// Wait for the next datagram (or raw packet, with port 0).
+    pub async fn recv_from(&self) -> Result<Datagram, NetError> {
+        loop {
+            let next = core::future::poll_fn(|cx| {
+                let inner = self.inner.lock();
+                match &*inner {
+                    Inner::Udp(s) => s.poll_recv_from(cx),
+                    Inner::Raw { reg, .. } => reg.poll_recv(cx),
+                    Inner::UnboundUdp => core::task::Poll::Ready(Err(NetError::NotConnected)),
+                }
+            })
+            .await?;
+            if self.accepts(&next) {
+                return Ok(next);
+            }
+        }
+    }
+
+    /// Take a queued datagram without waiting.
+    pub fn try_recv_from(&self) -> Result<Datagram, NetError> {
+        loop {
+            let next = match &*self.inner.lock() {
+                Inner::Udp(s) => s.try_recv_from(),
+                Inner::Raw { reg, .. } => reg.try_recv(),
+                Inner::UnboundUdp => return Err(NetError::NotConnected),
+            };
+            match next {
+                Some(d) if self.accepts(&d) => return Ok(d),
+                Some(_) => continue,
+                None => return Err(NetError::WouldBlock),
+            }
+        }
+    }
+
+    fn accepts(&self, d: &Datagram) -> bool {
+        match self.peer() {
+            Some((addr, port)) => d.1 == addr && (port == 0 || d.2 == port),
+            None => true,
+        }
+    }
+
+    /// Short description for diagnostics ("udp *:5353").
+    pub fn describe(&self) -> alloc::string::String {
+        match &*self.inner.lock() {
+            Inner::UnboundUdp => alloc::string::String::from("udp (unbound)"),
+            Inner::Udp(s) => alloc::format!("udp *:{}", s.local_port()),
+            Inner::Raw { protocol, .. } => alloc::format!("raw proto {}", protocol),
+        }
+    }
+}
+
+impl From<NetError> for crate::fs::vfs::VfsError {
+    fn from(e: NetError) -> Self {
+        use crate::fs::vfs::VfsError;
+        match e {
+            NetError::WouldBlock => VfsError::WouldBlock,
+            NetError::Unsupported => VfsError::Unsupported,
+            NetError::TooLarge => VfsError::NoSpace,
+            NetError::AddrInUse => VfsError::Exists,
+            _ => VfsError::Io,
+        }
+    }
+}
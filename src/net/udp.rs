@@ -0,0 +1,206 @@
+use crate::prelude::*;
+use crate::net::addr::Ipv4Addr;
+use crate::net::device::{NetError, NetInterface};
+use crate::net::ipv4::{checksum_add, checksum_finish, route, send_ipv4, Ipv4Header, PROTO_UDP};
+use crate::sync::channel::{channel, Receiver, Sender, TryRecvError, TrySendError};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const UDP_HEADER_LEN: usize = 8;
+/// Datagrams buffered per socket before new ones are dropped.
+pub const UDP_QUEUE_LEN: usize = 64;
+
+const EPHEMERAL_FIRST: u16 = 49152;
+
+/// A received datagram: payload, source address and source port.
+pub type Datagram = (Vec<u8>, Ipv4Addr, u16);
+
+/// Per-socket traffic counters.
+#[derive(Default)]
+struct UdpCounters {
+    rx: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx: AtomicU64,
+}
+
+struct PortEntry {
+    tx: Sender<Datagram>,
+    counters: Arc<UdpCounters>,
+}
+
+/// Bound ports and the channel feeding each socket.
+static SOCKETS: Mutex<BTreeMap<u16, PortEntry>> = Mutex::new(BTreeMap::new());
+
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut sum = checksum_add(0, &src.0);
+    sum = checksum_add(sum, &dst.0);
+    sum += PROTO_UDP as u32;
+    sum += segment.len() as u32;
+    checksum_finish(checksum_add(sum, segment))
+}
+
+/// A UDP socket bound to a local port. Dropping it frees the port.
+pub struct UdpSocket {
+    port: u16,
+    rx: Receiver<Datagram>,
+    counters: Arc<UdpCounters>,
+}
+
+impl UdpSocket {
+    /// Bind `port`, or a free ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let (tx, rx) = channel(UDP_QUEUE_LEN);
+        let counters = Arc::new(UdpCounters::default());
+        // a random starting point makes the port hard to guess (RFC 6056)
+        let start = if port == 0 { crate::random::u64() } else { 0 };
+        let port = interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let port = if port == 0 {
+                let span = (u16::MAX - EPHEMERAL_FIRST) as u32 + 1;
+                let first = (start % span as u64) as u32;
+                (0..span)
+                    .map(|i| EPHEMERAL_FIRST + ((first + i) % span) as u16)
+                    .find(|p| !sockets.contains_key(p))
+                    .ok_or(NetError::AddrInUse)?
+            } else if sockets.contains_key(&port) {
+                return Err(NetError::AddrInUse);
+            } else {
+                port
+            };
+            sockets.insert(port, PortEntry { tx, counters: counters.clone() });
+            Ok(port)
+        })?;
+        Ok(UdpSocket { port, rx, counters })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `data` to `dst:port`.
+    pub async fn send_to(&self, data: &[u8], dst: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        udp_send(self.port, data, dst, port).await?;
+        self.counters.tx.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Wait for the next datagram.
+    pub async fn recv_from(&self) -> Result<Datagram, NetError> {
+        // the sender half lives in SOCKETS until we drop, so this never closes
+        self.rx.recv().await.ok_or(NetError::NoDevice)
+    }
+
+    pub fn poll_recv_from(&self, cx: &mut Context) -> Poll<Result<Datagram, NetError>> {
+        self.rx.poll_recv(cx).map(|d| d.ok_or(NetError::NoDevice))
+    }
+
+    /// Take a queued datagram without waiting.
+    pub fn try_recv_from(&self) -> Option<Datagram> {
+        match self.rx.try_recv() {
+            Ok(d) => Some(d),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => None,
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| SOCKETS.lock().remove(&self.port));
+    }
+}
+
+/// Send one datagram from local port `src_port` to `dst:port`.
+pub async fn udp_send(src_port: u16, data: &[u8], dst: Ipv4Addr, port: u16) -> Result<(), NetError> {
+    let len = UDP_HEADER_LEN + data.len();
+    if len > u16::MAX as usize {
+        return Err(NetError::TooLarge);
+    }
+    let (iface, _) = route(dst)?;
+    let mut seg = Vec::with_capacity(len);
+    seg.extend_from_slice(&src_port.to_be_bytes());
+    seg.extend_from_slice(&port.to_be_bytes());
+    seg.extend_from_slice(&(len as u16).to_be_bytes());
+    seg.extend_from_slice(&[0, 0]);
+    seg.extend_from_slice(data);
+    // an all-zero checksum means "none", so a computed zero is sent as ones
+    let sum = match udp_checksum(iface.ipv4().addr, dst, &seg) {
+        0 => 0xFFFF,
+        s => s,
+    };
+    seg[6..8].copy_from_slice(&sum.to_be_bytes());
+    send_ipv4(dst, PROTO_UDP, &seg).await
+}
+
+/// Ports with a bound socket.
+pub fn udp_ports() -> Vec<u16> {
+    interrupts::without_interrupts(|| SOCKETS.lock().keys().copied().collect())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdpSocketInfo {
+    pub port: u16,
+    /// Datagrams waiting to be received.
+    pub queued: usize,
+    pub rx: u64,
+    /// Datagrams dropped because the socket's queue was full.
+    pub rx_dropped: u64,
+    pub tx: u64,
+}
+
+pub fn udp_sockets() -> Vec<UdpSocketInfo> {
+    interrupts::without_interrupts(|| {
+        SOCKETS
+            .lock()
+            .iter()
+            .map(|(&port, e)| UdpSocketInfo {
+                port,
+                queued: e.tx.len(),
+                rx: e.counters.rx.load(Ordering::Relaxed),
+                rx_dropped: e.counters.rx_dropped.load(Ordering::Relaxed),
+                tx: e.counters.tx.load(Ordering::Relaxed),
+            })
+            .collect()
+    })
+}
+
+/// Handle a received UDP datagram: verify it and queue it on the socket
+/// bound to its destination port.
+pub fn udp_input(iface: &NetInterface, ip: &Ipv4Header, seg: &[u8]) {
+    if seg.len() < UDP_HEADER_LEN {
+        return iface.note_rx_error();
+    }
+    let len = u16::from_be_bytes([seg[4], seg[5]]) as usize;
+    if len < UDP_HEADER_LEN || len > seg.len() {
+        return iface.note_rx_error();
+    }
+    let seg = &seg[..len];
+    if u16::from_be_bytes([seg[6], seg[7]]) != 0 && udp_checksum(ip.src, ip.dst, seg) != 0 {
+        return iface.note_rx_error();
+    }
+    let src_port = u16::from_be_bytes([seg[0], seg[1]]);
+    let dst_port = u16::from_be_bytes([seg[2], seg[3]]);
+    let entry = interrupts::without_interrupts(|| {
+        SOCKETS.lock().get(&dst_port).map(|e| (e.tx.clone(), e.counters.clone()))
+    });
+    let delivered = match entry {
+        Some((tx, counters)) => match tx.try_send((seg[UDP_HEADER_LEN..].to_vec(), ip.src, src_port)) {
+            Ok(()) => {
+                counters.rx.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        },
+        None => false,
+    };
+    if !delivered {
+        iface.note_rx_dropped();
+    }
+}
@@ -0,0 +1,14 @@
+//! Kernel prelude
+//!
+//! What nearly every module uses: the logging and assertion macros and the
+//! heap types from `alloc`. Modules take it with `use crate::prelude::*`
+//! and name everything else by its subsystem path (`crate::hal::`,
+//! `crate::memory::`, `crate::devices::`, `crate::framework::`), so the
+//! dependencies between subsystems show in their imports.
+
+pub use crate::{cls, kassert, klog, kwarn_once, print, println, setcolor, setpos, trace};
+pub use alloc::boxed::Box;
+pub use alloc::format;
+pub use alloc::string::{String, ToString};
+pub use alloc::vec;
+pub use alloc::vec::Vec;
@@ -0,0 +1,138 @@
+//! CPU accounting
+//!
+//! Each process's running time is measured with the TSC: the scheduler
+//! brackets every stint in ring 3 (and the system calls made during it),
+//! and the syscall path brackets each call, so the difference is user time.
+//! Timer ticks sample where a running process is (user or kernel code) and
+//! watch how long it has held the CPU: one that runs `HOG_SLICE_MS` without
+//! blocking is flagged as a CPU hog. From then on the timer takes it off the
+//! CPU every `HOG_TURN_MS` it runs in ring 3 and requeues it, and the
+//! scheduler lets everything else in the run queue go ahead of it until it
+//! blocks again.
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::{rdtsc, tsc_hz};
+use crate::process::{current_process, Process};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// How long a process may run without blocking before it counts as a hog.
+pub const HOG_SLICE_MS: u64 = 1000;
+/// How long a hog runs before the timer requeues it.
+pub const HOG_TURN_MS: u64 = 10;
+
+pub struct CpuTimes {
+    /// Cycles spent running, user and kernel.
+    total: AtomicU64,
+    /// Cycles spent in system calls.
+    kernel: AtomicU64,
+    /// TSC at entry to the system call in progress, 0 if none.
+    syscall_started: AtomicU64,
+    /// TSC when the process last got the CPU, 0 while it doesn't have it.
+    slice_started: AtomicU64,
+    user_ticks: AtomicU64,
+    kernel_ticks: AtomicU64,
+    hog: AtomicBool,
+}
+
+impl CpuTimes {
+    pub const fn new() -> CpuTimes {
+        CpuTimes {
+            total: AtomicU64::new(0),
+            kernel: AtomicU64::new(0),
+            syscall_started: AtomicU64::new(0),
+            slice_started: AtomicU64::new(0),
+            user_ticks: AtomicU64::new(0),
+            kernel_ticks: AtomicU64::new(0),
+            hog: AtomicBool::new(false),
+        }
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn kernel_cycles(&self) -> u64 {
+        self.kernel.load(Ordering::Relaxed)
+    }
+
+    pub fn user_cycles(&self) -> u64 {
+        self.total_cycles().saturating_sub(self.kernel_cycles())
+    }
+
+    /// Timer ticks that found the process in (user, kernel) code.
+    pub fn ticks(&self) -> (u64, u64) {
+        (self.user_ticks.load(Ordering::Relaxed), self.kernel_ticks.load(Ordering::Relaxed))
+    }
+
+    pub fn is_hog(&self) -> bool {
+        self.hog.load(Ordering::Relaxed)
+    }
+
+    /// The process blocked: whatever it was doing, it isn't spinning.
+    pub(crate) fn clear_hog(&self) {
+        self.hog.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn slice_begin(&self) {
+        self.slice_started.store(rdtsc(), Ordering::Relaxed);
+    }
+
+    /// The process left the CPU. Closes a system call it left from (exit,
+    /// or one that blocked) as well as the stint itself.
+    pub(crate) fn slice_end(&self) {
+        let now = rdtsc();
+        let call = self.syscall_started.swap(0, Ordering::Relaxed);
+        if call != 0 {
+            self.kernel.fetch_add(now.wrapping_sub(call), Ordering::Relaxed);
+        }
+        let started = self.slice_started.swap(0, Ordering::Relaxed);
+        if started != 0 {
+            self.total.fetch_add(now.wrapping_sub(started), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Called by the syscall path on entry.
+pub fn syscall_begin() {
+    if let Some(proc) = current_process() {
+        proc.times.syscall_started.store(rdtsc(), Ordering::Relaxed);
+    }
+}
+
+/// Called by the syscall path just before returning to user mode.
+pub fn syscall_end() {
+    if let Some(proc) = current_process() {
+        let started = proc.times.syscall_started.swap(0, Ordering::Relaxed);
+        if started != 0 {
+            proc.times.kernel.fetch_add(rdtsc().wrapping_sub(started), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Called from the timer interrupt. `from_user` says whether it
+/// interrupted ring 3.
+pub fn account_tick(from_user: bool) {
+    let Some(proc) = current_process() else { return };
+    let times = &proc.times;
+    if from_user {
+        times.user_ticks.fetch_add(1, Ordering::Relaxed);
+    } else {
+        times.kernel_ticks.fetch_add(1, Ordering::Relaxed);
+    }
+    let started = times.slice_started.load(Ordering::Relaxed);
+    let limit = tsc_hz() / 1000 * HOG_SLICE_MS;
+    if started != 0 && rdtsc().wrapping_sub(started) > limit && !times.hog.swap(true, Ordering::Relaxed) {
+        println!("[PROC] pid {} ({}) has run {} ms without blocking; deprioritized", proc.pid(), proc.name(), HOG_SLICE_MS);
+    }
+}
+
+/// Whether the scheduler should let other ready processes go first.
+pub(crate) fn deprioritized(proc: &Process) -> bool {
+    proc.times.is_hog()
+}
+
+/// Whether a hog has had its turn on the CPU and should be requeued.
+pub(crate) fn turn_over(proc: &Process) -> bool {
+    let started = proc.times.slice_started.load(Ordering::Relaxed);
+    proc.times.is_hog() && started != 0 && rdtsc().wrapping_sub(started) > tsc_hz() / 1000 * HOG_TURN_MS
+}
@@ -0,0 +1,515 @@
+//! Per-process address spaces
+//!
+//! Each process gets its own PML4. The top-level entries the kernel uses
+//! are copied from the kernel's table when the space is created, so kernel
+//! code, the heap and the physical-memory window stay mapped (and shared)
+//! while a process runs; every other top-level slot belongs to the process.
+//! User mappings are refused in kernel slots, and only user slots are freed
+//! when the space is dropped.
+
+use crate::prelude::*;
+use crate::memory::paging::USER_SPACE_END;
+use crate::memory::with_frame_allocator;
+use crate::process::ProcessError;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags as Flags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Physical address of the kernel's PML4, captured by `init_kernel_template`.
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+/// Remember the active PML4 as the template for new address spaces and the
+/// table to return to when no process is running.
+pub fn init_kernel_template() {
+    let (frame, _) = Cr3::read();
+    KERNEL_PML4.store(frame.start_address().as_u64(), Ordering::Relaxed);
+}
+
+fn kernel_pml4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PML4.load(Ordering::Relaxed)))
+}
+
+/// Switch back to the kernel's own page tables.
+pub fn activate_kernel_space() {
+    let (current, flags) = Cr3::read();
+    if current != kernel_pml4() {
+        unsafe { Cr3::write(kernel_pml4(), flags) };
+    }
+}
+
+fn phys_offset() -> VirtAddr {
+    VirtAddr::new(crate::driver_framework::drivers::vbe_vga::get_boot_phys_offset())
+}
+
+fn table_at(frame: PhysFrame) -> *mut PageTable {
+    (phys_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn alloc_zeroed_frame() -> Result<PhysFrame, ProcessError> {
+    let frame = with_frame_allocator(|a| a.allocate_frame()).flatten().ok_or(ProcessError::NoMemory)?;
+    unsafe { core::ptr::write_bytes((phys_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    Ok(frame)
+}
+
+fn free_frame(frame: PhysFrame) {
+    with_frame_allocator(|a| unsafe { a.free_frame(frame) });
+}
+
+pub fn page_align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+pub fn page_align_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Loaded from the executable.
+    Image,
+    Stack,
+    Heap,
+    /// Anonymous memory from `mmap`.
+    Mmap,
+}
+
+/// A range of user memory, `[start, end)`, page aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+    pub writable: bool,
+}
+
+impl Region {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Pages here are backed on first touch rather than up front.
+    pub fn is_demand(&self) -> bool {
+        matches!(self.kind, RegionKind::Heap | RegionKind::Mmap)
+    }
+}
+
+/// Resident pages a process may use unless told otherwise (64 MiB).
+pub const DEFAULT_PAGE_LIMIT: usize = 16 * 1024;
+/// Most address space the heap and anonymous mappings may reserve together.
+pub const MAX_RESERVED: u64 = 1 << 30;
+
+/// Memory accounting for one address space.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryUsage {
+    /// User pages currently backed by a frame.
+    pub resident_pages: usize,
+    pub peak_pages: usize,
+    /// Cap on `resident_pages`.
+    pub page_limit: usize,
+    /// Bytes covered by regions, backed or not.
+    pub virtual_bytes: u64,
+    /// Pages filled in on first touch.
+    pub demand_faults: u64,
+}
+
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    /// Top-level slots shared with the kernel.
+    kernel_slots: [bool; 512],
+    regions: Vec<Region>,
+    /// Current program break; the heap region ends on the page after it.
+    brk: u64,
+    usage: MemoryUsage,
+}
+
+impl AddressSpace {
+    /// A fresh space holding only the kernel's mappings.
+    pub fn new() -> Result<AddressSpace, ProcessError> {
+        let pml4 = alloc_zeroed_frame()?;
+        let template = unsafe { &*table_at(kernel_pml4()) };
+        let table = unsafe { &mut *table_at(pml4) };
+        let mut kernel_slots = [false; 512];
+        for (i, entry) in template.iter().enumerate() {
+            if !entry.is_unused() {
+                table[i] = entry.clone();
+                kernel_slots[i] = true;
+            }
+        }
+        let usage = MemoryUsage { page_limit: DEFAULT_PAGE_LIMIT, ..MemoryUsage::default() };
+        Ok(AddressSpace { pml4, kernel_slots, regions: Vec::new(), brk: 0, usage })
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn mapped_pages(&self) -> usize {
+        self.usage.resident_pages
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage { virtual_bytes: self.regions.iter().map(|r| r.len()).sum(), ..self.usage }
+    }
+
+    pub fn set_page_limit(&mut self, pages: usize) {
+        self.usage.page_limit = pages;
+    }
+
+    /// Load this space into CR3.
+    pub fn activate(&self) {
+        unsafe { Cr3::write(self.pml4, Cr3Flags::empty()) };
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(&mut *table_at(self.pml4), phys_offset()) }
+    }
+
+    /// Whether `[start, end)` is usable for user mappings.
+    fn check_range(&self, start: u64, end: u64) -> Result<(), ProcessError> {
+        if start >= end || end > USER_SPACE_END {
+            return Err(ProcessError::AddressConflict);
+        }
+        let first = (start >> 39) as usize;
+        let last = ((end - 1) >> 39) as usize;
+        if (first..=last).any(|slot| self.kernel_slots[slot]) {
+            return Err(ProcessError::AddressConflict);
+        }
+        Ok(())
+    }
+
+    /// Whether `[start, end)` overlaps a region other than `except`.
+    fn overlaps(&self, start: u64, end: u64, except: Option<usize>) -> bool {
+        self.regions
+            .iter()
+            .enumerate()
+            .any(|(i, r)| Some(i) != except && r.start < end && start < r.end)
+    }
+
+    fn reserved_bytes(&self) -> u64 {
+        self.regions.iter().filter(|r| r.is_demand()).map(|r| r.len()).sum()
+    }
+
+    /// Back one page with a zeroed frame, or give an existing page write
+    /// access if `writable`.
+    fn map_page(&mut self, addr: u64, writable: bool) -> Result<(), ProcessError> {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let mut flags = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+        if writable {
+            flags |= Flags::WRITABLE;
+        }
+        let active = self.is_active();
+        if self.mapper().translate_page(page).is_ok() {
+            if writable {
+                let flush = unsafe { self.mapper().update_flags(page, flags).map_err(|_| ProcessError::NoMemory)? };
+                if active { flush.flush() } else { flush.ignore() }
+            }
+            return Ok(());
+        }
+        if self.usage.resident_pages >= self.usage.page_limit {
+            return Err(ProcessError::NoMemory);
+        }
+        let frame = alloc_zeroed_frame()?;
+        let parent = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let pml4 = self.pml4;
+        let result = with_frame_allocator(|a| unsafe {
+            OffsetPageTable::new(&mut *table_at(pml4), phys_offset())
+                .map_to_with_table_flags(page, frame, flags, parent, a)
+                .map(|f| if active { f.flush() } else { f.ignore() })
+        });
+        if !matches!(result, Some(Ok(()))) {
+            free_frame(frame);
+            return Err(ProcessError::NoMemory);
+        }
+        self.usage.resident_pages += 1;
+        self.usage.peak_pages = core::cmp::max(self.usage.peak_pages, self.usage.resident_pages);
+        Ok(())
+    }
+
+    /// Drop the frames behind `[start, end)`; pages that were never touched
+    /// are skipped.
+    fn unmap_range(&mut self, start: u64, end: u64) {
+        let active = self.is_active();
+        let mut addr = start;
+        while addr < end {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            if let Ok((frame, flush)) = self.mapper().unmap(page) {
+                if active { flush.flush() } else { flush.ignore() }
+                free_frame(frame);
+                self.usage.resident_pages -= 1;
+            }
+            addr += PAGE_SIZE;
+        }
+    }
+
+    /// Back `[start, start + len)` (widened to whole pages) with zeroed
+    /// frames and record it as a region. Pages that are already mapped are
+    /// kept, gaining write access if the new region needs it.
+    pub fn map_region(&mut self, start: u64, len: u64, kind: RegionKind, writable: bool) -> Result<Region, ProcessError> {
+        let end = page_align_up(start.checked_add(len).ok_or(ProcessError::AddressConflict)?);
+        let start = page_align_down(start);
+        self.check_range(start, end)?;
+        let mut addr = start;
+        while addr < end {
+            self.map_page(addr, writable)?;
+            addr += PAGE_SIZE;
+        }
+        let region = Region { start, end, kind, writable };
+        self.regions.push(region);
+        Ok(region)
+    }
+
+    /// Start the heap (empty) at `start`, which sets the initial break.
+    pub fn reserve_heap(&mut self, start: u64) -> Region {
+        let start = page_align_up(start);
+        let region = Region { start, end: start, kind: RegionKind::Heap, writable: true };
+        self.regions.push(region);
+        self.brk = start;
+        region
+    }
+
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// Move the program break to `new`. The heap grows lazily: pages are
+    /// only backed when first touched. Returns the break in effect
+    /// afterwards, which is the old one if the request can't be met.
+    pub fn set_brk(&mut self, new: u64) -> u64 {
+        let Some(heap) = self.regions.iter().position(|r| r.kind == RegionKind::Heap) else {
+            return self.brk;
+        };
+        let start = self.regions[heap].start;
+        let old_end = self.regions[heap].end;
+        if new < start {
+            return self.brk;
+        }
+        let end = page_align_up(new);
+        if end > old_end {
+            if self.check_range(start, end).is_err()
+                || self.overlaps(start, end, Some(heap))
+                || self.reserved_bytes() + (end - old_end) > MAX_RESERVED
+            {
+                return self.brk;
+            }
+        } else {
+            self.unmap_range(end, old_end);
+        }
+        self.regions[heap].end = end;
+        self.brk = new;
+        self.brk
+    }
+
+    /// Reserve `len` bytes of demand-paged anonymous memory, placed below
+    /// the lowest region under `ceiling` that leaves room. Returns the
+    /// start address.
+    pub fn mmap_anonymous(&mut self, len: u64, writable: bool, ceiling: u64) -> Result<u64, ProcessError> {
+        let len = page_align_up(len);
+        if len == 0 {
+            return Err(ProcessError::AddressConflict);
+        }
+        if self.reserved_bytes() + len > MAX_RESERVED {
+            return Err(ProcessError::NoMemory);
+        }
+        // walk down from the ceiling, hopping below whatever is in the way
+        let mut top = ceiling;
+        loop {
+            let start = top.checked_sub(len).ok_or(ProcessError::NoMemory)?;
+            let blocker = self.regions.iter().filter(|r| r.start < top && start < r.end).map(|r| r.start).min();
+            match blocker {
+                Some(below) => top = below,
+                None if self.check_range(start, top).is_ok() => {
+                    self.regions.push(Region { start, end: top, kind: RegionKind::Mmap, writable });
+                    return Ok(start);
+                }
+                // a kernel slot: continue below it
+                None => top = start & !((1u64 << 39) - 1),
+            }
+        }
+    }
+
+    /// Remove anonymous mappings from `[start, start + len)`, splitting
+    /// regions that straddle the range.
+    pub fn munmap(&mut self, start: u64, len: u64) -> Result<(), ProcessError> {
+        if start % PAGE_SIZE != 0 || len == 0 {
+            return Err(ProcessError::AddressConflict);
+        }
+        let end = page_align_up(start.checked_add(len).ok_or(ProcessError::AddressConflict)?);
+        let mut kept = Vec::new();
+        for r in self.regions.drain(..) {
+            if r.kind != RegionKind::Mmap || r.end <= start || end <= r.start {
+                kept.push(r);
+                continue;
+            }
+            if r.start < start {
+                kept.push(Region { end: start, ..r });
+            }
+            if end < r.end {
+                kept.push(Region { start: end, ..r });
+            }
+        }
+        self.regions = kept;
+        self.unmap_range(start, end);
+        Ok(())
+    }
+
+    /// Fill in the page holding `addr` if it lies in a demand-paged region
+    /// that allows the access. Returns false for a genuine fault.
+    pub fn handle_fault(&mut self, addr: u64, write: bool) -> bool {
+        let region = match self.regions.iter().find(|r| r.contains(addr)) {
+            Some(r) if r.is_demand() && (r.writable || !write) => *r,
+            _ => return false,
+        };
+        if self.map_page(page_align_down(addr), region.writable).is_err() {
+            return false;
+        }
+        self.usage.demand_faults += 1;
+        true
+    }
+
+    /// Back every demand-paged page in `[start, start + len)` so kernel
+    /// code can copy to or from it without faulting. Returns false if part
+    /// of the range isn't user memory the caller may access this way.
+    pub fn populate(&mut self, start: u64, len: u64, write: bool) -> bool {
+        let Some(end) = start.checked_add(len) else { return false };
+        let mut addr = page_align_down(start);
+        while addr < end {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            if self.mapper().translate_page(page).is_err() && !self.handle_fault(addr, write) {
+                return false;
+            }
+            addr += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// A copy of this space: same regions and break, with every backed page
+    /// copied into a fresh frame.
+    pub fn duplicate(&mut self) -> Result<AddressSpace, ProcessError> {
+        let mut copy = AddressSpace::new()?;
+        copy.usage.page_limit = self.usage.page_limit;
+        for addr in self.backed_pages() {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            let writable = self.mapper().translate(page.start_address());
+            let writable = match writable {
+                x86_64::structures::paging::mapper::TranslateResult::Mapped { flags, .. } => flags.contains(Flags::WRITABLE),
+                _ => continue,
+            };
+            copy.map_page(addr, writable)?;
+            let src = self.mapper().translate_addr(page.start_address()).ok_or(ProcessError::NoMemory)?;
+            let dst = copy.mapper().translate_addr(page.start_address()).ok_or(ProcessError::NoMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (phys_offset() + src.as_u64()).as_ptr::<u8>(),
+                    (phys_offset() + dst.as_u64()).as_mut_ptr::<u8>(),
+                    PAGE_SIZE as usize,
+                );
+            }
+        }
+        copy.regions = self.regions.clone();
+        copy.brk = self.brk;
+        Ok(copy)
+    }
+
+    /// Addresses of every user page backed by a frame.
+    fn backed_pages(&self) -> Vec<u64> {
+        let mut out = Vec::new();
+        let pml4 = unsafe { &*table_at(self.pml4) };
+        for (i4, e4) in pml4.iter().enumerate() {
+            if self.kernel_slots[i4] || e4.is_unused() {
+                continue;
+            }
+            let pdpt = unsafe { &*table_at(PhysFrame::containing_address(e4.addr())) };
+            for (i3, e3) in pdpt.iter().enumerate().filter(|(_, e)| !e.is_unused()) {
+                let pd = unsafe { &*table_at(PhysFrame::containing_address(e3.addr())) };
+                for (i2, e2) in pd.iter().enumerate().filter(|(_, e)| !e.is_unused()) {
+                    let pt = unsafe { &*table_at(PhysFrame::containing_address(e2.addr())) };
+                    for (i1, _) in pt.iter().enumerate().filter(|(_, e)| !e.is_unused()) {
+                        out.push(((i4 as u64) << 39) | ((i3 as u64) << 30) | ((i2 as u64) << 21) | ((i1 as u64) << 12));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Copy `data` into mapped user memory at `addr`.
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), ProcessError> {
+        let mut done = 0;
+        while done < data.len() {
+            let va = addr + done as u64;
+            let phys = self.mapper().translate_addr(VirtAddr::new(va)).ok_or(ProcessError::AddressConflict)?;
+            let chunk = core::cmp::min(data.len() - done, (PAGE_SIZE - (va % PAGE_SIZE)) as usize);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[done..].as_ptr(),
+                    (phys_offset() + phys.as_u64()).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Free every user page and page table below the kernel's slots.
+    fn release_user(&mut self) {
+        let pml4 = unsafe { &mut *table_at(self.pml4) };
+        for slot in 0..512 {
+            if self.kernel_slots[slot] || pml4[slot].is_unused() {
+                continue;
+            }
+            let pdpt_frame = PhysFrame::containing_address(pml4[slot].addr());
+            let pdpt = unsafe { &mut *table_at(pdpt_frame) };
+            for pdpte in pdpt.iter_mut().filter(|e| !e.is_unused()) {
+                let pd_frame = PhysFrame::containing_address(pdpte.addr());
+                let pd = unsafe { &mut *table_at(pd_frame) };
+                for pde in pd.iter_mut().filter(|e| !e.is_unused()) {
+                    let pt_frame = PhysFrame::containing_address(pde.addr());
+                    let pt = unsafe { &mut *table_at(pt_frame) };
+                    for pte in pt.iter_mut().filter(|e| !e.is_unused()) {
+                        free_frame(PhysFrame::containing_address(pte.addr()));
+                        pte.set_unused();
+                    }
+                    free_frame(pt_frame);
+                    pde.set_unused();
+                }
+                free_frame(pd_frame);
+                pdpte.set_unused();
+            }
+            free_frame(pdpt_frame);
+            pml4[slot].set_unused();
+        }
+        self.regions.clear();
+        self.usage.resident_pages = 0;
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // never tear down the tables the CPU is walking
+        if Cr3::read().0 == self.pml4 {
+            activate_kernel_space();
+        }
+        self.release_user();
+        free_frame(self.pml4);
+    }
+}
@@ -0,0 +1,119 @@
+//! ELF64 executable parsing
+//!
+//! Only statically linked x86-64 executables are accepted. The loader needs
+//! the entry point and the PT_LOAD segments; everything else is ignored.
+
+use crate::prelude::*;
+use crate::memory::paging::USER_SPACE_END;
+use crate::process::ProcessError;
+use alloc::vec::Vec;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const EHDR_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ElfSegment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub file_offset: usize,
+    pub file_size: usize,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+    pub entry: u64,
+    pub segments: Vec<ElfSegment>,
+}
+
+impl ElfImage {
+    /// First address past the highest loaded byte.
+    pub fn end(&self) -> u64 {
+        self.segments.iter().map(|s| s.vaddr + s.mem_size).max().unwrap_or(0)
+    }
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+/// Validate `data` as an executable and list what has to be loaded.
+pub fn parse_elf(data: &[u8]) -> Result<ElfImage, ProcessError> {
+    if data.len() < EHDR_LEN || &data[..4] != ELF_MAGIC {
+        return Err(ProcessError::BadElf("not an ELF file"));
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return Err(ProcessError::BadElf("not 64-bit little-endian"));
+    }
+    if u16_at(data, 16) != ET_EXEC {
+        return Err(ProcessError::BadElf("not a static executable"));
+    }
+    if u16_at(data, 18) != EM_X86_64 {
+        return Err(ProcessError::BadElf("not x86-64"));
+    }
+    let entry = u64_at(data, 24);
+    let phoff = u64_at(data, 32) as usize;
+    let phentsize = u16_at(data, 54) as usize;
+    let phnum = u16_at(data, 56) as usize;
+    if phentsize < PHDR_LEN {
+        return Err(ProcessError::BadElf("bad program header size"));
+    }
+    let table_end = phnum.checked_mul(phentsize).and_then(|n| n.checked_add(phoff));
+    if table_end.is_none_or(|end| end > data.len()) {
+        return Err(ProcessError::BadElf("program headers out of range"));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = &data[phoff + i * phentsize..];
+        if u32_at(ph, 0) != PT_LOAD {
+            continue;
+        }
+        let flags = u32_at(ph, 4);
+        let file_offset = u64_at(ph, 8) as usize;
+        let vaddr = u64_at(ph, 16);
+        let file_size = u64_at(ph, 32) as usize;
+        let mem_size = u64_at(ph, 40);
+        if mem_size == 0 {
+            continue;
+        }
+        if file_size as u64 > mem_size || file_offset.checked_add(file_size).is_none_or(|end| end > data.len()) {
+            return Err(ProcessError::BadElf("segment out of range"));
+        }
+        if vaddr.checked_add(mem_size).is_none_or(|end| end > USER_SPACE_END) {
+            return Err(ProcessError::BadElf("segment outside user space"));
+        }
+        segments.push(ElfSegment {
+            vaddr,
+            mem_size,
+            file_offset,
+            file_size,
+            writable: flags & PF_W != 0,
+            executable: flags & PF_X != 0,
+        });
+    }
+    if segments.is_empty() {
+        return Err(ProcessError::BadElf("nothing to load"));
+    }
+    if !segments.iter().any(|s| s.executable && entry >= s.vaddr && entry < s.vaddr + s.mem_size) {
+        return Err(ProcessError::BadElf("entry point outside code"));
+    }
+    Ok(ElfImage { entry, segments })
+}
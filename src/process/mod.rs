@@ -0,0 +1,542 @@
+//! User processes
+//!
+//! A process is a loaded program with its own address space and descriptor
+//! table, whose descriptors 0, 1 and 2 start out on the console.
+//! `spawn_process` loads a static ELF executable from the VFS into a fresh
+//! address space, adds a stack holding its arguments and environment (laid
+//! out as the System V ABI expects at `_start`) and an (initially empty)
+//! heap region, and queues it on the process scheduler. The process ends
+//! when it calls `exit` or faults; its memory and descriptors are released
+//! at once, and the exit status is kept until its parent collects it with the `wait`
+//! syscall (or, for processes the kernel started, `wait_process`).
+//!
+//! `fork` copies the caller's memory eagerly and shares its open files
+//! with the child, as on Unix; `exec` replaces the image but keeps the
+//! descriptors. A process that waits for a child that is still running
+//! gives up the CPU; the child's exit puts it back on the run queue with
+//! the result in place.
+//!
+//! User memory layout: the executable goes wherever it was linked (any
+//! top-level slot the kernel doesn't use), the heap starts on the first
+//! page after it, and the stack sits at the top of the lower half with
+//! anonymous mappings growing down beneath it. Heap and mappings are
+//! demand paged: `brk` and `mmap` only reserve address space, and the page
+//! fault handler backs pages as they are touched, up to the space's page
+//! limit.
+
+pub mod accounting;
+pub use accounting::*;
+pub mod address_space;
+pub use address_space::*;
+pub mod elf;
+pub use elf::*;
+pub mod sched;
+pub use sched::*;
+pub mod signal;
+pub use signal::*;
+pub mod smoketest;
+
+use crate::prelude::*;
+use crate::arch::syscall::SyscallFrame;
+use crate::arch::usermode::UserContext;
+use crate::fs::fd::FdTable;
+use crate::fs::vfs::{self, VfsError};
+use crate::sync::WaitQueue;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub type Pid = u64;
+
+pub const USER_STACK_TOP: u64 = 0x0000_7FFF_FFFF_F000;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+/// Most bytes of arguments, environment and pointers to them a program
+/// can be started with (half the stack).
+pub const ARG_MAX: usize = 32 * 1024;
+/// Environment for programs the kernel starts itself.
+pub const DEFAULT_ENV: &[&str] = &["PATH=/bin", "HOME=/"];
+/// Anonymous mappings are placed below this, leaving a guard page under
+/// the stack.
+pub const MMAP_CEILING: u64 = USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    Vfs(VfsError),
+    BadElf(&'static str),
+    NoMemory,
+    /// The executable or a mapping overlaps kernel address space.
+    AddressConflict,
+    NoSuchProcess,
+    /// `wait` with no matching child.
+    NoChild,
+    InvalidSignal,
+    /// Arguments and environment don't fit in `ARG_MAX`.
+    ArgsTooLong,
+}
+
+impl core::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProcessError::Vfs(e) => write!(f, "{}", e),
+            ProcessError::BadElf(why) => write!(f, "bad executable: {}", why),
+            ProcessError::NoMemory => f.write_str("out of memory"),
+            ProcessError::AddressConflict => f.write_str("address range not available"),
+            ProcessError::NoSuchProcess => f.write_str("no such process"),
+            ProcessError::NoChild => f.write_str("no child processes"),
+            ProcessError::InvalidSignal => f.write_str("invalid signal"),
+            ProcessError::ArgsTooLong => f.write_str("argument list too long"),
+        }
+    }
+}
+
+impl From<VfsError> for ProcessError {
+    fn from(e: VfsError) -> Self {
+        ProcessError::Vfs(e)
+    }
+}
+
+impl ProcessError {
+    pub fn errno(&self) -> i64 {
+        use crate::arch::syscall::{errno_for, E2BIG, ECHILD, EINVAL, ENOEXEC, ENOMEM, ESRCH};
+        match self {
+            ProcessError::Vfs(e) => errno_for(*e),
+            ProcessError::BadElf(_) | ProcessError::AddressConflict => ENOEXEC,
+            ProcessError::NoMemory => ENOMEM,
+            ProcessError::NoSuchProcess => ESRCH,
+            ProcessError::NoChild => ECHILD,
+            ProcessError::InvalidSignal => EINVAL,
+            ProcessError::ArgsTooLong => E2BIG,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    /// Blocked in `wait` for `child` (any child if `None`); the status goes
+    /// to `status_ptr` in user memory unless it is 0.
+    Waiting { child: Option<Pid>, status_ptr: u64 },
+    Exited(i32),
+}
+
+impl core::fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProcessState::Ready => f.write_str("ready"),
+            ProcessState::Running => f.write_str("running"),
+            ProcessState::Waiting { .. } => f.write_str("waiting"),
+            ProcessState::Exited(status) => write!(f, "exited({})", status),
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    /// 0 once there is no parent to collect the status.
+    parent: AtomicU64,
+    name: Mutex<String>,
+    /// Where user code resumes next time the process runs.
+    context: Mutex<UserContext>,
+    state: Mutex<ProcessState>,
+    /// Dropped at exit; `None` afterwards.
+    space: Mutex<Option<AddressSpace>>,
+    fds: Mutex<FdTable>,
+    signals: Signals,
+    times: CpuTimes,
+    exited: WaitQueue,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        match self.parent.load(Ordering::Relaxed) {
+            0 => None,
+            p => Some(p),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn context(&self) -> UserContext {
+        *self.context.lock()
+    }
+
+    pub fn state(&self) -> ProcessState {
+        interrupts::without_interrupts(|| *self.state.lock())
+    }
+
+    fn set_state(&self, state: ProcessState) {
+        interrupts::without_interrupts(|| *self.state.lock() = state);
+    }
+
+    pub fn fds(&self) -> &Mutex<FdTable> {
+        &self.fds
+    }
+
+    pub fn signals(&self) -> &Signals {
+        &self.signals
+    }
+
+    pub fn times(&self) -> &CpuTimes {
+        &self.times
+    }
+
+    /// Run `f` on the address space, if the process still has one.
+    pub fn with_space<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+        self.space.lock().as_mut().map(f)
+    }
+
+    /// Record the exit status, release memory and descriptors, and hand the
+    /// status to a parent blocked in `wait`.
+    fn finish(&self, status: i32) {
+        self.set_state(ProcessState::Exited(status));
+        self.fds.lock().clear();
+        let space = self.space.lock().take();
+        drop(space);
+        self.exited.wake_all();
+
+        // children outlive their parent without anyone to wait for them
+        for child in processes().iter().filter(|c| c.parent() == Some(self.pid)) {
+            child.parent.store(0, Ordering::Relaxed);
+            if let ProcessState::Exited(_) = child.state() {
+                PROCESSES.lock().remove(&child.pid);
+            }
+        }
+
+        let Some(parent) = self.parent().and_then(find_process) else { return };
+        if let ProcessState::Waiting { child, status_ptr } = parent.state() {
+            if child.is_none_or(|c| c == self.pid) {
+                PROCESSES.lock().remove(&self.pid);
+                if status_ptr != 0 {
+                    parent.with_space(|space| space.write(status_ptr, &status.to_le_bytes()));
+                }
+                parent.context.lock().rax = self.pid;
+                parent.set_state(ProcessState::Ready);
+                enqueue_process(parent);
+            }
+        }
+    }
+}
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+pub fn find_process(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Every process not yet reaped, by pid.
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// A snapshot of one process for `ps`-style listings.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    /// Pages of memory currently backing the process (0 once it exited).
+    pub resident_pages: usize,
+    /// Address space reserved, backed or not.
+    pub virtual_bytes: u64,
+    /// TSC cycles spent in user code and in system calls.
+    pub user_cycles: u64,
+    pub kernel_cycles: u64,
+    /// Flagged for running too long without blocking.
+    pub hog: bool,
+}
+
+/// Snapshot of every process not yet reaped, by pid.
+pub fn list() -> Vec<ProcessInfo> {
+    processes()
+        .iter()
+        .map(|p| {
+            let usage = p.with_space(|space| space.usage());
+            ProcessInfo {
+                pid: p.pid,
+                parent: p.parent(),
+                name: p.name(),
+                state: p.state(),
+                resident_pages: usage.map_or(0, |u| u.resident_pages),
+                virtual_bytes: usage.map_or(0, |u| u.virtual_bytes),
+                user_cycles: p.times.user_cycles(),
+                kernel_cycles: p.times.kernel_cycles(),
+                hog: p.times.is_hog(),
+            }
+        })
+        .collect()
+}
+
+/// Print the process table, one line per process.
+pub fn print_processes() {
+    use crate::arch::tsc_timer::cycles_to_us;
+    println!("  PID  PPID STATE       RSS(KiB)  VIRT(KiB) USER(ms)  SYS(ms) NAME");
+    for p in list() {
+        println!(
+            "{:>5} {:>5} {:<11} {:>8} {:>10} {:>8} {:>8} {}{}",
+            p.pid,
+            p.parent.unwrap_or(0),
+            alloc::format!("{}", p.state),
+            p.resident_pages * 4,
+            p.virtual_bytes / 1024,
+            cycles_to_us(p.user_cycles) / 1000,
+            cycles_to_us(p.kernel_cycles) / 1000,
+            p.name,
+            if p.hog { " (hog)" } else { "" }
+        );
+    }
+}
+
+fn insert_process(
+    name: &str,
+    parent: Option<Pid>,
+    space: AddressSpace,
+    fds: FdTable,
+    signals: Signals,
+    context: UserContext,
+) -> Arc<Process> {
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let proc = Arc::new(Process {
+        pid,
+        parent: AtomicU64::new(parent.unwrap_or(0)),
+        name: Mutex::new(String::from(name)),
+        context: Mutex::new(context),
+        state: Mutex::new(ProcessState::Ready),
+        space: Mutex::new(Some(space)),
+        fds: Mutex::new(fds),
+        signals,
+        times: CpuTimes::new(),
+        exited: WaitQueue::new(),
+    });
+    PROCESSES.lock().insert(pid, proc.clone());
+    proc
+}
+
+fn program_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Build an address space for `image`, copying its segments out of `data`.
+/// Returns the space and the initial stack pointer.
+fn load_image(image: &ElfImage, data: &[u8], argv: &[&str], envp: &[&str]) -> Result<(AddressSpace, u64), ProcessError> {
+    let mut space = AddressSpace::new()?;
+    for seg in image.segments.iter() {
+        space.map_region(seg.vaddr, seg.mem_size, RegionKind::Image, seg.writable)?;
+        space.write(seg.vaddr, &data[seg.file_offset..seg.file_offset + seg.file_size])?;
+    }
+    space.reserve_heap(image.end());
+    space.map_region(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE, RegionKind::Stack, true)?;
+    let sp = build_initial_stack(&mut space, image.entry, argv, envp)?;
+    Ok((space, sp))
+}
+
+/// Lay out the System V process stack below `USER_STACK_TOP`: `argc` at
+/// the returned (16-byte aligned) stack pointer, then the `argv` and
+/// `envp` pointer arrays, each ending in NULL, then the auxiliary vector;
+/// the strings themselves sit above.
+fn build_initial_stack(space: &mut AddressSpace, entry: u64, argv: &[&str], envp: &[&str]) -> Result<u64, ProcessError> {
+    const AT_NULL: u64 = 0;
+    const AT_PAGESZ: u64 = 6;
+    const AT_ENTRY: u64 = 9;
+
+    let strings: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 6;
+    if strings + words * 8 > ARG_MAX {
+        return Err(ProcessError::ArgsTooLong);
+    }
+
+    let mut top = USER_STACK_TOP;
+    let mut copy_out = |list: &[&str]| -> Result<Vec<u64>, ProcessError> {
+        let mut addrs = Vec::with_capacity(list.len() + 1);
+        for s in list {
+            top -= s.len() as u64 + 1;
+            space.write(top, s.as_bytes())?;
+            space.write(top + s.len() as u64, &[0])?;
+            addrs.push(top);
+        }
+        addrs.push(0);
+        Ok(addrs)
+    };
+    let argv_ptrs = copy_out(argv)?;
+    let envp_ptrs = copy_out(envp)?;
+
+    let mut pointers = Vec::with_capacity(words);
+    pointers.push(argv.len() as u64);
+    pointers.extend_from_slice(&argv_ptrs);
+    pointers.extend_from_slice(&envp_ptrs);
+    pointers.extend_from_slice(&[AT_PAGESZ, PAGE_SIZE, AT_ENTRY, entry, AT_NULL, 0]);
+
+    let sp = (top - pointers.len() as u64 * 8) & !15;
+    let bytes: Vec<u8> = pointers.iter().flat_map(|w| w.to_le_bytes()).collect();
+    space.write(sp, &bytes)?;
+    Ok(sp)
+}
+
+fn load_program(path: &str, argv: &[&str], envp: &[&str]) -> Result<(AddressSpace, UserContext), ProcessError> {
+    let data = vfs::read_file(path)?;
+    let image = parse_elf(&data)?;
+    let (space, sp) = load_image(&image, &data, argv, envp)?;
+    Ok((space, UserContext::new(image.entry, sp)))
+}
+
+/// Load the executable at `path` as a new process and queue it to run.
+/// `argv` conventionally starts with the program's name.
+pub fn spawn_process(path: &str, argv: &[&str], envp: &[&str], parent: Option<Pid>) -> Result<Pid, ProcessError> {
+    let (space, context) = load_program(path, argv, envp)?;
+    Ok(start_process(program_name(path), parent, space, context))
+}
+
+/// Like `spawn_process`, for an executable already in memory.
+pub fn spawn_image(name: &str, data: &[u8], argv: &[&str], envp: &[&str], parent: Option<Pid>) -> Result<Pid, ProcessError> {
+    let image = parse_elf(data)?;
+    let (space, sp) = load_image(&image, data, argv, envp)?;
+    Ok(start_process(name, parent, space, UserContext::new(image.entry, sp)))
+}
+
+fn start_process(name: &str, parent: Option<Pid>, space: AddressSpace, context: UserContext) -> Pid {
+    let mut fds = FdTable::new();
+    if let Some(console) = crate::devices::chardev::char_device("console") {
+        fds.set_stdio(console);
+    }
+    let proc = insert_process(name, parent, space, fds, Signals::new(), context);
+    println!("[PROC] pid {} ({}) loaded, entry {:#x}", proc.pid, name, context.rip);
+    enqueue_process(proc.clone());
+    proc.pid
+}
+
+/// Copy the running process. The child resumes from the same system call
+/// with a return value of 0; the parent gets the child's pid.
+pub fn fork_current(frame: &SyscallFrame) -> Result<Pid, ProcessError> {
+    let parent = current_process().ok_or(ProcessError::NoSuchProcess)?;
+    let space = parent.with_space(|space| space.duplicate()).ok_or(ProcessError::NoSuchProcess)??;
+    let fds = parent.fds.lock().clone();
+    let signals = parent.signals.inherit();
+    let child = insert_process(&parent.name(), Some(parent.pid), space, fds, signals, frame.user_context(0));
+    enqueue_process(child.clone());
+    Ok(child.pid)
+}
+
+/// Replace the running process's image with the executable at `path`.
+/// On success the system call returns into the new program's entry point.
+pub fn exec_current(path: &str, argv: &[&str], envp: &[&str], frame: &mut SyscallFrame) -> Result<(), ProcessError> {
+    let proc = current_process().ok_or(ProcessError::NoSuchProcess)?;
+    let (space, context) = load_program(path, argv, envp)?;
+    // switch to the new tables before the old ones are freed
+    space.activate();
+    let old = proc.space.lock().replace(space);
+    drop(old);
+    *proc.name.lock() = String::from(program_name(path));
+    proc.signals.reset_handlers();
+    frame.set_user_context(&context);
+    Ok(())
+}
+
+/// Collect an exited child of `parent` (any child if `child` is `None`).
+/// `Ok(None)` means a matching child exists but is still running.
+pub fn reap_child(parent: Pid, child: Option<Pid>) -> Result<Option<(Pid, i32)>, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let mut matching = table
+        .values()
+        .filter(|p| p.parent() == Some(parent) && child.is_none_or(|c| c == p.pid))
+        .peekable();
+    if matching.peek().is_none() {
+        return Err(ProcessError::NoChild);
+    }
+    let done = matching.find_map(|p| match p.state() {
+        ProcessState::Exited(status) => Some((p.pid, status)),
+        _ => None,
+    });
+    if let Some((pid, _)) = done {
+        table.remove(&pid);
+    }
+    Ok(done)
+}
+
+/// Block the running process in `wait` until a matching child exits. The
+/// caller's registers are saved from `frame`; the call completes when the
+/// child's exit requeues the process.
+pub fn wait_block(frame: &SyscallFrame, child: Option<Pid>, status_ptr: u64) -> ! {
+    let proc = current_process().expect("wait_block outside a process");
+    *proc.context.lock() = frame.user_context(0);
+    proc.times.clear_hog();
+    proc.set_state(ProcessState::Waiting { child, status_ptr });
+    drop(proc);
+    suspend_current()
+}
+
+/// Wait for `pid` to exit, then remove it from the process table and
+/// return its status.
+pub async fn wait_process(pid: Pid) -> Result<i32, ProcessError> {
+    let proc = find_process(pid).ok_or(ProcessError::NoSuchProcess)?;
+    let status = proc
+        .exited
+        .wait_until(|| match proc.state() {
+            ProcessState::Exited(s) => Some(s),
+            _ => None,
+        })
+        .await;
+    PROCESSES.lock().remove(&pid);
+    Ok(status)
+}
+
+/// The program named by `init=PATH` on the kernel command line, with the
+/// words after `--` as its arguments. Other `key=value` words that aren't
+/// kernel options are passed to it as environment, after `DEFAULT_ENV`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitCommand {
+    pub path: String,
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
+}
+
+pub fn parse_init_command(cmdline: &str) -> Option<InitCommand> {
+    let mut words = cmdline.split_whitespace();
+    let mut path = None;
+    let mut envp: Vec<String> = DEFAULT_ENV.iter().map(|s| String::from(*s)).collect();
+    for word in words.by_ref() {
+        if word == "--" {
+            break;
+        }
+        match word.split_once('=') {
+            Some(("init", p)) if !p.is_empty() => path = Some(String::from(p)),
+            Some((key, _)) if !key.contains('.') && !matches!(key, "init" | "trace" | "crashdump" | "fault") => envp.push(String::from(word)),
+            _ => {}
+        }
+    }
+    let path = path?;
+    let argv = core::iter::once(path.clone()).chain(words.map(String::from)).collect();
+    Some(InitCommand { path, argv, envp })
+}
+
+/// Start the program named on the kernel command line, if there is one.
+pub fn spawn_init(cmdline: &str) -> Option<Result<Pid, ProcessError>> {
+    let cmd = parse_init_command(cmdline)?;
+    let argv: Vec<&str> = cmd.argv.iter().map(|s| s.as_str()).collect();
+    let envp: Vec<&str> = cmd.envp.iter().map(|s| s.as_str()).collect();
+    Some(spawn_process(&cmd.path, &argv, &envp, None))
+}
+
+/// Wire the process layer into the syscall table and start the scheduler.
+/// Call once, on the BSP, after paging and the VFS are up.
+pub fn init_processes() {
+    init_kernel_template();
+    crate::arch::syscall::set_exit_hook(exit_current);
+    crate::devices::tty::set_tty_interrupt_hook(|| signal_current(SIGINT));
+    crate::arch::syscall::set_spawn_hook(|path, argv, envp| {
+        let parent = current_process().map(|p| p.pid());
+        spawn_process(path, argv, envp, parent).map_err(|e| e.errno())
+    });
+    start_scheduler();
+}
@@ -0,0 +1,136 @@
+//! Process scheduler
+//!
+//! Ready processes wait in a FIFO queue drained by the `procsched` task on
+//! the BSP (the CPU whose MSRs route `syscall`). The task switches to the
+//! process's address space and drops to ring 3; the process then runs until
+//! it exits, faults or blocks, at which point control unwinds back into the
+//! task. A blocked process has its registers saved and is requeued by
+//! whatever it waits for.
+//! Processes mostly don't preempt one another: one runs until it blocks
+//! before the next starts, and other executor tasks wait meanwhile
+//! (interrupts still arrive). The exception is a CPU hog, which the timer
+//! takes off the CPU in ring 3 every `HOG_TURN_MS` (`preempt_user`) and puts
+//! back at the end of the run queue.
+
+use crate::prelude::*;
+use crate::arch::usermode::UserContext;
+use crate::process::{activate_kernel_space, deliver_signals, deprioritized, signal_name, signal_status, turn_over, Process, ProcessState};
+use crate::sync::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+static RUN_QUEUE: Mutex<VecDeque<Arc<Process>>> = Mutex::new(VecDeque::new());
+static READY: WaitQueue = WaitQueue::new();
+static CURRENT: Mutex<Option<Arc<Process>>> = Mutex::new(None);
+/// Kernel stack pointer saved on entry to the running process.
+static mut LAUNCH_RSP: u64 = 0;
+
+pub fn enqueue_process(proc: Arc<Process>) {
+    interrupts::without_interrupts(|| RUN_QUEUE.lock().push_back(proc));
+    READY.wake_one();
+}
+
+/// Processes waiting for their turn.
+pub fn ready_count() -> usize {
+    interrupts::without_interrupts(|| RUN_QUEUE.lock().len())
+}
+
+/// The process whose code is running, if any.
+pub fn current_process() -> Option<Arc<Process>> {
+    interrupts::without_interrupts(|| CURRENT.lock().clone())
+}
+
+pub(crate) fn start_scheduler() {
+    crate::arch::task::spawn_named_on(0, "procsched", async {
+        loop {
+            let next = READY.wait_until(|| interrupts::without_interrupts(|| pop_next(&mut RUN_QUEUE.lock()))).await;
+            run(next);
+            crate::arch::task::yield_now().await;
+        }
+    });
+}
+
+/// The first ready process that isn't a CPU hog, else the first hog.
+fn pop_next(queue: &mut VecDeque<Arc<Process>>) -> Option<Arc<Process>> {
+    let at = queue.iter().position(|p| !deprioritized(p)).unwrap_or(0);
+    queue.remove(at)
+}
+
+fn run(proc: Arc<Process>) {
+    let activated = proc.with_space(|space| space.activate()).is_some();
+    if !activated {
+        return;
+    }
+    let mut context = proc.context();
+    if let Err(sig) = deliver_signals(&proc, &mut context) {
+        activate_kernel_space();
+        println!("[PROC] pid {} ({}) killed by {}", proc.pid(), proc.name(), signal_name(sig));
+        proc.finish(signal_status(sig));
+        return;
+    }
+    interrupts::without_interrupts(|| *CURRENT.lock() = Some(proc.clone()));
+    proc.set_state(ProcessState::Running);
+    // every entry starts from the top of this CPU's entry stack
+    let cpu = crate::arch::task::current_cpu();
+    crate::arch::stackguard::check_stack_canaries(cpu);
+    crate::arch::percpu::set_kernel_stack(crate::arch::percpu::entry_stack_top(cpu));
+    trace!(crate::trace::Subsys::Sched, "run pid {} rip {:#x}", proc.pid(), context.rip);
+    proc.times().slice_begin();
+    let status = unsafe { crate::arch::usermode::enter_user(&context, &raw mut LAUNCH_RSP) } as i32;
+    proc.times().slice_end();
+    crate::arch::stackguard::check_stack_canaries(cpu);
+    trace!(crate::trace::Subsys::Sched, "left pid {} ({})", proc.pid(), proc.state());
+    // back from exit, a fault or a blocking call, still on the process's
+    // tables with interrupts masked
+    activate_kernel_space();
+    interrupts::without_interrupts(|| *CURRENT.lock() = None);
+    interrupts::enable();
+    // suspended rather than finished: blocked, or requeued to handle a fault
+    if let ProcessState::Waiting { .. } | ProcessState::Ready = proc.state() {
+        return;
+    }
+    let peak_kib = proc.with_space(|space| space.usage().peak_pages * 4).unwrap_or(0);
+    println!("[PROC] pid {} ({}) exited with status {} (peak {} KiB)", proc.pid(), proc.name(), status, peak_kib);
+    proc.finish(status);
+}
+
+/// End the running process with `status`. Called from the `exit` syscall.
+pub fn exit_current(status: i32) -> ! {
+    interrupts::disable();
+    unsafe { crate::arch::usermode::leave_user(LAUNCH_RSP, status as u32 as u64) }
+}
+
+/// Give up the CPU without exiting. The caller has already saved the
+/// process's context and moved it out of the running state; whoever wakes
+/// it puts it back on the run queue.
+pub(crate) fn suspend_current() -> ! {
+    interrupts::disable();
+    unsafe { crate::arch::usermode::leave_user(LAUNCH_RSP, 0) }
+}
+
+/// Called by the timer interrupt from ring 3 with the registers it
+/// interrupted. A hog that has had its turn is requeued with them, and
+/// doesn't return; anything else carries on.
+pub(crate) fn preempt_user(context: &UserContext) {
+    let Some(proc) = current_process() else { return };
+    if !turn_over(&proc) {
+        return;
+    }
+    trace!(crate::trace::Subsys::Sched, "preempt pid {} at {:#x}", proc.pid(), context.rip);
+    *proc.context.lock() = *context;
+    proc.set_state(ProcessState::Ready);
+    enqueue_process(proc);
+    suspend_current()
+}
+
+/// Back a demand-paged page for the running process. Called by the page
+/// fault handler for not-present faults from user mode; false means the
+/// access was invalid.
+pub fn handle_user_page_fault(addr: u64, write: bool) -> bool {
+    match current_process() {
+        Some(proc) => proc.with_space(|space| space.handle_fault(addr, write)).unwrap_or(false),
+        None => false,
+    }
+}
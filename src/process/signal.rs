@@ -0,0 +1,324 @@
+//! Signals
+//!
+//! A small subset of Unix signals: a process can be sent one with `kill`,
+//! gets `SIGSEGV`, `SIGILL` or `SIGFPE` when it faults, and the process on
+//! the console gets `SIGINT` when Ctrl+C is typed. Every signal here ends
+//! the process by default (exit status 128 + the signal number); a process
+//! can ignore one, or register a handler with `sigaction`.
+//!
+//! Signals are acted on when the process is about to return to ring 3:
+//! after a system call, when the scheduler resumes it, and - for signals
+//! whose action ends the process - when an interrupt arrives while it
+//! runs, so a program spinning in a loop can still be stopped. A handler
+//! runs on the process's stack with the interrupted registers saved below
+//! it; it is called as `handler(sig, info)`, where `info` is the faulting
+//! address for fault signals and 0 otherwise, and returns into the
+//! `restorer` given to `sigaction`, which must make the `sigreturn` call.
+//!
+//! Faults are reported from exception handlers, which don't see the general
+//! registers, so a fault handler's saved context holds only the
+//! instruction and stack pointers. Its handler is reset to the default when
+//! it runs: returning retries the faulting instruction, and a second fault
+//! ends the process.
+
+use crate::prelude::*;
+use crate::arch::usermode::UserContext;
+use crate::process::{current_process, enqueue_process, exit_current, find_process, suspend_current, Pid, Process, ProcessError, ProcessState};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub type Signal = u32;
+
+/// Signal numbers. Values match Linux.
+pub const SIGINT: Signal = 2;
+pub const SIGILL: Signal = 4;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGUSR1: Signal = 10;
+pub const SIGSEGV: Signal = 11;
+pub const SIGUSR2: Signal = 12;
+pub const SIGTERM: Signal = 15;
+
+/// Signals that exist: `kill` and `sigaction` reject anything else.
+const SUPPORTED: u32 = (1 << SIGINT) | (1 << SIGILL) | (1 << SIGFPE) | (1 << SIGKILL) | (1 << SIGUSR1) | (1 << SIGSEGV) | (1 << SIGUSR2) | (1 << SIGTERM);
+const NSIG: usize = 32;
+
+/// `sigaction` handler values with special meaning.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Bytes below the interrupted stack pointer left alone when a handler
+/// frame is pushed (the System V red zone).
+const RED_ZONE: u64 = 128;
+
+pub fn signal_name(sig: Signal) -> &'static str {
+    match sig {
+        SIGINT => "SIGINT",
+        SIGILL => "SIGILL",
+        SIGFPE => "SIGFPE",
+        SIGKILL => "SIGKILL",
+        SIGUSR1 => "SIGUSR1",
+        SIGSEGV => "SIGSEGV",
+        SIGUSR2 => "SIGUSR2",
+        SIGTERM => "SIGTERM",
+        _ => "unknown signal",
+    }
+}
+
+/// Exit status of a process ended by `sig`, as shells report it.
+pub fn signal_status(sig: Signal) -> i32 {
+    128 + sig as i32
+}
+
+fn supported(sig: Signal) -> bool {
+    (sig as usize) < NSIG && SUPPORTED & (1 << sig) != 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN` or the handler's address.
+    pub handler: u64,
+    /// Where the handler returns to; calls `sigreturn`.
+    pub restorer: u64,
+}
+
+/// Per-process signal state.
+pub struct Signals {
+    pending: AtomicU32,
+    /// Faulting address passed to a fault signal's handler.
+    fault_addr: AtomicU64,
+    actions: Mutex<[SigAction; NSIG]>,
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            pending: AtomicU32::new(0),
+            fault_addr: AtomicU64::new(0),
+            actions: Mutex::new([SigAction { handler: SIG_DFL, restorer: 0 }; NSIG]),
+        }
+    }
+
+    /// Bitmask of signals raised but not yet acted on.
+    pub fn pending(&self) -> u32 {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn action(&self, sig: Signal) -> SigAction {
+        interrupts::without_interrupts(|| self.actions.lock()[sig as usize])
+    }
+
+    /// Replace the action for `sig`, returning the old one.
+    pub fn set_action(&self, sig: Signal, action: SigAction) -> Result<SigAction, ProcessError> {
+        if !supported(sig) || sig == SIGKILL {
+            return Err(ProcessError::InvalidSignal);
+        }
+        Ok(interrupts::without_interrupts(|| core::mem::replace(&mut self.actions.lock()[sig as usize], action)))
+    }
+
+    /// Mark `sig` pending unless it is ignored.
+    fn raise(&self, sig: Signal) -> bool {
+        if sig != SIGKILL && self.action(sig).handler == SIG_IGN {
+            return false;
+        }
+        self.pending.fetch_or(1 << sig, Ordering::AcqRel);
+        true
+    }
+
+    /// Take the lowest pending signal.
+    fn take(&self) -> Option<Signal> {
+        let mut pending = self.pending.load(Ordering::Acquire);
+        while pending != 0 {
+            let sig = pending.trailing_zeros();
+            match self.pending.compare_exchange(pending, pending & !(1 << sig), Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(sig),
+                Err(now) => pending = now,
+            }
+        }
+        None
+    }
+
+    /// A child's signal state after `fork`: the same actions, nothing pending.
+    pub(crate) fn inherit(&self) -> Signals {
+        let actions = interrupts::without_interrupts(|| *self.actions.lock());
+        Signals { pending: AtomicU32::new(0), fault_addr: AtomicU64::new(0), actions: Mutex::new(actions) }
+    }
+
+    /// After `exec` the old handlers are gone; ignored signals stay ignored.
+    pub(crate) fn reset_handlers(&self) {
+        interrupts::without_interrupts(|| {
+            for action in self.actions.lock().iter_mut() {
+                if action.handler != SIG_IGN {
+                    *action = SigAction::default();
+                }
+            }
+        });
+    }
+}
+
+/// Send `sig` to process `pid`. Signal 0 only checks that the process
+/// exists. A process blocked in `wait` is woken, and the call fails with
+/// `EINTR` once the signal has been dealt with.
+pub fn send_signal(pid: Pid, sig: Signal) -> Result<(), ProcessError> {
+    let proc = find_process(pid).ok_or(ProcessError::NoSuchProcess)?;
+    if sig == 0 {
+        return Ok(());
+    }
+    if !supported(sig) {
+        return Err(ProcessError::InvalidSignal);
+    }
+    if let ProcessState::Exited(_) = proc.state() {
+        return Ok(());
+    }
+    if proc.signals.raise(sig) {
+        interrupt_wait(&proc);
+    }
+    Ok(())
+}
+
+/// Send `sig` to the process running on the CPU, if there is one. Used for
+/// Ctrl+C on the console.
+pub fn signal_current(sig: Signal) {
+    if let Some(proc) = current_process() {
+        proc.signals.raise(sig);
+    }
+}
+
+fn interrupt_wait(proc: &Arc<Process>) {
+    let woken = interrupts::without_interrupts(|| {
+        let mut state = proc.state.lock();
+        if let ProcessState::Waiting { .. } = *state {
+            *state = ProcessState::Ready;
+            true
+        } else {
+            false
+        }
+    });
+    if woken {
+        proc.context.lock().rax = (-crate::arch::syscall::EINTR) as u64;
+        enqueue_process(proc.clone());
+    }
+}
+
+/// Act on `proc`'s pending signals before it returns to user mode with
+/// `ctx`. Ignored signals are dropped, and at most one handler frame is
+/// pushed (the rest wait until the handler has returned to the kernel).
+/// `Err` carries a signal whose action ends the process.
+pub(crate) fn deliver_signals(proc: &Process, ctx: &mut UserContext) -> Result<(), Signal> {
+    while let Some(sig) = proc.signals.take() {
+        let action = proc.signals.action(sig);
+        match action.handler {
+            _ if sig == SIGKILL => return Err(sig),
+            SIG_IGN => continue,
+            SIG_DFL => return Err(sig),
+            _ => {}
+        }
+        let info = match sig {
+            SIGSEGV | SIGILL | SIGFPE => {
+                // a second fault from the handler ends the process
+                let _ = proc.signals.set_action(sig, SigAction::default());
+                proc.signals.fault_addr.swap(0, Ordering::Relaxed)
+            }
+            _ => 0,
+        };
+        if !push_handler_frame(proc, ctx, sig, info, action) {
+            return Err(SIGSEGV);
+        }
+        return Ok(());
+    }
+    Ok(())
+}
+
+/// Save `ctx` on the user stack and point it at the handler instead.
+fn push_handler_frame(proc: &Process, ctx: &mut UserContext, sig: Signal, info: u64, action: SigAction) -> bool {
+    const CONTEXT_SIZE: u64 = core::mem::size_of::<UserContext>() as u64;
+    let Some(saved_at) = ctx.rsp.checked_sub(RED_ZONE + CONTEXT_SIZE).map(|a| a & !15) else {
+        return false;
+    };
+    let return_at = saved_at - 8;
+    let saved = unsafe { core::slice::from_raw_parts((ctx as *const UserContext).cast::<u8>(), CONTEXT_SIZE as usize) };
+    let written = proc
+        .with_space(|space| space.write(saved_at, saved).and_then(|_| space.write(return_at, &action.restorer.to_le_bytes())))
+        .is_some_and(|r| r.is_ok());
+    if !written {
+        return false;
+    }
+    let mut handler_ctx = UserContext::new(action.handler, return_at);
+    handler_ctx.rdi = sig as u64;
+    handler_ctx.rsi = info;
+    handler_ctx.rdx = saved_at;
+    handler_ctx.rflags = ctx.rflags;
+    *ctx = handler_ctx;
+    true
+}
+
+/// Called by the syscall layer just before it returns to user mode.
+pub fn deliver_on_syscall_return(frame: &mut crate::arch::syscall::SyscallFrame) {
+    let Some(proc) = current_process() else { return };
+    if proc.signals.pending() == 0 {
+        return;
+    }
+    let mut ctx = frame.user_context(frame.rax);
+    match deliver_signals(&proc, &mut ctx) {
+        Ok(()) => {
+            frame.set_user_context(&ctx);
+            frame.rax = ctx.rax;
+        }
+        Err(sig) => {
+            drop(proc);
+            kill_current(sig)
+        }
+    }
+}
+
+/// Called at the end of an interrupt that arrived in user mode. No kernel
+/// code was interrupted, so this can take locks freely: keystrokes are
+/// handed to the console (which is how Ctrl+C is noticed while a process
+/// runs), and a pending signal that ends the process takes effect now.
+/// Handlers wait for the next system call or reschedule.
+pub fn interrupted_user() {
+    crate::devices::tty::tty_pump();
+    let Some(proc) = current_process() else { return };
+    let pending = proc.signals.pending();
+    let fatal = (0..NSIG as Signal)
+        .filter(|&sig| pending & (1 << sig) != 0)
+        .find(|&sig| sig == SIGKILL || proc.signals.action(sig).handler == SIG_DFL);
+    drop(proc);
+    if let Some(sig) = fatal {
+        kill_current(sig);
+    }
+}
+
+/// End the running process because of `sig`.
+pub fn kill_current(sig: Signal) -> ! {
+    if let Some(proc) = current_process() {
+        println!("[PROC] pid {} ({}) killed by {}", proc.pid(), proc.name(), signal_name(sig));
+    }
+    exit_current(signal_status(sig))
+}
+
+/// Report a fault in user mode as `sig`. Exception handlers call this only
+/// when the faulting code was running in ring 3; `rip`, `rsp` and `rflags`
+/// come from the exception frame.
+pub fn user_fault(what: &str, addr: u64, sig: Signal, rip: u64, rsp: u64, rflags: u64) -> ! {
+    let proc = match current_process() {
+        Some(proc) => proc,
+        None => panic!("{} in user mode at {:#x} with no process running", what, addr),
+    };
+    println!("[PROC] pid {} ({}): {} at {:#x}", proc.pid(), proc.name(), what, addr);
+    let action = proc.signals.action(sig);
+    if action.handler == SIG_DFL || action.handler == SIG_IGN {
+        drop(proc);
+        kill_current(sig);
+    }
+    proc.signals.fault_addr.store(addr, Ordering::Relaxed);
+    proc.signals.pending.fetch_or(1 << sig, Ordering::AcqRel);
+    let mut ctx = UserContext::new(rip, rsp);
+    ctx.rflags = rflags;
+    *proc.context.lock() = ctx;
+    proc.set_state(ProcessState::Ready);
+    enqueue_process(proc);
+    suspend_current()
+}
@@ -0,0 +1,187 @@
+//! Ring-3 smoke test
+//!
+//! A tiny position-independent program assembled into the kernel image and
+//! wrapped in an ELF header at run time. It checks that `write`, `sleep`,
+//! `read`, `brk` (with a demand-paged touch) and the unknown-call path
+//! behave, prints a verdict and exits with 0, or with the number of the
+//! first failing check. Run at boot when QEMU is given
+//! `-fw_cfg name=opt/neutrix/run_user_test,string=1`, so a change anywhere
+//! on the syscall/process path shows up as a failed boot.
+
+use crate::prelude::*;
+use crate::process::{spawn_image, wait_process, DEFAULT_ENV};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+
+/// fw_cfg file that turns the test on.
+pub const RUN_USER_TEST_FW_CFG_NAME: &str = "opt/neutrix/run_user_test";
+
+/// Where the program is linked; any top-level slot the kernel leaves free.
+const SMOKE_BASE: u64 = 0x0000_6000_0000_0000;
+
+global_asm!(
+    ".pushsection .rodata.neutrix_smoketest, \"a\"",
+    ".global neutrix_smoketest_start",
+    ".global neutrix_smoketest_end",
+    "neutrix_smoketest_start:",
+    // 1: banner to stdout, all bytes written
+    "mov eax, 2",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsmoke_hello]",
+    "lea rdx, [rip + .Lsmoke_hello_end]",
+    "sub rdx, rsi",
+    "mov rbx, rdx",
+    "syscall",
+    "mov r12d, 1",
+    "cmp rax, rbx",
+    "jne .Lsmoke_fail",
+    // 2: sleep 10 ms
+    "mov eax, 5",
+    "mov edi, 10",
+    "syscall",
+    "mov r12d, 2",
+    "test rax, rax",
+    "jne .Lsmoke_fail",
+    // 3: read from a descriptor that isn't open -> -EBADF
+    "sub rsp, 16",
+    "mov eax, 1",
+    "mov edi, 99",
+    "mov rsi, rsp",
+    "mov edx, 16",
+    "syscall",
+    "add rsp, 16",
+    "mov r12d, 3",
+    "cmp rax, -9",
+    "jne .Lsmoke_fail",
+    // 4: write from a kernel address -> -EFAULT
+    "mov eax, 2",
+    "mov edi, 1",
+    "movabs rsi, 0xffff800000000000",
+    "mov edx, 16",
+    "syscall",
+    "mov r12d, 4",
+    "cmp rax, -14",
+    "jne .Lsmoke_fail",
+    // 5: grow the heap by a page and touch it
+    "mov eax, 7",
+    "xor edi, edi",
+    "syscall",
+    "mov rbx, rax",
+    "lea rdi, [rax + 4096]",
+    "mov eax, 7",
+    "syscall",
+    "mov r12d, 5",
+    "lea rcx, [rbx + 4096]",
+    "cmp rax, rcx",
+    "jne .Lsmoke_fail",
+    "mov qword ptr [rbx], 0x5a",
+    "cmp qword ptr [rbx], 0x5a",
+    "jne .Lsmoke_fail",
+    // 6: unknown call -> -ENOSYS
+    "mov eax, 999",
+    "syscall",
+    "mov r12d, 6",
+    "cmp rax, -38",
+    "jne .Lsmoke_fail",
+    // pass
+    "mov eax, 2",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsmoke_pass]",
+    "lea rdx, [rip + .Lsmoke_pass_end]",
+    "sub rdx, rsi",
+    "syscall",
+    "xor eax, eax",
+    "xor edi, edi",
+    "syscall",
+    ".Lsmoke_fail:",
+    "mov eax, 2",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsmoke_failed]",
+    "lea rdx, [rip + .Lsmoke_failed_end]",
+    "sub rdx, rsi",
+    "syscall",
+    "xor eax, eax",
+    "mov edi, r12d",
+    "syscall",
+    "ud2",
+    ".Lsmoke_hello: .ascii \"[SMOKE] hello from ring 3\\n\"",
+    ".Lsmoke_hello_end:",
+    ".Lsmoke_pass: .ascii \"[SMOKE] all checks passed\\n\"",
+    ".Lsmoke_pass_end:",
+    ".Lsmoke_failed: .ascii \"[SMOKE] check failed\\n\"",
+    ".Lsmoke_failed_end:",
+    "neutrix_smoketest_end:",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static neutrix_smoketest_start: u8;
+    static neutrix_smoketest_end: u8;
+}
+
+fn smoke_test_code() -> &'static [u8] {
+    unsafe {
+        let start = &raw const neutrix_smoketest_start;
+        let end = &raw const neutrix_smoketest_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// The test program as a static executable: one read-only, executable
+/// segment at `SMOKE_BASE`, entered at its first byte.
+pub fn smoke_test_elf() -> Vec<u8> {
+    const EHDR_LEN: u64 = 64;
+    const PHDR_LEN: u64 = 56;
+    let code = smoke_test_code();
+    let offset = EHDR_LEN + PHDR_LEN;
+    let mut elf = Vec::with_capacity(offset as usize + code.len());
+    // ELF header
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, SysV
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86-64
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&SMOKE_BASE.to_le_bytes()); // entry
+    elf.extend_from_slice(&EHDR_LEN.to_le_bytes()); // phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // shoff
+    elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    elf.extend_from_slice(&(EHDR_LEN as u16).to_le_bytes());
+    elf.extend_from_slice(&(PHDR_LEN as u16).to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes()); // phnum
+    elf.extend_from_slice(&[0; 6]); // no section headers
+    // PT_LOAD, R+X
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&5u32.to_le_bytes());
+    elf.extend_from_slice(&offset.to_le_bytes());
+    elf.extend_from_slice(&SMOKE_BASE.to_le_bytes()); // vaddr
+    elf.extend_from_slice(&SMOKE_BASE.to_le_bytes()); // paddr
+    elf.extend_from_slice(&(code.len() as u64).to_le_bytes()); // filesz
+    elf.extend_from_slice(&(code.len() as u64).to_le_bytes()); // memsz
+    elf.extend_from_slice(&0x1000u64.to_le_bytes()); // align
+    elf.extend_from_slice(code);
+    elf
+}
+
+/// Whether the boot configuration asks for the test.
+pub fn run_user_test_requested() -> bool {
+    crate::devices::fw_cfg::read_file(RUN_USER_TEST_FW_CFG_NAME).is_some_and(|v| !v.is_empty() && v[0] != b'0')
+}
+
+/// Start the smoke test and report its verdict when it exits.
+pub fn run_user_test() {
+    let pid = match spawn_image("smoketest", &smoke_test_elf(), &["smoketest"], DEFAULT_ENV, None) {
+        Ok(pid) => pid,
+        Err(e) => {
+            println!("[SMOKE] FAIL: could not start: {}", e);
+            return;
+        }
+    };
+    crate::arch::task::spawn_named("smoketest", async move {
+        match wait_process(pid).await {
+            Ok(0) => println!("[SMOKE] PASS"),
+            Ok(status) => println!("[SMOKE] FAIL: exit status {}", status),
+            Err(e) => println!("[SMOKE] FAIL: {}", e),
+        }
+    });
+}
@@ -0,0 +1,228 @@
+//! Kernel random numbers
+//!
+//! `fill` and `u64` draw from a ChaCha20 generator. After every request
+//! the generator replaces its key with fresh keystream, so output already
+//! handed out can't be recomputed from a later copy of its state.
+//!
+//! The key is first made from RDSEED, RDRAND (when the CPU has them) and
+//! the jitter of timing short loops with the TSC. After that, interrupt
+//! arrival times and whatever drivers pass to `add_entropy` are xored
+//! into a small pool without locks, so the interrupt path stays cheap; a
+//! request folds the pool into the key, together with new RDSEED/RDRAND
+//! output, once `RESEED_EVENTS` samples have arrived and at most once per
+//! `RESEED_INTERVAL_NS`.
+//!
+//! Nothing here blocks: the first request seeds the generator if `init`
+//! hasn't run yet.
+
+pub mod chacha;
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::rdtsc;
+use chacha::chacha20_block;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// New pool samples needed before a reseed.
+pub const RESEED_EVENTS: u32 = 64;
+pub const RESEED_INTERVAL_NS: u64 = 1_000_000_000;
+/// Bytes generated per hold of the generator lock.
+const FILL_CHUNK: usize = 256;
+/// TSC jitter samples folded into each seed word at boot.
+const JITTER_SAMPLES: usize = 64;
+/// Nonces separate output blocks from the blocks that make the next key.
+const NONCE_OUTPUT: u64 = 0;
+const NONCE_REKEY: u64 = 1;
+const NONCE_RESEED: u64 = 2;
+
+const HW_PROBED: u8 = 1;
+const HW_RDRAND: u8 = 2;
+const HW_RDSEED: u8 = 4;
+
+static POOL: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// Samples added to `POOL` since the last reseed.
+static EVENTS: AtomicU32 = AtomicU32::new(0);
+static HW: AtomicU8 = AtomicU8::new(0);
+
+struct Crng {
+    key: [u32; 8],
+    counter: u64,
+    seeded: bool,
+    reseeds: u64,
+    last_reseed_ns: u64,
+}
+
+static CRNG: Mutex<Crng> = Mutex::new(Crng { key: [0; 8], counter: 0, seeded: false, reseeds: 0, last_reseed_ns: 0 });
+
+fn hw() -> u8 {
+    let mut hw = HW.load(Ordering::Relaxed);
+    if hw & HW_PROBED == 0 {
+        let feats = crate::arch::detect_cpu_features();
+        hw = HW_PROBED | if feats.rdrand { HW_RDRAND } else { 0 } | if feats.rdseed { HW_RDSEED } else { 0 };
+        HW.store(hw, Ordering::Relaxed);
+    }
+    hw
+}
+
+fn rdrand() -> Option<u64> {
+    if hw() & HW_RDRAND == 0 {
+        return None;
+    }
+    // RDRAND can run dry briefly; Intel suggests ten tries
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe { core::arch::asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdseed() -> Option<u64> {
+    if hw() & HW_RDSEED == 0 {
+        return None;
+    }
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe { core::arch::asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Fold the timing noise of short memory loops into one word.
+fn tsc_jitter() -> u64 {
+    let mut scratch = [0u64; 16];
+    let mut acc = 0u64;
+    let mut prev = rdtsc();
+    for i in 0..JITTER_SAMPLES {
+        for (j, s) in scratch.iter_mut().enumerate() {
+            unsafe { core::ptr::write_volatile(s, acc ^ (i * j) as u64) };
+        }
+        let now = rdtsc();
+        acc = acc.rotate_left(7) ^ now.wrapping_sub(prev);
+        prev = now;
+    }
+    acc
+}
+
+fn mix(sample: u64) {
+    let n = EVENTS.fetch_add(1, Ordering::Relaxed);
+    POOL[n as usize % POOL.len()].fetch_xor(sample.rotate_left(n % 64), Ordering::Relaxed);
+}
+
+/// Mix an interrupt's arrival time into the pool. Called from `note_irq`.
+pub fn add_interrupt_timing(vector: u8) {
+    mix(rdtsc() ^ (vector as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+}
+
+/// Mix `data` (device serial numbers, packet timings, bytes written to
+/// `urandom`) into the pool. It is not assumed to be secret.
+pub fn add_entropy(data: &[u8]) {
+    for chunk in data.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        mix(u64::from_le_bytes(word) ^ rdtsc());
+    }
+}
+
+/// Replace the key with one derived from it, the pool, and whatever the
+/// CPU offers.
+fn reseed(crng: &mut Crng, boot: bool) {
+    let mut seed = [0u64; 8];
+    for (i, word) in seed.iter_mut().enumerate() {
+        *word = POOL[i % POOL.len()].swap(0, Ordering::Relaxed) ^ rdtsc().rotate_left(i as u32 * 8);
+        if let Some(r) = rdseed().or_else(rdrand) {
+            *word ^= r;
+        }
+        if boot {
+            *word ^= tsc_jitter();
+        }
+    }
+    EVENTS.store(0, Ordering::Relaxed);
+    let mut key = crng.key;
+    for (i, k) in key.iter_mut().enumerate() {
+        *k ^= (seed[i / 2] >> (32 * (i % 2))) as u32;
+    }
+    let block = chacha20_block(&key, crng.reseeds, NONCE_RESEED ^ seed[4] ^ seed[5].rotate_left(17) ^ seed[6] ^ seed[7].rotate_left(41));
+    crng.key.copy_from_slice(&block[..8]);
+    crng.counter = 0;
+    crng.seeded = true;
+    crng.reseeds += 1;
+    crng.last_reseed_ns = crate::time::uptime_ns();
+}
+
+fn with_crng<R>(f: impl FnOnce(&mut Crng) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut crng = CRNG.lock();
+        if !crng.seeded {
+            reseed(&mut crng, true);
+        } else if EVENTS.load(Ordering::Relaxed) >= RESEED_EVENTS
+            && crate::time::uptime_ns().saturating_sub(crng.last_reseed_ns) >= RESEED_INTERVAL_NS
+        {
+            reseed(&mut crng, false);
+        }
+        f(&mut crng)
+    })
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for part in buf.chunks_mut(FILL_CHUNK) {
+        with_crng(|crng| {
+            for out in part.chunks_mut(64) {
+                let block = chacha20_block(&crng.key, crng.counter, NONCE_OUTPUT);
+                crng.counter += 1;
+                for (dst, src) in out.iter_mut().zip(block.iter().flat_map(|w| w.to_le_bytes())) {
+                    *dst = src;
+                }
+            }
+            let next = chacha20_block(&crng.key, crng.counter, NONCE_REKEY);
+            crng.counter += 1;
+            crng.key.copy_from_slice(&next[..8]);
+        });
+    }
+}
+
+pub fn u64() -> u64 {
+    let mut b = [0u8; 8];
+    fill(&mut b);
+    u64::from_le_bytes(b)
+}
+
+/// What the generator has to work with.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomStatus {
+    pub rdrand: bool,
+    pub rdseed: bool,
+    pub reseeds: u64,
+    /// Samples in the pool waiting for the next reseed.
+    pub pending: u32,
+}
+
+pub fn random_status() -> RandomStatus {
+    let hw = hw();
+    let reseeds = interrupts::without_interrupts(|| CRNG.lock().reseeds);
+    RandomStatus {
+        rdrand: hw & HW_RDRAND != 0,
+        rdseed: hw & HW_RDSEED != 0,
+        reseeds,
+        pending: EVENTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Seed the generator now rather than on first use, and say from what.
+pub fn init() {
+    with_crng(|_| {});
+    let hw = hw();
+    println!(
+        "[RANDOM] seeded from {}{}TSC jitter",
+        if hw & HW_RDSEED != 0 { "RDSEED, " } else { "" },
+        if hw & HW_RDRAND != 0 { "RDRAND, " } else { "" }
+    );
+}
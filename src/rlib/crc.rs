@@ -0,0 +1,87 @@
+//! CRC32 and CRC32C
+//!
+//! `crc32` is the IEEE polynomial used by GPT headers, Ethernet, zlib and
+//! most archive formats; `crc32c` is Castagnoli's, used by ext4, btrfs,
+//! iSCSI and SCTP. Both take and return the finished value, so a long
+//! input can be fed in pieces: `crc32(b, crc32(a, 0))` equals the CRC of
+//! `a` followed by `b`. CRC32C uses the SSE4.2 `crc32` instruction when
+//! the CPU has it.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const CRC32_POLY: u32 = 0xEDB8_8320;
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const fn make_table(poly: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ poly } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_table(CRC32_POLY);
+static CRC32C_TABLE: [u32; 256] = make_table(CRC32C_POLY);
+
+fn update_table(table: &[u32; 256], mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc = table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// CRC32 (IEEE) of `data`, continuing from `crc` (0 to start).
+pub fn crc32(data: &[u8], crc: u32) -> u32 {
+    !update_table(&CRC32_TABLE, !crc, data)
+}
+
+const SSE42_PROBED: u8 = 1;
+const SSE42_PRESENT: u8 = 2;
+static SSE42: AtomicU8 = AtomicU8::new(0);
+
+fn have_sse42() -> bool {
+    let mut state = SSE42.load(Ordering::Relaxed);
+    if state == 0 {
+        state = SSE42_PROBED | if crate::arch::detect_cpu_features().sse4_2 { SSE42_PRESENT } else { 0 };
+        SSE42.store(state, Ordering::Relaxed);
+    }
+    state & SSE42_PRESENT != 0
+}
+
+#[target_feature(enable = "sse4.2")]
+fn crc32c_sse42(mut crc: u32, data: &[u8]) -> u32 {
+    use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut words = data.chunks_exact(8);
+    let mut crc64 = crc as u64;
+    for word in &mut words {
+        crc64 = _mm_crc32_u64(crc64, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    crc = crc64 as u32;
+    for &b in words.remainder() {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    crc
+}
+
+/// CRC32C (Castagnoli) of `data`, continuing from `crc` (0 to start).
+pub fn crc32c(data: &[u8], crc: u32) -> u32 {
+    if have_sse42() {
+        // checked above that the CPU has SSE4.2
+        !unsafe { crc32c_sse42(!crc, data) }
+    } else {
+        crc32c_soft(data, crc)
+    }
+}
+
+/// CRC32C without the SSE4.2 path, to check it against.
+pub fn crc32c_soft(data: &[u8], crc: u32) -> u32 {
+    !update_table(&CRC32C_TABLE, !crc, data)
+}
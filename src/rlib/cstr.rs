@@ -0,0 +1,67 @@
+//! C strings
+//!
+//! Firmware tables, tar headers and ELF string tables hold NUL-terminated
+//! strings, usually inside a fixed-size field. The slice functions stop at
+//! the first NUL or the end of the slice, whichever comes first, so a
+//! field that fills its whole width is still read safely; only `strlen`
+//! walks a raw pointer.
+
+use core::cmp::Ordering;
+
+/// Bytes before the NUL at `s`.
+///
+/// # Safety
+///
+/// `s` must point to readable memory that contains a NUL.
+pub unsafe fn strlen(s: *const u8) -> usize {
+    let mut len = 0;
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    len
+}
+
+/// Bytes before the first NUL, at most `max` and at most `s.len()`.
+pub fn strnlen(s: &[u8], max: usize) -> usize {
+    let s = &s[..max.min(s.len())];
+    s.iter().position(|&b| b == 0).unwrap_or(s.len())
+}
+
+/// `s` up to its first NUL.
+pub fn cstr_bytes(s: &[u8]) -> &[u8] {
+    &s[..strnlen(s, s.len())]
+}
+
+/// `s` up to its first NUL, or "" if that isn't UTF-8.
+pub fn cstr(s: &[u8]) -> &str {
+    core::str::from_utf8(cstr_bytes(s)).unwrap_or("")
+}
+
+/// The string starting at `offset` in a string table, as in ELF's
+/// `.strtab`; `None` if `offset` is outside it or the string isn't UTF-8.
+pub fn cstr_at(table: &[u8], offset: usize) -> Option<&str> {
+    core::str::from_utf8(cstr_bytes(table.get(offset..)?)).ok()
+}
+
+/// Compare two C strings bytewise, as `strcmp` does.
+pub fn strcmp(a: &[u8], b: &[u8]) -> Ordering {
+    cstr_bytes(a).cmp(cstr_bytes(b))
+}
+
+/// Compare at most `n` bytes of two C strings.
+pub fn strncmp(a: &[u8], b: &[u8], n: usize) -> Ordering {
+    let a = cstr_bytes(a);
+    let b = cstr_bytes(b);
+    a[..n.min(a.len())].cmp(&b[..n.min(b.len())])
+}
+
+/// Copy the C string `src` into `dst` and pad the rest of `dst` with NULs,
+/// like `strncpy`. Returns the bytes copied; if that is `dst.len()` there
+/// is no terminating NUL, again like `strncpy`.
+pub fn strncpy(dst: &mut [u8], src: &[u8]) -> usize {
+    let src = cstr_bytes(src);
+    let n = src.len().min(dst.len());
+    dst[..n].copy_from_slice(&src[..n]);
+    dst[n..].fill(0);
+    n
+}
@@ -0,0 +1,163 @@
+//! FNV-1a and SipHash
+//!
+//! FNV-1a is a few instructions per byte and fine for keys nobody outside
+//! the kernel chooses: interned names, device IDs. SipHash-2-4 takes a
+//! secret key, so an attacker who picks the keys (addresses and ports off
+//! the network, file names) can't steer them into one bucket. Both
+//! implement `core::hash::Hasher`; `FnvBuildHasher` and `SipBuildHasher`
+//! plug them into hash maps, the latter with a key from `random`.
+
+use core::hash::{BuildHasher, BuildHasherDefault, Hasher};
+
+pub const FNV32_OFFSET: u32 = 0x811C_9DC5;
+pub const FNV32_PRIME: u32 = 0x0100_0193;
+pub const FNV64_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+pub const FNV64_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+pub fn fnv1a_32(data: &[u8]) -> u32 {
+    data.iter().fold(FNV32_OFFSET, |h, &b| (h ^ b as u32).wrapping_mul(FNV32_PRIME))
+}
+
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(FNV64_OFFSET, |h, &b| (h ^ b as u64).wrapping_mul(FNV64_PRIME))
+}
+
+/// 64-bit FNV-1a as a `Hasher`.
+#[derive(Debug, Clone, Copy)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(FNV64_OFFSET)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(FNV64_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+/// SipHash-2-4 with a 128-bit key, as a `Hasher`.
+#[derive(Debug, Clone, Copy)]
+pub struct SipHasher {
+    v: [u64; 4],
+    /// Bytes not yet making up a whole word, low byte first.
+    tail: u64,
+    ntail: usize,
+    len: u64,
+}
+
+impl SipHasher {
+    pub fn new(k0: u64, k1: u64) -> SipHasher {
+        SipHasher {
+            v: [k0 ^ 0x736F_6D65_7073_6575, k1 ^ 0x646F_7261_6E64_6F6D, k0 ^ 0x6C79_6765_6E65_7261, k1 ^ 0x7465_6462_7974_6573],
+            tail: 0,
+            ntail: 0,
+            len: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        let v = &mut self.v;
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v[3] ^= m;
+        self.round();
+        self.round();
+        self.v[0] ^= m;
+    }
+}
+
+impl Hasher for SipHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        while self.ntail != 0 && !bytes.is_empty() {
+            self.tail |= (bytes[0] as u64) << (8 * self.ntail);
+            self.ntail = (self.ntail + 1) % 8;
+            bytes = &bytes[1..];
+            if self.ntail == 0 {
+                let m = core::mem::take(&mut self.tail);
+                self.compress(m);
+            }
+        }
+        if self.ntail != 0 {
+            return;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.compress(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        for (i, &b) in words.remainder().iter().enumerate() {
+            self.tail |= (b as u64) << (8 * i);
+        }
+        self.ntail = words.remainder().len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut s = *self;
+        s.compress(s.tail | (s.len & 0xFF) << 56);
+        s.v[2] ^= 0xFF;
+        for _ in 0..4 {
+            s.round();
+        }
+        s.v[0] ^ s.v[1] ^ s.v[2] ^ s.v[3]
+    }
+}
+
+/// SipHash-2-4 of `data` under the key `(k0, k1)`.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut h = SipHasher::new(k0, k1);
+    h.write(data);
+    h.finish()
+}
+
+/// Builds `SipHasher`s sharing one random key, like std's `RandomState`.
+#[derive(Debug, Clone, Copy)]
+pub struct SipBuildHasher {
+    k0: u64,
+    k1: u64,
+}
+
+impl SipBuildHasher {
+    pub fn new() -> SipBuildHasher {
+        SipBuildHasher { k0: crate::random::u64(), k1: crate::random::u64() }
+    }
+
+    pub const fn with_keys(k0: u64, k1: u64) -> SipBuildHasher {
+        SipBuildHasher { k0, k1 }
+    }
+}
+
+impl Default for SipBuildHasher {
+    fn default() -> SipBuildHasher {
+        SipBuildHasher::new()
+    }
+}
+
+impl BuildHasher for SipBuildHasher {
+    type Hasher = SipHasher;
+
+    fn build_hasher(&self) -> SipHasher {
+        SipHasher::new(self.k0, self.k1)
+    }
+}
@@ -1,2 +1,6 @@
+pub mod crc;
+pub mod cstr;
+pub mod hash;
 pub mod mem;
+pub mod numfmt;
 use mem::*;
\ No newline at end of file
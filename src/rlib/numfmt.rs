@@ -0,0 +1,106 @@
+//! Number formatting without an allocator
+//!
+//! `NumBuf` renders an integer into a buffer on the stack. Interrupt
+//! handlers, the fault path and log sinks that run with a lock held use it
+//! where `alloc::format!` could deadlock on the heap lock or fail outright.
+//! It implements `Display`, so it also goes straight into `write!`.
+
+use core::fmt;
+
+/// Room for `u64::MAX` in decimal, or `i64::MIN` with its sign, or 16 hex
+/// digits with a `0x` prefix.
+const NUM_BUF_LEN: usize = 20;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// A formatted integer, right-aligned in a fixed buffer.
+#[derive(Clone, Copy)]
+pub struct NumBuf {
+    buf: [u8; NUM_BUF_LEN],
+    start: usize,
+}
+
+impl NumBuf {
+    fn empty() -> NumBuf {
+        NumBuf { buf: [0; NUM_BUF_LEN], start: NUM_BUF_LEN }
+    }
+
+    fn push_front(&mut self, b: u8) {
+        self.start -= 1;
+        self.buf[self.start] = b;
+    }
+
+    /// `n` in decimal.
+    pub fn dec(mut n: u64) -> NumBuf {
+        let mut out = NumBuf::empty();
+        loop {
+            out.push_front(b'0' + (n % 10) as u8);
+            n /= 10;
+            if n == 0 {
+                return out;
+            }
+        }
+    }
+
+    /// `n` in decimal, with a `-` if negative.
+    pub fn signed(n: i64) -> NumBuf {
+        let mut out = NumBuf::dec(n.unsigned_abs());
+        if n < 0 {
+            out.push_front(b'-');
+        }
+        out
+    }
+
+    /// `n` in lowercase hex, zero-padded to at least `min_digits` (at most
+    /// 16), with a `0x` prefix.
+    pub fn hex(mut n: u64, min_digits: usize) -> NumBuf {
+        let mut out = NumBuf::empty();
+        let mut digits = 0;
+        while n != 0 || digits < min_digits.clamp(1, 16) {
+            out.push_front(HEX_DIGITS[(n & 0xF) as usize]);
+            n >>= 4;
+            digits += 1;
+        }
+        out.push_front(b'x');
+        out.push_front(b'0');
+        out
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ASCII digits, letters, 'x' and '-' are ever written
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+}
+
+impl fmt::Display for NumBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for NumBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Copy `n` in decimal into `out`; returns the bytes written, or `None`
+/// if `out` is too short.
+pub fn format_dec(n: u64, out: &mut [u8]) -> Option<usize> {
+    copy_out(NumBuf::dec(n).as_bytes(), out)
+}
+
+/// Copy `n` as `0x`-prefixed hex, at least `min_digits` digits, into
+/// `out`; returns the bytes written, or `None` if `out` is too short.
+pub fn format_hex(n: u64, min_digits: usize, out: &mut [u8]) -> Option<usize> {
+    copy_out(NumBuf::hex(n, min_digits).as_bytes(), out)
+}
+
+fn copy_out(text: &[u8], out: &mut [u8]) -> Option<usize> {
+    out.get_mut(..text.len())?.copy_from_slice(text);
+    Some(text.len())
+}
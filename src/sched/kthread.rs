@@ -0,0 +1,414 @@
+//! Kernel threads and switching between them
+//!
+//! Thread 0 is the context the executor runs in, on the boot stack; the
+//! others are spawned, each with a kernel stack (`memory::stack`). They all run
+//! on `KTHREAD_CPU`: spawning from another CPU works, but only that one
+//! switches. A switch pushes the callee-saved registers on the old thread's
+//! stack, saves its `fxsave` image (kernel code uses SSE) and loads the new
+//! thread's.
+//!
+//! The timer handler calls `preempt` last. It switches only when it
+//! interrupted kernel code outside any other handler, the running thread
+//! has had its slice (or is the executor, idling) and another thread is
+//! ready. It never switches away from a thread holding the heap lock, which
+//! the next thread might wait for with interrupts off, nor, built with
+//! lockdep, from one holding a tracked lock, as lockdep keeps held locks per
+//! CPU rather than per thread.
+//!
+//! A finished thread's stack is freed by `reap`, which `spawn_kthread` and
+//! the executor's idle loop call on `KTHREAD_CPU`, so never while a switch
+//! is still leaving it.
+
+use crate::prelude::*;
+use crate::arch::usermode::FpuState;
+use crate::memory::{alloc_kernel_stack, KernelStack};
+use crate::sync::{IrqSpinlock, Spinlock, WaitQueue};
+use crate::time::clock::uptime_ns;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::PrivilegeLevel;
+
+/// Threads at once, thread 0 included.
+pub const MAX_KTHREADS: usize = 32;
+/// How long a thread runs before the timer switches to the next ready one.
+pub const TIME_SLICE_NS: u64 = 10_000_000;
+/// The CPU kernel threads run on.
+pub const KTHREAD_CPU: usize = 0;
+/// Kept in the lowest word of each stack, and checked whenever the thread
+/// is switched out.
+const STACK_CANARY: u64 = 0x6B74_6872_6561_6421;
+/// Callee-saved registers the switch pushes.
+const SAVED_REGS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Sleeping,
+    Done,
+}
+
+impl ThreadState {
+    fn from_u8(v: u8) -> ThreadState {
+        match v {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Sleeping,
+            _ => ThreadState::Done,
+        }
+    }
+}
+
+struct KThread {
+    id: usize,
+    name: String,
+    state: AtomicU8,
+    /// Uptime a sleeping thread is due at.
+    wake_ns: AtomicU64,
+    /// Stack pointer while switched out.
+    rsp: UnsafeCell<u64>,
+    fpu: UnsafeCell<FpuState>,
+    /// Whether it starts with interrupts enabled: as its spawner had them.
+    irqs: bool,
+    entry: Spinlock<Option<Box<dyn FnOnce() + Send>>>,
+    /// None for thread 0, which has the boot stack.
+    stack: Option<KernelStack>,
+    switches: AtomicU64,
+    runtime_ns: AtomicU64,
+    exited: WaitQueue,
+}
+
+// `rsp` and `fpu` are only touched by the switch, with interrupts off on
+// `KTHREAD_CPU`.
+unsafe impl Sync for KThread {}
+
+impl KThread {
+    fn boot() -> KThread {
+        KThread {
+            id: 0,
+            name: "kernel".to_string(),
+            state: AtomicU8::new(ThreadState::Running as u8),
+            wake_ns: AtomicU64::new(0),
+            rsp: UnsafeCell::new(0),
+            fpu: UnsafeCell::new(FpuState::default()),
+            irqs: true,
+            entry: Spinlock::new(None),
+            stack: None,
+            switches: AtomicU64::new(0),
+            runtime_ns: AtomicU64::new(0),
+            exited: WaitQueue::new(),
+        }
+    }
+
+    fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    fn ready(&self, now: u64) -> bool {
+        match self.state() {
+            ThreadState::Ready => true,
+            ThreadState::Sleeping => self.wake_ns.load(Ordering::Relaxed) <= now,
+            _ => false,
+        }
+    }
+
+    fn check_stack(&self) {
+        if let Some(stack) = &self.stack
+            && stack.as_slice()[0] != STACK_CANARY
+        {
+            panic!("kernel thread {} ({}) overran its stack", self.id, self.name);
+        }
+    }
+}
+
+struct Table {
+    threads: [Option<Arc<KThread>>; MAX_KTHREADS],
+    /// Slot of the running thread.
+    current: usize,
+    /// When it was switched in.
+    since: u64,
+}
+
+static TABLE: IrqSpinlock<Table> = IrqSpinlock::new(Table { threads: [const { None }; MAX_KTHREADS], current: 0, since: 0 });
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+global_asm!(
+    ".global neutrix_switch_thread",
+    "neutrix_switch_thread:",
+    "fxsave64 [rdx]",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "fxrstor64 [rcx]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    ".global neutrix_kthread_start",
+    "neutrix_kthread_start:",
+    "and rsp, -16",
+    "call {entry}",
+    "ud2",
+    entry = sym kthread_entry,
+);
+
+unsafe extern "C" {
+    fn neutrix_switch_thread(old_rsp: *mut u64, new_rsp: u64, old_fpu: *mut FpuState, new_fpu: *const FpuState);
+    fn neutrix_kthread_start();
+}
+
+/// A spawned thread. Dropping the handle leaves the thread running.
+pub struct KThreadHandle(Arc<KThread>);
+
+impl KThreadHandle {
+    pub fn id(&self) -> usize {
+        self.0.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.state() == ThreadState::Done
+    }
+
+    /// Wait for the thread to return.
+    pub async fn join(&self) {
+        self.0.exited.wait_until(|| self.is_finished().then_some(())).await
+    }
+}
+
+fn on_kthread_cpu() -> bool {
+    crate::arch::task::current_cpu() == KTHREAD_CPU
+}
+
+/// Run `f` on a new kernel thread. Fails if `MAX_KTHREADS` threads exist
+/// already, or there is no kernel stack for it.
+pub fn spawn_kthread<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Result<KThreadHandle, &'static str> {
+    reap();
+    let mut stack = alloc_kernel_stack()?;
+    let words = stack.as_mut_slice();
+    words[0] = STACK_CANARY;
+    // what the switch pops: zeroed registers, then neutrix_kthread_start
+    // as the return address
+    let top = words.len() - 1;
+    words[top] = neutrix_kthread_start as usize as u64;
+    let rsp = stack.top() - (SAVED_REGS as u64 + 1) * 8;
+    let thread = Arc::new(KThread {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name: name.to_string(),
+        state: AtomicU8::new(ThreadState::Ready as u8),
+        wake_ns: AtomicU64::new(0),
+        rsp: UnsafeCell::new(rsp),
+        fpu: UnsafeCell::new(FpuState::default()),
+        irqs: interrupts::are_enabled(),
+        entry: Spinlock::new(Some(Box::new(f))),
+        stack: Some(stack),
+        switches: AtomicU64::new(0),
+        runtime_ns: AtomicU64::new(0),
+        exited: WaitQueue::new(),
+    });
+    let mut boot = TABLE.lock().threads[0].is_none().then(|| Arc::new(KThread::boot()));
+    let mut table = TABLE.lock();
+    if table.threads[0].is_none() {
+        table.threads[0] = boot.take();
+    }
+    let slot = (1..MAX_KTHREADS).find(|&i| table.threads[i].is_none()).ok_or("too many kernel threads")?;
+    table.threads[slot] = Some(thread.clone());
+    drop(table);
+    Ok(KThreadHandle(thread))
+}
+
+/// Where a new thread starts, on its own stack with interrupts off.
+extern "C" fn kthread_entry() -> ! {
+    let thread = current_thread().expect("kernel thread started outside the scheduler");
+    // SAFETY: the table keeps the thread until it is done and switched out
+    let thread = unsafe { &*thread };
+    if thread.irqs {
+        interrupts::enable();
+    }
+    let f = thread.entry.lock().take();
+    if let Some(f) = f {
+        f();
+    }
+    interrupts::disable();
+    thread.set_state(ThreadState::Done);
+    thread.exited.wake_all();
+    schedule(uptime_ns(), false);
+    unreachable!("finished kernel thread switched back in");
+}
+
+fn current_thread() -> Option<*const KThread> {
+    let table = TABLE.lock();
+    table.threads[table.current].as_deref().map(|t| t as *const KThread)
+}
+
+/// Switch to the next ready thread after the running one, round-robin.
+/// Interrupts must be off. When `preempting`, only once the running thread
+/// has had its slice. False if nothing else ran.
+fn schedule(now: u64, preempting: bool) -> bool {
+    let mut table = TABLE.lock();
+    let cur = table.current;
+    let Some(old) = table.threads[cur].as_deref().map(|t| t as *const KThread) else { return false };
+    let idle = cur == 0 && crate::arch::task::cpu_is_idle(KTHREAD_CPU);
+    if preempting && !idle && now.saturating_sub(table.since) < TIME_SLICE_NS {
+        return false;
+    }
+    let Some(next) = (1..MAX_KTHREADS).map(|i| (cur + i) % MAX_KTHREADS).find(|&i| table.threads[i].as_ref().is_some_and(|t| t.ready(now))) else {
+        return false;
+    };
+    let new = table.threads[next].as_deref().map(|t| t as *const KThread).unwrap();
+    table.current = next;
+    let ran = now.saturating_sub(core::mem::replace(&mut table.since, now));
+    drop(table);
+    // SAFETY: reap only frees threads that are done and not current, and
+    // only on this CPU, so neither goes away before the switch is over
+    let (old, new) = unsafe { (&*old, &*new) };
+    old.runtime_ns.fetch_add(ran, Ordering::Relaxed);
+    old.switches.fetch_add(1, Ordering::Relaxed);
+    let _ = old.state.compare_exchange(ThreadState::Running as u8, ThreadState::Ready as u8, Ordering::AcqRel, Ordering::Acquire);
+    new.set_state(ThreadState::Running);
+    old.check_stack();
+    unsafe { neutrix_switch_thread(old.rsp.get(), *new.rsp.get(), old.fpu.get(), new.fpu.get()) };
+    true
+}
+
+/// Called last in the timer handler: move on to the next ready thread if
+/// the running one has had its slice.
+pub fn preempt(frame: &InterruptStackFrame) {
+    if frame.code_segment.rpl() != PrivilegeLevel::Ring0 || !on_kthread_cpu() || crate::arch::idt::in_irq() {
+        return;
+    }
+    if crate::memory::heap_locked() || crate::sync::lockdep::task_holds_locks() {
+        return;
+    }
+    schedule(uptime_ns(), true);
+}
+
+/// Let the next ready thread run. False if there was none, or this isn't
+/// `KTHREAD_CPU`.
+pub fn yield_now() -> bool {
+    on_kthread_cpu() && interrupts::without_interrupts(|| schedule(uptime_ns(), false))
+}
+
+/// Put the calling kernel thread to sleep for `ms` milliseconds. Anywhere
+/// but on a spawned thread, busy-waits with `mdelay` instead.
+pub fn kthread_sleep_ms(ms: u64) {
+    let slept = on_kthread_cpu()
+        && interrupts::without_interrupts(|| {
+            let table = TABLE.lock();
+            if table.current == 0 {
+                return false;
+            }
+            let Some(thread) = table.threads[table.current].clone() else { return false };
+            drop(table);
+            let now = uptime_ns();
+            let due = now.saturating_add(ms.saturating_mul(1_000_000));
+            thread.wake_ns.store(due, Ordering::Relaxed);
+            thread.set_state(ThreadState::Sleeping);
+            crate::arch::tsc_timer::rearm_before(due);
+            if !schedule(now, false) {
+                thread.set_state(ThreadState::Running);
+            }
+            true
+        });
+    if !slept {
+        crate::time::delay::mdelay(ms);
+    }
+}
+
+/// Id of the thread running on `KTHREAD_CPU`; 0 is the executor's.
+pub fn current_kthread_id() -> usize {
+    let table = TABLE.lock();
+    table.threads[table.current].as_ref().map_or(0, |t| t.id)
+}
+
+/// Whether a spawned thread is running or ready to. While one is, the
+/// timer keeps ticking and the executor doesn't halt.
+pub fn kthreads_busy() -> bool {
+    let now = uptime_ns();
+    let table = TABLE.lock();
+    table.current != 0 || table.threads[1..].iter().flatten().any(|t| t.ready(now))
+}
+
+/// When the first sleeping thread is due, or `u64::MAX`.
+pub fn next_kthread_wake() -> u64 {
+    let table = TABLE.lock();
+    let sleeping = table.threads.iter().flatten().filter(|t| t.state() == ThreadState::Sleeping);
+    sleeping.map(|t| t.wake_ns.load(Ordering::Relaxed)).min().unwrap_or(u64::MAX)
+}
+
+/// Drop finished threads, freeing their stacks. Returns how many there
+/// were. Does nothing off `KTHREAD_CPU`.
+pub fn reap() -> usize {
+    if !on_kthread_cpu() {
+        return 0;
+    }
+    let mut dead: [Option<Arc<KThread>>; MAX_KTHREADS] = [const { None }; MAX_KTHREADS];
+    let mut n = 0;
+    let mut table = TABLE.lock();
+    let cur = table.current;
+    for (i, slot) in table.threads.iter_mut().enumerate() {
+        if i != cur && slot.as_ref().is_some_and(|t| t.state() == ThreadState::Done) {
+            dead[n] = slot.take();
+            n += 1;
+        }
+    }
+    drop(table);
+    n
+}
+
+/// A kernel thread, as `kthreads` lists it.
+#[derive(Debug, Clone)]
+pub struct KThreadInfo {
+    pub id: usize,
+    pub name: String,
+    pub state: ThreadState,
+    pub switches: u64,
+    pub runtime_ns: u64,
+}
+
+pub fn kthreads() -> Vec<KThreadInfo> {
+    let threads: Vec<Arc<KThread>> = TABLE.lock().threads.iter().flatten().cloned().collect();
+    threads
+        .iter()
+        .map(|t| KThreadInfo {
+            id: t.id,
+            name: t.name.clone(),
+            state: t.state(),
+            switches: t.switches.load(Ordering::Relaxed),
+            runtime_ns: t.runtime_ns.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+pub fn print_kthreads() {
+    let threads = kthreads();
+    if threads.is_empty() {
+        println!("no kernel threads");
+        return;
+    }
+    for t in threads {
+        println!("  {} {:?} switches={} ran={}us {}", t.id, t.state, t.switches, t.runtime_ns / 1000, t.name);
+    }
+}
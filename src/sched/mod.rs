@@ -0,0 +1,10 @@
+//! Kernel threads
+//!
+//! The executor runs async tasks cooperatively, so a task that computes
+//! for a long time holds up every other one, input included. Work like
+//! that goes on a kernel thread instead: `spawn_kthread` gives a closure
+//! its own stack, and the timer interrupt switches between the threads and
+//! the executor round-robin, a `TIME_SLICE_NS` slice each.
+
+pub mod kthread;
+pub use kthread::*;
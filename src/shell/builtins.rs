@@ -0,0 +1,567 @@
+//! Built-in shell commands
+
+use crate::prelude::*;
+use super::{arg, commands, find_command, parse_number, register_command, Command};
+use crate::fs::vfs::{self, OpenOptions, SeekFrom};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Bytes read per chunk by `cat` and `hexdump`.
+const CHUNK: usize = 512;
+
+fn help(args: &[&str]) -> Result<(), String> {
+    if let Some(name) = args.get(1) {
+        let cmd = find_command(name).ok_or_else(|| format!("help: no command '{}'", name))?;
+        println!("{} {}", cmd.name, cmd.usage);
+        println!("  {}", cmd.help);
+        return Ok(());
+    }
+    for cmd in commands() {
+        println!("{:<10} {}", cmd.name, cmd.help);
+    }
+    println!("Anything else runs /bin/NAME (or NAME, if it contains '/').");
+    Ok(())
+}
+
+fn lsdev(_args: &[&str]) -> Result<(), String> {
+    crate::driver_framework::manager::GLOBAL_MANAGER.list_devices();
+    Ok(())
+}
+
+fn devctl(args: &[&str]) -> Result<(), String> {
+    let id = parse_number(arg(args, 1)?)? as usize;
+    let manager = &crate::driver_framework::manager::GLOBAL_MANAGER;
+    if args.len() > 2 {
+        return manager.control_device(id, arg(args, 2)?, arg(args, 3)?).map_err(|e| format!("devctl: {}", e));
+    }
+    let controls = manager.device_controls(id).map_err(|e| format!("devctl: {}", e))?;
+    if controls.is_empty() {
+        println!("device {} has no settings", id);
+    }
+    for (key, value) in controls {
+        println!("{:<16} {}", key, value);
+    }
+    Ok(())
+}
+
+fn lspci(_args: &[&str]) -> Result<(), String> {
+    let manager = &crate::driver_framework::manager::GLOBAL_MANAGER;
+    let mut functions: Vec<_> = manager.devices.lock().iter()
+        .filter_map(|e| {
+            let info = e.device.info();
+            Some((info.location?, e.device.id, info, e.driver.is_some()))
+        })
+        .collect();
+    functions.sort_by_key(|f| f.0);
+    for (addr, id, info, driver) in functions {
+        println!(
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} {}",
+            addr,
+            info.vendor_id,
+            info.device_id,
+            info.class,
+            info.subclass,
+            info.prog_if,
+            if driver { "[driver]" } else { "" }
+        );
+        // show where functions behind bridges hang
+        if manager.pci_parent(id).is_some() {
+            if let Some(path) = manager.device_path(id) {
+                println!("    {}", path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str]) -> Result<(), String> {
+    let (used, free) = crate::memory::allocator::heap_usage();
+    println!("heap:   {} KiB used, {} KiB free of {} KiB", used / 1024, free / 1024, crate::memory::allocator::HEAP_SIZE / 1024);
+    match crate::memory::with_frame_allocator(|f| (f.free_frames(), f.total_frames())) {
+        Some((free, total)) => println!("frames: {} free ({} MiB) of {} ({} MiB)", free, free * 4 / 1024, total, total * 4 / 1024),
+        None => println!("frames: no allocator"),
+    }
+    Ok(())
+}
+
+fn uptime(_args: &[&str]) -> Result<(), String> {
+    let us = crate::time::uptime_ns() / 1000;
+    println!("up {}.{:06} s (clock source {:?})", us / 1_000_000, us % 1_000_000, crate::time::clock_source());
+    Ok(())
+}
+
+fn date(_args: &[&str]) -> Result<(), String> {
+    let note = if crate::time::wall_clock_synced() { "" } else { " (not set)" };
+    println!("{} UTC{}", crate::time::now(), note);
+    Ok(())
+}
+
+fn ntp(args: &[&str]) -> Result<(), String> {
+    use crate::net::sntp;
+    match args.get(1).copied() {
+        Some("sync") => {
+            sntp::sntp_sync_now();
+            return Ok(());
+        }
+        Some("server") => {
+            let addr = arg(args, 2)?;
+            sntp::set_sntp_server(crate::net::Ipv4Addr::parse(addr).ok_or_else(|| format!("ntp: bad address '{}'", addr))?);
+            sntp::sntp_sync_now();
+            return Ok(());
+        }
+        Some(other) => return Err(format!("ntp: unknown subcommand '{}'", other)),
+        None => {}
+    }
+    let st = sntp::sntp_status();
+    println!("server {}{}, {} syncs, {} failures", st.server, if st.enabled { "" } else { " (off)" }, st.syncs, st.failures);
+    if let Some(last) = st.last {
+        let ago = crate::time::uptime_ns().saturating_sub(st.last_sync_ns) / 1_000_000_000;
+        println!("last sync {} s ago: stratum {}, offset {} us, delay {} us", ago, last.stratum, last.offset_ns / 1000, last.delay_ns / 1000);
+    }
+    if let Some(ppb) = st.drift_ppb {
+        println!("drift {} ppb", ppb);
+    }
+    Ok(())
+}
+
+fn random(args: &[&str]) -> Result<(), String> {
+    if let Some(n) = args.get(1) {
+        let n: usize = n.parse().map_err(|_| format!("random: bad length '{}'", n))?;
+        let mut buf = alloc::vec![0u8; n.min(4096)];
+        crate::random::fill(&mut buf);
+        for line in buf.chunks(32) {
+            for b in line {
+                print!("{:02x}", b);
+            }
+            println!();
+        }
+        return Ok(());
+    }
+    let st = crate::random::random_status();
+    println!(
+        "RDSEED {}, RDRAND {}; {} reseeds, {} samples pooled",
+        if st.rdseed { "yes" } else { "no" },
+        if st.rdrand { "yes" } else { "no" },
+        st.reseeds,
+        st.pending
+    );
+    Ok(())
+}
+
+fn insmod(args: &[&str]) -> Result<(), String> {
+    let path = arg(args, 1)?;
+    crate::kmod::insmod(path).map(|_| ()).map_err(|e| format!("insmod: {}: {}", path, e))
+}
+
+fn rmmod(args: &[&str]) -> Result<(), String> {
+    let name = arg(args, 1)?;
+    crate::kmod::rmmod(name).map_err(|e| format!("rmmod: {}: {}", name, e))
+}
+
+fn lsmod(_args: &[&str]) -> Result<(), String> {
+    for m in crate::kmod::modules() {
+        println!(
+            "{:<16} {:#x} {:>7} bytes  drivers: {} ({} devices)  used by: {}",
+            m.name,
+            m.base,
+            m.size,
+            if m.drivers.is_empty() { "-".to_string() } else { m.drivers.join(",") },
+            m.devices,
+            if m.used_by.is_empty() { "-".to_string() } else { m.used_by.join(",") }
+        );
+    }
+    Ok(())
+}
+
+fn config_cmd(args: &[&str]) -> Result<(), String> {
+    use crate::config;
+    match args.get(1).copied() {
+        None => {
+            for (key, value) in config::config_entries() {
+                println!("{} = {}", key, value);
+            }
+        }
+        Some("get") => {
+            let key = arg(args, 2)?;
+            println!("{}", config::config_get(key).ok_or_else(|| format!("config: {} is not set", key))?);
+        }
+        Some("set") => {
+            let key = arg(args, 2)?;
+            arg(args, 3)?;
+            let value = args[3..].join(" ");
+            config::config_set(key, &value).map_err(|e| format!("config: {}", e))?;
+        }
+        Some("unset") => {
+            let key = arg(args, 2)?;
+            if !config::config_unset(key) {
+                return Err(format!("config: {} is not set", key));
+            }
+        }
+        Some("save") => {
+            let device = config::save_config().map_err(|e| format!("config: {}", e))?;
+            println!("saved to {}:{}", device, config::FAT_CONFIG_PATH);
+        }
+        Some("reload") => config::load_config(),
+        Some(other) => return Err(format!("config: unknown subcommand '{}'", other)),
+    }
+    Ok(())
+}
+
+fn sym(args: &[&str]) -> Result<(), String> {
+    let what = arg(args, 1)?;
+    if let Ok(addr) = parse_number(what) {
+        if let Some((name, offset)) = crate::symbols::resolve(addr) {
+            println!("{:#018x} {}+{:#x}", addr, name, offset);
+        } else if let Some((module, offset)) = crate::kmod::module_at(addr as usize) {
+            println!("{:#018x} [{}]+{:#x}", addr, module, offset);
+        } else {
+            return Err(format!("sym: nothing known at {:#x}", addr));
+        }
+        return Ok(());
+    }
+    let addr = crate::symbols::lookup_name(what).ok_or_else(|| format!("sym: no symbol {}", what))?;
+    println!("{:#018x} {}", addr, what);
+    Ok(())
+}
+
+fn hotkeys(_args: &[&str]) -> Result<(), String> {
+    for hotkey in crate::input::hotkeys() {
+        println!("{:<20} {}", format!("{}", hotkey.combo), hotkey.name);
+    }
+    Ok(())
+}
+
+fn blank(args: &[&str]) -> Result<(), String> {
+    match args.get(1).copied() {
+        None => {
+            let idle = crate::input::input_idle_ns() / 1_000_000_000;
+            match crate::input::blank_timeout_secs() {
+                0 => println!("blanking off; no input for {}s", idle),
+                secs => println!("blank after {}s; no input for {}s", secs, idle),
+            }
+            if crate::driver_framework::drivers::vbe_vga::screen_blanked() {
+                println!("screen blanked");
+            }
+        }
+        Some("now") => crate::input::blank_screen(),
+        Some(secs) => crate::input::set_blank_timeout(parse_number(secs)?),
+    }
+    Ok(())
+}
+
+fn brightness(args: &[&str]) -> Result<(), String> {
+    use crate::driver_framework::drivers::vbe_vga;
+    let (mut brightness, mut gamma) = vbe_vga::display_levels();
+    match args.get(1).copied() {
+        None => {}
+        Some("reset") => (brightness, gamma) = (vbe_vga::BRIGHTNESS_MAX, 100),
+        Some(percent) => {
+            brightness = u32::try_from(parse_number(percent.trim_end_matches('%'))?).unwrap_or(u32::MAX);
+            if let Some(g) = args.get(2) {
+                gamma = vbe_vga::parse_gamma(g).ok_or_else(|| format!("bad gamma: {}", g))?;
+            }
+        }
+    }
+    if args.len() > 1 {
+        vbe_vga::set_display_levels(brightness, gamma)?;
+    }
+    println!("brightness {}% gamma {}.{:02}", brightness, gamma / 100, gamma % 100);
+    Ok(())
+}
+
+fn irq(_args: &[&str]) -> Result<(), String> {
+    let requests = crate::hal::irq_requests();
+    match crate::hal::irq_mode() {
+        crate::hal::IrqMode::Pic => println!("mode Pic, {} spurious IRQ 7/15", crate::hal::spurious_pic_count()),
+        mode => println!("mode {:?}", mode),
+    }
+    for (vector, count) in crate::arch::idt::irq_counts() {
+        let mut owners = String::new();
+        for (device, _, gsi) in requests.iter().filter(|r| r.1 == vector) {
+            match gsi {
+                Some(gsi) => owners.push_str(&format!(" dev {} (GSI {})", device, gsi)),
+                None => owners.push_str(&format!(" dev {}", device)),
+            }
+        }
+        println!("0x{:02x} {:>10}{}", vector, count, owners);
+    }
+    for (vector, count, masked) in crate::hal::irq::unhandled_irqs() {
+        println!("0x{:02x} {:>10} unhandled{}", vector, count, if masked { ", masked as a storm" } else { "" });
+    }
+    Ok(())
+}
+
+fn ps(_args: &[&str]) -> Result<(), String> {
+    crate::process::print_processes();
+    Ok(())
+}
+
+fn tasks(_args: &[&str]) -> Result<(), String> {
+    crate::arch::task::print_stats();
+    Ok(())
+}
+
+fn kthreads(_args: &[&str]) -> Result<(), String> {
+    crate::sched::print_kthreads();
+    Ok(())
+}
+
+fn mount(args: &[&str]) -> Result<(), String> {
+    if args.len() == 1 {
+        for (path, fs) in vfs::mounts() {
+            println!("{:<6} on {}", fs, path);
+        }
+        return Ok(());
+    }
+    let (kind, path) = (arg(args, 1)?, arg(args, 2)?);
+    let fs: alloc::sync::Arc<dyn vfs::FileSystem> = match kind {
+        "ramfs" => alloc::sync::Arc::new(crate::fs::RamFs::new()),
+        _ => return Err(format!("mount: unknown filesystem type '{}'", kind)),
+    };
+    vfs::mount(path, fs).map_err(|e| format!("mount: {}: {}", path, e))
+}
+
+fn umount(args: &[&str]) -> Result<(), String> {
+    let path = arg(args, 1)?;
+    vfs::unmount(path).map_err(|e| format!("umount: {}: {}", path, e))
+}
+
+fn ls(args: &[&str]) -> Result<(), String> {
+    let path = args.get(1).copied().unwrap_or("/");
+    for entry in vfs::read_dir(path).map_err(|e| format!("ls: {}: {}", path, e))? {
+        let suffix = if entry.kind == vfs::FileType::Directory { "/" } else { "" };
+        println!("{:>10} {}{}", entry.size, entry.name, suffix);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> Result<(), String> {
+    arg(args, 1)?;
+    for &path in &args[1..] {
+        let mut file = vfs::open(path, OpenOptions::read()).map_err(|e| format!("cat: {}: {}", path, e))?;
+        let mut buf = [0u8; CHUNK];
+        loop {
+            let n = file.read(&mut buf).map_err(|e| format!("cat: {}: {}", path, e))?;
+            if n == 0 {
+                break;
+            }
+            print!("{}", String::from_utf8_lossy(&buf[..n]));
+        }
+    }
+    Ok(())
+}
+
+fn hexdump(args: &[&str]) -> Result<(), String> {
+    let path = arg(args, 1)?;
+    let offset = args.get(2).map(|s| parse_number(s)).transpose()?.unwrap_or(0);
+    let mut left = args.get(3).map(|s| parse_number(s)).transpose()?.unwrap_or(u64::MAX);
+    let mut file = vfs::open(path, OpenOptions::read()).map_err(|e| format!("hexdump: {}: {}", path, e))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| format!("hexdump: {}", e))?;
+    let mut at = offset;
+    let mut buf = [0u8; CHUNK];
+    while left > 0 {
+        let want = (left.min(CHUNK as u64)) as usize;
+        let n = file.read(&mut buf[..want]).map_err(|e| format!("hexdump: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        for line in buf[..n].chunks(16) {
+            print_hex_line(at, line);
+            at += line.len() as u64;
+        }
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// `offset  xx xx ... |ascii|`
+pub fn print_hex_line(offset: u64, bytes: &[u8]) {
+    let mut hex = String::new();
+    for i in 0..16 {
+        match bytes.get(i) {
+            Some(b) => hex.push_str(&format!("{:02x} ", b)),
+            None => hex.push_str("   "),
+        }
+        if i == 7 {
+            hex.push(' ');
+        }
+    }
+    let ascii: String = bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+    println!("{:08x}  {} |{}|", offset, hex, ascii);
+}
+
+fn dmesg(_args: &[&str]) -> Result<(), String> {
+    let mut buf = alloc::vec![0u8; crate::log::LOG_RING_SIZE];
+    let n = crate::log::read_log_ring(&mut buf);
+    // printing appends to the ring, so print from the copy
+    for chunk in buf[..n].utf8_chunks() {
+        print!("{}", chunk.valid());
+    }
+    Ok(())
+}
+
+fn log_cmd(args: &[&str]) -> Result<(), String> {
+    if args.len() == 1 {
+        for (name, level) in crate::log::sinks().into_iter().flatten() {
+            println!("{:<9} {}", name, level);
+        }
+        println!("timestamps: {:?}", crate::log::timestamps());
+        return Ok(());
+    }
+    let (name, value) = (arg(args, 1)?, arg(args, 2)?);
+    if name == "time" {
+        let mode = crate::log::Timestamps::parse(value).ok_or_else(|| format!("log: bad time mode '{}'", value))?;
+        crate::log::set_timestamps(mode);
+        return Ok(());
+    }
+    if value == "off" {
+        crate::log::remove_sink(name);
+        return Ok(());
+    }
+    let level = crate::log::Level::parse(value).ok_or_else(|| format!("log: bad level '{}'", value))?;
+    match crate::log::builtin_sink(name) {
+        Some(sink) => crate::log::add_sink(sink, level).map_err(|e| format!("log: {}", e)),
+        None if crate::log::set_sink_level(name, level) => Ok(()),
+        None => Err(format!("log: no sink '{}'", name)),
+    }
+}
+
+fn trace_cmd(args: &[&str]) -> Result<(), String> {
+    use crate::trace::Subsys;
+    match args.get(1).copied() {
+        None => {
+            for s in Subsys::ALL {
+                println!("{:<8} {}", s.name(), if crate::trace::trace_enabled(s) { "on" } else { "off" });
+            }
+            Ok(())
+        }
+        Some("dump") => {
+            crate::trace::dump_trace();
+            Ok(())
+        }
+        Some(op @ ("on" | "off")) => {
+            let name = arg(args, 2)?;
+            let subsystems: Vec<Subsys> = if name == "all" {
+                Subsys::ALL.to_vec()
+            } else {
+                alloc::vec![Subsys::from_name(name).ok_or_else(|| format!("trace: no subsystem '{}'", name))?]
+            };
+            for s in subsystems {
+                if op == "on" { crate::trace::trace_enable(s) } else { crate::trace::trace_disable(s) }
+            }
+            Ok(())
+        }
+        Some(other) => Err(format!("trace: unknown operation '{}'", other)),
+    }
+}
+
+fn perf(args: &[&str]) -> Result<(), String> {
+    use crate::arch::pmu::{self, PmuEvent};
+    match arg(args, 1)? {
+        "start" => {
+            let event = match args.get(2).copied().unwrap_or("cycles") {
+                "cycles" => PmuEvent::Cycles,
+                "instructions" => PmuEvent::Instructions,
+                "llc-misses" => PmuEvent::LlcMisses,
+                "llc-refs" => PmuEvent::LlcReferences,
+                "branches" => PmuEvent::BranchInstructions,
+                "branch-misses" => PmuEvent::BranchMisses,
+                other => return Err(format!("perf: unknown event '{}'", other)),
+            };
+            let period = args.get(3).map(|s| parse_number(s)).transpose()?.unwrap_or(1_000_000);
+            pmu::start_sampling(event, period).map_err(|e| format!("perf: {}", e))
+        }
+        "stop" => {
+            let top = args.get(2).map(|s| parse_number(s)).transpose()?.unwrap_or(20);
+            pmu::stop_sampling();
+            pmu::print_profile(top as usize);
+            Ok(())
+        }
+        other => Err(format!("perf: unknown operation '{}'", other)),
+    }
+}
+
+fn netstat(_args: &[&str]) -> Result<(), String> {
+    crate::net::netstat::print_netstat();
+    Ok(())
+}
+
+fn pcap(args: &[&str]) -> Result<(), String> {
+    use crate::net::pcap;
+    match arg(args, 1)? {
+        "start" => {
+            let iface = args.get(2).map(|s| parse_number(s)).transpose()?.map(|i| i as usize);
+            pcap::capture_start(pcap::DEFAULT_SNAPLEN, pcap::DEFAULT_CAPTURE_FRAMES, iface);
+            Ok(())
+        }
+        "stop" => {
+            pcap::capture_stop();
+            let (held, dropped) = pcap::capture_counts();
+            println!("{} frames captured, {} overwritten", held, dropped);
+            Ok(())
+        }
+        "dump" => {
+            println!("{} frames written to serial", pcap::dump_pcap_serial());
+            Ok(())
+        }
+        "save" => {
+            let path = arg(args, 2)?;
+            let n = pcap::save_pcap(path).map_err(|e| format!("pcap: {}: {}", path, e))?;
+            println!("{} bytes written to {}", n, path);
+            Ok(())
+        }
+        other => Err(format!("pcap: unknown operation '{}'", other)),
+    }
+}
+
+fn echo(args: &[&str]) -> Result<(), String> {
+    println!("{}", args[1..].join(" "));
+    Ok(())
+}
+
+fn reboot(_args: &[&str]) -> Result<(), String> {
+    crate::arch::processor::reboot()
+}
+
+pub(super) fn register_builtins() {
+    let builtins = [
+        Command { name: "help", usage: "[COMMAND]", help: "list commands, or describe one", run: help },
+        Command { name: "lsdev", usage: "", help: "devices known to the device manager", run: lsdev },
+        Command { name: "devctl", usage: "ID [KEY VALUE]", help: "show or change a device's settings", run: devctl },
+        Command { name: "lspci", usage: "", help: "PCI functions found at boot", run: lspci },
+        Command { name: "mem", usage: "", help: "heap and physical frame usage", run: mem },
+        Command { name: "uptime", usage: "", help: "time since boot on the monotonic clock", run: uptime },
+        Command { name: "date", usage: "", help: "current date and time (UTC)", run: date },
+        Command { name: "ntp", usage: "[sync | server ADDR]", help: "SNTP status, sync now, or change server", run: ntp },
+        Command { name: "random", usage: "[BYTES]", help: "random generator status, or print random bytes", run: random },
+        Command { name: "insmod", usage: "PATH", help: "load a kernel module", run: insmod },
+        Command { name: "rmmod", usage: "NAME", help: "unload a kernel module", run: rmmod },
+        Command { name: "lsmod", usage: "", help: "loaded kernel modules", run: lsmod },
+        Command { name: "config", usage: "[get KEY | set KEY VALUE | unset KEY | save | reload]", help: "show or change kernel settings", run: config_cmd },
+        Command { name: "sym", usage: "ADDR | NAME", help: "name a kernel address, or find a symbol", run: sym },
+        Command { name: "hotkeys", usage: "", help: "key combinations the kernel intercepts", run: hotkeys },
+        Command { name: "blank", usage: "[now | SECONDS]", help: "screen blanking: show it, blank now, or set the timeout (0 = off)", run: blank },
+        Command { name: "brightness", usage: "[PERCENT [GAMMA] | reset]", help: "show or set the screen's brightness and gamma", run: brightness },
+        Command { name: "irq", usage: "", help: "interrupt counts per vector, the devices holding them, and unhandled ones", run: irq },
+        Command { name: "ps", usage: "", help: "processes", run: ps },
+        Command { name: "tasks", usage: "", help: "executor tasks and per-CPU statistics", run: tasks },
+        Command { name: "kthreads", usage: "", help: "kernel threads and their run time", run: kthreads },
+        Command { name: "mount", usage: "[ramfs PATH]", help: "list mounts, or mount a new ramfs", run: mount },
+        Command { name: "umount", usage: "PATH", help: "unmount a filesystem", run: umount },
+        Command { name: "ls", usage: "[PATH]", help: "list a directory", run: ls },
+        Command { name: "cat", usage: "PATH...", help: "print files", run: cat },
+        Command { name: "hexdump", usage: "PATH [OFFSET [LEN]]", help: "print a file in hex", run: hexdump },
+        Command { name: "echo", usage: "WORD...", help: "print the arguments", run: echo },
+        Command { name: "dmesg", usage: "", help: "recent kernel log output", run: dmesg },
+        Command { name: "log", usage: "[SINK LEVEL|off] | time off|mono|tsc", help: "list log sinks, set one's level, or the line timestamps", run: log_cmd },
+        Command { name: "trace", usage: "[on|off SUBSYS|all] [dump]", help: "trace event masks and dump", run: trace_cmd },
+        Command { name: "perf", usage: "start [EVENT [PERIOD]] | stop [TOP]", help: "sample RIPs with the PMU", run: perf },
+        Command { name: "netstat", usage: "", help: "network interfaces and sockets", run: netstat },
+        Command { name: "pcap", usage: "start [IFACE] | stop | dump | save PATH", help: "packet capture", run: pcap },
+        Command { name: "reboot", usage: "", help: "sync filesystems and reset the machine", run: reboot },
+    ];
+    for cmd in builtins {
+        register_command(cmd);
+    }
+}
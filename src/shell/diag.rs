@@ -0,0 +1,442 @@
+//! Hardware diagnostic commands
+//!
+//! Dumps of firmware tables and interrupt-controller, PCI and paging state,
+//! read live from the hardware when the command runs rather than printed
+//! once during boot.
+
+use crate::prelude::*;
+use crate::driver_framework::device::PciAddress;
+use super::builtins::print_hex_line;
+use super::{arg, parse_number, register_command, Command};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+fn phys_offset() -> u64 {
+    crate::driver_framework::drivers::vbe_vga::get_boot_phys_offset()
+}
+
+/// The table with `sig`. The DSDT isn't listed in the RSDT; it is found
+/// through the FADT.
+fn acpi_table(sig: &[u8; 4]) -> Option<(u64, u32)> {
+    use crate::devices::acpi::{self, AcpiTableHeader, Facp};
+    if let Some(t) = acpi::find_table(sig) {
+        return Some((t.phys_addr, t.length));
+    }
+    if sig != b"DSDT" {
+        return None;
+    }
+    let fadt = acpi::find_table(b"FACP")?;
+    let facp = unsafe { core::ptr::read_unaligned((fadt.phys_addr + phys_offset()) as *const Facp) };
+    let dsdt = facp.dsdt as u64;
+    if dsdt == 0 {
+        return None;
+    }
+    let header = unsafe { core::ptr::read_unaligned((dsdt + phys_offset()) as *const AcpiTableHeader) };
+    Some((dsdt, header.length))
+}
+
+fn acpi_cmd(args: &[&str]) -> Result<(), String> {
+    let Some(sig) = args.get(1) else {
+        for t in crate::devices::acpi::tables() {
+            println!("{} @ {:#010x} {:>6} bytes", String::from_utf8_lossy(&t.signature), t.phys_addr, t.length);
+        }
+        return Ok(());
+    };
+    let sig: [u8; 4] = sig.as_bytes().try_into().map_err(|_| format!("acpi: signature must be 4 characters: {}", sig))?;
+    let (phys, len) = acpi_table(&sig).ok_or_else(|| format!("acpi: no table {}", args[1]))?;
+    println!("{} @ {:#x}, {} bytes", args[1], phys, len);
+    let bytes = unsafe { core::slice::from_raw_parts((phys + phys_offset()) as *const u8, len as usize) };
+    for (i, line) in bytes.chunks(16).enumerate() {
+        print_hex_line(i as u64 * 16, line);
+    }
+    Ok(())
+}
+
+const DELIVERY_MODES: [&str; 8] = ["fixed", "lowest", "smi", "rsvd", "nmi", "init", "rsvd", "extint"];
+
+fn ioports(_args: &[&str]) -> Result<(), String> {
+    for (device, controller, base, len) in crate::hal::ioport::port_claims() {
+        let owner = match device {
+            crate::hal::ioport::KERNEL_PORT_OWNER => String::from("kernel"),
+            id => format!("dev {}", id),
+        };
+        println!("{:#06x}-{:#06x} {:<8} {}", base, base as u32 + len as u32 - 1, controller, owner);
+    }
+    Ok(())
+}
+
+fn caches(_args: &[&str]) -> Result<(), String> {
+    for c in crate::arch::processor::detect_caches() {
+        let ways = match c.ways {
+            0 => String::from("full"),
+            n => format!("{}-way", n),
+        };
+        println!("L{} {:<11} {:>6} KiB  {:>3}-byte lines  {:<6} {} sets", c.level, format!("{:?}", c.kind), c.size / 1024, c.line_size, ways, c.sets);
+    }
+    println!("CLFLUSH line size: {} bytes", crate::arch::processor::cache_line_size());
+    Ok(())
+}
+
+fn ioapic(_args: &[&str]) -> Result<(), String> {
+    let offset = VirtAddr::new(phys_offset());
+    let ioapics = crate::hal::ioapic::list_ioapics();
+    if ioapics.is_empty() {
+        return Err(String::from("ioapic: none found"));
+    }
+    for io in ioapics {
+        println!("IOAPIC id {} @ {:#x}, GSI {}-{}", io.id, io.phys_addr, io.gsi_base, io.gsi_base + io.redir_entries - 1);
+        for gsi in io.gsi_base..io.gsi_base + io.redir_entries {
+            let Some((low, high)) = crate::hal::ioapic::read_redirection_entry(gsi, offset) else { continue };
+            println!(
+                "  GSI {:>3}: vector {:#04x} {:<6} dest {:>3} {} {:<5} {:<4}{}",
+                gsi,
+                low & 0xFF,
+                DELIVERY_MODES[(low >> 8 & 7) as usize],
+                high >> 24,
+                if low & 1 << 11 != 0 { "logical " } else { "physical" },
+                if low & 1 << 15 != 0 { "level" } else { "edge" },
+                if low & 1 << 13 != 0 { "low" } else { "high" },
+                if low & 1 << 16 != 0 { " masked" } else { "" }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn iommu(_args: &[&str]) -> Result<(), String> {
+    let units = crate::hal::iommu::iommu_status();
+    if units.is_empty() {
+        println!("no IOMMU; device DMA is not remapped");
+    }
+    for unit in units {
+        println!(
+            "unit @ {:#x} seg {}: {}-level, {}, fault status {:#x}",
+            unit.register_base,
+            unit.segment,
+            unit.levels,
+            if unit.translating { "translating" } else { "off" },
+            unit.fault_status
+        );
+        for (addr, id) in unit.domains {
+            println!("  {} domain {}", addr, id);
+        }
+        for (addr, reason, fault_addr, write) in unit.faults {
+            println!("  fault: {} {} {:#x} reason {:#04x}", addr, if write { "write" } else { "read" }, fault_addr, reason);
+        }
+    }
+    for (device, addr, len, translated) in crate::hal::dma::dma_mappings() {
+        println!("device {:>3} DMA {:#x}+{:#x}{}", device, addr, len, if translated { " (remapped)" } else { "" });
+    }
+    Ok(())
+}
+
+fn bars(_args: &[&str]) -> Result<(), String> {
+    for (device, index, m) in crate::hal::bar::bar_mappings() {
+        println!("device {:>3} BAR{} {:#x}+{:#x} at {:#x}", device, index, m.phys, m.len, m.virt);
+    }
+    Ok(())
+}
+
+/// Vectors whose bits are set in the 256-bit register starting at `base`.
+fn lapic_vectors(base: usize) -> Vec<u32> {
+    let mut vectors = Vec::new();
+    for word in 0..8 {
+        let bits = crate::hal::apic::read_register(base + word * 0x10).unwrap_or(0);
+        vectors.extend((0..32).filter(|b| bits & 1 << b != 0).map(|b| word as u32 * 32 + b));
+    }
+    vectors
+}
+
+fn lapic(_args: &[&str]) -> Result<(), String> {
+    use crate::hal::apic::read_register;
+    let reg = |off| read_register(off).ok_or_else(|| String::from("lapic: not initialized"));
+    let version = reg(0x30)?;
+    println!("id {} version {:#x} max LVT {}", reg(0x20)? >> 24, version & 0xFF, (version >> 16 & 0xFF) + 1);
+    println!("TPR {:#x} PPR {:#x} SVR {:#x} ({}) ESR {:#x}", reg(0x80)?, reg(0xA0)?, reg(0xF0)?,
+        if reg(0xF0)? & 0x100 != 0 { "enabled" } else { "disabled" }, reg(0x280)?);
+    for (name, off) in [("timer", 0x320), ("thermal", 0x330), ("perf", 0x340), ("LINT0", 0x350), ("LINT1", 0x360), ("error", 0x370)] {
+        let lvt = reg(off)?;
+        println!(
+            "LVT {:<7} {:#010x} vector {:#04x}{}",
+            name,
+            lvt,
+            lvt & 0xFF,
+            if lvt & 1 << 16 != 0 { " masked" } else { "" }
+        );
+    }
+    println!("timer initial {:#x} current {:#x} divide {:#x}", reg(0x380)?, reg(0x390)?, reg(0x3E0)?);
+    let (errors, thermal) = crate::hal::apic::lvt_event_counts();
+    println!("APIC errors {} thermal events {} spurious {}", errors, thermal, crate::hal::apic::spurious_count());
+    println!("ISR {:x?}", lapic_vectors(0x100));
+    println!("TMR {:x?}", lapic_vectors(0x180));
+    println!("IRR {:x?}", lapic_vectors(0x200));
+    Ok(())
+}
+
+fn cpus(_args: &[&str]) -> Result<(), String> {
+    let madt = crate::devices::acpi::get_local_apics();
+    if madt.is_empty() {
+        return Err(String::from("cpus: no processors in the MADT"));
+    }
+    let online: Vec<(usize, u8)> = (0..crate::arch::task::MAX_CPUS)
+        .filter_map(|cpu| crate::arch::task::cpu_apic_id(cpu).map(|id| (cpu, id)))
+        .collect();
+    println!("APIC  UID  STATE");
+    for c in &madt {
+        let state = match online.iter().find(|&&(_, id)| id == c.apic_id) {
+            Some(&(cpu, _)) => format!("cpu{}", cpu),
+            None if c.enabled => String::from("offline"),
+            None => String::from("hot-pluggable"),
+        };
+        println!("{:>4}  {:>3}  {}", c.apic_id, c.processor_id, state);
+    }
+    println!("{} of {} online, this is cpu{}", crate::hal::smp::cpu_count(), madt.len(), crate::hal::smp::current_cpu());
+    Ok(())
+}
+
+fn fbbench(args: &[&str]) -> Result<(), String> {
+    let lines = match args.get(1) {
+        Some(n) => parse_number(n)? as usize,
+        None => 200,
+    };
+    let (batched, per_glyph) = crate::driver_framework::drivers::console::console_text_benchmark(lines)
+        .ok_or_else(|| String::from("fbbench: no framebuffer console"))?;
+    println!("{} lines: {} us by rows, {} us by glyphs ({}.{}x)", lines, batched / 1000, per_glyph / 1000,
+        per_glyph / batched.max(1), per_glyph * 10 / batched.max(1) % 10);
+    Ok(())
+}
+
+fn pcicfg(args: &[&str]) -> Result<(), String> {
+    let text = arg(args, 1)?;
+    let addr = PciAddress::parse(text).ok_or_else(|| format!("pcicfg: bad address {} (want [SSSS:]BB:DD.F)", text))?;
+    if addr.config_read(0) & 0xFFFF == 0xFFFF {
+        return Err(format!("pcicfg: no device at {}", addr));
+    }
+    // the header and capability list; extended space needs ECAM
+    let mut space = [0u8; 256];
+    for (i, dword) in space.chunks_mut(4).enumerate() {
+        dword.copy_from_slice(&addr.config_read(i as u16 * 4).to_le_bytes());
+    }
+    for (i, line) in space.chunks(16).enumerate() {
+        print_hex_line(i as u64 * 16, line);
+    }
+    Ok(())
+}
+
+fn print_entry(level: &str, index: usize, flags: PageTableFlags, addr: u64) {
+    println!("{} [{:>3}] {:#014x} {:?}", level, index, addr, flags);
+}
+
+fn vmmap(args: &[&str]) -> Result<(), String> {
+    let addr = parse_number(arg(args, 1)?)?;
+    let virt = VirtAddr::try_new(addr).map_err(|_| format!("vmmap: {:#x} is not canonical", addr))?;
+    let (frame, _) = Cr3::read();
+    println!("CR3 {:#x}", frame.start_address().as_u64());
+    let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+    // bits of the address below each level's page size
+    let page_bits = [0u32, 30, 21, 12];
+    let mut table_phys = frame.start_address().as_u64();
+    for (level, (name, index)) in ["PML4", "PDPT", "PD  ", "PT  "].into_iter().zip(indices).enumerate() {
+        let table = unsafe { &*((table_phys + phys_offset()) as *const PageTable) };
+        let entry = &table[index];
+        print_entry(name, usize::from(index), entry.flags(), entry.addr().as_u64());
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            println!("{:#x} is not mapped", addr);
+            return Ok(());
+        }
+        let leaf = level == 3 || (level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE));
+        if leaf {
+            let mask = (1u64 << page_bits[level]) - 1;
+            println!("{:#x} -> phys {:#x} ({} KiB page)", addr, entry.addr().as_u64() + (addr & mask), (mask + 1) / 1024);
+            return Ok(());
+        }
+        table_phys = entry.addr().as_u64();
+    }
+    Ok(())
+}
+
+fn crashdump(args: &[&str]) -> Result<(), String> {
+    use crate::arch::crashdump::{clear_stored_dump, crashdump_region, read_stored_dump, set_crashdump_region};
+    match args.get(1).copied() {
+        None => match crashdump_region() {
+            Some((dev, lba)) => println!("dump region {} at LBA {}", dev, lba),
+            None => println!("no dump region (set one with crashdump=DEVICE:LBA)"),
+        },
+        Some("show") => match read_stored_dump()? {
+            Some(text) => print!("{}", text),
+            None => println!("no crash dump stored"),
+        },
+        Some("clear") => clear_stored_dump()?,
+        Some("set") => {
+            let dev = arg(args, 2)?;
+            if crate::storage::block_device(dev).is_none() {
+                return Err(format!("crashdump: no block device {}", dev));
+            }
+            set_crashdump_region(Some((dev, parse_number(arg(args, 3)?)?)));
+        }
+        Some("off") => set_crashdump_region(None),
+        Some(other) => return Err(format!("crashdump: unknown subcommand {}", other)),
+    }
+    Ok(())
+}
+
+fn lockdep(_args: &[&str]) -> Result<(), String> {
+    use crate::sync::lockdep::{lock_classes, lockdep_stats, LOCKDEP};
+    if !LOCKDEP {
+        return Err(String::from("lockdep: not built in (--features lockdep)"));
+    }
+    let found = lockdep_stats();
+    println!(
+        "{} lock classes, {} orders, {} reports{}",
+        found.classes,
+        found.orders,
+        found.reports,
+        if found.overflowed { " (tables full; some locks unchecked)" } else { "" }
+    );
+    for class in lock_classes() {
+        let irq = match (class.in_irq, class.irqs_on) {
+            (Some(_), Some(_)) => "irq+on",
+            (Some(_), None) => "irq",
+            (None, Some(_)) => "on",
+            (None, None) => "-",
+        };
+        println!("{:>10} {:<6} {:>3} before  {}", class.acquisitions, irq, class.before, class.created);
+    }
+    Ok(())
+}
+
+fn ps2(args: &[&str]) -> Result<(), String> {
+    use crate::driver_framework::drivers::ps2::{keyboard_leds, mouse_sample_rate, ps2_port_status, set_mouse_sample_rate, Ps2Port};
+    if let Some(&"rate") = args.get(1) {
+        let rate = parse_number(arg(args, 2)?)?;
+        if !u8::try_from(rate).is_ok_and(set_mouse_sample_rate) {
+            return Err(format!("ps2: {} isn't a PS/2 sample rate", rate));
+        }
+        return Ok(());
+    }
+    for port in [Ps2Port::Keyboard, Ps2Port::Mouse] {
+        let st = ps2_port_status(port);
+        println!(
+            "{:<5} {:<7} {} resets, {} disconnects, {} re-inits",
+            port.name(),
+            if st.present { "present" } else { "absent" },
+            st.resets,
+            st.disconnects,
+            st.reinits
+        );
+    }
+    println!("leds {:#x}, mouse sample rate {}/s", keyboard_leds(), mouse_sample_rate());
+    println!(
+        "keyboard scancode set {}, controller translation {}",
+        crate::input::keyboard_scancodes() as u8,
+        if crate::driver_framework::drivers::ps2kbd::controller_translates() { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+fn asserts(_args: &[&str]) -> Result<(), String> {
+    println!("policy {:?}", crate::kassert::POLICY);
+    crate::kassert::for_each_site(|site| {
+        let kind = match site.kind {
+            crate::kassert::SiteKind::Assert => "assert",
+            crate::kassert::SiteKind::Warn => "warn",
+        };
+        println!("{:>6} {:<6} {}:{}", site.hits(), kind, site.file, site.line);
+    });
+    Ok(())
+}
+
+fn heapstats(args: &[&str]) -> Result<(), String> {
+    use crate::memory::heaptrack;
+    match args.get(1).copied() {
+        None => heaptrack::print_heap_stats(10),
+        Some("on") => heaptrack::set_heap_tracking(true),
+        Some("off") => heaptrack::set_heap_tracking(false),
+        Some("snap") => heaptrack::heap_snapshot(),
+        Some("diff") => heaptrack::print_heap_diff().map_err(|e| format!("heapstats: {}", e))?,
+        Some(top) => heaptrack::print_heap_stats(parse_number(top)? as usize),
+    }
+    Ok(())
+}
+
+fn fault(args: &[&str]) -> Result<(), String> {
+    use crate::fault::{fault_rate, faults_injected, set_fault_rate, FaultPoint};
+    let Some(name) = args.get(1) else {
+        for point in FaultPoint::ALL {
+            match fault_rate(point) {
+                0 => println!("{:<9} off      {} injected", point.name(), faults_injected(point)),
+                n => println!("{:<9} 1 in {:<4} {} injected", point.name(), n, faults_injected(point)),
+            }
+        }
+        return Ok(());
+    };
+    if *name == "off" {
+        FaultPoint::ALL.into_iter().for_each(|p| set_fault_rate(p, 0));
+        return Ok(());
+    }
+    let point = FaultPoint::from_name(name).ok_or_else(|| format!("fault: unknown point {}", name))?;
+    let rate = match arg(args, 2)? {
+        "off" => 0,
+        n => parse_number(n)? as u32,
+    };
+    set_fault_rate(point, rate);
+    Ok(())
+}
+
+fn bootlog(_args: &[&str]) -> Result<(), String> {
+    crate::time::print_boot_timeline();
+    Ok(())
+}
+
+fn timers(args: &[&str]) -> Result<(), String> {
+    use crate::arch::tsc_timer::{set_tickless, tickless, timer_kind};
+    match args.get(1..) {
+        Some(["tickless", "on"]) => set_tickless(true),
+        Some(["tickless", "off"]) => set_tickless(false),
+        Some([]) | None => {}
+        _ => return Err(String::from("usage: timers [tickless on|off]")),
+    }
+    match timer_kind() {
+        Some(kind) => println!("tick: {:?}, tickless idle {}", kind, if tickless() { "on" } else { "off" }),
+        None => println!("tick: no timer interrupt"),
+    }
+    let pending = crate::time::pending_timers();
+    println!("{} pending, {} callbacks run", pending.len(), crate::time::timers_fired());
+    println!("{:>6} {:>12} {:>12}", "ID", "DUE(us)", "PERIOD(us)");
+    for (id, due, period) in pending {
+        let period = if period == 0 { String::from("-") } else { format!("{}", period / 1000) };
+        println!("{:>6} {:>12} {:>12}", id.0, due / 1000, period);
+    }
+    Ok(())
+}
+
+pub(super) fn register_diagnostics() {
+    let diagnostics = [
+        Command { name: "acpi", usage: "[SIGNATURE]", help: "list ACPI tables, or dump one (e.g. APIC, DSDT)", run: acpi_cmd },
+        Command { name: "caches", usage: "", help: "CPU cache levels and the CLFLUSH line size", run: caches },
+        Command { name: "ioapic", usage: "", help: "I/O APIC redirection tables", run: ioapic },
+        Command { name: "ioports", usage: "", help: "I/O port ranges and the controllers that claimed them", run: ioports },
+        Command { name: "lapic", usage: "", help: "local APIC registers of this CPU", run: lapic },
+        Command { name: "cpus", usage: "", help: "processors in the MADT and which CPU each runs as", run: cpus },
+        Command { name: "iommu", usage: "", help: "VT-d units, device domains, DMA faults and mappings", run: iommu },
+        Command { name: "bars", usage: "", help: "memory BARs drivers have mapped", run: bars },
+        Command { name: "pcicfg", usage: "[SSSS:]BB:DD.F", help: "PCI configuration space of one function", run: pcicfg },
+        Command { name: "heapstats", usage: "[TOP | on | off | snap | diff]", help: "heap use per allocation tag", run: heapstats },
+        Command { name: "fault", usage: "[POINT N|off] | off", help: "fault injection rates (fail one in N)", run: fault },
+        Command { name: "timers", usage: "[tickless on|off]", help: "tick mode and pending timer callbacks", run: timers },
+        Command { name: "bootlog", usage: "", help: "time taken by each boot phase", run: bootlog },
+        Command { name: "lockdep", usage: "", help: "lock classes and what lockdep has found", run: lockdep },
+        Command { name: "ps2", usage: "[rate N]", help: "PS/2 device presence and hot-plug counts; mouse sample rate", run: ps2 },
+        Command { name: "asserts", usage: "", help: "kassert!/kwarn_once! sites that have fired", run: asserts },
+        Command { name: "crashdump", usage: "[show | clear | set DEVICE LBA | off]", help: "the stored crash dump and its region", run: crashdump },
+        Command { name: "vmmap", usage: "ADDR", help: "page-table walk for a virtual address", run: vmmap },
+        Command { name: "fbbench", usage: "[LINES]", help: "framebuffer console text speed, a row of glyphs vs a glyph at a time", run: fbbench },
+    ];
+    for cmd in diagnostics {
+        register_command(cmd);
+    }
+}
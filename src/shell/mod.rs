@@ -0,0 +1,159 @@
+//! Kernel shell
+//!
+//! An executor task that reads lines from the console with `getline()`,
+//! splits them into words (double quotes group words with spaces) and runs
+//! the named command from the registry. Subsystems add their own commands
+//! with `register_command`; the built-ins live in `builtins` and the
+//! hardware dumps in `diag`. A word that isn't a command is run as a
+//! program (`/bin/NAME` unless it contains a `/`), and the shell waits for
+//! it to exit before prompting again.
+
+pub mod builtins;
+pub mod diag;
+
+use crate::prelude::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const PROMPT: &str = "neutrix> ";
+
+/// Runs a command. `args[0]` is the command name.
+pub type CommandFn = fn(&[&str]) -> Result<(), String>;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, as shown by `help`: `"PATH [OFFSET [LEN]]"`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Add `cmd`, replacing a command of the same name.
+pub fn register_command(cmd: Command) {
+    interrupts::without_interrupts(|| {
+        let mut commands = COMMANDS.lock();
+        match commands.iter_mut().find(|c| c.name == cmd.name) {
+            Some(c) => *c = cmd,
+            None => commands.push(cmd),
+        }
+    });
+}
+
+pub fn find_command(name: &str) -> Option<Command> {
+    interrupts::without_interrupts(|| COMMANDS.lock().iter().find(|c| c.name == name).copied())
+}
+
+/// Registered commands, sorted by name.
+pub fn commands() -> Vec<Command> {
+    let mut all = interrupts::without_interrupts(|| COMMANDS.lock().clone());
+    all.sort_by_key(|c| c.name);
+    all
+}
+
+/// Split a line into words. Double quotes group, and a backslash escapes
+/// the next character.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                word.push(chars.next().ok_or("trailing backslash")?);
+                in_word = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(String::from("unterminated quote"));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Parse a number given in decimal or with a `0x` prefix.
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    parsed.map_err(|_| alloc::format!("not a number: {}", s))
+}
+
+/// Fetch argument `i` or fail with the command's usage.
+pub fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i).copied().ok_or_else(|| match find_command(args[0]) {
+        Some(cmd) => alloc::format!("usage: {} {}", cmd.name, cmd.usage),
+        None => String::from("missing argument"),
+    })
+}
+
+async fn run_program(args: &[&str]) -> Result<(), String> {
+    let path = if args[0].contains('/') { String::from(args[0]) } else { alloc::format!("/bin/{}", args[0]) };
+    let pid = crate::process::spawn_process(&path, args, crate::process::DEFAULT_ENV, None)
+        .map_err(|e| alloc::format!("{}: {}", args[0], e))?;
+    let status = crate::process::wait_process(pid).await.map_err(|e| alloc::format!("{}: {}", args[0], e))?;
+    if status != 0 {
+        println!("[{} exited with status {}]", args[0], status);
+    }
+    Ok(())
+}
+
+/// Run one command line.
+pub async fn run_line(line: &str) {
+    let words = match split_words(line) {
+        Ok(w) => w,
+        Err(e) => {
+            println!("shell: {}", e);
+            return;
+        }
+    };
+    let args: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    let Some(&name) = args.first() else { return };
+    let result = match find_command(name) {
+        Some(cmd) => (cmd.run)(&args),
+        None => run_program(&args).await,
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+async fn shell_task() {
+    println!("neutrix shell; 'help' lists commands");
+    loop {
+        print!("{}", PROMPT);
+        let line = crate::driver_framework::drivers::ps2kbd::getline().await;
+        run_line(line.trim()).await;
+    }
+}
+
+/// Register the built-in commands and start the shell on the BSP.
+pub fn start_shell() {
+    builtins::register_builtins();
+    diag::register_diagnostics();
+    crate::arch::task::spawn_named_on(0, "shell", shell_task());
+}
@@ -0,0 +1,396 @@
+//! Block request layer
+//!
+//! Each registered `BlockDevice` gets a `BlockQueue`: callers submit
+//! `BlockRequest`s and get a `BlockFuture` (or a completion callback) back,
+//! and a per-device worker task drains the queue. The worker merges runs of
+//! adjacent requests of the same kind into one device transfer and retries
+//! transient errors according to the queue's `RetryPolicy`.
+//!
+//! Code that runs before the executor (e.g. mounting the root filesystem
+//! during boot) can use the `*_blocking` helpers, which drain the queue in
+//! the caller's context.
+
+use crate::prelude::*;
+use crate::storage::blockdev::{BlockDevice, BlockError};
+use crate::sync::{WaitQueue, Waiter};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Largest transfer, in blocks, the worker builds by merging requests.
+pub const MAX_MERGE_BLOCKS: u64 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+    Flush,
+}
+
+/// Result of a request: the request's buffer (filled for reads) or an error.
+pub type BlockResult = Result<Vec<u8>, BlockError>;
+
+pub struct BlockRequest {
+    pub op: BlockOp,
+    pub lba: u64,
+    /// Read: sized to the number of bytes wanted. Write: the data. Flush: empty.
+    pub buffer: Vec<u8>,
+}
+
+impl BlockRequest {
+    pub fn read(lba: u64, len: usize) -> Self {
+        BlockRequest { op: BlockOp::Read, lba, buffer: vec![0u8; len] }
+    }
+
+    pub fn write(lba: u64, data: Vec<u8>) -> Self {
+        BlockRequest { op: BlockOp::Write, lba, buffer: data }
+    }
+
+    pub fn flush() -> Self {
+        BlockRequest { op: BlockOp::Flush, lba: 0, buffer: Vec::new() }
+    }
+}
+
+/// How the worker reacts to device errors.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first failure for `Io`/`Timeout` errors.
+    pub max_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_retries: 3 }
+    }
+}
+
+struct Completion {
+    result: Mutex<Option<BlockResult>>,
+    callback: Mutex<Option<Box<dyn FnOnce(&BlockResult) + Send>>>,
+    wait: WaitQueue,
+}
+
+impl Completion {
+    fn new() -> Arc<Completion> {
+        Arc::new(Completion { result: Mutex::new(None), callback: Mutex::new(None), wait: WaitQueue::new() })
+    }
+
+    fn complete(&self, result: BlockResult) {
+        if let Some(cb) = self.callback.lock().take() {
+            cb(&result);
+        }
+        *self.result.lock() = Some(result);
+        self.wait.wake_all();
+    }
+}
+
+/// Future for a submitted request.
+pub struct BlockFuture {
+    completion: Arc<Completion>,
+    waiter: Waiter,
+}
+
+impl Future for BlockFuture {
+    type Output = BlockResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<BlockResult> {
+        let this = self.get_mut();
+        let completion = &this.completion;
+        this.waiter.poll_until(&completion.wait, cx, || completion.result.lock().take())
+    }
+}
+
+impl Drop for BlockFuture {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.completion.wait);
+    }
+}
+
+struct Pending {
+    req: BlockRequest,
+    completion: Arc<Completion>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockQueueStats {
+    pub submitted: u64,
+    /// Requests folded into a neighbour's transfer.
+    pub merged: u64,
+    pub retries: u64,
+    pub errors: u64,
+}
+
+pub struct BlockQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: Mutex<VecDeque<Pending>>,
+    /// Serializes device access between the worker and blocking callers.
+    io: Mutex<()>,
+    wait: WaitQueue,
+    policy: Mutex<RetryPolicy>,
+    submitted: AtomicU64,
+    merged: AtomicU64,
+    retries: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl BlockQueue {
+    fn new(device: Arc<dyn BlockDevice>) -> Self {
+        BlockQueue {
+            device,
+            pending: Mutex::new(VecDeque::new()),
+            io: Mutex::new(()),
+            wait: WaitQueue::new(),
+            policy: Mutex::new(RetryPolicy::default()),
+            submitted: AtomicU64::new(0),
+            merged: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.policy.lock() = policy;
+    }
+
+    pub fn stats(&self) -> BlockQueueStats {
+        BlockQueueStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            merged: self.merged.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Queue a request; the returned future resolves when it completes.
+    pub fn submit(&self, req: BlockRequest) -> BlockFuture {
+        let completion = Completion::new();
+        self.enqueue(req, completion.clone());
+        BlockFuture { completion, waiter: Waiter::new() }
+    }
+
+    /// Queue a request and call `callback` from the worker when it completes.
+    pub fn submit_with_callback(&self, req: BlockRequest, callback: impl FnOnce(&BlockResult) + Send + 'static) {
+        let completion = Completion::new();
+        *completion.callback.lock() = Some(Box::new(callback));
+        self.enqueue(req, completion);
+    }
+
+    fn enqueue(&self, req: BlockRequest, completion: Arc<Completion>) {
+        // reject malformed requests up front so merging only sees valid ones
+        if req.op != BlockOp::Flush {
+            if let Err(e) = self.device.check_range(req.lba, req.buffer.len()) {
+                completion.complete(Err(e));
+                return;
+            }
+            if req.op == BlockOp::Write && self.device.is_read_only() {
+                completion.complete(Err(BlockError::ReadOnly));
+                return;
+            }
+        }
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+        interrupts::without_interrupts(|| self.pending.lock().push_back(Pending { req, completion }));
+        self.wait.wake_one();
+    }
+
+    pub async fn read(&self, lba: u64, len: usize) -> BlockResult {
+        self.submit(BlockRequest::read(lba, len)).await
+    }
+
+    pub async fn write(&self, lba: u64, data: Vec<u8>) -> Result<(), BlockError> {
+        self.submit(BlockRequest::write(lba, data)).await.map(|_| ())
+    }
+
+    pub async fn flush(&self) -> Result<(), BlockError> {
+        self.submit(BlockRequest::flush()).await.map(|_| ())
+    }
+
+    /// Read synchronously, draining the queue in the caller's context.
+    pub fn read_blocking(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let data = self.run_blocking(BlockRequest::read(lba, buf.len()))?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Write synchronously, draining the queue in the caller's context.
+    pub fn write_blocking(&self, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.run_blocking(BlockRequest::write(lba, data.to_vec())).map(|_| ())
+    }
+
+    pub fn flush_blocking(&self) -> Result<(), BlockError> {
+        self.run_blocking(BlockRequest::flush()).map(|_| ())
+    }
+
+    fn run_blocking(&self, req: BlockRequest) -> BlockResult {
+        let completion = Completion::new();
+        self.enqueue(req, completion.clone());
+        loop {
+            if let Some(result) = completion.result.lock().take() {
+                return result;
+            }
+            if !self.process_batch() {
+                // the worker owns our request; wait for it to finish
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Take the next request plus any adjacent ones it can be merged with.
+    fn take_batch(&self) -> Vec<Pending> {
+        interrupts::without_interrupts(|| {
+            let mut pending = self.pending.lock();
+            let first = match pending.pop_front() {
+                Some(p) => p,
+                None => return Vec::new(),
+            };
+            let bs = self.device.block_size() as u64;
+            let op = first.req.op;
+            let mut end = first.req.lba + first.req.buffer.len() as u64 / bs;
+            let start = first.req.lba;
+            let mut batch = vec![first];
+            if op == BlockOp::Flush {
+                return batch;
+            }
+            while let Some(next) = pending.front() {
+                let blocks = next.req.buffer.len() as u64 / bs;
+                if next.req.op != op || next.req.lba != end || end + blocks - start > MAX_MERGE_BLOCKS {
+                    break;
+                }
+                end += blocks;
+                batch.push(pending.pop_front().unwrap());
+            }
+            batch
+        })
+    }
+
+    /// Run one (possibly merged) transfer. Returns false if nothing was queued.
+    fn process_batch(&self) -> bool {
+        let _io = self.io.lock();
+        let mut batch = self.take_batch();
+        if batch.is_empty() {
+            return false;
+        }
+        self.merged.fetch_add(batch.len() as u64 - 1, Ordering::Relaxed);
+        let op = batch[0].req.op;
+        let lba = batch[0].req.lba;
+
+        if batch.len() == 1 {
+            let p = batch.pop().unwrap();
+            let mut buffer = p.req.buffer;
+            let result = self.transfer(op, lba, &mut buffer).map(|_| buffer);
+            p.completion.complete(result);
+            return true;
+        }
+
+        let total: usize = batch.iter().map(|p| p.req.buffer.len()).sum();
+        let mut buffer = Vec::with_capacity(total);
+        match op {
+            BlockOp::Write => batch.iter().for_each(|p| buffer.extend_from_slice(&p.req.buffer)),
+            _ => buffer.resize(total, 0),
+        }
+        match self.transfer(op, lba, &mut buffer) {
+            Ok(()) => {
+                let mut off = 0;
+                for p in batch {
+                    let len = p.req.buffer.len();
+                    let mut out = p.req.buffer;
+                    if op == BlockOp::Read {
+                        out.copy_from_slice(&buffer[off..off + len]);
+                    }
+                    off += len;
+                    p.completion.complete(Ok(out));
+                }
+            }
+            Err(e) => batch.into_iter().for_each(|p| p.completion.complete(Err(e))),
+        }
+        true
+    }
+
+    fn transfer(&self, op: BlockOp, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let policy = *self.policy.lock();
+        let mut attempt = 0;
+        loop {
+            let result = match op {
+                BlockOp::Read => self.device.read_blocks(lba, buffer),
+                BlockOp::Write => self.device.write_blocks(lba, buffer),
+                BlockOp::Flush => self.device.flush(),
+            };
+            match result {
+                Err(BlockError::Io) | Err(BlockError::Timeout) if attempt < policy.max_retries => {
+                    attempt += 1;
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    async fn worker(self: Arc<Self>) {
+        loop {
+            let queue = &self;
+            queue.wait.wait_until(|| interrupts::without_interrupts(|| !queue.pending.lock().is_empty()).then_some(())).await;
+            while queue.process_batch() {
+                crate::arch::task::yield_now().await;
+            }
+        }
+    }
+}
+
+static QUEUES: Mutex<Vec<Arc<BlockQueue>>> = Mutex::new(Vec::new());
+
+/// Put `device` behind a request queue and start its worker task.
+pub fn register_block_device(device: Arc<dyn BlockDevice>) -> Arc<BlockQueue> {
+    let queue = Arc::new(BlockQueue::new(device));
+    println!("[BLOCK] {}: {} blocks of {} bytes", queue.name(), queue.num_blocks(), queue.block_size());
+    QUEUES.lock().push(queue.clone());
+    crate::arch::task::spawn_named("blkq", queue.clone().worker());
+    queue
+}
+
+/// Look up a registered device's queue by name.
+pub fn block_device(name: &str) -> Option<Arc<BlockQueue>> {
+    QUEUES.lock().iter().find(|q| q.name() == name).cloned()
+}
+
+/// `block_device` for the panic path: gives up instead of spinning when the
+/// device list is locked.
+pub fn try_block_device(name: &str) -> Option<Arc<BlockQueue>> {
+    QUEUES.try_lock()?.iter().find(|q| q.name() == name).cloned()
+}
+
+pub fn block_devices() -> Vec<Arc<BlockQueue>> {
+    QUEUES.lock().clone()
+}
+
+/// Name of each registered device, for diagnostics.
+pub fn block_device_names() -> Vec<String> {
+    QUEUES.lock().iter().map(|q| String::from(q.name())).collect()
+}
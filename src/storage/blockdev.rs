@@ -0,0 +1,78 @@
+use crate::prelude::*;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// LBA range runs past the end of the device.
+    OutOfRange,
+    /// Buffer length isn't a whole number of blocks.
+    BadBuffer,
+    ReadOnly,
+    /// The device reported a transfer error.
+    Io,
+    Timeout,
+    NoDevice,
+    /// Someone else holds the device; see `BlockDevice::write_blocks_nowait`.
+    Busy,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BlockError::OutOfRange => "LBA out of range",
+            BlockError::BadBuffer => "buffer is not a multiple of the block size",
+            BlockError::ReadOnly => "device is read-only",
+            BlockError::Io => "I/O error",
+            BlockError::Timeout => "timed out",
+            BlockError::NoDevice => "no such device",
+            BlockError::Busy => "device locked",
+        };
+        f.write_str(s)
+    }
+}
+
+impl From<crate::time::TimeoutError> for BlockError {
+    fn from(_: crate::time::TimeoutError) -> BlockError {
+        BlockError::Timeout
+    }
+}
+
+/// A device addressed in fixed-size blocks. Implementations do the transfer
+/// synchronously; queuing, merging and retries live in `storage::block`.
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn block_size(&self) -> usize;
+    fn num_blocks(&self) -> u64;
+
+    /// Read `buf.len() / block_size()` blocks starting at `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / block_size()` blocks starting at `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// `write_blocks` for the panic path: fails with `Busy` instead of
+    /// waiting for a lock, which a stopped CPU may hold for good, and the
+    /// data is on the device when it returns.
+    fn write_blocks_nowait(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Check that `len` bytes at `lba` are block-aligned and on the device.
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        let bs = self.block_size();
+        if bs == 0 || len % bs != 0 {
+            return Err(BlockError::BadBuffer);
+        }
+        let count = (len / bs) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.num_blocks() => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+//! Block storage: the `BlockDevice` trait drivers implement and the request
+//! queue layer (`block`) that filesystems use on top of it.
+
+pub mod blockdev;
+pub use blockdev::*;
+pub mod block;
+pub use block::*;
+pub mod ramdisk;
+pub use ramdisk::*;
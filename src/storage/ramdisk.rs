@@ -0,0 +1,82 @@
+//! Memory-backed block device, used for the initrd and for testing
+//! filesystems without real hardware.
+
+use crate::prelude::*;
+use crate::storage::blockdev::{BlockDevice, BlockError};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub struct RamDisk {
+    name: String,
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Zero-filled disk of `blocks` blocks.
+    pub fn new(name: &str, block_size: usize, blocks: u64) -> Self {
+        RamDisk { name: String::from(name), block_size, data: Mutex::new(vec![0u8; block_size * blocks as usize]), read_only: false }
+    }
+
+    /// Disk backed by an existing image; a trailing partial block is zero-padded.
+    pub fn from_image(name: &str, block_size: usize, mut image: Vec<u8>, read_only: bool) -> Self {
+        let rem = image.len() % block_size;
+        if rem != 0 {
+            image.resize(image.len() + block_size - rem, 0);
+        }
+        RamDisk { name: String::from(name), block_size, data: Mutex::new(image), read_only }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_blocks_nowait(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut data = self.data.try_lock().ok_or(BlockError::Busy)?;
+        // `check_range` would take the lock again for the size
+        if self.block_size == 0 || buf.len() % self.block_size != 0 {
+            return Err(BlockError::BadBuffer);
+        }
+        let start = (lba as usize).checked_mul(self.block_size).ok_or(BlockError::OutOfRange)?;
+        let dest = start.checked_add(buf.len()).and_then(|end| data.get_mut(start..end)).ok_or(BlockError::OutOfRange)?;
+        dest.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,154 @@
+//! Kernel symbol table
+//!
+//! The kernel carries the names of its own functions so backtraces, trace
+//! lines and profiles can say where an address is. `KSYMS` reserves a
+//! fixed-size `.ksyms` section that holds only a header when linked; after
+//! linking, `tools/gen_ksyms.py` fills it from the ELF symbol table with
+//! `objcopy --update-section`, in place, so no address moves. A kernel that
+//! skipped that step has an empty table and addresses print as numbers.
+//!
+//! Layout, little-endian: the magic, the entry count (u32) and the offset
+//! of the names from the start of the section (u32), then `count` entries
+//! of address (u64), name offset (u32) and size (u32), sorted by address,
+//! then the NUL-terminated names. Lookups binary-search the section where
+//! it lies, without allocating or locking, so the panic path can use them.
+
+use crate::prelude::*;
+use core::fmt;
+
+/// Bytes reserved for the table; `gen_ksyms.py` fails if the names don't fit.
+pub const KSYMS_SIZE: usize = 1024 * 1024;
+pub const KSYMS_MAGIC: &[u8; 8] = b"NXKSYMS1";
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 16;
+
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = {
+    let mut blob = [0; KSYMS_SIZE];
+    let mut i = 0;
+    while i < KSYMS_MAGIC.len() {
+        blob[i] = KSYMS_MAGIC[i];
+        i += 1;
+    }
+    blob
+};
+
+/// A symbol table in the `.ksyms` layout.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+    names: usize,
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+impl<'a> SymbolTable<'a> {
+    /// `None` if `data` doesn't start with the magic or the entries run
+    /// past the end.
+    pub fn parse(data: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if data.get(..KSYMS_MAGIC.len())? != KSYMS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 8)? as usize;
+        let names = read_u32(data, 12)? as usize;
+        let entries_end = HEADER_LEN.checked_add(count.checked_mul(ENTRY_LEN)?)?;
+        (entries_end <= names && names <= data.len()).then_some(SymbolTable { data, count, names })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn addr(&self, index: usize) -> u64 {
+        read_u64(self.data, HEADER_LEN + index * ENTRY_LEN).unwrap_or(0)
+    }
+
+    /// Entry `index`: address, size (0 if unknown) and name.
+    pub fn get(&self, index: usize) -> Option<(u64, u32, &'a str)> {
+        if index >= self.count {
+            return None;
+        }
+        let entry = HEADER_LEN + index * ENTRY_LEN;
+        let name_off = self.names.checked_add(read_u32(self.data, entry + 8)? as usize)?;
+        let name = crate::rlib::cstr::cstr_at(self.data, name_off).unwrap_or("?");
+        Some((self.addr(index), read_u32(self.data, entry + 12)?, name))
+    }
+
+    /// The symbol containing `addr` and the offset into it. An address past
+    /// the end of a symbol whose size is known belongs to none.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        // first entry above addr, by binary search over the sorted entries
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.addr(mid) <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let (start, size, name) = self.get(lo.checked_sub(1)?)?;
+        let offset = addr - start;
+        (size == 0 || offset < size as u64).then_some((name, offset))
+    }
+
+    /// The address of `name`. A linear search; not for hot paths.
+    pub fn find(&self, name: &str) -> Option<u64> {
+        (0..self.count).filter_map(|i| self.get(i)).find(|&(_, _, n)| n == name).map(|(addr, _, _)| addr)
+    }
+}
+
+/// The kernel's own table, empty if `gen_ksyms.py` didn't fill it.
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+    // patched after linking: the compiler must not see through to the
+    // initializer
+    let ptr = core::hint::black_box(&raw const KSYMS) as *const u8;
+    SymbolTable::parse(unsafe { core::slice::from_raw_parts(ptr, KSYMS_SIZE) })
+}
+
+/// The kernel symbol containing `addr`, and the offset into it.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    kernel_symbols()?.lookup(addr)
+}
+
+/// The address of the kernel symbol `name`.
+pub fn lookup_name(name: &str) -> Option<u64> {
+    kernel_symbols()?.find(name)
+}
+
+/// Formats an address as `name+0x1c` when it resolves, as hex otherwise.
+/// For trace points and log lines: it allocates nothing.
+#[derive(Clone, Copy)]
+pub struct Sym(pub u64);
+
+impl fmt::Display for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Name backtraces and profiles from now on.
+pub fn init() {
+    match kernel_symbols() {
+        Some(table) if !table.is_empty() => {
+            crate::arch::panic::set_symbol_resolver(resolve);
+            println!("[SYMBOLS] {} kernel symbols", table.len());
+        }
+        _ => println!("[SYMBOLS] no symbol table; run tools/gen_ksyms.py on the kernel"),
+    }
+}
@@ -0,0 +1,233 @@
+//! Bounded async MPSC channels
+//!
+//! `channel::<T>(cap)` returns a `Sender`/`Receiver` pair backed by a
+//! lock-free `ArrayQueue`. `try_send` never blocks or allocates, so IRQ
+//! handlers can feed a channel directly: the message goes in the queue made
+//! up front, and waking the receiver neither frees its wait queue nor grows
+//! the executor's (which has room for every task; see `task::Task::submit`).
+//! Tasks use `send().await` to wait for space and `recv().await` (or the
+//! `Stream` impl) to wait for data.
+//!
+//! The channel closes when every `Sender` or the `Receiver` is dropped.
+
+use crate::prelude::*;
+use crate::sync::waitqueue::{WaitQueue, Waiter};
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+
+struct Chan<T> {
+    queue: ArrayQueue<T>,
+    /// The receiver parks here waiting for data.
+    recv_wait: WaitQueue,
+    /// Senders park here waiting for space.
+    send_wait: WaitQueue,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+/// Create a channel holding at most `capacity` messages.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(Chan {
+        queue: ArrayQueue::new(capacity.max(1)),
+        recv_wait: WaitQueue::new(),
+        send_wait: WaitQueue::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity; the message is handed back.
+    Full(T),
+    /// The receiver is gone; the message is handed back.
+    Closed(T),
+}
+
+/// The receiver was dropped before the message could be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and the queue is drained.
+    Closed,
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send without waiting. Safe to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.chan.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        match self.chan.queue.push(value) {
+            Ok(()) => {
+                self.chan.recv_wait.wake_one();
+                Ok(())
+            }
+            Err(value) => Err(TrySendError::Full(value)),
+        }
+    }
+
+    /// Wait for space and send `value`.
+    pub fn send(&self, value: T) -> SendMessage<'_, T> {
+        SendMessage { sender: self, value: Some(value), waiter: Waiter::new() }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.chan.receiver_alive.load(Ordering::Acquire)
+    }
+
+    /// Number of messages waiting to be received.
+    pub fn len(&self) -> usize {
+        self.chan.queue.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.chan.queue.capacity()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::AcqRel);
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // last sender gone: let the receiver observe the close
+            self.chan.recv_wait.wake_all();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct SendMessage<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Waiter,
+}
+
+impl<'a, T> Unpin for SendMessage<'a, T> {}
+
+impl<'a, T> Future for SendMessage<'a, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        let this = self.get_mut();
+        let sender = this.sender;
+        let value = &mut this.value;
+        this.waiter.poll_until(&sender.chan.send_wait, cx, || {
+            let v = value.take().expect("SendMessage polled after completion");
+            match sender.try_send(v) {
+                Ok(()) => Some(Ok(())),
+                Err(TrySendError::Closed(v)) => Some(Err(SendError(v))),
+                Err(TrySendError::Full(v)) => {
+                    *value = Some(v);
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl<'a, T> Drop for SendMessage<'a, T> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.sender.chan.send_wait);
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.chan.queue.pop() {
+            Some(v) => {
+                self.chan.send_wait.wake_one();
+                Ok(v)
+            }
+            None if self.chan.senders.load(Ordering::Acquire) == 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Poll for the next message; `None` once the channel is closed and drained.
+    pub fn poll_recv(&self, cx: &mut Context) -> Poll<Option<T>> {
+        self.chan.recv_wait.poll_until(cx, || self.next_message())
+    }
+
+    fn next_message(&self) -> Option<Option<T>> {
+        match self.try_recv() {
+            Ok(v) => Some(Some(v)),
+            Err(TryRecvError::Closed) => Some(None),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    /// Wait for the next message; `None` once the channel is closed and drained.
+    pub fn recv(&self) -> Recv<'_, T> {
+        Recv { receiver: self, waiter: Waiter::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chan.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chan.queue.is_empty()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.receiver_alive.store(false, Ordering::Release);
+        self.chan.send_wait.wake_all();
+    }
+}
+
+impl<T> futures_util::stream::Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a Receiver<T>,
+    waiter: Waiter,
+}
+
+impl<'a, T> Unpin for Recv<'a, T> {}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let receiver = this.receiver;
+        this.waiter.poll_until(&receiver.chan.recv_wait, cx, || receiver.next_message())
+    }
+}
+
+impl<'a, T> Drop for Recv<'a, T> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.receiver.chan.recv_wait);
+    }
+}
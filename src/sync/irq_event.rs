@@ -0,0 +1,122 @@
+//! IRQ-to-future bridge
+//!
+//! An `IrqEvent` is bound to an interrupt vector; each interrupt marks it
+//! pending and wakes its waiters, and `wait().await` resumes once it is
+//! pending, clearing it. Interrupts that arrive while nobody is waiting are
+//! coalesced into a single pending event, so a driver's loop looks like:
+//!
+//! ```ignore
+//! static EVENT: IrqEvent = IrqEvent::new();
+//! EVENT.bind(vector);
+//! loop {
+//!     EVENT.wait().await;
+//!     // drain the device
+//! }
+//! ```
+
+use crate::prelude::*;
+use crate::sync::waitqueue::{WaitQueue, Waiter};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU16, Ordering};
+use core::task::{Context, Poll};
+
+// Bound event per vector, read by `dispatch` in interrupt context.
+static EVENTS: [AtomicPtr<IrqEvent>; 256] = [const { AtomicPtr::new(core::ptr::null_mut()) }; 256];
+
+const UNBOUND: u16 = u16::MAX;
+
+pub struct IrqEvent {
+    pending: AtomicBool,
+    count: AtomicU64,
+    vector: AtomicU16,
+    waiters: WaitQueue,
+}
+
+impl IrqEvent {
+    pub const fn new() -> Self {
+        IrqEvent {
+            pending: AtomicBool::new(false),
+            count: AtomicU64::new(0),
+            vector: AtomicU16::new(UNBOUND),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Install an IDT handler for `vector` that signals this event and EOIs.
+    /// Replaces whatever handler the vector had.
+    pub fn bind(&'static self, vector: u8) {
+        EVENTS[vector as usize].store(self as *const IrqEvent as *mut IrqEvent, Ordering::Release);
+        self.vector.store(vector as u16, Ordering::Release);
+        crate::arch::idt::register_irq_fn(vector, dispatch);
+    }
+
+    /// Detach from the vector and restore the default handler.
+    pub fn unbind(&self) {
+        let vector = self.vector.swap(UNBOUND, Ordering::AcqRel);
+        if vector != UNBOUND {
+            crate::arch::idt::unregister_irq_fn(vector as u8);
+            EVENTS[vector as usize].store(core::ptr::null_mut(), Ordering::Release);
+        }
+    }
+
+    /// The vector this event is bound to, if any.
+    pub fn vector(&self) -> Option<u8> {
+        match self.vector.load(Ordering::Acquire) {
+            UNBOUND => None,
+            v => Some(v as u8),
+        }
+    }
+
+    /// Mark the event pending and wake waiters. Called from the bound
+    /// vector's handler, but may also be used to signal it by hand.
+    pub fn signal(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.pending.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    /// Consume a pending event without waiting.
+    pub fn try_take(&self) -> bool {
+        self.pending.swap(false, Ordering::AcqRel)
+    }
+
+    /// Wait until the event is pending and consume it.
+    pub fn wait(&self) -> IrqWait<'_> {
+        IrqWait { event: self, waiter: Waiter::new() }
+    }
+
+    /// Interrupts seen since boot, including coalesced ones.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+fn dispatch(vector: u8) {
+    let event = EVENTS[vector as usize].load(Ordering::Acquire);
+    if !event.is_null() {
+        unsafe { (*event).signal(); }
+    }
+}
+
+/// Future returned by `IrqEvent::wait`.
+pub struct IrqWait<'a> {
+    event: &'a IrqEvent,
+    waiter: Waiter,
+}
+
+impl<'a> Future for IrqWait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let event = this.event;
+        this.waiter.poll_until(&event.waiters, cx, || event.try_take().then_some(()))
+    }
+}
+
+impl<'a> Drop for IrqWait<'a> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.event.waiters);
+    }
+}
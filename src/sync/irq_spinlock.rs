@@ -0,0 +1,114 @@
+//! Spinlock that masks interrupts while held
+//!
+//! A plain `spin::Mutex` taken by both a task and an interrupt handler
+//! deadlocks when the interrupt arrives on the CPU that holds it: the
+//! handler spins on a lock whose holder can't run until it returns.
+//! `IrqSpinlock` disables interrupts before taking the lock and puts the
+//! flag back as it was once the guard is dropped, so the holder always
+//! finishes first. Guards nest; drop them in reverse order, or interrupts
+//! come back on while an inner one is still held.
+//!
+//! Hold it briefly: interrupts wait for as long as the guard lives.
+//!
+//! Like `Spinlock`, it reports to `lockdep`, as a lock that is always
+//! taken with interrupts off.
+
+use crate::prelude::*;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use crate::sync::lockdep;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+pub struct IrqSpinlock<T: ?Sized> {
+    class: &'static Location<'static>,
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSpinlock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        IrqSpinlock { class: Location::caller(), inner: Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// Disable interrupts and spin until the lock is free.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::lock_acquire(self.class, Location::caller(), false);
+        IrqSpinlockGuard { class: self.class, guard: ManuallyDrop::new(self.inner.lock()), were_enabled }
+    }
+
+    /// Release the lock whoever holds it. For the panic path only, once the
+    /// other CPUs are stopped and a holder on this one won't run again.
+    ///
+    /// # Safety
+    /// Nobody else may use the data the holder's guard gives it.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() };
+    }
+
+    /// Take the lock if it is free. Interrupts are left as they were if not.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep::lock_acquired_try(self.class, Location::caller(), false);
+                Some(IrqSpinlockGuard { class: self.class, guard: ManuallyDrop::new(guard), were_enabled })
+            }
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        IrqSpinlock::new(T::default())
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    class: &'static Location<'static>,
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before an interrupt can come in and want it
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::lock_release(self.class);
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Lock dependency checking
+//!
+//! Built with `--features lockdep`, every `Spinlock` and `IrqSpinlock`
+//! acquisition is recorded. Locks are grouped into classes by where they
+//! were created. For each CPU and context (task, or interrupt handler) the
+//! classes held are kept on a stack, and taking B while holding A records
+//! the order A -> B. Three things are reported, each once, with the call
+//! sites involved:
+//!
+//! - taking a lock its own context already holds, which spins forever;
+//! - an order that closes a cycle (A -> B seen before, B -> A now), which
+//!   deadlocks two CPUs that each get halfway;
+//! - a class taken in an interrupt handler and also, elsewhere, with
+//!   interrupts enabled, which deadlocks when the interrupt lands on the
+//!   CPU holding it.
+//!
+//! Reports are printed; nothing stops. A recursive acquisition is printed
+//! at once, since it never gets further; the others wait until the CPU
+//! releases the last lock it holds, so printing can't take a lock the
+//! reporter holds. Without the feature every hook returns at once.
+//!
+//! All bookkeeping is in fixed tables, since the hooks run wherever locks
+//! are taken: classes past `MAX_CLASSES`, orders past `MAX_EDGES` and
+//! locks nested deeper than `MAX_HELD` go unchecked.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const LOCKDEP: bool = cfg!(feature = "lockdep");
+
+/// Lock classes told apart.
+pub const MAX_CLASSES: usize = 256;
+/// Distinct orders remembered, for naming the sites in a report.
+pub const MAX_EDGES: usize = 1024;
+/// Locks held at once per CPU and context.
+pub const MAX_HELD: usize = 16;
+const WORDS: usize = MAX_CLASSES / 64;
+/// Earlier orders quoted in an inversion report.
+const MAX_PATH: usize = 4;
+/// Reports waiting to be printed; more are counted but lost.
+const MAX_PENDING: usize = 8;
+
+type Site = &'static Location<'static>;
+
+#[derive(Clone, Copy)]
+struct Class {
+    key: Site,
+    acquisitions: u64,
+    /// First acquisition in an interrupt handler.
+    in_irq: Option<Site>,
+    /// First acquisition elsewhere with interrupts enabled.
+    irqs_on: Option<Site>,
+    irq_reported: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: u16,
+    site: Site,
+}
+
+#[derive(Clone, Copy)]
+struct HeldStack {
+    locks: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+/// `to` was taken at `site` while `from`, taken at `held_site`, was held.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: u16,
+    to: u16,
+    site: Site,
+    held_site: Site,
+}
+
+struct State {
+    classes: [Class; MAX_CLASSES],
+    nclasses: usize,
+    /// Bit `b` of `after[a]`: b has been taken while holding a.
+    after: [[u64; WORDS]; MAX_CLASSES],
+    edges: [Option<Edge>; MAX_EDGES],
+    nedges: usize,
+    /// Per CPU: task context, then interrupt context.
+    held: [[HeldStack; 2]; MAX_CPUS],
+    pending: Reports<MAX_PENDING>,
+    /// Something didn't fit in a table.
+    overflowed: bool,
+}
+
+const NO_CLASS: Class = Class { key: Location::caller(), acquisitions: 0, in_irq: None, irqs_on: None, irq_reported: false };
+const NO_HELD: HeldStack = HeldStack { locks: [None; MAX_HELD], depth: 0 };
+
+static STATE: Mutex<State> = Mutex::new(State {
+    classes: [NO_CLASS; MAX_CLASSES],
+    nclasses: 0,
+    after: [[0; WORDS]; MAX_CLASSES],
+    edges: [None; MAX_EDGES],
+    nedges: 0,
+    held: [[NO_HELD; 2]; MAX_CPUS],
+    pending: Reports::new(),
+    overflowed: false,
+});
+/// Set while a CPU is in a hook, so the locks its reports take aren't
+/// checked in turn.
+static BUSY: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+enum Report {
+    Recursive { class: Site, site: Site, held_site: Site },
+    Inversion { class: Site, site: Site, held: Site, held_site: Site, earlier: [Option<Edge>; MAX_PATH] },
+    IrqUnsafe { class: Site, in_irq: Site, irqs_on: Site },
+}
+
+/// Up to this many reports come out of one acquisition.
+const MAX_REPORTS: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Reports<const N: usize> {
+    list: [Option<Report>; N],
+    len: usize,
+    lost: usize,
+}
+
+impl<const N: usize> Reports<N> {
+    const fn new() -> Self {
+        Reports { list: [None; N], len: 0, lost: 0 }
+    }
+
+    fn push(&mut self, report: Report) {
+        match self.list.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Some(report);
+                self.len += 1;
+            }
+            None => self.lost += 1,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Report> {
+        self.list[..self.len].iter().flatten()
+    }
+}
+
+fn has(set: &[u64; WORDS], i: usize) -> bool {
+    set[i / 64] & (1 << (i % 64)) != 0
+}
+
+fn insert(set: &mut [u64; WORDS], i: usize) {
+    set[i / 64] |= 1 << (i % 64);
+}
+
+impl State {
+    fn class_index(&mut self, key: Site) -> Option<usize> {
+        let known = self.classes[..self.nclasses].iter().position(|c| core::ptr::eq(c.key, key));
+        if known.is_some() {
+            return known;
+        }
+        if self.nclasses == MAX_CLASSES {
+            self.overflowed = true;
+            return None;
+        }
+        self.classes[self.nclasses] = Class { key, ..NO_CLASS };
+        self.nclasses += 1;
+        Some(self.nclasses - 1)
+    }
+
+    fn key(&self, class: u16) -> Site {
+        self.classes[class as usize].key
+    }
+
+    fn edge(&self, from: usize, to: usize) -> Option<Edge> {
+        self.edges[..self.nedges].iter().flatten().find(|e| e.from as usize == from && e.to as usize == to).copied()
+    }
+
+    /// The orders leading from `from` to `to`, if `to` can be reached.
+    fn path(&self, from: usize, to: usize) -> Option<[Option<Edge>; MAX_PATH]> {
+        let mut parent = [u16::MAX; MAX_CLASSES];
+        let mut queue = [0u16; MAX_CLASSES];
+        let mut seen = [0u64; WORDS];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from as u16;
+        insert(&mut seen, from);
+        while head < tail {
+            let at = queue[head] as usize;
+            head += 1;
+            if at == to {
+                let mut path = [None; MAX_PATH];
+                let (mut node, mut n) = (to, 0);
+                while node != from && n < MAX_PATH {
+                    let prev = parent[node] as usize;
+                    path[n] = self.edge(prev, node);
+                    node = prev;
+                    n += 1;
+                }
+                path[..n].reverse();
+                return Some(path);
+            }
+            for next in 0..self.nclasses {
+                if has(&self.after[at], next) && !has(&seen, next) {
+                    insert(&mut seen, next);
+                    parent[next] = at as u16;
+                    queue[tail] = next as u16;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn note_context(&mut self, class: usize, site: Site, in_irq: bool, irqs_enabled: bool, reports: &mut Reports<MAX_REPORTS>) {
+        let c = &mut self.classes[class];
+        c.acquisitions += 1;
+        if in_irq {
+            c.in_irq.get_or_insert(site);
+        } else if irqs_enabled {
+            c.irqs_on.get_or_insert(site);
+        }
+        if let (Some(in_irq), Some(irqs_on), false) = (c.in_irq, c.irqs_on, c.irq_reported) {
+            c.irq_reported = true;
+            reports.push(Report::IrqUnsafe { class: c.key, in_irq, irqs_on });
+        }
+    }
+
+    fn check_order(&mut self, cpu: usize, ctx: usize, class: usize, site: Site, reports: &mut Reports<MAX_REPORTS>) {
+        let stack = self.held[cpu][ctx];
+        for held in stack.locks[..stack.depth].iter().flatten() {
+            let from = held.class as usize;
+            if from == class {
+                reports.push(Report::Recursive { class: self.key(held.class), site, held_site: held.site });
+                continue;
+            }
+            if has(&self.after[from], class) {
+                continue;
+            }
+            if let Some(earlier) = self.path(class, from) {
+                reports.push(Report::Inversion {
+                    class: self.key(class as u16),
+                    site,
+                    held: self.key(held.class),
+                    held_site: held.site,
+                    earlier,
+                });
+            }
+            // recorded either way, so each pair is reported once
+            insert(&mut self.after[from], class);
+            if self.nedges < MAX_EDGES {
+                self.edges[self.nedges] = Some(Edge { from: held.class, to: class as u16, site, held_site: held.site });
+                self.nedges += 1;
+            } else {
+                self.overflowed = true;
+            }
+        }
+    }
+
+    fn push_held(&mut self, cpu: usize, ctx: usize, class: usize, site: Site) {
+        let stack = &mut self.held[cpu][ctx];
+        if stack.depth == MAX_HELD {
+            self.overflowed = true;
+            return;
+        }
+        stack.locks[stack.depth] = Some(Held { class: class as u16, site });
+        stack.depth += 1;
+    }
+
+    fn pop_held(&mut self, cpu: usize, ctx: usize, class: usize) {
+        let stack = &mut self.held[cpu][ctx];
+        // locks needn't be released in order: drop the newest of this class
+        let Some(at) = stack.locks[..stack.depth].iter().rposition(|h| h.is_some_and(|h| h.class as usize == class)) else {
+            return;
+        };
+        stack.locks.copy_within(at + 1..stack.depth, at);
+        stack.depth -= 1;
+        stack.locks[stack.depth] = None;
+    }
+}
+
+/// Run `f` on the tables unless this CPU is already in a hook, then print
+/// what it found, or what was waiting, if that is safe.
+fn with_state(f: impl FnOnce(&mut State, usize, usize, &mut Reports<MAX_REPORTS>)) {
+    let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+    if BUSY[cpu].swap(true, Ordering::Acquire) {
+        return;
+    }
+    let ctx = crate::arch::idt::in_irq() as usize;
+    let mut found = Reports::<MAX_REPORTS>::new();
+    let (now, pending) = interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        f(&mut state, cpu, ctx, &mut found);
+        let mut now = Reports::<MAX_REPORTS>::new();
+        for report in found.iter() {
+            match report {
+                Report::Recursive { .. } => now.push(*report),
+                _ => state.pending.push(*report),
+            }
+        }
+        let idle = state.held[cpu][ctx].depth == 0;
+        (now, if idle { core::mem::replace(&mut state.pending, Reports::new()) } else { Reports::new() })
+    });
+    for report in now.iter().chain(pending.iter()) {
+        print_report(report);
+    }
+    if pending.lost != 0 {
+        REPORTS.fetch_add(pending.lost, Ordering::Relaxed);
+        println!("[LOCKDEP] {} more reports lost", pending.lost);
+    }
+    BUSY[cpu].store(false, Ordering::Release);
+}
+
+fn print_report(report: &Report) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    match *report {
+        Report::Recursive { class, site, held_site } => {
+            println!("[LOCKDEP] recursive locking at {} of the lock created at {}", site, class);
+            println!("[LOCKDEP]   already held since {}", held_site);
+        }
+        Report::Inversion { class, site, held, held_site, earlier } => {
+            println!("[LOCKDEP] lock order inversion at {}: took the lock created at {}", site, class);
+            println!("[LOCKDEP]   while holding the lock created at {}, taken at {}", held, held_site);
+            println!("[LOCKDEP]   the opposite order was seen:");
+            for e in earlier.iter().flatten() {
+                println!("[LOCKDEP]     {} while holding a lock taken at {}", e.site, e.held_site);
+            }
+        }
+        Report::IrqUnsafe { class, in_irq, irqs_on } => {
+            println!("[LOCKDEP] the lock created at {} is taken in an interrupt handler at {}", class, in_irq);
+            println!("[LOCKDEP]   and with interrupts enabled at {}", irqs_on);
+        }
+    }
+}
+
+/// About to wait for the lock created at `class`. `irqs_enabled` is false
+/// for locks that turn interrupts off themselves.
+#[inline]
+pub fn lock_acquire(class: Site, site: Site, irqs_enabled: bool) {
+    if !LOCKDEP {
+        return;
+    }
+    with_state(|state, cpu, ctx, reports| {
+        let Some(index) = state.class_index(class) else { return };
+        state.note_context(index, site, ctx == 1, irqs_enabled, reports);
+        state.check_order(cpu, ctx, index, site, reports);
+        state.push_held(cpu, ctx, index, site);
+    });
+}
+
+/// Took the lock created at `class` without waiting. A try can't deadlock,
+/// so the order isn't checked, but what is taken next is ordered after it.
+#[inline]
+pub fn lock_acquired_try(class: Site, site: Site, irqs_enabled: bool) {
+    if !LOCKDEP {
+        return;
+    }
+    with_state(|state, cpu, ctx, reports| {
+        let Some(index) = state.class_index(class) else { return };
+        state.note_context(index, site, ctx == 1, irqs_enabled, reports);
+        state.push_held(cpu, ctx, index, site);
+    });
+}
+
+#[inline]
+pub fn lock_release(class: Site) {
+    if !LOCKDEP {
+        return;
+    }
+    with_state(|state, cpu, ctx, _| {
+        if let Some(index) = state.class_index(class) {
+            state.pop_held(cpu, ctx, index);
+        }
+    });
+}
+
+/// Whether this CPU's task context holds a tracked lock. Kernel threads
+/// share that stack, so the scheduler doesn't switch away from one that
+/// does.
+pub fn task_holds_locks() -> bool {
+    if !LOCKDEP {
+        return false;
+    }
+    let cpu = crate::arch::task::current_cpu() % MAX_CPUS;
+    interrupts::without_interrupts(|| STATE.lock().held[cpu][0].depth != 0)
+}
+
+/// A lock class, as the `lockdep` command shows it.
+#[derive(Debug, Clone, Copy)]
+pub struct LockClassInfo {
+    pub created: Site,
+    pub acquisitions: u64,
+    pub in_irq: Option<Site>,
+    pub irqs_on: Option<Site>,
+    /// Classes taken while this one was held.
+    pub before: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockdepStats {
+    pub classes: usize,
+    pub orders: usize,
+    pub reports: usize,
+    /// A table filled up and something went unchecked.
+    pub overflowed: bool,
+}
+
+pub fn lockdep_stats() -> LockdepStats {
+    let state = interrupts::without_interrupts(|| {
+        let s = STATE.lock();
+        (s.nclasses, s.nedges, s.overflowed)
+    });
+    LockdepStats { classes: state.0, orders: state.1, reports: REPORTS.load(Ordering::Relaxed), overflowed: state.2 }
+}
+
+/// Every class seen so far, in the order first taken.
+pub fn lock_classes() -> Vec<LockClassInfo> {
+    // allocated up front: the allocator may not be called under the lock
+    let mut classes = Vec::with_capacity(MAX_CLASSES);
+    interrupts::without_interrupts(|| {
+        let s = STATE.lock();
+        for (i, c) in s.classes[..s.nclasses].iter().enumerate() {
+            classes.push(LockClassInfo {
+                created: c.key,
+                acquisitions: c.acquisitions,
+                in_irq: c.in_irq,
+                irqs_on: c.irqs_on,
+                before: s.after[i].iter().map(|w| w.count_ones() as usize).sum(),
+            });
+        }
+    });
+    classes
+}
@@ -0,0 +1,19 @@
+//! Synchronization primitives for kernel tasks and drivers.
+
+pub mod waitqueue;
+pub use waitqueue::*;
+pub mod mutex;
+pub use mutex::*;
+pub mod rwlock;
+pub use rwlock::*;
+pub mod semaphore;
+pub use semaphore::*;
+pub mod channel;
+pub use channel::*;
+pub mod irq_event;
+pub use irq_event::*;
+pub mod irq_spinlock;
+pub use irq_spinlock::*;
+pub mod spinlock;
+pub use spinlock::*;
+pub mod lockdep;
@@ -0,0 +1,164 @@
+//! Async reader-writer lock. Any number of readers or a single writer may
+//! hold the lock; contending tasks park on a wait queue.
+
+use crate::prelude::*;
+use crate::sync::waitqueue::{WaitQueue, Waiter};
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+// State layout: top bit = writer holds the lock, low bits = reader count.
+const WRITER: usize = 1 << (usize::BITS - 1);
+const READERS_MASK: usize = WRITER - 1;
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock { state: AtomicUsize::new(0), waiters: WaitQueue::new(), data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take a shared lock without waiting.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut cur = self.state.load(Ordering::Acquire);
+        loop {
+            if cur & WRITER != 0 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(cur, cur + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    /// Take the exclusive lock without waiting.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut cur = self.state.load(Ordering::Acquire);
+        loop {
+            if cur & (WRITER | READERS_MASK) != 0 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(cur, WRITER, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(RwLockWriteGuard { lock: self }),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    pub fn read(&self) -> RwLockRead<'_, T> {
+        RwLockRead { lock: self, waiter: Waiter::new() }
+    }
+
+    pub fn write(&self) -> RwLockWrite<'_, T> {
+        RwLockWrite { lock: self, waiter: Waiter::new() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+/// Future returned by `RwLock::read`.
+pub struct RwLockRead<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    waiter: Waiter,
+}
+
+impl<'a, T: ?Sized> Future for RwLockRead<'a, T> {
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockReadGuard<'a, T>> {
+        let this = self.get_mut();
+        let lock = this.lock;
+        this.waiter.poll_until(&lock.waiters, cx, || lock.try_read())
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockRead<'a, T> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.lock.waiters);
+    }
+}
+
+/// Future returned by `RwLock::write`.
+pub struct RwLockWrite<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    waiter: Waiter,
+}
+
+impl<'a, T: ?Sized> Future for RwLockWrite<'a, T> {
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockWriteGuard<'a, T>> {
+        let this = self.get_mut();
+        let lock = this.lock;
+        this.waiter.poll_until(&lock.waiters, cx, || lock.try_write())
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWrite<'a, T> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.lock.waiters);
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        let prev = self.lock.state.fetch_sub(1, Ordering::AcqRel);
+        if prev == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
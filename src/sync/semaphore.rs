@@ -0,0 +1,88 @@
+//! Counting semaphore that parks the waiting task instead of spinning.
+
+use crate::prelude::*;
+use crate::sync::waitqueue::{WaitQueue, Waiter};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { permits: AtomicUsize::new(permits), waiters: WaitQueue::new() }
+    }
+
+    /// Number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    /// Take a permit without waiting. Returns None if none are available.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut cur = self.permits.load(Ordering::Acquire);
+        while cur > 0 {
+            match self.permits.compare_exchange_weak(cur, cur - 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(SemaphorePermit { sem: self }),
+                Err(actual) => cur = actual,
+            }
+        }
+        None
+    }
+
+    /// Wait until a permit is available and take it.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire { sem: self, waiter: Waiter::new() }
+    }
+
+    /// Return `n` permits to the semaphore and wake waiters.
+    pub fn add_permits(&self, n: usize) {
+        self.permits.fetch_add(n, Ordering::AcqRel);
+        // wake everyone: `n` permits may be enough for several waiters
+        self.waiters.wake_all();
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    sem: &'a Semaphore,
+    waiter: Waiter,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let this = self.get_mut();
+        let sem = this.sem;
+        this.waiter.poll_until(&sem.waiters, cx, || sem.try_acquire())
+    }
+}
+
+impl<'a> Drop for Acquire<'a> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&self.sem.waiters);
+    }
+}
+
+/// RAII permit; returns itself to the semaphore on drop.
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Consume the permit without returning it to the semaphore.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        self.sem.add_permits(1);
+    }
+}
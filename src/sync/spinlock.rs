@@ -0,0 +1,74 @@
+//! Spinlock checked by lockdep
+//!
+//! The same lock as `spin::Mutex`, plus calls into `lockdep` on every
+//! acquisition and release. Without the `lockdep` feature those calls
+//! compile to nothing. A lock's class is where it was created, so every
+//! lock made by one `new` call site is checked as one.
+
+use crate::prelude::*;
+use crate::sync::lockdep;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use spin::{Mutex, MutexGuard};
+
+pub struct Spinlock<T: ?Sized> {
+    class: &'static Location<'static>,
+    inner: Mutex<T>,
+}
+
+impl<T> Spinlock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Spinlock { class: Location::caller(), inner: Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Spinlock<T> {
+    /// Spin until the lock is free.
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        lockdep::lock_acquire(self.class, Location::caller(), x86_64::instructions::interrupts::are_enabled());
+        SpinlockGuard { class: self.class, guard: self.inner.lock() }
+    }
+
+    /// Take the lock if it is free.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        lockdep::lock_acquired_try(self.class, Location::caller(), x86_64::instructions::interrupts::are_enabled());
+        Some(SpinlockGuard { class: self.class, guard })
+    }
+
+    /// Where the lock was created.
+    pub fn class(&self) -> &'static Location<'static> {
+        self.class
+    }
+}
+
+pub struct SpinlockGuard<'a, T: ?Sized> {
+    class: &'static Location<'static>,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> Deref for SpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::lock_release(self.class);
+    }
+}
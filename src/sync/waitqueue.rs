@@ -0,0 +1,176 @@
+//! Wait queues for event-driven blocking
+//!
+//! A `WaitQueue` holds the wakers of tasks that are parked until some event
+//! occurs (an IRQ fires, a buffer gains data, a lock is released). Producers
+//! call `wake_one`/`wake_all`; consumers call `register` or use the
+//! `poll_until`/`wait_until` helpers which handle the register-then-recheck
+//! dance needed to avoid lost wakeups.
+//!
+//! The internal lock is only ever taken with interrupts disabled, so it is
+//! safe to wake from IRQ handlers. It is a `Spinlock` rather than an
+//! `IrqSpinlock` so that lockdep sees any use that forgets to. Waking takes
+//! wakers off the queue in place and never frees it: the queue keeps its
+//! capacity, so `register` only allocates when more tasks wait at once than
+//! ever did before, and that happens in the waiting task, not in an IRQ.
+//!
+//! A future that waits keeps a `Waiter`, which takes its waker back off the
+//! queue when the future completes or is dropped. Otherwise the waker would
+//! stay behind: the next wakeup would go to it instead of a live waiter, and
+//! if its task had finished, dropping it there would free the task, in an
+//! interrupt handler if that is where the wakeup came from. A future dropped
+//! after it was woken passes the wakeup on.
+
+use crate::prelude::*;
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use crate::sync::spinlock::Spinlock;
+use x86_64::instructions::interrupts;
+
+pub struct WaitQueue {
+    waiters: Spinlock<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    #[track_caller]
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Spinlock::new(VecDeque::new()) }
+    }
+
+    /// Park `waker` on this queue. Registering a waker that would wake the
+    /// same task as one already queued is a no-op.
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if !waiters.iter().any(|w| w.will_wake(waker)) {
+                waiters.push_back(waker.clone());
+            }
+        });
+    }
+
+    /// Take `waker` (or any waker for the same task) off the queue. Returns
+    /// false if it wasn't there.
+    pub fn deregister(&self, waker: &Waker) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let before = waiters.len();
+            // the caller holds a clone, so none of these is the last reference
+            waiters.retain(|w| !w.will_wake(waker));
+            waiters.len() != before
+        })
+    }
+
+    /// Wake the longest-waiting task. Returns false if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        let waker = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        match waker {
+            Some(w) => { w.wake(); true }
+            None => false,
+        }
+    }
+
+    /// Wake every parked task and return how many were woken. Tasks that
+    /// park again while this runs stay parked.
+    pub fn wake_all(&self) -> usize {
+        let parked = interrupts::without_interrupts(|| self.waiters.lock().len());
+        let mut count = 0;
+        while count < parked && self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+
+    /// True if no task is currently parked on this queue.
+    pub fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.waiters.lock().is_empty())
+    }
+
+    /// Poll `cond` and park the current task if it yields `None`. The
+    /// condition is re-checked after registering so a wakeup racing with
+    /// registration is never lost. Intended for `poll`/`poll_next` impls.
+    pub fn poll_until<T>(&self, cx: &mut Context, mut cond: impl FnMut() -> Option<T>) -> Poll<T> {
+        if let Some(v) = cond() {
+            return Poll::Ready(v);
+        }
+        self.register(cx.waker());
+        match cond() {
+            Some(v) => Poll::Ready(v),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Future resolving once `cond` returns `Some`.
+    pub fn wait_until<T, F: FnMut() -> Option<T>>(&self, cond: F) -> WaitUntil<'_, F> {
+        WaitUntil { queue: self, cond, waiter: Waiter::new() }
+    }
+}
+
+/// The waker a future left on a `WaitQueue`. Futures poll through
+/// `Waiter::poll_until` and call `cancel` from their `Drop`.
+pub struct Waiter {
+    waker: Option<Waker>,
+}
+
+impl Waiter {
+    pub const fn new() -> Waiter {
+        Waiter { waker: None }
+    }
+
+    /// `WaitQueue::poll_until`, remembering the waker while it is queued.
+    pub fn poll_until<T>(&mut self, queue: &WaitQueue, cx: &mut Context, cond: impl FnMut() -> Option<T>) -> Poll<T> {
+        let poll = queue.poll_until(cx, cond);
+        match poll {
+            Poll::Pending => self.waker = Some(cx.waker().clone()),
+            Poll::Ready(_) => self.done(queue),
+        }
+        poll
+    }
+
+    /// `WaitQueue::register`, remembering the waker. The caller rechecks
+    /// whatever it waits for.
+    pub fn register(&mut self, queue: &WaitQueue, cx: &Context) {
+        queue.register(cx.waker());
+        self.waker = Some(cx.waker().clone());
+    }
+
+    /// The future has what it waited for: take its waker off `queue` if no
+    /// wakeup did.
+    pub fn done(&mut self, queue: &WaitQueue) {
+        if let Some(waker) = self.waker.take() {
+            queue.deregister(&waker);
+        }
+    }
+
+    /// The future is going away: take its waker off `queue`, or if a wakeup
+    /// already took it, hand that wakeup to the next waiter.
+    pub fn cancel(&mut self, queue: &WaitQueue) {
+        if let Some(waker) = self.waker.take() {
+            if !queue.deregister(&waker) {
+                queue.wake_one();
+            }
+        }
+    }
+}
+
+/// Future returned by `WaitQueue::wait_until`.
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    cond: F,
+    waiter: Waiter,
+}
+
+impl<'a, T, F: FnMut() -> Option<T> + Unpin> Future for WaitUntil<'a, F> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let this = self.get_mut();
+        this.waiter.poll_until(this.queue, cx, &mut this.cond)
+    }
+}
+
+impl<'a, F> Drop for WaitUntil<'a, F> {
+    fn drop(&mut self) {
+        self.waiter.cancel(self.queue);
+    }
+}
@@ -0,0 +1,1138 @@
+//! Test cases run by the test kernel.
+
+use crate::prelude::*;
+use crate::memory::{self, allocator};
+use crate::testing::test_phys_offset;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+#[test_case]
+fn heap_simple_allocation() {
+    let a = Box::new(41);
+    let b = Box::new(13);
+    assert_eq!(*a, 41);
+    assert_eq!(*b, 13);
+}
+
+#[test_case]
+fn heap_large_vec() {
+    let n = 1000u64;
+    let v: Vec<u64> = (0..n).collect();
+    assert_eq!(v.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn heap_reuses_freed_memory() {
+    // far more than the heap holds at once
+    for i in 0..allocator::HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn frame_allocator_hands_out_distinct_frames() {
+    memory::with_frame_allocator(|frames| {
+        let a = frames.allocate_frame().expect("out of frames");
+        let b = frames.allocate_frame().expect("out of frames");
+        assert_ne!(a, b);
+        assert_eq!(a.start_address().as_u64() % 4096, 0);
+        unsafe {
+            frames.free_frame(a);
+            frames.free_frame(b);
+        }
+    })
+    .expect("no frame allocator");
+}
+
+#[test_case]
+fn frame_allocator_reuses_freed_frames() {
+    memory::with_frame_allocator(|frames| {
+        // drain a batch, free it, and get the same amount back
+        let batch: Vec<_> = (0..64).map(|_| frames.allocate_frame().expect("out of frames")).collect();
+        for &f in &batch {
+            unsafe { frames.free_frame(f) };
+        }
+        for _ in 0..batch.len() {
+            let f = frames.allocate_frame().expect("freed frames not reused");
+            unsafe { frames.free_frame(f) };
+        }
+    })
+    .expect("no frame allocator");
+}
+
+#[test_case]
+fn paging_maps_and_unmaps_a_page() {
+    let mut space = memory::kernel_space().expect("no kernel space");
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x_5555_dead_0000));
+    let frame = memory::with_frame_allocator(|frames| frames.allocate_frame().expect("out of frames"))
+        .expect("no frame allocator");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { space.map(page, frame, flags).expect("map failed") };
+
+    assert_eq!(space.translate(page.start_address()), Some(frame.start_address()));
+    let virt = page.start_address().as_mut_ptr::<u64>();
+    let phys = (test_phys_offset() + frame.start_address().as_u64()) as *const u64;
+    unsafe {
+        virt.write_volatile(0xfeed_f00d);
+        assert_eq!(phys.read_volatile(), 0xfeed_f00d);
+    }
+
+    let unmapped = space.unmap(page).expect("unmap failed");
+    assert_eq!(unmapped, frame);
+    assert_eq!(space.translate(page.start_address()), None);
+    drop(space);
+    memory::with_frame_allocator(|frames| unsafe { frames.free_frame(frame) });
+}
+
+/// A MADT with a local APIC address, one I/O APIC and one override.
+fn known_madt() -> Vec<u8> {
+    let mut t = Vec::new();
+    t.extend_from_slice(b"APIC");
+    t.extend_from_slice(&0u32.to_le_bytes()); // length, patched below
+    t.push(3); // revision
+    t.push(0); // checksum, patched below
+    t.extend_from_slice(b"NEUTRX");
+    t.extend_from_slice(b"TESTMADT");
+    t.extend_from_slice(&1u32.to_le_bytes());
+    t.extend_from_slice(&0u32.to_le_bytes());
+    t.extend_from_slice(&0u32.to_le_bytes());
+    t.extend_from_slice(&0xFEE0_0000u32.to_le_bytes()); // local APIC address
+    t.extend_from_slice(&1u32.to_le_bytes()); // PCAT_COMPAT
+    // I/O APIC: id 7 at 0xFEC0_0000, GSI base 0
+    t.extend_from_slice(&[1, 12, 7, 0]);
+    t.extend_from_slice(&0xFEC0_0000u32.to_le_bytes());
+    t.extend_from_slice(&0u32.to_le_bytes());
+    // override: ISA IRQ 0 -> GSI 2, flags 0
+    t.extend_from_slice(&[2, 10, 0, 0]);
+    t.extend_from_slice(&2u32.to_le_bytes());
+    t.extend_from_slice(&0u16.to_le_bytes());
+    // processors: APIC ids 0 and 2 enabled, 4 online capable, 6 unusable
+    for (uid, apic_id, flags) in [(0u8, 0u8, 1u32), (1, 2, 1), (2, 4, 2), (3, 6, 0)] {
+        t.extend_from_slice(&[0, 8, uid, apic_id]);
+        t.extend_from_slice(&flags.to_le_bytes());
+    }
+
+    let len = t.len() as u32;
+    t[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = t.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    t[9] = 0u8.wrapping_sub(sum);
+    t
+}
+
+#[test_case]
+fn acpi_parses_known_madt() {
+    let madt = known_madt();
+    crate::devices::acpi::parse_madt(madt.as_ptr());
+    assert_eq!(crate::devices::acpi::get_local_apic_address(), Some(0xFEE0_0000));
+    assert_eq!(crate::devices::acpi::madt_pcat_compat(), Some(true));
+    let ioapics = crate::devices::acpi::get_ioapics();
+    assert!(ioapics.iter().any(|io| io.id == 7 && io.addr == 0xFEC0_0000 && io.gsi_base == 0));
+    let isos = crate::devices::acpi::get_isos();
+    assert!(isos.iter().any(|iso| iso.bus == 0 && iso.source == 0 && iso.gsi == 2));
+    let cpus = crate::devices::acpi::get_local_apics();
+    assert!(cpus.iter().any(|c| c.apic_id == 2 && c.processor_id == 1 && c.enabled));
+    assert!(cpus.iter().any(|c| c.apic_id == 4 && !c.enabled));
+    assert!(!cpus.iter().any(|c| c.apic_id == 6));
+}
+
+#[test_case]
+fn acpi_rejects_bad_checksum() {
+    let mut madt = known_madt();
+    madt[44 + 4] = 0xFF; // corrupt the I/O APIC address without fixing the checksum
+    let before = crate::devices::acpi::get_ioapics().len();
+    crate::devices::acpi::parse_madt(madt.as_ptr());
+    assert_eq!(crate::devices::acpi::get_ioapics().len(), before);
+}
+
+/// A DMAR with a catch-all unit, a unit for one function behind a bridge,
+/// and a reserved region.
+fn known_dmar() -> Vec<u8> {
+    let mut t = Vec::new();
+    t.extend_from_slice(b"DMAR");
+    t.extend_from_slice(&0u32.to_le_bytes()); // length, patched below
+    t.push(1); // revision
+    t.push(0); // checksum, patched below
+    t.extend_from_slice(b"NEUTRX");
+    t.extend_from_slice(b"TESTDMAR");
+    t.extend_from_slice(&1u32.to_le_bytes());
+    t.extend_from_slice(&0u32.to_le_bytes());
+    t.extend_from_slice(&0u32.to_le_bytes());
+    t.push(38); // host address width - 1
+    t.push(1); // INTR_REMAP
+    t.extend_from_slice(&[0; 10]);
+    // DRHD: segment 0 @ 0xFED9_0000, endpoint 00:1c.0 -> 02.0 below it
+    t.extend_from_slice(&0u16.to_le_bytes());
+    t.extend_from_slice(&26u16.to_le_bytes());
+    t.extend_from_slice(&[0, 0]);
+    t.extend_from_slice(&0u16.to_le_bytes());
+    t.extend_from_slice(&0xFED9_0000u64.to_le_bytes());
+    t.extend_from_slice(&[1, 10, 0, 0, 0, 0, 0x1c, 0, 2, 0]);
+    // DRHD: catch-all for segment 0 @ 0xFED9_1000, no scopes
+    t.extend_from_slice(&0u16.to_le_bytes());
+    t.extend_from_slice(&16u16.to_le_bytes());
+    t.extend_from_slice(&[1, 0]);
+    t.extend_from_slice(&0u16.to_le_bytes());
+    t.extend_from_slice(&0xFED9_1000u64.to_le_bytes());
+    // RMRR: 0x7C00_0000-0x7C0F_FFFF for 00:14.0
+    t.extend_from_slice(&1u16.to_le_bytes());
+    t.extend_from_slice(&32u16.to_le_bytes());
+    t.extend_from_slice(&[0, 0]);
+    t.extend_from_slice(&0u16.to_le_bytes());
+    t.extend_from_slice(&0x7C00_0000u64.to_le_bytes());
+    t.extend_from_slice(&0x7C0F_FFFFu64.to_le_bytes());
+    t.extend_from_slice(&[1, 8, 0, 0, 0, 0, 0x14, 0]);
+
+    let len = t.len() as u32;
+    t[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = t.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    t[9] = 0u8.wrapping_sub(sum);
+    t
+}
+
+#[test_case]
+fn acpi_parses_known_dmar() {
+    let dmar = known_dmar();
+    crate::devices::acpi::parse_dmar(dmar.as_ptr());
+    let info = crate::devices::acpi::get_dmar().unwrap();
+    assert_eq!(info.host_address_width, 39);
+    assert_eq!(info.units.len(), 2);
+    let unit = &info.units[0];
+    assert!(!unit.include_pci_all && unit.register_base == 0xFED9_0000);
+    assert_eq!(unit.scopes.len(), 1);
+    assert_eq!((unit.scopes[0].kind, unit.scopes[0].start_bus), (1, 0));
+    assert_eq!(unit.scopes[0].path, [(0x1c, 0), (2, 0)]);
+    assert!(info.units[1].include_pci_all && info.units[1].scopes.is_empty());
+    let region = &info.reserved[0];
+    assert_eq!((region.base, region.limit), (0x7C00_0000, 0x7C0F_FFFF));
+    assert_eq!(region.scopes[0].path, [(0x14, 0)]);
+}
+
+#[test_case]
+fn fault_alloc_fails_fallible_allocation() {
+    use crate::fault::{set_fault_rate, FaultPoint};
+    let mut v: Vec<u8> = Vec::new();
+    set_fault_rate(FaultPoint::Alloc, 1);
+    let result = v.try_reserve(64);
+    set_fault_rate(FaultPoint::Alloc, 0);
+    assert!(result.is_err());
+    assert!(v.try_reserve(64).is_ok());
+}
+
+#[test_case]
+fn fault_netrx_flips_one_bit() {
+    use crate::fault::{corrupt_frame, set_fault_rate, FaultPoint};
+    let mut frame = [0u8; 64];
+    set_fault_rate(FaultPoint::NetRx, 1);
+    corrupt_frame(&mut frame);
+    set_fault_rate(FaultPoint::NetRx, 0);
+    assert_eq!(frame.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+}
+
+struct NullNetDevice;
+
+impl crate::net::NetworkDevice for NullNetDevice {
+    fn name(&self) -> &str {
+        "null0"
+    }
+
+    fn mac(&self) -> crate::net::MacAddr {
+        crate::net::MacAddr([0x02, 0, 0, 0, 0, 1])
+    }
+
+    fn transmit(&self, _frame: &[u8]) -> Result<(), crate::net::NetError> {
+        Ok(())
+    }
+}
+
+#[test_case]
+fn fault_queue_full_drops_received_frames() {
+    use crate::fault::{set_fault_rate, FaultPoint};
+    let iface = crate::net::register_net_device(alloc::sync::Arc::new(NullNetDevice));
+    set_fault_rate(FaultPoint::QueueFull, 1);
+    let delivered = iface.deliver(alloc::vec![0u8; 60]);
+    set_fault_rate(FaultPoint::QueueFull, 0);
+    assert!(!delivered);
+    assert_eq!(iface.stats().rx_dropped, 1);
+    assert!(iface.deliver(alloc::vec![0u8; 60]));
+}
+
+#[test_case]
+fn wall_clock_date_conversions() {
+    use crate::time::DateTime;
+    let dt = |year, month, day, hour, minute, second| DateTime { year, month, day, hour, minute, second, nanos: 0 };
+    assert_eq!(dt(1970, 1, 1, 0, 0, 0).unix_secs(), 0);
+    assert_eq!(dt(2000, 2, 29, 12, 0, 0).unix_secs(), 951_825_600);
+    assert_eq!(dt(2038, 1, 19, 3, 14, 8).unix_secs(), 1 << 31);
+    // 2000 is a leap year, 2100 is not
+    assert!(dt(2000, 2, 29, 0, 0, 0).is_valid());
+    assert!(!dt(2100, 2, 29, 0, 0, 0).is_valid());
+    assert!(!dt(2023, 4, 31, 0, 0, 0).is_valid());
+    for secs in [0u64, 951_782_399, 951_868_800, 4_107_542_399, 4_107_542_400] {
+        let d = DateTime::from_unix_ns(secs * 1_000_000_000);
+        assert!(d.is_valid());
+        assert_eq!(d.unix_secs(), secs);
+    }
+    assert_eq!(DateTime::from_unix_ns(4_107_542_400 * 1_000_000_000), dt(2100, 3, 1, 0, 0, 0));
+}
+
+#[test_case]
+fn sntp_timestamps_and_offset() {
+    use crate::net::sntp::{ntp_to_unix_ns, unix_ns_to_ntp, SntpSample};
+    assert_eq!(ntp_to_unix_ns(2_208_988_800 << 32), 0);
+    // half a second is 0x8000_0000 in the fraction
+    assert_eq!(unix_ns_to_ntp(1_500_000_000), (2_208_988_801 << 32) | 0x8000_0000);
+    for ns in [0u64, 1, 999_999_999, 1_700_000_000_123_456_789] {
+        assert!(ntp_to_unix_ns(unix_ns_to_ntp(ns)).abs_diff(ns) <= 1);
+    }
+    // server 10 ms ahead, 4 ms each way, 2 ms to answer
+    let s = SntpSample::from_timestamps(1_000_000_000, 1_014_000_000, 1_016_000_000, 1_010_000_000, 2);
+    assert_eq!(s.offset_ns, 10_000_000);
+    assert_eq!(s.delay_ns, 8_000_000);
+}
+
+#[test_case]
+fn ipv4_reassembly_rejects_overlong_fragments() {
+    use crate::net::addr::Ipv4Addr;
+    use crate::net::ipv4::{reassemble, Ipv4Header, PROTO_UDP};
+    let frag = |id: u16, off: usize, more: bool| Ipv4Header {
+        header_len: 20,
+        total_len: 0,
+        id,
+        frag_offset: off,
+        more_fragments: more,
+        ttl: 64,
+        protocol: PROTO_UDP,
+        src: Ipv4Addr([10, 0, 0, 1]),
+        dst: Ipv4Addr([10, 0, 0, 2]),
+    };
+    // pieces reach 32, then a last fragment says the datagram ends at 16
+    assert_eq!(reassemble(&frag(0x7001, 0, true), &[1; 8]), None);
+    assert_eq!(reassemble(&frag(0x7001, 16, true), &[2; 16]), None);
+    assert_eq!(reassemble(&frag(0x7001, 24, true), &[3; 8]), None);
+    assert_eq!(reassemble(&frag(0x7001, 8, false), &[4; 8]), None);
+    // that dropped what had come, so it can start over
+    assert_eq!(reassemble(&frag(0x7001, 0, true), &[1; 8]), None);
+    assert_eq!(reassemble(&frag(0x7001, 8, false), &[4; 8]).as_deref(), Some(&[[1u8; 8], [4; 8]].concat()[..]));
+    // past the end the last fragment set
+    assert_eq!(reassemble(&frag(0x7003, 8, false), &[2; 8]), None);
+    assert_eq!(reassemble(&frag(0x7003, 16, true), &[3; 8]), None);
+    assert_eq!(reassemble(&frag(0x7003, 0, true), &[1; 8]), None);
+    // and a well-formed datagram still goes through
+    assert_eq!(reassemble(&frag(0x7002, 8, false), &[2; 8]), None);
+    assert_eq!(reassemble(&frag(0x7002, 0, true), &[1; 8]).as_deref(), Some(&[[1u8; 8], [2; 8]].concat()[..]));
+}
+
+#[test_case]
+fn boot_memory_map_regions() {
+    use crate::boot::{MemoryKind, MemoryMap, MAX_MEMORY_REGIONS};
+    let mut map = MemoryMap::new();
+    assert!(map.push(0x10_0000, 0x20_0000, MemoryKind::Usable));
+    assert!(map.push(0x20_0000, 0x20_0000, MemoryKind::Usable));
+    assert!(map.push(0x20_0000, 0x30_0000, MemoryKind::Kernel));
+    assert!(map.push(0x30_0000, 0x38_0000, MemoryKind::Usable));
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.usable().count(), 2);
+    assert_eq!(map.usable_bytes(), 0x18_0000);
+    for i in 0..MAX_MEMORY_REGIONS as u64 {
+        map.push(i << 20, (i << 20) + 0x1000, MemoryKind::Reserved);
+    }
+    assert_eq!(map.len(), MAX_MEMORY_REGIONS);
+    assert!(!map.push(0, 0x1000, MemoryKind::Reserved));
+}
+
+#[test_case]
+fn chacha20_block_rfc8439() {
+    use crate::random::chacha::chacha20_block;
+    // RFC 8439 section 2.3.2: key 00..1f, nonce 00000009 0000004a 00000000
+    let mut key = [0u32; 8];
+    for (i, k) in key.iter_mut().enumerate() {
+        let b = (i * 4) as u8;
+        *k = u32::from_le_bytes([b, b + 1, b + 2, b + 3]);
+    }
+    let block = chacha20_block(&key, 1 | (0x0900_0000 << 32), 0x4a00_0000);
+    assert_eq!(
+        block,
+        [
+            0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3, 0xc7f4d1c7, 0x0368c033, 0x9aaa2204, 0x4e6cd4c3,
+            0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9, 0xd19c12b5, 0xb94e16de, 0xe883d0cb, 0x4e3c50a2,
+        ]
+    );
+}
+
+#[test_case]
+fn crc32_check_values() {
+    use crate::rlib::crc::{crc32, crc32c, crc32c_soft};
+    assert_eq!(crc32(b"123456789", 0), 0xCBF4_3926);
+    assert_eq!(crc32(b"56789", crc32(b"1234", 0)), 0xCBF4_3926);
+    assert_eq!(crc32c(b"123456789", 0), 0xE306_9283);
+    let data: alloc::vec::Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+    assert_eq!(crc32c(&data, 5), crc32c_soft(&data, 5));
+}
+
+#[test_case]
+fn fnv_and_siphash_vectors() {
+    use crate::rlib::hash::{fnv1a_32, fnv1a_64, siphash24, SipHasher};
+    use core::hash::Hasher;
+    assert_eq!(fnv1a_32(b"a"), 0xE40C_292C);
+    assert_eq!(fnv1a_64(b"a"), 0xAF63_DC4C_8601_EC8C);
+    // from the SipHash paper: key 00..0f, message 00..0e
+    let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+    let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+    let msg: [u8; 15] = core::array::from_fn(|i| i as u8);
+    assert_eq!(siphash24(k0, k1, &msg), 0xA129_CA61_49BE_45E5);
+    assert_eq!(siphash24(k0, k1, &[]), 0x726F_DB47_DD0E_0E31);
+    let mut h = SipHasher::new(k0, k1);
+    h.write(&msg[..3]);
+    h.write(&msg[3..10]);
+    h.write(&msg[10..]);
+    assert_eq!(h.finish(), 0xA129_CA61_49BE_45E5);
+}
+
+#[test_case]
+fn c_string_helpers() {
+    use crate::rlib::cstr::{cstr, cstr_at, strcmp, strlen, strncmp, strncpy, strnlen};
+    use core::cmp::Ordering;
+    assert_eq!(unsafe { strlen(b"abc\0def".as_ptr()) }, 3);
+    assert_eq!(strnlen(b"abcdef", 4), 4);
+    assert_eq!(strnlen(b"ab\0cd", 4), 2);
+    assert_eq!(cstr(b"RSDT\0\0\0\0"), "RSDT");
+    assert_eq!(cstr(b"FACP"), "FACP");
+    let strtab = b"\0main\0_start\0";
+    assert_eq!(cstr_at(strtab, 6), Some("_start"));
+    assert_eq!(cstr_at(strtab, 0), Some(""));
+    assert_eq!(cstr_at(strtab, 99), None);
+    assert_eq!(strcmp(b"abc\0x", b"abc"), Ordering::Equal);
+    assert_eq!(strcmp(b"ab", b"abc"), Ordering::Less);
+    assert_eq!(strncmp(b"abcd", b"abce", 3), Ordering::Equal);
+    let mut dst = [0xFFu8; 6];
+    assert_eq!(strncpy(&mut dst, b"hi\0there"), 2);
+    assert_eq!(dst, *b"hi\0\0\0\0");
+    assert_eq!(strncpy(&mut dst[..2], b"long"), 2);
+}
+
+#[test_case]
+fn numbuf_formats_without_alloc() {
+    use crate::rlib::numfmt::{format_dec, NumBuf};
+    assert_eq!(NumBuf::dec(0).as_str(), "0");
+    assert_eq!(NumBuf::dec(u64::MAX).as_str(), "18446744073709551615");
+    assert_eq!(NumBuf::signed(i64::MIN).as_str(), "-9223372036854775808");
+    assert_eq!(NumBuf::hex(0xBEEF, 8).as_str(), "0x0000beef");
+    assert_eq!(NumBuf::hex(0, 0).as_str(), "0x0");
+    assert_eq!(NumBuf::hex(u64::MAX, 0).as_str(), "0xffffffffffffffff");
+    let mut out = [0u8; 4];
+    assert_eq!(format_dec(1234, &mut out), Some(4));
+    assert_eq!(format_dec(12345, &mut out), None);
+}
+
+#[test_case]
+fn kmod_relocations() {
+    use crate::kmod::elf::{apply_relocation, link_module, R_X86_64_32, R_X86_64_64, R_X86_64_GOTPCREL, R_X86_64_PC32};
+    let mut image = [0u8; 16];
+    let base = 0x1000;
+    apply_relocation(&mut image, base, 0, R_X86_64_64, 0x1234_5678_9ABC, None, 4).unwrap();
+    assert_eq!(u64::from_le_bytes(image[..8].try_into().unwrap()), 0x1234_5678_9AC0);
+    // call at 0x1008 to 0x1000 with the usual -4 addend
+    apply_relocation(&mut image, base, 8, R_X86_64_PC32, 0x1000, None, -4).unwrap();
+    assert_eq!(i32::from_le_bytes(image[8..12].try_into().unwrap()), -12);
+    assert!(apply_relocation(&mut image, base, 8, R_X86_64_PC32, 0x4444_4444_0000, None, 0).is_err());
+    assert!(apply_relocation(&mut image, base, 8, R_X86_64_32, 1 << 32, None, 0).is_err());
+    assert!(apply_relocation(&mut image, base, 8, R_X86_64_GOTPCREL, 0, None, 0).is_err());
+    assert!(apply_relocation(&mut image, base, 14, R_X86_64_PC32, 0x1000, None, 0).is_err());
+    assert!(link_module(b"\x7fELF not an object", |_| None).is_err());
+}
+
+#[test_case]
+fn config_text_parsing() {
+    use crate::config::{parse_config, parse_video_mode};
+    let entries = parse_config("# settings\nconsole.fg = white  # trailing\n\nvideo.mode=800x600x16\nnonsense\n");
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].0.as_str(), entries[0].1.as_str()), ("console.fg", "white"));
+    assert_eq!((entries[1].0.as_str(), entries[1].1.as_str()), ("video.mode", "800x600x16"));
+    assert_eq!(parse_video_mode("1280x1024"), Some((1280, 1024, 32)));
+    assert_eq!(parse_video_mode("800x600x16"), Some((800, 600, 16)));
+    assert_eq!(parse_video_mode("800x600x7"), None);
+    assert_eq!(parse_video_mode("800"), None);
+}
+
+#[test_case]
+fn symbol_table_lookup() {
+    use crate::symbols::{SymbolTable, KSYMS_MAGIC};
+    let entries: [(u64, u32, u32); 3] = [(0x1000, 0, 0x20), (0x1040, 6, 0), (0x2000, 11, 0x10)];
+    let mut blob = alloc::vec::Vec::new();
+    blob.extend_from_slice(KSYMS_MAGIC);
+    blob.extend_from_slice(&3u32.to_le_bytes());
+    blob.extend_from_slice(&(16u32 + 3 * 16).to_le_bytes());
+    for (addr, name, size) in entries {
+        blob.extend_from_slice(&addr.to_le_bytes());
+        blob.extend_from_slice(&name.to_le_bytes());
+        blob.extend_from_slice(&size.to_le_bytes());
+    }
+    blob.extend_from_slice(b"alpha\0beta\0gamma\0");
+    let table = SymbolTable::parse(&blob).unwrap();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.lookup(0xfff), None);
+    assert_eq!(table.lookup(0x1000), Some(("alpha", 0)));
+    assert_eq!(table.lookup(0x101f), Some(("alpha", 0x1f)));
+    // past alpha's end, before beta
+    assert_eq!(table.lookup(0x1020), None);
+    // beta has no size, so it runs up to gamma
+    assert_eq!(table.lookup(0x1fff), Some(("beta", 0xfbf)));
+    assert_eq!(table.lookup(0x200f), Some(("gamma", 0xf)));
+    assert_eq!(table.lookup(0x2010), None);
+    assert_eq!(table.find("gamma"), Some(0x2000));
+    assert!(SymbolTable::parse(&blob[..40]).is_none());
+}
+
+#[test_case]
+fn stack_canaries_intact() {
+    use crate::arch::stackguard::{check_stack_canaries, STACK_CANARY};
+    let base = crate::arch::percpu::entry_stack_base(0);
+    assert_eq!(unsafe { (base as *const u64).read_volatile() }, STACK_CANARY);
+    let base = crate::arch::gdt::double_fault_stack_base(0);
+    assert_eq!(unsafe { (base as *const u64).read_volatile() }, STACK_CANARY);
+    let base = crate::arch::gdt::nmi_stack_base(0);
+    assert_eq!(unsafe { (base as *const u64).read_volatile() }, STACK_CANARY);
+    check_stack_canaries(0);
+}
+
+#[test_case]
+fn dropped_wait_leaves_no_waker_queued() {
+    use crate::sync::WaitQueue;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    let queue = WaitQueue::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut wait = queue.wait_until(|| None::<()>);
+    assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+    assert!(!queue.is_empty());
+    drop(wait);
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn irq_spinlock_restores_interrupts() {
+    use crate::sync::IrqSpinlock;
+    use x86_64::instructions::interrupts;
+    let lock = IrqSpinlock::new(0);
+    let before = interrupts::are_enabled();
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert_eq!(interrupts::are_enabled(), before);
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn spinlock_class_is_creation_site() {
+    use crate::sync::Spinlock;
+    let lock = Spinlock::new(1);
+    let line = line!() - 1;
+    assert_eq!((lock.class().file(), lock.class().line()), (file!(), line));
+    let guard = lock.lock();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(lock.try_lock().map(|g| *g), Some(1));
+}
+
+#[test_case]
+fn panic_screen_renders_without_driver() {
+    use crate::arch::panic::Registers;
+    use crate::arch::panic_screen::{render, BACKGROUND};
+    use crate::driver_framework::drivers::vbe_vga::{FramebufferInfo, RawFramebuffer};
+    let (width, height) = (320usize, 250usize);
+    let mut pixels = alloc::vec![0u32; width * height];
+    let fb = RawFramebuffer {
+        base: pixels.as_mut_ptr() as u64,
+        info: FramebufferInfo { width: width as u32, height: height as u32, bpp: 32, pitch: width * 4 },
+    };
+    render(fb, &format_args!("EXCEPTION: TEST\nline two"), 0, &Registers::capture(), b"one\ntwo\n");
+    assert_eq!(pixels[width * (height - 1)], BACKGROUND);
+    // the title bar is inverted, the message below it drawn in text color
+    assert!(pixels[..width * 10].iter().any(|&p| p == BACKGROUND));
+    assert!(pixels[width * 20..width * 30].iter().any(|&p| p != BACKGROUND));
+}
+
+#[test_case]
+fn framebuffer_reads_back_and_clips() {
+    use crate::driver_framework::drivers::vbe_vga::{FramebufferInfo, RawFramebuffer};
+    // rows padded past the visible width, as a pitch may be
+    let (width, height, stride) = (8usize, 4usize, 10usize);
+    let mut pixels: Vec<u32> = (0..(stride * height) as u32).collect();
+    let fb = RawFramebuffer {
+        base: pixels.as_mut_ptr() as u64,
+        info: FramebufferInfo { width: width as u32, height: height as u32, bpp: 32, pitch: stride * 4 },
+    };
+    assert_eq!(fb.read_pixel(3, 2), Some(23));
+    assert_eq!(fb.read_pixel(8, 0), None);
+    assert_eq!(fb.read_pixel(0, 4), None);
+    assert_eq!(fb.read_rect(1, 1, 2, 2), [11, 12, 21, 22]);
+    // the part past the right and bottom edges reads as 0
+    assert_eq!(fb.read_rect(7, 3, 2, 2), [37, 0, 0, 0]);
+    assert!(fb.read_rect(9, 0, 2, 1).iter().all(|&p| p == 0));
+}
+
+#[test_case]
+fn display_levels_adjust_colors() {
+    use crate::driver_framework::drivers::vbe_vga::{adjust_color, parse_gamma, set_display_levels};
+    assert_eq!(parse_gamma("1.8"), Some(180));
+    assert_eq!(parse_gamma("0.45"), Some(45));
+    assert_eq!(parse_gamma("2"), Some(200));
+    assert_eq!(parse_gamma("1.234"), None);
+    assert_eq!(adjust_color(0xFF12_3456), 0xFF12_3456);
+    assert!(set_display_levels(5, 100).is_err());
+    assert!(set_display_levels(100, 400).is_err());
+    set_display_levels(50, 100).unwrap();
+    // alpha is kept, black stays black
+    assert_eq!(adjust_color(0xFFFF_FFFF), 0xFF80_8080);
+    assert_eq!(adjust_color(0), 0);
+    set_display_levels(100, 200).unwrap();
+    // 255 * sqrt(0x40 / 255) = 127.75
+    assert_eq!(adjust_color(0x40_4040), 0x80_8080);
+    assert_eq!(adjust_color(0xFF_FFFF), 0xFF_FFFF);
+    set_display_levels(100, 100).unwrap();
+    assert_eq!(adjust_color(0x40_4040), 0x40_4040);
+}
+
+#[test_case]
+fn framebuffer_maps_channels() {
+    use crate::driver_framework::drivers::vbe_vga::{FramebufferInfo, RawFramebuffer};
+    let mut pixels = vec![0x8010_2030u32, 0xFF00_00FF, 7, 9];
+    let fb = RawFramebuffer {
+        base: pixels.as_mut_ptr() as u64,
+        info: FramebufferInfo { width: 2, height: 1, bpp: 32, pitch: 16 },
+    };
+    let halve: [u8; 256] = core::array::from_fn(|c| (c / 2) as u8);
+    fb.map_channels(&halve);
+    fb.write_rect(1, 0, 2, 1, &[0x0011_2233, 5]);
+    assert_eq!(pixels, [0x8008_1018, 0x0011_2233, 7, 9]);
+}
+
+#[test_case]
+fn ps2_keyboard_self_test_code() {
+    use crate::driver_framework::drivers::ps2kbd::is_self_test_code;
+    // left shift press and release
+    assert!(!is_self_test_code(0x2A));
+    assert!(!is_self_test_code(0xAA));
+    // the fake shift release after a prefix
+    assert!(!is_self_test_code(0xE0));
+    assert!(!is_self_test_code(0xAA));
+    assert!(is_self_test_code(0xAA));
+    assert!(is_self_test_code(0xFC));
+}
+
+#[test_case]
+fn ps2_keyboard_self_test_code_set2() {
+    use crate::driver_framework::drivers::ps2kbd::is_self_test_code;
+    use crate::input::scancode::{set_keyboard_scancodes, Scancodes};
+    set_keyboard_scancodes(Scancodes::Set2);
+    // left shift press (0x12), then a release prefix: 0xAA never follows one
+    assert!(!is_self_test_code(0x12));
+    assert!(!is_self_test_code(0xF0));
+    assert!(!is_self_test_code(0xAA));
+    assert!(is_self_test_code(0xAA));
+    set_keyboard_scancodes(Scancodes::Set1);
+}
+
+#[test_case]
+fn scancode_decoder_assembles_sequences() {
+    use crate::input::scancode::{ScancodeDecoder, Scancodes};
+    use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+    let feed = |set, bytes: &[u8]| {
+        let mut decoder = ScancodeDecoder::new(set);
+        bytes.iter().filter_map(|&b| decoder.add_byte(b)).collect::<Vec<KeyEvent>>()
+    };
+    let down = |code| KeyEvent::new(code, KeyState::Down);
+    let up = |code| KeyEvent::new(code, KeyState::Up);
+
+    assert_eq!(feed(Scancodes::Set1, &[0x1E, 0x9E]), [down(KeyCode::A), up(KeyCode::A)]);
+    // Print Screen with its fake shift, and a doubled prefix
+    assert_eq!(feed(Scancodes::Set1, &[0xE0, 0x2A, 0xE0, 0x37]), [down(KeyCode::PrintScreen)]);
+    assert_eq!(feed(Scancodes::Set1, &[0xE0, 0xE0, 0x48]), [down(KeyCode::ArrowUp)]);
+    // Pause is one key, and Num Lock doesn't come out of it
+    assert_eq!(
+        feed(Scancodes::Set1, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5]),
+        [down(KeyCode::PauseBreak), up(KeyCode::PauseBreak)]
+    );
+
+    assert_eq!(feed(Scancodes::Set2, &[0x1C, 0xF0, 0x1C]), [down(KeyCode::A), up(KeyCode::A)]);
+    assert_eq!(
+        feed(Scancodes::Set2, &[0xE0, 0x12, 0xE0, 0x75, 0xE0, 0xF0, 0x75, 0xE0, 0xF0, 0x12]),
+        [down(KeyCode::ArrowUp), up(KeyCode::ArrowUp)]
+    );
+    assert_eq!(
+        feed(Scancodes::Set2, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77]),
+        [down(KeyCode::PauseBreak), up(KeyCode::PauseBreak)]
+    );
+    // an unknown extended code leaves nothing behind for the next key
+    assert_eq!(feed(Scancodes::Set2, &[0xE0, 0x01, 0x1C]), [down(KeyCode::A)]);
+}
+
+#[test_case]
+fn hotkey_intercepts_registered_combo() {
+    use crate::input::{intercept, register_hotkey, unregister_hotkey, KeyCombo, MOD_ALT, MOD_CTRL};
+    use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+    let combo = KeyCombo::parse("Ctrl+Alt+F12").unwrap();
+    assert_eq!(combo, KeyCombo::new(MOD_CTRL | MOD_ALT, KeyCode::F12));
+    assert_eq!(alloc::format!("{}", combo), "ctrl+alt+f12");
+    assert!(KeyCombo::parse("hyper+x").is_none());
+    register_hotkey(combo, "test", || {}).unwrap();
+    assert!(register_hotkey(combo, "test", || {}).is_err());
+
+    let key = |code, state| KeyEvent::new(code, state);
+    assert!(intercept(&key(KeyCode::F12, KeyState::Down)).is_none());
+    assert!(intercept(&key(KeyCode::LControl, KeyState::Down)).is_none());
+    assert!(intercept(&key(KeyCode::RAltGr, KeyState::Down)).is_none());
+    assert_eq!(intercept(&key(KeyCode::F12, KeyState::Down)).map(|h| h.name), Some("test"));
+    assert!(intercept(&key(KeyCode::F12, KeyState::Up)).is_none());
+    intercept(&key(KeyCode::RAltGr, KeyState::Up));
+    assert!(intercept(&key(KeyCode::F12, KeyState::Down)).is_none());
+    intercept(&key(KeyCode::LControl, KeyState::Up));
+    assert!(unregister_hotkey(combo));
+}
+
+#[test_case]
+fn mouse_gestures_from_reports() {
+    use crate::input::{GestureTracker, MouseButton::Left, MouseEventKind::*, MouseReport};
+    let mut tracker = GestureTracker::new();
+    let mut feed = |buttons: u8, dx: i32, wheel: i8, ms: u64| {
+        let mut kinds = alloc::vec::Vec::new();
+        let report = MouseReport { buttons, x: 0, y: 0, dx, dy: 0, wheel };
+        tracker.feed(&report, ms * 1_000_000, |e| kinds.push(e.kind));
+        kinds
+    };
+    assert_eq!(feed(1, 0, 0, 0), [Press(Left)]);
+    assert_eq!(feed(0, 0, 0, 100), [Release(Left), Click(Left)]);
+    assert_eq!(feed(1, 0, 0, 200), [Press(Left)]);
+    assert_eq!(feed(0, 0, 0, 250), [Release(Left), Click(Left), DoubleClick(Left)]);
+    // a press that moves far enough is a drag, not a click
+    assert_eq!(feed(1, 2, 0, 2000), [Move, Press(Left)]);
+    assert_eq!(feed(1, 5, 0, 2010), [DragStart(Left), DragMove(Left)]);
+    assert_eq!(feed(0, 0, -1, 2020), [Release(Left), DragEnd(Left), Wheel(-1)]);
+    // held too long to be a click
+    feed(1, 0, 0, 3000);
+    assert_eq!(feed(0, 0, 0, 4000), [Release(Left)]);
+}
+
+#[test_case]
+fn mouse_motion_settings() {
+    use crate::input::*;
+    let saved = mouse_settings();
+    set_mouse_settings(MouseSettings::DEFAULT);
+    assert_eq!(scale_mouse_motion(3, -40), (3, -16));
+    assert!(set_mouse_setting("sensitivity", "50").is_ok());
+    // half a pixel is carried into the next report
+    assert_eq!(scale_mouse_motion(1, 0), (0, 0));
+    assert_eq!(scale_mouse_motion(1, 0), (1, 0));
+    assert!(set_mouse_setting("sensitivity", "100").is_ok());
+    assert!(set_mouse_setting("invert_y", "on").is_ok());
+    assert_eq!(scale_mouse_motion(0, 2), (0, -2));
+    assert!(set_mouse_setting("accel", "linear").is_ok());
+    assert_eq!(mouse_setting("accel").as_deref(), Some("linear"));
+    assert_eq!(AccelCurve::Linear.factor(8, 4), 200);
+    assert_eq!(AccelCurve::Quadratic.factor(16, 4), MAX_ACCEL_PERCENT);
+    assert_eq!(scale_mouse_motion(8, 0), (16, 0));
+    assert!(set_mouse_setting("accel", "fast").is_err());
+    assert!(set_mouse_setting("speed", "1").is_err());
+    set_mouse_settings(saved);
+}
+
+#[test_case]
+fn console_cursor_style() {
+    use crate::driver_framework::drivers::console::*;
+    let saved = console_cursor();
+    for style in [CursorStyle::Off, CursorStyle::Underline, CursorStyle::Block] {
+        assert_eq!(CursorStyle::parse(style.name()), Some(style));
+        set_console_cursor(style);
+        assert_eq!(console_cursor(), style);
+    }
+    assert!(CursorStyle::parse("bar").is_none());
+    set_console_cursor(saved);
+}
+
+#[test_case]
+fn early_log_replays_into_sink() {
+    use crate::log::{LogSink, EARLY_LOG_SIZE, EARLY_SINK};
+    struct Capture(spin::Mutex<alloc::string::String>);
+    impl LogSink for Capture {
+        fn name(&self) -> &'static str {
+            "capture"
+        }
+        fn write_str(&self, s: &str) {
+            self.0.lock().push_str(s);
+        }
+    }
+    let capture = Capture(spin::Mutex::new(alloc::string::String::new()));
+    // whatever earlier boot output is still held goes first
+    crate::log::replay_early_log(&capture);
+    capture.0.lock().clear();
+
+    EARLY_SINK.write_str("acpi: ok\npci: 3 devices\n");
+    crate::log::replay_early_log(&capture);
+    assert_eq!(capture.0.lock().as_str(), "acpi: ok\npci: 3 devices\n");
+    capture.0.lock().clear();
+    crate::log::replay_early_log(&capture);
+    assert!(capture.0.lock().is_empty());
+
+    // on overflow only whole lines are replayed
+    for _ in 0..EARLY_LOG_SIZE / 10 + 1 {
+        EARLY_SINK.write_str("012345678\n");
+    }
+    crate::log::replay_early_log(&capture);
+    let text = capture.0.lock();
+    assert!(text.starts_with("[... earlier boot output dropped]\n012345678\n"));
+    assert!(text.lines().skip(1).all(|l| l == "012345678"));
+}
+
+#[test_case]
+fn pci_address_parse_and_display() {
+    use crate::driver_framework::device::PciAddress;
+    let addr = PciAddress::parse("00:1f.2").unwrap();
+    assert_eq!(addr, PciAddress::new(0, 0, 0x1f, 2));
+    assert_eq!(alloc::format!("{}", addr), "0000:00:1f.2");
+    assert_eq!(PciAddress::parse("0001:02:03.4"), Some(PciAddress::new(1, 2, 3, 4)));
+    assert!(PciAddress::parse("00:20.0").is_none());
+    assert!(PciAddress::parse("00:1f.8").is_none());
+    assert!(PciAddress::parse("1f.2").is_none());
+    // the host bridge is always there on a PC
+    assert_ne!(PciAddress::new(0, 0, 0, 0).config_read(0) & 0xFFFF, 0xFFFF);
+    assert_eq!(PciAddress::new(0, 0, 0, 0).config_read(0x1000), 0xFFFF_FFFF);
+}
+
+#[test_case]
+fn ioapic_iso_flags_decode() {
+    use crate::hal::ioapic::{iso_redirection_bits, REDIR_ACTIVE_LOW, REDIR_LEVEL_TRIGGERED};
+    // conforming to ISA, and explicit active high / edge
+    assert_eq!(iso_redirection_bits(0x0), 0);
+    assert_eq!(iso_redirection_bits(0x5), 0);
+    // the usual SCI override: level, active low
+    assert_eq!(iso_redirection_bits(0xD), REDIR_ACTIVE_LOW | REDIR_LEVEL_TRIGGERED);
+    assert_eq!(iso_redirection_bits(0x3), REDIR_ACTIVE_LOW);
+    assert_eq!(iso_redirection_bits(0xC), REDIR_LEVEL_TRIGGERED);
+}
+
+#[test_case]
+fn ioapic_mask_gsi_keeps_entry() {
+    use crate::hal::ioapic::{list_ioapics, mask_gsi, read_redirection_entry, write_redirection_entry_for_gsi, REDIR_MASKED};
+    let offset = x86_64::VirtAddr::new(crate::driver_framework::drivers::get_boot_phys_offset());
+    let Some(io) = list_ioapics().first().copied() else { return };
+    // the last input is free on the machines we test on
+    let gsi = io.gsi_base + io.redir_entries - 1;
+    let (low, high) = read_redirection_entry(gsi, offset).unwrap();
+    assert!(mask_gsi(gsi, offset));
+    let (masked, high_after) = read_redirection_entry(gsi, offset).unwrap();
+    assert_eq!(masked, low | REDIR_MASKED);
+    assert_eq!(high_after, high);
+    write_redirection_entry_for_gsi(gsi, low, high, offset);
+}
+
+#[test_case]
+fn apic_error_lvt_programmed() {
+    use crate::hal::apic::{read_register, ERROR_VECTOR};
+    let Some(lvt) = read_register(0x370) else { return };
+    assert_eq!(lvt & 0xFF, ERROR_VECTOR as u32);
+    assert_eq!(lvt & 1 << 16, 0);
+}
+
+#[test_case]
+fn apic_spurious_vector_set() {
+    use crate::hal::apic::{read_register, SPURIOUS_VECTOR};
+    let Some(svr) = read_register(0xF0) else { return };
+    assert_eq!(svr & 0xFF, SPURIOUS_VECTOR as u32);
+    assert_ne!(svr & 0x100, 0);
+}
+
+#[test_case]
+fn ioapic_isa_irq_route_follows_isos() {
+    use crate::devices::acpi::IsoInfo;
+    use crate::hal::ioapic::{isa_irq_route, REDIR_ACTIVE_LOW, REDIR_LEVEL_TRIGGERED};
+    let isos = [IsoInfo { bus: 0, source: 0, gsi: 2, flags: 0 }, IsoInfo { bus: 0, source: 9, gsi: 9, flags: 0xD }];
+    assert_eq!(isa_irq_route(&isos, 0), (2, 0));
+    assert_eq!(isa_irq_route(&isos, 9), (9, REDIR_ACTIVE_LOW | REDIR_LEVEL_TRIGGERED));
+    assert_eq!(isa_irq_route(&isos, 1), (1, 0));
+}
+
+#[test_case]
+fn request_irq_shares_and_frees_vectors() {
+    use crate::driver_framework::device::{Device, DeviceInfo};
+    use crate::hal::{free_device_irqs, free_irq, irq_requests, request_irq};
+    fn handler(_vector: u8) {}
+    let info = DeviceInfo {
+        vendor_id: 0xffff,
+        device_id: 0xffff,
+        class: 0xFF,
+        subclass: 0,
+        prog_if: 0,
+        resources: Vec::new(),
+        capabilities: Vec::new(),
+        description: alloc::string::String::from("irq test"),
+        location: None,
+    };
+    let (a, b) = (Device::new(0xA000, info.clone()), Device::new(0xA001, info));
+    // not an ISA vector: no IOAPIC line behind it
+    let vector = 0xE7;
+    request_irq(&a, vector, handler).unwrap();
+    assert!(request_irq(&a, vector, handler).is_err());
+    request_irq(&b, vector, handler).unwrap();
+    assert!(irq_requests().contains(&(0xA000, vector, None)));
+    assert!(free_irq(&a, vector));
+    assert!(!free_irq(&a, vector));
+    assert!(irq_requests().iter().any(|r| r.1 == vector));
+    assert_eq!(free_device_irqs(0xA001), 1);
+    assert!(!irq_requests().iter().any(|r| r.1 == vector));
+}
+
+#[test_case]
+fn dma_map_without_iommu_is_physical() {
+    use crate::driver_framework::device::{Device, DeviceInfo};
+    use crate::hal::dma::{dma_map, dma_mappings, dma_unmap, free_device_dma, DmaDirection};
+    let info = DeviceInfo {
+        vendor_id: 0xffff,
+        device_id: 0xffff,
+        class: 0xFF,
+        subclass: 0,
+        prog_if: 0,
+        resources: Vec::new(),
+        capabilities: Vec::new(),
+        description: alloc::string::String::from("dma test"),
+        location: None,
+    };
+    let dev = Device::new(0xA100, info);
+    let phys = x86_64::PhysAddr::new(0x10_0000);
+    assert!(dma_map(&dev, phys, 0, DmaDirection::ToDevice).is_err());
+    assert_eq!(dma_map(&dev, phys, 512, DmaDirection::ToDevice), Ok(0x10_0000));
+    assert_eq!(dma_map(&dev, phys + 0x1000u64, 4096, DmaDirection::FromDevice), Ok(0x10_1000));
+    assert!(dma_mappings().contains(&(0xA100, 0x10_0000, 512, false)));
+    assert!(dma_unmap(&dev, 0x10_0000, 512));
+    assert!(!dma_unmap(&dev, 0x10_0000, 512));
+    assert_eq!(free_device_dma(0xA100), 1);
+    assert!(!dma_mappings().iter().any(|m| m.0 == 0xA100));
+}
+
+#[test_case]
+fn noapic_flag_selects_pic_mode() {
+    use crate::hal::intctl::noapic_requested;
+    assert!(noapic_requested("log=debug noapic"));
+    assert!(noapic_requested("noapic"));
+    assert!(!noapic_requested("noapicx apic=noapic"));
+    assert!(!noapic_requested(""));
+}
+
+#[test_case]
+fn driver_params_from_cmdline() {
+    use crate::driver_framework::driver::DriverParams;
+    let mut params = DriverParams::new();
+    params.set("rate", "100");
+    params.merge_cmdline("ps2mouse", "log=debug ps2mouse.rate=40 ps2mouse.resolution=8 ps2mousex.rate=1 ps2mouse.=1 ps2mouse.flag");
+    assert_eq!(params.get("rate"), Some("40"));
+    assert_eq!(params.get("resolution"), Some("8"));
+    assert_eq!(params.iter().count(), 2);
+    assert!(DriverParams::new().is_empty());
+}
+
+#[test_case]
+fn port_claims_share_a_controller_and_refuse_others() {
+    use crate::driver_framework::device::{Device, DeviceInfo};
+    use crate::hal::ioport::{free_device_ports, port_claims, release_ports, request_ports};
+    let info = DeviceInfo {
+        vendor_id: 0xffff,
+        device_id: 0xffff,
+        class: 0xFF,
+        subclass: 0,
+        prog_if: 0,
+        resources: Vec::new(),
+        capabilities: Vec::new(),
+        description: alloc::string::String::from("port test"),
+        location: None,
+    };
+    let (a, b) = (Device::new(0xA200, info.clone()), Device::new(0xA201, info));
+    request_ports(&a, "test", 0xE000, 8).unwrap();
+    assert!(request_ports(&a, "test", 0xE004, 2).is_err());
+    request_ports(&b, "test", 0xE004, 2).unwrap();
+    assert!(request_ports(&b, "other", 0xE007, 4).is_err());
+    request_ports(&b, "other", 0xE008, 4).unwrap();
+    assert!(request_ports(&b, "other", 0xFFFF, 2).is_err());
+    assert!(port_claims().contains(&(0xA200, "test", 0xE000, 8)));
+    assert!(release_ports(&a, 0xE000));
+    assert!(!release_ports(&a, 0xE000));
+    assert_eq!(free_device_ports(0xA201), 2);
+    assert!(!port_claims().iter().any(|c| c.0 == 0xA200 || c.0 == 0xA201));
+}
+
+#[test_case]
+fn unhandled_irq_storm_is_flagged() {
+    use crate::hal::irq::{unhandled_irq, unhandled_irqs, IRQ_STORM_THRESHOLD};
+    // stray vectors: no line behind them
+    for _ in 0..3 {
+        unhandled_irq(0xE9);
+    }
+    assert!(unhandled_irqs().contains(&(0xE9, 3, false)));
+    for _ in 0..=IRQ_STORM_THRESHOLD {
+        unhandled_irq(0xEA);
+    }
+    assert!(unhandled_irqs().iter().any(|&(v, n, masked)| v == 0xEA && n == IRQ_STORM_THRESHOLD + 1 && masked));
+}
+
+#[test_case]
+fn console_colors_follow_backend_switch() {
+    use crate::bootvga::vga_buffer::Color;
+    use crate::devices::console::{console, console_colors, set_console_backend, set_console_colors, VGA_TEXT_CONSOLE};
+    let before = console_colors();
+    assert_eq!(console().name(), "vga");
+    set_console_colors(Color::White, Color::Blue);
+    assert_eq!(console_colors(), (Color::White, Color::Blue));
+    set_console_backend(&VGA_TEXT_CONSOLE);
+    assert_eq!(console_colors(), (Color::White, Color::Blue));
+    // clamped, not ignored
+    setpos!(100, 100);
+    set_console_colors(before.0, before.1);
+}
+
+#[test_case]
+fn cache_line_size_and_flush() {
+    use crate::arch::processor::{cache_line_size, detect_caches, flush_cache_range};
+    use crate::hal::dma::{dma_align, dma_alignment};
+    let line = cache_line_size();
+    assert!(line.is_power_of_two() && line >= 32);
+    assert_eq!(dma_alignment(), line);
+    assert_eq!(dma_align(1), line);
+    assert_eq!(dma_align(line + 1), 2 * line);
+    for c in detect_caches() {
+        assert!(c.level >= 1 && c.size > 0 && c.line_size > 0);
+    }
+    // an unaligned range across lines flushes and leaves the data alone
+    let buf = vec![0xA5u8; 3 * line];
+    flush_cache_range(buf[line / 2..].as_ptr(), 2 * line);
+    flush_cache_range(buf.as_ptr(), 0);
+    assert!(buf.iter().all(|&b| b == 0xA5));
+}
+
+#[test_case]
+fn pit_delay_waits_at_least_the_time_asked() {
+    use crate::arch::tsc_timer::{pit_countdown, rdtsc, PIT_HZ};
+    use crate::time::delay::{delay_with, mdelay, udelay, DelaySource};
+    udelay(0);
+    mdelay(0);
+    let Some(one_ms) = pit_countdown((PIT_HZ / 1000) as u16) else { return };
+    let start = rdtsc();
+    delay_with(DelaySource::Pit, 2000);
+    assert!(rdtsc().wrapping_sub(start) >= one_ms);
+    // without a PM timer the PM timer source counts on the PIT
+    if crate::devices::acpi::pm_timer().is_none() {
+        let start = rdtsc();
+        delay_with(DelaySource::PmTimer, 2000);
+        assert!(rdtsc().wrapping_sub(start) >= one_ms);
+    }
+}
+
+#[test_case]
+fn map_bar_is_cached_and_shares_pages() {
+    use crate::driver_framework::device::{Device, DeviceInfo, Resource, ResourceKind};
+    use crate::hal::bar::{bar_mappings, free_device_bars, unmap_bar};
+    let Some(offset) = crate::boot::boot_params().map(|p| p.phys_offset) else { return };
+    let info = DeviceInfo {
+        vendor_id: 0xffff,
+        device_id: 0xffff,
+        class: 0xFF,
+        subclass: 0,
+        prog_if: 0,
+        resources: vec![
+            Resource { kind: ResourceKind::MemoryMapped, addr: 0xFEBF_0010, len: 0x100 },
+            Resource { kind: ResourceKind::IO, addr: 0xC000, len: 0x20 },
+            Resource { kind: ResourceKind::MemoryMapped, addr: 0xFEBF_0800, len: 0x800 },
+        ],
+        capabilities: Vec::new(),
+        description: alloc::string::String::from("bar test"),
+        location: None,
+    };
+    let dev = Device::new(0xA200, info);
+    let bar0 = dev.map_bar(0).expect("BAR0 not mapped");
+    assert_eq!((bar0.phys, bar0.virt, bar0.len), (0xFEBF_0010, offset + 0xFEBF_0010, 0x100));
+    assert_eq!(dev.map_bar(0), Ok(bar0));
+    let bar1 = dev.map_bar(1).expect("BAR1 not mapped");
+    assert_eq!(bar1.virt, offset + 0xFEBF_0800);
+    assert!(dev.map_bar(2).is_err());
+    assert_eq!(bar_mappings().iter().filter(|m| m.0 == 0xA200).count(), 2);
+    // the page BAR0 mapped stays mapped for BAR1
+    assert!(unmap_bar(&dev, 0));
+    let translated = crate::memory::kernel_space().and_then(|s| s.translate(x86_64::VirtAddr::new(bar1.virt)));
+    assert_eq!(translated, Some(x86_64::PhysAddr::new(0xFEBF_0800)));
+    assert_eq!(free_device_bars(0xA200), 1);
+    assert!(!bar_mappings().iter().any(|m| m.0 == 0xA200));
+}
+
+#[test_case]
+fn kthreads_take_turns_and_are_reaped() {
+    use crate::memory::kernel_stacks_in_use;
+    use crate::sched::{kthreads, reap, spawn_kthread, yield_now};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+    let stacks = kernel_stacks_in_use();
+    let step = |n: usize| {
+        move || {
+            let mut x = n as f64;
+            for _ in 0..3 {
+                STEPS.fetch_add(1, Ordering::SeqCst);
+                yield_now();
+                x *= 2.0;
+            }
+            assert_eq!(x, n as f64 * 8.0);
+        }
+    };
+    let a = spawn_kthread("test-a", step(1)).expect("spawn failed");
+    let b = spawn_kthread("test-b", step(3)).expect("spawn failed");
+    assert_ne!(a.id(), b.id());
+    assert_eq!(kernel_stacks_in_use(), stacks + 2);
+    let mut turns = 0;
+    while !(a.is_finished() && b.is_finished()) {
+        assert!(yield_now(), "no kernel thread ran");
+        turns += 1;
+        assert!(turns < 100);
+    }
+    assert_eq!(STEPS.load(Ordering::SeqCst), 6);
+    assert_eq!(reap(), 2);
+    assert!(!kthreads().iter().any(|t| t.name.starts_with("test-")));
+    // dropping the handles gave the stacks back
+    drop((a, b));
+    assert_eq!(kernel_stacks_in_use(), stacks);
+}
+
+#[test_case]
+fn kernel_stacks_sit_above_a_guard_page() {
+    use crate::memory::{alloc_kernel_stack, kernel_space, KERNEL_STACK_SIZE};
+    use x86_64::VirtAddr;
+    let mut stack = alloc_kernel_stack().expect("no kernel stack");
+    let (bottom, top) = (stack.bottom(), stack.top());
+    assert_eq!(top - bottom, KERNEL_STACK_SIZE as u64);
+    assert!(stack.as_slice().iter().all(|&w| w == 0));
+    stack.as_mut_slice()[0] = 1;
+    let space = kernel_space().expect("no kernel space");
+    assert!(space.translate(VirtAddr::new(bottom)).is_some());
+    assert!(space.translate(VirtAddr::new(top - 8)).is_some());
+    assert!(space.translate(VirtAddr::new(bottom - 8)).is_none());
+    drop(space);
+    drop(stack);
+    assert!(kernel_space().unwrap().translate(VirtAddr::new(bottom)).is_none());
+}
+
+#[test_case]
+fn input_wakes_a_blanked_screen() {
+    use crate::driver_framework::drivers::vbe_vga::screen_blanked;
+    use crate::input::{blank_screen, input_idle_ns, note_input};
+    blank_screen();
+    assert!(screen_blanked());
+    note_input();
+    assert!(!screen_blanked());
+    assert!(input_idle_ns() < 1_000_000_000);
+}
@@ -0,0 +1,100 @@
+//! In-kernel test harness
+//!
+//! `cargo test` builds the library as its own kernel (see `test_kernel_main`
+//! in `lib.rs`), which `bootimage runner` (the runner set in
+//! `.cargo/config.toml`) boots under QEMU with an `isa-debug-exit` device
+//! at port 0xF4 and COM1 on stdio. The test kernel
+//! brings up memory, the heap and the descriptor tables, runs every
+//! `#[test_case]`, and leaves QEMU through the exit device: `Success` maps to
+//! exit status 33, which `test-success-exit-code` in `Cargo.toml` turns back
+//! into a pass. A panic fails the run.
+//!
+//! `selftest` runs a smaller set of checks in the normal kernel when it is
+//! booted with `--selftest`.
+
+#[cfg(test)]
+mod cases;
+pub mod selftest;
+
+use crate::prelude::*;
+use crate::arch::gdt::init_gdt;
+use crate::arch::idt::{hlt, init_idt};
+use crate::arch::processor::enable_sse;
+use crate::memory::{self, allocator, BootInfoFrameAllocator};
+use bootloader::BootInfo;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::instructions::port::Port;
+
+/// Port of QEMU's `isa-debug-exit` device.
+pub const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Written to the exit device; QEMU exits with `(code << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32) };
+    // not under QEMU, or no exit device
+    hlt();
+}
+
+/// A test case that reports its own name.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("{}...\t", core::any::type_name::<T>());
+        self();
+        println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    println!("[failed]");
+    println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+static mut FRAME_ALLOCATOR: Option<BootInfoFrameAllocator> = None;
+static mut PHYS_OFFSET: u64 = 0;
+
+/// The subset of `kernel_main` tests rely on: SSE, paging, the frame
+/// allocator, the heap, GDT and IDT. Output goes to the serial port.
+pub fn init_test_kernel(boot_info: &'static BootInfo) {
+    let _ = crate::log::add_sink(&crate::log::SERIAL_SINK, crate::log::Level::Trace);
+    enable_sse();
+    let params = crate::boot::install_boot_params(crate::boot::BootParams::from_bootloader(boot_info));
+    let phys_offset = VirtAddr::new(params.phys_offset);
+    unsafe {
+        PHYS_OFFSET = phys_offset.as_u64();
+        let mut mapper = memory::init(phys_offset);
+        // same reservation heuristic as kernel_main: a MiB past this code
+        let code_phys = (init_test_kernel as usize as u64).checked_sub(phys_offset.as_u64());
+        let reserved_end = code_phys.map(|p| (p & !0xFFF) + 1024 * 1024);
+        let frames = (*(&raw mut FRAME_ALLOCATOR)).insert(BootInfoFrameAllocator::init(&params.memory_map, phys_offset, reserved_end));
+        memory::set_global_frame_allocator(frames as *mut _);
+        allocator::init_heap(&mut mapper, frames).expect("heap initialization failed");
+        memory::init_kernel_space(mapper);
+    }
+    init_gdt();
+    init_idt();
+}
+
+pub fn test_phys_offset() -> u64 {
+    unsafe { PHYS_OFFSET }
+}
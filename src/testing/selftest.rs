@@ -0,0 +1,233 @@
+//! Boot-time self-test
+//!
+//! With `--selftest` on the kernel command line, `kernel_main` runs these
+//! checks on the real kernel instead of starting init or the shell, prints
+//! one line per check and a summary to every log sink plus COM1, and exits
+//! QEMU through the debug-exit device: `Success` if everything passed. CI
+//! boots the normal image this way; `cargo test` covers the rest.
+
+use crate::prelude::*;
+use crate::memory;
+use crate::testing::{exit_qemu, QemuExitCode};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Whether `--selftest` is on the command line.
+pub fn selftest_requested(cmdline: &str) -> bool {
+    cmdline.split_whitespace().any(|w| w == "--selftest")
+}
+
+struct Context {
+    phys_offset: u64,
+}
+
+type Check = fn(&mut Context) -> Result<(), String>;
+
+const CHECKS: [(&str, Check); 6] = [
+    ("memcpy", check_memcpy),
+    ("memset", check_memset),
+    ("memmove", check_memmove),
+    ("frame allocator", check_frame_allocator),
+    ("page mapping", check_page_mapping),
+    ("acpi checksums", check_acpi_checksums),
+];
+
+/// Sizes around the 16- and 64-byte steps of the SSE routines.
+const SIZES: [usize; 16] = [0, 1, 7, 15, 16, 17, 31, 48, 63, 64, 65, 127, 129, 255, 1000, 4096];
+const BUF_LEN: usize = 4096 + 64;
+
+static mut SRC: [u8; BUF_LEN] = [0; BUF_LEN];
+static mut DST: [u8; BUF_LEN] = [0; BUF_LEN];
+
+fn buffers() -> (&'static mut [u8; BUF_LEN], &'static mut [u8; BUF_LEN]) {
+    // only the CPU running the self-test touches these
+    unsafe { (&mut *(&raw mut SRC), &mut *(&raw mut DST)) }
+}
+
+/// Deterministic, position-dependent fill so misplaced bytes show up.
+fn pattern(buf: &mut [u8], seed: u8) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        unsafe { write_volatile(b, (i as u8).wrapping_mul(31).wrapping_add(seed)) };
+    }
+}
+
+/// Byte at `i`, read so the compiler can't turn a comparison loop into a
+/// call to the routines under test.
+fn at(buf: &[u8], i: usize) -> u8 {
+    unsafe { read_volatile(&buf[i]) }
+}
+
+fn check_memcpy(_: &mut Context) -> Result<(), String> {
+    let (src, dst) = buffers();
+    for len in SIZES {
+        for (s_off, d_off) in [(0, 0), (1, 0), (0, 1), (3, 13), (15, 1), (8, 8)] {
+            pattern(src, 7);
+            pattern(dst, 99);
+            unsafe { crate::rlib::mem::memcpy(dst.as_mut_ptr().add(d_off), src.as_ptr().add(s_off), len) };
+            for i in 0..BUF_LEN {
+                let want = if (d_off..d_off + len).contains(&i) { at(src, i - d_off + s_off) } else { (i as u8).wrapping_mul(31).wrapping_add(99) };
+                if at(dst, i) != want {
+                    return Err(format!("len {} src+{} dst+{}: byte {} is {:#04x}, want {:#04x}", len, s_off, d_off, i, at(dst, i), want));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_memset(_: &mut Context) -> Result<(), String> {
+    let (_, dst) = buffers();
+    for len in SIZES {
+        for off in [0, 1, 5, 15] {
+            pattern(dst, 3);
+            unsafe { crate::rlib::mem::memset(dst.as_mut_ptr().add(off), 0xA5, len) };
+            for i in 0..BUF_LEN {
+                let want = if (off..off + len).contains(&i) { 0xA5 } else { (i as u8).wrapping_mul(31).wrapping_add(3) };
+                if at(dst, i) != want {
+                    return Err(format!("len {} +{}: byte {} is {:#04x}, want {:#04x}", len, off, i, at(dst, i), want));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_memmove(_: &mut Context) -> Result<(), String> {
+    let (reference, buf) = buffers();
+    for len in SIZES.into_iter().filter(|&l| l + 64 <= BUF_LEN) {
+        // overlapping both ways, by less and more than a vector
+        for (from, to) in [(0, 1), (1, 0), (0, 15), (15, 0), (3, 20), (20, 3), (0, 33), (33, 0), (16, 32), (32, 16)] {
+            pattern(buf, 11);
+            pattern(reference, 11);
+            unsafe { crate::rlib::mem::memmove(buf.as_mut_ptr().add(to), buf.as_ptr().add(from), len) };
+            for i in 0..BUF_LEN {
+                let want = if (to..to + len).contains(&i) { at(reference, i - to + from) } else { at(reference, i) };
+                if at(buf, i) != want {
+                    return Err(format!("len {} {}->{}: byte {} is {:#04x}, want {:#04x}", len, from, to, i, at(buf, i), want));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Frames taken by the allocator stress.
+const STRESS_FRAMES: usize = 1024;
+
+fn check_frame_allocator(ctx: &mut Context) -> Result<(), String> {
+    let phys_offset = ctx.phys_offset;
+    memory::with_frame_allocator(|frames| {
+        let free_before = frames.free_frames();
+        let mut taken = Vec::with_capacity(STRESS_FRAMES);
+        while taken.len() < STRESS_FRAMES {
+            match frames.allocate_frame() {
+                Some(f) => taken.push(f),
+                None => break,
+            }
+        }
+        // tag each frame with its own address, then read all tags back
+        for f in &taken {
+            let p = (phys_offset + f.start_address().as_u64()) as *mut u64;
+            unsafe { write_volatile(p, f.start_address().as_u64()) };
+        }
+        let mut result = Ok(());
+        for f in &taken {
+            let p = (phys_offset + f.start_address().as_u64()) as *const u64;
+            if unsafe { read_volatile(p) } != f.start_address().as_u64() {
+                result = Err(format!("frame {:#x} handed out twice", f.start_address().as_u64()));
+                break;
+            }
+        }
+        for f in taken.drain(..) {
+            unsafe { frames.free_frame(f) };
+        }
+        result?;
+        if frames.free_frames() != free_before {
+            return Err(format!("{} frames free before, {} after", free_before, frames.free_frames()));
+        }
+        Ok(())
+    })
+    .ok_or_else(|| String::from("no frame allocator"))?
+}
+
+/// Where the mapping check maps its pages.
+const MAP_TEST_BASE: u64 = 0x_5555_5e1f_0000;
+const MAP_TEST_PAGES: u64 = 16;
+
+fn check_page_mapping(ctx: &mut Context) -> Result<(), String> {
+    for i in 0..MAP_TEST_PAGES {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(MAP_TEST_BASE + i * 4096));
+        let mut space = memory::kernel_space().ok_or("no kernel space")?;
+        let frame = memory::with_frame_allocator(|frames| frames.allocate_frame())
+            .ok_or("no frame allocator")?
+            .ok_or("out of frames")?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if unsafe { space.map(page, frame, flags) }.is_err() {
+            memory::with_frame_allocator(|frames| unsafe { frames.free_frame(frame) });
+            return Err(String::from("map failed"));
+        }
+
+        let result = (|| {
+            if space.translate(page.start_address()) != Some(frame.start_address()) {
+                return Err(format!("{:#x} translates wrong", page.start_address().as_u64()));
+            }
+            let virt = page.start_address().as_mut_ptr::<u64>();
+            let phys = (ctx.phys_offset + frame.start_address().as_u64()) as *const u64;
+            let value = 0x5e1f_7e57_0000 | i;
+            unsafe { write_volatile(virt, value) };
+            if unsafe { read_volatile(phys) } != value {
+                return Err(format!("write through {:#x} not seen at phys {:#x}", page.start_address().as_u64(), frame.start_address().as_u64()));
+            }
+            Ok(())
+        })();
+
+        let unmapped = space.unmap(page).map_err(|_| String::from("unmap failed"))?;
+        memory::with_frame_allocator(|frames| unsafe { frames.free_frame(unmapped) });
+        result?;
+        if space.translate(page.start_address()).is_some() {
+            return Err(format!("{:#x} still mapped after unmap", page.start_address().as_u64()));
+        }
+    }
+    Ok(())
+}
+
+fn check_acpi_checksums(ctx: &mut Context) -> Result<(), String> {
+    let rsdp = crate::devices::acpi::find_rsdp(ctx.phys_offset).ok_or("no RSDP")?;
+    if !rsdp.checksum_valid() {
+        return Err(String::from("RSDP checksum"));
+    }
+    let tables = crate::devices::acpi::tables();
+    if tables.is_empty() {
+        return Err(String::from("no tables found"));
+    }
+    for t in tables {
+        let bytes = unsafe { core::slice::from_raw_parts((ctx.phys_offset + t.phys_addr) as *const u8, t.length as usize) };
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0 {
+            return Err(format!("{} checksum", String::from_utf8_lossy(&t.signature)));
+        }
+    }
+    Ok(())
+}
+
+/// Run every check, print a summary and exit QEMU.
+pub fn run_selftest(phys_offset: u64) -> ! {
+    let _ = crate::log::add_sink(&crate::log::SERIAL_SINK, crate::log::Level::Info);
+    let mut ctx = Context { phys_offset };
+    let mut failed = 0;
+    println!("[SELFTEST] running {} checks", CHECKS.len());
+    for (name, check) in CHECKS {
+        match check(&mut ctx) {
+            Ok(()) => println!("[SELFTEST] {:<16} ok", name),
+            Err(e) => {
+                failed += 1;
+                println!("[SELFTEST] {:<16} FAILED: {}", name, e);
+            }
+        }
+    }
+    println!("[SELFTEST] {} passed, {} failed", CHECKS.len() - failed, failed);
+    exit_qemu(if failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed })
+}
@@ -0,0 +1,102 @@
+//! Boot timeline
+//!
+//! `boot_phase("pci scan", || ...)` records TSC stamps around one step of
+//! kernel initialization. Phases may nest (ACPI runs inside HAL init) and
+//! are kept in a fixed table, since the first ones run before the heap
+//! exists. Stamps are raw cycles, converted when printed: the TSC is only
+//! calibrated part way through boot.
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::{cycles_to_us, rdtsc};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Phases recorded at most; later ones are timed but not kept.
+pub const MAX_BOOT_PHASES: usize = 48;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootPhase {
+    pub name: &'static str,
+    /// Nesting depth; 0 for phases run directly by `kernel_main`.
+    pub depth: u8,
+    pub start: u64,
+    /// 0 while the phase is running.
+    pub end: u64,
+}
+
+struct Timeline {
+    phases: [Option<BootPhase>; MAX_BOOT_PHASES],
+    len: usize,
+}
+
+static TIMELINE: Mutex<Timeline> = Mutex::new(Timeline { phases: [None; MAX_BOOT_PHASES], len: 0 });
+static BOOT_START: AtomicU64 = AtomicU64::new(0);
+static BOOT_END: AtomicU64 = AtomicU64::new(0);
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Stamp the start of boot. Called first thing in `kernel_main`.
+pub fn boot_start() {
+    crate::time::clock::start_clock();
+    BOOT_START.store(rdtsc(), Ordering::Relaxed);
+}
+
+/// Stamp the end of boot, once the kernel is about to run its executor.
+pub fn boot_done() {
+    BOOT_END.store(rdtsc(), Ordering::Relaxed);
+    let us = cycles_to_us(BOOT_END.load(Ordering::Relaxed).wrapping_sub(BOOT_START.load(Ordering::Relaxed)));
+    println!("[BOOT] up in {}.{:03} ms; 'bootlog' shows the phases", us / 1000, us % 1000);
+}
+
+/// Run `f` as the boot phase `name`.
+pub fn boot_phase<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let depth = DEPTH.fetch_add(1, Ordering::Relaxed);
+    let slot = interrupts::without_interrupts(|| {
+        let mut t = TIMELINE.lock();
+        let slot = t.len;
+        if slot < MAX_BOOT_PHASES {
+            t.phases[slot] = Some(BootPhase { name, depth: depth as u8, start: rdtsc(), end: 0 });
+            t.len += 1;
+        }
+        slot
+    });
+    let value = f();
+    let end = rdtsc();
+    interrupts::without_interrupts(|| {
+        if let Some(Some(p)) = TIMELINE.lock().phases.get_mut(slot) {
+            p.end = end;
+        }
+    });
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+    value
+}
+
+/// The recorded phases in the order they started.
+pub fn boot_phases() -> alloc::vec::Vec<BootPhase> {
+    interrupts::without_interrupts(|| TIMELINE.lock().phases.iter().flatten().copied().collect())
+}
+
+fn ms(us: u64) -> (u64, u64) {
+    (us / 1000, us % 1000)
+}
+
+/// Print each phase's start (relative to `boot_start`) and duration.
+pub fn print_boot_timeline() {
+    let base = BOOT_START.load(Ordering::Relaxed);
+    println!("{:>12} {:>12}  PHASE", "START(ms)", "TOOK(ms)");
+    for p in boot_phases() {
+        let (s, s_frac) = ms(cycles_to_us(p.start.wrapping_sub(base)));
+        let name_pad = p.depth as usize * 2;
+        if p.end == 0 {
+            println!("{:>8}.{:03} {:>12}  {:name_pad$}{}", s, s_frac, "running", "", p.name);
+            continue;
+        }
+        let (d, d_frac) = ms(cycles_to_us(p.end - p.start));
+        println!("{:>8}.{:03} {:>8}.{:03}  {:name_pad$}{}", s, s_frac, d, d_frac, "", p.name);
+    }
+    let end = BOOT_END.load(Ordering::Relaxed);
+    if end != 0 {
+        let (t, t_frac) = ms(cycles_to_us(end.wrapping_sub(base)));
+        println!("total {}.{:03} ms", t, t_frac);
+    }
+}
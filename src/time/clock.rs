@@ -0,0 +1,241 @@
+//! Monotonic clock
+//!
+//! `uptime_ns()` and `Instant::now()` give nanoseconds since `boot_start`,
+//! read from the TSC once its rate is measured against the HPET or PIT, or
+//! straight from the HPET main counter when the TSC is missing or not
+//! invariant. Reading the clock
+//! takes no locks, so it works in interrupt handlers and on the panic path.
+//!
+//! The clock never goes backwards: when calibration changes the TSC rate
+//! or the source switches, the current reading becomes the new base. A
+//! small sequence counter keeps readers on other CPUs from seeing half of
+//! such a change.
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::{rdtsc, tsc_hz};
+use crate::devices::acpi;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const HPET_CONFIG_OFFSET: u64 = 0x10;
+const HPET_ENABLE_CNF: u64 = 1;
+const HPET_MAIN_COUNTER_OFFSET: u64 = 0xF0;
+/// Longest tick period the HPET spec allows (100 ns).
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Tsc,
+    Hpet,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Tsc as u8);
+/// Odd while the base below is being changed.
+static SEQ: AtomicU64 = AtomicU64::new(0);
+/// Counter value (TSC or HPET ticks) at `BASE_NS`.
+static BASE_COUNT: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+/// Virtual address of the HPET main counter, 0 until mapped.
+static HPET_COUNTER: AtomicU64 = AtomicU64::new(0);
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+pub fn clock_source() -> ClockSource {
+    if SOURCE.load(Ordering::Relaxed) == ClockSource::Hpet as u8 { ClockSource::Hpet } else { ClockSource::Tsc }
+}
+
+/// The HPET main counter and its period in femtoseconds, once mapped.
+pub fn hpet_counter() -> Option<(*const u64, u64)> {
+    match HPET_COUNTER.load(Ordering::Relaxed) {
+        0 => None,
+        va => Some((va as *const u64, HPET_PERIOD_FS.load(Ordering::Relaxed))),
+    }
+}
+
+fn read_count(source: ClockSource) -> u64 {
+    match source {
+        ClockSource::Tsc => rdtsc(),
+        ClockSource::Hpet => unsafe { core::ptr::read_volatile(HPET_COUNTER.load(Ordering::Relaxed) as *const u64) },
+    }
+}
+
+fn count_to_ns(source: ClockSource, count: u64) -> u64 {
+    let ns = match source {
+        ClockSource::Tsc => count as u128 * 1_000_000_000 / tsc_hz().max(1) as u128,
+        ClockSource::Hpet => count as u128 * HPET_PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000,
+    };
+    ns as u64
+}
+
+/// Nanoseconds since `boot_start`.
+pub fn uptime_ns() -> u64 {
+    loop {
+        let seq = SEQ.load(Ordering::Acquire);
+        if seq & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let source = clock_source();
+        let base_count = BASE_COUNT.load(Ordering::Relaxed);
+        let base_ns = BASE_NS.load(Ordering::Relaxed);
+        let count = read_count(source);
+        if SEQ.load(Ordering::Acquire) == seq {
+            return base_ns + count_to_ns(source, count.wrapping_sub(base_count));
+        }
+    }
+}
+
+/// Make the current reading the new base, then run `change` (which may
+/// switch the source or the TSC rate). Only the boot CPU does this.
+fn rebase(change: impl FnOnce()) {
+    let now = uptime_ns();
+    SEQ.fetch_add(1, Ordering::AcqRel);
+    change();
+    BASE_COUNT.store(read_count(clock_source()), Ordering::Relaxed);
+    BASE_NS.store(now, Ordering::Relaxed);
+    SEQ.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Start the clock at zero. Called from `boot_start`.
+pub(crate) fn start_clock() {
+    BASE_COUNT.store(rdtsc(), Ordering::Relaxed);
+    BASE_NS.store(0, Ordering::Relaxed);
+}
+
+/// Map the HPET registers, if ACPI found an HPET below 4 GiB, and make
+/// sure its main counter is running.
+fn map_hpet_counter(phys_offset: VirtAddr) -> Option<()> {
+    let hpet_base = acpi::get_hpet_address()?;
+    // An HPET above 4 GiB may be outside the direct physical mapping;
+    // leave it alone rather than risk a fault.
+    if hpet_base >= 0x1_0000_0000 {
+        return None;
+    }
+    let virt = VirtAddr::new(phys_offset.as_u64() + hpet_base);
+    let mut space = crate::memory::kernel_space()?;
+    if space.translate(virt).is_none() {
+        let page: Page<Size4KiB> = Page::containing_address(virt);
+        let frame = PhysFrame::containing_address(PhysAddr::new(hpet_base));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe { space.map(page, frame, flags) }.ok()?;
+    }
+    drop(space);
+    let regs = virt.as_u64();
+    // the capabilities register has the real tick period; the ACPI table
+    // copy is only a hint
+    let caps = unsafe { core::ptr::read_volatile(regs as *const u64) };
+    let period_fs = match caps >> 32 {
+        p @ 1..=HPET_MAX_PERIOD_FS => p,
+        _ => acpi::get_hpet_period_fs(),
+    };
+    if period_fs == 0 {
+        return None;
+    }
+    let config = (regs + HPET_CONFIG_OFFSET) as *mut u64;
+    unsafe {
+        let c = core::ptr::read_volatile(config);
+        if c & HPET_ENABLE_CNF == 0 {
+            core::ptr::write_volatile(config, c | HPET_ENABLE_CNF);
+        }
+    }
+    let counter = regs + HPET_MAIN_COUNTER_OFFSET;
+    HPET_PERIOD_FS.store(period_fs, Ordering::Relaxed);
+    HPET_COUNTER.store(counter, Ordering::Relaxed);
+    Some(())
+}
+
+/// Map the HPET, measure the TSC rate against it (or the PIT without
+/// one), and pick the clock source: the TSC if it is invariant or there is
+/// nothing better, the HPET otherwise. Called once ACPI has been parsed.
+pub fn init_clock(phys_offset: VirtAddr) {
+    use crate::arch::tsc_timer::{calibrate_with_hpet, calibrate_with_pit, tsc_calibration, TscCalibration};
+    let has_hpet = map_hpet_counter(phys_offset).is_some();
+    let feats = crate::arch::detect_cpu_features();
+    let use_hpet = has_hpet && !(feats.tsc && feats.invariant_tsc);
+    rebase(|| {
+        if feats.tsc && !calibrate_with_hpet() {
+            calibrate_with_pit();
+        }
+        if use_hpet {
+            SOURCE.store(ClockSource::Hpet as u8, Ordering::Relaxed);
+        }
+    });
+    if feats.tsc && tsc_calibration() == TscCalibration::Guess {
+        println!("[TIME] could not measure the TSC; its rate is a guess ({} Hz)", tsc_hz());
+    }
+    if feats.tsc && !feats.invariant_tsc {
+        println!("[TIME] TSC is not invariant{}", if use_hpet { "; timing with the HPET" } else { " and there is no HPET" });
+    }
+    println!("[TIME] clock source {:?}, TSC {} Hz ({:?})", clock_source(), tsc_hz(), tsc_calibration());
+}
+
+/// A reading of the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { ns: uptime_ns() }
+    }
+
+    /// Time since `boot_start` at this instant.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.ns)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.ns
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<Instant> {
+        let ns = u64::try_from(d.as_nanos()).ok()?;
+        Some(Instant { ns: self.ns.checked_add(ns)? })
+    }
+
+    pub fn saturating_add(&self, d: Duration) -> Instant {
+        self.checked_add(d).unwrap_or(Instant { ns: u64::MAX })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, d: Duration) -> Instant {
+        self.saturating_add(d)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, d: Duration) {
+        *self = *self + d;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Busy-wait for `d`. For short hardware settle times; anything that can
+/// sleep should use `sleep_ms`.
+pub fn spin_for(d: Duration) {
+    let end = Instant::now() + d;
+    while Instant::now() < end {
+        core::hint::spin_loop();
+    }
+}
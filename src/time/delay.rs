@@ -0,0 +1,102 @@
+//! Short busy-wait delays
+//!
+//! `udelay` and `mdelay` wait a given time for hardware to settle. Unlike
+//! `spin_for`, they don't go through the monotonic clock, which is only
+//! right once `init_clock` has measured the TSC: they count the TSC once
+//! it is calibrated, and before that the ACPI PM timer or, without one,
+//! PIT channel 2. None of them takes a lock or allocates, so they work
+//! before the heap is up and in interrupt handlers.
+//!
+//! They wait at least the time asked for, and on a busy or virtualised
+//! machine sometimes rather more. Anything longer than a few milliseconds
+//! that can sleep should use `sleep_ms`.
+
+use crate::prelude::*;
+use crate::arch::tsc_timer::{pit_countdown, rdtsc, tsc_calibration, tsc_hz, TscCalibration, PIT_HZ};
+
+/// ACPI PM timer rate.
+const PM_TIMER_HZ: u64 = 3_579_545;
+/// Longest PIT channel 2 countdown.
+const PIT_MAX_COUNT: u64 = 0xFFFF;
+
+/// What a delay counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelaySource {
+    Tsc,
+    PmTimer,
+    Pit,
+}
+
+/// The source `udelay` uses right now: the TSC once its rate has been
+/// measured, else the PM timer if the FADT has one, else the PIT.
+pub fn delay_source() -> DelaySource {
+    if tsc_calibration() != TscCalibration::Guess {
+        DelaySource::Tsc
+    } else if crate::devices::acpi::pm_timer().is_some() {
+        DelaySource::PmTimer
+    } else {
+        DelaySource::Pit
+    }
+}
+
+/// Busy-wait at least `us` microseconds.
+pub fn udelay(us: u64) {
+    delay_with(delay_source(), us);
+}
+
+/// Busy-wait at least `ms` milliseconds.
+pub fn mdelay(ms: u64) {
+    for _ in 0..ms {
+        udelay(1000);
+    }
+}
+
+/// Busy-wait `us` microseconds counted by `source`. The PM timer falls back
+/// to the PIT when there isn't one.
+pub(crate) fn delay_with(source: DelaySource, us: u64) {
+    if us == 0 {
+        return;
+    }
+    match source {
+        DelaySource::Tsc => {
+            let cycles = (us as u128 * tsc_hz() as u128).div_ceil(1_000_000) as u64;
+            let start = rdtsc();
+            while rdtsc().wrapping_sub(start) < cycles {
+                core::hint::spin_loop();
+            }
+        }
+        DelaySource::PmTimer => match crate::devices::acpi::pm_timer() {
+            Some((port, wide)) => pm_timer_delay(port, wide, us),
+            None => pit_delay(us),
+        },
+        DelaySource::Pit => pit_delay(us),
+    }
+}
+
+fn pm_timer_delay(port: u16, wide: bool, us: u64) {
+    let mask: u32 = if wide { u32::MAX } else { 0x00FF_FFFF };
+    let ticks = (us as u128 * PM_TIMER_HZ as u128).div_ceil(1_000_000) as u64;
+    let read = || unsafe { crate::arch::ports::indw(port) } & mask;
+    // the counter wraps every few seconds at 24 bits, so add up the steps
+    // between reads rather than compare against an end value
+    let mut last = read();
+    let mut elapsed = 0u64;
+    while elapsed < ticks {
+        core::hint::spin_loop();
+        let now = read();
+        elapsed += (now.wrapping_sub(last) & mask) as u64;
+        last = now;
+    }
+}
+
+fn pit_delay(us: u64) {
+    let mut counts = (us as u128 * PIT_HZ as u128).div_ceil(1_000_000) as u64;
+    while counts > 0 {
+        let chunk = counts.min(PIT_MAX_COUNT);
+        if pit_countdown(chunk as u16).is_none() {
+            kwarn_once!("[TIME] PIT channel 2 didn't count down; delay cut short");
+            return;
+        }
+        counts -= chunk;
+    }
+}
@@ -0,0 +1,15 @@
+//! Time keeping: the monotonic and wall clocks, deadlines and timeouts,
+//! short delays, timer callbacks, and the boot timeline.
+
+pub mod clock;
+pub use clock::*;
+pub mod wallclock;
+pub use wallclock::*;
+pub mod timeout;
+pub use timeout::*;
+pub mod delay;
+pub use delay::*;
+pub mod timer;
+pub use timer::*;
+pub mod bootlog;
+pub use bootlog::*;
@@ -0,0 +1,179 @@
+//! Deadlines and timeouts
+//!
+//! Hardware waits should be bounded in wall time, not in loop iterations
+//! whose duration depends on CPU speed. `spin_until_ms` bounds a polling
+//! loop; `with_timeout_ms` bounds a future. Both measure time with the
+//! monotonic clock.
+
+use crate::prelude::*;
+use crate::time::clock::{uptime_ns, Instant};
+use crate::sync::{WaitQueue, Waiter};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+/// Returned when an operation doesn't finish before its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// The timeout that expired, in milliseconds.
+    pub ms: u64,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out after {}ms", self.ms)
+    }
+}
+
+impl From<TimeoutError> for &'static str {
+    fn from(_: TimeoutError) -> &'static str {
+        "timed out"
+    }
+}
+
+/// A point in time on the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub fn after_ms(ms: u64) -> Deadline {
+        Deadline::after_us(ms.saturating_mul(1000))
+    }
+
+    pub fn after_us(us: u64) -> Deadline {
+        Deadline { at: Instant::now() + Duration::from_micros(us) }
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+}
+
+/// Poll `f` until it returns `Some` or `ms` milliseconds pass.
+pub fn spin_until_ms<T>(ms: u64, mut f: impl FnMut() -> Option<T>) -> Result<T, TimeoutError> {
+    let deadline = Deadline::after_ms(ms);
+    loop {
+        if let Some(v) = f() {
+            return Ok(v);
+        }
+        if deadline.expired() {
+            // one last look, in case we were preempted past the deadline
+            return f().ok_or(TimeoutError { ms });
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Tasks waiting for a deadline park here; the timer interrupt wakes them
+/// all once the earliest of their deadlines passes.
+static TICK_WAIT: WaitQueue = WaitQueue::new();
+/// Earliest deadline (uptime ns) of a task parked on `TICK_WAIT`.
+static SLEEP_DUE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Park the current task until `deadline`, making sure a timer interrupt
+/// comes by then.
+fn wait_for(waiter: &mut Waiter, cx: &mut Context, deadline: Deadline) {
+    waiter.register(&TICK_WAIT, cx);
+    let ns = deadline.at.as_nanos();
+    SLEEP_DUE.fetch_min(ns, Ordering::Relaxed);
+    crate::arch::tsc_timer::rearm_before(ns);
+}
+
+/// Earliest time (uptime ns) anything waits for: a sleeping task or a
+/// timer callback. `u64::MAX` if nothing does.
+pub fn next_event_ns() -> u64 {
+    SLEEP_DUE.load(Ordering::Relaxed).min(crate::time::timer::next_due())
+}
+
+/// Called from the timer interrupt. Waking the sleepers neither allocates
+/// nor frees: `TICK_WAIT` is drained in place, a woken task goes on a run
+/// queue with room for it, and every waker left on it belongs to a live
+/// `Timeout` or `Sleep` (which take theirs off when they finish or are
+/// dropped), so none is the last reference to its task.
+pub fn timer_tick() {
+    if uptime_ns() >= SLEEP_DUE.load(Ordering::Relaxed) {
+        // woken tasks that are still early park again with their deadlines
+        SLEEP_DUE.store(u64::MAX, Ordering::Relaxed);
+        TICK_WAIT.wake_all();
+    }
+    crate::time::timer::check_timers();
+}
+
+/// Run `future`, giving up after `ms` milliseconds. Deadline checks happen
+/// whenever the future is woken and when the deadline passes.
+pub fn with_timeout_ms<F: Future>(ms: u64, future: F) -> Timeout<F> {
+    Timeout { future, deadline: Deadline::after_ms(ms), ms, waiter: Waiter::new() }
+}
+
+/// Future returned by `with_timeout_ms`.
+pub struct Timeout<F> {
+    future: F,
+    deadline: Deadline,
+    ms: u64,
+    waiter: Waiter,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimeoutError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<F::Output, TimeoutError>> {
+        // SAFETY: `future` is structurally pinned and never moved out
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(v) = future.poll(cx) {
+            this.waiter.done(&TICK_WAIT);
+            return Poll::Ready(Ok(v));
+        }
+        if this.deadline.expired() {
+            this.waiter.done(&TICK_WAIT);
+            return Poll::Ready(Err(TimeoutError { ms: this.ms }));
+        }
+        wait_for(&mut this.waiter, cx, this.deadline);
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for Timeout<F> {
+    fn drop(&mut self) {
+        self.waiter.cancel(&TICK_WAIT);
+    }
+}
+
+/// Future that completes once `ms` milliseconds have passed.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep { deadline: Deadline::after_ms(ms), waiter: Waiter::new() }
+}
+
+/// Future returned by `sleep_ms`.
+pub struct Sleep {
+    deadline: Deadline,
+    waiter: Waiter,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if !this.deadline.expired() {
+            wait_for(&mut this.waiter, cx, this.deadline);
+            // the deadline may have passed before the waker was registered
+            if !this.deadline.expired() {
+                return Poll::Pending;
+            }
+        }
+        this.waiter.done(&TICK_WAIT);
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.waiter.cancel(&TICK_WAIT);
+    }
+}
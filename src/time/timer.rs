@@ -0,0 +1,235 @@
+//! Timer callbacks
+//!
+//! `after(d, f)` runs `f` once, `d` from now; `every(p, f)` runs `f` every
+//! `p` until cancelled. Both return a `TimerId` for `cancel`. Drivers that
+//! need to poll hardware use these instead of spawning their own loop.
+//!
+//! Pending timers sit in a hashed timer wheel of `WHEEL_SLOTS` slots, each
+//! `WHEEL_TICK_NS` of monotonic time wide; a timer whose deadline lies more
+//! than one turn ahead stays in its slot until the wheel comes round to it
+//! again. The timer interrupt only compares the clock against the earliest
+//! deadline and wakes the `ktimer` task, which runs due callbacks in task
+//! context, one at a time, so they may take locks and allocate. A callback
+//! delays every timer behind it, so it should be short; longer work belongs
+//! on the workqueue.
+
+use crate::prelude::*;
+use crate::arch::task;
+use crate::sync::WaitQueue;
+use crate::time::clock::uptime_ns;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Width of one wheel slot.
+pub const WHEEL_TICK_NS: u64 = 1_000_000;
+/// Slots in the wheel; one turn covers `WHEEL_SLOTS * WHEEL_TICK_NS`.
+pub const WHEEL_SLOTS: usize = 256;
+
+/// Identifies a pending timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(pub u64);
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Every(Box<dyn FnMut() + Send>),
+}
+
+struct Timer {
+    id: TimerId,
+    deadline: u64,
+    /// Nanoseconds between runs, 0 for one-shot timers.
+    period: u64,
+    callback: Callback,
+}
+
+struct Wheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    len: usize,
+    /// Tick up to which slots have been run.
+    tick: u64,
+    /// The periodic timer whose callback is running, and whether it was
+    /// cancelled meanwhile.
+    running: Option<TimerId>,
+    cancel_running: bool,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: [const { Vec::new() }; WHEEL_SLOTS],
+    len: 0,
+    tick: 0,
+    running: None,
+    cancel_running: false,
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Earliest pending deadline, `u64::MAX` when nothing is pending.
+static NEXT_DUE: AtomicU64 = AtomicU64::new(u64::MAX);
+static TIMER_WAIT: WaitQueue = WaitQueue::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+fn slot_of(deadline: u64) -> usize {
+    (deadline / WHEEL_TICK_NS) as usize % WHEEL_SLOTS
+}
+
+fn with_wheel<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut WHEEL.lock()))
+}
+
+impl Wheel {
+    fn insert(&mut self, timer: Timer) {
+        NEXT_DUE.fetch_min(timer.deadline, Ordering::Relaxed);
+        crate::arch::tsc_timer::rearm_before(timer.deadline);
+        self.slots[slot_of(timer.deadline)].push(timer);
+        self.len += 1;
+    }
+
+    fn earliest(&self) -> u64 {
+        self.slots.iter().flatten().map(|t| t.deadline).min().unwrap_or(u64::MAX)
+    }
+
+    /// Take one timer due at `now`, walking slots from the last tick run.
+    fn take_due(&mut self, now: u64) -> Option<Timer> {
+        let now_tick = now / WHEEL_TICK_NS;
+        // after a long gap, one turn visits every slot
+        let start = self.tick.max(now_tick.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for tick in start..=now_tick {
+            let slot = &mut self.slots[tick as usize % WHEEL_SLOTS];
+            if let Some(i) = slot.iter().position(|t| t.deadline <= now) {
+                self.tick = tick;
+                self.len -= 1;
+                return Some(slot.swap_remove(i));
+            }
+        }
+        self.tick = now_tick;
+        None
+    }
+}
+
+fn add(delay: Duration, period: u64, callback: Callback) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let deadline = uptime_ns().saturating_add(delay.as_nanos().min(u64::MAX as u128) as u64);
+    with_wheel(|w| w.insert(Timer { id, deadline, period, callback }));
+    TIMER_WAIT.wake_one();
+    id
+}
+
+/// Run `f` once, `delay` from now.
+pub fn after(delay: Duration, f: impl FnOnce() + Send + 'static) -> TimerId {
+    add(delay, 0, Callback::Once(Box::new(f)))
+}
+
+/// Run `f` every `period`, first `period` from now. Runs that fall behind
+/// are skipped rather than made up.
+pub fn every(period: Duration, f: impl FnMut() + Send + 'static) -> TimerId {
+    let period = period.max(Duration::from_nanos(WHEEL_TICK_NS));
+    add(period, period.as_nanos() as u64, Callback::Every(Box::new(f)))
+}
+
+/// Stop a timer. Returns whether it was still going to run; a periodic
+/// timer cancelled from its own callback is not run again.
+pub fn cancel(id: TimerId) -> bool {
+    let (stopped, removed) = with_wheel(|w| {
+        if w.running == Some(id) {
+            w.cancel_running = true;
+            return (true, None);
+        }
+        for slot in w.slots.iter_mut() {
+            if let Some(i) = slot.iter().position(|t| t.id == id) {
+                w.len -= 1;
+                return (true, Some(slot.swap_remove(i)));
+            }
+        }
+        (false, None)
+    });
+    // dropped outside the lock: the closure may own anything
+    drop(removed);
+    stopped
+}
+
+/// Pending timers as (id, nanoseconds until due, period in nanoseconds),
+/// soonest first.
+pub fn pending_timers() -> Vec<(TimerId, u64, u64)> {
+    let mut timers: Vec<(TimerId, u64, u64)> =
+        with_wheel(|w| w.slots.iter().flatten().map(|t| (t.id, t.deadline, t.period)).collect());
+    let now = uptime_ns();
+    for t in timers.iter_mut() {
+        t.1 = t.1.saturating_sub(now);
+    }
+    timers.sort_by_key(|t| t.1);
+    timers
+}
+
+/// Earliest pending deadline in uptime ns, `u64::MAX` if none.
+pub(crate) fn next_due() -> u64 {
+    NEXT_DUE.load(Ordering::Relaxed)
+}
+
+/// Callbacks run since boot.
+pub fn timers_fired() -> u64 {
+    FIRED.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt: wake `ktimer` if a timer is due.
+pub(crate) fn check_timers() {
+    if uptime_ns() >= NEXT_DUE.load(Ordering::Relaxed) {
+        TIMER_WAIT.wake_one();
+    }
+}
+
+fn due() -> Option<()> {
+    (uptime_ns() >= NEXT_DUE.load(Ordering::Relaxed)).then_some(())
+}
+
+async fn timer_task() {
+    loop {
+        TIMER_WAIT.wait_until(due).await;
+        loop {
+            let now = uptime_ns();
+            let Some(mut timer) = with_wheel(|w| {
+                let t = w.take_due(now);
+                if let Some(t) = &t {
+                    w.running = (t.period != 0).then_some(t.id);
+                    w.cancel_running = false;
+                }
+                t
+            }) else {
+                break;
+            };
+            FIRED.fetch_add(1, Ordering::Relaxed);
+            match timer.callback {
+                Callback::Once(f) => f(),
+                Callback::Every(ref mut f) => {
+                    f();
+                    let now = uptime_ns();
+                    timer.deadline = timer.deadline.saturating_add(timer.period);
+                    if timer.deadline <= now {
+                        timer.deadline = now + timer.period;
+                    }
+                    let cancelled = with_wheel(|w| {
+                        w.running = None;
+                        if core::mem::take(&mut w.cancel_running) {
+                            return Some(timer);
+                        }
+                        w.insert(timer);
+                        None
+                    });
+                    drop(cancelled);
+                }
+            }
+        }
+        with_wheel(|w| NEXT_DUE.store(w.earliest(), Ordering::Relaxed));
+        task::yield_now().await;
+    }
+}
+
+/// Spawn the `ktimer` task. Timers added earlier run once it starts.
+pub fn init_timers() {
+    if !STARTED.swap(true, Ordering::SeqCst) {
+        task::spawn_named("ktimer", timer_task());
+    }
+}
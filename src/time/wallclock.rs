@@ -0,0 +1,162 @@
+//! Wall-clock time
+//!
+//! The RTC is read once at boot (`sync_wall_clock`) to find the Unix time
+//! at which the monotonic clock started; after that `now()` is that plus
+//! `uptime_ns()`, so it needs no locks or port I/O and moves smoothly
+//! between RTC seconds. Time is UTC, as the RTC is assumed to keep it.
+//! Until synced, `now()` counts from the Unix epoch. The SNTP client steps
+//! it with `adjust_wall_clock` when the network has better time.
+
+use crate::prelude::*;
+use crate::time::clock::uptime_ns;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Unix time in nanoseconds when `uptime_ns()` was 0.
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+static SYNCED: AtomicBool = AtomicBool::new(false);
+
+/// A calendar date and time of day, UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanos: u32,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Hinnant's
+/// `days_from_civil`).
+fn days_from_civil(year: u16, month: u8, day: u8) -> i64 {
+    let y = year as i64 - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The date `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (u16, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year as u16, month, day)
+}
+
+impl DateTime {
+    /// Whether every field is in range, leap days included.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && (self.nanos as u64) < NANOS_PER_SEC
+    }
+
+    pub fn from_unix_ns(ns: u64) -> DateTime {
+        let secs = ns / NANOS_PER_SEC;
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let tod = secs % SECS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (tod / 3600) as u8,
+            minute: (tod / 60 % 60) as u8,
+            second: (tod % 60) as u8,
+            nanos: (ns % NANOS_PER_SEC) as u32,
+        }
+    }
+
+    /// Seconds since the Unix epoch. The date must be valid.
+    pub fn unix_secs(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day) as u64;
+        days * SECS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn unix_ns(&self) -> u64 {
+        self.unix_secs() * NANOS_PER_SEC + self.nanos as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Whether the wall clock has been set from the RTC (or otherwise).
+pub fn wall_clock_synced() -> bool {
+    SYNCED.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn unix_time_ns() -> u64 {
+    BOOT_UNIX_NS.load(Ordering::Relaxed) + uptime_ns()
+}
+
+/// The current UTC date and time.
+pub fn now() -> DateTime {
+    DateTime::from_unix_ns(unix_time_ns())
+}
+
+/// Set the wall clock to `unix_ns` now.
+pub fn set_wall_clock(unix_ns: u64) {
+    BOOT_UNIX_NS.store(unix_ns.saturating_sub(uptime_ns()), Ordering::Relaxed);
+    SYNCED.store(true, Ordering::Relaxed);
+}
+
+/// Move the wall clock `offset_ns` forward (or back, if negative), as
+/// when a time server says it is off.
+pub fn adjust_wall_clock(offset_ns: i64) {
+    let base = BOOT_UNIX_NS.load(Ordering::Relaxed);
+    BOOT_UNIX_NS.store(base.saturating_add_signed(offset_ns), Ordering::Relaxed);
+    SYNCED.store(true, Ordering::Relaxed);
+}
+
+/// Set the wall clock from the RTC. Called at boot once ACPI has been
+/// parsed (for the century register) and the monotonic clock calibrated.
+pub fn sync_wall_clock() -> bool {
+    match crate::devices::rtc::read_rtc() {
+        Some(dt) => {
+            set_wall_clock(dt.unix_ns());
+            println!("[TIME] wall clock {} UTC from the RTC", dt);
+            true
+        }
+        None => {
+            println!("[TIME] RTC gave no valid time; wall clock starts at the epoch");
+            false
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Trace events
+//!
+//! `trace!(Subsys::Sched, "run pid {}", pid)` records a timestamped line in
+//! the current CPU's ring without taking locks or allocating, so it can be
+//! used in interrupt handlers and around the scheduler where printing would
+//! change the timing being looked at. Each subsystem is switched on and off
+//! in a mask (all off at boot; `trace=irq,sched` on the command line); a
+//! disabled trace point costs one atomic load and formats nothing.
+//!
+//! Every CPU writes only its own ring. A writer claims a slot with a
+//! `fetch_add` on the ring head, so nested interrupts on the same CPU get
+//! slots of their own, and publishes it through the slot's sequence number;
+//! `dump_trace` skips slots that change while it reads them.
+
+use crate::prelude::*;
+use crate::arch::task::MAX_CPUS;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Entries kept per CPU; older ones are overwritten.
+pub const TRACE_ENTRIES: usize = 256;
+/// Bytes of message kept per entry; longer messages are cut.
+pub const TRACE_MSG_LEN: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsys {
+    Irq,
+    Sched,
+    Syscall,
+    Timer,
+    Mem,
+    Net,
+    Fs,
+    Driver,
+}
+
+impl Subsys {
+    pub const ALL: [Subsys; 8] =
+        [Subsys::Irq, Subsys::Sched, Subsys::Syscall, Subsys::Timer, Subsys::Mem, Subsys::Net, Subsys::Fs, Subsys::Driver];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsys::Irq => "irq",
+            Subsys::Sched => "sched",
+            Subsys::Syscall => "syscall",
+            Subsys::Timer => "timer",
+            Subsys::Mem => "mem",
+            Subsys::Net => "net",
+            Subsys::Fs => "fs",
+            Subsys::Driver => "driver",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Subsys> {
+        Subsys::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u8
+    }
+}
+
+static MASK: AtomicU32 = AtomicU32::new(0);
+
+pub fn trace_enabled(subsys: Subsys) -> bool {
+    MASK.load(Ordering::Relaxed) & subsys.bit() != 0
+}
+
+pub fn trace_enable(subsys: Subsys) {
+    MASK.fetch_or(subsys.bit(), Ordering::Relaxed);
+}
+
+pub fn trace_disable(subsys: Subsys) {
+    MASK.fetch_and(!subsys.bit(), Ordering::Relaxed);
+}
+
+/// Enable the subsystems named in `trace=a,b,...` (or `trace=all`) on the
+/// kernel command line.
+pub fn configure_trace_from_cmdline(cmdline: &str) {
+    let Some(names) = cmdline.split_whitespace().find_map(|w| w.strip_prefix("trace=")) else { return };
+    for name in names.split(',') {
+        if name == "all" {
+            Subsys::ALL.into_iter().for_each(trace_enable);
+        } else if let Some(s) = Subsys::from_name(name) {
+            trace_enable(s);
+        } else {
+            println!("[TRACE] unknown subsystem '{}'", name);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    tsc: u64,
+    subsys: Subsys,
+    len: u8,
+    msg: [u8; TRACE_MSG_LEN],
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry { tsc: 0, subsys: Subsys::Irq, len: 0, msg: [0; TRACE_MSG_LEN] };
+
+    fn text(&self) -> &str {
+        let bytes = &self.msg[..self.len as usize];
+        // a cut may split a character; keep the valid prefix
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        }
+    }
+}
+
+struct Slot {
+    /// Index of the record held plus one; 0 while it's being written.
+    seq: AtomicU64,
+    entry: UnsafeCell<Entry>,
+}
+
+struct Ring {
+    head: AtomicUsize,
+    slots: [Slot; TRACE_ENTRIES],
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            head: AtomicUsize::new(0),
+            slots: [const { Slot { seq: AtomicU64::new(0), entry: UnsafeCell::new(Entry::EMPTY) } }; TRACE_ENTRIES],
+        }
+    }
+
+    /// The entry recorded as number `n`, if it is still in the ring and not
+    /// being overwritten.
+    fn read(&self, n: usize) -> Option<Entry> {
+        let slot = &self.slots[n % TRACE_ENTRIES];
+        if slot.seq.load(Ordering::Acquire) != n as u64 + 1 {
+            return None;
+        }
+        let entry = unsafe { core::ptr::read_volatile(slot.entry.get()) };
+        (slot.seq.load(Ordering::Acquire) == n as u64 + 1).then_some(entry)
+    }
+}
+
+static RINGS: [Ring; MAX_CPUS] = [const { Ring::new() }; MAX_CPUS];
+
+/// Formats into an entry's message, dropping what doesn't fit.
+struct MsgWriter<'a> {
+    entry: &'a mut Entry,
+}
+
+impl fmt::Write for MsgWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let at = self.entry.len as usize;
+        let n = s.len().min(TRACE_MSG_LEN - at);
+        self.entry.msg[at..at + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.entry.len += n as u8;
+        Ok(())
+    }
+}
+
+/// Record an event. Use `trace!`, which skips formatting when `subsys` is off.
+pub fn trace_record(subsys: Subsys, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let ring = &RINGS[crate::arch::task::current_cpu() % MAX_CPUS];
+    let n = ring.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &ring.slots[n % TRACE_ENTRIES];
+    slot.seq.store(0, Ordering::Release);
+    let mut entry = Entry { tsc: crate::arch::tsc_timer::rdtsc(), subsys, len: 0, msg: [0; TRACE_MSG_LEN] };
+    let _ = MsgWriter { entry: &mut entry }.write_fmt(args);
+    unsafe { core::ptr::write_volatile(slot.entry.get(), entry) };
+    slot.seq.store(n as u64 + 1, Ordering::Release);
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($subsys:expr, $($arg:tt)*) => {
+        if $crate::trace::trace_enabled($subsys) {
+            $crate::trace::trace_record($subsys, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Print every CPU's recorded events merged in time order, with times in
+/// milliseconds since the oldest. Recording continues meanwhile;
+/// entries overwritten during the dump are skipped.
+pub fn dump_trace() {
+    // per CPU: next record to print and the end of the range
+    let mut next = [0usize; MAX_CPUS];
+    let mut end = [0usize; MAX_CPUS];
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        end[cpu] = ring.head.load(Ordering::Acquire);
+        next[cpu] = end[cpu].saturating_sub(TRACE_ENTRIES);
+    }
+    let mut base = None;
+    loop {
+        let mut best: Option<(usize, Entry)> = None;
+        for cpu in 0..MAX_CPUS {
+            while next[cpu] < end[cpu] {
+                match RINGS[cpu].read(next[cpu]) {
+                    Some(e) => {
+                        if best.map_or(true, |(_, b)| e.tsc < b.tsc) {
+                            best = Some((cpu, e));
+                        }
+                        break;
+                    }
+                    None => next[cpu] += 1,
+                }
+            }
+        }
+        let Some((cpu, e)) = best else { break };
+        next[cpu] += 1;
+        let base = *base.get_or_insert(e.tsc);
+        let us = crate::arch::tsc_timer::cycles_to_us(e.tsc.wrapping_sub(base));
+        println!("{:>10}.{:03} cpu{} {:<7} {}", us / 1000, us % 1000, cpu, e.subsys.name(), e.text());
+    }
+}
@@ -0,0 +1,84 @@
+#!/usr/bin/env python3
+"""Fill the kernel's .ksyms section with its function symbols.
+
+Run on the linked kernel ELF before it is packed into a boot image:
+
+    python3 tools/gen_ksyms.py target/x86_64-blog_os/release/neutrix
+
+The section keeps its size, so no address in the kernel moves. The layout
+is the one `src/symbols/mod.rs` reads. `NM` and `OBJCOPY` choose the tools
+(e.g. llvm-nm and llvm-objcopy); both default to the binutils ones.
+"""
+
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+MAGIC = b"NXKSYMS1"
+SECTION = ".ksyms"
+# function symbols: text, and weak ones (compiler_builtins has plenty)
+TYPES = set("tTwW")
+NM_LINE = re.compile(r"^([0-9a-f]+) (?:([0-9a-f]{16}) )?([A-Za-z]) (.+)$")
+RUST_HASH = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def read_symbols(nm, elf):
+    out = subprocess.run(
+        [nm, "--defined-only", "--numeric-sort", "--print-size", "--demangle", elf],
+        check=True, capture_output=True, text=True,
+    ).stdout
+    symbols = {}
+    for line in out.splitlines():
+        m = NM_LINE.match(line)
+        if not m or m.group(3) not in TYPES:
+            continue
+        addr = int(m.group(1), 16)
+        size = int(m.group(2), 16) if m.group(2) else 0
+        name = RUST_HASH.sub("", m.group(4))
+        # aliases at one address: keep the first, or the one with a size
+        if addr not in symbols or (symbols[addr][0] == 0 and size):
+            symbols[addr] = (min(size, 0xFFFFFFFF), name)
+    return sorted((addr, size, name) for addr, (size, name) in symbols.items())
+
+
+def build_table(symbols, capacity):
+    names = bytearray()
+    entries = bytearray()
+    for addr, size, name in symbols:
+        entries += struct.pack("<QII", addr, len(names), size)
+        names += name.encode() + b"\0"
+    header = MAGIC + struct.pack("<II", len(symbols), 16 + len(entries))
+    table = header + entries + names
+    if len(table) > capacity:
+        sys.exit(f"gen_ksyms: {len(table)} bytes of symbols don't fit in {capacity}; raise KSYMS_SIZE")
+    return table + bytes(capacity - len(table))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(f"usage: {sys.argv[0]} KERNEL_ELF")
+    elf = sys.argv[1]
+    nm = os.environ.get("NM", "nm")
+    objcopy = os.environ.get("OBJCOPY", "objcopy")
+
+    with tempfile.TemporaryDirectory() as tmp:
+        current = os.path.join(tmp, "current")
+        subprocess.run([objcopy, "--dump-section", f"{SECTION}={current}", elf, os.path.join(tmp, "scratch")], check=True)
+        with open(current, "rb") as f:
+            capacity = len(f.read())
+            f.seek(0)
+            if f.read(len(MAGIC)) != MAGIC:
+                sys.exit(f"gen_ksyms: {SECTION} in {elf} isn't a symbol table")
+        symbols = read_symbols(nm, elf)
+        table = os.path.join(tmp, "table")
+        with open(table, "wb") as f:
+            f.write(build_table(symbols, capacity))
+        subprocess.run([objcopy, "--update-section", f"{SECTION}={table}", elf], check=True)
+    print(f"gen_ksyms: {len(symbols)} symbols in {elf}")
+
+
+if __name__ == "__main__":
+    main()